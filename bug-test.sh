@@ -27,7 +27,7 @@ echo "client started"
 sleep 1
 
 # Start VPN
-docker exec $CLIENT sh -c 'RUST_LOG=info x2ssh --vpn --config /etc/x2ssh/config.toml -i /tmp/keys/id_ed25519 -p 22 root@10.10.0.20 > /tmp/x2ssh.log 2>&1 &'
+docker exec $CLIENT sh -c 'RUST_LOG=info x2ssh vpn --config /etc/x2ssh/config.toml -i /tmp/keys/id_ed25519 -p 22 root@10.10.0.20 > /tmp/x2ssh.log 2>&1 &'
 echo "VPN started"
 
 # Wait for VPN to establish
@@ -0,0 +1,146 @@
+use crate::control::BATCH;
+use crate::control::MARKER;
+
+/// Largest batch frame a sender builds. Small enough to go out as a single
+/// SSH channel message.
+pub const MAX_BATCH_LEN: usize = 16 * 1024;
+
+/// Collects IP packets into batch frames: a control frame of kind batch,
+/// then each packet as its length (u16 BE) and bytes. Only sent once both
+/// sides announced [`Features::BATCH`](crate::Features::BATCH).
+#[derive(Debug)]
+pub struct Batch {
+    frame: Vec<u8>,
+    packets: usize,
+}
+
+impl Default for Batch {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Batch {
+    pub fn new() -> Self {
+        Self {
+            frame: vec![MARKER, BATCH],
+            packets: 0,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.packets == 0
+    }
+
+    /// Adds `packet`, an IP packet (at most 65535 bytes). When it does not
+    /// fit, returns the frame of the packets added before it, and the
+    /// packet starts the next one.
+    pub fn push(&mut self, packet: &[u8]) -> Option<Vec<u8>> {
+        let len = u16::try_from(packet.len()).expect("IP packets are at most 65535 bytes");
+        let full = !self.is_empty() && self.frame.len() + 2 + packet.len() > MAX_BATCH_LEN;
+        let frame = if full { self.take() } else { None };
+        self.frame.extend_from_slice(&len.to_be_bytes());
+        self.frame.extend_from_slice(packet);
+        self.packets += 1;
+        frame
+    }
+
+    /// The frame to send for the packets added so far, if any: a lone
+    /// packet as a plain frame, several as a batch frame.
+    pub fn take(&mut self) -> Option<Vec<u8>> {
+        let frame = match self.packets {
+            0 => return None,
+            1 => self.frame[4..].to_vec(),
+            _ => std::mem::replace(&mut self.frame, Vec::with_capacity(MAX_BATCH_LEN)),
+        };
+        self.frame.clear();
+        self.frame.extend_from_slice(&[MARKER, BATCH]);
+        self.packets = 0;
+        Some(frame)
+    }
+}
+
+/// Splits a batch frame into its packets. Returns `None` for any other
+/// frame, and an error for a batch whose lengths do not add up.
+pub fn unbatch(frame: &[u8]) -> anyhow::Result<Option<Vec<&[u8]>>> {
+    let [MARKER, BATCH, rest @ ..] = frame else {
+        return Ok(None);
+    };
+    let mut rest = rest;
+    let mut packets = Vec::new();
+    while let [hi, lo, tail @ ..] = rest {
+        let len = usize::from(u16::from_be_bytes([*hi, *lo]));
+        if tail.len() < len {
+            anyhow::bail!(
+                "batch frame cut short: packet of {} bytes, {} left",
+                len,
+                tail.len()
+            );
+        }
+        let (packet, tail) = tail.split_at(len);
+        packets.push(packet);
+        rest = tail;
+    }
+    if !rest.is_empty() {
+        anyhow::bail!("batch frame ends in a stray byte");
+    }
+    Ok(Some(packets))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Control;
+
+    #[test]
+    fn test_round_trip() {
+        let mut batch = Batch::new();
+        assert_eq!(batch.take(), None);
+        assert_eq!(batch.push(&[0x45; 20]), None);
+        assert_eq!(batch.push(&[0x60; 40]), None);
+        assert_eq!(batch.push(&[]), None);
+
+        let frame = batch.take().unwrap();
+        assert!(batch.is_empty());
+        assert_eq!(Control::parse(&frame), None);
+        assert_eq!(unbatch(&frame).unwrap().unwrap(), vec![
+            &[0x45; 20][..],
+            &[0x60; 40][..],
+            &[][..]
+        ]);
+    }
+
+    #[test]
+    fn test_lone_packet_is_sent_plain() {
+        let mut batch = Batch::new();
+        batch.push(&[0x45; 20]);
+        assert_eq!(batch.take().unwrap(), vec![0x45; 20]);
+        assert_eq!(unbatch(&[0x45; 20]).unwrap(), None);
+        assert_eq!(unbatch(&Control::Ping(1).encode()).unwrap(), None);
+    }
+
+    #[test]
+    fn test_full_batch_is_returned() {
+        let mut batch = Batch::new();
+        let packet = vec![0x45; 1500];
+        let mut frames = Vec::new();
+        for _ in 0..20 {
+            frames.extend(batch.push(&packet));
+        }
+        frames.extend(batch.take());
+
+        assert!(frames.iter().all(|frame| frame.len() <= MAX_BATCH_LEN));
+        let packets: usize = frames
+            .iter()
+            .map(|frame| unbatch(frame).unwrap().map_or(1, |packets| packets.len()))
+            .sum();
+        assert_eq!(packets, 20);
+    }
+
+    #[test]
+    fn test_malformed_batch() {
+        assert!(unbatch(&[MARKER, BATCH, 0, 5, 1, 2]).is_err());
+        assert!(unbatch(&[MARKER, BATCH, 0, 1, 1, 0]).is_err());
+        assert_eq!(unbatch(&[MARKER, BATCH]).unwrap(), Some(vec![]));
+    }
+}
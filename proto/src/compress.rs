@@ -0,0 +1,98 @@
+use crate::control::COMPRESSED;
+use crate::control::MARKER;
+
+/// Frames shorter than this are sent as they are unless the sender was
+/// given another threshold: below it, LZ4 rarely wins back its overhead.
+pub const DEFAULT_THRESHOLD: usize = 256;
+
+/// Largest frame a compressed frame may expand to: one IP packet of the
+/// maximum size, or a batch, with room to spare.
+const MAX_DECOMPRESSED_LEN: usize = 128 * 1024;
+
+/// Compresses `frame` into a control frame of kind compressed: the
+/// original length (u32 BE), then the LZ4 block. Returns `None` when
+/// `frame` is shorter than `threshold` or does not get smaller, so
+/// incompressible traffic (TLS, video) goes out unchanged. Only used once
+/// both sides announced [`Features::LZ4`](crate::Features::LZ4).
+pub fn compress(frame: &[u8], threshold: usize) -> Option<Vec<u8>> {
+    if frame.len() < threshold {
+        return None;
+    }
+    let block = lz4_flex::block::compress(frame);
+    let len = u32::try_from(frame.len()).ok()?;
+    if 6 + block.len() >= frame.len() {
+        return None;
+    }
+    let mut compressed = Vec::with_capacity(6 + block.len());
+    compressed.extend_from_slice(&[MARKER, COMPRESSED]);
+    compressed.extend_from_slice(&len.to_be_bytes());
+    compressed.extend_from_slice(&block);
+    Some(compressed)
+}
+
+/// Restores the frame inside a compressed frame. Returns `None` for any
+/// other frame.
+pub fn decompress(frame: &[u8]) -> anyhow::Result<Option<Vec<u8>>> {
+    let [MARKER, COMPRESSED, l0, l1, l2, l3, block @ ..] = frame else {
+        return Ok(None);
+    };
+    let len = u32::from_be_bytes([*l0, *l1, *l2, *l3]) as usize;
+    if len > MAX_DECOMPRESSED_LEN {
+        anyhow::bail!("compressed frame claims {} bytes", len);
+    }
+    let decompressed = lz4_flex::block::decompress(block, len)
+        .map_err(|e| anyhow::anyhow!("corrupt compressed frame: {}", e))?;
+    if decompressed.len() != len {
+        anyhow::bail!(
+            "compressed frame expanded to {} bytes, not {}",
+            decompressed.len(),
+            len
+        );
+    }
+    Ok(Some(decompressed))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Control;
+
+    #[test]
+    fn test_round_trip() {
+        let frame = b"GET /index.html HTTP/1.1\r\nHost: example.com\r\n\r\n".repeat(20);
+        let compressed = compress(&frame, DEFAULT_THRESHOLD).unwrap();
+        assert!(compressed.len() < frame.len() / 4);
+        assert_eq!(Control::parse(&compressed), None);
+        assert_eq!(decompress(&compressed).unwrap(), Some(frame));
+    }
+
+    #[test]
+    fn test_skips_small_and_incompressible() {
+        assert_eq!(compress(&[0x45; 100], DEFAULT_THRESHOLD), None);
+        assert!(compress(&[0x45; 100], 64).is_some());
+
+        // A byte sequence with no repeats, like encrypted traffic.
+        let mut state = 0x2545_f491_4f6c_dd1du64;
+        let random: Vec<u8> = (0..1400)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                (state >> 32) as u8
+            })
+            .collect();
+        assert_eq!(compress(&random, DEFAULT_THRESHOLD), None);
+        assert_eq!(decompress(&random).unwrap(), None);
+    }
+
+    #[test]
+    fn test_rejects_corrupt_frames() {
+        let mut compressed = compress(&[0x45; 1000], DEFAULT_THRESHOLD).unwrap();
+        compressed[2..6].copy_from_slice(&u32::MAX.to_be_bytes());
+        assert!(decompress(&compressed).is_err());
+
+        let mut compressed = compress(&[0x45; 1000], DEFAULT_THRESHOLD).unwrap();
+        compressed.truncate(compressed.len() - 2);
+        assert!(decompress(&compressed).is_err());
+    }
+}
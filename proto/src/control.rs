@@ -0,0 +1,118 @@
+use std::net::Ipv4Addr;
+
+/// Control frames travel in the same stream as IP packets. They start with a
+/// zero byte, which no IP packet does (its first nibble is the version, 4 or
+/// 6), followed by the kind and 8 bytes of payload: a big-endian nonce, or
+/// an address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Control {
+    /// Asks the peer to answer with a [`Control::Pong`] carrying the nonce.
+    Ping(u64),
+    Pong(u64),
+    /// The client tunnel address a shared agent leased to this client, sent
+    /// right after the handshake. The payload is the address, the prefix
+    /// length and three zero bytes.
+    Assign {
+        address: Ipv4Addr,
+        prefix_len: u8,
+    },
+}
+
+pub(crate) const MARKER: u8 = 0;
+const PING: u8 = 1;
+const PONG: u8 = 2;
+const ASSIGN: u8 = 7;
+/// The handshake's [`Hello`](crate::Hello) frame.
+pub(crate) const HELLO: u8 = 3;
+/// A [`Batch`](crate::Batch) of packets.
+pub(crate) const BATCH: u8 = 4;
+/// A frame compressed with [`compress`](crate::compress).
+pub(crate) const COMPRESSED: u8 = 5;
+/// A [`gso`](crate::gso) frame.
+pub(crate) const GSO: u8 = 6;
+const LEN: usize = 10;
+
+impl Control {
+    /// Returns the control frame in `frame`, or `None` if it is a packet.
+    pub fn parse(frame: &[u8]) -> Option<Self> {
+        let [MARKER, kind, payload @ ..] = frame else {
+            return None;
+        };
+        let payload: [u8; 8] = payload.try_into().ok()?;
+        match *kind {
+            PING => Some(Self::Ping(u64::from_be_bytes(payload))),
+            PONG => Some(Self::Pong(u64::from_be_bytes(payload))),
+            ASSIGN => {
+                let [a, b, c, d, prefix_len, ..] = payload;
+                Some(Self::Assign {
+                    address: Ipv4Addr::new(a, b, c, d),
+                    prefix_len,
+                })
+            }
+            _ => None,
+        }
+    }
+
+    pub fn encode(self) -> [u8; LEN] {
+        let (kind, payload) = match self {
+            Self::Ping(nonce) => (PING, nonce.to_be_bytes()),
+            Self::Pong(nonce) => (PONG, nonce.to_be_bytes()),
+            Self::Assign {
+                address,
+                prefix_len,
+            } => {
+                let mut payload = [0u8; 8];
+                payload[..4].copy_from_slice(&address.octets());
+                payload[4] = prefix_len;
+                (ASSIGN, payload)
+            }
+        };
+        let mut frame = [0u8; LEN];
+        frame[0] = MARKER;
+        frame[1] = kind;
+        frame[2..].copy_from_slice(&payload);
+        frame
+    }
+
+    /// The frame to send back on receiving this one, if any.
+    pub fn reply(self) -> Option<Self> {
+        match self {
+            Self::Ping(nonce) => Some(Self::Pong(nonce)),
+            Self::Pong(_) | Self::Assign { .. } => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let assign = Control::Assign {
+            address: Ipv4Addr::new(10, 8, 0, 3),
+            prefix_len: 24,
+        };
+        for control in [
+            Control::Ping(0),
+            Control::Ping(u64::MAX),
+            Control::Pong(42),
+            assign,
+        ] {
+            assert_eq!(Control::parse(&control.encode()), Some(control));
+        }
+        assert_eq!(Control::Ping(7).reply(), Some(Control::Pong(7)));
+        assert_eq!(Control::Pong(7).reply(), None);
+        assert_eq!(assign.reply(), None);
+    }
+
+    #[test]
+    fn test_packets_are_not_control() {
+        assert_eq!(Control::parse(&[]), None);
+        assert_eq!(Control::parse(&[0x45; 20]), None);
+        assert_eq!(Control::parse(&[0x60; 40]), None);
+        // Right marker, but the wrong length or an unknown kind.
+        assert_eq!(Control::parse(&[0, PING, 1]), None);
+        assert_eq!(Control::parse(&[0, 9, 0, 0, 0, 0, 0, 0, 0, 1]), None);
+    }
+}
@@ -26,7 +26,11 @@ pub async fn write_framed<W: AsyncWrite + Unpin>(
 
 #[cfg(test)]
 mod tests {
+    use proptest::prelude::*;
+
     use super::*;
+    use crate::test_support::ChunkedReader;
+    use crate::test_support::packets;
 
     #[tokio::test]
     async fn test_round_trip() {
@@ -77,4 +81,47 @@ mod tests {
             assert_eq!(&received, expected);
         }
     }
+
+    fn block_on<F: std::future::Future>(future: F) -> F::Output {
+        tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap()
+            .block_on(future)
+    }
+
+    proptest! {
+        #[test]
+        fn prop_round_trip_any_split(
+            packets in packets(prop::collection::vec(any::<u8>(), 0..1500)),
+            points in prop::collection::vec(any::<prop::sample::Index>(), 0..32),
+        ) {
+            let mut stream = Vec::new();
+            block_on(async {
+                for packet in &packets {
+                    write_framed(&mut stream, packet).await.unwrap();
+                }
+            });
+
+            let points = points.iter().map(|i| i.index(stream.len() + 1)).collect();
+            let mut reader = ChunkedReader::new(&stream, points);
+
+            for expected in &packets {
+                let received = block_on(read_framed(&mut reader)).unwrap();
+                prop_assert_eq!(&received, expected);
+            }
+            prop_assert!(block_on(read_framed(&mut reader)).is_err());
+        }
+
+        #[test]
+        fn prop_truncated_stream_is_error(
+            packet in prop::collection::vec(any::<u8>(), 1..512),
+            cut in any::<prop::sample::Index>(),
+        ) {
+            let mut stream = Vec::new();
+            block_on(write_framed(&mut stream, &packet)).unwrap();
+            let truncated = &stream[..cut.index(stream.len())];
+
+            prop_assert!(block_on(read_framed(&mut &truncated[..])).is_err());
+        }
+    }
 }
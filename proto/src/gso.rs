@@ -0,0 +1,56 @@
+use crate::control::GSO;
+use crate::control::MARKER;
+
+/// Length of the virtio-net header an offload-enabled TUN device puts in
+/// front of every packet.
+pub const VNET_HDR_LEN: usize = 10;
+
+/// Bytes in front of the virtio-net header in a GSO frame.
+pub const PREFIX: [u8; 2] = [MARKER, GSO];
+
+/// Largest GSO frame: the prefix, the header and a 64 KiB packet.
+pub const MAX_GSO_FRAME: usize = PREFIX.len() + VNET_HDR_LEN + 65535;
+
+/// Whether `frame` is a GSO frame: a control frame of kind GSO, then the
+/// virtio-net header and the packet as an offload-enabled TUN device hands
+/// them over, before segmenting it or completing its checksum. This lets a
+/// single frame carry up to 64 KiB of a TCP stream. Only sent once both
+/// sides announced [`Features::OFFLOAD`](crate::Features::OFFLOAD).
+pub fn is_gso(frame: &[u8]) -> bool {
+    frame.starts_with(&PREFIX)
+}
+
+/// The virtio-net header and packet of a GSO frame, as written to the
+/// receiving TUN device; `None` if `frame` is not one.
+pub fn vnet_packet(frame: &[u8]) -> Option<&[u8]> {
+    frame.strip_prefix(&PREFIX[..])
+}
+
+/// Whether a virtio-net header asks for nothing (no checksum to complete,
+/// no segmentation), so its packet can travel as a plain frame.
+pub fn is_plain(vnet_hdr: &[u8]) -> bool {
+    vnet_hdr[..2] == [0, 0]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Control;
+    use crate::unbatch;
+
+    #[test]
+    fn test_gso_frame() {
+        let mut frame = PREFIX.to_vec();
+        frame.extend_from_slice(&[1, 4, 0, 0, 0, 0, 0, 0, 0, 0]);
+        frame.extend_from_slice(&[0x45; 3000]);
+        assert!(is_gso(&frame));
+        assert_eq!(vnet_packet(&frame).unwrap().len(), VNET_HDR_LEN + 3000);
+        assert!(!is_plain(vnet_packet(&frame).unwrap()));
+        assert_eq!(Control::parse(&frame), None);
+        assert!(unbatch(&frame).unwrap().is_none());
+
+        assert!(!is_gso(&[0x45; 20]));
+        assert_eq!(vnet_packet(&[0x45; 20]), None);
+        assert!(is_plain(&[0; VNET_HDR_LEN]));
+    }
+}
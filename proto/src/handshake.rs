@@ -0,0 +1,180 @@
+use tokio::io::AsyncRead;
+use tokio::io::AsyncWrite;
+
+use crate::control::HELLO;
+use crate::control::MARKER;
+
+/// Identifies an x2ssh agent channel, so a stray program on the other end
+/// fails the handshake instead of being fed packets.
+pub const MAGIC: [u8; 4] = *b"X2SH";
+
+/// Bumped on any change to the frame format that the other side could
+/// misread.
+pub const PROTOCOL_VERSION: u16 = 1;
+
+const LEN: usize = 12;
+
+/// Optional protocol capabilities; only those both sides announce are used.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Features(u32);
+
+impl Features {
+    pub const NONE: Self = Self(0);
+    /// Answers [`Control::Ping`](crate::Control::Ping) frames.
+    pub const KEEPALIVE: Self = Self(1 << 0);
+    /// The agent masquerades the tunnel subnet on the server. It announces
+    /// this only once the NAT rules are in place.
+    pub const NAT: Self = Self(1 << 1);
+    /// The agent forwards DNS queries sent to its tunnel address.
+    pub const DNS: Self = Self(1 << 2);
+    /// Understands [`Batch`](crate::Batch) frames.
+    pub const BATCH: Self = Self(1 << 3);
+    /// Understands LZ4-compressed frames and sends them. Clients only
+    /// announce it when compression is turned on.
+    pub const LZ4: Self = Self(1 << 4);
+    /// Sends and accepts [`gso`](crate::gso) frames. Agents only announce
+    /// it when their TUN device was created with offloads.
+    pub const OFFLOAD: Self = Self(1 << 5);
+    /// The agent serves several clients from one TUN device and leases
+    /// each its address with a [`Control::Assign`](crate::Control::Assign)
+    /// right after the handshake.
+    pub const SHARED: Self = Self(1 << 6);
+
+    /// Everything this build supports.
+    pub const fn all() -> Self {
+        Self(
+            Self::KEEPALIVE.0
+                | Self::NAT.0
+                | Self::DNS.0
+                | Self::BATCH.0
+                | Self::LZ4.0
+                | Self::OFFLOAD.0
+                | Self::SHARED.0,
+        )
+    }
+
+    pub const fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub const fn intersection(self, other: Self) -> Self {
+        Self(self.0 & other.0)
+    }
+
+    pub const fn without(self, other: Self) -> Self {
+        Self(self.0 & !other.0)
+    }
+}
+
+/// The first frame each side sends: a control frame of kind hello, then
+/// [`MAGIC`], the protocol version (u16 BE) and feature bits (u32 BE).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Hello {
+    pub version: u16,
+    pub features: Features,
+}
+
+impl Hello {
+    /// A hello for this build's protocol version.
+    pub fn new(features: Features) -> Self {
+        Self {
+            version: PROTOCOL_VERSION,
+            features,
+        }
+    }
+
+    pub fn encode(&self) -> [u8; LEN] {
+        let mut frame = [0u8; LEN];
+        frame[0] = MARKER;
+        frame[1] = HELLO;
+        frame[2..6].copy_from_slice(&MAGIC);
+        frame[6..8].copy_from_slice(&self.version.to_be_bytes());
+        frame[8..].copy_from_slice(&self.features.0.to_be_bytes());
+        frame
+    }
+
+    pub fn parse(frame: &[u8]) -> anyhow::Result<Self> {
+        let [MARKER, HELLO, m0, m1, m2, m3, v0, v1, f0, f1, f2, f3] = *frame else {
+            anyhow::bail!("expected a hello frame, got {} other bytes", frame.len());
+        };
+        if [m0, m1, m2, m3] != MAGIC {
+            anyhow::bail!("hello frame has the wrong magic");
+        }
+        Ok(Self {
+            version: u16::from_be_bytes([v0, v1]),
+            features: Features(u32::from_be_bytes([f0, f1, f2, f3])),
+        })
+    }
+
+    /// The features to use with a peer that sent `peer`. Fails unless both
+    /// sides speak the same protocol version.
+    pub fn agree(&self, peer: &Hello) -> anyhow::Result<Features> {
+        if peer.version != self.version {
+            anyhow::bail!(
+                "peer speaks protocol version {}, this side speaks {}",
+                peer.version,
+                self.version
+            );
+        }
+        Ok(self.features.intersection(peer.features))
+    }
+}
+
+/// The agent's side of the handshake: sends its hello, then expects the
+/// client's as the first frame.
+pub async fn accept<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    features: Features,
+) -> anyhow::Result<Features>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let ours = Hello::new(features);
+    crate::write_framed(writer, &ours.encode()).await?;
+    let theirs = Hello::parse(&crate::read_framed(reader).await?)?;
+    ours.agree(&theirs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Control;
+
+    #[test]
+    fn test_hello_round_trip() {
+        let hello = Hello::new(Features::all());
+        assert_eq!(Hello::parse(&hello.encode()).unwrap(), hello);
+        // Never mistaken for a packet or another control frame.
+        assert_eq!(Control::parse(&hello.encode()), None);
+
+        assert!(Hello::parse(&[0x45; 12]).is_err());
+        assert!(Hello::parse(&Control::Ping(1).encode()).is_err());
+        let mut wrong_magic = hello.encode();
+        wrong_magic[2] = b'Y';
+        assert!(Hello::parse(&wrong_magic).is_err());
+    }
+
+    #[test]
+    fn test_agree() {
+        let ours = Hello::new(Features::KEEPALIVE);
+        let old = Hello::new(Features::NONE);
+        assert_eq!(ours.agree(&old).unwrap(), Features::NONE);
+        assert_eq!(ours.agree(&ours).unwrap(), Features::KEEPALIVE);
+        let nat = Hello::new(Features::all());
+        assert!(nat.agree(&nat).unwrap().contains(Features::NAT));
+        let no_nat = Hello::new(Features::all().without(Features::NAT));
+        assert_eq!(
+            nat.agree(&no_nat).unwrap(),
+            Features::all().without(Features::NAT)
+        );
+
+        let newer = Hello {
+            version: PROTOCOL_VERSION + 1,
+            features: Features::all(),
+        };
+        let error = ours.agree(&newer).unwrap_err().to_string();
+        assert!(error.contains("protocol version 2"), "{error}");
+    }
+}
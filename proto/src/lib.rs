@@ -1,3 +1,21 @@
+pub mod batch;
+pub mod compress;
+pub mod control;
 pub mod framing;
+pub mod gso;
+pub mod handshake;
+pub mod packet;
+#[cfg(any(test, feature = "test-support"))]
+pub mod test_support;
+pub use batch::Batch;
+pub use batch::unbatch;
+pub use compress::compress;
+pub use compress::decompress;
+pub use control::Control;
 pub use framing::read_framed;
 pub use framing::write_framed;
+pub use handshake::Features;
+pub use handshake::Hello;
+
+/// MTU of both tunnel ends unless configured otherwise.
+pub const DEFAULT_MTU: u16 = 1400;
@@ -0,0 +1,147 @@
+use crate::gso;
+use crate::gso::VNET_HDR_LEN;
+
+/// Why a frame is not an IP packet a TUN device should be given.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Malformed {
+    /// The first nibble is neither 4 nor 6.
+    Version(u8),
+    /// Shorter than the IP header, or than the length the header gives.
+    Truncated,
+    /// An IPv4 header length below 20 bytes or a total length below it.
+    HeaderLength,
+}
+
+impl std::fmt::Display for Malformed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Malformed::Version(version) => write!(f, "IP version {version}"),
+            Malformed::Truncated => f.write_str("truncated"),
+            Malformed::HeaderLength => f.write_str("bad IPv4 header length"),
+        }
+    }
+}
+
+impl std::error::Error for Malformed {}
+
+/// Checks that `packet` is a whole IPv4 or IPv6 packet: a known version,
+/// a complete header, and at least as many bytes as the header says the
+/// packet has (trailing padding is allowed).
+pub fn validate(packet: &[u8]) -> Result<(), Malformed> {
+    match packet.first().map(|byte| byte >> 4) {
+        Some(4) => {
+            if packet.len() < 20 {
+                return Err(Malformed::Truncated);
+            }
+            let header_len = usize::from(packet[0] & 0x0f) * 4;
+            let total_len = usize::from(u16::from_be_bytes([packet[2], packet[3]]));
+            if header_len < 20 || total_len < header_len {
+                return Err(Malformed::HeaderLength);
+            }
+            if total_len > packet.len() {
+                return Err(Malformed::Truncated);
+            }
+            Ok(())
+        }
+        Some(6) => {
+            if packet.len() < 40 {
+                return Err(Malformed::Truncated);
+            }
+            let payload_len = usize::from(u16::from_be_bytes([packet[4], packet[5]]));
+            if 40 + payload_len > packet.len() {
+                return Err(Malformed::Truncated);
+            }
+            Ok(())
+        }
+        Some(version) => Err(Malformed::Version(version)),
+        None => Err(Malformed::Truncated),
+    }
+}
+
+/// [`validate`] for a frame bound for a TUN device: with `offload`, a GSO
+/// frame is checked by the packet it carries.
+pub fn validate_frame(frame: &[u8], offload: bool) -> Result<(), Malformed> {
+    match gso::vnet_packet(frame).filter(|_| offload) {
+        Some(vnet) if vnet.len() < VNET_HDR_LEN => Err(Malformed::Truncated),
+        Some(vnet) => validate(&vnet[VNET_HDR_LEN..]),
+        None => validate(frame),
+    }
+}
+
+/// The IP protocol (IPv6 next header) of a valid `packet` and the bytes
+/// after its header. IPv6 extension headers are not followed: their
+/// packets report the first extension header.
+pub fn transport(packet: &[u8]) -> Option<(u8, &[u8])> {
+    match packet.first()? >> 4 {
+        4 => {
+            let header_len = usize::from(packet[0] & 0x0f) * 4;
+            Some((*packet.get(9)?, packet.get(header_len..)?))
+        }
+        6 => Some((*packet.get(6)?, packet.get(40..)?)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ipv4(total_len: u16, len: usize) -> Vec<u8> {
+        let mut packet = vec![0; len];
+        packet[0] = 0x45;
+        packet[2..4].copy_from_slice(&total_len.to_be_bytes());
+        packet[9] = 6;
+        packet
+    }
+
+    #[test]
+    fn test_validate() {
+        assert_eq!(validate(&ipv4(40, 40)), Ok(()));
+        assert_eq!(validate(&ipv4(40, 46)), Ok(()));
+        assert_eq!(validate(&ipv4(60, 40)), Err(Malformed::Truncated));
+        assert_eq!(validate(&ipv4(12, 40)), Err(Malformed::HeaderLength));
+        let mut options = ipv4(40, 40);
+        options[0] = 0x44;
+        assert_eq!(validate(&options), Err(Malformed::HeaderLength));
+        assert_eq!(validate(&ipv4(40, 40)[..19]), Err(Malformed::Truncated));
+
+        let mut ipv6 = vec![0; 48];
+        ipv6[0] = 0x60;
+        ipv6[5] = 8;
+        assert_eq!(validate(&ipv6), Ok(()));
+        ipv6[5] = 9;
+        assert_eq!(validate(&ipv6), Err(Malformed::Truncated));
+
+        assert_eq!(validate(&[]), Err(Malformed::Truncated));
+        assert_eq!(validate(&[0x00, 4, 0, 0]), Err(Malformed::Version(0)));
+        assert_eq!(validate(&[0x50; 64]), Err(Malformed::Version(5)));
+    }
+
+    #[test]
+    fn test_validate_frame() {
+        let mut frame = gso::PREFIX.to_vec();
+        frame.extend_from_slice(&[0; VNET_HDR_LEN]);
+        frame.extend_from_slice(&ipv4(3000, 3000));
+        assert_eq!(validate_frame(&frame, true), Ok(()));
+        assert_eq!(validate_frame(&frame, false), Err(Malformed::Version(0)));
+        assert_eq!(
+            validate_frame(&frame[..frame.len() - 1], true),
+            Err(Malformed::Truncated)
+        );
+        assert_eq!(
+            validate_frame(&gso::PREFIX, true),
+            Err(Malformed::Truncated)
+        );
+    }
+
+    #[test]
+    fn test_transport() {
+        let packet = ipv4(44, 44);
+        assert_eq!(transport(&packet), Some((6, &packet[20..])));
+        let mut ipv6 = vec![0; 48];
+        ipv6[0] = 0x60;
+        ipv6[6] = 58;
+        assert_eq!(transport(&ipv6), Some((58, &ipv6[40..])));
+        assert_eq!(transport(&[]), None);
+    }
+}
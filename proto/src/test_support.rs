@@ -0,0 +1,75 @@
+//! Fixtures for tests of the framed agent protocol, shared with x2ssh's
+//! tests through the `test-support` feature.
+
+use std::collections::VecDeque;
+use std::pin::Pin;
+use std::task::Context;
+use std::task::Poll;
+
+use proptest::prelude::*;
+use tokio::io::AsyncRead;
+use tokio::io::ReadBuf;
+
+/// Yields a byte stream in caller-chosen chunks, one per read, like SSH
+/// `Data` messages arriving with arbitrary boundaries.
+pub struct ChunkedReader {
+    chunks: VecDeque<Vec<u8>>,
+}
+
+impl ChunkedReader {
+    /// Splits `data` at `points`; those out of range or repeated are
+    /// ignored.
+    pub fn new(data: &[u8], mut points: Vec<usize>) -> Self {
+        points.retain(|&p| p > 0 && p < data.len());
+        points.sort_unstable();
+        points.dedup();
+
+        let mut chunks = VecDeque::new();
+        let mut start = 0;
+        for point in points.into_iter().chain([data.len()]) {
+            chunks.push_back(data[start..point].to_vec());
+            start = point;
+        }
+        Self { chunks }
+    }
+}
+
+impl AsyncRead for ChunkedReader {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        if let Some(mut chunk) = self.chunks.pop_front() {
+            let n = chunk.len().min(buf.remaining());
+            buf.put_slice(&chunk[..n]);
+            if n < chunk.len() {
+                self.chunks.push_front(chunk.split_off(n));
+            }
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// `packets` framed as [`write_framed`](crate::write_framed) sends them.
+pub fn framed(packets: &[Vec<u8>]) -> Vec<u8> {
+    let mut stream = Vec::new();
+    for packet in packets {
+        stream.extend_from_slice(&(packet.len() as u32).to_be_bytes());
+        stream.extend_from_slice(packet);
+    }
+    stream
+}
+
+/// Up to 8 packets, mostly from `packet`, with empty and maximum-size
+/// (65535, the largest IP packet) frames mixed in.
+pub fn packets(
+    packet: impl Strategy<Value = Vec<u8>> + 'static,
+) -> impl Strategy<Value = Vec<Vec<u8>>> {
+    let packet = prop_oneof![
+        8 => packet,
+        1 => Just(Vec::new()),
+        1 => any::<u8>().prop_map(|b| vec![b; 65535]),
+    ];
+    prop::collection::vec(packet, 0..8)
+}
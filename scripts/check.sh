@@ -78,6 +78,11 @@ if ! run_check "Rust linting (clippy)" "cargo clippy -- -D warnings"; then
     FAILED=1
 fi
 
+# Rust SOCKS-only build (without the vpn feature)
+if ! run_check "Rust SOCKS-only build" "cargo clippy -p x2ssh --no-default-features --all-targets -- -D warnings"; then
+    FAILED=1
+fi
+
 # Rust unit tests
 if ! run_check "Rust unit tests" "cargo test"; then
     FAILED=1
@@ -44,6 +44,7 @@ def x2ssh_process(ssh_container: SshContainer) -> Iterator[dict[str, object]]:
         "cargo",
         "run",
         "--",
+        "proxy",
         "-D",
         f"127.0.0.1:{proxy_port}",
         "-p",
@@ -132,10 +132,10 @@ class VpnSession:
         self.env = env
 
     def start_vpn(self, timeout: float = 30.0) -> None:
-        """Start x2ssh --vpn in client container (background process)."""
+        """Start x2ssh vpn in client container (background process)."""
         self.env.exec_client("pkill -INT -x x2ssh || true")
         self.env.exec_client(
-            "RUST_LOG=info x2ssh --vpn --config /etc/x2ssh/config.toml "
+            "RUST_LOG=info x2ssh vpn --config /etc/x2ssh/config.toml "
             "-i /tmp/keys/id_ed25519 "
             "-p 22 root@10.10.0.20 "
             "> /tmp/x2ssh.log 2>&1 &"
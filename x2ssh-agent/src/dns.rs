@@ -0,0 +1,212 @@
+//! A DNS forwarder on the server's tunnel address (`--dns`), so clients
+//! resolve through the tunnel with the server's own resolver.
+
+use std::net::IpAddr;
+use std::net::Ipv4Addr;
+use std::net::Ipv6Addr;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::net::TcpListener;
+use tokio::net::TcpStream;
+use tokio::net::UdpSocket;
+
+/// How long the upstream resolver gets to answer a query.
+const UPSTREAM_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Largest DNS message over UDP with EDNS.
+const MAX_UDP: usize = 4096;
+
+pub struct DnsForwarder {
+    udp: Arc<UdpSocket>,
+    tcp: TcpListener,
+    upstream: SocketAddr,
+}
+
+impl DnsForwarder {
+    /// Binds UDP and TCP on `listen`, forwarding to `upstream`.
+    pub async fn bind(listen: SocketAddr, upstream: SocketAddr) -> anyhow::Result<Self> {
+        let udp = UdpSocket::bind(listen)
+            .await
+            .map_err(|e| anyhow::anyhow!("cannot listen for DNS on udp {}: {}", listen, e))?;
+        // With port 0, TCP takes the port the kernel picked for UDP.
+        let listen = udp.local_addr()?;
+        let tcp = TcpListener::bind(listen)
+            .await
+            .map_err(|e| anyhow::anyhow!("cannot listen for DNS on tcp {}: {}", listen, e))?;
+        Ok(Self {
+            udp: Arc::new(udp),
+            tcp,
+            upstream,
+        })
+    }
+
+    /// Uses the first usable nameserver in the server's `/etc/resolv.conf`.
+    pub async fn bind_system(listen: SocketAddr) -> anyhow::Result<Self> {
+        let resolv_conf = std::fs::read_to_string("/etc/resolv.conf")
+            .map_err(|e| anyhow::anyhow!("cannot read /etc/resolv.conf: {}", e))?;
+        let upstream = nameserver(&resolv_conf, listen.ip())
+            .ok_or_else(|| anyhow::anyhow!("/etc/resolv.conf has no usable nameserver"))?;
+        let forwarder = Self::bind(listen, SocketAddr::new(upstream, 53)).await?;
+        eprintln!(
+            "DNS forwarder on {} using {}",
+            forwarder.local_addr()?,
+            upstream
+        );
+        Ok(forwarder)
+    }
+
+    pub fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        self.udp.local_addr()
+    }
+
+    /// Answers queries until the agent exits. Each query is forwarded on
+    /// its own, so a slow answer does not hold up the others.
+    pub async fn serve(self) -> anyhow::Result<()> {
+        let upstream = self.upstream;
+        let tcp = self.tcp;
+        tokio::spawn(async move {
+            loop {
+                let (client, peer) = match tcp.accept().await {
+                    Ok(accepted) => accepted,
+                    Err(e) => {
+                        eprintln!("DNS accept error: {}", e);
+                        continue;
+                    }
+                };
+                tokio::spawn(async move {
+                    if let Err(e) = forward_tcp(client, upstream).await {
+                        eprintln!("DNS over TCP for {} failed: {}", peer, e);
+                    }
+                });
+            }
+        });
+
+        let mut buf = vec![0u8; MAX_UDP];
+        loop {
+            let (n, peer) = self.udp.recv_from(&mut buf).await?;
+            let query = buf[..n].to_vec();
+            let udp = Arc::clone(&self.udp);
+            tokio::spawn(async move {
+                match forward_udp(&query, upstream).await {
+                    Ok(response) => {
+                        let _ = udp.send_to(&response, peer).await;
+                    }
+                    Err(e) => eprintln!("DNS query for {} failed: {}", peer, e),
+                }
+            });
+        }
+    }
+}
+
+async fn forward_udp(query: &[u8], upstream: SocketAddr) -> anyhow::Result<Vec<u8>> {
+    let id = query
+        .get(..2)
+        .ok_or_else(|| anyhow::anyhow!("query too short"))?;
+    let local: SocketAddr = match upstream {
+        SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+    };
+    let socket = UdpSocket::bind(local).await?;
+    socket.connect(upstream).await?;
+    socket.send(query).await?;
+
+    let mut buf = vec![0u8; MAX_UDP];
+    tokio::time::timeout(UPSTREAM_TIMEOUT, async {
+        loop {
+            let n = socket.recv(&mut buf).await?;
+            // Ignore stray datagrams that do not answer this query.
+            if buf.get(..2) == Some(id) {
+                buf.truncate(n);
+                return Ok(buf);
+            }
+        }
+    })
+    .await
+    .map_err(|_| anyhow::anyhow!("{} did not answer", upstream))?
+}
+
+async fn forward_tcp(mut client: TcpStream, upstream: SocketAddr) -> anyhow::Result<()> {
+    let mut server = tokio::time::timeout(UPSTREAM_TIMEOUT, TcpStream::connect(upstream))
+        .await
+        .map_err(|_| anyhow::anyhow!("connecting to {} timed out", upstream))??;
+    tokio::io::copy_bidirectional(&mut client, &mut server).await?;
+    Ok(())
+}
+
+/// The first nameserver in `resolv_conf` that is not the forwarder itself.
+/// Scoped IPv6 addresses (`fe80::1%eth0`) are skipped.
+fn nameserver(resolv_conf: &str, listen: IpAddr) -> Option<IpAddr> {
+    resolv_conf
+        .lines()
+        .filter_map(|line| line.trim().strip_prefix("nameserver"))
+        .filter_map(|rest| rest.trim().parse().ok())
+        .find(|ip| *ip != listen)
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::AsyncReadExt;
+    use tokio::io::AsyncWriteExt;
+
+    use super::*;
+
+    #[test]
+    fn test_nameserver() {
+        let listen: IpAddr = "10.8.0.1".parse().unwrap();
+        let conf = "# generated\nsearch example.com\nnameserver fe80::1%eth0\nnameserver \
+                    10.8.0.1\nnameserver 127.0.0.53\nnameserver 1.1.1.1\n";
+        assert_eq!(
+            nameserver(conf, listen),
+            Some("127.0.0.53".parse().unwrap())
+        );
+        assert_eq!(nameserver("search example.com\n", listen), None);
+    }
+
+    #[tokio::test]
+    async fn test_forwards_udp_and_tcp() {
+        // Upstream answers every UDP query with its ID and "answer".
+        let upstream_udp = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let upstream_addr = upstream_udp.local_addr().unwrap();
+        let upstream_tcp = TcpListener::bind(upstream_addr).await.unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 512];
+            loop {
+                let (n, peer) = upstream_udp.recv_from(&mut buf).await.unwrap();
+                let mut response = buf[..2].to_vec();
+                response.extend_from_slice(b"answer");
+                assert_eq!(&buf[2..n], b"query");
+                upstream_udp.send_to(&response, peer).await.unwrap();
+            }
+        });
+        tokio::spawn(async move {
+            let (mut stream, _) = upstream_tcp.accept().await.unwrap();
+            let mut query = [0u8; 7];
+            stream.read_exact(&mut query).await.unwrap();
+            stream.write_all(b"\0\x04tcp!").await.unwrap();
+        });
+
+        let forwarder = DnsForwarder::bind("127.0.0.1:0".parse().unwrap(), upstream_addr)
+            .await
+            .unwrap();
+        let listen = forwarder.local_addr().unwrap();
+        tokio::spawn(forwarder.serve());
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client.connect(listen).await.unwrap();
+        client.send(b"\x12\x34query").await.unwrap();
+        let mut buf = [0u8; 512];
+        let n = tokio::time::timeout(Duration::from_secs(5), client.recv(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(&buf[..n], b"\x12\x34answer");
+
+        let mut stream = TcpStream::connect(listen).await.unwrap();
+        stream.write_all(b"\0\x05query").await.unwrap();
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).await.unwrap();
+        assert_eq!(response, b"\0\x04tcp!");
+    }
+}
@@ -1,29 +1,198 @@
+mod dns;
+mod shared;
+
+use std::net::Ipv4Addr;
+use std::net::SocketAddr;
+use std::path::PathBuf;
 use std::sync::Arc;
+use std::time::Duration;
+
+use proto::Batch;
+use proto::Control;
+use proto::Features;
+use proto::gso;
+use tokio::sync::Mutex;
+use x2ssh_net::TunConfig;
+use x2ssh_net::nat::Nat;
+
+use crate::dns::DnsForwarder;
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    let args: Vec<String> = std::env::args().collect();
-    if args.len() != 3 || args[1] != "--ip" {
-        eprintln!("Usage: x2ssh-agent --ip <SUBNET_IP/PREFIX>");
-        eprintln!("Example: x2ssh-agent --ip 10.8.0.1/24");
-        std::process::exit(1);
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match args.as_slice() {
+        [flag] if flag == "--loopback" => run_loopback().await,
+        _ => match parse_tun_args(&args) {
+            Ok(options) => match (&options.serve, &options.connect) {
+                (Some(socket), _) => shared::serve(&options, socket).await,
+                (None, Some(socket)) => shared::connect(&options, socket, &args).await,
+                (None, None) => run_tun(&options).await,
+            },
+            Err(e) => {
+                eprintln!("{e}");
+                eprintln!(
+                    "Usage: x2ssh-agent --ip <SUBNET_IP/PREFIX> [--ip6 <SUBNET_IP6/PREFIX>] \
+                     [--name <TUN>] [--mtu <BYTES>] [--nat] [--dns] [--offload] [--batch-delay-ms \
+                     <MS>] [--compress-threshold <BYTES>] [--serve <SOCKET> | --connect <SOCKET> \
+                     [--peer-address <IP>] [--peer-id <ID>]]"
+                );
+                eprintln!("       x2ssh-agent --loopback");
+                eprintln!("Example: x2ssh-agent --ip 10.8.0.1/24 --ip6 fd00:8::1/64");
+                std::process::exit(1);
+            }
+        },
     }
-    let subnet_ip = &args[2];
+}
 
-    let tun = create_tun(subnet_ip).await?;
-    let tun = Arc::new(tun);
+/// What the agent sets up besides bridging packets.
+struct TunOptions {
+    tun: TunConfig,
+    /// Masquerade the tunnel subnet (`--nat`).
+    nat: bool,
+    /// Forward DNS queries sent to the tunnel address (`--dns`).
+    dns: bool,
+    /// How long a packet from the TUN device waits for others to share its
+    /// frame (`--batch-delay-ms`). Packets already waiting are always
+    /// batched.
+    batch_delay: Duration,
+    /// Frames shorter than this are not compressed (`--compress-threshold`).
+    /// The client decides whether compression is used at all.
+    compress_threshold: usize,
+    /// Serve several clients on this Unix socket (`--serve`), see
+    /// [`shared`].
+    serve: Option<PathBuf>,
+    /// Join the agent serving this socket instead (`--connect`), asking
+    /// for `peer_address` (`--peer-address`) as `peer_id` (`--peer-id`).
+    connect: Option<PathBuf>,
+    peer_address: Option<Ipv4Addr>,
+    peer_id: String,
+}
+
+/// Parses `--ip`, `--ip6`, `--name`, `--mtu`, `--nat`, `--dns`,
+/// `--offload`, `--batch-delay-ms`, `--compress-threshold`, `--serve`,
+/// `--connect`, `--peer-address` and `--peer-id`, in any order.
+/// `--offload` creates the TUN device with offloads.
+fn parse_tun_args(args: &[String]) -> anyhow::Result<TunOptions> {
+    let mut address = None;
+    let mut address6 = None;
+    let mut name = None;
+    let mut mtu = None;
+    let mut nat = false;
+    let mut dns = false;
+    let mut offload = false;
+    let mut batch_delay = Duration::ZERO;
+    let mut compress_threshold = proto::compress::DEFAULT_THRESHOLD;
+    let mut serve = None;
+    let mut connect = None;
+    let mut peer_address = None;
+    let mut peer_id = format!("pid-{}", std::process::id());
+
+    let mut args = args.iter();
+    while let Some(flag) = args.next() {
+        match flag.as_str() {
+            "--nat" => {
+                nat = true;
+                continue;
+            }
+            "--dns" => {
+                dns = true;
+                continue;
+            }
+            "--offload" => {
+                offload = true;
+                continue;
+            }
+            _ => {}
+        }
+        let value = args
+            .next()
+            .ok_or_else(|| anyhow::anyhow!("missing value for {flag}"))?;
+        match flag.as_str() {
+            "--ip" => address = Some(value.parse()?),
+            "--ip6" => address6 = Some(value.parse()?),
+            "--name" => name = Some(value.clone()),
+            "--mtu" => mtu = Some(value.parse()?),
+            "--batch-delay-ms" => batch_delay = Duration::from_millis(value.parse()?),
+            "--compress-threshold" => compress_threshold = value.parse()?,
+            "--serve" => serve = Some(PathBuf::from(value)),
+            "--connect" => connect = Some(PathBuf::from(value)),
+            "--peer-address" => peer_address = Some(value.parse()?),
+            "--peer-id" => peer_id = value.clone(),
+            _ => anyhow::bail!("unknown argument: {flag}"),
+        }
+    }
+
+    let address = address.ok_or_else(|| anyhow::anyhow!("--ip is required"))?;
+    let mut config = TunConfig::new(address);
+    config.name = name;
+    config.address6 = address6;
+    config.offload = offload;
+    if let Some(mtu) = mtu {
+        config.mtu = mtu;
+    }
+    Ok(TunOptions {
+        tun: config,
+        nat,
+        dns,
+        batch_delay,
+        compress_threshold,
+        serve,
+        connect,
+        peer_address,
+        peer_id,
+    })
+}
+
+/// Bridge framed packets on stdin/stdout to a freshly created TUN device.
+/// Frames are raw IP packets of either version; the kernel routes them.
+/// The OS destroys the device when the process exits; NAT and the DNS
+/// forwarder, set up before the handshake so failures reach the client,
+/// are removed on the way out.
+async fn run_tun(options: &TunOptions) -> anyhow::Result<()> {
+    let (tun, nat, features) = setup(options).await?;
+    let features = features.without(Features::SHARED);
+    let offload = options.tun.offload;
 
-    let tun_for_write = Arc::clone(&tun);
     let mut stdin = tokio::io::stdin();
+    let mut stdout = tokio::io::stdout();
+    let features = handshake(&mut stdin, &mut stdout, features).await?;
+    let batching = features.contains(Features::BATCH);
+    let batch_delay = options.batch_delay;
+    let compress_threshold = features
+        .contains(Features::LZ4)
+        .then_some(options.compress_threshold);
+
+    let tun_for_write = Arc::clone(&tun);
+    // Shared so keepalive replies can be written between packets.
+    let stdout = Arc::new(Mutex::new(stdout));
+    let replies = Arc::clone(&stdout);
 
     // Client → Server TUN: Read framed packet from stdin, write to TUN
     let client_to_tun = tokio::spawn(async move {
+        let mut malformed = 0;
         loop {
             match proto::read_framed(&mut stdin).await {
-                Ok(packet) => {
-                    if let Err(e) = tun_for_write.send(&packet).await {
-                        eprintln!("TUN send error: {}", e);
-                        return Err::<(), anyhow::Error>(e.into());
+                Ok(frame) => {
+                    let frame = proto::decompress(&frame)?.unwrap_or(frame);
+                    if let Some(control) = Control::parse(&frame) {
+                        if let Some(reply) = control.reply() {
+                            let mut stdout = replies.lock().await;
+                            proto::write_framed(&mut *stdout, &reply.encode()).await?;
+                        }
+                        continue;
+                    }
+                    let packets = match proto::unbatch(&frame)? {
+                        Some(packets) => packets,
+                        None => vec![frame.as_slice()],
+                    };
+                    for packet in packets {
+                        if !check(packet, offload, &mut malformed) {
+                            continue;
+                        }
+                        if let Err(e) = send(&tun_for_write, packet, offload).await {
+                            eprintln!("TUN send error: {}", e);
+                            return Err::<(), anyhow::Error>(e.into());
+                        }
                     }
                 }
                 Err(e) => {
@@ -35,23 +204,45 @@ async fn main() -> anyhow::Result<()> {
     });
 
     let tun_for_read = Arc::clone(&tun);
-    let mut stdout = tokio::io::stdout();
+    let read_len = options.tun.read_len();
 
-    // Server TUN → Client: Read from TUN, write framed to stdout
+    // Server TUN → Client: Read from TUN, write framed to stdout. With
+    // batching, packets read before the batch delay ends share one frame.
     let tun_to_client = tokio::spawn(async move {
-        let mut buf = vec![0u8; 2048];
+        let mut buf = vec![0u8; read_len];
+        let mut batch = Batch::new();
         loop {
-            match tun_for_read.recv(&mut buf).await {
-                Ok(n) => {
-                    eprintln!("TUN→CLIENT: sending {} bytes", n);
-                    if let Err(e) = proto::write_framed(&mut stdout, &buf[..n]).await {
-                        eprintln!("stdout write error: {}", e);
-                        return Err::<(), anyhow::Error>(e);
+            let n = recv(&tun_for_read, &mut buf, offload)
+                .await
+                .inspect_err(|e| {
+                    eprintln!("TUN recv error: {}", e);
+                })?;
+            let mut frames = Vec::new();
+            let done = add(&mut batch, &mut frames, &buf[..n]);
+
+            if batching && !done {
+                let deadline = tokio::time::Instant::now() + batch_delay;
+                // Polls the device before the deadline, so packets already
+                // waiting are picked up even without a delay.
+                while let Ok(received) =
+                    tokio::time::timeout_at(deadline, recv(&tun_for_read, &mut buf, offload)).await
+                {
+                    let n = received.inspect_err(|e| eprintln!("TUN recv error: {}", e))?;
+                    if add(&mut batch, &mut frames, &buf[..n]) {
+                        break;
                     }
                 }
-                Err(e) => {
-                    eprintln!("TUN recv error: {}", e);
-                    return Err::<(), anyhow::Error>(e.into());
+            }
+            frames.extend(batch.take());
+
+            let mut stdout = stdout.lock().await;
+            for frame in frames {
+                let frame = compress_threshold
+                    .and_then(|threshold| proto::compress(&frame, threshold))
+                    .unwrap_or(frame);
+                if let Err(e) = proto::write_framed(&mut *stdout, &frame).await {
+                    eprintln!("stdout write error: {}", e);
+                    return Err::<(), anyhow::Error>(e);
                 }
             }
         }
@@ -68,24 +259,163 @@ async fn main() -> anyhow::Result<()> {
                 eprintln!("TUN->Client task failed: {}", e);
             }
         }
+        signal = terminated() => eprintln!("Received {}, exiting", signal?),
+    }
+
+    drop(nat);
+    Ok(())
+}
+
+/// Creates the TUN device with NAT and the DNS forwarder as `options` ask,
+/// returning the features the agent can offer on top of it.
+async fn setup(
+    options: &TunOptions,
+) -> anyhow::Result<(Arc<tun_rs::AsyncDevice>, Option<Nat>, Features)> {
+    let config = &options.tun;
+    let tun = Arc::new(config.create()?);
+    let mut features = Features::all();
+    let nat = if options.nat {
+        let nat = Nat::enable(&tun.name()?, config.address, config.address6)?;
+        eprintln!(
+            "NAT enabled for {} (nftables table inet {})",
+            config.address,
+            nat.table()
+        );
+        Some(nat)
+    } else {
+        features = features.without(Features::NAT);
+        None
+    };
+    if options.dns {
+        let listen = SocketAddr::new(config.address.addr().into(), 53);
+        let forwarder = DnsForwarder::bind_system(listen).await?;
+        tokio::spawn(async move {
+            if let Err(e) = forwarder.serve().await {
+                eprintln!("DNS forwarder failed: {}", e);
+            }
+        });
+    } else {
+        features = features.without(Features::DNS);
+    }
+    if !config.offload {
+        features = features.without(Features::OFFLOAD);
     }
 
+    Ok((tun, nat, features))
+}
+
+/// Reads a packet from the TUN device, or with offloads a GSO frame.
+async fn recv(tun: &tun_rs::AsyncDevice, buf: &mut [u8], offload: bool) -> std::io::Result<usize> {
+    if offload {
+        return x2ssh_net::offload::recv(tun, buf).await;
+    }
+    tun.recv(buf).await
+}
+
+/// Writes a packet, or with offloads a GSO frame, to the TUN device.
+async fn send(tun: &tun_rs::AsyncDevice, packet: &[u8], offload: bool) -> std::io::Result<()> {
+    if offload {
+        return x2ssh_net::offload::send(tun, packet).await;
+    }
+    tun.send(packet).await?;
     Ok(())
-    // TUN is destroyed automatically when the process exits — no cleanup needed
 }
 
-/// Create a TUN interface with the given subnet IP, configure it, and bring it
-/// up. The OS destroys this interface automatically when the process exits.
-async fn create_tun(subnet_ip: &str) -> anyhow::Result<tun_rs::AsyncDevice> {
-    // Parse "addr/prefix" — e.g. "10.8.0.1/24"
-    let (addr_str, prefix_str) = subnet_ip
-        .split_once('/')
-        .ok_or_else(|| anyhow::anyhow!("expected ADDR/PREFIX, got: {subnet_ip}"))?;
-    let prefix: u8 = prefix_str.parse()?;
-
-    let dev = tun_rs::DeviceBuilder::new()
-        .ipv4(addr_str, prefix, None)
-        .mtu(1400)
-        .build_async()?;
-    Ok(dev)
+/// Whether `packet` from the client may be written to the TUN device. A
+/// malformed one is counted in `malformed` instead, and logged when the
+/// count reaches a power of two so a flood cannot fill the log.
+fn check(packet: &[u8], offload: bool, malformed: &mut u64) -> bool {
+    let Err(e) = proto::packet::validate_frame(packet, offload) else {
+        return true;
+    };
+    *malformed += 1;
+    if malformed.is_power_of_two() {
+        eprintln!("Dropped a malformed packet ({e}); {malformed} so far");
+    }
+    false
+}
+
+/// Adds a packet read from the TUN device to `batch`, moving finished
+/// frames to `frames`. Returns whether the batch is done: it is once full,
+/// and a GSO frame, which goes out on its own, ends it.
+fn add(batch: &mut Batch, frames: &mut Vec<Vec<u8>>, packet: &[u8]) -> bool {
+    if gso::is_gso(packet) {
+        frames.extend(batch.take());
+        frames.push(packet.to_vec());
+        return true;
+    }
+    match batch.push(packet) {
+        Some(full) => {
+            frames.push(full);
+            true
+        }
+        None => false,
+    }
+}
+
+/// Waits for the signals the agent is stopped with: SIGTERM from the
+/// client's cleanup, SIGHUP when the SSH session goes away.
+async fn terminated() -> anyhow::Result<&'static str> {
+    use tokio::signal::unix::SignalKind;
+    use tokio::signal::unix::signal;
+
+    let mut term = signal(SignalKind::terminate())?;
+    let mut hup = signal(SignalKind::hangup())?;
+    let mut int = signal(SignalKind::interrupt())?;
+    Ok(tokio::select! {
+        _ = term.recv() => "SIGTERM",
+        _ = hup.recv() => "SIGHUP",
+        _ = int.recv() => "SIGINT",
+    })
+}
+
+/// Reflect every frame back to the client without touching the network,
+/// answering keepalive pings as the TUN mode does. Lets the client exercise
+/// the agent protocol without root or a TUN device.
+async fn run_loopback() -> anyhow::Result<()> {
+    let mut stdin = tokio::io::stdin();
+    let mut stdout = tokio::io::stdout();
+    let features = Features::all()
+        .without(Features::NAT)
+        .without(Features::DNS)
+        .without(Features::OFFLOAD)
+        .without(Features::SHARED);
+    handshake(&mut stdin, &mut stdout, features).await?;
+
+    loop {
+        let packet = match proto::read_framed(&mut stdin).await {
+            Ok(packet) => packet,
+            Err(e) if is_eof(&e) => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        match Control::parse(&packet) {
+            Some(control) => {
+                if let Some(reply) = control.reply() {
+                    proto::write_framed(&mut stdout, &reply.encode()).await?;
+                }
+            }
+            None => proto::write_framed(&mut stdout, &packet).await?,
+        }
+    }
+}
+
+/// Exchanges hellos with the client before any packet flows, so a client
+/// built for another protocol version fails right away.
+async fn handshake(
+    stdin: &mut tokio::io::Stdin,
+    stdout: &mut tokio::io::Stdout,
+    features: Features,
+) -> anyhow::Result<Features> {
+    let features = proto::handshake::accept(stdin, stdout, features).await?;
+    eprintln!(
+        "Handshake complete: protocol v{}, {:?}",
+        proto::handshake::PROTOCOL_VERSION,
+        features
+    );
+    Ok(features)
+}
+
+fn is_eof(e: &anyhow::Error) -> bool {
+    e.downcast_ref::<std::io::Error>()
+        .is_some_and(|e| e.kind() == std::io::ErrorKind::UnexpectedEof)
 }
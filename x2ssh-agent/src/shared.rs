@@ -0,0 +1,614 @@
+//! One agent serving several clients (`--serve`): it owns the TUN device,
+//! NAT and the DNS forwarder, leases each client an address of the tunnel
+//! subnet and routes packets from the device to the client leasing their
+//! destination. The agent each client starts joins it over a Unix socket
+//! (`--connect`) and relays its channel there, starting the shared agent
+//! first when none is running.
+
+use std::collections::BTreeMap;
+use std::net::Ipv4Addr;
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use ipnet::Ipv4Net;
+use proto::Control;
+use proto::Features;
+use tokio::net::UnixListener;
+use tokio::net::UnixStream;
+use tokio::sync::mpsc;
+use tokio::task::JoinSet;
+
+use crate::TunOptions;
+
+/// How long a joining agent waits for the shared agent it started.
+const START_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Packets queued for a client before more are dropped.
+const PEER_QUEUE: usize = 1024;
+
+/// The answer to a [`Request`] the client may join with.
+const OK: &[u8] = b"ok";
+
+/// Flags of a joining agent that the shared agent it starts does not take.
+const JOIN_FLAGS: [&str; 3] = ["--connect", "--peer-address", "--peer-id"];
+
+/// What a joining agent asks for, the first frame on the socket: the
+/// tunnel address its client expects the server on, the address it wants
+/// (`-` for any) and its client's id. The answer is a frame with [`OK`] or
+/// why the client cannot join.
+#[derive(Debug, PartialEq)]
+struct Request {
+    server: Ipv4Net,
+    address: Option<Ipv4Addr>,
+    peer_id: String,
+}
+
+impl Request {
+    fn encode(&self) -> Vec<u8> {
+        let address = self
+            .address
+            .map_or_else(|| "-".to_string(), |address| address.to_string());
+        format!("{} {} {}", self.server, address, self.peer_id).into_bytes()
+    }
+
+    fn parse(frame: &[u8]) -> anyhow::Result<Self> {
+        let text = std::str::from_utf8(frame)?;
+        let mut words = text.split(' ');
+        let (Some(server), Some(address), Some(peer_id), None) =
+            (words.next(), words.next(), words.next(), words.next())
+        else {
+            anyhow::bail!("malformed request: {text}");
+        };
+        Ok(Self {
+            server: server.parse()?,
+            address: match address {
+                "-" => None,
+                address => Some(address.parse()?),
+            },
+            peer_id: peer_id.to_string(),
+        })
+    }
+}
+
+/// Addresses of the tunnel subnet leased to clients, each with the sender
+/// of its client's packets.
+struct Pool<T> {
+    /// The shared agent's own address and the subnet.
+    server: Ipv4Net,
+    leases: BTreeMap<Ipv4Addr, Lease<T>>,
+    generation: u64,
+}
+
+struct Lease<T> {
+    peer_id: String,
+    /// Tells a lease apart from a later one of the same address.
+    generation: u64,
+    value: T,
+}
+
+impl<T> Pool<T> {
+    fn new(server: Ipv4Net) -> Self {
+        Self {
+            server,
+            leases: BTreeMap::new(),
+            generation: 0,
+        }
+    }
+
+    /// Leases `requested` if it is free, else the address `peer_id` held
+    /// before, else the first free one. A client reconnecting with the same
+    /// id takes its address over from its stale lease. Returns the address
+    /// and the generation to release it with, or `None` if the subnet is
+    /// full.
+    fn lease(
+        &mut self,
+        requested: Option<Ipv4Addr>,
+        peer_id: &str,
+        value: T,
+    ) -> Option<(Ipv4Addr, u64)> {
+        let previous = self
+            .leases
+            .iter()
+            .find(|(_, lease)| lease.peer_id == peer_id)
+            .map(|(address, _)| *address);
+        let address = requested
+            .filter(|address| self.is_free(*address, peer_id))
+            .or(previous)
+            .or_else(|| {
+                self.server
+                    .hosts()
+                    .find(|address| self.is_free(*address, peer_id))
+            })?;
+        if let Some(previous) = previous {
+            self.leases.remove(&previous);
+        }
+        self.generation += 1;
+        self.leases.insert(address, Lease {
+            peer_id: peer_id.to_string(),
+            generation: self.generation,
+            value,
+        });
+        Some((address, self.generation))
+    }
+
+    /// Ends the lease of `address`, unless it has been taken over since.
+    fn release(&mut self, address: Ipv4Addr, generation: u64) {
+        if self
+            .leases
+            .get(&address)
+            .is_some_and(|lease| lease.generation == generation)
+        {
+            self.leases.remove(&address);
+        }
+    }
+
+    fn get(&self, address: Ipv4Addr) -> Option<&T> {
+        self.leases.get(&address).map(|lease| &lease.value)
+    }
+
+    fn is_free(&self, address: Ipv4Addr, peer_id: &str) -> bool {
+        let subnet = self.server.trunc();
+        let host = subnet.contains(&address)
+            && (subnet.prefix_len() >= 31
+                || (address != subnet.network() && address != subnet.broadcast()));
+        host && address != self.server.addr()
+            && self
+                .leases
+                .get(&address)
+                .is_none_or(|lease| lease.peer_id == peer_id)
+    }
+}
+
+/// The state the shared agent's clients share.
+struct Server {
+    address: Ipv4Net,
+    tun: Arc<tun_rs::AsyncDevice>,
+    features: Features,
+    compress_threshold: usize,
+    /// Bytes a packet read from `tun` can take.
+    read_len: usize,
+    pool: Mutex<Pool<mpsc::Sender<Vec<u8>>>>,
+}
+
+/// Serves clients joining on `socket` until the last one leaves. The TUN
+/// device is plain: no offloads and IPv4 only, since packets are routed to
+/// clients by their IPv4 destination.
+pub async fn serve(options: &TunOptions, socket: &Path) -> anyhow::Result<()> {
+    if options.tun.address6.is_some() || options.tun.offload {
+        anyhow::bail!("--serve supports neither --ip6 nor --offload");
+    }
+    let (listener, _lock) = bind(socket).await?;
+    let (tun, nat, features) = crate::setup(options).await?;
+    let server = Arc::new(Server {
+        address: options.tun.address,
+        tun,
+        features: features.without(Features::OFFLOAD),
+        compress_threshold: options.compress_threshold,
+        read_len: options.tun.read_len(),
+        pool: Mutex::new(Pool::new(options.tun.address)),
+    });
+    eprintln!(
+        "Serving {} for clients joining on {}",
+        options.tun.address,
+        socket.display()
+    );
+
+    let mut from_tun = tokio::spawn(route_from_tun(Arc::clone(&server)));
+    let terminated = crate::terminated();
+    tokio::pin!(terminated);
+    let mut peers = JoinSet::new();
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => {
+                let (stream, _) = accepted?;
+                let server = Arc::clone(&server);
+                peers.spawn(async move {
+                    if let Err(e) = serve_peer(stream, &server).await {
+                        eprintln!("Client failed: {:#}", e);
+                    }
+                });
+            }
+            Some(_) = peers.join_next() => {
+                if peers.is_empty() {
+                    eprintln!("Last client left, exiting");
+                    break;
+                }
+            }
+            result = &mut from_tun => {
+                if let Ok(Err(e)) = result {
+                    eprintln!("TUN recv error: {}", e);
+                }
+                break;
+            }
+            signal = &mut terminated => {
+                eprintln!("Received {}, exiting", signal?);
+                break;
+            }
+        }
+    }
+
+    let _ = std::fs::remove_file(socket);
+    drop(nat);
+    Ok(())
+}
+
+/// Listens on `socket`, replacing a stale one left by an agent that did
+/// not exit cleanly. Only root, which the agents run as, may connect.
+///
+/// The agent serving `socket` holds a lock on the file next to it for as
+/// long as it runs, returned with the listener, and only the lock holder
+/// touches the socket. So when clients start agents at the same time, one
+/// serves and the others find it and exit, instead of unlinking its socket.
+async fn bind(socket: &Path) -> anyhow::Result<(UnixListener, std::fs::File)> {
+    use std::os::unix::fs::PermissionsExt;
+
+    if let Some(dir) = socket.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    let lock_path = socket.with_extension("lock");
+    let lock = std::fs::OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(&lock_path)
+        .map_err(|e| anyhow::anyhow!("cannot open {}: {}", lock_path.display(), e))?;
+    // The holder may be an agent about to listen, or one shutting down.
+    let deadline = tokio::time::Instant::now() + START_TIMEOUT;
+    loop {
+        match lock.try_lock() {
+            Ok(()) => break,
+            Err(std::fs::TryLockError::WouldBlock) => {
+                if std::os::unix::net::UnixStream::connect(socket).is_ok() {
+                    anyhow::bail!("another agent already serves {}", socket.display());
+                }
+                if tokio::time::Instant::now() >= deadline {
+                    anyhow::bail!("another agent holds {}", lock_path.display());
+                }
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            }
+            Err(std::fs::TryLockError::Error(e)) => {
+                anyhow::bail!("cannot lock {}: {}", lock_path.display(), e)
+            }
+        }
+    }
+
+    let _ = std::fs::remove_file(socket);
+    let listener = UnixListener::bind(socket)
+        .map_err(|e| anyhow::anyhow!("cannot listen on {}: {}", socket.display(), e))?;
+    std::fs::set_permissions(socket, std::fs::Permissions::from_mode(0o600))?;
+    Ok((listener, lock))
+}
+
+/// Sends each packet from the TUN device to the client leasing its
+/// destination; others are dropped, as are packets for a client that falls
+/// behind.
+async fn route_from_tun(server: Arc<Server>) -> anyhow::Result<()> {
+    let mut buf = vec![0u8; server.read_len];
+    loop {
+        let n = server.tun.recv(&mut buf).await?;
+        let packet = &buf[..n];
+        let Some(destination) = ipv4_address(packet, 16) else {
+            continue;
+        };
+        if let Some(peer) = server.pool.lock().unwrap().get(destination) {
+            let _ = peer.try_send(packet.to_vec());
+        }
+    }
+}
+
+/// Takes a joining client through the request, the handshake and the
+/// address assignment, then bridges its frames until it leaves.
+async fn serve_peer(mut stream: UnixStream, server: &Server) -> anyhow::Result<()> {
+    let request = Request::parse(&proto::read_framed(&mut stream).await?)?;
+    if request.server != server.address {
+        let answer = format!(
+            "the shared agent serves {}, not {}",
+            server.address, request.server
+        );
+        proto::write_framed(&mut stream, answer.as_bytes()).await?;
+        anyhow::bail!(answer);
+    }
+    let (tx, mut rx) = mpsc::channel(PEER_QUEUE);
+    let lease = server
+        .pool
+        .lock()
+        .unwrap()
+        .lease(request.address, &request.peer_id, tx.clone());
+    let Some((address, generation)) = lease else {
+        let answer = format!("no address left in {}", server.address.trunc());
+        proto::write_framed(&mut stream, answer.as_bytes()).await?;
+        anyhow::bail!(answer);
+    };
+
+    let result = async {
+        proto::write_framed(&mut stream, OK).await?;
+        let (mut reader, mut writer) = stream.split();
+        let features = proto::handshake::accept(&mut reader, &mut writer, server.features).await?;
+        let assign = Control::Assign {
+            address,
+            prefix_len: server.address.prefix_len(),
+        };
+        proto::write_framed(&mut writer, &assign.encode()).await?;
+        eprintln!("Client {} joined as {}", request.peer_id, address);
+        let compress_threshold = features
+            .contains(Features::LZ4)
+            .then_some(server.compress_threshold);
+
+        let to_peer = async {
+            while let Some(frame) = rx.recv().await {
+                let frame = compress_threshold
+                    .and_then(|threshold| proto::compress(&frame, threshold))
+                    .unwrap_or(frame);
+                proto::write_framed(&mut writer, &frame).await?;
+            }
+            Ok::<(), anyhow::Error>(())
+        };
+        // Packets not from the client's own address are dropped, so a client
+        // cannot pose as another.
+        let from_peer = async {
+            let mut malformed = 0;
+            loop {
+                let frame = proto::read_framed(&mut reader).await?;
+                let frame = proto::decompress(&frame)?.unwrap_or(frame);
+                if let Some(control) = Control::parse(&frame) {
+                    if let Some(reply) = control.reply() {
+                        let _ = tx.try_send(reply.encode().to_vec());
+                    }
+                    continue;
+                }
+                let packets = match proto::unbatch(&frame)? {
+                    Some(packets) => packets,
+                    None => vec![frame.as_slice()],
+                };
+                for packet in packets {
+                    if crate::check(packet, false, &mut malformed)
+                        && ipv4_address(packet, 12) == Some(address)
+                    {
+                        server.tun.send(packet).await?;
+                    }
+                }
+            }
+        };
+        tokio::select! {
+            result = to_peer => result,
+            result = from_peer => result,
+        }
+    }
+    .await;
+
+    server.pool.lock().unwrap().release(address, generation);
+    eprintln!("Client {} left {}", request.peer_id, address);
+    match result {
+        Err(e) if crate::is_eof(&e) => Ok(()),
+        result => result,
+    }
+}
+
+/// The IPv4 source (`offset` 12) or destination (16) of `packet`.
+fn ipv4_address(packet: &[u8], offset: usize) -> Option<Ipv4Addr> {
+    if packet.len() < 20 || packet[0] >> 4 != 4 {
+        return None;
+    }
+    let octets: [u8; 4] = packet[offset..offset + 4].try_into().ok()?;
+    Some(Ipv4Addr::from(octets))
+}
+
+/// Joins the agent serving `socket`, starting it with the rest of `args`
+/// if none is, and relays the client's channel on stdin/stdout to it.
+pub async fn connect(options: &TunOptions, socket: &Path, args: &[String]) -> anyhow::Result<()> {
+    let mut stream = match UnixStream::connect(socket).await {
+        Ok(stream) => stream,
+        Err(e)
+            if matches!(
+                e.kind(),
+                std::io::ErrorKind::NotFound | std::io::ErrorKind::ConnectionRefused
+            ) =>
+        {
+            start(socket, args)?;
+            wait_for(socket).await?
+        }
+        Err(e) => anyhow::bail!("cannot connect to {}: {}", socket.display(), e),
+    };
+
+    let request = Request {
+        server: options.tun.address,
+        address: options.peer_address,
+        peer_id: options.peer_id.clone(),
+    };
+    proto::write_framed(&mut stream, &request.encode()).await?;
+    let answer = proto::read_framed(&mut stream).await?;
+    if answer != OK {
+        anyhow::bail!("{}", String::from_utf8_lossy(&answer));
+    }
+
+    let mut stdio = tokio::io::join(tokio::io::stdin(), tokio::io::stdout());
+    tokio::select! {
+        result = tokio::io::copy_bidirectional(&mut stdio, &mut stream) => {
+            result?;
+        }
+        signal = crate::terminated() => eprintln!("Received {}, exiting", signal?),
+    }
+    Ok(())
+}
+
+/// Starts the shared agent for `socket` in its own process group, so it
+/// outlives this agent and the SSH session that started it. Its output is
+/// appended to a log next to the socket, which agents other clients start
+/// at the same time share; all but one of them exit in [`bind`].
+fn start(socket: &Path, args: &[String]) -> anyhow::Result<()> {
+    use std::os::unix::process::CommandExt;
+
+    eprintln!("Starting the shared agent on {}", socket.display());
+    if let Some(dir) = socket.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    let log = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(socket.with_extension("log"))?;
+    std::process::Command::new(std::env::current_exe()?)
+        .args(serve_args(socket, args))
+        .stdin(std::process::Stdio::null())
+        .stdout(std::process::Stdio::null())
+        .stderr(log)
+        .process_group(0)
+        .spawn()
+        .map_err(|e| anyhow::anyhow!("cannot start the shared agent: {}", e))?;
+    Ok(())
+}
+
+/// The shared agent's arguments: this agent's, without the ones for
+/// joining, and `--serve`.
+fn serve_args(socket: &Path, args: &[String]) -> Vec<String> {
+    let mut serve = vec!["--serve".to_string(), socket.display().to_string()];
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if JOIN_FLAGS.contains(&arg.as_str()) {
+            args.next();
+        } else {
+            serve.push(arg.clone());
+        }
+    }
+    serve
+}
+
+/// Connects to the shared agent just started, once it listens.
+async fn wait_for(socket: &Path) -> anyhow::Result<UnixStream> {
+    let deadline = tokio::time::Instant::now() + START_TIMEOUT;
+    loop {
+        match UnixStream::connect(socket).await {
+            Ok(stream) => return Ok(stream),
+            Err(e) if tokio::time::Instant::now() >= deadline => {
+                let log = std::fs::read_to_string(socket.with_extension("log")).unwrap_or_default();
+                anyhow::bail!(
+                    "the shared agent did not start on {}: {}\n{}",
+                    socket.display(),
+                    e,
+                    log.trim_end()
+                );
+            }
+            Err(_) => tokio::time::sleep(Duration::from_millis(100)).await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_request_round_trip() {
+        let request = Request {
+            server: "10.8.0.1/24".parse().unwrap(),
+            address: Some("10.8.0.2".parse().unwrap()),
+            peer_id: "4f2a".to_string(),
+        };
+        assert_eq!(request.encode(), b"10.8.0.1/24 10.8.0.2 4f2a");
+        assert_eq!(Request::parse(&request.encode()).unwrap(), request);
+
+        let any = Request::parse(b"10.8.0.1/24 - 4f2a").unwrap();
+        assert_eq!(any.address, None);
+        assert!(Request::parse(b"10.8.0.1/24 - 4f2a extra").is_err());
+        assert!(Request::parse(b"10.8.0.1/24 -").is_err());
+    }
+
+    #[test]
+    fn test_pool_leases() {
+        let mut pool = Pool::new("10.8.0.1/29".parse().unwrap());
+        let addr = |s: &str| s.parse::<Ipv4Addr>().unwrap();
+
+        // Requested addresses are granted when free and in the subnet.
+        assert_eq!(
+            pool.lease(Some(addr("10.8.0.2")), "a", 'a'),
+            Some((addr("10.8.0.2"), 1))
+        );
+        // Taken, the server's or outside the subnet: the first free one.
+        assert_eq!(
+            pool.lease(Some(addr("10.8.0.2")), "b", 'b'),
+            Some((addr("10.8.0.3"), 2))
+        );
+        assert_eq!(
+            pool.lease(Some(addr("10.8.0.1")), "c", 'c'),
+            Some((addr("10.8.0.4"), 3))
+        );
+        assert_eq!(
+            pool.lease(Some(addr("10.8.0.7")), "d", 'd'),
+            Some((addr("10.8.0.5"), 4))
+        );
+        assert_eq!(pool.get(addr("10.8.0.3")), Some(&'b'));
+
+        // A reconnecting client takes its address over; the stale lease's
+        // release is a no-op.
+        assert_eq!(pool.lease(None, "b", 'B'), Some((addr("10.8.0.3"), 5)));
+        pool.release(addr("10.8.0.3"), 2);
+        assert_eq!(pool.get(addr("10.8.0.3")), Some(&'B'));
+
+        assert_eq!(pool.lease(None, "e", 'e'), Some((addr("10.8.0.6"), 6)));
+        assert_eq!(pool.lease(None, "f", 'f'), None);
+        pool.release(addr("10.8.0.4"), 3);
+        assert_eq!(pool.get(addr("10.8.0.4")), None);
+        assert_eq!(pool.lease(None, "f", 'f'), Some((addr("10.8.0.4"), 7)));
+    }
+
+    #[test]
+    fn test_serve_args() {
+        let args: Vec<String> = [
+            "--ip",
+            "10.8.0.1/24",
+            "--connect",
+            "/run/x2ssh/shared.sock",
+            "--nat",
+            "--peer-address",
+            "10.8.0.2",
+            "--peer-id",
+            "4f2a",
+            "--dns",
+        ]
+        .map(String::from)
+        .to_vec();
+        assert_eq!(serve_args(Path::new("/run/x2ssh/shared.sock"), &args), [
+            "--serve",
+            "/run/x2ssh/shared.sock",
+            "--ip",
+            "10.8.0.1/24",
+            "--nat",
+            "--dns"
+        ]);
+    }
+
+    #[test]
+    fn test_ipv4_address() {
+        let mut packet = [0u8; 20];
+        packet[0] = 0x45;
+        packet[12..16].copy_from_slice(&[10, 8, 0, 2]);
+        packet[16..20].copy_from_slice(&[1, 1, 1, 1]);
+        assert_eq!(ipv4_address(&packet, 12), Some(Ipv4Addr::new(10, 8, 0, 2)));
+        assert_eq!(ipv4_address(&packet, 16), Some(Ipv4Addr::new(1, 1, 1, 1)));
+        packet[0] = 0x60;
+        assert_eq!(ipv4_address(&packet, 12), None);
+        assert_eq!(ipv4_address(&packet[..19], 12), None);
+    }
+
+    #[tokio::test]
+    async fn test_bind_once() {
+        let dir = std::env::temp_dir().join(format!("x2ssh-agent-bind-{}", std::process::id()));
+        let socket = dir.join("shared.sock");
+
+        let (listener, lock) = bind(&socket).await.unwrap();
+        let err = bind(&socket).await.unwrap_err();
+        assert!(
+            err.to_string().starts_with("another agent already serves"),
+            "{err}"
+        );
+        assert!(socket.exists());
+
+        // A socket left behind by an agent that is gone is replaced.
+        drop((listener, lock));
+        let (_listener, _lock) = bind(&socket).await.unwrap();
+        assert!(std::os::unix::net::UnixStream::connect(&socket).is_ok());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
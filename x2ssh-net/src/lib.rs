@@ -0,0 +1,6 @@
+pub mod nat;
+#[cfg(target_os = "linux")]
+pub mod offload;
+pub mod tun;
+pub use tun::DEFAULT_MTU;
+pub use tun::TunConfig;
@@ -0,0 +1,164 @@
+//! Masquerading the tunnel subnet on the server (the agent's `--nat`), so
+//! clients reach the internet without PostUp iptables hooks.
+
+use std::io::Write;
+use std::process::Command;
+use std::process::Stdio;
+
+use ipnet::Ipv4Net;
+use ipnet::Ipv6Net;
+
+/// Prefix of the nftables tables the agents create, one per TUN device.
+const TABLE_PREFIX: &str = "x2ssh_agent_";
+
+const IPV4_FORWARD: &str = "/proc/sys/net/ipv4/ip_forward";
+const IPV6_FORWARD: &str = "/proc/sys/net/ipv6/conf/all/forwarding";
+
+/// IP forwarding and a masquerade rule for traffic from the tunnel leaving
+/// through any other interface. Both are undone on drop; forwarding only
+/// if this agent turned it on and no other agent's table is left.
+pub struct Nat {
+    table: String,
+    /// Forwarding switches this agent turned on.
+    enabled_forwarding: Vec<&'static str>,
+}
+
+impl Nat {
+    pub fn enable(tun: &str, subnet: Ipv4Net, subnet6: Option<Ipv6Net>) -> anyhow::Result<Self> {
+        let mut nat = Self {
+            table: table_name(tun),
+            enabled_forwarding: Vec::new(),
+        };
+        nat.enable_forwarding(IPV4_FORWARD)?;
+        if subnet6.is_some() {
+            nat.enable_forwarding(IPV6_FORWARD)?;
+        }
+        run_nft(&ruleset(&nat.table, tun, subnet, subnet6))?;
+        Ok(nat)
+    }
+
+    /// The nftables table (in the `inet` family) holding the rule.
+    pub fn table(&self) -> &str {
+        &self.table
+    }
+
+    fn enable_forwarding(&mut self, switch: &'static str) -> anyhow::Result<()> {
+        let current = std::fs::read_to_string(switch)
+            .map_err(|e| anyhow::anyhow!("cannot read {}: {}", switch, e))?;
+        if current.trim() != "1" {
+            std::fs::write(switch, "1")
+                .map_err(|e| anyhow::anyhow!("cannot enable forwarding in {}: {}", switch, e))?;
+            self.enabled_forwarding.push(switch);
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Nat {
+    fn drop(&mut self) {
+        if let Err(e) = run_nft(&format!("delete table inet {}\n", self.table)) {
+            eprintln!("Failed to remove nftables table inet {}: {}", self.table, e);
+        }
+        if self.enabled_forwarding.is_empty() {
+            return;
+        }
+        // Another client's agent may still be forwarding through this host.
+        let others = Command::new("nft")
+            .args(["list", "tables"])
+            .output()
+            .map(|output| String::from_utf8_lossy(&output.stdout).contains(TABLE_PREFIX))
+            .unwrap_or(true);
+        if others {
+            eprintln!("Leaving IP forwarding on for other x2ssh agents");
+            return;
+        }
+        for switch in &self.enabled_forwarding {
+            if let Err(e) = std::fs::write(switch, "0") {
+                eprintln!("Failed to restore {}: {}", switch, e);
+            }
+        }
+    }
+}
+
+/// nftables only allows letters, digits and underscores in a bare name.
+fn table_name(tun: &str) -> String {
+    let tun: String = tun
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    format!("{TABLE_PREFIX}{tun}")
+}
+
+/// Recreating the table in one transaction replaces leftovers from an agent
+/// that was killed before it could clean up.
+fn ruleset(table: &str, tun: &str, subnet: Ipv4Net, subnet6: Option<Ipv6Net>) -> String {
+    let mut rules = vec![
+        format!("table inet {table}"),
+        format!("delete table inet {table}"),
+        format!("table inet {table} {{"),
+        "    chain postrouting {".to_string(),
+        "        type nat hook postrouting priority 100; policy accept;".to_string(),
+        format!(
+            "        ip saddr {} oifname != \"{tun}\" masquerade",
+            subnet.trunc()
+        ),
+    ];
+    if let Some(subnet6) = subnet6 {
+        rules.push(format!(
+            "        ip6 saddr {} oifname != \"{tun}\" masquerade",
+            subnet6.trunc()
+        ));
+    }
+    rules.push("    }".to_string());
+    rules.push("}".to_string());
+
+    let mut script = rules.join("\n");
+    script.push('\n');
+    script
+}
+
+fn run_nft(rules: &str) -> anyhow::Result<()> {
+    let mut child = Command::new("nft")
+        .args(["-f", "-"])
+        .stdin(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| anyhow::anyhow!("failed to run nft (is nftables installed?): {}", e))?;
+
+    let mut stdin = child.stdin.take().expect("stdin is piped");
+    stdin.write_all(rules.as_bytes())?;
+    drop(stdin);
+
+    let output = child.wait_with_output()?;
+    if !output.status.success() {
+        anyhow::bail!(
+            "nft failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ruleset() {
+        let rules = ruleset(
+            &table_name("tun-x2ssh"),
+            "tun-x2ssh",
+            "10.8.0.1/24".parse().unwrap(),
+            Some("fd00:8::1/64".parse().unwrap()),
+        );
+        assert!(rules.starts_with(
+            "table inet x2ssh_agent_tun_x2ssh\ndelete table inet x2ssh_agent_tun_x2ssh\n"
+        ));
+        assert!(rules.contains("type nat hook postrouting priority 100;"));
+        assert!(rules.contains("ip saddr 10.8.0.0/24 oifname != \"tun-x2ssh\" masquerade"));
+        assert!(rules.contains("ip6 saddr fd00:8::/64 oifname != \"tun-x2ssh\" masquerade"));
+
+        let rules = ruleset("t", "tun0", "10.8.0.1/24".parse().unwrap(), None);
+        assert!(!rules.contains("ip6"));
+    }
+}
@@ -0,0 +1,46 @@
+//! Reading and writing a TUN device created with offloads
+//! ([`TunConfig::offload`](crate::TunConfig::offload)). Such a device puts a
+//! virtio-net header in front of every packet, and hands over TCP segments
+//! of up to 64 KiB that still need segmenting or a checksum. Those travel
+//! the tunnel as [`proto::gso`] frames, everything else as plain packets.
+
+use std::io;
+use std::io::IoSlice;
+
+use proto::gso;
+use proto::gso::VNET_HDR_LEN;
+
+/// Reads the next packet from `device` into `buf`, which must hold
+/// [`gso::MAX_GSO_FRAME`] bytes, and returns its length: a plain packet
+/// when its header asks for nothing, a GSO frame otherwise.
+pub async fn recv(device: &tun_rs::AsyncDevice, buf: &mut [u8]) -> io::Result<usize> {
+    let prefix = gso::PREFIX.len();
+    let n = device.recv(&mut buf[prefix..]).await?;
+    if n < VNET_HDR_LEN {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "TUN device read shorter than its virtio-net header",
+        ));
+    }
+    let packet = prefix + VNET_HDR_LEN;
+    if gso::is_plain(&buf[prefix..packet]) {
+        buf.copy_within(packet..prefix + n, 0);
+        return Ok(n - VNET_HDR_LEN);
+    }
+    buf[..prefix].copy_from_slice(&gso::PREFIX);
+    Ok(prefix + n)
+}
+
+/// Writes a plain packet or a GSO frame to `device`.
+pub async fn send(device: &tun_rs::AsyncDevice, frame: &[u8]) -> io::Result<()> {
+    match gso::vnet_packet(frame) {
+        Some(vnet_packet) => device.send(vnet_packet).await?,
+        None => {
+            let header = [0; VNET_HDR_LEN];
+            device
+                .send_vectored(&[IoSlice::new(&header), IoSlice::new(frame)])
+                .await?
+        }
+    };
+    Ok(())
+}
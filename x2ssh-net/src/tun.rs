@@ -0,0 +1,127 @@
+use ipnet::Ipv4Net;
+use ipnet::Ipv6Net;
+pub use proto::DEFAULT_MTU;
+
+/// Addresses and settings of one end of the tunnel. The client and the
+/// agent create their TUN devices from this, so both ends are set up the
+/// same way.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TunConfig {
+    /// Interface name; the OS picks one (`tunN`) when unset.
+    pub name: Option<String>,
+    /// IPv4 address with the tunnel subnet's prefix, e.g. `10.8.0.1/24`.
+    pub address: Ipv4Net,
+    /// IPv6 address with prefix, for dual-stack tunnels.
+    pub address6: Option<Ipv6Net>,
+    pub mtu: u16,
+    /// Queues of a Linux multiqueue device, each with its own file
+    /// descriptor; see [`create_queues`](Self::create_queues).
+    pub queues: usize,
+    /// Linux only: create the device with offloads (`IFF_VNET_HDR`, TSO
+    /// and GRO), so TCP travels in segments of up to 64 KiB. It must then
+    /// be read and written through [`offload`](crate::offload).
+    pub offload: bool,
+    /// Attach to an existing persistent interface `name` (`ip tuntap add`,
+    /// systemd-networkd) instead of creating one, leaving its addresses,
+    /// MTU and state to whoever set it up. Opening it needs no privileges
+    /// when it belongs to this user, and it outlives the device.
+    pub persistent: bool,
+}
+
+impl TunConfig {
+    pub fn new(address: Ipv4Net) -> Self {
+        Self {
+            name: None,
+            address,
+            address6: None,
+            mtu: DEFAULT_MTU,
+            queues: 1,
+            offload: false,
+            persistent: false,
+        }
+    }
+
+    /// Bytes one read from the device can return: a GSO frame with
+    /// [`offload`](Self::offload), else a packet of up to the MTU, which a
+    /// [`persistent`](Self::persistent) interface may have set to anything.
+    pub fn read_len(&self) -> usize {
+        if self.offload {
+            proto::gso::MAX_GSO_FRAME
+        } else if self.persistent {
+            usize::from(u16::MAX)
+        } else {
+            usize::from(self.mtu)
+        }
+    }
+
+    /// Creates the TUN interface, assigns its addresses and brings it up.
+    /// The OS destroys it when the device is dropped or the process exits.
+    /// A [`persistent`](Self::persistent) interface is only attached to.
+    pub fn create(&self) -> anyhow::Result<tun_rs::AsyncDevice> {
+        let mut builder = tun_rs::DeviceBuilder::new();
+        if self.persistent {
+            let Some(name) = &self.name else {
+                anyhow::bail!("attaching to a persistent TUN device needs its name");
+            };
+            builder = builder.name(name).inherit_enable_state();
+        } else {
+            builder = builder
+                .ipv4(self.address.addr(), self.address.prefix_len(), None)
+                .mtu(self.mtu);
+            if let Some(name) = &self.name {
+                builder = builder.name(name);
+            }
+            if let Some(address6) = self.address6 {
+                builder = builder.ipv6(address6.addr(), address6.prefix_len());
+            }
+        }
+        #[cfg(target_os = "linux")]
+        {
+            builder = builder.multi_queue(self.queues > 1).offload(self.offload);
+        }
+        Ok(builder.build_async()?)
+    }
+
+    /// Creates the interface like [`create`](Self::create), returning one
+    /// device per queue. The kernel spreads the packets it routes into the
+    /// interface over the queues by flow, and each queue is read and
+    /// written on its own, so several tasks can share the packet load.
+    #[cfg(target_os = "linux")]
+    pub fn create_queues(&self) -> anyhow::Result<Vec<tun_rs::AsyncDevice>> {
+        anyhow::ensure!(self.queues > 0, "a TUN device needs at least one queue");
+        let first = self.create()?;
+        let mut queues = Vec::with_capacity(self.queues);
+        for _ in 1..self.queues {
+            queues.push(first.try_clone()?);
+        }
+        queues.insert(0, first);
+        Ok(queues)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tun_config_new() {
+        let config = TunConfig::new("10.8.0.1/24".parse().unwrap());
+        assert_eq!(config.address.addr().to_string(), "10.8.0.1");
+        assert_eq!(config.address.prefix_len(), 24);
+        assert_eq!(config.name, None);
+        assert_eq!(config.address6, None);
+        assert_eq!(config.mtu, DEFAULT_MTU);
+        assert_eq!(config.queues, 1);
+        assert!(!config.offload);
+        assert!(!config.persistent);
+    }
+
+    #[test]
+    fn test_read_len() {
+        let mut config = TunConfig::new("10.8.0.1/24".parse().unwrap());
+        config.mtu = 9000;
+        assert_eq!(config.read_len(), 9000);
+        config.offload = true;
+        assert_eq!(config.read_len(), proto::gso::MAX_GSO_FRAME);
+    }
+}
@@ -4,6 +4,12 @@ use std::process::Command;
 
 fn main() {
     println!("cargo:rerun-if-changed=../x2ssh-agent/");
+    println!("cargo:rerun-if-changed=../x2ssh-net/");
+
+    // Without the embedded agent, VPN mode runs one installed on the server.
+    if env::var_os("CARGO_FEATURE_EMBED_AGENT").is_none() {
+        return;
+    }
 
     let out_dir = env::var("OUT_DIR").expect("OUT_DIR not set");
     let cargo = env::var("CARGO").expect("CARGO not set");
@@ -0,0 +1,305 @@
+//! How a session logs in to the server. [`TransportConfig::auth`] lists the
+//! [`AuthMethod`]s to try, in order, on every connect and reconnect until
+//! the server accepts one.
+//!
+//! x2ssh itself uses [`PublicKeyAuth`] with the identity file. Library users
+//! can also log in through the SSH agent ([`AgentAuth`]) or with a password
+//! ([`PasswordAuth`]), or implement [`AuthMethod`] for anything else, such
+//! as a certificate fetched from a CA just before each login.
+//!
+//! [`TransportConfig::auth`]: crate::transport::TransportConfig::auth
+
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use futures::future::BoxFuture;
+use russh::keys::HashAlg;
+use russh::keys::PrivateKey;
+use russh::keys::PrivateKeyWithHashAlg;
+use russh::keys::agent::client::AgentClient;
+use tracing::debug;
+
+use crate::retry::ErrorClass;
+use crate::secret::SecretSource;
+use crate::transport::SessionHandle;
+use crate::transport::rsa_hash;
+
+/// What a login is for.
+#[derive(Debug, Clone, Copy)]
+pub struct AuthContext<'a> {
+    pub user: &'a str,
+    /// Whether `ssh-rsa` (SHA-1) signatures are allowed; see
+    /// [`TransportConfig::legacy_server`](crate::transport::TransportConfig::legacy_server).
+    pub legacy_server: bool,
+}
+
+impl AuthContext<'_> {
+    /// The hash to sign with an RSA key, going by what the server announced.
+    /// `true` alongside it when the server announced no SHA-2 signatures.
+    pub async fn rsa_hash(
+        &self,
+        session: &SessionHandle,
+    ) -> anyhow::Result<(Option<HashAlg>, bool)> {
+        let advertised = session.best_supported_rsa_hash().await?;
+        Ok((
+            rsa_hash(advertised, self.legacy_server),
+            !matches!(advertised, Some(Some(_))),
+        ))
+    }
+}
+
+/// How one attempt to log in ended, short of an error.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AuthOutcome {
+    Accepted,
+    /// The server refused, or the method had nothing to offer it; with why,
+    /// when the method can tell.
+    Rejected {
+        hint: Option<String>,
+    },
+}
+
+/// A way to log in. Errors end the connect attempt; wrap those that
+/// retrying cannot fix, such as an unreadable key, in
+/// [`ErrorClass::Auth`].
+pub trait AuthMethod: Send + Sync + 'static {
+    /// What to call it in logs and errors, e.g. `publickey ~/.ssh/id_ed25519`.
+    fn name(&self) -> String;
+
+    /// Tries to log in on `session`, which has not authenticated yet.
+    fn authenticate<'a>(
+        &'a self,
+        session: &'a mut SessionHandle,
+        context: AuthContext<'a>,
+    ) -> BoxFuture<'a, anyhow::Result<AuthOutcome>>;
+}
+
+/// Tries `methods` in order until the server accepts one.
+pub(crate) async fn authenticate(
+    session: &mut SessionHandle,
+    methods: &[Arc<dyn AuthMethod>],
+    context: AuthContext<'_>,
+) -> anyhow::Result<()> {
+    let mut hints = Vec::new();
+    for method in methods {
+        match method.authenticate(session, context).await? {
+            AuthOutcome::Accepted => {
+                debug!("Logged in as {} with {}", context.user, method.name());
+                return Ok(());
+            }
+            AuthOutcome::Rejected { hint } => hints.extend(hint),
+        }
+    }
+    let tried: Vec<String> = methods.iter().map(|method| method.name()).collect();
+    Err(ErrorClass::Auth.wrap(anyhow::anyhow!(failure_message(&tried, &hints))))
+}
+
+fn failure_message(tried: &[String], hints: &[String]) -> String {
+    let mut message = "Authentication failed".to_string();
+    if tried.len() > 1 {
+        message.push_str(&format!(" with {}", tried.join(", ")));
+    }
+    for hint in hints {
+        message.push_str("; ");
+        message.push_str(hint);
+    }
+    message
+}
+
+/// Signs with a private key file, decrypting it with the passphrase from
+/// `passphrase` if it is encrypted. The key is read on each login and the
+/// passphrase wiped once it is decrypted.
+pub struct PublicKeyAuth {
+    path: PathBuf,
+    passphrase: Option<SecretSource>,
+}
+
+impl PublicKeyAuth {
+    pub fn new(path: PathBuf, passphrase: Option<SecretSource>) -> Self {
+        Self { path, passphrase }
+    }
+}
+
+impl AuthMethod for PublicKeyAuth {
+    fn name(&self) -> String {
+        format!("publickey {}", self.path.display())
+    }
+
+    fn authenticate<'a>(
+        &'a self,
+        session: &'a mut SessionHandle,
+        context: AuthContext<'a>,
+    ) -> BoxFuture<'a, anyhow::Result<AuthOutcome>> {
+        Box::pin(async move {
+            let key = load_key(&self.path, self.passphrase.as_ref())
+                .map_err(|e| ErrorClass::Auth.wrap(e))?;
+            let (hash, sha1_only) = context.rsa_hash(session).await?;
+            let sha1_only = sha1_only && key.algorithm().is_rsa();
+            let result = session
+                .authenticate_publickey(
+                    context.user,
+                    PrivateKeyWithHashAlg::new(Arc::new(key), hash),
+                )
+                .await?;
+            if result.success() {
+                return Ok(AuthOutcome::Accepted);
+            }
+            Ok(AuthOutcome::Rejected {
+                hint: (sha1_only && !context.legacy_server).then(|| {
+                    "the server did not announce SHA-2 RSA signatures, so it may only accept \
+                     ssh-rsa (try --legacy-server)"
+                        .to_string()
+                }),
+            })
+        })
+    }
+}
+
+/// Signs with each key the SSH agent at `SSH_AUTH_SOCK` holds, in turn.
+/// Without a reachable agent it is rejected, leaving the login to the next
+/// method.
+pub struct AgentAuth;
+
+impl AuthMethod for AgentAuth {
+    fn name(&self) -> String {
+        "agent".to_string()
+    }
+
+    fn authenticate<'a>(
+        &'a self,
+        session: &'a mut SessionHandle,
+        context: AuthContext<'a>,
+    ) -> BoxFuture<'a, anyhow::Result<AuthOutcome>> {
+        Box::pin(async move {
+            let mut agent = match AgentClient::connect_env().await {
+                Ok(agent) => agent,
+                Err(e) => {
+                    return Ok(AuthOutcome::Rejected {
+                        hint: Some(format!("no SSH agent: {}", e)),
+                    });
+                }
+            };
+            let keys = agent
+                .request_identities()
+                .await
+                .map_err(|e| anyhow::anyhow!("cannot list the SSH agent's keys: {}", e))?;
+            if keys.is_empty() {
+                return Ok(AuthOutcome::Rejected {
+                    hint: Some("the SSH agent has no keys".to_string()),
+                });
+            }
+            let (hash, _) = context.rsa_hash(session).await?;
+            for key in keys {
+                let hash = key.algorithm().is_rsa().then_some(hash).flatten();
+                let result = session
+                    .authenticate_publickey_with(context.user, key, hash, &mut agent)
+                    .await?;
+                if result.success() {
+                    return Ok(AuthOutcome::Accepted);
+                }
+            }
+            Ok(AuthOutcome::Rejected { hint: None })
+        })
+    }
+}
+
+/// Sends a password, read from `source` on each login.
+pub struct PasswordAuth {
+    source: SecretSource,
+}
+
+impl PasswordAuth {
+    pub fn new(source: SecretSource) -> Self {
+        Self { source }
+    }
+}
+
+impl AuthMethod for PasswordAuth {
+    fn name(&self) -> String {
+        "password".to_string()
+    }
+
+    fn authenticate<'a>(
+        &'a self,
+        session: &'a mut SessionHandle,
+        context: AuthContext<'a>,
+    ) -> BoxFuture<'a, anyhow::Result<AuthOutcome>> {
+        Box::pin(async move {
+            let password = self.source.read().map_err(|e| ErrorClass::Auth.wrap(e))?;
+            let result = session
+                .authenticate_password(context.user, password.as_str())
+                .await?;
+            Ok(if result.success() {
+                AuthOutcome::Accepted
+            } else {
+                AuthOutcome::Rejected { hint: None }
+            })
+        })
+    }
+}
+
+/// Loads the private key at `path`, decrypting it with the passphrase from
+/// `passphrase` if it is encrypted.
+fn load_key(path: &Path, passphrase: Option<&SecretSource>) -> anyhow::Result<PrivateKey> {
+    match russh::keys::load_secret_key(path, None) {
+        Err(russh::keys::Error::KeyIsEncrypted) => {
+            let Some(source) = passphrase else {
+                anyhow::bail!(
+                    "{} is encrypted; set passphrase_cmd or passphrase_keyring under [connection]",
+                    path.display()
+                );
+            };
+            let passphrase = source.read()?;
+            russh::keys::load_secret_key(path, Some(&passphrase)).map_err(|e| {
+                anyhow::anyhow!(
+                    "cannot decrypt {} with the passphrase from {}: {}",
+                    path.display(),
+                    source,
+                    e
+                )
+            })
+        }
+        result => Ok(result?),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encrypted_key() {
+        let keys = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("../tests/fixtures/keys");
+        let encrypted = keys.join("id_ed25519_encrypted");
+        let passphrase = |command: &str| SecretSource::Command(command.to_string());
+
+        assert!(load_key(&keys.join("id_ed25519"), None).is_ok());
+        let err = load_key(&encrypted, None).unwrap_err();
+        assert!(err.to_string().ends_with(
+            "is encrypted; set passphrase_cmd or passphrase_keyring under [connection]"
+        ));
+        let key = load_key(&encrypted, Some(&passphrase("echo x2ssh-test"))).unwrap();
+        assert_eq!(key.comment(), "encrypted-test-key");
+        let err = load_key(&encrypted, Some(&passphrase("echo wrong"))).unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("with the passphrase from command `echo wrong`")
+        );
+    }
+
+    #[test]
+    fn failure_message_names_methods_and_hints() {
+        let key = "publickey /keys/id_rsa".to_string();
+        assert_eq!(
+            failure_message(std::slice::from_ref(&key), &[]),
+            "Authentication failed"
+        );
+        assert_eq!(
+            failure_message(&[key, "agent".to_string()], &[
+                "the SSH agent has no keys".to_string()
+            ]),
+            "Authentication failed with publickey /keys/id_rsa, agent; the SSH agent has no keys"
+        );
+    }
+}
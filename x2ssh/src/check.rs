@@ -0,0 +1,274 @@
+//! Semantic checks of a config, for `x2ssh config validate`: everything a
+//! session would refuse at start, plus settings that parse but are likely
+//! mistakes. All findings are collected rather than stopping at the first.
+
+#[cfg(feature = "vpn")]
+mod vpn;
+
+use crate::config::AppConfig;
+use crate::config::SocksConfig;
+use crate::socks::Acl;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    /// A session with this config would fail to start or misbehave.
+    Error,
+    /// Accepted, but probably not what was meant.
+    Warning,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Finding {
+    pub severity: Severity,
+    pub message: String,
+}
+
+impl std::fmt::Display for Finding {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.severity {
+            Severity::Error => write!(f, "error: {}", self.message),
+            Severity::Warning => write!(f, "warning: {}", self.message),
+        }
+    }
+}
+
+#[derive(Default)]
+struct Findings(Vec<Finding>);
+
+impl Findings {
+    fn error(&mut self, message: impl std::fmt::Display) {
+        self.0.push(Finding {
+            severity: Severity::Error,
+            message: message.to_string(),
+        });
+    }
+
+    fn warning(&mut self, message: impl std::fmt::Display) {
+        self.0.push(Finding {
+            severity: Severity::Warning,
+            message: message.to_string(),
+        });
+    }
+
+    /// The value of `result`, recording its error.
+    fn ok<T>(&mut self, result: anyhow::Result<T>) -> Option<T> {
+        result.map_err(|e| self.error(format!("{:#}", e))).ok()
+    }
+}
+
+/// Checks `config`, errors and warnings in the order of the config file.
+pub fn check(config: &AppConfig) -> Vec<Finding> {
+    let mut findings = Findings::default();
+    #[cfg(feature = "vpn")]
+    vpn::check_vpn(&config.vpn, &mut findings);
+
+    let connection = &config.connection;
+    if connection.port == 0 {
+        findings.error("connection.port must not be 0");
+    }
+    findings.ok(connection.passphrase());
+    if let Some(host_key) = &connection.host_key {
+        findings.ok(crate::transport::parse_host_key(host_key));
+    }
+    if let Some(identity) = &connection.identity
+        && !identity.exists()
+    {
+        findings.warning(format!(
+            "connection.identity {} does not exist",
+            identity.display()
+        ));
+    }
+
+    let retry = &config.retry;
+    if retry.backoff < 1.0 {
+        findings.warning("retry.backoff below 1.0 makes retries come faster, not slower");
+    }
+    if retry.initial_delay > retry.max_delay {
+        findings.warning("retry.initial_delay is longer than retry.max_delay");
+    }
+    findings.ok(retry.health_options());
+    if let Some(max_total_duration) = retry.max_total_duration
+        && max_total_duration < retry.initial_delay
+    {
+        findings.warning(
+            "retry.max_total_duration is shorter than retry.initial_delay, so a lost connection \
+             is never retried",
+        );
+    }
+
+    check_socks(&config.socks, &mut findings);
+    findings.ok(config.forward.forwards());
+
+    if let Some(key_file) = &config.journal.key_file
+        && !key_file.exists()
+    {
+        findings.warning(format!(
+            "journal.key_file {} does not exist",
+            key_file.display()
+        ));
+    }
+    findings.0
+}
+
+fn check_socks(config: &SocksConfig, findings: &mut Findings) {
+    // Only where secrets come from is checked; reading them could run
+    // commands or prompt.
+    if let Some(auth) = &config.auth {
+        findings.ok(auth.source());
+    }
+    findings.ok(Acl::parse(&config.acl));
+    let Some(listen) = &config.listen else {
+        return;
+    };
+    match crate::socks::parse_listen(listen) {
+        Ok(addr) => {
+            if !addr.ip().is_loopback() && config.auth.is_none() && config.acl.is_empty() {
+                findings.warning(format!(
+                    "socks.listen {} is reachable from other hosts with neither auth nor acl set: \
+                     anyone who can connect can use the proxy",
+                    listen
+                ));
+            }
+        }
+        Err(e) => findings.error(format!("socks.listen: {}", e)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn messages(config: &AppConfig) -> Vec<String> {
+        check(config).iter().map(ToString::to_string).collect()
+    }
+
+    #[test]
+    fn test_default_config_is_clean() {
+        assert_eq!(messages(&AppConfig::default()), Vec::<String>::new());
+    }
+
+    #[cfg(feature = "vpn")]
+    #[test]
+    fn test_addresses() {
+        let mut config = AppConfig::default();
+        config.vpn.server_address = "10.9.0.1/24".to_string();
+        assert_eq!(messages(&config), ["error: client_address 10.8.0.2/24 \
+                                        and server_address 10.9.0.1/24 are \
+                                        not in the same subnet"]);
+
+        config.vpn.server_address = "10.8.0.2/24".to_string();
+        assert_eq!(messages(&config), ["error: client_address and \
+                                        server_address are the same address \
+                                        10.8.0.2"]);
+
+        config.vpn.server_address = "10.8.0.300/24".to_string();
+        let messages = messages(&config);
+        assert_eq!(messages.len(), 1);
+        assert!(messages[0].starts_with("error: invalid server_address"));
+    }
+
+    #[cfg(feature = "vpn")]
+    #[test]
+    fn test_overlapping_routes() {
+        let mut config = AppConfig::default();
+        config.vpn.include = vec!["10.0.0.0/8".to_string(), "172.16.0.0/12".to_string()];
+        config.vpn.exclude = vec!["10.1.0.0/16".to_string(), "10.8.0.0/16".to_string()];
+        config.vpn.routes = vec!["172.16.0.0/12 via lan".to_string()];
+        assert_eq!(messages(&config), [
+            "warning: include 10.0.0.0/8 overlaps exclude 10.1.0.0/16; the more specific route \
+             wins",
+            "warning: include 10.0.0.0/8 overlaps exclude 10.8.0.0/16; the more specific route \
+             wins",
+            "error: include 172.16.0.0/12 and route 172.16.0.0/12 ask for the same network both \
+             through and around the tunnel",
+            "warning: exclude 10.8.0.0/16 overlaps the tunnel subnet 10.8.0.0/24",
+        ]);
+    }
+
+    #[cfg(feature = "vpn")]
+    #[test]
+    fn test_zero_intervals() {
+        let mut config = AppConfig::default();
+        config.vpn.roaming_interval = std::time::Duration::ZERO;
+        assert_eq!(messages(&config), [
+            "error: roaming_interval must be longer than 0"
+        ]);
+
+        let mut config = AppConfig::default();
+        config.vpn.keepalive_interval = std::time::Duration::ZERO;
+        assert_eq!(messages(&config), [
+            "error: keepalive_interval must be longer than 0"
+        ]);
+
+        let mut config = AppConfig::default();
+        config.retry.health_interval = std::time::Duration::ZERO;
+        assert_eq!(messages(&config), [
+            "error: retry.health_interval must be longer than 0"
+        ]);
+    }
+
+    #[test]
+    fn test_socks() {
+        let mut config = AppConfig::default();
+        config.socks.listen = Some("1080".to_string());
+        assert_eq!(messages(&config), Vec::<String>::new());
+
+        config.socks.listen = Some("0.0.0.0:1080".to_string());
+        assert_eq!(messages(&config), ["warning: socks.listen 0.0.0.0:1080 \
+                                        is reachable from other hosts with \
+                                        neither auth nor acl set: anyone who \
+                                        can connect can use the proxy"]);
+        config.socks.acl = vec!["allow 192.168.0.0/16".to_string()];
+        assert_eq!(messages(&config), Vec::<String>::new());
+
+        config.socks.auth = Some(crate::config::SocksAuthConfig {
+            username: "alice".to_string(),
+            password: None,
+            password_file: None,
+            password_cmd: Some("exit 1".to_string()),
+            password_keyring: Some("socks".to_string()),
+        });
+        assert_eq!(messages(&config), ["error: socks auth: password_cmd and \
+                                        password_keyring are both set; use \
+                                        only one of password, password_file, \
+                                        password_cmd, password_keyring"]);
+        config.socks.auth = None;
+
+        config.socks.listen = Some("localhost:1080".to_string());
+        config.socks.acl = vec!["allow 192.168.0.0/16 deny".to_string()];
+        let messages = messages(&config);
+        assert_eq!(messages.len(), 2, "{messages:?}");
+        assert!(messages[0].starts_with("error: invalid acl rule"));
+        assert!(messages[1].starts_with("error: socks.listen: Invalid SOCKS address"));
+    }
+
+    #[test]
+    fn test_forwards() {
+        let mut config = AppConfig::default();
+        config.forward.local = vec!["8080:db:5432".to_string()];
+        assert_eq!(messages(&config), Vec::<String>::new());
+
+        config.forward.local.push("8080:db".to_string());
+        assert_eq!(messages(&config), ["error: forward.local: invalid \
+                                        forward '-L 8080:db': expected \
+                                        [BIND:]PORT:HOST:HOSTPORT"]);
+    }
+
+    #[cfg(feature = "vpn")]
+    #[test]
+    fn test_hooks() {
+        let mut config = AppConfig::default();
+        config.vpn.post_up = vec![
+            "iptables -A FORWARD -i %i -j ACCEPT".to_string(),
+            "echo %server_ip".to_string(),
+        ];
+        config.vpn.local_post_up = vec!["if true; then echo".to_string(), " ".to_string()];
+        let messages = messages(&config);
+        assert_eq!(messages.len(), 3, "{messages:?}");
+        assert!(messages[0].starts_with("error: post_up: 'iptables"));
+        assert!(messages[0].contains("server_tun"), "{}", messages[0]);
+        assert!(messages[1].starts_with("error: local_post_up command `if true; then echo`"));
+        assert!(messages[1].contains("not valid sh"), "{}", messages[1]);
+        assert_eq!(messages[2], "error: local_post_up has an empty command");
+    }
+}
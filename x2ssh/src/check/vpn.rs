@@ -0,0 +1,190 @@
+//! Checks of the `[vpn]` section, which only a build with VPN mode has.
+
+use std::net::IpAddr;
+use std::net::Ipv4Addr;
+use std::net::SocketAddr;
+
+use ipnet::IpNet;
+use ipnet::Ipv6Net;
+
+use super::Findings;
+use crate::config::RouteVia;
+use crate::config::VpnConfig;
+use crate::vpn::domains::DomainRules;
+use crate::vpn::hooks::HookVars;
+
+/// Stands in for the SSH server's address when hooks are expanded.
+const SSH_HOST: IpAddr = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+
+pub(super) fn check_vpn(config: &VpnConfig, findings: &mut Findings) {
+    findings.ok(crate::vpn::session::validate(config));
+    findings.ok(config.sudo_password());
+
+    let client = findings.ok(config.parse_client_address());
+    let server = findings.ok(config.parse_server_address());
+    if let (Some((client_ip, client_net)), Some((server_ip, server_net))) = (client, server) {
+        check_pair("", client_ip, client_net, server_ip, server_net, findings);
+    }
+    let ipv6 = findings.ok(config.ipv6_addresses()).flatten();
+    if let Some((client, server)) = ipv6 {
+        check_pair("6", client.addr(), client, server.addr(), server, findings);
+        if config.mtu < 1280 {
+            findings.error(format!(
+                "mtu {} is below the 1280 bytes IPv6 needs (client_address6 is set)",
+                config.mtu
+            ));
+        }
+    }
+    if config.mtu < 576 {
+        findings.warning(format!(
+            "mtu {} is below the 576 bytes every IPv4 host must accept",
+            config.mtu
+        ));
+    }
+
+    // Routes into the tunnel and around it, by the key that asked for them.
+    let mut tunnel = Vec::new();
+    let mut around = Vec::new();
+    for include in &config.include {
+        match include.parse::<IpNet>() {
+            Ok(net) => tunnel.push((format!("include {}", include), net.trunc())),
+            Err(e) => findings.error(format!("invalid include '{}': {}", include, e)),
+        }
+    }
+    for exclusion in &config.exclude {
+        match exclusion.parse::<IpNet>() {
+            Ok(net) => around.push((format!("exclude {}", exclusion), net.trunc())),
+            Err(e) => findings.error(format!("invalid exclude '{}': {}", exclusion, e)),
+        }
+    }
+    for route in findings.ok(config.static_routes()).unwrap_or_default() {
+        let name = format!("route {}", route.destination);
+        match route.via {
+            RouteVia::Tun => tunnel.push((name, route.destination)),
+            RouteVia::Lan => around.push((name, route.destination)),
+        }
+    }
+    for (tunnel_name, tunnel_net) in &tunnel {
+        for (around_name, around_net) in &around {
+            if tunnel_net == around_net {
+                findings.error(format!(
+                    "{} and {} ask for the same network both through and around the tunnel",
+                    tunnel_name, around_name
+                ));
+            } else if tunnel_net.contains(around_net) || around_net.contains(tunnel_net) {
+                findings.warning(format!(
+                    "{} overlaps {}; the more specific route wins",
+                    tunnel_name, around_name
+                ));
+            }
+        }
+    }
+    if let Some((_, network)) = client {
+        for (name, net) in &around {
+            if net.contains(&network.trunc()) || network.trunc().contains(net) {
+                findings.warning(format!(
+                    "{} overlaps the tunnel subnet {}",
+                    name,
+                    network.trunc()
+                ));
+            }
+        }
+    }
+
+    findings.ok(DomainRules::parse(&config.domains));
+
+    if config.dns64 {
+        if let Err(e) = config.nat64_prefix.parse::<Ipv6Net>() {
+            findings.error(format!(
+                "invalid nat64_prefix '{}': {}",
+                config.nat64_prefix, e
+            ));
+        }
+        if let Err(e) = config.dns64_listen.parse::<SocketAddr>() {
+            findings.error(format!(
+                "invalid dns64_listen '{}': {}",
+                config.dns64_listen, e
+            ));
+        }
+    }
+
+    if client.is_some() && server.is_some() {
+        check_hooks(config, findings);
+    }
+}
+
+/// Checks that a client and server address (`suffix` "" or "6") share
+/// their subnet.
+fn check_pair(
+    suffix: &str,
+    client_ip: IpAddr,
+    client_net: IpNet,
+    server_ip: IpAddr,
+    server_net: IpNet,
+    findings: &mut Findings,
+) {
+    if client_ip == server_ip {
+        findings.error(format!(
+            "client_address{suffix} and server_address{suffix} are the same address {}",
+            client_ip
+        ));
+    } else if client_net.trunc() != server_net.trunc() {
+        findings.error(format!(
+            "client_address{suffix} {} and server_address{suffix} {} are not in the same subnet",
+            client_net, server_net
+        ));
+    }
+}
+
+/// Expands every hook as it would be run and has `sh -n` check its syntax.
+fn check_hooks(config: &VpnConfig, findings: &mut Findings) {
+    let (Some(remote), Some(local)) = (
+        findings.ok(HookVars::remote(config, SSH_HOST)),
+        findings.ok(HookVars::local(config, SSH_HOST)),
+    ) else {
+        return;
+    };
+    let hooks = [
+        ("post_up", &config.post_up, &remote),
+        ("pre_down", &config.pre_down, &remote),
+        ("local_post_up", &config.local_post_up, &local),
+        ("local_pre_down", &config.local_pre_down, &local),
+    ];
+    for (key, commands, vars) in hooks {
+        for command in commands {
+            if command.trim().is_empty() {
+                findings.error(format!("{} has an empty command", key));
+                continue;
+            }
+            let expanded = match vars.expand(command) {
+                Ok(expanded) => expanded,
+                Err(e) => {
+                    findings.error(format!("{}: {}", key, e));
+                    continue;
+                }
+            };
+            if let Err(e) = shell_syntax(&expanded) {
+                findings.error(format!("{} command `{}`: {}", key, command, e));
+            }
+        }
+    }
+}
+
+#[cfg(unix)]
+fn shell_syntax(command: &str) -> Result<(), String> {
+    let output = std::process::Command::new("sh")
+        .args(["-n", "-c", command])
+        .stdin(std::process::Stdio::null())
+        .output()
+        .map_err(|e| format!("cannot run sh to check it: {}", e))?;
+    if output.status.success() {
+        return Ok(());
+    }
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    Err(format!("not valid sh: {}", stderr.trim()))
+}
+
+#[cfg(not(unix))]
+fn shell_syntax(_command: &str) -> Result<(), String> {
+    Ok(())
+}
@@ -1,46 +1,479 @@
+use std::collections::BTreeMap;
 use std::net::IpAddr;
 use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
 
 use ipnet::IpNet;
 use serde::Deserialize;
+use serde::Serialize;
+use zeroize::Zeroizing;
 
-#[derive(Debug, Clone, Default, Deserialize)]
+use crate::forward::Forward;
+use crate::otel::TraceOptions;
+use crate::retry::DegradeThresholds;
+use crate::secret::SecretSource;
+use crate::socks::Acl;
+use crate::socks::CircuitBreaker;
+use crate::socks::Credentials;
+use crate::socks::ResolvePolicy;
+use crate::socks::Resolvers;
+use crate::socks::SocksOptions;
+use crate::transport::HealthOptions;
+use crate::transport::TcpOptions;
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct AppConfig {
+    /// Other config files laid over this one in order, relative to its
+    /// directory; `*` and `?` may be used in file names (`hosts/*.toml`).
+    /// [`AppConfig::load`] resolves them and leaves this empty.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub include: Vec<String>,
     #[serde(default)]
     pub vpn: VpnConfig,
     #[serde(default)]
     pub connection: ConnectionConfig,
     #[serde(default)]
     pub retry: RetryConfig,
+    #[serde(default)]
+    pub socks: SocksConfig,
+    #[serde(default)]
+    pub forward: ForwardConfig,
+    #[serde(default)]
+    pub journal: JournalConfig,
+    #[serde(default)]
+    pub telemetry: TelemetryConfig,
+    #[serde(default)]
+    pub stats: StatsConfig,
+    /// Named variants of the config (`[profiles.work]`, ...), each holding
+    /// any of the sections above. Selecting one with
+    /// [`AppConfig::from_toml_profile`] lays its keys over the top-level
+    /// ones.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub profiles: BTreeMap<String, toml::Table>,
 }
 
+/// A config with every setting commented out at its default, as `x2ssh
+/// config init` writes it.
+pub const TEMPLATE: &str = include_str!("../config.example.toml");
+
+/// Prefix of the environment variables that override config keys:
+/// `X2SSH_<SECTION>_<KEY>`, e.g. `X2SSH_VPN_MTU` for `mtu` under `[vpn]`.
+/// `__` steps into a nested table, as in `X2SSH_SOCKS_AUTH__USERNAME` for
+/// `username` under `[socks.auth]`. Profiles cannot be set this way.
+pub const ENV_PREFIX: &str = "X2SSH_";
+
+/// The sections environment variables can address.
+const ENV_SECTIONS: &[&str] = &[
+    "vpn",
+    "connection",
+    "retry",
+    "socks",
+    "forward",
+    "journal",
+    "telemetry",
+    "stats",
+];
+
 impl AppConfig {
+    /// Reads a config file and the files it includes, each merged over
+    /// the one before like a profile. Errors name the file they are in.
     pub fn load(path: &Path) -> anyhow::Result<Self> {
-        let content = std::fs::read_to_string(path)?;
-        let config: AppConfig = toml::from_str(&content)?;
+        let table = load_table(path, &mut Vec::new())?;
+        let config: Self = toml::Value::Table(table.clone())
+            .try_into()
+            .map_err(config_error)?;
+        for name in config.profiles.keys() {
+            Self::with_profile(table.clone(), name)?;
+        }
         Ok(config)
     }
+
+    /// [`AppConfig::load`] with the profile `name` applied.
+    pub fn load_profile(path: &Path, name: &str) -> anyhow::Result<Self> {
+        Self::table_profile(load_table(path, &mut Vec::new())?, name)
+    }
+
+    /// Parses a TOML config. Unknown keys are rejected, with a suggestion
+    /// when one is close to a known key; every profile is checked the same
+    /// way. `include` needs a file to resolve against, see
+    /// [`AppConfig::load`].
+    pub fn from_toml(content: &str) -> anyhow::Result<Self> {
+        let config = Self::parse(content)?;
+        if !config.include.is_empty() {
+            anyhow::bail!("include is only supported in config files");
+        }
+        Ok(config)
+    }
+
+    /// Parses a TOML config and applies the profile `name`: tables in the
+    /// profile are merged key by key into the top-level ones, other values
+    /// (including arrays) replace them.
+    pub fn from_toml_profile(content: &str, name: &str) -> anyhow::Result<Self> {
+        Self::from_toml(content)?;
+        Self::table_profile(toml::from_str(content)?, name)
+    }
+
+    /// [`AppConfig::from_toml`], leaving `include` as it is.
+    fn parse(content: &str) -> anyhow::Result<Self> {
+        let config: Self = toml::from_str(content).map_err(config_error)?;
+        if !config.profiles.is_empty() {
+            let table: toml::Table = toml::from_str(content)?;
+            for name in config.profiles.keys() {
+                Self::with_profile(table.clone(), name)?;
+            }
+        }
+        Ok(config)
+    }
+
+    fn table_profile(table: toml::Table, name: &str) -> anyhow::Result<Self> {
+        let profiles = table.get("profiles").and_then(toml::Value::as_table);
+        if !profiles.is_some_and(|profiles| profiles.contains_key(name)) {
+            let known: Vec<&str> = profiles
+                .map(|profiles| profiles.keys().map(String::as_str).collect())
+                .unwrap_or_default();
+            anyhow::bail!(
+                "unknown profile '{}'; the config defines: {}",
+                name,
+                if known.is_empty() {
+                    "none".to_string()
+                } else {
+                    known.join(", ")
+                }
+            );
+        }
+        Self::with_profile(table, name)
+    }
+
+    /// Applies the `X2SSH_<SECTION>_<KEY>` variables among `vars` over the
+    /// config. A value is read as TOML (`1400`, `true`, `["10.0.0.0/8"]`)
+    /// where its key takes that type, and otherwise as a string, so
+    /// `10.8.0.2/24`, `30s` or a `listen` port of `1080` need no quotes.
+    /// Other `X2SSH_` variables are ignored.
+    pub fn with_env(
+        self,
+        vars: impl IntoIterator<Item = (String, String)>,
+    ) -> anyhow::Result<Self> {
+        let vars: Vec<_> = vars
+            .into_iter()
+            .filter_map(|(name, value)| Some((env_key(&name)?, value)))
+            .collect();
+        if vars.is_empty() {
+            return Ok(self);
+        }
+
+        let toml::Value::Table(mut table) = toml::Value::try_from(&self)? else {
+            unreachable!("the config serializes as a table");
+        };
+        let mut overrides = toml::Table::new();
+        for ((section, key), value) in vars {
+            let mut typed = env_value(&value);
+            if !typed.is_str()
+                && !accepts(&table, &section, &key, typed.clone())
+                && accepts(&table, &section, &key, toml::Value::String(value.clone()))
+            {
+                typed = toml::Value::String(value);
+            }
+            merge_tables(&mut overrides, env_override(section, key, typed));
+        }
+        merge_tables(&mut table, overrides);
+        toml::Value::Table(table).try_into().map_err(|e| {
+            config_error(e).context(format!("in {}* environment variables", ENV_PREFIX))
+        })
+    }
+
+    fn with_profile(mut table: toml::Table, name: &str) -> anyhow::Result<Self> {
+        let Some(toml::Value::Table(mut profiles)) = table.remove("profiles") else {
+            anyhow::bail!("unknown profile '{}'", name);
+        };
+        let Some(toml::Value::Table(profile)) = profiles.remove(name) else {
+            anyhow::bail!("unknown profile '{}'", name);
+        };
+        if profile.contains_key("profiles") {
+            anyhow::bail!("profile '{}': profiles cannot be nested", name);
+        }
+        if profile.contains_key("include") {
+            anyhow::bail!("profile '{}': include only works at the top level", name);
+        }
+        merge_tables(&mut table, profile);
+        toml::Value::Table(table)
+            .try_into()
+            .map_err(|e| config_error(e).context(format!("in profile '{}'", name)))
+    }
+
+    /// Writes the config as TOML, creating parent directories as needed.
+    /// [`AppConfig::load`] reads back an identical config.
+    pub fn save(&self, path: &Path) -> anyhow::Result<()> {
+        if let Some(parent) = path.parent()
+            && !parent.as_os_str().is_empty()
+        {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::write(path, self.to_toml()?)?;
+        Ok(())
+    }
+
+    pub fn to_toml(&self) -> anyhow::Result<String> {
+        Ok(toml::to_string_pretty(self)?)
+    }
 }
 
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct VpnConfig {
     #[serde(default = "default_client_address")]
     pub client_address: String,
     #[serde(default = "default_server_address")]
     pub server_address: String,
+    /// Optional IPv6 client address with prefix; enables dual-stack when set
+    /// together with `server_address6`.
+    #[serde(default)]
+    pub client_address6: Option<String>,
+    #[serde(default)]
+    pub server_address6: Option<String>,
     #[serde(default = "default_client_tun")]
     pub client_tun: String,
+    /// Attach to an existing `client_tun` (created once by root with `ip
+    /// tuntap add mode tun user <USER>` or systemd-networkd) instead of
+    /// creating it. Its addresses, MTU and routes are left to the system,
+    /// so x2ssh then runs without root unless `kill_switch` is on.
+    #[serde(default)]
+    pub persistent_tun: bool,
+    /// TUN interface name on the server; the kernel picks `tunN` when unset.
+    #[serde(default)]
+    pub server_tun: Option<String>,
     #[serde(default = "default_mtu")]
     pub mtu: u16,
+    /// Queues of the client TUN device (Linux multiqueue), each with its
+    /// own reader and writer task, to spread packet work over CPU cores.
+    #[serde(default = "default_tun_queues")]
+    pub tun_queues: usize,
+    /// Linux only: create both TUN devices with offloads (TSO/GRO), so TCP
+    /// crosses the tunnel in segments of up to 64 KiB instead of one frame
+    /// per MTU-sized packet. Cannot be combined with `dns64`.
+    #[serde(default)]
+    pub offload: bool,
+    /// When non-empty, only these CIDRs are routed through the tunnel and
+    /// the default route is left alone (split tunnel).
+    #[serde(default)]
+    pub include: Vec<String>,
+    /// Domains whose addresses are routed through the tunnel, either exact
+    /// (`git.corp.example`) or wildcard (`*.corp.example`). Implies split
+    /// tunnel, like `include`.
+    #[serde(default)]
+    pub domains: Vec<String>,
     #[serde(default)]
     pub exclude: Vec<String>,
+    /// Extra routes installed with the tunnel and removed with it, as
+    /// `CIDR via tun` or `CIDR via lan` (the uplink the client had before
+    /// connecting).
+    #[serde(default)]
+    pub routes: Vec<String>,
+    /// Also exclude the client's directly-connected subnets, so local
+    /// machines stay reachable outside the tunnel.
+    #[serde(default)]
+    pub exclude_lan: bool,
     #[serde(default)]
     pub post_up: Vec<String>,
     #[serde(default)]
     pub pre_down: Vec<String>,
+    /// Commands run on the client through `sh -c` once the tunnel is up,
+    /// e.g. for local firewall tweaks or notifications.
+    #[serde(default)]
+    pub local_post_up: Vec<String>,
+    /// Commands run on the client before the tunnel goes down.
+    #[serde(default)]
+    pub local_pre_down: Vec<String>,
+    /// Block all traffic that bypasses the tunnel while the VPN is up.
+    #[serde(default)]
+    pub kill_switch: bool,
+    /// Drop TCP connection attempts (SYN without ACK) arriving through the
+    /// tunnel, so hosts on the server's side cannot connect to the client.
+    /// Replies to the client's own connections still pass.
+    #[serde(default)]
+    pub block_inbound: bool,
+    /// IP protocols dropped in both directions: numbers or `icmp`, `tcp`,
+    /// `udp`, `gre`, `esp`, `ah`, `icmpv6`, `sctp`.
+    #[serde(default)]
+    pub blocked_protocols: Vec<String>,
+    /// How full-tunnel routing is installed.
+    #[serde(default)]
+    pub routing_mode: RoutingMode,
+    /// Linux only: route just the processes of this cgroup v2 (a path
+    /// below `/sys/fs/cgroup`, created if missing) through the tunnel,
+    /// instead of the whole machine. Start programs in it with `x2ssh exec
+    /// -- CMD`. Uses `policy_table` for its table and fwmark.
+    #[serde(default)]
+    pub cgroup: Option<String>,
+    /// Routing table and fwmark used by `routing_mode = "policy"`.
+    #[serde(default = "default_policy_table")]
+    pub policy_table: u32,
+    /// Metric of the tunnel's default route with `routing_mode = "metric"`;
+    /// must be lower than the system's default route's.
+    #[serde(default = "default_route_metric")]
+    pub route_metric: u32,
+    #[serde(default = "default_roaming")]
+    pub roaming: bool,
+    #[serde(
+        default = "default_roaming_interval",
+        alias = "roaming_interval_ms",
+        with = "interval_serde"
+    )]
+    pub roaming_interval: Duration,
+    /// Put back the tunnel's routes when something else on the system
+    /// (NetworkManager, DHCP) overwrites them.
+    #[serde(default = "default_heal_routes")]
+    pub heal_routes: bool,
+    /// Once the tunnel is up, ping the server's tunnel address and fetch
+    /// `self_test_url` through it, reporting where traffic stops if it
+    /// does.
+    #[serde(default)]
+    pub self_test: bool,
+    /// `http://` URL fetched by the self-test; for `https://` only the
+    /// connection is made.
+    #[serde(default = "default_self_test_url")]
+    pub self_test_url: String,
+    /// How often to ping the agent over the tunnel channel.
+    #[serde(
+        default = "default_keepalive_interval",
+        alias = "keepalive_interval_ms",
+        with = "interval_serde"
+    )]
+    pub keepalive_interval: Duration,
+    /// Silence from the agent after which the tunnel is considered dead and
+    /// the SSH session is reconnected.
+    #[serde(
+        default = "default_keepalive_timeout",
+        alias = "keepalive_timeout_ms",
+        with = "interval_serde"
+    )]
+    pub keepalive_timeout: Duration,
+    /// How long the agent gets to create its TUN device and report ready
+    /// before the start fails with its error output.
+    #[serde(
+        default = "default_agent_start_timeout",
+        alias = "agent_start_timeout_ms",
+        with = "duration_serde"
+    )]
+    pub agent_start_timeout: Duration,
+    /// How long a packet waits for others to share its frame to or from
+    /// the agent, saving channel writes for small-packet traffic. Packets
+    /// already queued share a frame even with `0s`.
+    #[serde(
+        default = "default_batch_delay",
+        alias = "batch_delay_ms",
+        with = "duration_serde"
+    )]
+    pub batch_delay: Duration,
+    /// What a full forwarding queue does to the side reading packets in.
+    #[serde(default)]
+    pub queue_policy: QueuePolicy,
+    /// Compress frames to and from the agent with LZ4, for text-heavy
+    /// traffic over slow links. Frames that do not shrink are sent as they
+    /// are.
+    #[serde(default)]
+    pub compress: bool,
+    /// Frames shorter than this many bytes are never compressed.
+    #[serde(default = "default_compress_threshold")]
+    pub compress_threshold: usize,
+    /// For IPv6-only clients: run a local resolver that answers AAAA
+    /// queries for IPv4-only names with addresses in `nat64_prefix`, and
+    /// translate traffic to that prefix into IPv4. Needs a dual-stack
+    /// tunnel (`client_address6`/`server_address6`).
+    #[serde(default)]
+    pub dns64: bool,
+    #[serde(default = "default_nat64_prefix")]
+    pub nat64_prefix: String,
+    /// Where the DNS64 resolver listens; point the system resolver here.
+    #[serde(default = "default_dns64_listen")]
+    pub dns64_listen: String,
+    /// Resolver that DNS64 queries are sent to from the server, as `IP` or
+    /// `IP:PORT`. Defaults to the server's first `/etc/resolv.conf`
+    /// nameserver.
+    #[serde(default)]
+    pub dns64_upstream: Option<String>,
+    /// Absolute path of the agent binary on the server, kept between
+    /// sessions. By default each session uploads its own copy to the
+    /// server's runtime dir (or `~/.cache/x2ssh`) and removes it on exit.
+    /// A binary there that x2ssh did not upload is run, not overwritten.
+    #[serde(default)]
+    pub agent_path: Option<String>,
+    /// Upload the agent built into x2ssh when the server is missing it or
+    /// has another build. With `false`, the agent already installed at
+    /// `agent_path`, or as `x2ssh-agent` on the server's `PATH`, e.g. by a
+    /// package, is run as it is; a build without the embedded agent always
+    /// does that.
+    #[serde(default = "default_deploy_agent")]
+    pub deploy_agent: bool,
+    /// Leave the agent running and its per-session binary on the server
+    /// when the session ends, e.g. to debug the agent.
+    #[serde(default)]
+    pub keep_agent: bool,
+    /// Unix socket on the server where one agent serves several clients.
+    /// The first client's agent creates the server TUN device and serves it
+    /// there; later clients join it and are leased an address of the
+    /// subnet, `client_address` if it is free. Needs `agent_path`.
+    #[serde(default)]
+    pub shared_agent: Option<String>,
+    /// Have the agent enable IP forwarding and masquerade the tunnel subnet
+    /// on the server (nftables), instead of PostUp iptables commands.
+    /// Both are removed when the agent exits.
+    #[serde(default)]
+    pub nat: bool,
+    /// Have the agent answer DNS on the server's tunnel address (port 53),
+    /// forwarding to the server's resolver. Point the client's DNS there.
+    #[serde(default)]
+    pub agent_dns: bool,
+    /// How the agent is run as root on the server. Not needed when logging
+    /// in as root.
+    #[serde(default)]
+    pub elevation: Elevation,
+    /// Local file holding the server user's sudo password, for servers
+    /// without passwordless sudo. Fed to `sudo -S` over stdin.
+    #[serde(default)]
+    pub sudo_password_file: Option<PathBuf>,
+    /// Command printing that password on its first line, instead of the
+    /// file.
+    #[serde(default)]
+    pub sudo_password_cmd: Option<String>,
+    /// OS keyring account holding that password, instead of the file.
+    #[serde(default)]
+    pub sudo_password_keyring: Option<String>,
+    /// Local pcapng file receiving every packet read from or written to the
+    /// client TUN device, marked outbound or inbound, for Wireshark.
+    #[serde(default)]
+    pub pcap: Option<PathBuf>,
 }
 
 impl VpnConfig {
+    /// Where the server user's sudo password comes from, if anywhere.
+    pub fn sudo_password(&self) -> anyhow::Result<Option<SecretSource>> {
+        SecretSource::one_of([
+            (
+                "sudo_password_file",
+                self.sudo_password_file.clone().map(SecretSource::File),
+            ),
+            (
+                "sudo_password_cmd",
+                self.sudo_password_cmd.clone().map(SecretSource::Command),
+            ),
+            (
+                "sudo_password_keyring",
+                self.sudo_password_keyring
+                    .clone()
+                    .map(SecretSource::Keyring),
+            ),
+        ])
+    }
+
+    /// Whether only selected traffic is routed through the tunnel, leaving
+    /// the default route alone.
+    pub fn split_tunnel(&self) -> bool {
+        !self.include.is_empty() || !self.domains.is_empty()
+    }
+
     pub fn parse_client_address(&self) -> anyhow::Result<(IpAddr, IpNet)> {
         let net: IpNet = self.client_address.parse().map_err(|e| {
             anyhow::anyhow!("invalid client_address '{}': {}", self.client_address, e)
@@ -71,6 +504,37 @@ impl VpnConfig {
         let (_ip, net) = self.parse_client_address()?;
         Ok(net)
     }
+
+    /// Parses `routes`.
+    pub fn static_routes(&self) -> anyhow::Result<Vec<StaticRoute>> {
+        self.routes
+            .iter()
+            .map(|route| route.parse().map_err(|e: String| anyhow::anyhow!(e)))
+            .collect()
+    }
+
+    /// Returns the IPv6 `(client, server)` addresses when dual-stack is
+    /// configured. Both must be given, and both must be IPv6.
+    pub fn ipv6_addresses(&self) -> anyhow::Result<Option<(IpNet, IpNet)>> {
+        let (client, server) = match (&self.client_address6, &self.server_address6) {
+            (None, None) => return Ok(None),
+            (Some(client), Some(server)) => (client, server),
+            _ => anyhow::bail!("client_address6 and server_address6 must be set together"),
+        };
+
+        let parse = |name: &str, value: &str| -> anyhow::Result<IpNet> {
+            match value.parse() {
+                Ok(net @ IpNet::V6(_)) => Ok(net),
+                Ok(IpNet::V4(_)) => anyhow::bail!("{} '{}' is not an IPv6 address", name, value),
+                Err(e) => anyhow::bail!("invalid {} '{}': {}", name, value, e),
+            }
+        };
+
+        Ok(Some((
+            parse("client_address6", client)?,
+            parse("server_address6", server)?,
+        )))
+    }
 }
 
 impl Default for VpnConfig {
@@ -78,15 +542,186 @@ impl Default for VpnConfig {
         Self {
             client_address: default_client_address(),
             server_address: default_server_address(),
+            client_address6: None,
+            server_address6: None,
             client_tun: default_client_tun(),
+            server_tun: None,
             mtu: default_mtu(),
+            tun_queues: default_tun_queues(),
+            offload: false,
+            persistent_tun: false,
+            include: Vec::new(),
+            domains: Vec::new(),
             exclude: Vec::new(),
+            routes: Vec::new(),
+            exclude_lan: false,
             post_up: Vec::new(),
             pre_down: Vec::new(),
+            local_post_up: Vec::new(),
+            local_pre_down: Vec::new(),
+            kill_switch: false,
+            block_inbound: false,
+            blocked_protocols: Vec::new(),
+            routing_mode: RoutingMode::default(),
+            cgroup: None,
+            policy_table: default_policy_table(),
+            route_metric: default_route_metric(),
+            roaming: default_roaming(),
+            roaming_interval: default_roaming_interval(),
+            heal_routes: default_heal_routes(),
+            self_test: false,
+            self_test_url: default_self_test_url(),
+            keepalive_interval: default_keepalive_interval(),
+            keepalive_timeout: default_keepalive_timeout(),
+            agent_start_timeout: default_agent_start_timeout(),
+            batch_delay: default_batch_delay(),
+            queue_policy: QueuePolicy::default(),
+            compress: false,
+            compress_threshold: default_compress_threshold(),
+            dns64: false,
+            nat64_prefix: default_nat64_prefix(),
+            dns64_listen: default_dns64_listen(),
+            dns64_upstream: None,
+            agent_path: None,
+            deploy_agent: default_deploy_agent(),
+            keep_agent: false,
+            shared_agent: None,
+            nat: false,
+            agent_dns: false,
+            elevation: Elevation::default(),
+            sudo_password_file: None,
+            sudo_password_cmd: None,
+            sudo_password_keyring: None,
+            pcap: None,
+        }
+    }
+}
+
+/// How the tunnel becomes the default route.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RoutingMode {
+    /// Delete the default route and add one via the TUN device; restored on
+    /// cleanup.
+    #[default]
+    Replace,
+    /// Leave the main table alone: put the TUN default route in a separate
+    /// table and select it with `ip rule` (like wg-quick). A crash leaves at
+    /// worst unused rules behind; the system keeps its own default route.
+    Policy,
+    /// Add the TUN default route next to the system's, with a lower metric
+    /// (`route_metric`), and only remove it on cleanup. A DHCP client that
+    /// re-adds its default route does not displace the tunnel's.
+    Metric,
+}
+
+impl std::str::FromStr for RoutingMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "replace" => Ok(RoutingMode::Replace),
+            "policy" => Ok(RoutingMode::Policy),
+            "metric" => Ok(RoutingMode::Metric),
+            _ => Err(format!(
+                "invalid routing mode '{s}': expected replace, policy or metric"
+            )),
         }
     }
 }
 
+/// What happens to a packet when the queue in front of its writer is full,
+/// i.e. the SSH channel or the TUN device falls behind a burst.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum QueuePolicy {
+    /// Drop and count the packet, as a router does, so the reader keeps
+    /// going: the agent reader still sees keepalive replies, and TCP backs
+    /// off on the loss.
+    #[default]
+    Drop,
+    /// Make the reader wait for room. Nothing is dropped here, but a slow
+    /// writer stalls its reader, and the kernel drops at the TUN device
+    /// instead, where it is not counted.
+    Wait,
+}
+
+impl std::str::FromStr for QueuePolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "drop" => Ok(QueuePolicy::Drop),
+            "wait" => Ok(QueuePolicy::Wait),
+            _ => Err(format!("invalid queue policy '{s}': expected drop or wait")),
+        }
+    }
+}
+
+/// How the agent gets root on the server when the SSH user is not root.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Elevation {
+    /// sudo if it works without a password or with `sudo_password_file`,
+    /// else doas.
+    #[default]
+    Auto,
+    Sudo,
+    Doas,
+}
+
+impl std::str::FromStr for Elevation {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(Elevation::Auto),
+            "sudo" => Ok(Elevation::Sudo),
+            "doas" => Ok(Elevation::Doas),
+            _ => Err(format!(
+                "invalid elevation '{s}': expected auto, sudo or doas"
+            )),
+        }
+    }
+}
+
+/// A user-supplied route from `routes`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StaticRoute {
+    pub destination: IpNet,
+    pub via: RouteVia,
+}
+
+/// Where a [`StaticRoute`] sends its traffic.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RouteVia {
+    /// Into the TUN device, like `include`.
+    Tun,
+    /// Through the client's original default gateway, like `exclude`.
+    Lan,
+}
+
+impl std::str::FromStr for StaticRoute {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = |reason: &str| format!("invalid route '{s}': {reason}");
+        let [destination, "via", via] = s.split_whitespace().collect::<Vec<_>>()[..] else {
+            return Err(invalid("expected 'CIDR via tun' or 'CIDR via lan'"));
+        };
+        let destination = destination
+            .parse::<IpNet>()
+            .map_err(|e| invalid(&e.to_string()))?
+            .trunc();
+        let via = match via {
+            "tun" => RouteVia::Tun,
+            "lan" => RouteVia::Lan,
+            _ => return Err(invalid("expected tun or lan after 'via'")),
+        };
+        Ok(Self { destination, via })
+    }
+}
+
 fn default_client_address() -> String {
     "10.8.0.2/24".to_string()
 }
@@ -95,24 +730,171 @@ fn default_server_address() -> String {
     "10.8.0.1/24".to_string()
 }
 
+fn default_tun_queues() -> usize {
+    1
+}
+
 fn default_client_tun() -> String {
     "tun-x2ssh".to_string()
 }
 
 fn default_mtu() -> u16 {
-    1400
+    proto::DEFAULT_MTU
+}
+
+fn default_policy_table() -> u32 {
+    0x7832
 }
 
-#[derive(Debug, Clone, Deserialize)]
+fn default_route_metric() -> u32 {
+    1
+}
+
+fn default_roaming() -> bool {
+    true
+}
+
+fn default_deploy_agent() -> bool {
+    true
+}
+
+fn default_roaming_interval() -> Duration {
+    Duration::from_secs(2)
+}
+
+fn default_heal_routes() -> bool {
+    true
+}
+
+fn default_self_test_url() -> String {
+    "http://example.com/".to_string()
+}
+
+fn default_keepalive_interval() -> Duration {
+    Duration::from_secs(2)
+}
+
+fn default_keepalive_timeout() -> Duration {
+    Duration::from_secs(10)
+}
+
+/// How long the VPN agent gets to create its TUN device and say hello,
+/// unless `agent_start_timeout` says otherwise.
+pub const AGENT_START_TIMEOUT: Duration = Duration::from_secs(15);
+
+fn default_agent_start_timeout() -> Duration {
+    AGENT_START_TIMEOUT
+}
+
+fn default_batch_delay() -> Duration {
+    Duration::from_millis(1)
+}
+
+fn default_compress_threshold() -> usize {
+    proto::compress::DEFAULT_THRESHOLD
+}
+
+fn default_nat64_prefix() -> String {
+    "64:ff9b::/96".to_string()
+}
+
+fn default_dns64_listen() -> String {
+    "[::1]:53".to_string()
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct ConnectionConfig {
+    /// The SSH server, used when no destination is given on the command
+    /// line.
+    #[serde(default)]
+    pub host: Option<String>,
+    /// The user to log in as when the destination does not name one.
+    #[serde(default)]
+    pub user: Option<String>,
     #[serde(default = "default_port")]
     pub port: u16,
+    /// The private key to authenticate with, unless `-i` is given.
+    #[serde(default)]
+    pub identity: Option<PathBuf>,
+    /// Command printing the passphrase of an encrypted identity on its
+    /// first line, e.g. `pass show ssh/id_ed25519`.
+    #[serde(default)]
+    pub passphrase_cmd: Option<String>,
+    /// OS keyring account holding that passphrase; see
+    /// [`SecretSource::Keyring`].
+    #[serde(default)]
+    pub passphrase_keyring: Option<String>,
+    /// The server's expected host key as an OpenSSH SHA-256 fingerprint
+    /// (`SHA256:...`, as `ssh-keygen -lf` prints it). Any other key fails
+    /// the connection; unset, any host key is accepted.
+    #[serde(default)]
+    pub host_key: Option<String>,
+    #[serde(default = "default_nodelay")]
+    pub nodelay: bool,
+    #[serde(default, alias = "keepalive_ms", with = "option_duration_serde")]
+    pub keepalive: Option<Duration>,
+    #[serde(default)]
+    pub recv_buffer_size: Option<usize>,
+    #[serde(default)]
+    pub send_buffer_size: Option<usize>,
+    /// How long to wait on exit for open SOCKS connections to finish, and
+    /// for the server to answer each channel close before abandoning the
+    /// channel.
+    #[serde(
+        default = "default_shutdown_timeout",
+        alias = "shutdown_timeout_ms",
+        with = "duration_serde"
+    )]
+    pub shutdown_timeout: Duration,
+    /// Also offer the SHA-1 key exchanges, `ssh-rsa` signatures and CBC
+    /// ciphers that old dropbear/OpenSSH servers need. Weaker; off unless
+    /// such a server is the only option.
+    #[serde(default)]
+    pub legacy_server: bool,
+}
+
+impl ConnectionConfig {
+    /// Where the identity's passphrase comes from, if anywhere.
+    pub fn passphrase(&self) -> anyhow::Result<Option<SecretSource>> {
+        SecretSource::one_of([
+            (
+                "passphrase_cmd",
+                self.passphrase_cmd.clone().map(SecretSource::Command),
+            ),
+            (
+                "passphrase_keyring",
+                self.passphrase_keyring.clone().map(SecretSource::Keyring),
+            ),
+        ])
+    }
+
+    pub fn tcp_options(&self) -> TcpOptions {
+        TcpOptions {
+            nodelay: self.nodelay,
+            keepalive: self.keepalive,
+            recv_buffer_size: self.recv_buffer_size,
+            send_buffer_size: self.send_buffer_size,
+        }
+    }
 }
 
 impl Default for ConnectionConfig {
     fn default() -> Self {
         Self {
+            host: None,
+            user: None,
             port: default_port(),
+            identity: None,
+            passphrase_cmd: None,
+            passphrase_keyring: None,
+            host_key: None,
+            nodelay: default_nodelay(),
+            keepalive: None,
+            recv_buffer_size: None,
+            send_buffer_size: None,
+            shutdown_timeout: default_shutdown_timeout(),
+            legacy_server: false,
         }
     }
 }
@@ -121,70 +903,878 @@ fn default_port() -> u16 {
     22
 }
 
-#[derive(Debug, Clone, Deserialize)]
+fn default_nodelay() -> bool {
+    true
+}
+
+fn default_shutdown_timeout() -> Duration {
+    Duration::from_secs(5)
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SocksConfig {
+    /// Address to listen on, `HOST:PORT` or a bare port on 127.0.0.1;
+    /// setting it enables the proxy as `-D` does.
+    #[serde(default)]
+    pub listen: Option<String>,
+    /// Username and password clients must authenticate with.
+    #[serde(default)]
+    pub auth: Option<SocksAuthConfig>,
+    /// Client access rules, `allow CIDR` or `deny CIDR`; the first match
+    /// decides.
+    #[serde(default)]
+    pub acl: Vec<String>,
+    #[serde(default = "default_buffer_size")]
+    pub buffer_size: usize,
+    #[serde(default = "default_nodelay")]
+    pub nodelay: bool,
+    #[serde(default, alias = "keepalive_ms", with = "option_duration_serde")]
+    pub keepalive: Option<Duration>,
+    #[serde(default)]
+    pub recv_buffer_size: Option<usize>,
+    #[serde(default)]
+    pub send_buffer_size: Option<usize>,
+    #[serde(default)]
+    pub redial: bool,
+    #[serde(
+        default = "default_redial_timeout",
+        alias = "redial_timeout_ms",
+        with = "duration_serde"
+    )]
+    pub redial_timeout: Duration,
+    /// Resolution policy by domain suffix: `local`, `remote` (the SSH
+    /// server resolves) or `socks5://HOST:PORT` (an upstream proxy reached
+    /// through the server resolves, e.g. Tor for `onion`).
+    #[serde(default)]
+    pub resolve: BTreeMap<String, ResolvePolicy>,
+    /// Channel opens to one destination failing in a row before it is
+    /// refused without trying for `breaker_cooldown`; 0 disables this.
+    #[serde(default = "default_breaker_failures")]
+    pub breaker_failures: u32,
+    #[serde(default = "default_breaker_cooldown", with = "duration_serde")]
+    pub breaker_cooldown: Duration,
+}
+
+impl SocksConfig {
+    /// The settings for each connection; reads the auth password file.
+    pub fn options(&self) -> anyhow::Result<SocksOptions> {
+        Ok(SocksOptions {
+            buffer_size: self.buffer_size,
+            tcp: TcpOptions {
+                nodelay: self.nodelay,
+                keepalive: self.keepalive,
+                recv_buffer_size: self.recv_buffer_size,
+                send_buffer_size: self.send_buffer_size,
+            },
+            redial: self.redial.then_some(self.redial_timeout),
+            resolve: Resolvers::new(&self.resolve),
+            auth: self
+                .auth
+                .as_ref()
+                .map(SocksAuthConfig::credentials)
+                .transpose()?,
+            acl: Acl::parse(&self.acl)?,
+            open: Default::default(),
+            breaker: Arc::new(CircuitBreaker::new(
+                self.breaker_failures,
+                self.breaker_cooldown,
+            )),
+        })
+    }
+}
+
+impl Default for SocksConfig {
+    fn default() -> Self {
+        Self {
+            listen: None,
+            auth: None,
+            acl: Vec::new(),
+            buffer_size: default_buffer_size(),
+            nodelay: default_nodelay(),
+            keepalive: None,
+            recv_buffer_size: None,
+            send_buffer_size: None,
+            redial: false,
+            redial_timeout: default_redial_timeout(),
+            resolve: BTreeMap::new(),
+            breaker_failures: default_breaker_failures(),
+            breaker_cooldown: default_breaker_cooldown(),
+        }
+    }
+}
+
+/// `[socks] auth`: a username and either the password or a file holding
+/// it.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SocksAuthConfig {
+    pub username: String,
+    #[serde(default)]
+    pub password: Option<String>,
+    /// Read at start-up; a trailing newline is ignored.
+    #[serde(default)]
+    pub password_file: Option<PathBuf>,
+    /// Command printing the password on its first line, run at start-up.
+    #[serde(default)]
+    pub password_cmd: Option<String>,
+    /// OS keyring account holding the password.
+    #[serde(default)]
+    pub password_keyring: Option<String>,
+}
+
+impl SocksAuthConfig {
+    pub fn credentials(&self) -> anyhow::Result<Credentials> {
+        let source = self.source()?;
+        Ok(Credentials {
+            username: self.username.clone(),
+            password: source
+                .read()
+                .map_err(|e| anyhow::anyhow!("socks auth password: {}", e))?,
+        })
+    }
+
+    /// Where the password comes from; exactly one key must say.
+    pub fn source(&self) -> anyhow::Result<SecretSource> {
+        SecretSource::one_of([
+            (
+                "password",
+                self.password
+                    .clone()
+                    .map(|password| SecretSource::Inline(Zeroizing::new(password))),
+            ),
+            (
+                "password_file",
+                self.password_file.clone().map(SecretSource::File),
+            ),
+            (
+                "password_cmd",
+                self.password_cmd.clone().map(SecretSource::Command),
+            ),
+            (
+                "password_keyring",
+                self.password_keyring.clone().map(SecretSource::Keyring),
+            ),
+        ])
+        .map_err(|e| anyhow::anyhow!("socks auth: {}", e))?
+        .ok_or_else(|| {
+            anyhow::anyhow!(
+                "socks auth needs one of password, password_file, password_cmd and \
+                 password_keyring"
+            )
+        })
+    }
+}
+
+fn default_redial_timeout() -> Duration {
+    Duration::from_secs(10)
+}
+
+fn default_breaker_failures() -> u32 {
+    5
+}
+
+fn default_breaker_cooldown() -> Duration {
+    Duration::from_secs(30)
+}
+
+fn default_buffer_size() -> usize {
+    64 * 1024
+}
+
+/// Forwards every session starts with, besides the ones given on the
+/// command line.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ForwardConfig {
+    /// Local forwards (`-L`), as `[BIND:]PORT:HOST:HOSTPORT`.
+    #[serde(default)]
+    pub local: Vec<String>,
+}
+
+impl ForwardConfig {
+    pub fn forwards(&self) -> anyhow::Result<Vec<Forward>> {
+        self.local
+            .iter()
+            .map(|spec| {
+                Forward::parse("-L", spec).map_err(|e| anyhow::anyhow!("forward.local: {}", e))
+            })
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct JournalConfig {
+    /// Append-only session journal; disabled when unset.
+    #[serde(default)]
+    pub path: Option<PathBuf>,
+    /// File holding the HMAC key used to sign journal entries.
+    #[serde(default)]
+    pub key_file: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct StatsConfig {
+    /// File each proxy and VPN session appends its traffic totals to, for
+    /// `x2ssh stats`; disabled when unset.
+    #[serde(default)]
+    pub path: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TelemetryConfig {
+    /// OpenTelemetry collector traces are exported to over OTLP/HTTP, e.g.
+    /// `http://127.0.0.1:4318`; disabled when unset.
+    #[serde(default)]
+    pub otlp_endpoint: Option<String>,
+    /// Share of SOCKS connections and reconnects traced, 0 to 1.
+    #[serde(default = "default_sample_ratio")]
+    pub sample_ratio: f64,
+    #[serde(default = "default_service_name")]
+    pub service_name: String,
+}
+
+impl Default for TelemetryConfig {
+    fn default() -> Self {
+        Self {
+            otlp_endpoint: None,
+            sample_ratio: default_sample_ratio(),
+            service_name: default_service_name(),
+        }
+    }
+}
+
+impl TelemetryConfig {
+    /// Trace export settings, exporting to `endpoint` if given, else to
+    /// `otlp_endpoint`; `None` when neither is set.
+    pub fn trace_options(&self, endpoint: Option<&str>) -> anyhow::Result<Option<TraceOptions>> {
+        let Some(endpoint) = endpoint.or(self.otlp_endpoint.as_deref()) else {
+            return Ok(None);
+        };
+        if !(0.0..=1.0).contains(&self.sample_ratio) {
+            anyhow::bail!("telemetry.sample_ratio must be between 0 and 1");
+        }
+        Ok(Some(TraceOptions {
+            endpoint: endpoint
+                .parse()
+                .map_err(|e| anyhow::anyhow!("telemetry.otlp_endpoint: {}", e))?,
+            sample_ratio: self.sample_ratio,
+            service_name: self.service_name.clone(),
+        }))
+    }
+}
+
+fn default_sample_ratio() -> f64 {
+    1.0
+}
+
+fn default_service_name() -> String {
+    "x2ssh".to_string()
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct RetryConfig {
     #[serde(default)]
     pub max_attempts: MaxAttempts,
-    #[serde(default = "default_initial_delay_ms")]
-    pub initial_delay_ms: u64,
+    #[serde(
+        default = "default_initial_delay",
+        alias = "initial_delay_ms",
+        with = "duration_serde"
+    )]
+    pub initial_delay: Duration,
     #[serde(default = "default_backoff")]
     pub backoff: f64,
-    #[serde(default = "default_max_delay_ms")]
-    pub max_delay_ms: u64,
-    #[serde(default = "default_health_interval_ms")]
-    pub health_interval_ms: u64,
+    #[serde(
+        default = "default_max_delay",
+        alias = "max_delay_ms",
+        with = "duration_serde"
+    )]
+    pub max_delay: Duration,
+    #[serde(
+        default = "default_health_interval",
+        alias = "health_interval_ms",
+        with = "interval_serde"
+    )]
+    pub health_interval: Duration,
+    /// How long one reconnect may keep retrying before the session gives
+    /// up; unlimited if `None`.
+    #[serde(default, with = "option_duration_serde")]
+    pub max_total_duration: Option<Duration>,
+    #[serde(default)]
+    pub health_check: HealthCheck,
+    /// How long one probe may take; for `tcp`, the time between TCP
+    /// keepalive probes.
+    #[serde(default = "default_health_timeout", with = "duration_serde")]
+    pub health_timeout: Duration,
+    /// Failed probes in a row before the connection counts as lost.
+    #[serde(default = "default_health_failures")]
+    pub health_failures: u32,
+    /// Where `echo` probes connect, as `HOST:PORT` seen from the server.
+    #[serde(default = "default_health_echo_target")]
+    pub health_echo_target: String,
+    /// Recent probes judged for degradation.
+    #[serde(default = "default_health_window")]
+    pub health_window: usize,
+    /// Median probe round-trip time above which the connection is reported
+    /// as degraded.
+    #[serde(default = "default_health_rtt_warn", with = "duration_serde")]
+    pub health_rtt_warn: Duration,
+    /// Share of failed probes, 0 to 1, above which it is reported as
+    /// degraded.
+    #[serde(default = "default_health_failure_rate_warn")]
+    pub health_failure_rate_warn: f64,
+}
+
+impl RetryConfig {
+    pub fn health_options(&self) -> anyhow::Result<HealthOptions> {
+        let (host, port) = self
+            .health_echo_target
+            .rsplit_once(':')
+            .and_then(|(host, port)| Some((host, port.parse().ok()?)))
+            .filter(|(host, _)| !host.is_empty())
+            .ok_or_else(|| {
+                anyhow::anyhow!(
+                    "retry.health_echo_target '{}': expected HOST:PORT",
+                    self.health_echo_target
+                )
+            })?;
+        if self.health_interval.is_zero() {
+            anyhow::bail!("retry.health_interval must be longer than 0");
+        }
+        if self.health_failures == 0 {
+            anyhow::bail!("retry.health_failures must be at least 1");
+        }
+        if self.health_window == 0 {
+            anyhow::bail!("retry.health_window must be at least 1");
+        }
+        if !(0.0..=1.0).contains(&self.health_failure_rate_warn) {
+            anyhow::bail!("retry.health_failure_rate_warn must be between 0 and 1");
+        }
+        Ok(HealthOptions {
+            check: self.health_check,
+            timeout: self.health_timeout,
+            failures: self.health_failures,
+            echo_target: (host.trim_matches(['[', ']']).to_string(), port),
+            degrade: DegradeThresholds {
+                window: self.health_window,
+                rtt: self.health_rtt_warn,
+                failure_rate: self.health_failure_rate_warn,
+            },
+        })
+    }
 }
 
 impl Default for RetryConfig {
     fn default() -> Self {
         Self {
             max_attempts: MaxAttempts::default(),
-            initial_delay_ms: default_initial_delay_ms(),
+            initial_delay: default_initial_delay(),
             backoff: default_backoff(),
-            max_delay_ms: default_max_delay_ms(),
-            health_interval_ms: default_health_interval_ms(),
+            max_delay: default_max_delay(),
+            health_interval: default_health_interval(),
+            max_total_duration: None,
+            health_check: HealthCheck::default(),
+            health_timeout: default_health_timeout(),
+            health_failures: default_health_failures(),
+            health_echo_target: default_health_echo_target(),
+            health_window: default_health_window(),
+            health_rtt_warn: default_health_rtt_warn(),
+            health_failure_rate_warn: default_health_failure_rate_warn(),
         }
     }
 }
 
-fn default_initial_delay_ms() -> u64 {
-    1000
-}
+fn default_initial_delay() -> Duration {
+    Duration::from_secs(1)
+}
+
+fn default_backoff() -> f64 {
+    2.0
+}
+
+fn default_max_delay() -> Duration {
+    Duration::from_secs(30)
+}
+
+fn default_health_interval() -> Duration {
+    Duration::from_secs(5)
+}
+
+fn default_health_timeout() -> Duration {
+    Duration::from_secs(10)
+}
+
+fn default_health_failures() -> u32 {
+    1
+}
+
+fn default_health_echo_target() -> String {
+    "127.0.0.1:22".to_string()
+}
+
+fn default_health_window() -> usize {
+    20
+}
+
+fn default_health_rtt_warn() -> Duration {
+    Duration::from_secs(1)
+}
+
+fn default_health_failure_rate_warn() -> f64 {
+    0.2
+}
+
+/// How the SSH connection is checked every `health_interval`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HealthCheck {
+    /// Open and close a session channel; fails against servers that refuse
+    /// or limit sessions, such as forwarding-only gateways.
+    #[default]
+    Channel,
+    /// An SSH keepalive request the server must answer.
+    Keepalive,
+    /// TCP keepalive on the SSH connection, with the kernel's probes; only
+    /// notices a dead peer, not a stuck SSH server.
+    Tcp,
+    /// A `direct-tcpip` connection through the server to
+    /// `health_echo_target`, which must send something first (as an SSH
+    /// server's banner); checks forwarding end to end.
+    Echo,
+}
+
+impl std::str::FromStr for HealthCheck {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "channel" => Ok(HealthCheck::Channel),
+            "keepalive" => Ok(HealthCheck::Keepalive),
+            "tcp" => Ok(HealthCheck::Tcp),
+            "echo" => Ok(HealthCheck::Echo),
+            _ => Err(format!(
+                "invalid health check '{s}': expected channel, keepalive, tcp or echo"
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub enum MaxAttempts {
+    #[default]
+    Inf,
+    Count(u32),
+}
+
+impl MaxAttempts {
+    /// The attempt limit, `None` for unlimited.
+    pub fn limit(&self) -> Option<u32> {
+        match self {
+            MaxAttempts::Inf => None,
+            MaxAttempts::Count(count) => Some(*count),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for MaxAttempts {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum SelfDeser {
+            Str(String),
+            Int(u32),
+        }
+
+        match SelfDeser::deserialize(deserializer)? {
+            SelfDeser::Str(x) if x.eq_ignore_ascii_case("inf") => Ok(MaxAttempts::Inf),
+            SelfDeser::Str(x) => Err(serde::de::Error::custom(format!(
+                "expected \"inf\" or a number, got: {x}"
+            ))),
+            SelfDeser::Int(x) => Ok(MaxAttempts::Count(x)),
+        }
+    }
+}
+
+/// Turns a TOML error into one that suggests the field meant, when it
+/// names an unknown field close to a known one.
+fn config_error(e: toml::de::Error) -> anyhow::Error {
+    match suggest_field(e.message()) {
+        Some(suggestion) => anyhow::anyhow!("{}help: did you mean `{}`?", e, suggestion),
+        None => e.into(),
+    }
+}
+
+/// `$XDG_CONFIG_HOME/x2ssh/config.toml`, or `~/.config/x2ssh/config.toml`
+/// without it; `None` when neither variable is set.
+pub fn default_path() -> Option<PathBuf> {
+    let dir = std::env::var_os("XDG_CONFIG_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))?;
+    Some(dir.join("x2ssh").join("config.toml"))
+}
+
+/// The config every user of the machine falls back to.
+pub const SYSTEM_PATH: &str = "/etc/x2ssh/config.toml";
+
+/// The config file used when none is given: the user's ([`default_path`])
+/// if it exists, else [`SYSTEM_PATH`] if that does.
+pub fn discover() -> Option<PathBuf> {
+    first_existing([default_path(), Some(PathBuf::from(SYSTEM_PATH))])
+}
+
+fn first_existing(candidates: impl IntoIterator<Item = Option<PathBuf>>) -> Option<PathBuf> {
+    candidates.into_iter().flatten().find(|path| path.is_file())
+}
+
+/// Writes [`TEMPLATE`] to `path`, creating parent directories. An existing
+/// file is only replaced with `force`.
+pub fn write_template(path: &Path, force: bool) -> anyhow::Result<()> {
+    if path.exists() && !force {
+        anyhow::bail!(
+            "{} already exists; use --force to replace it",
+            path.display()
+        );
+    }
+    if let Some(parent) = path.parent()
+        && !parent.as_os_str().is_empty()
+    {
+        std::fs::create_dir_all(parent)?;
+    }
+    std::fs::write(path, TEMPLATE)?;
+    Ok(())
+}
+
+/// Whether the environment variable `name` overrides a config key.
+pub fn is_env_override(name: &str) -> bool {
+    env_key(name).is_some()
+}
+
+/// The section and key an `X2SSH_<SECTION>_<KEY>` variable names; the
+/// key keeps its `__` separators.
+fn env_key(name: &str) -> Option<(String, String)> {
+    let name = name.strip_prefix(ENV_PREFIX)?.to_ascii_lowercase();
+    ENV_SECTIONS.iter().find_map(|section| {
+        let key = name.strip_prefix(section)?.strip_prefix('_')?;
+        (!key.split("__").any(str::is_empty)).then(|| (section.to_string(), key.to_string()))
+    })
+}
+
+/// A table setting `key` in `section` to `value`, with each `__` in `key`
+/// stepping into a nested table.
+fn env_override(section: String, key: String, value: toml::Value) -> toml::Table {
+    let value = key.rsplit("__").fold(value, |value, key| {
+        toml::Value::Table(toml::Table::from_iter([(key.to_string(), value)]))
+    });
+    toml::Table::from_iter([(section, value)])
+}
+
+/// Whether `config` still deserializes with `key` in `section` set to
+/// `value`.
+fn accepts(config: &toml::Table, section: &str, key: &str, value: toml::Value) -> bool {
+    let mut config = config.clone();
+    merge_tables(
+        &mut config,
+        env_override(section.to_string(), key.to_string(), value),
+    );
+    toml::Value::Table(config).try_into::<AppConfig>().is_ok()
+}
+
+fn env_value(value: &str) -> toml::Value {
+    let parsed = toml::from_str::<toml::Table>(&format!("value = {}", value))
+        .ok()
+        .and_then(|mut table| table.remove("value"));
+    match parsed {
+        // `inf` is how `max_attempts` spells unlimited, not a float.
+        Some(toml::Value::Float(float)) if !float.is_finite() => {
+            toml::Value::String(value.to_string())
+        }
+        Some(parsed) => parsed,
+        None => toml::Value::String(value.to_string()),
+    }
+}
+
+/// Reads the config file at `path` as a table, with its includes merged
+/// in. `stack` holds the files being read, to catch include cycles.
+fn load_table(path: &Path, stack: &mut Vec<PathBuf>) -> anyhow::Result<toml::Table> {
+    let in_file = |e: anyhow::Error| anyhow::anyhow!("{}: {:#}", path.display(), e);
+    let canonical = path.canonicalize().map_err(|e| in_file(e.into()))?;
+    if let Some(start) = stack.iter().position(|file| *file == canonical) {
+        let cycle: Vec<String> = stack[start..]
+            .iter()
+            .chain([&canonical])
+            .map(|file| file.display().to_string())
+            .collect();
+        anyhow::bail!("include cycle: {}", cycle.join(" -> "));
+    }
+    let content = std::fs::read_to_string(path).map_err(|e| in_file(e.into()))?;
+    // Each file must be a valid config by itself, so errors point at it.
+    let config = AppConfig::parse(&content).map_err(in_file)?;
+    let mut table: toml::Table = toml::from_str(&content).map_err(|e| in_file(e.into()))?;
+    table.remove("include");
+
+    let dir = path.parent().unwrap_or(Path::new(""));
+    stack.push(canonical);
+    for pattern in &config.include {
+        let files = include_files(dir, pattern)
+            .map_err(|e| in_file(e.context(format!("include '{}'", pattern))))?;
+        for file in files {
+            merge_tables(&mut table, load_table(&file, stack)?);
+        }
+    }
+    stack.pop();
+    Ok(table)
+}
+
+/// The files an `include` entry names, relative to `dir`. A wildcard in
+/// the file name matches any number of files, in name order; a plain name
+/// must exist.
+fn include_files(dir: &Path, pattern: &str) -> anyhow::Result<Vec<PathBuf>> {
+    let path = dir.join(pattern);
+    let Some(name) = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .filter(|name| name.contains(['*', '?']))
+    else {
+        if !path.is_file() {
+            anyhow::bail!("{} does not exist", path.display());
+        }
+        return Ok(vec![path]);
+    };
+    let parent = path.parent().unwrap_or(Path::new(""));
+    let mut files = Vec::new();
+    for entry in std::fs::read_dir(parent)
+        .map_err(|e| anyhow::anyhow!("cannot read {}: {}", parent.display(), e))?
+    {
+        let entry = entry?;
+        if entry
+            .file_name()
+            .to_str()
+            .is_some_and(|file| wildcard_match(name.as_bytes(), file.as_bytes()))
+            && entry.path().is_file()
+        {
+            files.push(entry.path());
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// Matches `name` against `pattern`, where `*` stands for any run of
+/// characters and `?` for one. Hidden files only match a leading `.`.
+fn wildcard_match(pattern: &[u8], name: &[u8]) -> bool {
+    if name.starts_with(b".") && !pattern.starts_with(b".") {
+        return false;
+    }
+    fn matches(pattern: &[u8], name: &[u8]) -> bool {
+        match (pattern.first(), name.first()) {
+            (None, None) => true,
+            (Some(b'*'), _) => {
+                matches(&pattern[1..], name) || (!name.is_empty() && matches(pattern, &name[1..]))
+            }
+            (Some(b'?'), Some(_)) => matches(&pattern[1..], &name[1..]),
+            (Some(p), Some(n)) if p == n => matches(&pattern[1..], &name[1..]),
+            _ => false,
+        }
+    }
+    matches(pattern, name)
+}
+
+/// Lays `overlay` over `base`, recursing into tables present in both.
+fn merge_tables(base: &mut toml::Table, overlay: toml::Table) {
+    for (key, value) in overlay {
+        match (base.get_mut(&key), value) {
+            (Some(toml::Value::Table(base)), toml::Value::Table(overlay)) => {
+                merge_tables(base, overlay)
+            }
+            (_, value) => {
+                base.insert(key, value);
+            }
+        }
+    }
+}
+
+/// Picks the expected field closest to the unknown one from serde's
+/// "unknown field `x`, expected one of `a`, `b`" message.
+fn suggest_field(message: &str) -> Option<&str> {
+    let rest = message.strip_prefix("unknown field `")?;
+    let (unknown, rest) = rest.split_once('`')?;
+    let expected = rest
+        .strip_prefix(", expected one of ")
+        .or_else(|| rest.strip_prefix(", expected "))?;
+
+    expected
+        .split(", ")
+        .map(|field| field.trim_matches('`'))
+        .map(|field| (strsim::osa_distance(unknown, field), field))
+        .filter(|(distance, _)| *distance <= (unknown.len() / 3).max(1))
+        .min_by_key(|(distance, _)| *distance)
+        .map(|(_, field)| field)
+}
+
+/// Parses a duration such as `500ms`, `2s`, `1m30s` or `1.5h`. A bare
+/// number is milliseconds, matching the older `*_ms` config keys.
+pub fn parse_duration(input: &str) -> Result<Duration, String> {
+    let input = input.trim();
+    if input.is_empty() {
+        return Err("empty duration".to_string());
+    }
+    if let Ok(ms) = input.parse::<u64>() {
+        return Ok(Duration::from_millis(ms));
+    }
+
+    let mut total = Duration::ZERO;
+    let mut rest = input;
+    while !rest.is_empty() {
+        let number_len = rest
+            .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+            .ok_or_else(|| format!("missing unit in duration '{input}'"))?;
+        let (number, tail) = rest.split_at(number_len);
+        let unit_len = tail
+            .find(|c: char| c.is_ascii_digit() || c == '.')
+            .unwrap_or(tail.len());
+        let (unit, tail) = tail.split_at(unit_len);
+
+        let value: f64 = number
+            .parse()
+            .map_err(|_| format!("invalid number in duration '{input}'"))?;
+        let unit_secs = match unit {
+            "ms" => 0.001,
+            "s" => 1.0,
+            "m" => 60.0,
+            "h" => 3600.0,
+            _ => {
+                return Err(format!(
+                    "unknown unit '{unit}' in duration '{input}' (use ms, s, m or h)"
+                ));
+            }
+        };
+        let part = Duration::try_from_secs_f64(value * unit_secs)
+            .map_err(|e| format!("invalid duration '{input}': {e}"))?;
+        total = total
+            .checked_add(part)
+            .ok_or_else(|| format!("duration '{input}' is too long"))?;
+        rest = tail;
+    }
+    Ok(total)
+}
+
+/// [`parse_duration`] for how often something runs, which cannot be zero.
+pub fn parse_interval(input: &str) -> Result<Duration, String> {
+    let interval = parse_duration(input)?;
+    if interval.is_zero() {
+        return Err("interval must be longer than 0".to_string());
+    }
+    Ok(interval)
+}
+
+/// Formats a duration in the largest unit that represents it exactly, at
+/// millisecond precision.
+pub fn format_duration(duration: Duration) -> String {
+    let ms = duration.as_millis();
+    match ms {
+        0 => "0s".to_string(),
+        ms if ms % 3_600_000 == 0 => format!("{}h", ms / 3_600_000),
+        ms if ms % 60_000 == 0 => format!("{}m", ms / 60_000),
+        ms if ms % 1000 == 0 => format!("{}s", ms / 1000),
+        ms => format!("{ms}ms"),
+    }
+}
+
+/// A duration in a config file: a string for [`parse_duration`] or a
+/// whole number of milliseconds.
+struct DurationValue(Duration);
+
+impl<'de> Deserialize<'de> for DurationValue {
+    fn deserialize<D: serde::Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+        d.deserialize_any(DurationVisitor).map(DurationValue)
+    }
+}
+
+struct DurationVisitor;
+
+impl serde::de::Visitor<'_> for DurationVisitor {
+    type Value = Duration;
+
+    fn expecting(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("a duration such as \"500ms\", \"5s\", \"2m\" or \"1m30s\", or milliseconds")
+    }
+
+    fn visit_str<E: serde::de::Error>(self, value: &str) -> Result<Duration, E> {
+        parse_duration(value).map_err(E::custom)
+    }
+
+    fn visit_u64<E: serde::de::Error>(self, ms: u64) -> Result<Duration, E> {
+        Ok(Duration::from_millis(ms))
+    }
+
+    fn visit_i64<E: serde::de::Error>(self, ms: i64) -> Result<Duration, E> {
+        u64::try_from(ms)
+            .map(Duration::from_millis)
+            .map_err(|_| E::invalid_value(serde::de::Unexpected::Signed(ms), &self))
+    }
+}
+
+mod duration_serde {
+    use std::time::Duration;
+
+    use serde::Deserialize;
+
+    pub fn serialize<S: serde::Serializer>(value: &Duration, s: S) -> Result<S::Ok, S::Error> {
+        s.serialize_str(&super::format_duration(*value))
+    }
+
+    pub fn deserialize<'de, D: serde::Deserializer<'de>>(d: D) -> Result<Duration, D::Error> {
+        Ok(super::DurationValue::deserialize(d)?.0)
+    }
+}
+
+/// [`duration_serde`] for how often something runs, which cannot be zero.
+mod interval_serde {
+    use std::time::Duration;
 
-fn default_backoff() -> f64 {
-    2.0
-}
+    pub use super::duration_serde::serialize;
 
-fn default_max_delay_ms() -> u64 {
-    30000
+    pub fn deserialize<'de, D: serde::Deserializer<'de>>(d: D) -> Result<Duration, D::Error> {
+        let interval = super::duration_serde::deserialize(d)?;
+        if interval.is_zero() {
+            return Err(serde::de::Error::custom("interval must be longer than 0"));
+        }
+        Ok(interval)
+    }
 }
 
-fn default_health_interval_ms() -> u64 {
-    5000
-}
+mod option_duration_serde {
+    use std::time::Duration;
 
-#[derive(Debug, Clone, Default)]
-pub enum MaxAttempts {
-    #[default]
-    Inf,
-    Count(u32),
-}
+    use serde::Deserialize;
 
-impl<'de> Deserialize<'de> for MaxAttempts {
-    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
-        #[derive(Deserialize)]
-        #[serde(untagged)]
-        enum SelfDeser {
-            Str(String),
-            Int(u32),
+    pub fn serialize<S: serde::Serializer>(
+        value: &Option<Duration>,
+        s: S,
+    ) -> Result<S::Ok, S::Error> {
+        match value {
+            Some(value) => s.serialize_str(&super::format_duration(*value)),
+            None => s.serialize_none(),
         }
+    }
 
-        match SelfDeser::deserialize(deserializer)? {
-            SelfDeser::Str(x) if x.eq_ignore_ascii_case("inf") => Ok(MaxAttempts::Inf),
-            SelfDeser::Str(x) => Err(serde::de::Error::custom(format!(
-                "expected \"inf\" or a number, got: {x}"
-            ))),
-            SelfDeser::Int(x) => Ok(MaxAttempts::Count(x)),
+    pub fn deserialize<'de, D: serde::Deserializer<'de>>(
+        d: D,
+    ) -> Result<Option<Duration>, D::Error> {
+        Ok(Option::<super::DurationValue>::deserialize(d)?.map(|value| value.0))
+    }
+}
+
+impl Serialize for MaxAttempts {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            MaxAttempts::Inf => serializer.serialize_str("inf"),
+            MaxAttempts::Count(x) => serializer.serialize_u32(*x),
         }
     }
 }
@@ -211,13 +1801,74 @@ mod tests {
 client_address = "192.168.100.2/24"
 server_address = "192.168.100.1/24"
 client_tun = "wg-x2ssh"
+server_tun = "tun-srv"
 mtu = 1280
+tun_queues = 4
+persistent_tun = true
+offload = true
+include = ["172.20.0.0/16"]
+domains = ["*.corp.example"]
 exclude = ["10.0.0.0/8"]
+exclude_lan = true
+routing_mode = "policy"
+queue_policy = "wait"
+policy_table = 1234
+route_metric = 5
+cgroup = "user.slice/x2ssh"
 post_up = ["sysctl -w net.ipv4.ip_forward=1"]
 pre_down = ["iptables -t nat -D POSTROUTING -o eth0 -j MASQUERADE"]
+roaming = false
+roaming_interval_ms = 500
+heal_routes = false
+self_test = true
+self_test_url = "http://10.0.0.1/health"
+keepalive_interval_ms = 1000
+keepalive_timeout_ms = 6000
+agent_start_timeout = "30s"
+batch_delay = "0s"
+compress = true
+compress_threshold = 512
+dns64 = true
+nat64_prefix = "2001:db8:64::/96"
+dns64_listen = "127.0.0.1:5353"
+dns64_upstream = "10.0.0.2"
+agent_path = "/opt/x2ssh/agent"
+keep_agent = true
+shared_agent = "/run/x2ssh/shared.sock"
+nat = true
+agent_dns = true
+routes = ["172.16.0.0/12 via tun", "10.10.0.0/16 via lan"]
+elevation = "doas"
+sudo_password_file = "/etc/x2ssh/sudo-password"
+pcap = "/tmp/x2ssh.pcapng"
+block_inbound = true
+blocked_protocols = ["gre", "41"]
 
 [connection]
+host = "vpn.example.com"
+user = "alice"
 port = 2222
+identity = "/home/alice/.ssh/id_ed25519"
+host_key = "SHA256:uNiVztksCsDhcc0u9e8BujQXVUpKZIDTMczCvj3tD2s"
+nodelay = false
+keepalive_ms = 15000
+recv_buffer_size = 262144
+send_buffer_size = 262144
+
+[socks]
+buffer_size = 131072
+nodelay = false
+
+[journal]
+path = "/var/log/x2ssh/journal.jsonl"
+key_file = "/etc/x2ssh/journal.key"
+
+[telemetry]
+otlp_endpoint = "http://otel-collector:4318"
+sample_ratio = 0.1
+
+[stats]
+path = "/var/lib/x2ssh/stats.jsonl"
 
 [retry]
 max_attempts = 5
@@ -232,18 +1883,107 @@ health_interval_ms = 3000
         assert_eq!(config.vpn.client_address, "192.168.100.2/24");
         assert_eq!(config.vpn.server_address, "192.168.100.1/24");
         assert_eq!(config.vpn.client_tun, "wg-x2ssh");
+        assert_eq!(config.vpn.server_tun.as_deref(), Some("tun-srv"));
         assert_eq!(config.vpn.mtu, 1280);
+        assert_eq!(config.vpn.tun_queues, 4);
+        assert!(config.vpn.persistent_tun);
+        assert!(config.vpn.offload);
+        assert_eq!(config.vpn.include, vec!["172.20.0.0/16"]);
+        assert_eq!(config.vpn.domains, vec!["*.corp.example"]);
         assert_eq!(config.vpn.exclude, vec!["10.0.0.0/8"]);
+        assert!(config.vpn.exclude_lan);
+        assert_eq!(config.vpn.routing_mode, RoutingMode::Policy);
+        assert_eq!(config.vpn.queue_policy, QueuePolicy::Wait);
+        assert_eq!(config.vpn.policy_table, 1234);
+        assert_eq!(config.vpn.route_metric, 5);
+        assert_eq!(config.vpn.cgroup.as_deref(), Some("user.slice/x2ssh"));
         assert_eq!(config.vpn.post_up, vec!["sysctl -w net.ipv4.ip_forward=1"]);
         assert_eq!(config.vpn.pre_down, vec![
             "iptables -t nat -D POSTROUTING -o eth0 -j MASQUERADE"
         ]);
+        assert!(!config.vpn.roaming);
+        assert_eq!(config.vpn.roaming_interval, Duration::from_millis(500));
+        assert!(!config.vpn.heal_routes);
+        assert!(config.vpn.self_test);
+        assert_eq!(config.vpn.self_test_url, "http://10.0.0.1/health");
+        assert_eq!(config.vpn.keepalive_interval, Duration::from_secs(1));
+        assert_eq!(config.vpn.keepalive_timeout, Duration::from_secs(6));
+        assert_eq!(config.vpn.agent_start_timeout, Duration::from_secs(30));
+        assert_eq!(config.vpn.batch_delay, Duration::ZERO);
+        assert!(config.vpn.compress);
+        assert_eq!(config.vpn.compress_threshold, 512);
+        assert!(config.vpn.dns64);
+        assert_eq!(config.vpn.nat64_prefix, "2001:db8:64::/96");
+        assert_eq!(config.vpn.dns64_listen, "127.0.0.1:5353");
+        assert_eq!(config.vpn.dns64_upstream.as_deref(), Some("10.0.0.2"));
+        assert_eq!(config.vpn.agent_path.as_deref(), Some("/opt/x2ssh/agent"));
+        assert!(config.vpn.keep_agent);
+        assert_eq!(
+            config.vpn.shared_agent.as_deref(),
+            Some("/run/x2ssh/shared.sock")
+        );
+        assert!(config.vpn.nat);
+        assert!(config.vpn.agent_dns);
+        assert_eq!(config.vpn.routes, vec![
+            "172.16.0.0/12 via tun",
+            "10.10.0.0/16 via lan"
+        ]);
+        assert_eq!(config.vpn.elevation, Elevation::Doas);
+        assert_eq!(
+            config.vpn.sudo_password_file,
+            Some(PathBuf::from("/etc/x2ssh/sudo-password"))
+        );
+        assert_eq!(config.vpn.pcap, Some(PathBuf::from("/tmp/x2ssh.pcapng")));
+        assert!(config.vpn.block_inbound);
+        assert_eq!(config.vpn.blocked_protocols, vec!["gre", "41"]);
+        assert_eq!(config.connection.host.as_deref(), Some("vpn.example.com"));
+        assert_eq!(config.connection.user.as_deref(), Some("alice"));
         assert_eq!(config.connection.port, 2222);
+        assert_eq!(
+            config.connection.identity,
+            Some(PathBuf::from("/home/alice/.ssh/id_ed25519"))
+        );
+        assert_eq!(
+            config.connection.host_key.as_deref(),
+            Some("SHA256:uNiVztksCsDhcc0u9e8BujQXVUpKZIDTMczCvj3tD2s")
+        );
+        assert!(!config.connection.nodelay);
+        assert_eq!(config.connection.keepalive, Some(Duration::from_secs(15)));
+        assert_eq!(config.connection.recv_buffer_size, Some(262144));
+        assert_eq!(config.connection.send_buffer_size, Some(262144));
+        assert_eq!(config.socks.buffer_size, 131072);
+        assert!(!config.socks.nodelay);
+        assert_eq!(
+            config.journal.path,
+            Some(PathBuf::from("/var/log/x2ssh/journal.jsonl"))
+        );
+        assert_eq!(
+            config.journal.key_file,
+            Some(PathBuf::from("/etc/x2ssh/journal.key"))
+        );
+        assert_eq!(
+            config.stats.path,
+            Some(PathBuf::from("/var/lib/x2ssh/stats.jsonl"))
+        );
+        let trace = config.telemetry.trace_options(None).unwrap().unwrap();
+        assert_eq!(trace.endpoint.host, "otel-collector");
+        assert_eq!(trace.sample_ratio, 0.1);
+        assert_eq!(trace.service_name, "x2ssh");
+        assert_eq!(
+            config
+                .telemetry
+                .trace_options(Some("localhost:9000"))
+                .unwrap()
+                .unwrap()
+                .endpoint
+                .port,
+            9000
+        );
         assert!(matches!(config.retry.max_attempts, MaxAttempts::Count(5)));
-        assert_eq!(config.retry.initial_delay_ms, 500);
+        assert_eq!(config.retry.initial_delay, Duration::from_millis(500));
         assert_eq!(config.retry.backoff, 1.5);
-        assert_eq!(config.retry.max_delay_ms, 10000);
-        assert_eq!(config.retry.health_interval_ms, 3000);
+        assert_eq!(config.retry.max_delay, Duration::from_secs(10));
+        assert_eq!(config.retry.health_interval, Duration::from_secs(3));
     }
 
     #[test]
@@ -257,6 +1997,7 @@ client_address = "10.9.0.2/24"
 
         assert_eq!(config.vpn.client_address, "10.9.0.2/24");
         assert_eq!(config.vpn.client_tun, "tun-x2ssh"); // default
+        assert_eq!(config.vpn.server_tun, None); // default
         assert_eq!(config.connection.port, 22); // default
         assert!(matches!(config.retry.max_attempts, MaxAttempts::Inf)); // default
     }
@@ -270,6 +2011,11 @@ client_address = "10.9.0.2/24"
         assert_eq!(config.vpn.client_address, "10.8.0.2/24");
         assert_eq!(config.vpn.mtu, 1400);
         assert_eq!(config.connection.port, 22);
+        assert!(config.connection.nodelay);
+        assert_eq!(config.connection.shutdown_timeout, Duration::from_secs(5));
+        assert!(!config.connection.legacy_server);
+        assert_eq!(config.socks.buffer_size, 65536);
+        assert!(config.journal.path.is_none());
         assert!(matches!(config.retry.max_attempts, MaxAttempts::Inf));
     }
 
@@ -278,6 +2024,112 @@ client_address = "10.9.0.2/24"
         let config = AppConfig::default();
         assert_eq!(config.vpn.client_address, "10.8.0.2/24");
         assert_eq!(config.vpn.mtu, 1400);
+        assert_eq!(config.vpn.routing_mode, RoutingMode::Replace);
+        assert_eq!(config.vpn.queue_policy, QueuePolicy::Drop);
+        assert!(config.vpn.heal_routes);
+    }
+
+    #[test]
+    fn test_socks_options_from_config() {
+        let toml = r#"[socks]
+buffer_size = 16384
+keepalive_ms = 30000
+send_buffer_size = 65536"#;
+        let (_temp, path) = write_temp_config(toml);
+        let config = AppConfig::load(&path).unwrap();
+
+        let options = config.socks.options().unwrap();
+        assert_eq!(options.buffer_size, 16384);
+        assert!(options.tcp.nodelay);
+        assert_eq!(options.tcp.keepalive, Some(Duration::from_secs(30)));
+        assert_eq!(options.tcp.recv_buffer_size, None);
+        assert_eq!(options.tcp.send_buffer_size, Some(65536));
+        assert_eq!(options.redial, None);
+    }
+
+    #[test]
+    fn test_socks_redial_option() {
+        let toml = r#"[socks]
+redial = true
+redial_timeout_ms = 5000"#;
+        let (_temp, path) = write_temp_config(toml);
+        let config = AppConfig::load(&path).unwrap();
+
+        assert_eq!(
+            config.socks.options().unwrap().redial,
+            Some(Duration::from_secs(5))
+        );
+    }
+
+    #[test]
+    fn test_socks_resolve() {
+        let toml = r#"[socks.resolve]
+".onion" = "socks5://127.0.0.1:9050"
+internal = "remote""#;
+        let config = AppConfig::from_toml(toml).unwrap();
+        let options = config.socks.options().unwrap();
+
+        assert_eq!(options.resolve.policy("abc.onion"), &ResolvePolicy::Socks {
+            host: "127.0.0.1".to_string(),
+            port: 9050
+        });
+        assert_eq!(
+            options.resolve.policy("git.internal"),
+            &ResolvePolicy::Remote
+        );
+        assert_eq!(options.resolve.policy("example.com"), &ResolvePolicy::Local);
+
+        let err = AppConfig::from_toml("[socks.resolve]\nonion = \"tor\"").unwrap_err();
+        assert!(err.to_string().contains("socks5://HOST:PORT"), "{err}");
+    }
+
+    #[test]
+    fn test_socks_listen_auth_acl() {
+        let dir = tempfile::tempdir().unwrap();
+        let password_file = dir.path().join("socks-password");
+        std::fs::write(&password_file, "secret\n").unwrap();
+        let toml = format!(
+            r#"[socks]
+listen = "0.0.0.0:1080"
+auth = {{ username = "alice", password_file = "{}" }}
+acl = ["allow 192.168.1.0/24"]"#,
+            password_file.display()
+        );
+        let config = AppConfig::from_toml(&toml).unwrap();
+        assert_eq!(config.socks.listen.as_deref(), Some("0.0.0.0:1080"));
+
+        let options = config.socks.options().unwrap();
+        assert_eq!(
+            options.auth,
+            Some(Credentials {
+                username: "alice".to_string(),
+                password: Zeroizing::new("secret".to_string()),
+            })
+        );
+        assert!(options.acl.allows("192.168.1.7".parse().unwrap()));
+        assert!(!options.acl.allows("127.0.0.1".parse().unwrap()));
+
+        let mut config = SocksConfig {
+            auth: Some(SocksAuthConfig {
+                username: "alice".to_string(),
+                password: Some("secret".to_string()),
+                password_file: Some(password_file),
+                password_cmd: None,
+                password_keyring: None,
+            }),
+            ..Default::default()
+        };
+        let err = config.options().unwrap_err().to_string();
+        assert_eq!(
+            err,
+            "socks auth: password and password_file are both set; use only one of password, \
+             password_file, password_cmd, password_keyring"
+        );
+
+        config.auth = None;
+        config.acl = vec!["allow everyone".to_string()];
+        let err = config.options().unwrap_err().to_string();
+        assert!(err.contains("invalid acl rule 'allow everyone'"), "{err}");
     }
 
     #[test]
@@ -385,4 +2237,594 @@ max_attempts = "invalid""#;
         let net = config.network().unwrap();
         assert_eq!(net.prefix_len(), 24);
     }
+
+    #[test]
+    fn test_vpn_config_ipv6_addresses() {
+        assert!(VpnConfig::default().ipv6_addresses().unwrap().is_none());
+
+        let config: AppConfig = toml::from_str(
+            r#"
+            [vpn]
+            client_address6 = "fd00:8::2/64"
+            server_address6 = "fd00:8::1/64"
+            "#,
+        )
+        .unwrap();
+        let (client, server) = config.vpn.ipv6_addresses().unwrap().unwrap();
+        assert_eq!(client.addr(), "fd00:8::2".parse::<IpAddr>().unwrap());
+        assert_eq!(server.addr(), "fd00:8::1".parse::<IpAddr>().unwrap());
+        assert_eq!(client.prefix_len(), 64);
+    }
+
+    #[test]
+    fn test_vpn_config_ipv6_addresses_invalid() {
+        let only_client = VpnConfig {
+            client_address6: Some("fd00:8::2/64".to_string()),
+            ..Default::default()
+        };
+        assert!(only_client.ipv6_addresses().is_err());
+
+        let v4 = VpnConfig {
+            client_address6: Some("10.8.0.2/24".to_string()),
+            server_address6: Some("fd00:8::1/64".to_string()),
+            ..Default::default()
+        };
+        assert!(v4.ipv6_addresses().is_err());
+    }
+
+    #[test]
+    fn test_vpn_config_static_routes() {
+        let config = VpnConfig {
+            routes: vec![
+                "172.16.0.0/12 via tun".to_string(),
+                "10.10.1.7/16  via  lan".to_string(),
+                "fd00:1::/48 via tun".to_string(),
+            ],
+            ..Default::default()
+        };
+        assert_eq!(config.static_routes().unwrap(), vec![
+            StaticRoute {
+                destination: "172.16.0.0/12".parse().unwrap(),
+                via: RouteVia::Tun,
+            },
+            StaticRoute {
+                destination: "10.10.0.0/16".parse().unwrap(),
+                via: RouteVia::Lan,
+            },
+            StaticRoute {
+                destination: "fd00:1::/48".parse().unwrap(),
+                via: RouteVia::Tun,
+            },
+        ]);
+
+        for bad in [
+            "172.16.0.0/12",
+            "172.16.0.0/12 via wan",
+            "10.0.0.0/33 via tun",
+        ] {
+            let config = VpnConfig {
+                routes: vec![bad.to_string()],
+                ..Default::default()
+            };
+            let error = config.static_routes().unwrap_err().to_string();
+            assert!(error.contains(bad), "{error}");
+        }
+    }
+
+    #[test]
+    fn test_config_save_load_round_trip() {
+        let toml = r#"
+[vpn]
+client_address = "192.168.100.2/24"
+client_address6 = "fd00:8::2/64"
+server_address6 = "fd00:8::1/64"
+exclude = ["10.0.0.0/8", "fd00:1::/48"]
+post_up = ["sysctl -w net.ipv4.ip_forward=1"]
+roaming = false
+
+[connection]
+port = 2222
+keepalive_ms = 15000
+
+[socks]
+redial = true
+
+[socks.resolve]
+onion = "socks5://127.0.0.1:9050"
+internal = "remote"
+
+[journal]
+path = "/var/log/x2ssh/journal.jsonl"
+
+[retry]
+max_attempts = 5
+backoff = 1.5
+"#;
+        let (_temp, path) = write_temp_config(toml);
+        let config = AppConfig::load(&path).unwrap();
+
+        let dir = tempfile::tempdir().unwrap();
+        let saved = dir.path().join("nested").join("config.toml");
+        config.save(&saved).unwrap();
+        let reloaded = AppConfig::load(&saved).unwrap();
+        assert_eq!(reloaded, config);
+
+        // Saving again must not drift.
+        assert_eq!(reloaded.to_toml().unwrap(), config.to_toml().unwrap());
+    }
+
+    #[test]
+    fn test_config_default_round_trip() {
+        let config = AppConfig::default();
+        let reloaded: AppConfig = toml::from_str(&config.to_toml().unwrap()).unwrap();
+        assert_eq!(reloaded, config);
+    }
+
+    #[test]
+    fn test_template_matches_defaults() {
+        assert_eq!(
+            AppConfig::from_toml(TEMPLATE).unwrap(),
+            AppConfig::default()
+        );
+
+        // Every default shows up, commented out, under its section.
+        let toml::Value::Table(defaults) = toml::Value::try_from(AppConfig::default()).unwrap()
+        else {
+            unreachable!();
+        };
+        for (section, keys) in defaults {
+            let start = TEMPLATE
+                .find(&format!("\n[{}]\n", section))
+                .unwrap_or_else(|| panic!("no [{section}] in the template"));
+            let body = &TEMPLATE[start + 1..];
+            let body = &body[..body.find("\n[").unwrap_or(body.len())];
+            for (key, value) in keys.as_table().unwrap() {
+                if value.is_table() {
+                    continue;
+                }
+                let line = format!("\n# {} = {}\n", key, value);
+                assert!(body.contains(&line), "[{section}] lacks {line:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn test_first_existing() {
+        let dir = tempfile::tempdir().unwrap();
+        let user = dir.path().join("user.toml");
+        let system = dir.path().join("system.toml");
+        assert_eq!(
+            first_existing([Some(user.clone()), Some(system.clone())]),
+            None
+        );
+
+        std::fs::write(&system, "").unwrap();
+        assert_eq!(
+            first_existing([None, Some(user.clone()), Some(system.clone())]),
+            Some(system.clone())
+        );
+        std::fs::write(&user, "").unwrap();
+        assert_eq!(
+            first_existing([Some(user.clone()), Some(system)]),
+            Some(user)
+        );
+        // A directory in the way is skipped.
+        assert_eq!(first_existing([Some(dir.path().to_path_buf())]), None);
+    }
+
+    #[test]
+    fn test_write_template() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("x2ssh").join("config.toml");
+        write_template(&path, false).unwrap();
+        assert_eq!(AppConfig::load(&path).unwrap(), AppConfig::default());
+        assert!(write_template(&path, false).is_err());
+        write_template(&path, true).unwrap();
+    }
+
+    #[test]
+    fn test_max_attempts_serialize() {
+        let mut config = AppConfig::default();
+        assert!(config.to_toml().unwrap().contains("max_attempts = \"inf\""));
+
+        config.retry.max_attempts = MaxAttempts::Count(3);
+        assert!(config.to_toml().unwrap().contains("max_attempts = 3"));
+    }
+
+    #[test]
+    fn test_profiles() {
+        let toml = r#"
+[connection]
+host = "home.example.com"
+user = "alice"
+keepalive = "30s"
+
+[vpn]
+routes = ["0.0.0.0/0"]
+
+[profiles.work.connection]
+host = "work.example.com"
+
+[profiles.work.vpn]
+routes = ["10.0.0.0/8"]
+
+[profiles.work.retry]
+max_attempts = 3
+
+[profiles.home]
+"#;
+        let config = AppConfig::from_toml(toml).unwrap();
+        assert_eq!(config.connection.host.as_deref(), Some("home.example.com"));
+        assert_eq!(config.profiles.len(), 2);
+
+        let work = AppConfig::from_toml_profile(toml, "work").unwrap();
+        assert_eq!(work.connection.host.as_deref(), Some("work.example.com"));
+        // Keys the profile leaves alone keep their top-level values.
+        assert_eq!(work.connection.user.as_deref(), Some("alice"));
+        assert_eq!(work.connection.keepalive, Some(Duration::from_secs(30)));
+        assert_eq!(work.vpn.routes, vec!["10.0.0.0/8".to_string()]);
+        assert_eq!(work.retry.max_attempts, MaxAttempts::Count(3));
+        assert!(work.profiles.is_empty());
+
+        let home = AppConfig::from_toml_profile(toml, "home").unwrap();
+        assert_eq!(home.connection, config.connection);
+
+        let err = AppConfig::from_toml_profile(toml, "cafe").unwrap_err();
+        assert!(err.to_string().contains("home, work"), "{err}");
+    }
+
+    #[test]
+    fn test_env_overrides() {
+        let file =
+            AppConfig::from_toml("[vpn]\nmtu = 1300\nclient_address = \"10.8.0.2/24\"\n").unwrap();
+        let vars = [
+            ("X2SSH_VPN_MTU", "1400"),
+            ("X2SSH_VPN_ROUTES", r#"["10.0.0.0/8", "192.168.0.0/16"]"#),
+            ("X2SSH_CONNECTION_HOST", "203.0.113.7"),
+            ("X2SSH_CONNECTION_NODELAY", "false"),
+            ("X2SSH_CONNECTION_KEEPALIVE", "15s"),
+            ("X2SSH_RETRY_MAX_ATTEMPTS", "inf"),
+            ("X2SSH_RETRY_BACKOFF", "1.5"),
+            ("X2SSH_AUTO_SUDO", "1"),
+            ("HOME", "/root"),
+        ]
+        .map(|(name, value)| (name.to_string(), value.to_string()));
+        let config = file.clone().with_env(vars).unwrap();
+
+        assert_eq!(config.vpn.mtu, 1400);
+        assert_eq!(config.vpn.client_address, file.vpn.client_address);
+        assert_eq!(config.vpn.routes, vec![
+            "10.0.0.0/8".to_string(),
+            "192.168.0.0/16".to_string()
+        ]);
+        assert_eq!(config.connection.host.as_deref(), Some("203.0.113.7"));
+        assert!(!config.connection.nodelay);
+        assert_eq!(config.connection.keepalive, Some(Duration::from_secs(15)));
+        assert_eq!(config.retry.max_attempts, MaxAttempts::Inf);
+        assert_eq!(config.retry.backoff, 1.5);
+
+        // Keys that take strings keep values that read as numbers.
+        let vars = [
+            ("X2SSH_SOCKS_LISTEN", "1080"),
+            ("X2SSH_CONNECTION_USER", "1000"),
+        ]
+        .map(|(name, value)| (name.to_string(), value.to_string()));
+        let config = file.clone().with_env(vars).unwrap();
+        assert_eq!(config.socks.listen.as_deref(), Some("1080"));
+        assert_eq!(config.connection.user.as_deref(), Some("1000"));
+
+        // `__` reaches nested tables.
+        let vars = [
+            ("X2SSH_SOCKS_AUTH__USERNAME", "alice"),
+            ("X2SSH_SOCKS_AUTH__PASSWORD", "secret"),
+            ("X2SSH_SOCKS_RESOLVE__ONION", "remote"),
+        ]
+        .map(|(name, value)| (name.to_string(), value.to_string()));
+        let config = file.clone().with_env(vars).unwrap();
+        let auth = config.socks.auth.unwrap();
+        assert_eq!(auth.username, "alice");
+        assert_eq!(auth.password.as_deref(), Some("secret"));
+        assert_eq!(config.socks.resolve["onion"], ResolvePolicy::Remote);
+
+        assert_eq!(file.clone().with_env([]).unwrap(), file);
+        let err = file
+            .with_env([("X2SSH_VPN_MTUU".to_string(), "1400".to_string())])
+            .unwrap_err();
+        assert!(
+            format!("{err:#}").contains("did you mean `mtu`?"),
+            "{err:#}"
+        );
+
+        assert!(is_env_override("X2SSH_SOCKS_BUFFER_SIZE"));
+        assert!(!is_env_override("X2SSH_AUTO_SUDO"));
+        assert!(!is_env_override("X2SSH_VPN_"));
+        assert!(is_env_override("X2SSH_SOCKS_AUTH__USERNAME"));
+        assert!(!is_env_override("X2SSH_SOCKS_AUTH__"));
+    }
+
+    #[test]
+    fn test_profile_unknown_field_rejected() {
+        let err = AppConfig::from_toml(
+            "[profiles.work.connection]
+hots = \"a\"\n",
+        )
+        .unwrap_err();
+        let message = format!("{err:#}");
+        assert!(message.contains("in profile 'work'"), "{message}");
+        assert!(message.contains("did you mean `host`?"), "{message}");
+    }
+
+    #[test]
+    fn test_include() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("hosts")).unwrap();
+        let config = dir.path().join("config.toml");
+        std::fs::write(
+            &config,
+            r#"include = ["secrets.toml", "hosts/*.toml"]
+
+[connection]
+host = "server.example.com"
+port = 2222
+
+[vpn]
+exclude = ["10.0.0.0/8"]
+"#,
+        )
+        .unwrap();
+        std::fs::write(
+            dir.path().join("secrets.toml"),
+            "[socks.auth]\nusername = \"alice\"\npassword = \"secret\"\n",
+        )
+        .unwrap();
+        std::fs::write(
+            dir.path().join("hosts/a.toml"),
+            "[vpn]\nexclude = [\"192.168.0.0/16\"]\n[profiles.work.connection]\nport = 22\n",
+        )
+        .unwrap();
+        std::fs::write(
+            dir.path().join("hosts/b.toml"),
+            "[connection]\nhost = \"other.example.com\"\n",
+        )
+        .unwrap();
+        std::fs::write(dir.path().join("hosts/.c.toml"), "not toml").unwrap();
+        std::fs::write(dir.path().join("hosts/notes.txt"), "not toml").unwrap();
+
+        let loaded = AppConfig::load(&config).unwrap();
+        assert!(loaded.include.is_empty());
+        assert_eq!(loaded.connection.host.as_deref(), Some("other.example.com"));
+        assert_eq!(loaded.connection.port, 2222);
+        assert_eq!(loaded.vpn.exclude, vec!["192.168.0.0/16"]);
+        assert_eq!(
+            loaded
+                .socks
+                .auth
+                .as_ref()
+                .map(|auth| auth.username.as_str()),
+            Some("alice")
+        );
+        let work = AppConfig::load_profile(&config, "work").unwrap();
+        assert_eq!(work.connection.port, 22);
+
+        // A mistake is reported in the file that makes it.
+        std::fs::write(
+            dir.path().join("hosts/b.toml"),
+            "[connection]\nhots = \"a\"\n",
+        )
+        .unwrap();
+        let message = format!("{:#}", AppConfig::load(&config).unwrap_err());
+        assert!(message.contains("b.toml:"), "{message}");
+        assert!(message.contains("did you mean `host`?"), "{message}");
+
+        std::fs::write(
+            dir.path().join("hosts/b.toml"),
+            "include = [\"../config.toml\"]\n",
+        )
+        .unwrap();
+        let message = format!("{:#}", AppConfig::load(&config).unwrap_err());
+        assert!(message.starts_with("include cycle: "), "{message}");
+        assert!(message.ends_with("config.toml"), "{message}");
+
+        std::fs::write(
+            dir.path().join("hosts/b.toml"),
+            "include = [\"missing.toml\"]\n",
+        )
+        .unwrap();
+        let message = format!("{:#}", AppConfig::load(&config).unwrap_err());
+        assert!(
+            message.contains("b.toml: include 'missing.toml'"),
+            "{message}"
+        );
+        assert!(message.contains("does not exist"), "{message}");
+
+        let err = AppConfig::from_toml("include = [\"secrets.toml\"]\n").unwrap_err();
+        assert!(err.to_string().contains("only supported in config files"));
+    }
+
+    #[test]
+    fn test_wildcard_match() {
+        assert!(wildcard_match(b"*.toml", b"a.toml"));
+        assert!(!wildcard_match(b"*.toml", b".hidden.toml"));
+        assert!(wildcard_match(b"host-?.toml", b"host-1.toml"));
+        assert!(!wildcard_match(b"host-?.toml", b"host-12.toml"));
+        assert!(wildcard_match(b"*", b"anything"));
+        assert!(!wildcard_match(b"*.toml", b"a.toml.bak"));
+        assert!(wildcard_match(b".*.toml", b".hidden.toml"));
+    }
+
+    #[test]
+    fn test_unknown_field_rejected_with_suggestion() {
+        let err = AppConfig::from_toml("[vpn]\ncient_address = \"10.8.0.2/24\"\n").unwrap_err();
+        let message = err.to_string();
+        assert!(
+            message.contains("unknown field `cient_address`"),
+            "{message}"
+        );
+        assert!(
+            message.contains("did you mean `client_address`?"),
+            "{message}"
+        );
+
+        let err = AppConfig::from_toml("[retry]\nmax_attemps = 3\n").unwrap_err();
+        assert!(err.to_string().contains("did you mean `max_attempts`?"));
+    }
+
+    #[test]
+    fn test_unknown_section_rejected_with_suggestion() {
+        let err = AppConfig::from_toml("[vnp]\nmtu = 1280\n").unwrap_err();
+        assert!(err.to_string().contains("did you mean `vpn`?"));
+    }
+
+    #[test]
+    fn test_unknown_field_without_close_match() {
+        let err = AppConfig::from_toml("[socks]\ncompletely_unrelated = 1\n").unwrap_err();
+        let message = err.to_string();
+        assert!(message.contains("unknown field `completely_unrelated`"));
+        assert!(!message.contains("did you mean"));
+    }
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("500ms"), Ok(Duration::from_millis(500)));
+        assert_eq!(parse_duration("2s"), Ok(Duration::from_secs(2)));
+        assert_eq!(parse_duration("1m"), Ok(Duration::from_secs(60)));
+        assert_eq!(parse_duration("1m30s"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_duration("1.5h"), Ok(Duration::from_secs(5400)));
+        assert_eq!(parse_duration("1500"), Ok(Duration::from_millis(1500)));
+
+        assert!(parse_duration("").is_err());
+        assert!(parse_duration("5 days").is_err());
+        assert!(parse_duration("ms").is_err());
+        assert!(parse_duration("10x").is_err());
+        assert!(parse_duration("18446744073709551615s1s").is_err());
+    }
+
+    #[test]
+    fn test_format_duration() {
+        assert_eq!(format_duration(Duration::ZERO), "0s");
+        assert_eq!(format_duration(Duration::from_millis(250)), "250ms");
+        assert_eq!(format_duration(Duration::from_millis(1500)), "1500ms");
+        assert_eq!(format_duration(Duration::from_secs(90)), "90s");
+        assert_eq!(format_duration(Duration::from_secs(120)), "2m");
+        assert_eq!(format_duration(Duration::from_secs(7200)), "2h");
+    }
+
+    #[test]
+    fn test_duration_strings_in_config() {
+        let config = AppConfig::from_toml(
+            r#"
+[vpn]
+roaming_interval = "500ms"
+
+[connection]
+keepalive = "15s"
+shutdown_timeout = "2s"
+legacy_server = true
+
+[socks]
+redial_timeout = "1m"
+
+[retry]
+initial_delay = "250ms"
+max_delay = "2m"
+health_interval = 3000
+max_total_duration = "10m"
+"#,
+        )
+        .unwrap();
+
+        assert_eq!(config.vpn.roaming_interval, Duration::from_millis(500));
+        assert_eq!(config.connection.keepalive, Some(Duration::from_secs(15)));
+        assert_eq!(config.connection.shutdown_timeout, Duration::from_secs(2));
+        assert!(config.connection.legacy_server);
+        assert_eq!(config.socks.redial_timeout, Duration::from_secs(60));
+        assert_eq!(config.retry.initial_delay, Duration::from_millis(250));
+        assert_eq!(config.retry.max_delay, Duration::from_secs(120));
+        assert_eq!(config.retry.health_interval, Duration::from_secs(3));
+        assert_eq!(
+            config.retry.max_total_duration,
+            Some(Duration::from_secs(600))
+        );
+
+        let toml = config.to_toml().unwrap();
+        assert!(toml.contains("max_delay = \"2m\""), "{toml}");
+        assert!(!toml.contains("_ms"), "{toml}");
+    }
+
+    #[test]
+    fn test_health_options() {
+        let health = AppConfig::default().retry.health_options().unwrap();
+        assert_eq!(health.check, HealthCheck::Channel);
+        assert_eq!(health.failures, 1);
+        assert_eq!(health.echo_target, ("127.0.0.1".to_string(), 22));
+
+        let config = AppConfig::from_toml(
+            r#"
+[retry]
+health_check = "echo"
+health_timeout = "3s"
+health_failures = 3
+health_echo_target = "[::1]:2222"
+health_rtt_warn = "300ms"
+"#,
+        )
+        .unwrap();
+        let health = config.retry.health_options().unwrap();
+        assert_eq!(health.check, HealthCheck::Echo);
+        assert_eq!(health.timeout, Duration::from_secs(3));
+        assert_eq!(health.failures, 3);
+        assert_eq!(health.echo_target, ("::1".to_string(), 2222));
+        assert_eq!(health.degrade.rtt, Duration::from_millis(300));
+        assert_eq!(health.degrade.window, 20);
+
+        assert!(AppConfig::from_toml("[retry]\nhealth_check = \"icmp\"\n").is_err());
+        for retry in [
+            "health_echo_target = \"localhost\"",
+            "health_echo_target = \":22\"",
+            "health_failures = 0",
+            "health_failure_rate_warn = 1.5",
+        ] {
+            let config = AppConfig::from_toml(&format!("[retry]\n{retry}\n")).unwrap();
+            assert!(config.retry.health_options().is_err(), "{retry}");
+        }
+    }
+
+    #[test]
+    fn test_invalid_duration_in_config() {
+        let err = AppConfig::from_toml("[retry]\ninitial_delay = \"soon\"\n").unwrap_err();
+        assert!(err.to_string().contains("duration"), "{err}");
+        assert!(err.to_string().contains("initial_delay"), "{err}");
+
+        for value in ["1.5", "-100", "true"] {
+            let err = AppConfig::from_toml(&format!("[retry]\nmax_delay = {value}\n")).unwrap_err();
+            assert!(
+                err.to_string()
+                    .contains("expected a duration such as \"500ms\""),
+                "{err}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_zero_interval_in_config() {
+        for value in ["\"0s\"", "0"] {
+            let err =
+                AppConfig::from_toml(&format!("[vpn]\nroaming_interval = {value}\n")).unwrap_err();
+            assert!(
+                err.to_string().contains("interval must be longer than 0"),
+                "{err}"
+            );
+        }
+        let err = AppConfig::from_toml("[vpn]\nroaming_interval_ms = 0\n").unwrap_err();
+        assert!(err.to_string().contains("roaming_interval"), "{err}");
+
+        for key in ["keepalive_interval", "keepalive_timeout"] {
+            let err = AppConfig::from_toml(&format!("[vpn]\n{key} = \"0ms\"\n")).unwrap_err();
+            assert!(err.to_string().contains(key), "{err}");
+        }
+        let err = AppConfig::from_toml("[retry]\nhealth_interval = 0\n").unwrap_err();
+        assert!(err.to_string().contains("health_interval"), "{err}");
+        assert_eq!(parse_interval("2s"), Ok(Duration::from_secs(2)));
+        assert!(parse_interval("0s").is_err());
+    }
 }
@@ -0,0 +1,545 @@
+//! The control socket of a running session: a Unix socket next to its
+//! status file that answers JSON requests, one per line, for `x2ssh status`
+//! and monitoring scripts, and adds and removes forwards for
+//! `x2ssh forward`.
+//!
+//! ```text
+//! > {"command":"status"}
+//! < {"status":{"pid":4242,"mode":"vpn","state":"connected",...}}
+//! > {"command":"forward_add","forward":"-L 8080:db:5432"}
+//! < {"forward_added":"-L 8080:db:5432"}
+//! ```
+
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use serde::Deserialize;
+use serde::Serialize;
+use tokio::task::JoinHandle;
+
+use crate::forward::Forward;
+use crate::forward::Forwards;
+use crate::metrics::LogSummaryMetrics;
+use crate::socks::OpenConnections;
+use crate::status;
+use crate::status::Status;
+use crate::status::Timeline;
+use crate::status::TimelineEvent;
+#[cfg(feature = "vpn")]
+use crate::vpn::routing::RoutingState;
+
+/// How long `x2ssh status` waits for a session to answer.
+pub const QUERY_TIMEOUT: Duration = Duration::from_secs(2);
+
+pub fn socket_path(dir: &Path, pid: u32) -> PathBuf {
+    dir.join(format!("control-{}.sock", pid))
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum Request {
+    Status,
+    ForwardAdd { forward: Forward },
+    ForwardRemove { forward: Forward },
+    ForwardList,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Response {
+    Status(Box<Report>),
+    /// The forward as it runs, with the port picked for port 0.
+    ForwardAdded(Forward),
+    ForwardRemoved(Forward),
+    Forwards(Vec<Forward>),
+    Error(String),
+}
+
+/// Whether the session has an SSH connection right now.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ConnectionState {
+    Connected,
+    Reconnecting,
+    /// Reconnecting gave up after the retry policy's last attempt, or on an
+    /// error that retrying cannot fix.
+    Disconnected,
+}
+
+impl std::fmt::Display for ConnectionState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Connected => "connected",
+            Self::Reconnecting => "reconnecting",
+            Self::Disconnected => "disconnected",
+        })
+    }
+}
+
+/// A session's state, as its control socket reports it and
+/// `x2ssh status --json` prints it.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Report {
+    #[serde(flatten)]
+    pub status: Status,
+    pub state: ConnectionState,
+    pub uptime_ms: u64,
+    pub reconnects: usize,
+    /// Open SOCKS connections, for SOCKS sessions.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub socks_active: Option<u64>,
+    /// Open SOCKS connections by the destination the client asked for.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub socks_destinations: BTreeMap<String, u64>,
+    /// The reconnect attempt under way, while reconnecting.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub attempt: Option<u32>,
+    /// Median round-trip time of the recent health probes.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rtt_ms: Option<u64>,
+    /// Routes the VPN has installed, as `ip route` shows them.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub routes: Vec<String>,
+    /// The forwards running, from the command line, the config or added
+    /// over the control socket.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub forwards: Vec<Forward>,
+    /// Whether the session answered itself, rather than this being read
+    /// from its status file.
+    #[serde(default)]
+    pub live: bool,
+}
+
+impl Report {
+    /// What the status file alone tells about a session.
+    pub fn from_status(status: Status, now_ms: u64) -> Self {
+        let state = match status.history.last().map(|entry| &entry.event) {
+            Some(TimelineEvent::Disconnected { .. }) => ConnectionState::Reconnecting,
+            Some(TimelineEvent::ReconnectFailed { .. }) => ConnectionState::Disconnected,
+            _ => ConnectionState::Connected,
+        };
+        Self {
+            state,
+            uptime_ms: now_ms.saturating_sub(status.session.started_ms),
+            reconnects: status.reconnects(),
+            socks_active: None,
+            socks_destinations: BTreeMap::new(),
+            attempt: None,
+            rtt_ms: None,
+            routes: Vec::new(),
+            forwards: Vec::new(),
+            live: false,
+            status,
+        }
+    }
+
+    /// [`Status::render`], with the live details in between.
+    pub fn render(&self, history: bool, now_ms: u64) -> String {
+        let mut out = self.status.render(false, now_ms);
+        match (self.state, self.attempt) {
+            (ConnectionState::Connected, _) => {}
+            (state, Some(attempt)) => out.push_str(&format!("  {} (attempt {})\n", state, attempt)),
+            (state, None) => out.push_str(&format!("  {}\n", state)),
+        }
+        if let Some(rtt_ms) = self.rtt_ms {
+            out.push_str(&format!("  health probe rtt: {}ms\n", rtt_ms));
+        }
+        if let Some(active) = self.socks_active {
+            out.push_str(&format!("  socks: {} open connection(s)\n", active));
+        }
+        for route in &self.routes {
+            out.push_str(&format!("  route: {}\n", route));
+        }
+        for forward in &self.forwards {
+            out.push_str(&format!("  forward: {}\n", forward));
+        }
+        if history {
+            out.push_str(&self.status.render_history());
+        }
+        out
+    }
+}
+
+/// What the control socket reports on.
+#[derive(Clone)]
+pub struct Control {
+    pub timeline: Arc<Timeline>,
+    pub metrics: Arc<LogSummaryMetrics>,
+    /// SOCKS sessions report their open connections.
+    pub socks: Option<Arc<OpenConnections>>,
+    /// VPN sessions report the routes recorded for this TUN device.
+    pub client_tun: Option<String>,
+    pub forwards: Arc<Forwards>,
+}
+
+impl Control {
+    pub fn report(&self) -> anyhow::Result<Report> {
+        let mut status = self
+            .timeline
+            .status()
+            .ok_or_else(|| anyhow::anyhow!("the session publishes no status"))?;
+        let snapshot = self.metrics.snapshot();
+        if self.client_tun.is_some() {
+            status.traffic = Some(snapshot.into());
+        }
+        let mut report = Report::from_status(status, status::now_ms());
+        if let Some(open) = &self.socks {
+            report.socks_active = Some(snapshot.socks_active());
+            report.socks_destinations = open.by_destination();
+        }
+        if report.state == ConnectionState::Reconnecting {
+            report.attempt = Some(self.timeline.attempt()).filter(|attempt| *attempt > 0);
+        }
+        report.rtt_ms = self.timeline.rtt().map(|rtt| rtt.as_millis() as u64);
+        #[cfg(feature = "vpn")]
+        if let Some(tun) = &self.client_tun {
+            report.routes = RoutingState::read(tun)?
+                .map(|state| state.routes())
+                .unwrap_or_default();
+        }
+        report.forwards = self.forwards.list();
+        report.live = true;
+        Ok(report)
+    }
+
+    /// A human-readable snapshot for the log: the report with its history,
+    /// open SOCKS connections by destination and the traffic counters.
+    pub fn dump(&self) -> String {
+        let mut out = match self.report() {
+            Ok(report) => report.render(true, status::now_ms()),
+            Err(e) => format!("  {:#}\n", e),
+        };
+        if let Some(open) = &self.socks {
+            for (destination, count) in open.by_destination() {
+                out.push_str(&format!("  socks: {} open to {}\n", count, destination));
+            }
+        }
+        out.push_str(&format!("  counters: {}\n", self.metrics.summary()));
+        let latency = self.metrics.latency_summary();
+        if !latency.is_empty() {
+            out.push_str(&format!("  vpn latency p50/p99: {}\n", latency));
+        }
+        out
+    }
+
+    async fn answer(&self, line: &str) -> Response {
+        let request = match serde_json::from_str(line) {
+            Ok(request) => request,
+            Err(e) => return Response::Error(format!("invalid request: {}", e)),
+        };
+        let response = match request {
+            Request::Status => self
+                .report()
+                .map(|report| Response::Status(Box::new(report))),
+            Request::ForwardAdd { forward } => {
+                self.forwards.add(forward).await.map(Response::ForwardAdded)
+            }
+            Request::ForwardRemove { forward } => self
+                .forwards
+                .remove(&forward)
+                .await
+                .map(|()| Response::ForwardRemoved(forward)),
+            Request::ForwardList => Ok(Response::Forwards(self.forwards.list())),
+        };
+        response.unwrap_or_else(|e| Response::Error(format!("{:#}", e)))
+    }
+}
+
+/// A listening control socket, removed when dropped.
+pub struct ControlSocket {
+    path: PathBuf,
+    task: JoinHandle<()>,
+}
+
+impl ControlSocket {
+    /// Listens on this process's control socket in `dir`, which only the
+    /// user running x2ssh may connect to.
+    #[cfg(unix)]
+    pub fn start(dir: &Path, control: Control) -> anyhow::Result<Self> {
+        use std::os::unix::fs::PermissionsExt;
+
+        use tracing::debug;
+
+        std::fs::create_dir_all(dir)?;
+        let path = socket_path(dir, std::process::id());
+        let _ = std::fs::remove_file(&path);
+        let listener = tokio::net::UnixListener::bind(&path)
+            .map_err(|e| anyhow::anyhow!("cannot listen on {}: {}", path.display(), e))?;
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o600))?;
+
+        let control = Arc::new(control);
+        let task = tokio::spawn(async move {
+            loop {
+                let stream = match listener.accept().await {
+                    Ok((stream, _)) => stream,
+                    Err(e) => {
+                        debug!("control socket accept error: {}", e);
+                        continue;
+                    }
+                };
+                let control = control.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = serve(&control, stream).await {
+                        debug!("control connection error: {}", e);
+                    }
+                });
+            }
+        });
+        Ok(Self { path, task })
+    }
+
+    #[cfg(not(unix))]
+    pub fn start(_dir: &Path, _control: Control) -> anyhow::Result<Self> {
+        anyhow::bail!("the control socket is only supported on Unix")
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+/// Logs [`Control::dump`] whenever the process gets SIGUSR1, which works
+/// without the control socket.
+#[cfg(unix)]
+pub fn dump_on_sigusr1(control: Control) -> anyhow::Result<()> {
+    use tokio::signal::unix::SignalKind;
+    use tokio::signal::unix::signal;
+    use tracing::info;
+
+    let mut usr1 = signal(SignalKind::user_defined1())?;
+    tokio::spawn(async move {
+        while usr1.recv().await.is_some() {
+            info!("Status dump (SIGUSR1):");
+            for line in control.dump().lines() {
+                info!("{}", line);
+            }
+        }
+    });
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn dump_on_sigusr1(_control: Control) -> anyhow::Result<()> {
+    Ok(())
+}
+
+impl Drop for ControlSocket {
+    fn drop(&mut self) {
+        self.task.abort();
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+#[cfg(unix)]
+async fn serve(control: &Control, stream: tokio::net::UnixStream) -> anyhow::Result<()> {
+    use tokio::io::AsyncBufReadExt;
+    use tokio::io::AsyncWriteExt;
+
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = tokio::io::BufReader::new(reader).lines();
+    while let Some(line) = lines.next_line().await? {
+        let mut response = serde_json::to_vec(&control.answer(&line).await)?;
+        response.push(b'\n');
+        writer.write_all(&response).await?;
+    }
+    Ok(())
+}
+
+/// Sends `request` to the control socket at `path` and returns the answer.
+#[cfg(unix)]
+pub async fn query(path: &Path, request: &Request) -> anyhow::Result<Response> {
+    use tokio::io::AsyncBufReadExt;
+    use tokio::io::AsyncWriteExt;
+
+    let exchange = async {
+        let stream = tokio::net::UnixStream::connect(path).await?;
+        let (reader, mut writer) = stream.into_split();
+        let mut line = serde_json::to_vec(request)?;
+        line.push(b'\n');
+        writer.write_all(&line).await?;
+
+        let mut answer = String::new();
+        tokio::io::BufReader::new(reader)
+            .read_line(&mut answer)
+            .await?;
+        anyhow::Ok(serde_json::from_str(&answer)?)
+    };
+    tokio::time::timeout(QUERY_TIMEOUT, exchange)
+        .await
+        .map_err(|_| anyhow::anyhow!("{} did not answer", path.display()))?
+}
+
+#[cfg(not(unix))]
+pub async fn query(_path: &Path, _request: &Request) -> anyhow::Result<Response> {
+    anyhow::bail!("the control socket is only supported on Unix")
+}
+
+/// The control sockets session `pid` may have, in the order to try them.
+fn find_sockets(pid: u32) -> impl Iterator<Item = PathBuf> {
+    status::status_dirs()
+        .into_iter()
+        .map(move |dir| socket_path(&dir, pid))
+        .filter(|path| path.exists())
+}
+
+/// Sends `request` to session `pid`, turning an error answer into an
+/// error.
+pub async fn request(pid: u32, request: &Request) -> anyhow::Result<Response> {
+    let path = find_sockets(pid).next().ok_or_else(|| {
+        anyhow::anyhow!("session {} has no control socket this user can reach", pid)
+    })?;
+    match query(&path, request).await? {
+        Response::Error(e) => anyhow::bail!("session {}: {}", pid, e),
+        response => Ok(response),
+    }
+}
+
+/// The session's own report, or what its status file says when it cannot
+/// be asked: another user's session, or one from an older x2ssh.
+pub async fn report(status: Status, now_ms: u64) -> Report {
+    for path in find_sockets(status.session.pid) {
+        if let Ok(Response::Status(report)) = query(&path, &Request::Status).await {
+            return *report;
+        }
+    }
+    Report::from_status(status, now_ms)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metrics::Metric;
+    use crate::metrics::MetricsSink;
+    use crate::retry::ErrorClass;
+    use crate::status::DisconnectCause;
+    use crate::status::SessionInfo;
+
+    #[test]
+    fn test_report_from_status() {
+        let session = SessionInfo::new("socks", "user@host:22");
+        let started = session.started_ms;
+        let timeline = Timeline::published(tempfile::tempdir().unwrap().path(), session).unwrap();
+        let report =
+            |timeline: &Timeline| Report::from_status(timeline.status().unwrap(), started + 5000);
+
+        assert_eq!(report(&timeline).state, ConnectionState::Connected);
+        assert_eq!(report(&timeline).uptime_ms, 5000);
+        timeline.record(TimelineEvent::Disconnected {
+            cause: DisconnectCause::HealthCheck,
+        });
+        assert_eq!(report(&timeline).state, ConnectionState::Reconnecting);
+        timeline.record(TimelineEvent::Reconnected {
+            downtime_ms: 800,
+            attempts: 2,
+        });
+        assert_eq!(report(&timeline).state, ConnectionState::Connected);
+        assert_eq!(report(&timeline).reconnects, 1);
+        timeline.record(TimelineEvent::ReconnectFailed {
+            attempts: 3,
+            class: Some(ErrorClass::Timeout),
+            downtime_ms: 60_000,
+        });
+        assert_eq!(report(&timeline).state, ConnectionState::Disconnected);
+        assert!(!report(&timeline).live);
+
+        let timeline = Arc::new(timeline);
+        timeline.record(TimelineEvent::Disconnected {
+            cause: DisconnectCause::RemoteClosed,
+        });
+        timeline.set_attempt(2);
+        let control = Control {
+            timeline,
+            metrics: Arc::new(LogSummaryMetrics::new()),
+            socks: None,
+            client_tun: None,
+            forwards: Arc::new(Forwards::new(Default::default())),
+        };
+        let live = control.report().unwrap();
+        assert_eq!(live.attempt, Some(2));
+        assert!(
+            live.render(false, started)
+                .contains("\n  reconnecting (attempt 2)\n")
+        );
+        let dump = control.dump();
+        assert!(dump.contains("\n  reconnecting (attempt 2)\n"), "{dump}");
+        assert!(dump.contains("connection closed by remote"), "{dump}");
+        assert!(dump.contains("\n  counters: reconnects 0 (failed 0), socks 0 active"));
+
+        assert!(Timeline::new().status().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_control_socket() {
+        let dir = tempfile::tempdir().unwrap();
+        let session = SessionInfo::new("socks", "user@host:22");
+        let metrics = Arc::new(LogSummaryMetrics::new());
+        metrics.record(Metric::SocksAccepted);
+        metrics.record(Metric::SocksAccepted);
+        metrics.record(Metric::SocksClosed {
+            sent: 1,
+            received: 2,
+        });
+        let open = Arc::new(OpenConnections::default());
+        let _connection = open.open("example.com:443".to_string());
+        let socket = ControlSocket::start(dir.path(), Control {
+            timeline: Arc::new(Timeline::published(dir.path(), session.clone()).unwrap()),
+            metrics,
+            socks: Some(open.clone()),
+            client_tun: None,
+            forwards: Arc::new(Forwards::new(Default::default())),
+        })
+        .unwrap();
+        assert_eq!(socket.path(), socket_path(dir.path(), session.pid));
+
+        let Response::Status(report) = query(socket.path(), &Request::Status).await.unwrap() else {
+            panic!("expected a status");
+        };
+        assert_eq!(report.status.session, session);
+        assert_eq!(report.state, ConnectionState::Connected);
+        assert_eq!(report.socks_active, Some(1));
+        assert_eq!(
+            report.socks_destinations,
+            BTreeMap::from([("example.com:443".to_string(), 1)])
+        );
+        assert!(report.live);
+
+        let control = Control {
+            timeline: Arc::new(Timeline::new()),
+            metrics: Arc::new(LogSummaryMetrics::new()),
+            socks: None,
+            client_tun: None,
+            forwards: Arc::new(Forwards::new(Default::default())),
+        };
+        assert!(
+            matches!(control.answer("{\"command\":\"reboot\"}").await, Response::Error(e) if e.starts_with("invalid request"))
+        );
+        assert_eq!(
+            control.answer("{\"command\":\"status\"}").await,
+            Response::Error("the session publishes no status".to_string())
+        );
+        assert_eq!(
+            control.answer("{\"command\":\"forward_list\"}").await,
+            Response::Forwards(Vec::new())
+        );
+        assert!(matches!(
+            control
+                .answer("{\"command\":\"forward_add\",\"forward\":\"-L 8080\"}")
+                .await,
+            Response::Error(e) if e.starts_with("invalid request")
+        ));
+        assert_eq!(
+            serde_json::to_string(&Request::ForwardAdd {
+                forward: "-L 8080:db:5432".parse().unwrap()
+            })
+            .unwrap(),
+            "{\"command\":\"forward_add\",\"forward\":\"-L 8080:db:5432\"}"
+        );
+
+        let path = socket.path().to_path_buf();
+        drop(socket);
+        assert!(!path.exists());
+    }
+}
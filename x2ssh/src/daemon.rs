@@ -0,0 +1,290 @@
+//! Running x2ssh in the background (`--daemon`): detaching from the
+//! terminal, the pidfile that keeps a second copy from starting, and the
+//! rotating log file that takes the place of the terminal.
+
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+
+/// Size at which the log file is rotated.
+pub const LOG_MAX_SIZE: u64 = 10 * 1024 * 1024;
+
+/// Rotated log files kept besides the current one.
+pub const LOG_KEEP: usize = 5;
+
+/// Where a daemon keeps its pidfile and its log.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Daemon {
+    pub pidfile: PathBuf,
+    pub log_file: PathBuf,
+}
+
+/// `x2ssh.pid` next to the session status files.
+pub fn default_pidfile() -> PathBuf {
+    crate::status::status_dir().join("x2ssh.pid")
+}
+
+/// `/var/log/x2ssh/x2ssh.log` as root, else under `$XDG_STATE_HOME`
+/// (`~/.local/state`).
+pub fn default_log_file() -> PathBuf {
+    if crate::elevate::is_root() {
+        return PathBuf::from("/var/log/x2ssh/x2ssh.log");
+    }
+    std::env::var_os("XDG_STATE_HOME")
+        .map(PathBuf::from)
+        .filter(|dir| dir.is_absolute())
+        .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".local/state")))
+        .unwrap_or_else(std::env::temp_dir)
+        .join("x2ssh")
+        .join("x2ssh.log")
+}
+
+/// The pid a pidfile holds.
+pub fn read_pidfile(path: &Path) -> anyhow::Result<u32> {
+    let content = std::fs::read_to_string(path)
+        .map_err(|e| anyhow::anyhow!("cannot read {}: {}", path.display(), e))?;
+    content
+        .trim()
+        .parse()
+        .map_err(|_| anyhow::anyhow!("{} does not hold a pid", path.display()))
+}
+
+/// The daemon's pidfile, locked for as long as the daemon runs and removed
+/// again when dropped. The lock, not the pid in it, tells whether a daemon
+/// is running: a pid left behind by one that died may belong to another
+/// process by now, and two daemons starting at once cannot both get it.
+#[derive(Debug)]
+pub struct PidFile {
+    path: PathBuf,
+    file: File,
+}
+
+impl PidFile {
+    /// Locks `path`, failing when a running daemon holds it. The lock is
+    /// inherited by [`detach`]'s child, which then calls [`write_pid`].
+    ///
+    /// [`write_pid`]: PidFile::write_pid
+    pub fn lock(path: &Path) -> anyhow::Result<Self> {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        loop {
+            let file = File::options()
+                .create(true)
+                .truncate(false)
+                .write(true)
+                .open(path)
+                .map_err(|e| anyhow::anyhow!("cannot open {}: {}", path.display(), e))?;
+            match file.try_lock() {
+                Ok(()) => {}
+                Err(std::fs::TryLockError::WouldBlock) => match read_pidfile(path) {
+                    Ok(pid) => anyhow::bail!(
+                        "x2ssh is already running (pid {} in {}); stop it with x2ssh stop \
+                         --pidfile {}",
+                        pid,
+                        path.display(),
+                        path.display()
+                    ),
+                    Err(_) => {
+                        anyhow::bail!("x2ssh is already running ({} is locked)", path.display())
+                    }
+                },
+                Err(std::fs::TryLockError::Error(e)) => {
+                    anyhow::bail!("cannot lock {}: {}", path.display(), e)
+                }
+            }
+            // A daemon exiting removes the file before giving up the lock;
+            // one locked after that is no longer the pidfile.
+            match std::fs::metadata(path) {
+                Ok(metadata) if same_file(&metadata, &file.metadata()?) => {
+                    return Ok(Self {
+                        path: path.to_path_buf(),
+                        file,
+                    });
+                }
+                _ => continue,
+            }
+        }
+    }
+
+    /// Writes this process's pid to the pidfile.
+    pub fn write_pid(&mut self) -> anyhow::Result<()> {
+        self.file.set_len(0)?;
+        writeln!(self.file, "{}", std::process::id())
+            .map_err(|e| anyhow::anyhow!("cannot write {}: {}", self.path.display(), e))
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+#[cfg(unix)]
+fn same_file(a: &std::fs::Metadata, b: &std::fs::Metadata) -> bool {
+    use std::os::unix::fs::MetadataExt;
+
+    (a.dev(), a.ino()) == (b.dev(), b.ino())
+}
+
+/// Without inodes to compare, the open file is taken to be the pidfile.
+#[cfg(not(unix))]
+fn same_file(_a: &std::fs::Metadata, _b: &std::fs::Metadata) -> bool {
+    true
+}
+
+/// Forks into the background: the parent prints the daemon's pid and
+/// exits, and only the child returns, in a new session with its standard
+/// streams on /dev/null. Must run before any threads are started.
+#[cfg(unix)]
+pub fn detach(log_file: &Path) -> anyhow::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    let null = File::options().read(true).write(true).open("/dev/null")?;
+    match unsafe { libc::fork() } {
+        -1 => anyhow::bail!("cannot fork: {}", std::io::Error::last_os_error()),
+        0 => {}
+        pid => {
+            println!(
+                "x2ssh running in the background (pid {}), logging to {}",
+                pid,
+                log_file.display()
+            );
+            std::process::exit(0);
+        }
+    }
+    if unsafe { libc::setsid() } == -1 {
+        anyhow::bail!("cannot detach: {}", std::io::Error::last_os_error());
+    }
+    for fd in 0..=2 {
+        unsafe { libc::dup2(null.as_raw_fd(), fd) };
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn detach(_log_file: &Path) -> anyhow::Result<()> {
+    anyhow::bail!("--daemon is only supported on Unix; run x2ssh as a service instead")
+}
+
+/// A log file that, once it reaches `max_size`, is renamed to `NAME.1`
+/// (older ones moving on to `NAME.2` up to `NAME.{keep}`) and started
+/// afresh.
+#[derive(Debug)]
+pub struct RotatingFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    max_size: u64,
+    keep: usize,
+}
+
+impl RotatingFile {
+    pub fn open(path: &Path, max_size: u64, keep: usize) -> anyhow::Result<Self> {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let file = open_append(path)
+            .map_err(|e| anyhow::anyhow!("cannot open {}: {}", path.display(), e))?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            size: file.metadata()?.len(),
+            file,
+            max_size,
+            keep,
+        })
+    }
+
+    fn rotated(&self, n: usize) -> PathBuf {
+        let mut name = self.path.clone().into_os_string();
+        name.push(format!(".{}", n));
+        PathBuf::from(name)
+    }
+
+    fn rotate(&mut self) -> std::io::Result<()> {
+        if self.keep == 0 {
+            self.file.set_len(0)?;
+        } else {
+            for n in (1..self.keep).rev() {
+                let _ = std::fs::rename(self.rotated(n), self.rotated(n + 1));
+            }
+            std::fs::rename(&self.path, self.rotated(1))?;
+            self.file = open_append(&self.path)?;
+        }
+        self.size = 0;
+        Ok(())
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if self.size > 0 && self.size + buf.len() as u64 > self.max_size {
+            self.rotate()?;
+        }
+        let written = self.file.write(buf)?;
+        self.size += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.file.flush()
+    }
+}
+
+fn open_append(path: &Path) -> std::io::Result<File> {
+    File::options().create(true).append(true).open(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rotating_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("logs").join("x2ssh.log");
+        let mut log = RotatingFile::open(&path, 10, 2).unwrap();
+        for line in ["aaaaaa\n", "bbbbbb\n", "cccccc\n", "dddddd\n"] {
+            log.write_all(line.as_bytes()).unwrap();
+        }
+
+        let read = |path: &Path| std::fs::read_to_string(path).unwrap();
+        assert_eq!(read(&path), "dddddd\n");
+        assert_eq!(read(&log.rotated(1)), "cccccc\n");
+        assert_eq!(read(&log.rotated(2)), "bbbbbb\n");
+        assert!(!log.rotated(3).exists());
+
+        // Reopening continues the current file.
+        let mut log = RotatingFile::open(&path, 10, 2).unwrap();
+        log.write_all(b"ee\n").unwrap();
+        assert_eq!(read(&path), "dddddd\nee\n");
+    }
+
+    #[test]
+    fn test_pidfile() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("run").join("x2ssh.pid");
+        let mut pidfile = PidFile::lock(&path).unwrap();
+        pidfile.write_pid().unwrap();
+        assert_eq!(read_pidfile(&path).unwrap(), std::process::id());
+        let err = PidFile::lock(&path).unwrap_err();
+        assert!(
+            err.to_string().starts_with("x2ssh is already running (pid"),
+            "{err}"
+        );
+        drop(pidfile);
+        assert!(!path.exists());
+
+        // A pid left behind without a lock, even of a running process, is
+        // stale.
+        std::fs::write(&path, format!("{}\n", std::process::id())).unwrap();
+        let mut pidfile = PidFile::lock(&path).unwrap();
+        pidfile.write_pid().unwrap();
+        assert_eq!(read_pidfile(&path).unwrap(), std::process::id());
+
+        std::fs::write(&path, "x2ssh\n").unwrap();
+        assert!(read_pidfile(&path).is_err());
+    }
+}
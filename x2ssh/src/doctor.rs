@@ -0,0 +1,382 @@
+//! `x2ssh doctor`: checks, before a VPN session is started, everything it
+//! needs on both ends, and says how to fix what is missing. Without it, most
+//! of these problems surface as an agent channel that closes with no
+//! explanation.
+
+use std::path::Path;
+
+use crate::config::Elevation;
+use crate::config::VpnConfig;
+use crate::transport::Transport;
+
+/// Bit of `CAP_NET_ADMIN` in the capability sets.
+const CAP_NET_ADMIN: u32 = 12;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    Pass,
+    /// Works, but may cause trouble.
+    Warn,
+    /// A VPN session would fail.
+    Fail,
+}
+
+/// The result of one check.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnosis {
+    pub check: &'static str,
+    pub outcome: Outcome,
+    pub detail: String,
+    /// What to do about a warning or failure.
+    pub fix: Option<String>,
+}
+
+impl Diagnosis {
+    pub fn pass(check: &'static str, detail: impl Into<String>) -> Self {
+        Self {
+            check,
+            outcome: Outcome::Pass,
+            detail: detail.into(),
+            fix: None,
+        }
+    }
+
+    pub fn warn(check: &'static str, detail: impl Into<String>, fix: impl Into<String>) -> Self {
+        Self {
+            check,
+            outcome: Outcome::Warn,
+            detail: detail.into(),
+            fix: Some(fix.into()),
+        }
+    }
+
+    pub fn fail(check: &'static str, detail: impl Into<String>, fix: impl Into<String>) -> Self {
+        Self {
+            check,
+            outcome: Outcome::Fail,
+            detail: detail.into(),
+            fix: Some(fix.into()),
+        }
+    }
+}
+
+impl std::fmt::Display for Diagnosis {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let label = match self.outcome {
+            Outcome::Pass => "ok  ",
+            Outcome::Warn => "warn",
+            Outcome::Fail => "FAIL",
+        };
+        write!(f, "[{}] {}: {}", label, self.check, self.detail)?;
+        if let Some(fix) = &self.fix {
+            write!(f, "\n       fix: {}", fix)?;
+        }
+        Ok(())
+    }
+}
+
+/// The checks of this machine; [`mtu`] comes separately, with the server's
+/// uplink MTU when it can be read.
+pub fn local(config: &VpnConfig) -> Vec<Diagnosis> {
+    let mut diagnoses = vec![privileges(config)];
+    if config.persistent_tun {
+        let device = Path::new("/sys/class/net").join(&config.client_tun);
+        diagnoses.push(if device.exists() {
+            Diagnosis::pass("local TUN", format!("{} exists", config.client_tun))
+        } else {
+            Diagnosis::fail(
+                "local TUN",
+                format!(
+                    "persistent_tun is set, but {} does not exist",
+                    config.client_tun
+                ),
+                format!(
+                    "create it once as root: ip tuntap add mode tun user $USER name {}",
+                    config.client_tun
+                ),
+            )
+        });
+    } else {
+        diagnoses.push(if Path::new("/dev/net/tun").exists() {
+            Diagnosis::pass("local TUN", "/dev/net/tun is present")
+        } else {
+            Diagnosis::fail(
+                "local TUN",
+                "/dev/net/tun is missing",
+                "load the tun module (modprobe tun); in a container, pass the device in, e.g. \
+                 docker run --device /dev/net/tun --cap-add NET_ADMIN",
+            )
+        });
+    }
+    if !config.persistent_tun {
+        diagnoses.push(tool(
+            "ip",
+            "routes are installed with it",
+            "install iproute2",
+        ));
+    }
+    if config.kill_switch || config.cgroup.is_some() {
+        diagnoses.push(tool(
+            "nft",
+            "the kill switch and cgroup routing use nftables",
+            "install nftables",
+        ));
+    }
+    diagnoses
+}
+
+/// Root or `CAP_NET_ADMIN`, which TUN devices, routes and firewall rules
+/// need, unless only an existing TUN device is attached.
+fn privileges(config: &VpnConfig) -> Diagnosis {
+    const CHECK: &str = "local privileges";
+    // The kill switch's firewall rules and the cgroup's policy routing need
+    // root even on a persistent TUN device.
+    if config.persistent_tun && !config.kill_switch && config.cgroup.is_none() {
+        return Diagnosis::pass(CHECK, "not needed for a persistent TUN device");
+    }
+    if crate::elevate::is_root() {
+        return Diagnosis::pass(CHECK, "running as root");
+    }
+    let status = std::fs::read_to_string("/proc/self/status").unwrap_or_default();
+    if has_cap_net_admin(&status) {
+        return Diagnosis::pass(CHECK, "CAP_NET_ADMIN");
+    }
+    Diagnosis::fail(
+        CHECK,
+        "neither root nor CAP_NET_ADMIN",
+        "run x2ssh vpn with sudo, or pass --auto-sudo",
+    )
+}
+
+/// Whether `CapEff` in a `/proc/<pid>/status` includes `CAP_NET_ADMIN`.
+fn has_cap_net_admin(status: &str) -> bool {
+    status
+        .lines()
+        .find_map(|line| line.strip_prefix("CapEff:"))
+        .and_then(|caps| u64::from_str_radix(caps.trim(), 16).ok())
+        .is_some_and(|caps| caps & (1 << CAP_NET_ADMIN) != 0)
+}
+
+/// Whether `name` is on the `PATH`.
+fn tool(name: &'static str, why: &str, fix: &str) -> Diagnosis {
+    let found = std::env::var_os("PATH")
+        .map(|path| std::env::split_paths(&path).any(|dir| dir.join(name).is_file()))
+        .unwrap_or(false);
+    if found {
+        Diagnosis::pass(name, "found")
+    } else {
+        Diagnosis::fail(name, format!("not found; {}", why), fix)
+    }
+}
+
+/// Whether the tunnel MTU is workable, and fits the server's uplink when
+/// that is known.
+pub fn mtu(mtu: u16, uplink: Option<u32>) -> Diagnosis {
+    const CHECK: &str = "MTU";
+    if mtu < 576 {
+        return Diagnosis::fail(
+            CHECK,
+            format!("{} is below 576, the least IPv4 hosts must accept", mtu),
+            "set vpn.mtu to 1280 or more",
+        );
+    }
+    if mtu < 1280 {
+        return Diagnosis::warn(
+            CHECK,
+            format!("{} is below 1280, the least IPv6 needs", mtu),
+            "set vpn.mtu to 1280 or more for IPv6 through the tunnel",
+        );
+    }
+    if let Some(uplink) = uplink
+        && u32::from(mtu) > uplink
+    {
+        return Diagnosis::warn(
+            CHECK,
+            format!(
+                "{} is larger than the server's uplink MTU of {}; larger packets are fragmented \
+                 or dropped on the way out",
+                mtu, uplink
+            ),
+            format!("set vpn.mtu to {} or less", uplink),
+        );
+    }
+    match uplink {
+        Some(uplink) => Diagnosis::pass(CHECK, format!("{} (server uplink {})", mtu, uplink)),
+        None => Diagnosis::pass(CHECK, mtu.to_string()),
+    }
+}
+
+/// What to try when the SSH connection itself fails.
+pub fn ssh(error: &anyhow::Error, destination: &str) -> Diagnosis {
+    let message = format!("{:#}", error);
+    let lower = message.to_lowercase();
+    let fix = if lower.contains("authentication failed") {
+        "check the user, the key (-i, or the ssh-agent) and that it is in the server's \
+         ~/.ssh/authorized_keys"
+            .to_string()
+    } else if lower.contains("identity") {
+        "pass a key with -i, or load one into the ssh-agent".to_string()
+    } else if lower.contains("host key") {
+        "check --host-key, or the server's entry in ~/.ssh/known_hosts".to_string()
+    } else if lower.contains("refused") {
+        "nothing listens on that port; check -p, and that sshd is running".to_string()
+    } else if lower.contains("timed out") || lower.contains("timeout") {
+        "the server does not answer; check the host name, firewalls and the network".to_string()
+    } else if lower.contains("resolve") || lower.contains("lookup") {
+        "the host name does not resolve; check its spelling and DNS".to_string()
+    } else {
+        format!("check that ssh {} works", destination)
+    };
+    Diagnosis::fail("SSH", message, fix)
+}
+
+/// The checks of the server, over an SSH session that is up.
+pub async fn remote(transport: &Transport, config: &VpnConfig) -> Vec<Diagnosis> {
+    let mut diagnoses = Vec::new();
+    diagnoses.push(match run(transport, "uname -sm").await {
+        Ok((0, system)) if system == "Linux x86_64" => Diagnosis::pass("server system", system),
+        Ok((_, system)) => Diagnosis::fail(
+            "server system",
+            format!("{}; the bundled agent runs on Linux x86_64", system),
+            "use a Linux x86_64 server",
+        ),
+        Err(e) => Diagnosis::fail("server system", e, "check that commands run over SSH"),
+    });
+    diagnoses.push(root(transport, config).await);
+    if config.shared_agent.is_none() {
+        diagnoses.push(match run(transport, "test -c /dev/net/tun").await {
+            Ok((0, _)) => Diagnosis::pass("server TUN", "/dev/net/tun is present"),
+            Ok(_) => Diagnosis::fail(
+                "server TUN",
+                "/dev/net/tun is missing",
+                "load the tun module on the server (modprobe tun); in a container or VPS without \
+                 it, ask for TUN/TAP to be enabled",
+            ),
+            Err(e) => Diagnosis::fail("server TUN", e, "check that commands run over SSH"),
+        });
+    }
+    let uplink = run(
+        transport,
+        "dev=$(ip route get 1.1.1.1 2>/dev/null | sed -n 's/.* dev \\([^ ]*\\).*/\\1/p'); cat \
+         /sys/class/net/\"$dev\"/mtu",
+    )
+    .await
+    .ok()
+    .and_then(|(code, mtu)| (code == 0).then(|| mtu.parse().ok()).flatten());
+    diagnoses.push(mtu(config.mtu, uplink));
+    diagnoses
+}
+
+/// Whether the agent can run as root on the server, tried the way
+/// [`RootAccess::detect`](crate::vpn::elevation::RootAccess::detect) does,
+/// but without uploading anything.
+async fn root(transport: &Transport, config: &VpnConfig) -> Diagnosis {
+    const CHECK: &str = "server root";
+    match run(transport, "id -u").await {
+        Ok((0, uid)) if uid == "0" => return Diagnosis::pass(CHECK, "logged in as root"),
+        Ok(_) => {}
+        Err(e) => return Diagnosis::fail(CHECK, e, "check that commands run over SSH"),
+    }
+    if config.elevation != Elevation::Doas {
+        match run(transport, "sudo -n true").await {
+            Ok((0, _)) => return Diagnosis::pass(CHECK, "passwordless sudo"),
+            _ => {
+                if let Ok(Some(source)) = config.sudo_password() {
+                    return Diagnosis::warn(
+                        CHECK,
+                        format!("sudo needs a password, to be taken from {}", source),
+                        "make sure the password is right; it is only tried when the VPN starts",
+                    );
+                }
+            }
+        }
+    }
+    if config.elevation != Elevation::Sudo
+        && let Ok((0, _)) = run(
+            transport,
+            "command -v doas >/dev/null || exit 127; doas -n true",
+        )
+        .await
+    {
+        return Diagnosis::pass(CHECK, "passwordless doas");
+    }
+    Diagnosis::fail(
+        CHECK,
+        "the SSH user is not root and cannot get root without a password",
+        "allow passwordless sudo for the SSH user (USER ALL=(root) NOPASSWD: ALL in \
+         /etc/sudoers.d/x2ssh), or set vpn.sudo_password_file",
+    )
+}
+
+/// Runs `command` on the server: its exit code and trimmed output.
+async fn run(transport: &Transport, command: &str) -> Result<(u32, String), String> {
+    let result = transport
+        .exec(command)
+        .await
+        .map_err(|e| format!("running {} failed: {}", command, e))?;
+    Ok((
+        result.exit_code,
+        String::from_utf8_lossy(&result.stdout).trim().to_string(),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_has_cap_net_admin() {
+        assert!(has_cap_net_admin(
+            "Name:\tx2ssh\nCapEff:\t000001ffffffffff\n"
+        ));
+        assert!(has_cap_net_admin("CapEff:\t0000000000001000\n"));
+        assert!(!has_cap_net_admin("CapEff:\t0000000000000000\n"));
+        assert!(!has_cap_net_admin("Name:\tx2ssh\n"));
+    }
+
+    #[test]
+    fn test_privileges() {
+        let not_needed = "not needed for a persistent TUN device";
+        let mut config = VpnConfig {
+            persistent_tun: true,
+            ..VpnConfig::default()
+        };
+        assert_eq!(privileges(&config).detail, not_needed);
+
+        config.cgroup = Some("x2ssh".to_string());
+        assert_ne!(privileges(&config).detail, not_needed);
+        config.cgroup = None;
+        config.kill_switch = true;
+        assert_ne!(privileges(&config).detail, not_needed);
+    }
+
+    #[test]
+    fn test_mtu() {
+        assert_eq!(mtu(1400, None).outcome, Outcome::Pass);
+        assert_eq!(mtu(1400, Some(1500)).outcome, Outcome::Pass);
+        assert_eq!(mtu(1400, Some(1280)).outcome, Outcome::Warn);
+        assert_eq!(mtu(1000, None).outcome, Outcome::Warn);
+        assert_eq!(mtu(500, None).outcome, Outcome::Fail);
+    }
+
+    #[test]
+    fn test_ssh_fix() {
+        let fix = |message: &str| ssh(&anyhow::anyhow!("{}", message), "u@h").fix.unwrap();
+        assert!(fix("Authentication failed").contains("authorized_keys"));
+        assert!(fix("Connection refused (os error 111)").contains("sshd"));
+        assert!(fix("connection timed out").contains("firewalls"));
+        assert_eq!(fix("something else"), "check that ssh u@h works");
+    }
+
+    #[test]
+    fn test_display() {
+        assert_eq!(
+            Diagnosis::fail("server TUN", "/dev/net/tun is missing", "modprobe tun").to_string(),
+            "[FAIL] server TUN: /dev/net/tun is missing\n       fix: modprobe tun"
+        );
+        assert_eq!(
+            Diagnosis::pass("ip", "found").to_string(),
+            "[ok  ] ip: found"
+        );
+    }
+}
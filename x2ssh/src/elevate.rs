@@ -0,0 +1,124 @@
+//! Re-running x2ssh under sudo for modes that need root (`--auto-sudo`).
+
+use std::ffi::OsString;
+use std::process::Command;
+
+/// Set in the re-executed process, so a sudo that does not actually grant
+/// root cannot cause a loop.
+pub const REEXEC_MARKER: &str = "X2SSH_AUTO_SUDO";
+
+/// Environment variables carried across sudo, which otherwise resets the
+/// environment.
+pub const PRESERVED_ENV: &[&str] = &["RUST_LOG", "SSH_AUTH_SOCK", "NO_COLOR", "TERM"];
+
+pub fn is_root() -> bool {
+    #[cfg(target_os = "linux")]
+    {
+        unsafe { libc::geteuid() == 0 }
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    {
+        false
+    }
+}
+
+/// Whether this process is already the result of an `--auto-sudo` re-exec.
+pub fn reexecuted() -> bool {
+    std::env::var_os(REEXEC_MARKER).is_some()
+}
+
+/// Builds `sudo --preserve-env=... -- <exe> <args>`, preserving the
+/// whitelisted variables that `is_set` reports as present and the config
+/// `overrides` (`X2SSH_<SECTION>_<KEY>`) that are set.
+pub fn sudo_command(
+    exe: impl Into<OsString>,
+    args: impl IntoIterator<Item = OsString>,
+    is_set: impl Fn(&str) -> bool,
+    overrides: &[String],
+) -> Command {
+    let preserved: Vec<&str> = PRESERVED_ENV
+        .iter()
+        .copied()
+        .filter(|var| is_set(var))
+        .chain(overrides.iter().map(String::as_str))
+        .chain([REEXEC_MARKER])
+        .collect();
+
+    let mut cmd = Command::new("sudo");
+    cmd.arg(format!("--preserve-env={}", preserved.join(",")))
+        .arg("--")
+        .arg(exe.into())
+        .args(args)
+        .env(REEXEC_MARKER, "1");
+    cmd
+}
+
+/// Replaces the current process with itself under sudo, with the same
+/// arguments followed by `extra_args`. Only returns on failure.
+#[cfg(unix)]
+pub fn reexec_with_sudo(extra_args: Vec<OsString>) -> anyhow::Error {
+    use std::os::unix::process::CommandExt;
+
+    let exe = match std::env::current_exe() {
+        Ok(exe) => exe,
+        Err(e) => return anyhow::anyhow!("cannot locate the x2ssh binary to re-run: {}", e),
+    };
+    let overrides: Vec<String> = std::env::vars_os()
+        .filter_map(|(name, _)| name.into_string().ok())
+        .filter(|name| crate::config::is_env_override(name))
+        .collect();
+    let err = sudo_command(
+        exe,
+        std::env::args_os().skip(1).chain(extra_args),
+        |var| std::env::var_os(var).is_some(),
+        &overrides,
+    )
+    .exec();
+    anyhow::anyhow!("failed to run sudo: {}", err)
+}
+
+#[cfg(not(unix))]
+pub fn reexec_with_sudo(_extra_args: Vec<OsString>) -> anyhow::Error {
+    anyhow::anyhow!("--auto-sudo is only supported on Unix; run as Administrator instead")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sudo_command_preserves_args_and_env_whitelist() {
+        let cmd = sudo_command(
+            "/usr/local/bin/x2ssh",
+            ["vpn", "--config", "vpn toml", "user@host"].map(OsString::from),
+            |var| var == "SSH_AUTH_SOCK" || var == "RUST_LOG",
+            &["X2SSH_VPN_MTU".to_string()],
+        );
+
+        assert_eq!(cmd.get_program(), "sudo");
+        let args: Vec<_> = cmd.get_args().collect();
+        assert_eq!(args, [
+            "--preserve-env=RUST_LOG,SSH_AUTH_SOCK,X2SSH_VPN_MTU,X2SSH_AUTO_SUDO",
+            "--",
+            "/usr/local/bin/x2ssh",
+            "vpn",
+            "--config",
+            "vpn toml",
+            "user@host",
+        ]);
+        assert!(
+            cmd.get_envs()
+                .any(|(key, value)| key == REEXEC_MARKER && value == Some("1".as_ref()))
+        );
+    }
+
+    #[test]
+    fn test_sudo_command_without_env() {
+        let cmd = sudo_command("x2ssh", [], |_| false, &[]);
+        assert_eq!(
+            cmd.get_args().next().unwrap(),
+            "--preserve-env=X2SSH_AUTO_SUDO"
+        );
+    }
+}
@@ -0,0 +1,503 @@
+//! Port forwards over the SSH session, as OpenSSH's `-L`, `-R` and `-D`
+//! make them, which a running session adds and removes on request
+//! (`x2ssh forward add -L 8080:db:5432`), and the stdio forward of
+//! `x2ssh stdio -W`.
+
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::OnceLock;
+
+use serde::Deserialize;
+use serde::Serialize;
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpListener;
+use tokio_util::sync::CancellationToken;
+use tracing::debug;
+use tracing::error;
+use tracing::info;
+
+use crate::socks;
+use crate::socks::SocksOptions;
+use crate::transport::Transport;
+
+/// One forward, written the way it is given to ssh:
+/// `-L [BIND:]PORT:HOST:HOSTPORT`, `-R [BIND:]PORT:HOST:HOSTPORT` or
+/// `-D [BIND:]PORT`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum Forward {
+    /// Listens here and connects to `host:host_port` from the server.
+    Local {
+        bind: Option<String>,
+        port: u16,
+        host: String,
+        host_port: u16,
+    },
+    /// Has the server listen and connects to `host:host_port` from here.
+    Remote {
+        bind: Option<String>,
+        port: u16,
+        host: String,
+        host_port: u16,
+    },
+    /// A SOCKS5 proxy listening here.
+    Dynamic { bind: Option<String>, port: u16 },
+}
+
+impl Forward {
+    /// Parses the argument of `flag` (`-L`, `-R` or `-D`).
+    pub fn parse(flag: &str, spec: &str) -> Result<Self, String> {
+        let invalid =
+            |expected: &str| format!("invalid forward '{flag} {spec}': expected {expected}");
+        let fields = split_fields(spec);
+        let port = |field: &str| field.parse::<u16>().ok();
+        match flag {
+            "-L" | "-R" => {
+                let expected = "[BIND:]PORT:HOST:HOSTPORT";
+                let (bind, rest) = match fields.len() {
+                    3 => (None, &fields[..]),
+                    4 => (Some(fields[0].clone()), &fields[1..]),
+                    _ => return Err(invalid(expected)),
+                };
+                let (Some(listen), Some(host_port)) = (port(&rest[0]), port(&rest[2])) else {
+                    return Err(invalid(expected));
+                };
+                if rest[1].is_empty() || host_port == 0 {
+                    return Err(invalid(expected));
+                }
+                let host = rest[1].clone();
+                if flag == "-L" {
+                    Ok(Forward::Local {
+                        bind,
+                        port: listen,
+                        host,
+                        host_port,
+                    })
+                } else if listen == 0 {
+                    Err(format!(
+                        "invalid forward '{flag} {spec}': the server needs a fixed port"
+                    ))
+                } else {
+                    Ok(Forward::Remote {
+                        bind,
+                        port: listen,
+                        host,
+                        host_port,
+                    })
+                }
+            }
+            "-D" => {
+                let expected = "[BIND:]PORT";
+                let (bind, listen) = match &fields[..] {
+                    [listen] => (None, listen),
+                    [bind, listen] => (Some(bind.clone()), listen),
+                    _ => return Err(invalid(expected)),
+                };
+                let port = port(listen).ok_or_else(|| invalid(expected))?;
+                Ok(Forward::Dynamic { bind, port })
+            }
+            _ => Err(format!("unknown forward '{flag}': expected -L, -R or -D")),
+        }
+    }
+
+    pub fn flag(&self) -> &'static str {
+        match self {
+            Forward::Local { .. } => "-L",
+            Forward::Remote { .. } => "-R",
+            Forward::Dynamic { .. } => "-D",
+        }
+    }
+
+    fn bind(&self) -> Option<&str> {
+        match self {
+            Forward::Local { bind, .. }
+            | Forward::Remote { bind, .. }
+            | Forward::Dynamic { bind, .. } => bind.as_deref(),
+        }
+    }
+
+    fn port(&self) -> u16 {
+        match self {
+            Forward::Local { port, .. }
+            | Forward::Remote { port, .. }
+            | Forward::Dynamic { port, .. } => *port,
+        }
+    }
+
+    /// Whether both listen on the same address, so they cannot both run.
+    fn conflicts(&self, other: &Forward) -> bool {
+        let remote = |forward: &Forward| matches!(forward, Forward::Remote { .. });
+        remote(self) == remote(other)
+            && self.port() != 0
+            && self.port() == other.port()
+            && self.bind() == other.bind()
+    }
+
+    /// Where a local listener binds: loopback unless a bind address is
+    /// given, `*` meaning every address.
+    fn local_bind(&self) -> &str {
+        match self.bind() {
+            None | Some("localhost") => "127.0.0.1",
+            Some("" | "*") => "0.0.0.0",
+            Some(bind) => bind,
+        }
+    }
+
+    /// The address the server is asked to listen on, as `ssh -R` asks.
+    fn remote_bind(&self) -> &str {
+        match self.bind() {
+            None => "localhost",
+            Some("*") => "",
+            Some(bind) => bind,
+        }
+    }
+}
+
+/// Splits on the colons outside `[...]`, dropping the brackets, so IPv6
+/// addresses can be written `[::1]`.
+fn split_fields(spec: &str) -> Vec<String> {
+    let mut fields = vec![String::new()];
+    let mut bracketed = false;
+    for c in spec.chars() {
+        match c {
+            '[' if !bracketed => bracketed = true,
+            ']' if bracketed => bracketed = false,
+            ':' if !bracketed => fields.push(String::new()),
+            c => fields.last_mut().unwrap().push(c),
+        }
+    }
+    fields
+}
+
+fn bracketed(host: &str) -> String {
+    if host.contains(':') {
+        format!("[{host}]")
+    } else {
+        host.to_string()
+    }
+}
+
+impl std::fmt::Display for Forward {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} ", self.flag())?;
+        if let Some(bind) = self.bind() {
+            write!(f, "{}:", bracketed(bind))?;
+        }
+        match self {
+            Forward::Local {
+                port,
+                host,
+                host_port,
+                ..
+            }
+            | Forward::Remote {
+                port,
+                host,
+                host_port,
+                ..
+            } => write!(f, "{}:{}:{}", port, bracketed(host), host_port),
+            Forward::Dynamic { port, .. } => write!(f, "{}", port),
+        }
+    }
+}
+
+impl std::str::FromStr for Forward {
+    type Err = String;
+
+    /// Parses `-L 8080:db:5432` and the like.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let (flag, spec) = match s.get(..2) {
+            Some(flag) => (flag, s[2..].trim_start()),
+            None => (s, ""),
+        };
+        Forward::parse(flag, spec)
+    }
+}
+
+impl TryFrom<String> for Forward {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<Forward> for String {
+    fn from(forward: Forward) -> Self {
+        forward.to_string()
+    }
+}
+
+/// A running forward: what stops the task accepting its connections, or
+/// nothing for a remote forward, whose listener is the server's.
+struct Running {
+    forward: Forward,
+    stop: Option<CancellationToken>,
+}
+
+/// The forwards a session runs. Requests made before the SSH session is
+/// up fail; they need [`Forwards::attach`] first.
+pub struct Forwards {
+    transport: OnceLock<Arc<Transport>>,
+    /// Settings for the SOCKS proxies of dynamic forwards.
+    socks: Arc<SocksOptions>,
+    running: Mutex<Vec<Running>>,
+}
+
+impl Forwards {
+    pub fn new(socks: SocksOptions) -> Self {
+        Self {
+            transport: OnceLock::new(),
+            socks: Arc::new(socks),
+            running: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Sets the SSH session forwards go over.
+    pub fn attach(&self, transport: Arc<Transport>) {
+        let _ = self.transport.set(transport);
+    }
+
+    /// The running forwards, in the order they were added.
+    pub fn list(&self) -> Vec<Forward> {
+        self.running
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|running| running.forward.clone())
+            .collect()
+    }
+
+    /// Starts `forward` and returns it as it runs, with the port picked
+    /// for a local listener asked for port 0.
+    pub async fn add(&self, forward: Forward) -> anyhow::Result<Forward> {
+        let transport = self
+            .transport
+            .get()
+            .ok_or_else(|| anyhow::anyhow!("the SSH session is not up yet"))?
+            .clone();
+        if let Some(running) = self
+            .list()
+            .iter()
+            .find(|running| running.conflicts(&forward))
+        {
+            anyhow::bail!("{} is already forwarded", running);
+        }
+
+        let (forward, stop) = match forward {
+            Forward::Remote {
+                ref host,
+                host_port,
+                port,
+                ..
+            } => {
+                transport
+                    .add_remote_forward(forward.remote_bind(), port, host, host_port)
+                    .await?;
+                (forward, None)
+            }
+            mut forward => {
+                let bind = forward.local_bind().to_string();
+                let listener = TcpListener::bind((bind.as_str(), forward.port()))
+                    .await
+                    .map_err(|e| anyhow::anyhow!("cannot listen for {}: {}", forward, e))?;
+                let bound = listener.local_addr()?.port();
+                match &mut forward {
+                    Forward::Local { port, .. } | Forward::Dynamic { port, .. } => *port = bound,
+                    Forward::Remote { .. } => unreachable!(),
+                }
+                let stop = transport.shutdown().child_token();
+                tokio::spawn(accept(
+                    listener,
+                    forward.clone(),
+                    transport,
+                    self.socks.clone(),
+                    stop.clone(),
+                ));
+                (forward, Some(stop))
+            }
+        };
+        info!("Forwarding {}", forward);
+        self.running.lock().unwrap().push(Running {
+            forward: forward.clone(),
+            stop,
+        });
+        Ok(forward)
+    }
+
+    /// Stops `forward`; connections it already accepted stay open.
+    pub async fn remove(&self, forward: &Forward) -> anyhow::Result<()> {
+        let running = {
+            let mut running = self.running.lock().unwrap();
+            let index = running
+                .iter()
+                .position(|running| running.forward == *forward)
+                .ok_or_else(|| anyhow::anyhow!("{} is not forwarded", forward))?;
+            running.remove(index)
+        };
+        match running.stop {
+            Some(stop) => stop.cancel(),
+            None => {
+                if let (Forward::Remote { port, .. }, Some(transport)) =
+                    (forward, self.transport.get())
+                {
+                    transport
+                        .cancel_remote_forward(forward.remote_bind(), *port)
+                        .await?;
+                }
+            }
+        }
+        info!("Stopped forwarding {}", forward);
+        Ok(())
+    }
+}
+
+impl Drop for Forwards {
+    fn drop(&mut self) {
+        for running in self.running.get_mut().unwrap().drain(..) {
+            if let Some(stop) = running.stop {
+                stop.cancel();
+            }
+        }
+    }
+}
+
+/// Accepts the connections of a local or dynamic forward until `stop` is
+/// cancelled.
+async fn accept(
+    listener: TcpListener,
+    forward: Forward,
+    transport: Arc<Transport>,
+    socks: Arc<SocksOptions>,
+    stop: CancellationToken,
+) {
+    loop {
+        let accepted = tokio::select! {
+            accepted = listener.accept() => accepted,
+            () = stop.cancelled() => return,
+        };
+        let (socket, client_addr) = match accepted {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                error!("{}: accept error: {}", forward, e);
+                continue;
+            }
+        };
+        let transport = transport.clone();
+        let forward = forward.clone();
+        let socks = socks.clone();
+        tokio::spawn(async move {
+            let result = match &forward {
+                Forward::Local {
+                    host, host_port, ..
+                } => match socks.tcp.apply(&socket) {
+                    Ok(()) => {
+                        transport
+                            .forward(host, *host_port, socket, socks.buffer_size)
+                            .await
+                    }
+                    Err(e) => Err(e.into()),
+                },
+                _ => socks::serve(transport, socket, &socks).await,
+            };
+            if let Err(e) = result {
+                debug!("{} for {}: {:#}", forward, client_addr, e);
+            }
+        });
+    }
+}
+
+/// Bridges stdin and stdout to `host:port`, which the server resolves and
+/// connects to (`ssh -W`). Returns once the remote end closes; stdin
+/// reaching EOF only ends the upload.
+pub async fn stdio(transport: &Transport, host: &str, port: u16) -> anyhow::Result<()> {
+    let stream = transport.open_forward_stream_to_host(host, port).await?;
+    let (mut remote_read, mut remote_write) = tokio::io::split(stream);
+    let mut stdin = tokio::io::stdin();
+    let mut stdout = tokio::io::stdout();
+
+    let upload = async {
+        let sent = tokio::io::copy(&mut stdin, &mut remote_write).await?;
+        remote_write.shutdown().await?;
+        debug!("stdin closed after {} bytes", sent);
+        std::future::pending::<anyhow::Result<()>>().await
+    };
+    let download = async {
+        let received = tokio::io::copy(&mut remote_read, &mut stdout).await?;
+        stdout.flush().await?;
+        debug!("{}:{} closed after {} bytes", host, port, received);
+        anyhow::Ok(())
+    };
+    tokio::select! {
+        result = upload => result,
+        result = download => result,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_forward() {
+        assert_eq!(
+            "-L 8080:db:5432".parse::<Forward>().unwrap(),
+            Forward::Local {
+                bind: None,
+                port: 8080,
+                host: "db".to_string(),
+                host_port: 5432,
+            }
+        );
+        assert_eq!(
+            Forward::parse("-R", "[::1]:2222:localhost:22").unwrap(),
+            Forward::Remote {
+                bind: Some("::1".to_string()),
+                port: 2222,
+                host: "localhost".to_string(),
+                host_port: 22,
+            }
+        );
+        assert_eq!("-D1080".parse::<Forward>().unwrap(), Forward::Dynamic {
+            bind: None,
+            port: 1080,
+        });
+        assert_eq!(
+            "-L *:0:[fd00::1]:80"
+                .parse::<Forward>()
+                .unwrap()
+                .to_string(),
+            "-L *:0:[fd00::1]:80"
+        );
+
+        for invalid in [
+            "-L 8080:db",
+            "-L 8080::5432",
+            "-L x:db:5432",
+            "-R 0:db:22",
+            "-D a:b:1080",
+            "-X 1080",
+        ] {
+            assert!(invalid.parse::<Forward>().is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn test_forward_conflicts() {
+        let forward = |s: &str| s.parse::<Forward>().unwrap();
+        assert!(forward("-L 8080:db:5432").conflicts(&forward("-D 8080")));
+        assert!(!forward("-L 8080:db:5432").conflicts(&forward("-R 8080:db:5432")));
+        assert!(!forward("-L 8080:db:5432").conflicts(&forward("-L *:8080:db:5432")));
+        assert!(!forward("-L 0:db:5432").conflicts(&forward("-L 0:db:5432")));
+    }
+
+    #[tokio::test]
+    async fn test_forwards_need_session() {
+        let forwards = Forwards::new(SocksOptions::default());
+        let err = forwards.add("-D 0".parse().unwrap()).await.unwrap_err();
+        assert_eq!(err.to_string(), "the SSH session is not up yet");
+        assert!(forwards.list().is_empty());
+        assert!(forwards.remove(&"-D 1080".parse().unwrap()).await.is_err());
+    }
+}
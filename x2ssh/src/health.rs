@@ -0,0 +1,179 @@
+//! `/healthz` and `/readyz` over HTTP, for container health checks and load
+//! balancers probing a running session.
+//!
+//! Both are judged from the session's [`Timeline`]: the SSH session is up
+//! unless the last thing recorded was a disconnect (or, for a VPN, a
+//! reconnect its agent has not resumed after), and it is ready once the
+//! readiness check has seen traffic flow end to end as well.
+
+use std::sync::Arc;
+
+use tokio::net::TcpListener;
+use tokio::net::TcpStream;
+use tracing::debug;
+
+use crate::metrics::read_request_head;
+use crate::metrics::respond;
+use crate::status::Timeline;
+use crate::status::TimelineEntry;
+use crate::status::TimelineEvent;
+
+/// Answers health probes for one session.
+pub struct HealthEndpoint {
+    timeline: Arc<Timeline>,
+    /// A VPN only carries traffic once its agent runs on the new session.
+    vpn: bool,
+}
+
+impl HealthEndpoint {
+    pub fn new(timeline: Arc<Timeline>, vpn: bool) -> Self {
+        Self { timeline, vpn }
+    }
+
+    /// `Err` with the reason while the session cannot carry traffic.
+    pub fn healthy(&self) -> Result<(), String> {
+        match outage(&self.timeline.history(), self.vpn) {
+            Some(reason) => match self.timeline.attempt() {
+                0 => Err(reason),
+                attempt => Err(format!("{reason}; reconnect attempt {attempt}")),
+            },
+            None => Ok(()),
+        }
+    }
+
+    /// [`healthy`](Self::healthy), and verified end to end.
+    pub fn ready(&self) -> Result<(), String> {
+        self.healthy()?;
+        if self.timeline.verified() {
+            Ok(())
+        } else {
+            Err("tunnel not verified yet".to_string())
+        }
+    }
+
+    /// Answers `GET /healthz` and `GET /readyz` on `listener`, one request
+    /// per connection. Runs until dropped.
+    pub async fn serve(self: Arc<Self>, listener: TcpListener) {
+        loop {
+            let (stream, peer) = match listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    debug!("Health endpoint accept error: {}", e);
+                    continue;
+                }
+            };
+            let endpoint = Arc::clone(&self);
+            tokio::spawn(async move {
+                if let Err(e) = endpoint.answer(stream).await {
+                    debug!("Health probe from {} failed: {}", peer, e);
+                }
+            });
+        }
+    }
+
+    async fn answer(&self, mut stream: TcpStream) -> anyhow::Result<()> {
+        let head = read_request_head(&mut stream).await?;
+        let check = if head.starts_with(b"GET /healthz ") {
+            self.healthy()
+        } else if head.starts_with(b"GET /readyz ") {
+            self.ready()
+        } else {
+            return respond(
+                stream,
+                "404 Not Found",
+                "text/plain",
+                "Not found; try /healthz or /readyz\n",
+            )
+            .await;
+        };
+        let (status, body) = match check {
+            Ok(()) => ("200 OK", "ok\n".to_string()),
+            Err(reason) => ("503 Service Unavailable", format!("{reason}\n")),
+        };
+        respond(stream, status, "text/plain", &body).await
+    }
+}
+
+/// Why the session cannot carry traffic, judged from the last connection
+/// event in `history`; `None` while it can.
+fn outage(history: &[TimelineEntry], vpn: bool) -> Option<String> {
+    let last = history.iter().rev().find(|entry| {
+        !matches!(
+            entry.event,
+            TimelineEvent::Degraded { .. } | TimelineEvent::Recovered { .. }
+        )
+    })?;
+    match &last.event {
+        TimelineEvent::Disconnected { cause } => Some(format!("disconnected: {cause}")),
+        TimelineEvent::ReconnectFailed { .. } => Some("gave up reconnecting".to_string()),
+        TimelineEvent::Reconnected { .. } if vpn => Some("VPN agent restarting".to_string()),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::AsyncReadExt;
+    use tokio::io::AsyncWriteExt;
+
+    use super::*;
+    use crate::status::DisconnectCause;
+
+    #[tokio::test]
+    async fn test_health_endpoint() {
+        let timeline = Arc::new(Timeline::new());
+        let endpoint = Arc::new(HealthEndpoint::new(timeline.clone(), true));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(endpoint.serve(listener));
+
+        let get = |path: &'static str| async move {
+            let mut stream = TcpStream::connect(addr).await.unwrap();
+            stream
+                .write_all(format!("GET {path} HTTP/1.1\r\nHost: x\r\n\r\n").as_bytes())
+                .await
+                .unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).await.unwrap();
+            response
+        };
+        assert!(get("/healthz").await.starts_with("HTTP/1.1 200 OK\r\n"));
+        let response = get("/readyz").await;
+        assert!(response.starts_with("HTTP/1.1 503 "), "{response}");
+        assert!(response.ends_with("\r\n\r\ntunnel not verified yet\n"));
+        timeline.set_verified();
+        assert!(get("/readyz").await.starts_with("HTTP/1.1 200 OK\r\n"));
+
+        timeline.record(TimelineEvent::Disconnected {
+            cause: DisconnectCause::RemoteClosed,
+        });
+        timeline.set_attempt(2);
+        let response = get("/healthz").await;
+        assert!(response.starts_with("HTTP/1.1 503 "), "{response}");
+        assert!(response.ends_with("connection closed by remote; reconnect attempt 2\n"));
+        assert!(get("/readyz").await.starts_with("HTTP/1.1 503 "));
+        assert!(get("/").await.starts_with("HTTP/1.1 404 Not Found\r\n"));
+    }
+
+    #[test]
+    fn test_outage() {
+        let entry = |event| TimelineEntry { ts_ms: 0, event };
+        let reconnected = entry(TimelineEvent::Reconnected {
+            downtime_ms: 100,
+            attempts: 1,
+        });
+        let degraded = entry(TimelineEvent::Degraded {
+            rtt_ms: Some(2000),
+            failed: 0,
+            probes: 10,
+        });
+        assert_eq!(outage(&[], true), None);
+        assert_eq!(outage(std::slice::from_ref(&reconnected), false), None);
+        assert_eq!(
+            outage(&[reconnected.clone(), degraded.clone()], true).as_deref(),
+            Some("VPN agent restarting")
+        );
+        let restarted = entry(TimelineEvent::AgentRestarted);
+        assert_eq!(outage(&[reconnected, restarted, degraded], true), None);
+    }
+}
@@ -0,0 +1,308 @@
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::io::BufRead;
+use std::io::BufReader;
+use std::io::Write;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+use hmac::Hmac;
+use hmac::Mac;
+use serde::Deserialize;
+use serde::Serialize;
+use sha2::Digest;
+use sha2::Sha256;
+
+/// Append-only session journal for compliance auditing.
+///
+/// Every entry carries the MAC of the previous one, so removing, reordering
+/// or editing lines breaks the chain. With a key the MAC is HMAC-SHA256;
+/// without one it degrades to a plain SHA-256 hash chain, which only catches
+/// accidental corruption.
+pub struct Journal {
+    writer: Mutex<JournalWriter>,
+}
+
+struct JournalWriter {
+    file: File,
+    key: Option<Vec<u8>>,
+    seq: u64,
+    prev: String,
+    sent: u64,
+    received: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum JournalEvent {
+    SessionStart {
+        user: String,
+        host: String,
+        port: u16,
+        mode: String,
+    },
+    Exec {
+        command: String,
+        exit_code: Option<u32>,
+    },
+    Forward {
+        peer: Option<String>,
+        destination: String,
+        sent: u64,
+        received: u64,
+    },
+    SessionEnd {
+        sent: u64,
+        received: u64,
+    },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JournalEntry {
+    pub seq: u64,
+    pub ts_ms: u64,
+    #[serde(flatten)]
+    pub event: JournalEvent,
+    pub prev: String,
+    pub mac: String,
+}
+
+/// The signed portion of an entry; serialized field order is fixed by the
+/// struct definition, so signing and verification see identical bytes.
+#[derive(Serialize)]
+struct Unsigned<'a> {
+    seq: u64,
+    ts_ms: u64,
+    #[serde(flatten)]
+    event: &'a JournalEvent,
+    prev: &'a str,
+}
+
+impl Journal {
+    /// Opens (or creates) the journal at `path`, continuing the chain from
+    /// its last entry.
+    pub fn open(path: &Path, key: Option<Vec<u8>>) -> anyhow::Result<Self> {
+        let (seq, prev) = match File::open(path) {
+            Ok(file) => match last_entry(file)? {
+                Some(entry) => (entry.seq + 1, entry.mac),
+                None => (0, String::new()),
+            },
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => (0, String::new()),
+            Err(e) => return Err(e.into()),
+        };
+
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+
+        Ok(Self {
+            writer: Mutex::new(JournalWriter {
+                file,
+                key,
+                seq,
+                prev,
+                sent: 0,
+                received: 0,
+            }),
+        })
+    }
+
+    pub fn record(&self, event: JournalEvent) -> anyhow::Result<()> {
+        let mut writer = self.writer.lock().unwrap();
+
+        if let JournalEvent::Forward { sent, received, .. } = &event {
+            writer.sent += sent;
+            writer.received += received;
+        }
+
+        let ts_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64;
+        let unsigned = Unsigned {
+            seq: writer.seq,
+            ts_ms,
+            event: &event,
+            prev: &writer.prev,
+        };
+        let mac = sign(writer.key.as_deref(), &serde_json::to_vec(&unsigned)?);
+
+        let entry = JournalEntry {
+            seq: writer.seq,
+            ts_ms,
+            event,
+            prev: writer.prev.clone(),
+            mac: mac.clone(),
+        };
+        let mut line = serde_json::to_vec(&entry)?;
+        line.push(b'\n');
+        writer.file.write_all(&line)?;
+        writer.file.flush()?;
+
+        writer.seq += 1;
+        writer.prev = mac;
+        Ok(())
+    }
+
+    /// Records the end of the session with the byte totals of all forwards
+    /// recorded through this handle.
+    pub fn record_end(&self) -> anyhow::Result<()> {
+        let (sent, received) = {
+            let writer = self.writer.lock().unwrap();
+            (writer.sent, writer.received)
+        };
+        self.record(JournalEvent::SessionEnd { sent, received })
+    }
+}
+
+/// Verifies the chain of the journal at `path`, returning the number of
+/// entries on success.
+pub fn verify(path: &Path, key: Option<&[u8]>) -> anyhow::Result<u64> {
+    let reader = BufReader::new(File::open(path)?);
+    let mut prev = String::new();
+    let mut count = 0;
+
+    for (i, line) in reader.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let entry: JournalEntry = serde_json::from_str(&line)
+            .map_err(|e| anyhow::anyhow!("line {}: malformed entry: {}", i + 1, e))?;
+
+        if entry.seq != count || entry.prev != prev {
+            anyhow::bail!("line {}: chain broken (entry missing or reordered)", i + 1);
+        }
+
+        let unsigned = Unsigned {
+            seq: entry.seq,
+            ts_ms: entry.ts_ms,
+            event: &entry.event,
+            prev: &entry.prev,
+        };
+        if sign(key, &serde_json::to_vec(&unsigned)?) != entry.mac {
+            anyhow::bail!("line {}: MAC mismatch (entry modified or wrong key)", i + 1);
+        }
+
+        prev = entry.mac;
+        count += 1;
+    }
+
+    Ok(count)
+}
+
+fn sign(key: Option<&[u8]>, data: &[u8]) -> String {
+    match key {
+        Some(key) => {
+            let mut mac =
+                Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts keys of any length");
+            mac.update(data);
+            hex::encode(mac.finalize().into_bytes())
+        }
+        None => hex::encode(Sha256::digest(data)),
+    }
+}
+
+fn last_entry(file: File) -> anyhow::Result<Option<JournalEntry>> {
+    let mut last = None;
+    for line in BufReader::new(file).lines() {
+        let line = line?;
+        if !line.trim().is_empty() {
+            last = Some(line);
+        }
+    }
+    match last {
+        Some(line) => Ok(Some(serde_json::from_str(&line)?)),
+        None => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn start_event() -> JournalEvent {
+        JournalEvent::SessionStart {
+            user: "alice".to_string(),
+            host: "server.com".to_string(),
+            port: 22,
+            mode: "socks".to_string(),
+        }
+    }
+
+    fn forward_event(sent: u64, received: u64) -> JournalEvent {
+        JournalEvent::Forward {
+            peer: Some("127.0.0.1:50000".to_string()),
+            destination: "93.184.216.34:443".to_string(),
+            sent,
+            received,
+        }
+    }
+
+    #[test]
+    fn test_journal_chain_verifies() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("journal.jsonl");
+        let key = b"secret".to_vec();
+
+        let journal = Journal::open(&path, Some(key.clone())).unwrap();
+        journal.record(start_event()).unwrap();
+        journal.record(forward_event(100, 200)).unwrap();
+        journal.record(forward_event(1, 2)).unwrap();
+        journal.record_end().unwrap();
+
+        assert_eq!(verify(&path, Some(&key)).unwrap(), 4);
+
+        let content = std::fs::read_to_string(&path).unwrap();
+        let last: JournalEntry = serde_json::from_str(content.lines().last().unwrap()).unwrap();
+        assert_eq!(last.event, JournalEvent::SessionEnd {
+            sent: 101,
+            received: 202
+        });
+    }
+
+    #[test]
+    fn test_journal_wrong_key_fails() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("journal.jsonl");
+
+        let journal = Journal::open(&path, Some(b"secret".to_vec())).unwrap();
+        journal.record(start_event()).unwrap();
+
+        assert!(verify(&path, Some(b"other")).is_err());
+    }
+
+    #[test]
+    fn test_journal_tampering_detected() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("journal.jsonl");
+
+        let journal = Journal::open(&path, None).unwrap();
+        journal.record(start_event()).unwrap();
+        journal.record(forward_event(100, 200)).unwrap();
+        drop(journal);
+
+        let content = std::fs::read_to_string(&path).unwrap();
+        std::fs::write(&path, content.replace("\"sent\":100", "\"sent\":1")).unwrap();
+        assert!(verify(&path, None).is_err());
+
+        let dropped_first: String = content.lines().skip(1).collect();
+        std::fs::write(&path, dropped_first).unwrap();
+        assert!(verify(&path, None).is_err());
+    }
+
+    #[test]
+    fn test_journal_reopen_continues_chain() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("journal.jsonl");
+
+        let journal = Journal::open(&path, None).unwrap();
+        journal.record(start_event()).unwrap();
+        drop(journal);
+
+        let journal = Journal::open(&path, None).unwrap();
+        journal.record(start_event()).unwrap();
+
+        assert_eq!(verify(&path, None).unwrap(), 2);
+    }
+}
@@ -1,5 +1,28 @@
+pub mod auth;
+pub mod check;
 pub mod config;
+pub mod control;
+pub mod daemon;
+pub mod doctor;
+pub mod elevate;
+pub mod forward;
+pub mod health;
+pub mod journal;
+pub mod logging;
+pub mod metrics;
+pub mod otel;
+pub mod ready;
 pub mod retry;
+pub mod secret;
+pub mod service;
+pub mod shutdown;
 pub mod socks;
+pub mod stats;
+pub mod status;
+pub mod systemd;
+#[cfg(all(feature = "vpn", any(test, feature = "test-utils")))]
+pub mod test_utils;
+pub mod top;
 pub mod transport;
+#[cfg(feature = "vpn")]
 pub mod vpn;
@@ -0,0 +1,118 @@
+//! Log output: how much of it (`-v`/`-q`, or `RUST_LOG`), in which format
+//! (`--log-format`), and where it goes.
+
+use serde::Deserialize;
+use serde::Serialize;
+use tracing::level_filters::LevelFilter;
+use tracing_subscriber::EnvFilter;
+use tracing_subscriber::fmt::MakeWriter;
+
+/// How each log line is written.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// Time, level, target and message on one line.
+    #[default]
+    Full,
+    /// [`LogFormat::Full`] without the clutter, for a terminal.
+    Compact,
+    /// Several lines per event, with the source location.
+    Pretty,
+    /// One JSON object per line, for log collectors.
+    Json,
+}
+
+impl std::str::FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "full" => Ok(LogFormat::Full),
+            "compact" => Ok(LogFormat::Compact),
+            "pretty" => Ok(LogFormat::Pretty),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!(
+                "invalid log format '{s}': expected full, compact, pretty or json"
+            )),
+        }
+    }
+}
+
+/// `default` moved up one level per `-v` and down one per `-q`; below
+/// `error`, nothing is logged.
+pub fn level(default: LevelFilter, verbose: u8, quiet: u8) -> LevelFilter {
+    const LEVELS: [LevelFilter; 6] = [
+        LevelFilter::OFF,
+        LevelFilter::ERROR,
+        LevelFilter::WARN,
+        LevelFilter::INFO,
+        LevelFilter::DEBUG,
+        LevelFilter::TRACE,
+    ];
+    let index = LEVELS.iter().position(|l| *l == default).unwrap_or(3) as isize;
+    let index = (index + verbose as isize - quiet as isize).clamp(0, LEVELS.len() as isize - 1);
+    LEVELS[index as usize]
+}
+
+/// `RUST_LOG` when it is set, for per-module levels; otherwise `level` for
+/// everything.
+pub fn filter(level: LevelFilter, rust_log: Option<&str>) -> anyhow::Result<EnvFilter> {
+    match rust_log.filter(|directives| !directives.trim().is_empty()) {
+        Some(directives) => EnvFilter::builder()
+            .parse(directives)
+            .map_err(|e| anyhow::anyhow!("invalid RUST_LOG '{}': {}", directives, e)),
+        None => Ok(EnvFilter::default().add_directive(level.into())),
+    }
+}
+
+/// Installs the global subscriber. Colors are only for a terminal.
+pub fn init<W>(format: LogFormat, filter: EnvFilter, writer: W, ansi: bool)
+where
+    W: for<'w> MakeWriter<'w> + Send + Sync + 'static,
+{
+    let subscriber = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(writer)
+        .with_ansi(ansi);
+    match format {
+        LogFormat::Full => subscriber.init(),
+        LogFormat::Compact => subscriber.compact().init(),
+        LogFormat::Pretty => subscriber.pretty().init(),
+        LogFormat::Json => subscriber.json().init(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_level() {
+        assert_eq!(level(LevelFilter::INFO, 0, 0), LevelFilter::INFO);
+        assert_eq!(level(LevelFilter::INFO, 1, 0), LevelFilter::DEBUG);
+        assert_eq!(level(LevelFilter::INFO, 5, 0), LevelFilter::TRACE);
+        assert_eq!(level(LevelFilter::INFO, 0, 1), LevelFilter::WARN);
+        assert_eq!(level(LevelFilter::INFO, 0, 3), LevelFilter::OFF);
+        assert_eq!(level(LevelFilter::WARN, 1, 0), LevelFilter::INFO);
+    }
+
+    #[test]
+    fn test_filter() {
+        let hint = |rust_log| {
+            filter(LevelFilter::INFO, rust_log)
+                .unwrap()
+                .max_level_hint()
+        };
+        assert_eq!(hint(None), Some(LevelFilter::INFO));
+        assert_eq!(hint(Some(" ")), Some(LevelFilter::INFO));
+        assert_eq!(hint(Some("warn,russh=trace")), Some(LevelFilter::TRACE));
+        assert!(filter(LevelFilter::INFO, Some("x2ssh=loud")).is_err());
+    }
+
+    #[test]
+    fn test_parse_log_format() {
+        assert_eq!("json".parse(), Ok(LogFormat::Json));
+        assert_eq!("compact".parse(), Ok(LogFormat::Compact));
+        assert!("xml".parse::<LogFormat>().is_err());
+    }
+}
@@ -1,20 +1,91 @@
-use std::net::IpAddr;
 use std::net::SocketAddr;
+use std::path::Path;
 use std::path::PathBuf;
 use std::sync::Arc;
+use std::sync::Mutex;
 use std::time::Duration;
 
+use clap::Args;
+use clap::CommandFactory;
 use clap::Parser;
+use clap::Subcommand;
 use tokio::net::TcpListener;
-use tokio::sync::watch;
+use tokio::task::JoinSet;
+use tracing::debug;
 use tracing::error;
 use tracing::info;
+use tracing::level_filters::LevelFilter;
 use tracing::warn;
+use x2ssh::auth::AuthMethod;
+use x2ssh::auth::PublicKeyAuth;
+use x2ssh::check;
+use x2ssh::check::Finding;
+use x2ssh::check::Severity;
 use x2ssh::config::AppConfig;
+use x2ssh::config::ConnectionConfig;
+use x2ssh::config::Elevation;
+use x2ssh::config::ForwardConfig;
+use x2ssh::config::HealthCheck;
+use x2ssh::config::JournalConfig;
+use x2ssh::config::QueuePolicy;
+use x2ssh::config::RetryConfig;
+use x2ssh::config::RoutingMode;
+use x2ssh::config::SocksConfig;
+use x2ssh::config::StatsConfig;
+use x2ssh::config::TelemetryConfig;
+use x2ssh::config::default_path;
+use x2ssh::config::discover;
+use x2ssh::config::parse_duration;
+use x2ssh::config::parse_interval;
+use x2ssh::config::write_template;
+use x2ssh::control;
+use x2ssh::control::Control;
+use x2ssh::control::ControlSocket;
+use x2ssh::control::Request;
+use x2ssh::control::Response;
+use x2ssh::daemon;
+use x2ssh::daemon::Daemon;
+use x2ssh::daemon::PidFile;
+use x2ssh::daemon::RotatingFile;
+use x2ssh::doctor;
+use x2ssh::elevate;
+use x2ssh::forward;
+use x2ssh::forward::Forward;
+use x2ssh::forward::Forwards;
+use x2ssh::health::HealthEndpoint;
+use x2ssh::journal::Journal;
+use x2ssh::journal::JournalEvent;
+use x2ssh::logging;
+use x2ssh::logging::LogFormat;
+use x2ssh::metrics::FanoutMetrics;
+use x2ssh::metrics::LogSummaryMetrics;
+use x2ssh::metrics::MetricsSink;
+use x2ssh::metrics::NoopMetrics;
+use x2ssh::metrics::PrometheusMetrics;
+use x2ssh::otel::Tracer;
+use x2ssh::ready::Readiness;
+use x2ssh::retry::AdaptiveInterval;
+use x2ssh::retry::GaveUp;
 use x2ssh::retry::RetryPolicy;
+use x2ssh::service;
+use x2ssh::service::Service;
+use x2ssh::shutdown::Shutdown;
+use x2ssh::shutdown::Signals;
 use x2ssh::socks;
+use x2ssh::socks::ResolvePolicy;
+use x2ssh::stats;
+use x2ssh::stats::StatsRecorder;
+use x2ssh::stats::Totals;
+use x2ssh::status;
+use x2ssh::status::SessionInfo;
+use x2ssh::status::Status;
+use x2ssh::status::Timeline;
+use x2ssh::systemd;
+use x2ssh::top;
+use x2ssh::top::Top;
 use x2ssh::transport::Transport;
 use x2ssh::transport::TransportConfig;
+#[cfg(feature = "vpn")]
 use x2ssh::vpn;
 
 fn parse_user_host(s: &str) -> Result<(String, String), String> {
@@ -25,20 +96,269 @@ fn parse_user_host(s: &str) -> Result<(String, String), String> {
     Ok((parts[0].to_string(), parts[1].to_string()))
 }
 
+/// Parses `HOST:PORT`, with an IPv6 host in brackets.
+fn parse_host_port(s: &str) -> Result<(String, u16), String> {
+    let invalid = || format!("Expected format: HOST:PORT, got '{}'", s);
+    let (host, port) = s.rsplit_once(':').ok_or_else(invalid)?;
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    if host.is_empty() {
+        return Err(invalid());
+    }
+    Ok((host.to_string(), port.parse().map_err(|_| invalid())?))
+}
+
+fn parse_resolve_rule(s: &str) -> Result<(String, ResolvePolicy), String> {
+    let (suffix, policy) = s
+        .split_once('=')
+        .ok_or_else(|| "Expected format: SUFFIX=POLICY".to_string())?;
+    Ok((suffix.to_string(), policy.parse()?))
+}
+
 #[derive(Parser, Debug)]
 #[command(name = "x2ssh")]
 #[command(about = "SOCKS5 proxy and VPN tunnel over SSH")]
 struct Cli {
+    #[command(subcommand)]
+    command: Command,
+
+    /// Config file path [default: $XDG_CONFIG_HOME/x2ssh/config.toml, then
+    /// /etc/x2ssh/config.toml, if they exist]
+    #[arg(long = "config", value_name = "FILE", global = true)]
+    config: Option<PathBuf>,
+
+    /// Use the config file's `[profiles.NAME]` sections over the top-level
+    /// ones
+    #[arg(long = "profile", value_name = "NAME", global = true)]
+    profile: Option<String>,
+
+    /// Log more: -v for debug, -vv for trace (RUST_LOG, when set, takes
+    /// precedence)
+    #[arg(short = 'v', long = "verbose", action = clap::ArgAction::Count, global = true)]
+    verbose: u8,
+
+    /// Log less: -q for warnings and errors, -qq for errors, -qqq for
+    /// nothing
+    #[arg(
+        short = 'q',
+        long = "quiet",
+        action = clap::ArgAction::Count,
+        global = true,
+        conflicts_with = "verbose"
+    )]
+    quiet: u8,
+
+    /// Log line format: full, compact, pretty or json [default: full]
+    #[arg(long = "log-format", value_name = "FORMAT", global = true)]
+    log_format: Option<LogFormat>,
+}
+
+/// How to reach the SSH server, and what to run alongside the session;
+/// shared by `proxy` and `vpn`.
+#[derive(Args, Debug)]
+struct ConnectArgs {
+    /// The SSH server; USER@ may be left out when `[connection]` has a
+    /// user, and the whole destination when it has a host
     #[arg(value_name = "USER@HOST")]
-    destination: String,
+    destination: Option<String>,
 
-    /// Enable VPN mode (requires root/sudo for TUN and routing)
-    #[arg(long = "vpn")]
-    vpn: bool,
+    /// SSH port (default: `[connection]` port, or 22)
+    #[arg(short = 'p', long = "port")]
+    port: Option<u16>,
 
-    /// Config file path
-    #[arg(long = "config", value_name = "FILE")]
-    config: Option<PathBuf>,
+    #[arg(short = 'i', long = "identity", value_name = "FILE")]
+    identity: Option<PathBuf>,
+
+    /// Only accept the server host key with this fingerprint, as
+    /// `ssh-keygen -l` prints it (SHA256:...)
+    #[arg(long = "host-key", value_name = "FINGERPRINT")]
+    host_key: Option<String>,
+
+    /// Listen on PORT here and connect to HOST:HOSTPORT from the server
+    /// (can be specified multiple times; added to `forward.local`)
+    #[arg(
+        short = 'L',
+        long = "local-forward",
+        value_name = "[BIND:]PORT:HOST:HOSTPORT",
+        value_parser = |s: &str| Forward::parse("-L", s)
+    )]
+    local_forward: Vec<Forward>,
+
+    /// Reconnect attempts before giving up [default: `retry.max_attempts`,
+    /// unlimited]
+    #[arg(long = "retry-max", value_name = "N")]
+    retry_max: Option<u32>,
+
+    /// First reconnect delay, e.g. 500ms [default: `retry.initial_delay`, 1s]
+    #[arg(long = "retry-delay", value_name = "DURATION", value_parser = parse_duration)]
+    retry_delay: Option<Duration>,
+
+    /// Delay multiplier per attempt [default: `retry.backoff`, 2]
+    #[arg(long = "retry-backoff", value_name = "N")]
+    retry_backoff: Option<f64>,
+
+    /// Longest reconnect delay, e.g. 1m [default: `retry.max_delay`, 30s]
+    #[arg(long = "retry-max-delay", value_name = "DURATION", value_parser = parse_duration)]
+    retry_max_delay: Option<Duration>,
+
+    /// Give up a reconnect that has not succeeded within this long, e.g.
+    /// 10m, and exit with status 75 [default: `retry.max_total_duration`,
+    /// unlimited]
+    #[arg(long = "retry-max-total", value_name = "DURATION", value_parser = parse_duration)]
+    retry_max_total: Option<Duration>,
+
+    /// Connection health check interval [default: `retry.health_interval`, 5s]
+    #[arg(long = "health-interval", value_name = "DURATION", value_parser = parse_interval)]
+    health_interval: Option<Duration>,
+
+    /// How the connection is checked: channel, keepalive, tcp or echo
+    /// [default: `retry.health_check`, channel]
+    #[arg(long = "health-check", value_name = "STRATEGY")]
+    health_check: Option<HealthCheck>,
+
+    /// Keep the health interval fixed instead of tightening it while the
+    /// connection is unstable
+    #[arg(long = "no-adaptive-health")]
+    no_adaptive_health: bool,
+
+    /// Append-only session journal file (overrides config)
+    #[arg(long = "journal", value_name = "FILE")]
+    journal: Option<PathBuf>,
+
+    /// Append the session's traffic totals to this file when it ends, for
+    /// `x2ssh stats` (overrides stats.path)
+    #[arg(long = "stats-file", value_name = "FILE")]
+    stats_file: Option<PathBuf>,
+
+    /// Log a summary of connection and traffic counters at this interval
+    /// (only when they changed) and on exit
+    #[arg(
+        long = "metrics-interval",
+        visible_alias = "stats-interval",
+        value_name = "DURATION",
+        value_parser = parse_interval
+    )]
+    metrics_interval: Option<Duration>,
+
+    /// Serve the counters in the Prometheus text format at
+    /// http://ADDR/metrics
+    #[arg(long = "metrics-listen", value_name = "ADDR")]
+    metrics_listen: Option<SocketAddr>,
+
+    /// Answer health probes at http://ADDR/healthz (SSH session up) and
+    /// http://ADDR/readyz (also verified end to end)
+    #[arg(long = "health-listen", value_name = "ADDR")]
+    health_listen: Option<SocketAddr>,
+
+    /// Export spans of SOCKS connections and reconnects to this
+    /// OpenTelemetry collector over OTLP/HTTP, e.g. http://127.0.0.1:4318
+    /// (overrides telemetry.otlp_endpoint)
+    #[arg(long = "otlp-endpoint", value_name = "URL")]
+    otlp_endpoint: Option<String>,
+
+    /// Run this local command once the tunnel is verified end to end (and
+    /// only then tell systemd the service is ready)
+    #[arg(long = "ready-command", value_name = "CMD")]
+    ready_command: Option<String>,
+
+    /// HOST:PORT that must accept a connection through the tunnel before
+    /// it counts as ready
+    #[arg(long = "ready-target", value_name = "HOST:PORT")]
+    ready_target: Option<String>,
+
+    /// How long to wait on exit for open SOCKS connections to finish, and
+    /// for the server to answer each channel close before abandoning the
+    /// channel [default: 5s]
+    #[arg(long = "shutdown-timeout", value_name = "DURATION", value_parser = parse_duration)]
+    shutdown_timeout: Option<Duration>,
+
+    /// Allow the weak algorithms (SHA-1 key exchange, ssh-rsa signatures,
+    /// CBC ciphers) that old SSH servers need
+    #[arg(long = "legacy-server")]
+    legacy_server: bool,
+
+    /// Run in the background (Unix), logging to --log-file; stop it with
+    /// `x2ssh stop`
+    #[arg(long = "daemon")]
+    daemon: bool,
+
+    /// Pidfile of the daemon, which also keeps a second one from starting
+    /// [default: x2ssh.pid in /run/x2ssh as root, else in
+    /// $XDG_RUNTIME_DIR/x2ssh]
+    #[arg(long = "pidfile", value_name = "FILE", requires = "daemon")]
+    pidfile: Option<PathBuf>,
+
+    /// Log to this file instead of the terminal, rotated at 10 MiB keeping
+    /// 5 old ones; a daemon always logs to one [default:
+    /// /var/log/x2ssh/x2ssh.log as root, else
+    /// $XDG_STATE_HOME/x2ssh/x2ssh.log]
+    #[arg(long = "log-file", value_name = "FILE")]
+    log_file: Option<PathBuf>,
+}
+
+#[derive(Args, Debug)]
+struct ProxyArgs {
+    #[command(flatten)]
+    connect: ConnectArgs,
+
+    /// SOCKS5 listen address, HOST:PORT or a bare port on 127.0.0.1
+    /// (overrides [socks] listen)
+    #[arg(short = 'D', long = "socks", value_name = "ADDR")]
+    socks_addr: Option<String>,
+
+    /// Resolve names under SUFFIX with POLICY: local, remote (on the SSH
+    /// server) or socks5://HOST:PORT (can be specified multiple times;
+    /// e.g. onion=socks5://127.0.0.1:9050)
+    #[arg(long = "socks-resolve", value_name = "SUFFIX=POLICY", value_parser = parse_resolve_rule)]
+    socks_resolve: Vec<(String, ResolvePolicy)>,
+}
+
+#[derive(Args, Debug)]
+struct StdioArgs {
+    #[command(flatten)]
+    connect: ConnectArgs,
+
+    /// Connect stdin and stdout to HOST:PORT as the SSH server sees it,
+    /// like `ssh -W`
+    #[arg(
+        short = 'W',
+        value_name = "HOST:PORT",
+        value_parser = parse_host_port,
+        conflicts_with_all = [
+            "daemon",
+            "local_forward",
+            "metrics_interval",
+            "metrics_listen",
+            "health_listen",
+            "otlp_endpoint",
+            "stats_file",
+            "ready_command",
+            "ready_target",
+        ]
+    )]
+    target: (String, u16),
+}
+
+#[derive(Args, Debug)]
+struct VpnArgs {
+    #[command(flatten)]
+    connect: ConnectArgs,
+
+    /// Re-run under sudo (same arguments) when not root
+    #[arg(long = "auto-sudo")]
+    auto_sudo: bool,
+
+    /// Undo the routes of a VPN run that was killed before it could clean
+    /// up, then exit; the same as `x2ssh vpn cleanup`
+    #[arg(long = "vpn-cleanup")]
+    vpn_cleanup: bool,
+
+    #[command(subcommand)]
+    action: Option<VpnCommand>,
+
+    /// Print the routes, firewall rules, DNS settings and commands a VPN
+    /// session would apply, without changing anything, then exit
+    #[arg(long = "vpn-dry-run", conflicts_with = "vpn_cleanup")]
+    vpn_dry_run: bool,
 
     /// VPN client address with prefix (e.g., 10.8.0.2/24)
     #[arg(long = "vpn-client-address", value_name = "ADDR/PREFIX")]
@@ -48,18 +368,177 @@ struct Cli {
     #[arg(long = "vpn-server-address", value_name = "ADDR/PREFIX")]
     vpn_server_address: Option<String>,
 
+    /// VPN client IPv6 address with prefix (e.g., fd00:8::2/64)
+    #[arg(long = "vpn-client-address6", value_name = "ADDR/PREFIX")]
+    vpn_client_address6: Option<String>,
+
+    /// VPN server IPv6 address with prefix (e.g., fd00:8::1/64)
+    #[arg(long = "vpn-server-address6", value_name = "ADDR/PREFIX")]
+    vpn_server_address6: Option<String>,
+
     /// Client TUN interface name (e.g., tun-x2ssh)
     #[arg(long = "vpn-client-tun", value_name = "NAME")]
     vpn_client_tun: Option<String>,
 
+    /// Server TUN interface name (the kernel picks tunN by default)
+    #[arg(long = "vpn-server-tun", value_name = "NAME")]
+    vpn_server_tun: Option<String>,
+
     /// TUN MTU in bytes
     #[arg(long = "vpn-mtu", value_name = "BYTES")]
     vpn_mtu: Option<u16>,
 
+    /// Client TUN queues, each forwarded by its own tasks (Linux multiqueue)
+    #[arg(long = "vpn-tun-queues", value_name = "N")]
+    vpn_tun_queues: Option<usize>,
+
+    /// Create both TUN devices with offloads (TSO/GRO), so TCP crosses the
+    /// tunnel in segments of up to 64 KiB (Linux)
+    #[arg(long = "vpn-offload")]
+    vpn_offload: bool,
+
+    /// Attach to an existing persistent client TUN instead of creating one;
+    /// its addresses and routes are left to the system (runs without root)
+    #[arg(long = "vpn-persistent-tun")]
+    vpn_persistent_tun: bool,
+
+    /// Route only this CIDR through the VPN (can be specified multiple times;
+    /// leaves the default route untouched)
+    #[arg(long = "vpn-include", value_name = "CIDR")]
+    vpn_include: Vec<String>,
+
+    /// Route this domain through the VPN, e.g. *.corp.example (can be
+    /// specified multiple times; implies split tunnel)
+    #[arg(long = "vpn-domain", value_name = "DOMAIN")]
+    vpn_domain: Vec<String>,
+
     /// CIDR to exclude from VPN (can be specified multiple times)
     #[arg(long = "vpn-exclude", value_name = "CIDR")]
     vpn_exclude: Vec<String>,
 
+    /// Extra route installed with the VPN, as "CIDR via tun" or "CIDR via
+    /// lan" (can be specified multiple times; overrides config)
+    #[arg(long = "vpn-route", value_name = "ROUTE")]
+    vpn_route: Vec<String>,
+
+    /// Exclude the client's directly-connected subnets from the VPN
+    #[arg(long = "vpn-exclude-lan")]
+    vpn_exclude_lan: bool,
+
+    /// Once the VPN is up, ping the server's tunnel address and fetch a URL
+    /// through it, reporting where traffic stops if it does
+    #[arg(long = "vpn-self-test")]
+    vpn_self_test: bool,
+
+    /// URL fetched by the self-test (http://, or https:// to only connect)
+    #[arg(long = "vpn-self-test-url", value_name = "URL")]
+    vpn_self_test_url: Option<String>,
+
+    /// Block traffic outside the tunnel while the VPN is up (nftables)
+    #[arg(long = "vpn-kill-switch")]
+    vpn_kill_switch: bool,
+
+    /// Drop TCP connection attempts arriving through the tunnel
+    #[arg(long = "vpn-block-inbound")]
+    vpn_block_inbound: bool,
+
+    /// Drop this IP protocol in both directions: a number or icmp, tcp,
+    /// udp, gre, esp, ah, icmpv6, sctp (can be specified multiple times)
+    #[arg(long = "vpn-block-protocol", value_name = "PROTO")]
+    vpn_block_protocol: Vec<String>,
+
+    /// How the tunnel becomes the default route: replace, policy (ip rule
+    /// and a separate table; survives crashes) or metric (a second default
+    /// route with a lower metric)
+    #[arg(long = "vpn-routing-mode", value_name = "MODE")]
+    vpn_routing_mode: Option<RoutingMode>,
+
+    /// Metric of the tunnel's default route with --vpn-routing-mode metric
+    #[arg(long = "vpn-route-metric", value_name = "METRIC")]
+    vpn_route_metric: Option<u32>,
+
+    /// Route only the processes of this cgroup v2 (below /sys/fs/cgroup)
+    /// through the VPN; start programs in it with `x2ssh exec -- CMD` (Linux)
+    #[arg(long = "vpn-cgroup", value_name = "PATH")]
+    vpn_cgroup: Option<String>,
+
+    /// What a full forwarding queue does: drop (and count) the packet, or
+    /// wait for room
+    #[arg(long = "vpn-queue-policy", value_name = "POLICY")]
+    vpn_queue_policy: Option<QueuePolicy>,
+
+    /// How often to ping the VPN agent through the tunnel channel
+    #[arg(long = "vpn-keepalive", value_name = "DURATION", value_parser = parse_interval)]
+    vpn_keepalive: Option<Duration>,
+
+    /// Reconnect when the VPN agent has not answered for this long
+    #[arg(long = "vpn-keepalive-timeout", value_name = "DURATION", value_parser = parse_interval)]
+    vpn_keepalive_timeout: Option<Duration>,
+
+    /// How long a packet waits for others to share its frame to or from
+    /// the agent (0s still batches packets already queued)
+    #[arg(long = "vpn-batch-delay", value_name = "DURATION", value_parser = parse_duration)]
+    vpn_batch_delay: Option<Duration>,
+
+    /// Compress tunnel frames with LZ4
+    #[arg(long = "vpn-compress")]
+    vpn_compress: bool,
+
+    /// Only compress frames of at least this many bytes
+    #[arg(long = "vpn-compress-threshold", value_name = "BYTES")]
+    vpn_compress_threshold: Option<usize>,
+
+    /// Run a local DNS64 resolver and translate the NAT64 prefix, so an
+    /// IPv6-only client reaches IPv4-only hosts
+    #[arg(long = "vpn-dns64")]
+    vpn_dns64: bool,
+
+    /// Absolute path for the agent binary on the server, kept between
+    /// sessions (default: a per-session file in the server's runtime dir).
+    /// A binary already there that x2ssh did not upload is run as it is,
+    /// never overwritten
+    #[arg(long = "vpn-agent-path", value_name = "PATH")]
+    vpn_agent_path: Option<String>,
+
+    /// Run the agent already installed at --vpn-agent-path instead of
+    /// uploading the one built into x2ssh
+    #[arg(long = "vpn-no-deploy-agent")]
+    vpn_no_deploy_agent: bool,
+
+    /// Leave the agent running and its binary on the server on exit
+    #[arg(long = "vpn-keep-agent")]
+    vpn_keep_agent: bool,
+
+    /// Share one agent and server TUN device with other clients through
+    /// this Unix socket on the server (needs --vpn-agent-path)
+    #[arg(long = "vpn-shared-agent", value_name = "SOCKET")]
+    vpn_shared_agent: Option<String>,
+
+    /// Have the agent enable forwarding and masquerade the VPN subnet on
+    /// the server (needs nftables there)
+    #[arg(long = "vpn-nat")]
+    vpn_nat: bool,
+
+    /// Have the agent forward DNS queries sent to the server's tunnel
+    /// address to the server's resolver
+    #[arg(long = "vpn-agent-dns")]
+    vpn_agent_dns: bool,
+
+    /// How the agent gets root on the server when not logging in as root:
+    /// auto, sudo or doas
+    #[arg(long = "vpn-elevation", value_name = "TOOL")]
+    vpn_elevation: Option<Elevation>,
+
+    /// File with the server user's sudo password, for servers without
+    /// passwordless sudo
+    #[arg(long = "vpn-sudo-password-file", value_name = "FILE")]
+    vpn_sudo_password_file: Option<PathBuf>,
+
+    /// Write every packet crossing the client TUN device, with its
+    /// direction, to this pcapng file (opens in Wireshark)
+    #[arg(long = "vpn-pcap", value_name = "FILE")]
+    vpn_pcap: Option<PathBuf>,
+
     /// PostUp command (can be specified multiple times; overrides config)
     #[arg(long = "vpn-post-up", value_name = "CMD")]
     vpn_post_up: Vec<String>,
@@ -68,83 +547,470 @@ struct Cli {
     #[arg(long = "vpn-pre-down", value_name = "CMD")]
     vpn_pre_down: Vec<String>,
 
-    #[arg(short = 'D', long = "socks", value_name = "ADDR")]
-    socks_addr: Option<String>,
+    /// Command run on the client once the tunnel is up (can be specified
+    /// multiple times; overrides config)
+    #[arg(long = "vpn-local-post-up", value_name = "CMD")]
+    vpn_local_post_up: Vec<String>,
 
-    #[arg(short = 'p', long = "port", default_value = "22")]
-    port: u16,
+    /// Command run on the client before the tunnel goes down (can be
+    /// specified multiple times; overrides config)
+    #[arg(long = "vpn-local-pre-down", value_name = "CMD")]
+    vpn_local_pre_down: Vec<String>,
+}
 
-    #[arg(short = 'i', long = "identity", value_name = "FILE")]
-    identity: Option<PathBuf>,
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Run a SOCKS5 proxy whose connections leave from the SSH server
+    Proxy(Box<ProxyArgs>),
+    /// Route traffic through a tunnel to the SSH server (needs root for
+    /// TUN and routing)
+    Vpn(Box<VpnArgs>),
+    /// Connect stdin and stdout to a host through the SSH server, for use
+    /// as an OpenSSH ProxyCommand (`x2ssh stdio -W %h:%p user@bastion`)
+    Stdio(Box<StdioArgs>),
+    /// Check, without starting it, whether a VPN session with these
+    /// arguments could run: SSH access, root on the server, TUN devices,
+    /// local tools and the MTU
+    Doctor(Box<VpnArgs>),
+    /// Show the x2ssh sessions running on this machine
+    Status {
+        /// Also list each session's recent disconnects and reconnects
+        #[arg(long = "history")]
+        history: bool,
+        /// Print the sessions as JSON, with their full history
+        #[arg(long = "json")]
+        json: bool,
+    },
+    /// Show the traffic of past sessions, by day and with --destinations
+    /// by SOCKS destination, from the stats file
+    Stats {
+        /// Only sessions that ended in the last N days
+        #[arg(long = "days", value_name = "N")]
+        days: Option<u64>,
+        /// Also list the traffic by SOCKS destination, busiest first
+        #[arg(long = "destinations")]
+        destinations: bool,
+        /// Print the totals as JSON
+        #[arg(long = "json")]
+        json: bool,
+        /// The stats file, when not taken from the config
+        #[arg(long = "file", value_name = "FILE")]
+        file: Option<PathBuf>,
+    },
+    /// Watch the running sessions live: connection state, reconnect
+    /// attempts, open SOCKS connections and VPN throughput
+    Top {
+        /// How often to refresh
+        #[arg(long = "interval", value_name = "DURATION", default_value = "1s", value_parser = parse_interval)]
+        interval: Duration,
+    },
+    /// Stop a running session the way Ctrl+C would; with several
+    /// running, name one by pid or pass --all
+    Stop {
+        #[arg(value_name = "PID", conflicts_with_all = ["all", "pidfile"])]
+        pid: Option<u32>,
+        /// Stop every running session
+        #[arg(long = "all", conflicts_with = "pidfile")]
+        all: bool,
+        /// Stop the daemon whose pid this file holds
+        #[arg(long = "pidfile", value_name = "FILE")]
+        pidfile: Option<PathBuf>,
+    },
+    /// Add, remove or list forwards in a running session without
+    /// restarting it
+    Forward {
+        #[command(subcommand)]
+        action: ForwardCommand,
+    },
+    /// Run a command in the VPN's cgroup (`vpn.cgroup`), so only its
+    /// traffic goes through the tunnel
+    Exec {
+        /// The cgroup, when not taken from the config
+        #[arg(long = "vpn-cgroup", value_name = "PATH")]
+        cgroup: Option<String>,
+        #[arg(required = true, trailing_var_arg = true, value_name = "CMD")]
+        command: Vec<String>,
+    },
+    /// Install a systemd unit (launchd job on macOS) that runs a proxy or
+    /// VPN at boot: x2ssh install-service vpn --vpn-nat user@host
+    InstallService {
+        /// Name of the unit (label of the launchd job)
+        #[arg(long = "name", default_value = "x2ssh")]
+        name: String,
+        /// Install a user service, run while the user is logged in (not
+        /// for VPN mode)
+        #[arg(long = "user")]
+        user: bool,
+        /// Print the service definition instead of installing it
+        #[arg(long = "print")]
+        print: bool,
+        /// Replace an existing service definition
+        #[arg(long = "force")]
+        force: bool,
+        /// The session to run, as it would be run by hand
+        #[arg(
+            required = true,
+            trailing_var_arg = true,
+            allow_hyphen_values = true,
+            value_name = "proxy|vpn ARGS"
+        )]
+        command: Vec<String>,
+    },
+    /// Print the completion script for a shell (bash, zsh, fish, elvish,
+    /// powershell)
+    Completions {
+        #[arg(value_name = "SHELL")]
+        shell: clap_complete::Shell,
+    },
+    /// Print the man page, or write one per subcommand into a directory
+    Man {
+        /// Write x2ssh.1, x2ssh-proxy.1, … here instead
+        #[arg(long = "out-dir", value_name = "DIR")]
+        out_dir: Option<PathBuf>,
+    },
+    /// Check or create a config file
+    Config {
+        #[command(subcommand)]
+        action: ConfigCommand,
+    },
+}
 
-    #[arg(long = "retry-max", value_name = "N")]
-    retry_max: Option<u32>,
+#[derive(Subcommand, Debug)]
+enum VpnCommand {
+    /// Undo the routes of a VPN run that was killed before it could clean
+    /// up, then exit
+    Cleanup,
+}
 
-    #[arg(long = "retry-delay", value_name = "MS", default_value = "1000")]
-    retry_delay: u64,
+#[derive(Subcommand, Debug)]
+enum ForwardCommand {
+    /// Start a forward
+    Add {
+        #[command(flatten)]
+        forward: ForwardArg,
+        /// The session, when several are running
+        #[arg(long = "pid", value_name = "PID")]
+        pid: Option<u32>,
+    },
+    /// Stop a forward, as `x2ssh forward list` shows it
+    Remove {
+        #[command(flatten)]
+        forward: ForwardArg,
+        /// The session, when several are running
+        #[arg(long = "pid", value_name = "PID")]
+        pid: Option<u32>,
+    },
+    /// List the forwards added to a session
+    List {
+        /// The session, when several are running
+        #[arg(long = "pid", value_name = "PID")]
+        pid: Option<u32>,
+    },
+}
 
-    #[arg(long = "retry-backoff", value_name = "N", default_value = "2")]
-    retry_backoff: f64,
+/// One forward, written as for ssh.
+#[derive(Args, Debug)]
+#[group(required = true, multiple = false)]
+struct ForwardArg {
+    /// Listen on PORT here and connect to HOST:HOSTPORT from the server
+    #[arg(
+        short = 'L',
+        value_name = "[BIND:]PORT:HOST:HOSTPORT",
+        value_parser = |s: &str| Forward::parse("-L", s)
+    )]
+    local: Option<Forward>,
+    /// Have the server listen on PORT and connect to HOST:HOSTPORT from
+    /// here
+    #[arg(
+        short = 'R',
+        value_name = "[BIND:]PORT:HOST:HOSTPORT",
+        value_parser = |s: &str| Forward::parse("-R", s)
+    )]
+    remote: Option<Forward>,
+    /// Run a SOCKS5 proxy on PORT here
+    #[arg(
+        short = 'D',
+        value_name = "[BIND:]PORT",
+        value_parser = |s: &str| Forward::parse("-D", s)
+    )]
+    dynamic: Option<Forward>,
+}
 
-    #[arg(long = "retry-max-delay", value_name = "MS", default_value = "30000")]
-    retry_max_delay: u64,
+impl ForwardArg {
+    fn forward(&self) -> Forward {
+        self.local
+            .clone()
+            .or_else(|| self.remote.clone())
+            .or_else(|| self.dynamic.clone())
+            .expect("clap requires one forward")
+    }
+}
 
-    #[arg(long = "health-interval", value_name = "MS", default_value = "5000")]
-    health_interval: u64,
+#[derive(Subcommand, Debug)]
+enum ConfigCommand {
+    /// Parse a config file (default: --config, or the XDG config file) and
+    /// check its settings and every profile; fails on errors
+    Validate {
+        #[arg(value_name = "FILE")]
+        file: Option<PathBuf>,
+    },
+    /// Write a config with every setting commented out at its default
+    Init {
+        /// Where to write it [default: $XDG_CONFIG_HOME/x2ssh/config.toml]
+        #[arg(value_name = "FILE")]
+        file: Option<PathBuf>,
+        /// Replace an existing file
+        #[arg(long = "force")]
+        force: bool,
+    },
 }
 
 impl Cli {
-    fn user_host(&self) -> Result<(String, String), String> {
-        parse_user_host(&self.destination)
+    /// `--config`, or the config file found in the default locations.
+    fn config_path(&self) -> Option<PathBuf> {
+        self.config.clone().or_else(discover)
     }
 
-    fn socks_socket_addr(&self) -> Result<SocketAddr, String> {
-        let addr = match &self.socks_addr {
-            Some(a) => a.clone(),
-            None => return Err("SOCKS address is required (-D, --socks)".to_string()),
+    /// Load the config file if specified or found, falling back to
+    /// defaults, with the selected profile and then `X2SSH_*` environment
+    /// overrides applied.
+    fn app_config(&self) -> anyhow::Result<AppConfig> {
+        let config = match &self.config_path() {
+            Some(config_path) if config_path.exists() => match &self.profile {
+                Some(profile) => AppConfig::load_profile(config_path, profile)?,
+                None => AppConfig::load(config_path)?,
+            },
+            _ => {
+                if let Some(profile) = &self.profile {
+                    anyhow::bail!("--profile {}: no config file to take it from", profile);
+                }
+                AppConfig::default()
+            }
         };
+        config.with_env(std::env::vars_os().filter_map(|(name, value)| {
+            Some((name.into_string().ok()?, value.into_string().ok()?))
+        }))
+    }
 
-        if let Ok(port) = addr.parse::<u16>() {
-            return Ok(SocketAddr::from(([127, 0, 0, 1], port)));
+    /// The `--log-file` of a session in the foreground.
+    fn log_file(&self) -> Option<&Path> {
+        match &self.command {
+            Command::Proxy(args) => args.connect.log_file.as_deref(),
+            Command::Vpn(args) => args.connect.log_file.as_deref(),
+            Command::Stdio(args) => args.connect.log_file.as_deref(),
+            _ => None,
         }
+    }
+
+    /// Where a `--daemon` session keeps its pidfile and log; `None` for
+    /// one in the foreground.
+    fn daemon(&self) -> Option<Daemon> {
+        let connect = match &self.command {
+            Command::Proxy(args) => &args.connect,
+            Command::Vpn(args) => &args.connect,
+            _ => return None,
+        };
+        connect.daemon.then(|| Daemon {
+            pidfile: connect
+                .pidfile
+                .clone()
+                .unwrap_or_else(daemon::default_pidfile),
+            log_file: connect
+                .log_file
+                .clone()
+                .unwrap_or_else(daemon::default_log_file),
+        })
+    }
+}
 
-        addr.parse::<SocketAddr>()
-            .map_err(|e| format!("Invalid SOCKS address '{}': {}", addr, e))
+impl ConnectArgs {
+    /// The user and host from the destination, falling back to
+    /// `[connection]` for whichever it leaves out.
+    fn user_host(&self, connection: &ConnectionConfig) -> Result<(String, String), String> {
+        let (user, host) = match &self.destination {
+            Some(destination) if destination.contains('@') => {
+                let (user, host) = parse_user_host(destination)?;
+                (Some(user), host)
+            }
+            Some(host) => (None, host.clone()),
+            None => match &connection.host {
+                Some(host) => (None, host.clone()),
+                None => {
+                    return Err(
+                        "USER@HOST is required (or host in the config's [connection])".to_string(),
+                    );
+                }
+            },
+        };
+        let user = user
+            .or_else(|| connection.user.clone())
+            .ok_or_else(|| "Expected format: USER@HOST (or user in [connection])".to_string())?;
+        Ok((user, host))
     }
 
-    fn transport_config(&self) -> Result<TransportConfig, String> {
-        let (user, host) = self.user_host()?;
+    fn transport_config(
+        &self,
+        connection: &ConnectionConfig,
+        retry: &RetryConfig,
+    ) -> Result<TransportConfig, String> {
+        let (user, host) = self.user_host(connection)?;
+        let mut health = retry.health_options().map_err(|e| e.to_string())?;
+        if let Some(check) = self.health_check {
+            health.check = check;
+        }
 
         let retry_policy = RetryPolicy {
-            max_attempts: self.retry_max,
-            initial_delay: Duration::from_millis(self.retry_delay),
-            backoff: self.retry_backoff,
-            max_delay: Duration::from_millis(self.retry_max_delay),
+            max_attempts: self.retry_max.or(retry.max_attempts.limit()),
+            initial_delay: self.retry_delay.unwrap_or(retry.initial_delay),
+            backoff: self.retry_backoff.unwrap_or(retry.backoff),
+            max_delay: self.retry_max_delay.unwrap_or(retry.max_delay),
+            max_total_duration: self.retry_max_total.or(retry.max_total_duration),
+        };
+
+        let passphrase = connection.passphrase().map_err(|e| e.to_string())?;
+        let auth: Vec<Arc<dyn AuthMethod>> = match self
+            .identity
+            .clone()
+            .or_else(|| connection.identity.clone())
+        {
+            Some(path) => vec![Arc::new(PublicKeyAuth::new(path, passphrase))],
+            None => Vec::new(),
         };
 
         Ok(TransportConfig {
             retry_policy,
-            health_interval: Duration::from_millis(self.health_interval),
-            key_path: self.identity.clone(),
+            health_interval: self.health_interval.unwrap_or(retry.health_interval),
+            health,
+            shutdown_timeout: self.shutdown_timeout.unwrap_or(connection.shutdown_timeout),
+            tcp: connection.tcp_options(),
+            legacy_server: self.legacy_server || connection.legacy_server,
+            journal: None,
+            stats: None,
+            metrics: Arc::new(NoopMetrics),
+            tracer: Tracer::default(),
+            timeline: Arc::new(Timeline::new()),
+            auth,
+            host_key: self
+                .host_key
+                .clone()
+                .or_else(|| connection.host_key.clone()),
             user,
             host,
-            port: self.port,
+            port: self.port.unwrap_or(connection.port),
         })
     }
 
-    /// Build VPN config by merging config file with CLI overrides.
-    /// CLI overrides take precedence over config file values.
-    fn vpn_config(&self) -> anyhow::Result<x2ssh::config::VpnConfig> {
-        // Start with defaults
-        let mut config = x2ssh::config::VpnConfig::default();
+    /// The forwards to start with: `forward.local`, then `-L`.
+    fn forwards(&self, config: &ForwardConfig) -> anyhow::Result<Vec<Forward>> {
+        let mut forwards = config.forwards()?;
+        forwards.extend(self.local_forward.iter().cloned());
+        Ok(forwards)
+    }
 
-        // Load config file if specified
-        if let Some(config_path) = &self.config
-            && config_path.exists()
-        {
-            let app_config = AppConfig::load(config_path)?;
-            config = app_config.vpn;
+    fn readiness(&self) -> Readiness {
+        Readiness {
+            command: self.ready_command.clone(),
+            target: self.ready_target.clone(),
+            probed: self.health_listen.is_some(),
         }
+    }
+
+    /// Serves `/healthz` and `/readyz` for the session behind `timeline`
+    /// with `--health-listen`.
+    async fn serve_health(&self, timeline: &Arc<Timeline>, vpn: bool) -> anyhow::Result<()> {
+        let Some(addr) = self.health_listen else {
+            return Ok(());
+        };
+        let listener = TcpListener::bind(addr)
+            .await
+            .map_err(|e| anyhow::anyhow!("Cannot serve health checks on {}: {}", addr, e))?;
+        info!(
+            "Serving health checks on http://{}/healthz",
+            listener.local_addr()?
+        );
+        let endpoint = Arc::new(HealthEndpoint::new(timeline.clone(), vpn));
+        tokio::spawn(endpoint.serve(listener));
+        Ok(())
+    }
+
+    /// Open the session journal if enabled via CLI or config file.
+    fn journal(&self, config: &JournalConfig) -> anyhow::Result<Option<Arc<Journal>>> {
+        let Some(path) = self.journal.as_ref().or(config.path.as_ref()) else {
+            return Ok(None);
+        };
+
+        let key = match &config.key_file {
+            Some(key_file) => Some(std::fs::read(key_file)?.trim_ascii().to_vec()),
+            None => {
+                warn!("Session journal has no key_file; entries are hash-chained but unsigned");
+                None
+            }
+        };
+
+        info!("Recording session journal to {}", path.display());
+        Ok(Some(Arc::new(Journal::open(path, key)?)))
+    }
+
+    /// Start keeping traffic totals if enabled via CLI or config file.
+    fn stats(
+        &self,
+        config: &StatsConfig,
+        mode: &str,
+        transport: &TransportConfig,
+    ) -> anyhow::Result<Option<Arc<StatsRecorder>>> {
+        let Some(path) = self.stats_file.as_ref().or(config.path.as_ref()) else {
+            return Ok(None);
+        };
+        let server = format!("{}@{}:{}", transport.user, transport.host, transport.port);
+        let recorder = StatsRecorder::open(path, mode, &server)
+            .map_err(|e| anyhow::anyhow!("Cannot open stats file {}: {}", path.display(), e))?;
+        info!("Recording traffic totals to {}", path.display());
+        Ok(Some(Arc::new(recorder)))
+    }
+
+    /// Export traces if enabled via CLI or config file.
+    fn tracer(&self, config: &TelemetryConfig) -> anyhow::Result<Tracer> {
+        Ok(match config.trace_options(self.otlp_endpoint.as_deref())? {
+            Some(options) => Tracer::start(options),
+            None => Tracer::default(),
+        })
+    }
+}
+
+impl ProxyArgs {
+    /// `-D`, or `[socks] listen`.
+    fn socks_listen<'a>(&'a self, config: &'a SocksConfig) -> Option<&'a str> {
+        self.socks_addr.as_deref().or(config.listen.as_deref())
+    }
+
+    fn socks_socket_addr(&self, config: &SocksConfig) -> Result<SocketAddr, String> {
+        let addr = self
+            .socks_listen(config)
+            .ok_or("SOCKS address is required (-D, --socks or [socks] listen)")?;
+
+        socks::parse_listen(addr)
+    }
+
+    fn socks_config(&self, config: &SocksConfig) -> SocksConfig {
+        let mut config = config.clone();
+        // CLI rules are added to (and override) the config file's.
+        config.resolve.extend(self.socks_resolve.iter().cloned());
+        config
+    }
+}
+
+impl VpnArgs {
+    /// Whether to only undo the routes of a killed run: `x2ssh vpn
+    /// cleanup` or `--vpn-cleanup`.
+    #[cfg(feature = "vpn")]
+    fn cleanup(&self) -> bool {
+        self.vpn_cleanup || matches!(self.action, Some(VpnCommand::Cleanup))
+    }
+
+    /// Build VPN config by merging config file with CLI overrides.
+    /// CLI overrides take precedence over config file values.
+    fn vpn_config(&self, app_config: &AppConfig) -> anyhow::Result<x2ssh::config::VpnConfig> {
+        let mut config = app_config.vpn.clone();
 
         // Apply CLI overrides
         if let Some(client_address) = &self.vpn_client_address {
@@ -153,15 +1019,119 @@ impl Cli {
         if let Some(server_address) = &self.vpn_server_address {
             config.server_address = server_address.clone();
         }
+        if let Some(client_address6) = &self.vpn_client_address6 {
+            config.client_address6 = Some(client_address6.clone());
+        }
+        if let Some(server_address6) = &self.vpn_server_address6 {
+            config.server_address6 = Some(server_address6.clone());
+        }
         if let Some(client_tun) = &self.vpn_client_tun {
             config.client_tun = client_tun.clone();
         }
+        if let Some(server_tun) = &self.vpn_server_tun {
+            config.server_tun = Some(server_tun.clone());
+        }
         if let Some(mtu) = self.vpn_mtu {
             config.mtu = mtu;
         }
+        if let Some(queues) = self.vpn_tun_queues {
+            config.tun_queues = queues;
+        }
+        if self.vpn_offload {
+            config.offload = true;
+        }
+        if self.vpn_persistent_tun {
+            config.persistent_tun = true;
+        }
+        if !self.vpn_include.is_empty() {
+            config.include = self.vpn_include.clone();
+        }
+        if !self.vpn_domain.is_empty() {
+            config.domains = self.vpn_domain.clone();
+        }
         if !self.vpn_exclude.is_empty() {
             config.exclude = self.vpn_exclude.clone();
         }
+        if !self.vpn_route.is_empty() {
+            config.routes = self.vpn_route.clone();
+        }
+        if self.vpn_exclude_lan {
+            config.exclude_lan = true;
+        }
+        if self.vpn_self_test {
+            config.self_test = true;
+        }
+        if let Some(url) = &self.vpn_self_test_url {
+            config.self_test_url = url.clone();
+        }
+        if self.vpn_kill_switch {
+            config.kill_switch = true;
+        }
+        if self.vpn_block_inbound {
+            config.block_inbound = true;
+        }
+        if !self.vpn_block_protocol.is_empty() {
+            config.blocked_protocols = self.vpn_block_protocol.clone();
+        }
+        if let Some(routing_mode) = self.vpn_routing_mode {
+            config.routing_mode = routing_mode;
+        }
+        if let Some(metric) = self.vpn_route_metric {
+            config.route_metric = metric;
+        }
+        if let Some(cgroup) = &self.vpn_cgroup {
+            config.cgroup = Some(cgroup.clone());
+        }
+        if let Some(queue_policy) = self.vpn_queue_policy {
+            config.queue_policy = queue_policy;
+        }
+        if let Some(interval) = self.vpn_keepalive {
+            config.keepalive_interval = interval;
+        }
+        if let Some(timeout) = self.vpn_keepalive_timeout {
+            config.keepalive_timeout = timeout;
+        }
+        if let Some(delay) = self.vpn_batch_delay {
+            config.batch_delay = delay;
+        }
+        if self.vpn_compress {
+            config.compress = true;
+        }
+        if let Some(threshold) = self.vpn_compress_threshold {
+            config.compress_threshold = threshold;
+        }
+        if self.vpn_dns64 {
+            config.dns64 = true;
+        }
+        if let Some(path) = &self.vpn_agent_path {
+            config.agent_path = Some(path.clone());
+        }
+        if self.vpn_no_deploy_agent {
+            config.deploy_agent = false;
+        }
+        if self.vpn_keep_agent {
+            config.keep_agent = true;
+        }
+        if let Some(socket) = &self.vpn_shared_agent {
+            config.shared_agent = Some(socket.clone());
+        }
+        if self.vpn_nat {
+            config.nat = true;
+        }
+        if self.vpn_agent_dns {
+            config.agent_dns = true;
+        }
+        if let Some(elevation) = self.vpn_elevation {
+            config.elevation = elevation;
+        }
+        if let Some(file) = &self.vpn_sudo_password_file {
+            config.sudo_password_file = Some(file.clone());
+            config.sudo_password_cmd = None;
+            config.sudo_password_keyring = None;
+        }
+        if let Some(file) = &self.vpn_pcap {
+            config.pcap = Some(file.clone());
+        }
         // CLI PostUp/PreDown completely override config file if specified
         if !self.vpn_post_up.is_empty() {
             config.post_up = self.vpn_post_up.clone();
@@ -169,163 +1139,1303 @@ impl Cli {
         if !self.vpn_pre_down.is_empty() {
             config.pre_down = self.vpn_pre_down.clone();
         }
+        if !self.vpn_local_post_up.is_empty() {
+            config.local_post_up = self.vpn_local_post_up.clone();
+        }
+        if !self.vpn_local_pre_down.is_empty() {
+            config.local_pre_down = self.vpn_local_pre_down.clone();
+        }
 
         Ok(config)
     }
 }
 
-#[tokio::main]
-async fn main() -> anyhow::Result<()> {
-    tracing_subscriber::fmt()
-        .with_max_level(tracing::Level::INFO)
-        .init();
-
+fn main() -> anyhow::Result<()> {
     let cli = Cli::parse();
+    let daemon = cli.daemon();
+    let level = match &cli.command {
+        // stdout is the connection; only problems go to stderr.
+        Command::Stdio(_) => LevelFilter::WARN,
+        _ => LevelFilter::INFO,
+    };
+    let rust_log = std::env::var("RUST_LOG").ok();
+    let filter = || {
+        logging::filter(
+            logging::level(level, cli.verbose, cli.quiet),
+            rust_log.as_deref(),
+        )
+    };
+    let format = cli.log_format.unwrap_or_default();
+    if daemon.is_none() {
+        match (cli.log_file(), &cli.command) {
+            (Some(path), _) => {
+                let log = RotatingFile::open(path, daemon::LOG_MAX_SIZE, daemon::LOG_KEEP)?;
+                logging::init(format, filter()?, Mutex::new(log), false);
+            }
+            (None, Command::Stdio(_)) => logging::init(format, filter()?, std::io::stderr, true),
+            (None, _) => logging::init(format, filter()?, std::io::stdout, true),
+        }
+    }
+    // Before detaching, while there is a terminal to ask for the password.
+    if let Command::Vpn(args) = &cli.command {
+        auto_sudo(&cli, args)?;
+    }
 
-    // SOCKS5 mode requires -D flag (for now, until VPN is fully implemented)
-    if cli.socks_addr.is_none() && !cli.vpn {
-        return Err(anyhow::anyhow!(
-            "Either --socks (-D) or --vpn must be specified"
-        ));
+    let pidfile = match &daemon {
+        Some(daemon) => {
+            check_config(&cli)?;
+            let mut pidfile = PidFile::lock(&daemon.pidfile)?;
+            let log = RotatingFile::open(&daemon.log_file, daemon::LOG_MAX_SIZE, daemon::LOG_KEEP)?;
+            let filter = filter()?;
+            daemon::detach(&daemon.log_file)?;
+            logging::init(format, filter, Mutex::new(log), false);
+            pidfile.write_pid()?;
+            Some(pidfile)
+        }
+        None => None,
+    };
+
+    let runtime = tokio::runtime::Runtime::new()?;
+    let result = runtime.block_on(run(&cli));
+    // Not waiting for a read of stdin (x2ssh stdio) that may never return.
+    runtime.shutdown_background();
+    if let (Some(_), Err(e)) = (&daemon, &result) {
+        // Nobody sees the daemon's stderr.
+        error!("{:#}", e);
     }
+    if let Err(e) = &result
+        && e.is::<GaveUp>()
+    {
+        eprintln!("Error: {:?}", e);
+        // Exiting skips destructors.
+        drop(pidfile);
+        std::process::exit(GaveUp::EXIT_CODE);
+    }
+    result
+}
 
-    if cli.socks_addr.is_some() {
-        let socks_addr = cli
-            .socks_socket_addr()
-            .map_err(|e| anyhow::anyhow!("{}", e))?;
+async fn run(cli: &Cli) -> anyhow::Result<()> {
+    match &cli.command {
+        Command::Proxy(args) => run_proxy(cli, args).await,
+        Command::Vpn(args) => run_vpn(cli, args).await,
+        Command::Stdio(args) => run_stdio(cli, args).await,
+        Command::Doctor(args) => run_doctor(cli, args).await,
+        Command::Status { history, json } => print_status(*history, *json).await,
+        Command::Stats {
+            days,
+            destinations,
+            json,
+            file,
+        } => print_stats(cli, *days, *destinations, *json, file.as_deref()),
+        Command::Top { interval } => run_top(*interval).await,
+        Command::Stop { pid, all, pidfile } => stop(*pid, *all, pidfile.as_deref()).await,
+        Command::Forward { action } => run_forward(action).await,
+        Command::Exec { cgroup, command } => run_exec(cli, cgroup.as_deref(), command),
+        Command::InstallService {
+            name,
+            user,
+            print,
+            force,
+            command,
+        } => install_service(cli, name, *user, *print, *force, command),
+        Command::Completions { shell } => {
+            print_completions(*shell);
+            Ok(())
+        }
+        Command::Man { out_dir } => print_man(out_dir.as_deref()),
+        Command::Config { action } => run_config(cli, action),
+    }
+}
 
-        let config = cli
-            .transport_config()
-            .map_err(|e| anyhow::anyhow!("{}", e))?;
-        let health_interval = config.health_interval;
+/// Builds the session's config and checks it the way `x2ssh config
+/// validate` does, so a daemon reports a mistake on the terminal rather
+/// than in its log after detaching.
+fn check_config(cli: &Cli) -> anyhow::Result<()> {
+    let mut config = cli.app_config()?;
+    let connect = match &cli.command {
+        Command::Proxy(args) => {
+            config.socks = args.socks_config(&config.socks);
+            &args.connect
+        }
+        Command::Vpn(args) => {
+            config.vpn = args.vpn_config(&config)?;
+            &args.connect
+        }
+        _ => return Ok(()),
+    };
+    connect
+        .transport_config(&config.connection, &config.retry)
+        .map_err(|e| anyhow::anyhow!("{}", e))?;
+    connect.forwards(&config.forward)?;
+    let errors: Vec<String> = check::check(&config)
+        .into_iter()
+        .filter(|finding| finding.severity == Severity::Error)
+        .map(|finding| finding.message)
+        .collect();
+    if !errors.is_empty() {
+        anyhow::bail!("{}", errors.join("; "));
+    }
+    Ok(())
+}
 
-        info!(
-            "Connecting to {}@{}:{}",
-            config.user, config.host, config.port
-        );
-        info!("SOCKS5 proxy listening on {}", socks_addr);
+/// With `--auto-sudo` and not root, re-runs x2ssh under sudo; returns only
+/// when that is not needed, or with the error that kept it from happening.
+fn auto_sudo(cli: &Cli, args: &VpnArgs) -> anyhow::Result<()> {
+    if args.vpn_dry_run || !args.auto_sudo || elevate::is_root() {
+        return Ok(());
+    }
+    if elevate::reexecuted() {
+        anyhow::bail!("still not root after re-running under sudo");
+    }
+    info!("VPN mode needs root; re-running under sudo");
+    // sudo may reset HOME, so hand a found config on explicitly.
+    let config = match (&cli.config, discover()) {
+        (None, Some(found)) => vec!["--config".into(), found.into_os_string()],
+        _ => Vec::new(),
+    };
+    Err(elevate::reexec_with_sudo(config))
+}
 
-        let transport = Arc::new(Transport::connect(config).await?);
-        info!("SSH session established");
+/// The metrics every session keeps, and where they go: the summary always
+/// (`x2ssh status` shows the tunnel traffic from it), Prometheus with
+/// `--metrics-listen`.
+async fn metrics(
+    args: &ConnectArgs,
+) -> anyhow::Result<(Arc<LogSummaryMetrics>, Arc<dyn MetricsSink>)> {
+    let summary = Arc::new(LogSummaryMetrics::new());
+    if let Some(interval) = args.metrics_interval {
+        let periodic = summary.clone();
+        tokio::spawn(async move { periodic.run(interval).await });
+    }
+    let metrics: Arc<dyn MetricsSink> = match args.metrics_listen {
+        Some(addr) => {
+            let listener = TcpListener::bind(addr)
+                .await
+                .map_err(|e| anyhow::anyhow!("Cannot serve metrics on {}: {}", addr, e))?;
+            info!(
+                "Serving metrics on http://{}/metrics",
+                listener.local_addr()?
+            );
+            let prometheus = Arc::new(PrometheusMetrics::new());
+            tokio::spawn(prometheus.clone().serve(listener));
+            Arc::new(FanoutMetrics(vec![summary.clone(), prometheus]))
+        }
+        None => summary.clone(),
+    };
+    Ok((summary, metrics))
+}
 
-        let (_shutdown_tx, shutdown_rx) = watch::channel(false);
+async fn run_proxy(cli: &Cli, args: &ProxyArgs) -> anyhow::Result<()> {
+    let app_config = cli.app_config()?;
+    let connect = &args.connect;
+    // With socket activation, systemd has bound the proxy's address.
+    let activated = systemd::listener()?;
+    let socks_addr = match activated {
+        Some(_) => None,
+        None => Some(
+            args.socks_socket_addr(&app_config.socks)
+                .map_err(|e| anyhow::anyhow!("{}", e))?,
+        ),
+    };
+    let socks_options = Arc::new(args.socks_config(&app_config.socks).options()?);
+    let forwards = Arc::new(Forwards::new((*socks_options).clone()));
+    let initial_forwards = connect.forwards(&app_config.forward)?;
+    let journal = connect.journal(&app_config.journal)?;
+    let readiness = connect.readiness();
+    let (summary, metrics) = metrics(connect).await?;
+
+    let mut config = connect
+        .transport_config(&app_config.connection, &app_config.retry)
+        .map_err(|e| anyhow::anyhow!("{}", e))?;
+    config.journal = journal.clone();
+    config.metrics = metrics;
+    config.tracer = connect.tracer(&app_config.telemetry)?;
+    config.stats = connect.stats(&app_config.stats, "socks", &config)?;
+    let stats = config.stats.clone();
+    config.timeline = publish_timeline("socks", &config);
+    connect.serve_health(&config.timeline, false).await?;
+    let _control = start_control(Control {
+        timeline: config.timeline.clone(),
+        metrics: summary.clone(),
+        socks: Some(socks_options.open.clone()),
+        client_tun: None,
+        forwards: forwards.clone(),
+    });
+    let health_interval = config.health_interval;
+    let adaptive_health = !connect.no_adaptive_health;
+
+    info!(
+        "Connecting to {}@{}:{}",
+        config.user, config.host, config.port
+    );
+    let session_start = JournalEvent::SessionStart {
+        user: config.user.clone(),
+        host: config.host.clone(),
+        port: config.port,
+        mode: "socks".to_string(),
+    };
+
+    let transport = Arc::new(Transport::connect(config).await?);
+    info!("SSH session established");
+    transport.record(session_start);
+    forwards.attach(transport.clone());
+    start_forwards(&forwards, initial_forwards).await?;
+
+    let mut health = tokio::spawn(health_monitor(
+        transport.clone(),
+        health_interval,
+        adaptive_health,
+    ));
+
+    let listener = match (activated, socks_addr) {
+        (Some(listener), _) => listener,
+        (None, Some(addr)) => TcpListener::bind(addr).await?,
+        (None, None) => unreachable!("no SOCKS address without socket activation"),
+    };
+    let mut connections = JoinSet::new();
+
+    let proxy = listener.local_addr()?;
+    info!("SOCKS5 proxy listening on {}", proxy);
+    let ready = readiness.signal_when(|| readiness.check_socks(proxy));
+    tokio::pin!(ready);
+    let mut signaled = false;
+    let mut signals = Signals::new()?;
+    let mut gave_up = None;
 
-        let health_transport = transport.clone();
-        tokio::spawn(async move {
-            health_monitor(health_transport, health_interval, shutdown_rx).await;
-        });
+    loop {
+        let accepted = tokio::select! {
+            accepted = listener.accept() => accepted,
+            () = &mut ready, if !signaled => {
+                signaled = true;
+                transport.timeline().set_verified();
+                continue;
+            }
+            signal = signals.recv() => {
+                info!("Received {}, shutting down", signal);
+                break;
+            }
+            health = &mut health => {
+                gave_up = health.ok().and_then(Result::err);
+                break;
+            }
+        };
 
-        let listener = TcpListener::bind(socks_addr).await?;
-
-        loop {
-            match listener.accept().await {
-                Ok((socket, client_addr)) => {
-                    let transport = transport.clone();
-                    tokio::spawn(async move {
-                        if let Err(e) = socks::serve(transport, socket).await {
-                            error!("SOCKS5 error for {}: {:#}", client_addr, e);
-                        }
-                    });
-                }
-                Err(err) => {
-                    error!("accept error: {:?}", err);
-                }
+        match accepted {
+            Ok((socket, client_addr)) => {
+                let transport = transport.clone();
+                let socks_options = socks_options.clone();
+                connections.spawn(async move {
+                    if let Err(e) = socks::serve(transport, socket, &socks_options).await {
+                        error!("SOCKS5 error for {}: {:#}", client_addr, e);
+                    }
+                });
+            }
+            Err(err) => {
+                error!("accept error: {:?}", err);
             }
         }
-    } else {
-        let vpn_config = cli.vpn_config()?;
-        info!("VPN mode enabled");
-        info!("VPN client address: {}", vpn_config.client_address);
-        info!("Client TUN: {}", vpn_config.client_tun);
+        // Reap finished connections so the set only holds open ones.
+        while connections.try_join_next().is_some() {}
+    }
+
+    // No new clients while the open connections finish.
+    drop(listener);
+    let mut shutdown = Shutdown::new(transport.shutdown_timeout());
+    signals
+        .unless_repeated(shutdown.drain("SOCKS connection(s)", &mut connections))
+        .await;
+    shutdown
+        .cancel_all(
+            "SOCKS connection(s)",
+            transport.shutdown(),
+            &mut connections,
+        )
+        .await;
+    shutdown.log();
+
+    if connect.metrics_interval.is_some() {
+        summary.log();
+    }
+    if let Some(stats) = &stats
+        && let Err(e) = stats.finish(0, 0)
+    {
+        warn!("Failed to write stats file: {}", e);
+    }
+    if let Some(journal) = &journal {
+        journal.record_end()?;
+    }
+    match gave_up {
+        Some(e) => Err(e),
+        None => Ok(()),
+    }
+}
+
+#[cfg(feature = "vpn")]
+async fn run_vpn(cli: &Cli, args: &VpnArgs) -> anyhow::Result<()> {
+    let app_config = cli.app_config()?;
+    let connect = &args.connect;
 
-        let transport_config = cli
-            .transport_config()
+    if args.cleanup() {
+        if args.vpn_dry_run {
+            anyhow::bail!("--vpn-dry-run cannot be combined with cleanup");
+        }
+        vpn::check_root()?;
+        let vpn_config = args.vpn_config(&app_config)?;
+        if vpn::routing::RoutingManager::recover(&vpn_config.client_tun).await? {
+            info!("Restored routes left behind on {}", vpn_config.client_tun);
+        } else {
+            info!("No leftover routes for {}", vpn_config.client_tun);
+        }
+        return Ok(());
+    }
+
+    if args.vpn_dry_run {
+        let vpn_config = args.vpn_config(&app_config)?;
+        let transport_config = connect
+            .transport_config(&app_config.connection, &app_config.retry)
             .map_err(|e| anyhow::anyhow!("{}", e))?;
+        let ssh_server = resolve_host(&transport_config.host, transport_config.port).await?;
+        println!("{}", vpn::dry_run::plan(&vpn_config, ssh_server).await?);
+        return Ok(());
+    }
 
-        info!(
-            "Connecting to {}@{}:{}",
-            transport_config.user, transport_config.host, transport_config.port
-        );
+    let vpn_config = args.vpn_config(&app_config)?;
+    let journal = connect.journal(&app_config.journal)?;
+    let readiness = connect.readiness();
+    let (summary, metrics) = metrics(connect).await?;
+    let forwards = Arc::new(Forwards::new(app_config.socks.options()?));
+    let initial_forwards = connect.forwards(&app_config.forward)?;
+
+    info!("VPN mode enabled");
+    info!("VPN client address: {}", vpn_config.client_address);
+    info!("Client TUN: {}", vpn_config.client_tun);
+
+    let mut transport_config = connect
+        .transport_config(&app_config.connection, &app_config.retry)
+        .map_err(|e| anyhow::anyhow!("{}", e))?;
+    transport_config.journal = journal.clone();
+    transport_config.metrics = metrics;
+    transport_config.tracer = connect.tracer(&app_config.telemetry)?;
+    transport_config.stats = connect.stats(&app_config.stats, "vpn", &transport_config)?;
+    let stats = transport_config.stats.clone();
+    transport_config.timeline = publish_timeline("vpn", &transport_config);
+    connect
+        .serve_health(&transport_config.timeline, true)
+        .await?;
+    let _control = start_control(Control {
+        timeline: transport_config.timeline.clone(),
+        metrics: summary.clone(),
+        socks: None,
+        client_tun: Some(vpn_config.client_tun.clone()),
+        forwards: forwards.clone(),
+    });
+    let traffic = tokio::spawn(publish_traffic(
+        transport_config.timeline.clone(),
+        summary.clone(),
+    ));
+
+    info!(
+        "Connecting to {}@{}:{}",
+        transport_config.user, transport_config.host, transport_config.port
+    );
+
+    let transport = Arc::new(Transport::connect(transport_config.clone()).await?);
+    info!("SSH session established");
+    forwards.attach(transport.clone());
+    start_forwards(&forwards, initial_forwards).await?;
+    transport.record(JournalEvent::SessionStart {
+        user: transport_config.user.clone(),
+        host: transport_config.host.clone(),
+        port: transport_config.port,
+        mode: "vpn".to_string(),
+    });
+
+    // Where the connection went, not a fresh lookup that may disagree.
+    let ssh_server_ip = transport.endpoints().peer.ip();
+
+    let result = vpn::run_vpn(&transport, &vpn_config, ssh_server_ip, &readiness).await;
+    traffic.abort();
+    if connect.metrics_interval.is_some() {
+        summary.log();
+    }
+    let snapshot = summary.snapshot();
+    if let Some(stats) = &stats
+        && let Err(e) = stats.finish(snapshot.tunnel_bytes_sent, snapshot.tunnel_bytes_received)
+    {
+        warn!("Failed to write stats file: {}", e);
+    }
+    if let Some(journal) = &journal {
+        journal.record_end()?;
+    }
+    result
+}
 
-        let transport = Transport::connect(transport_config.clone()).await?;
-        info!("SSH session established");
+#[cfg(not(feature = "vpn"))]
+async fn run_vpn(_cli: &Cli, _args: &VpnArgs) -> anyhow::Result<()> {
+    Err(without_vpn())
+}
 
-        let ssh_server_ip = resolve_host(&transport_config.host).await?;
+/// The error of VPN commands in a build without the `vpn` feature.
+#[cfg(not(feature = "vpn"))]
+fn without_vpn() -> anyhow::Error {
+    anyhow::anyhow!("this x2ssh was built without VPN support; rebuild it with the `vpn` feature")
+}
 
-        vpn::run_vpn(&transport, &vpn_config, ssh_server_ip).await?;
-        Ok(())
+/// Connects, retrying per the retry policy, and bridges stdin and stdout to
+/// the target until it closes. Such a session is short-lived and one of
+/// many, so it publishes no status.
+async fn run_stdio(cli: &Cli, args: &StdioArgs) -> anyhow::Result<()> {
+    let app_config = cli.app_config()?;
+    let connect = &args.connect;
+    let (host, port) = &args.target;
+
+    let mut config = connect
+        .transport_config(&app_config.connection, &app_config.retry)
+        .map_err(|e| anyhow::anyhow!("{}", e))?;
+    let journal = connect.journal(&app_config.journal)?;
+    config.journal = journal.clone();
+    let session_start = JournalEvent::SessionStart {
+        user: config.user.clone(),
+        host: config.host.clone(),
+        port: config.port,
+        mode: "stdio".to_string(),
+    };
+
+    let transport = Transport::connect_with_retry(config).await?;
+    transport.record(session_start);
+    let result = forward::stdio(&transport, host, *port).await;
+    if let Some(journal) = &journal {
+        journal.record_end()?;
     }
+    result
 }
 
-async fn health_monitor(
-    transport: Arc<Transport>,
-    interval: Duration,
-    mut shutdown: watch::Receiver<bool>,
-) {
-    let mut ticker = tokio::time::interval(interval);
+/// Publishes the session's reconnect timeline for `x2ssh status`. A session
+/// that cannot write its status file still runs, just without it.
+fn publish_timeline(mode: &str, config: &TransportConfig) -> Arc<Timeline> {
+    let destination = format!("{}@{}:{}", config.user, config.host, config.port);
+    let session = SessionInfo::new(mode, &destination);
+    match Timeline::published(&status::status_dir(), session) {
+        Ok(timeline) => Arc::new(timeline),
+        Err(e) => {
+            warn!("Failed to write status file: {}", e);
+            Arc::new(Timeline::new())
+        }
+    }
+}
 
-    loop {
-        tokio::select! {
-            _ = ticker.tick() => {
-                if transport.check_alive().await.is_err() {
-                    warn!("SSH connection lost, attempting reconnect...");
-                    if let Err(e) = transport.reconnect().await {
-                        error!("Reconnect failed: {}", e);
-                    }
-                }
-            }
-            _ = shutdown.changed() => {
-                break;
-            }
+/// Starts the forwards given on the command line and in the config; one
+/// that cannot listen fails the session.
+async fn start_forwards(forwards: &Forwards, initial: Vec<Forward>) -> anyhow::Result<()> {
+    for forward in initial {
+        forwards.add(forward).await?;
+    }
+    Ok(())
+}
+
+/// Answers `x2ssh status` and monitoring scripts over the control socket,
+/// and logs the same on SIGUSR1. A session that cannot open the socket
+/// still runs, just without it.
+fn start_control(control: Control) -> Option<ControlSocket> {
+    if let Err(e) = control::dump_on_sigusr1(control.clone()) {
+        warn!("Cannot handle SIGUSR1: {}", e);
+    }
+    match ControlSocket::start(&status::status_dir(), control) {
+        Ok(socket) => Some(socket),
+        Err(e) => {
+            warn!("Failed to open control socket: {}", e);
+            None
         }
     }
 }
 
-async fn resolve_host(host: &str) -> anyhow::Result<IpAddr> {
-    use tokio::net::lookup_host;
+/// How often a VPN session refreshes the traffic in its status file.
+#[cfg(feature = "vpn")]
+const TRAFFIC_INTERVAL: Duration = Duration::from_secs(5);
 
-    let addr = format!("{}:22", host);
-    let addrs: Vec<_> = lookup_host(&addr).await?.collect();
+/// Keeps the tunnel traffic in the status file current, so `x2ssh status`
+/// shows whether packets flow.
+#[cfg(feature = "vpn")]
+async fn publish_traffic(timeline: Arc<Timeline>, summary: Arc<LogSummaryMetrics>) {
+    let mut ticker = tokio::time::interval(TRAFFIC_INTERVAL);
+    loop {
+        ticker.tick().await;
+        timeline.set_traffic(summary.snapshot().into());
+    }
+}
 
-    addrs
-        .into_iter()
-        .next()
-        .map(|a| a.ip())
-        .ok_or_else(|| anyhow::anyhow!("Failed to resolve host: {}", host))
+/// Adds up the sessions in the stats file.
+fn print_stats(
+    cli: &Cli,
+    days: Option<u64>,
+    destinations: bool,
+    json: bool,
+    file: Option<&Path>,
+) -> anyhow::Result<()> {
+    let app_config = cli.app_config()?;
+    let path = file.or(app_config.stats.path.as_deref()).ok_or_else(|| {
+        anyhow::anyhow!("No stats file; set path under [stats] in the config or pass --file")
+    })?;
+    let since_ms = days.map_or(0, |days| {
+        status::now_ms().saturating_sub(days.saturating_mul(86_400_000))
+    });
+    let totals = Totals::of(&stats::read(path)?, since_ms);
+    if json {
+        println!("{}", serde_json::to_string_pretty(&totals)?);
+    } else {
+        print!("{}", totals.render(destinations));
+    }
+    Ok(())
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+async fn print_status(history: bool, json: bool) -> anyhow::Result<()> {
+    let now = status::now_ms();
+    let mut reports = Vec::new();
+    for session in status::running_sessions()? {
+        reports.push(control::report(session, now).await);
+    }
+    if json {
+        println!("{}", serde_json::to_string_pretty(&reports)?);
+        return Ok(());
+    }
+    if reports.is_empty() {
+        println!("No running x2ssh sessions");
+    }
+    for report in reports {
+        print!("{}", report.render(history, now));
+    }
+    Ok(())
+}
 
-    #[test]
-    fn test_argument_parsing() {
-        let cli = Cli::try_parse_from(["x2ssh", "-D", "1080", "user@host.com"]);
-        assert!(cli.is_ok());
-        let cli = cli.unwrap();
-        assert_eq!(cli.destination, "user@host.com");
-        assert_eq!(cli.port, 22);
-        assert!(!cli.vpn);
+/// Runs the VPN preflight checks and prints one line per check, failing
+/// if any check failed.
+async fn run_doctor(cli: &Cli, args: &VpnArgs) -> anyhow::Result<()> {
+    let app_config = cli.app_config()?;
+    let vpn_config = args.vpn_config(&app_config)?;
+    let transport_config = args
+        .connect
+        .transport_config(&app_config.connection, &app_config.retry)
+        .map_err(|e| anyhow::anyhow!("{}", e))?;
+    let destination = format!("{}@{}", transport_config.user, transport_config.host);
+
+    let mut diagnoses = doctor::local(&vpn_config);
+    match Transport::connect(transport_config).await {
+        Ok(transport) => {
+            diagnoses.push(doctor::Diagnosis::pass(
+                "SSH",
+                format!("logged in to {}", destination),
+            ));
+            diagnoses.extend(doctor::remote(&transport, &vpn_config).await);
+        }
+        Err(e) => {
+            diagnoses.push(doctor::ssh(&e, &destination));
+            diagnoses.push(doctor::mtu(vpn_config.mtu, None));
+        }
     }
 
-    #[test]
-    fn test_vpn_flag_parsing() {
-        let cli = Cli::try_parse_from(["x2ssh", "--vpn", "user@host.com"]).unwrap();
-        assert!(cli.vpn);
-        assert!(cli.socks_addr.is_none());
+    for diagnosis in &diagnoses {
+        println!("{}", diagnosis);
+    }
+    let failed = diagnoses
+        .iter()
+        .filter(|diagnosis| diagnosis.outcome == doctor::Outcome::Fail)
+        .count();
+    if failed > 0 {
+        anyhow::bail!("{} check(s) failed", failed);
     }
+    Ok(())
+}
 
-    #[test]
-    fn test_vpn_with_overrides() {
-        let cli = Cli::try_parse_from([
-            "x2ssh",
-            "--vpn",
-            "--vpn-client-address",
-            "10.9.0.2/24",
-            "--vpn-server-address",
-            "10.9.0.1/24",
-            "--vpn-mtu",
+/// Redraws the sessions every `interval` until a key asks to quit.
+async fn run_top(interval: Duration) -> anyhow::Result<()> {
+    use tokio::io::AsyncReadExt;
+
+    let terminal = top::Terminal::enter()?;
+    let mut view = Top::default();
+    let mut stdin = tokio::io::stdin();
+    let mut input = [0u8; 64];
+    let mut refresh = tokio::time::interval(interval);
+    loop {
+        tokio::select! {
+            _ = refresh.tick() => {
+                let now = status::now_ms();
+                let mut reports = Vec::new();
+                for session in status::running_sessions()? {
+                    reports.push(control::report(session, now).await);
+                }
+                view.update(reports, now);
+            }
+            read = stdin.read(&mut input) => {
+                let n = read?;
+                if n == 0 {
+                    return Ok(());
+                }
+                for key in top::keys(&input[..n]) {
+                    match key {
+                        top::Key::Quit => return Ok(()),
+                        top::Key::Next => view.select(true),
+                        top::Key::Previous => view.select(false),
+                    }
+                }
+            }
+        }
+        let (width, height) = terminal.size();
+        terminal.draw(&view.render(width, height))?;
+    }
+}
+
+/// The session `pid` names, or the only one running.
+fn pick_session(pid: Option<u32>) -> anyhow::Result<u32> {
+    let sessions = status::running_sessions()?;
+    match (pid, sessions.as_slice()) {
+        (Some(pid), _) if sessions.iter().any(|status| status.session.pid == pid) => Ok(pid),
+        (Some(pid), _) => anyhow::bail!("no running x2ssh session with pid {}", pid),
+        (None, [status]) => Ok(status.session.pid),
+        (None, []) => anyhow::bail!("no running x2ssh sessions"),
+        (None, _) => anyhow::bail!(
+            "{} sessions are running; name one with --pid (see x2ssh status)",
+            sessions.len()
+        ),
+    }
+}
+
+async fn run_forward(action: &ForwardCommand) -> anyhow::Result<()> {
+    let (pid, request) = match action {
+        ForwardCommand::Add { forward, pid } => (*pid, Request::ForwardAdd {
+            forward: forward.forward(),
+        }),
+        ForwardCommand::Remove { forward, pid } => (*pid, Request::ForwardRemove {
+            forward: forward.forward(),
+        }),
+        ForwardCommand::List { pid } => (*pid, Request::ForwardList),
+    };
+    let pid = pick_session(pid)?;
+    match control::request(pid, &request).await? {
+        Response::ForwardAdded(forward) => println!("Forwarding {} in session {}", forward, pid),
+        Response::ForwardRemoved(forward) => {
+            println!("Stopped forwarding {} in session {}", forward, pid)
+        }
+        Response::Forwards(forwards) if forwards.is_empty() => {
+            println!("No forwards in session {}", pid)
+        }
+        Response::Forwards(forwards) => {
+            for forward in forwards {
+                println!("{}", forward);
+            }
+        }
+        response => anyhow::bail!("session {}: unexpected answer {:?}", pid, response),
+    }
+    Ok(())
+}
+
+/// How long `x2ssh stop` waits for a session to finish shutting down.
+const STOP_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// Sends the session `pid` or `pidfile` names, or the only one running,
+/// SIGTERM and waits for it to exit. Only sessions whose status file
+/// checks out are signalled, never a process that took over a pid.
+async fn stop(pid: Option<u32>, all: bool, pidfile: Option<&Path>) -> anyhow::Result<()> {
+    let sessions = status::running_sessions()?;
+    let named = |pid: u32| sessions.iter().find(|status| status.session.pid == pid);
+    let stopping: Vec<&Status> = match (pid, pidfile) {
+        (_, Some(path)) => {
+            let pid = daemon::read_pidfile(path)?;
+            let Some(status) = named(pid) else {
+                anyhow::bail!("x2ssh is not running (pid {} in {})", pid, path.display());
+            };
+            vec![status]
+        }
+        (Some(pid), None) => {
+            let Some(status) = named(pid) else {
+                anyhow::bail!("no running x2ssh session with pid {}", pid);
+            };
+            vec![status]
+        }
+        (None, None) if all || sessions.len() == 1 => sessions.iter().collect(),
+        (None, None) if sessions.is_empty() => anyhow::bail!("no running x2ssh sessions"),
+        (None, None) => anyhow::bail!(
+            "{} sessions are running; name one by pid (see x2ssh status) or pass --all",
+            sessions.len()
+        ),
+    };
+
+    for status in &stopping {
+        status::terminate(status.session.pid)?;
+        info!("Stopping session {}", status.session.pid);
+    }
+    let deadline = tokio::time::Instant::now() + STOP_TIMEOUT;
+    for status in stopping {
+        let pid = status.session.pid;
+        while status.session.is_running() {
+            if tokio::time::Instant::now() >= deadline {
+                anyhow::bail!("session {} is still shutting down", pid);
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        println!("Stopped session {}", pid);
+    }
+    Ok(())
+}
+
+fn run_config(cli: &Cli, action: &ConfigCommand) -> anyhow::Result<()> {
+    match action {
+        ConfigCommand::Validate { file } => {
+            let path = file
+                .clone()
+                .or_else(|| cli.config_path())
+                .ok_or_else(|| anyhow::anyhow!("no config file given or found"))?;
+            let config = AppConfig::load(&path)?;
+            let mut findings: Vec<(Option<&str>, Finding)> = check::check(&config)
+                .into_iter()
+                .map(|finding| (None, finding))
+                .collect();
+            for name in config.profiles.keys() {
+                let profile = AppConfig::load_profile(&path, name)?;
+                findings.extend(
+                    check::check(&profile)
+                        .into_iter()
+                        .map(|finding| (Some(name.as_str()), finding)),
+                );
+            }
+
+            let errors = findings
+                .iter()
+                .filter(|(_, finding)| finding.severity == Severity::Error)
+                .count();
+            for (profile, finding) in &findings {
+                match profile {
+                    Some(profile) => println!("profile '{}': {}", profile, finding),
+                    None => println!("{}", finding),
+                }
+            }
+            if errors > 0 {
+                anyhow::bail!("{}: {} error(s)", path.display(), errors);
+            }
+            println!(
+                "{}: OK ({} warning(s))",
+                path.display(),
+                findings.len() - errors
+            );
+            Ok(())
+        }
+        ConfigCommand::Init { file, force } => {
+            let path = file
+                .clone()
+                .or_else(default_path)
+                .ok_or_else(|| anyhow::anyhow!("no config file given and no XDG config dir"))?;
+            write_template(&path, *force)?;
+            println!("Wrote {}", path.display());
+            Ok(())
+        }
+    }
+}
+
+/// Builds the service that runs `command` (`proxy ...` or `vpn ...`) with
+/// this x2ssh and config, and installs or prints it.
+fn install_service(
+    cli: &Cli,
+    name: &str,
+    user: bool,
+    print: bool,
+    force: bool,
+    command: &[String],
+) -> anyhow::Result<()> {
+    let session =
+        Cli::try_parse_from(std::iter::once("x2ssh").chain(command.iter().map(String::as_str)))
+            .map_err(|e| anyhow::anyhow!("invalid service command: {}", e))?;
+    let connect = match &session.command {
+        Command::Proxy(args) => &args.connect,
+        Command::Vpn(args) => &args.connect,
+        _ => anyhow::bail!("install-service runs `x2ssh proxy` or `x2ssh vpn`"),
+    };
+    let vpn = matches!(session.command, Command::Vpn(_));
+    if connect.daemon {
+        anyhow::bail!("the service manager keeps the session in the background; drop --daemon");
+    }
+    if vpn && user {
+        anyhow::bail!("VPN mode needs root, so it cannot be a --user service");
+    }
+
+    let exe = std::env::current_exe()?;
+    let mut args = vec![exe.to_string_lossy().into_owned()];
+    // The service starts with another HOME and working directory, so the
+    // config this run would use is passed explicitly.
+    if session.config.is_none()
+        && let Some(config) = cli.config_path().filter(|path| path.exists())
+    {
+        args.push("--config".to_string());
+        args.push(service::absolute(&config).to_string_lossy().into_owned());
+    }
+    if session.profile.is_none()
+        && let Some(profile) = &cli.profile
+    {
+        args.push("--profile".to_string());
+        args.push(profile.clone());
+    }
+    args.extend(command.iter().cloned());
+
+    let destination = match &connect.destination {
+        Some(destination) => format!(" to {}", destination),
+        None => String::new(),
+    };
+    let service = Service {
+        name: name.to_string(),
+        description: format!(
+            "x2ssh {}{}",
+            if vpn { "VPN" } else { "SOCKS5 proxy" },
+            destination
+        ),
+        command: args,
+        vpn,
+        user_service: user,
+        // A system-wide proxy runs as whoever installs it, not as root.
+        run_as: (!vpn && !user)
+            .then(|| {
+                std::env::var("SUDO_USER")
+                    .or_else(|_| std::env::var("USER"))
+                    .ok()
+            })
+            .flatten()
+            .filter(|account| account != "root"),
+    };
+
+    if print {
+        print!("{}", service.render()?);
+        return Ok(());
+    }
+    if !user && !elevate::is_root() {
+        anyhow::bail!("installing a system service needs root; run it with sudo, or pass --user");
+    }
+    let path = service.install(force)?;
+    println!("Installed {} and started {}", path.display(), name);
+    Ok(())
+}
+
+fn print_completions(shell: clap_complete::Shell) {
+    let mut command = Cli::command();
+    let name = command.get_name().to_string();
+    clap_complete::generate(shell, &mut command, name, &mut std::io::stdout());
+}
+
+fn print_man(out_dir: Option<&Path>) -> anyhow::Result<()> {
+    let command = Cli::command();
+    match out_dir {
+        Some(dir) => {
+            std::fs::create_dir_all(dir)?;
+            clap_mangen::generate_to(command, dir)
+                .map_err(|e| anyhow::anyhow!("cannot write man pages to {}: {}", dir.display(), e))
+        }
+        None => Ok(clap_mangen::Man::new(command).render(&mut std::io::stdout())?),
+    }
+}
+
+/// Joins the VPN's cgroup and replaces this process with `command`, which
+/// then only returns on failure. Under sudo, `command` runs as the user
+/// who ran sudo rather than as root.
+#[cfg(feature = "vpn")]
+fn run_exec(cli: &Cli, cgroup: Option<&str>, command: &[String]) -> anyhow::Result<()> {
+    let cgroup = match cgroup {
+        Some(cgroup) => Some(cgroup.to_string()),
+        None => cli.app_config()?.vpn.cgroup,
+    };
+    let Some(cgroup) = cgroup else {
+        anyhow::bail!("x2ssh exec needs the VPN's cgroup: set vpn.cgroup or pass --vpn-cgroup");
+    };
+    vpn::cgroup::join(&cgroup)?;
+
+    let (program, args) = command.split_first().expect("clap requires a command");
+    let mut process = std::process::Command::new(program);
+    process.args(args);
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::CommandExt;
+
+        if elevate::is_root()
+            && let Some((uid, gid)) = vpn::cgroup::invoking_user()
+        {
+            process.uid(uid).gid(gid);
+        }
+        let err = process.exec();
+        anyhow::bail!("failed to run {}: {}", program, err)
+    }
+    #[cfg(not(unix))]
+    {
+        let status = process.status()?;
+        std::process::exit(status.code().unwrap_or(1))
+    }
+}
+
+#[cfg(not(feature = "vpn"))]
+fn run_exec(_cli: &Cli, _cgroup: Option<&str>, _command: &[String]) -> anyhow::Result<()> {
+    Err(without_vpn())
+}
+
+/// Checks the connection every `interval`, reconnecting when it is lost,
+/// until the transport shuts down; returns the error of a reconnect that
+/// gave up, which ends the session.
+async fn health_monitor(
+    transport: Arc<Transport>,
+    interval: Duration,
+    adaptive: bool,
+) -> anyhow::Result<()> {
+    let mut interval = AdaptiveInterval::new(interval);
+
+    loop {
+        tokio::select! {
+            _ = tokio::time::sleep(systemd::health_interval(interval.current())) => {
+                if transport.health_check().await.is_ok() {
+                    systemd::watchdog();
+                    if adaptive {
+                        interval.on_healthy();
+                    }
+                    continue;
+                }
+
+                warn!("SSH connection lost, attempting reconnect...");
+                transport.reconnect(transport.lost_cause().await).await?;
+                if adaptive {
+                    interval.on_reconnect();
+                    debug!("Health interval now {:?}", interval.current());
+                }
+            }
+            () = transport.shutdown().cancelled() => {
+                return Ok(());
+            }
+        }
+    }
+}
+
+#[cfg(feature = "vpn")]
+async fn resolve_host(host: &str, port: u16) -> anyhow::Result<SocketAddr> {
+    tokio::net::lookup_host((host, port))
+        .await?
+        .next()
+        .ok_or_else(|| anyhow::anyhow!("Failed to resolve host: {}", host))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn proxy(args: &[&str]) -> Result<ProxyArgs, clap::Error> {
+        match Cli::try_parse_from(["x2ssh", "proxy"].iter().chain(args))?.command {
+            Command::Proxy(args) => Ok(*args),
+            command => panic!("expected proxy, got {:?}", command),
+        }
+    }
+
+    fn vpn(args: &[&str]) -> Result<VpnArgs, clap::Error> {
+        match Cli::try_parse_from(["x2ssh", "vpn"].iter().chain(args))?.command {
+            Command::Vpn(args) => Ok(*args),
+            command => panic!("expected vpn, got {:?}", command),
+        }
+    }
+
+    #[test]
+    fn test_argument_parsing() {
+        let args = proxy(&["-D", "1080", "user@host.com"]).unwrap();
+        assert_eq!(args.connect.destination.as_deref(), Some("user@host.com"));
+        assert_eq!(args.connect.port, None);
+        assert_eq!(args.socks_addr.as_deref(), Some("1080"));
+
+        // The mode is the subcommand; the old flags are gone.
+        assert!(Cli::try_parse_from(["x2ssh", "-D", "1080", "user@host.com"]).is_err());
+        assert!(Cli::try_parse_from(["x2ssh", "--vpn", "user@host.com"]).is_err());
+        assert!(vpn(&["-D", "1080", "user@host.com"]).is_err());
+    }
+
+    #[test]
+    fn test_retry_duration_flags() {
+        let args = proxy(&["-D", "1080", "user@host.com"]).unwrap();
+        let config = args
+            .connect
+            .transport_config(&ConnectionConfig::default(), &RetryConfig::default())
+            .unwrap();
+        assert_eq!(config.retry_policy.max_attempts, None);
+        assert_eq!(config.retry_policy.initial_delay, Duration::from_secs(1));
+        assert_eq!(config.retry_policy.backoff, 2.0);
+        assert_eq!(config.retry_policy.max_delay, Duration::from_secs(30));
+        assert_eq!(config.health_interval, Duration::from_secs(5));
+
+        // The config file's [retry] applies unless a flag overrides it.
+        let retry = AppConfig::from_toml(
+            "[retry]\nmax_attempts = 4\ninitial_delay = \"250ms\"\nmax_delay = \"2m\"\n",
+        )
+        .unwrap()
+        .retry;
+        let config = args
+            .connect
+            .transport_config(&ConnectionConfig::default(), &retry)
+            .unwrap();
+        assert_eq!(config.retry_policy.max_attempts, Some(4));
+        assert_eq!(
+            config.retry_policy.initial_delay,
+            Duration::from_millis(250)
+        );
+        assert_eq!(config.retry_policy.max_delay, Duration::from_secs(120));
+
+        let args = proxy(&[
+            "--retry-delay",
+            "500ms",
+            "--retry-max-delay",
+            "1m",
+            "--health-interval",
+            "2000",
+            "user@host.com",
+        ])
+        .unwrap();
+        let config = args
+            .connect
+            .transport_config(&ConnectionConfig::default(), &retry)
+            .unwrap();
+        assert_eq!(
+            config.retry_policy.initial_delay,
+            Duration::from_millis(500)
+        );
+        assert_eq!(config.retry_policy.max_delay, Duration::from_secs(60));
+        assert_eq!(config.retry_policy.max_total_duration, None);
+        assert_eq!(config.health_interval, Duration::from_secs(2));
+        assert_eq!(config.health.check, HealthCheck::Channel);
+
+        let args = proxy(&[
+            "--retry-max-total",
+            "10m",
+            "--health-check",
+            "keepalive",
+            "user@host.com",
+        ])
+        .unwrap();
+        let config = args
+            .connect
+            .transport_config(&ConnectionConfig::default(), &retry)
+            .unwrap();
+        assert_eq!(
+            config.retry_policy.max_total_duration,
+            Some(Duration::from_secs(600))
+        );
+        assert_eq!(config.health.check, HealthCheck::Keepalive);
+        assert!(proxy(&["--health-check", "icmp", "user@host.com"]).is_err());
+
+        assert!(proxy(&["--retry-delay", "soon", "user@host.com"]).is_err());
+    }
+
+    #[test]
+    fn test_shutdown_timeout_overrides_config() {
+        let connection = ConnectionConfig {
+            shutdown_timeout: Duration::from_secs(3),
+            ..Default::default()
+        };
+
+        let args = proxy(&["-D", "1080", "user@host.com"]).unwrap();
+        let config = args
+            .connect
+            .transport_config(&connection, &RetryConfig::default())
+            .unwrap();
+        assert_eq!(config.shutdown_timeout, Duration::from_secs(3));
+
+        let args = proxy(&["-D", "1080", "--shutdown-timeout", "500ms", "user@host.com"]).unwrap();
+        let config = args
+            .connect
+            .transport_config(&connection, &RetryConfig::default())
+            .unwrap();
+        assert_eq!(config.shutdown_timeout, Duration::from_millis(500));
+    }
+
+    #[test]
+    fn test_metrics_flags() {
+        let args = vpn(&[
+            "--stats-interval",
+            "30s",
+            "--metrics-listen",
+            "127.0.0.1:9150",
+            "user@host.com",
+        ])
+        .unwrap();
+        assert_eq!(args.connect.metrics_interval, Some(Duration::from_secs(30)));
+        assert_eq!(
+            args.connect.metrics_listen,
+            Some("127.0.0.1:9150".parse().unwrap())
+        );
+
+        let args = proxy(&["--metrics-interval", "1m", "-D", "1080", "u@h"]).unwrap();
+        assert_eq!(args.connect.metrics_interval, Some(Duration::from_secs(60)));
+        assert!(proxy(&["--metrics-interval", "0s", "-D", "1080", "u@h"]).is_err());
+        assert_eq!(args.connect.metrics_listen, None);
+    }
+
+    #[test]
+    fn test_destination_from_config() {
+        let connection = ConnectionConfig {
+            host: Some("vpn.example.com".to_string()),
+            user: Some("alice".to_string()),
+            port: 2222,
+            identity: Some(PathBuf::from("/keys/id_ed25519")),
+            host_key: Some("SHA256:RbfGliidVlNx91NGl6K8xWLhOZAspnCgX2R8U2vfsIQ".to_string()),
+            ..Default::default()
+        };
+
+        let args = vpn(&["--config", "x.toml"]).unwrap();
+        let config = args
+            .connect
+            .transport_config(&connection, &RetryConfig::default())
+            .unwrap();
+        assert_eq!(config.user, "alice");
+        assert_eq!(config.host, "vpn.example.com");
+        assert_eq!(config.port, 2222);
+        assert_eq!(config.auth.len(), 1);
+        assert_eq!(config.auth[0].name(), "publickey /keys/id_ed25519");
+        assert_eq!(config.host_key, connection.host_key);
+
+        // The command line wins, and may leave the user to the config.
+        let args = vpn(&[
+            "-p",
+            "22",
+            "-i",
+            "/other/key",
+            "--host-key",
+            "SHA256:uNiVztksCsDhcc0u9e8BujQXVUpKZIDTMczCvj3tD2s",
+            "other.example.com",
+        ])
+        .unwrap();
+        let config = args
+            .connect
+            .transport_config(&connection, &RetryConfig::default())
+            .unwrap();
+        assert_eq!(config.user, "alice");
+        assert_eq!(config.host, "other.example.com");
+        assert_eq!(config.port, 22);
+        assert_eq!(config.auth[0].name(), "publickey /other/key");
+        assert_eq!(
+            config.host_key.as_deref(),
+            Some("SHA256:uNiVztksCsDhcc0u9e8BujQXVUpKZIDTMczCvj3tD2s")
+        );
+
+        let args = vpn(&["bob@other.example.com"]).unwrap();
+        assert_eq!(
+            args.connect
+                .transport_config(&connection, &RetryConfig::default())
+                .unwrap()
+                .user,
+            "bob"
+        );
+        let args = vpn(&["other.example.com"]).unwrap();
+        assert!(
+            args.connect
+                .transport_config(&ConnectionConfig::default(), &RetryConfig::default())
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_profile_flag() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("x2ssh.toml");
+        std::fs::write(
+            &path,
+            "[connection]\nhost = \"home.example.com\"\n\n[profiles.work.connection]\nhost = \
+             \"work.example.com\"\n",
+        )
+        .unwrap();
+        let config = path.to_str().unwrap();
+
+        let cli = Cli::try_parse_from(["x2ssh", "vpn", "--config", config]).unwrap();
+        let app_config = cli.app_config().unwrap();
+        assert_eq!(
+            app_config.connection.host.as_deref(),
+            Some("home.example.com")
+        );
+
+        let cli =
+            Cli::try_parse_from(["x2ssh", "--config", config, "--profile", "work", "vpn"]).unwrap();
+        let app_config = cli.app_config().unwrap();
+        assert_eq!(
+            app_config.connection.host.as_deref(),
+            Some("work.example.com")
+        );
+
+        let cli =
+            Cli::try_parse_from(["x2ssh", "vpn", "--config", config, "--profile", "cafe"]).unwrap();
+        assert!(cli.app_config().is_err());
+    }
+
+    #[test]
+    fn test_config_subcommands() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.toml");
+        let file = path.to_str().unwrap();
+
+        let cli = Cli::try_parse_from(["x2ssh", "config", "init", file]).unwrap();
+        let Command::Config { action } = &cli.command else {
+            panic!("expected config, got {:?}", cli.command);
+        };
+        run_config(&cli, action).unwrap();
+        assert!(run_config(&cli, action).is_err());
+
+        let cli = Cli::try_parse_from(["x2ssh", "--config", file, "config", "validate"]).unwrap();
+        let Command::Config { action } = &cli.command else {
+            panic!("expected config, got {:?}", cli.command);
+        };
+        run_config(&cli, action).unwrap();
+
+        std::fs::write(
+            &path,
+            "[connection]\nport = 0\n\n[profiles.work.connection]\nport = 2222\n",
+        )
+        .unwrap();
+        let err = run_config(&cli, action).unwrap_err();
+        assert!(err.to_string().ends_with("1 error(s)"), "{err}");
+    }
+
+    #[test]
+    fn test_legacy_server_flag() {
+        let args = proxy(&["-D", "1080", "user@host.com"]).unwrap();
+        let config = args
+            .connect
+            .transport_config(&ConnectionConfig::default(), &RetryConfig::default())
+            .unwrap();
+        assert!(!config.legacy_server);
+
+        let legacy = ConnectionConfig {
+            legacy_server: true,
+            ..Default::default()
+        };
+        assert!(
+            args.connect
+                .transport_config(&legacy, &RetryConfig::default())
+                .unwrap()
+                .legacy_server
+        );
+
+        let args = proxy(&["-D", "1080", "--legacy-server", "user@host.com"]).unwrap();
+        let config = args
+            .connect
+            .transport_config(&ConnectionConfig::default(), &RetryConfig::default())
+            .unwrap();
+        assert!(config.legacy_server);
+    }
+
+    #[test]
+    fn test_vpn_flag_parsing() {
+        let args = vpn(&["user@host.com"]).unwrap();
+        assert_eq!(args.connect.destination.as_deref(), Some("user@host.com"));
+        assert!(!args.auto_sudo);
+    }
+
+    #[test]
+    fn test_vpn_with_overrides() {
+        let args = vpn(&[
+            "--vpn-client-address",
+            "10.9.0.2/24",
+            "--vpn-server-address",
+            "10.9.0.1/24",
+            "--vpn-mtu",
             "1280",
+            "--vpn-tun-queues",
+            "4",
+            "--vpn-offload",
+            "--vpn-persistent-tun",
+            "--vpn-server-tun",
+            "tun-srv",
             "--vpn-exclude",
             "192.168.0.0/16",
             "--vpn-exclude",
@@ -334,39 +2444,649 @@ mod tests {
         ])
         .unwrap();
 
-        assert!(cli.vpn);
-        assert_eq!(cli.vpn_client_address, Some("10.9.0.2/24".to_string()));
-        assert_eq!(cli.vpn_server_address, Some("10.9.0.1/24".to_string()));
-        assert_eq!(cli.vpn_mtu, Some(1280));
-        assert_eq!(cli.vpn_exclude, vec![
+        assert_eq!(args.vpn_client_address, Some("10.9.0.2/24".to_string()));
+        assert_eq!(args.vpn_server_address, Some("10.9.0.1/24".to_string()));
+        assert_eq!(args.vpn_mtu, Some(1280));
+        assert_eq!(args.vpn_tun_queues, Some(4));
+        assert!(args.vpn_config(&AppConfig::default()).unwrap().offload);
+        assert!(
+            args.vpn_config(&AppConfig::default())
+                .unwrap()
+                .persistent_tun
+        );
+        assert_eq!(
+            args.vpn_config(&AppConfig::default()).unwrap().server_tun,
+            Some("tun-srv".to_string())
+        );
+        assert_eq!(args.vpn_exclude, vec![
             "192.168.0.0/16".to_string(),
             "10.0.0.0/8".to_string()
         ]);
     }
 
     #[test]
-    fn test_vpn_post_up_pre_down() {
+    fn test_vpn_ipv6_overrides() {
+        let args = vpn(&[
+            "--vpn-client-address6",
+            "fd00:9::2/64",
+            "--vpn-server-address6",
+            "fd00:9::1/64",
+            "user@host.com",
+        ])
+        .unwrap();
+
+        let config = args.vpn_config(&AppConfig::default()).unwrap();
+        assert!(!config.kill_switch);
+        assert_eq!(config.client_address6, Some("fd00:9::2/64".to_string()));
+        assert_eq!(config.server_address6, Some("fd00:9::1/64".to_string()));
+        assert!(config.ipv6_addresses().unwrap().is_some());
+    }
+
+    #[test]
+    fn test_vpn_include_overrides_config() {
+        let mut app_config = AppConfig::default();
+        app_config.vpn.include = vec!["10.0.0.0/8".to_string()];
+
+        let args = vpn(&["user@host.com"]).unwrap();
+        assert_eq!(args.vpn_config(&app_config).unwrap().include, vec![
+            "10.0.0.0/8"
+        ]);
+
+        let args = vpn(&[
+            "--vpn-include",
+            "172.16.0.0/12",
+            "--vpn-include",
+            "fd00:1::/48",
+            "user@host.com",
+        ])
+        .unwrap();
+        assert_eq!(args.vpn_config(&app_config).unwrap().include, vec![
+            "172.16.0.0/12",
+            "fd00:1::/48"
+        ]);
+    }
+
+    #[test]
+    fn test_vpn_route_overrides_config() {
+        let mut app_config = AppConfig::default();
+        app_config.vpn.routes = vec!["10.0.0.0/8 via lan".to_string()];
+
+        let args = vpn(&["--vpn-route", "172.16.0.0/12 via tun", "user@host.com"]).unwrap();
+        assert_eq!(args.vpn_config(&app_config).unwrap().routes, vec![
+            "172.16.0.0/12 via tun"
+        ]);
+    }
+
+    #[test]
+    fn test_vpn_domain_flag() {
+        let args = vpn(&[
+            "--vpn-domain",
+            "*.corp.example",
+            "--vpn-domain",
+            "wiki.example.org",
+            "user@host.com",
+        ])
+        .unwrap();
+
+        let config = args.vpn_config(&AppConfig::default()).unwrap();
+        assert_eq!(config.domains, vec!["*.corp.example", "wiki.example.org"]);
+        assert!(config.split_tunnel());
+    }
+
+    #[test]
+    fn test_vpn_exclude_lan_flag() {
+        let args = vpn(&["user@host.com"]).unwrap();
+        assert!(!args.vpn_config(&AppConfig::default()).unwrap().exclude_lan);
+
+        let args = vpn(&["--vpn-exclude-lan", "user@host.com"]).unwrap();
+        assert!(args.vpn_config(&AppConfig::default()).unwrap().exclude_lan);
+    }
+
+    #[test]
+    fn test_vpn_queue_policy_flag() {
+        let args = vpn(&["user@host.com"]).unwrap();
+        assert_eq!(
+            args.vpn_config(&AppConfig::default()).unwrap().queue_policy,
+            QueuePolicy::Drop
+        );
+
+        let args = vpn(&["--vpn-queue-policy", "wait", "user@host.com"]).unwrap();
+        assert_eq!(
+            args.vpn_config(&AppConfig::default()).unwrap().queue_policy,
+            QueuePolicy::Wait
+        );
+    }
+
+    #[test]
+    fn test_exec_subcommand() {
         let cli = Cli::try_parse_from([
             "x2ssh",
-            "--vpn",
+            "exec",
+            "--vpn-cgroup",
+            "user.slice/x2ssh",
+            "--",
+            "curl",
+            "-s",
+            "https://example.com",
+        ])
+        .unwrap();
+        let Command::Exec { cgroup, command } = &cli.command else {
+            panic!("expected exec, got {:?}", cli.command);
+        };
+        assert_eq!(command, &["curl", "-s", "https://example.com"]);
+        assert_eq!(cgroup.as_deref(), Some("user.slice/x2ssh"));
+
+        assert!(Cli::try_parse_from(["x2ssh", "exec"]).is_err());
+    }
+
+    #[test]
+    fn test_vpn_routing_mode_flag() {
+        let args = vpn(&["--vpn-routing-mode", "policy", "user@host.com"]).unwrap();
+        assert_eq!(
+            args.vpn_config(&AppConfig::default()).unwrap().routing_mode,
+            RoutingMode::Policy
+        );
+
+        let args = vpn(&[
+            "--vpn-routing-mode",
+            "metric",
+            "--vpn-route-metric",
+            "50",
+            "user@host.com",
+        ])
+        .unwrap();
+        let config = args.vpn_config(&AppConfig::default()).unwrap();
+        assert_eq!(config.routing_mode, RoutingMode::Metric);
+        assert_eq!(config.route_metric, 50);
+
+        assert!(vpn(&["--vpn-routing-mode", "tables", "user@host.com",]).is_err());
+    }
+
+    #[test]
+    fn test_vpn_keepalive_flags() {
+        let args = vpn(&[
+            "--vpn-keepalive",
+            "500ms",
+            "--vpn-keepalive-timeout",
+            "3s",
+            "--vpn-batch-delay",
+            "0",
+            "user@host.com",
+        ])
+        .unwrap();
+        let config = args.vpn_config(&AppConfig::default()).unwrap();
+        assert_eq!(config.keepalive_interval, Duration::from_millis(500));
+        assert_eq!(config.keepalive_timeout, Duration::from_secs(3));
+        assert_eq!(config.batch_delay, Duration::ZERO);
+        assert!(!config.compress);
+        assert!(vpn(&["--vpn-keepalive", "0s", "user@host.com"]).is_err());
+
+        let args = vpn(&[
+            "--vpn-compress",
+            "--vpn-compress-threshold",
+            "128",
+            "user@host.com",
+        ])
+        .unwrap();
+        let config = args.vpn_config(&AppConfig::default()).unwrap();
+        assert!(config.compress);
+        assert_eq!(config.compress_threshold, 128);
+    }
+
+    #[test]
+    fn test_vpn_kill_switch_flag() {
+        let args = vpn(&["--vpn-kill-switch", "user@host.com"]).unwrap();
+        assert!(args.vpn_config(&AppConfig::default()).unwrap().kill_switch);
+    }
+
+    #[test]
+    fn test_vpn_filter_flags() {
+        let args = vpn(&[
+            "--vpn-block-inbound",
+            "--vpn-block-protocol",
+            "gre",
+            "--vpn-block-protocol",
+            "udp",
+            "user@host.com",
+        ])
+        .unwrap();
+        let config = args.vpn_config(&AppConfig::default()).unwrap();
+        assert!(config.block_inbound);
+        assert_eq!(config.blocked_protocols, vec!["gre", "udp"]);
+    }
+
+    #[test]
+    fn test_vpn_pcap_flag() {
+        let args = vpn(&["--vpn-pcap", "/tmp/tun.pcapng", "user@host.com"]).unwrap();
+        assert_eq!(
+            args.vpn_config(&AppConfig::default()).unwrap().pcap,
+            Some(PathBuf::from("/tmp/tun.pcapng"))
+        );
+    }
+
+    #[test]
+    fn test_vpn_dns64_flag() {
+        let args = vpn(&["--vpn-dns64", "user@host.com"]).unwrap();
+        assert!(args.vpn_config(&AppConfig::default()).unwrap().dns64);
+    }
+
+    #[test]
+    fn test_status_subcommand() {
+        let cli = Cli::try_parse_from(["x2ssh", "status", "--history"]).unwrap();
+        assert!(matches!(cli.command, Command::Status {
+            history: true,
+            json: false
+        }));
+        let cli = Cli::try_parse_from(["x2ssh", "status", "--json"]).unwrap();
+        assert!(matches!(cli.command, Command::Status { json: true, .. }));
+        let cli =
+            Cli::try_parse_from(["x2ssh", "stats", "--days", "30", "--destinations"]).unwrap();
+        assert!(matches!(cli.command, Command::Stats {
+            days: Some(30),
+            destinations: true,
+            json: false,
+            file: None,
+        }));
+
+        assert!(Cli::try_parse_from(["x2ssh"]).is_err());
+        assert!(Cli::try_parse_from(["x2ssh", "status", "user@host.com"]).is_err());
+
+        let cli = Cli::try_parse_from(["x2ssh", "top", "--interval", "250ms"]).unwrap();
+        assert!(
+            matches!(cli.command, Command::Top { interval } if interval == Duration::from_millis(250))
+        );
+        assert!(Cli::try_parse_from(["x2ssh", "top", "--interval", "0s"]).is_err());
+    }
+
+    #[test]
+    fn test_stop_subcommand() {
+        let cli = Cli::try_parse_from(["x2ssh", "stop", "4242"]).unwrap();
+        assert!(matches!(cli.command, Command::Stop {
+            pid: Some(4242),
+            all: false,
+            pidfile: None
+        }));
+        let cli = Cli::try_parse_from(["x2ssh", "stop", "--all"]).unwrap();
+        assert!(matches!(cli.command, Command::Stop {
+            pid: None,
+            all: true,
+            pidfile: None
+        }));
+        let cli =
+            Cli::try_parse_from(["x2ssh", "stop", "--pidfile", "/run/x2ssh/work.pid"]).unwrap();
+        let Command::Stop { pidfile, .. } = &cli.command else {
+            panic!("expected stop, got {:?}", cli.command);
+        };
+        assert_eq!(pidfile.as_deref(), Some(Path::new("/run/x2ssh/work.pid")));
+
+        assert!(Cli::try_parse_from(["x2ssh", "stop", "4242", "--all"]).is_err());
+        assert!(Cli::try_parse_from(["x2ssh", "stop", "4242", "--pidfile", "x.pid"]).is_err());
+    }
+
+    #[test]
+    fn test_local_forward_flag() {
+        let args = proxy(&[
+            "-L",
+            "8080:db:5432",
+            "--local-forward",
+            "[::1]:2222:host:22",
+            "user@host",
+        ])
+        .unwrap();
+        let config = ForwardConfig {
+            local: vec!["5000:cache:6379".to_string()],
+        };
+        let forwards: Vec<String> = args
+            .connect
+            .forwards(&config)
+            .unwrap()
+            .iter()
+            .map(ToString::to_string)
+            .collect();
+        assert_eq!(forwards, [
+            "-L 5000:cache:6379",
+            "-L 8080:db:5432",
+            "-L [::1]:2222:host:22"
+        ]);
+
+        assert!(vpn(&["-L", "8080:db:5432", "user@host"]).is_ok());
+        assert!(proxy(&["-L", "8080:db", "user@host"]).is_err());
+    }
+
+    #[test]
+    fn test_stdio_subcommand() {
+        let cli =
+            Cli::try_parse_from(["x2ssh", "stdio", "-W", "[fd00::1]:22", "user@bastion"]).unwrap();
+        let Command::Stdio(args) = &cli.command else {
+            panic!("expected stdio, got {:?}", cli.command);
+        };
+        assert_eq!(args.target, ("fd00::1".to_string(), 22));
+        assert_eq!(args.connect.destination.as_deref(), Some("user@bastion"));
+        assert!(cli.daemon().is_none());
+
+        assert!(Cli::try_parse_from(["x2ssh", "stdio", "user@bastion"]).is_err());
+        assert!(Cli::try_parse_from(["x2ssh", "stdio", "-W", "db", "user@bastion"]).is_err());
+        assert!(
+            Cli::try_parse_from([
+                "x2ssh",
+                "stdio",
+                "-W",
+                "db:5432",
+                "--daemon",
+                "user@bastion"
+            ])
+            .is_err()
+        );
+        assert!(
+            Cli::try_parse_from([
+                "x2ssh",
+                "stdio",
+                "-W",
+                "db:5432",
+                "-L",
+                "1:a:2",
+                "user@bastion"
+            ])
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn test_doctor_subcommand() {
+        let cli =
+            Cli::try_parse_from(["x2ssh", "doctor", "--vpn-kill-switch", "user@host"]).unwrap();
+        let Command::Doctor(args) = &cli.command else {
+            panic!("expected doctor, got {:?}", cli.command);
+        };
+        assert_eq!(args.connect.destination.as_deref(), Some("user@host"));
+        let config = args.vpn_config(&AppConfig::default()).unwrap();
+        assert!(config.kill_switch);
+    }
+
+    #[test]
+    fn test_install_service_subcommand() {
+        let cli = Cli::try_parse_from([
+            "x2ssh",
+            "install-service",
+            "--user",
+            "proxy",
+            "-D",
+            "1080",
+            "user@host",
+        ])
+        .unwrap();
+        let Command::InstallService {
+            name,
+            user,
+            command,
+            ..
+        } = &cli.command
+        else {
+            panic!("expected install-service, got {:?}", cli.command);
+        };
+        assert_eq!(name, "x2ssh");
+        assert!(user);
+        assert_eq!(command, &["proxy", "-D", "1080", "user@host"]);
+
+        assert!(Cli::try_parse_from(["x2ssh", "install-service"]).is_err());
+    }
+
+    #[test]
+    fn test_completions_and_man() {
+        Cli::command().debug_assert();
+
+        let cli = Cli::try_parse_from(["x2ssh", "completions", "zsh"]).unwrap();
+        assert!(matches!(cli.command, Command::Completions {
+            shell: clap_complete::Shell::Zsh
+        }));
+        assert!(Cli::try_parse_from(["x2ssh", "completions", "tcsh"]).is_err());
+
+        let mut script = Vec::new();
+        clap_complete::generate(
+            clap_complete::Shell::Bash,
+            &mut Cli::command(),
+            "x2ssh",
+            &mut script,
+        );
+        let script = String::from_utf8(script).unwrap();
+        assert!(script.contains("--local-forward"));
+        assert!(script.contains("install-service"));
+
+        let dir = tempfile::tempdir().unwrap();
+        print_man(Some(dir.path())).unwrap();
+        assert!(dir.path().join("x2ssh.1").exists());
+        assert!(dir.path().join("x2ssh-proxy.1").exists());
+    }
+
+    #[test]
+    fn test_forward_subcommand() {
+        let cli = Cli::try_parse_from([
+            "x2ssh",
+            "forward",
+            "add",
+            "-L",
+            "8080:db:5432",
+            "--pid",
+            "42",
+        ])
+        .unwrap();
+        let Command::Forward {
+            action: ForwardCommand::Add { forward, pid },
+        } = &cli.command
+        else {
+            panic!("expected forward add, got {:?}", cli.command);
+        };
+        assert_eq!(forward.forward().to_string(), "-L 8080:db:5432");
+        assert_eq!(*pid, Some(42));
+
+        let cli = Cli::try_parse_from(["x2ssh", "forward", "remove", "-D", "1080"]).unwrap();
+        let Command::Forward {
+            action: ForwardCommand::Remove { forward, pid: None },
+        } = &cli.command
+        else {
+            panic!("expected forward remove, got {:?}", cli.command);
+        };
+        assert_eq!(forward.forward().to_string(), "-D 1080");
+        assert!(Cli::try_parse_from(["x2ssh", "forward", "list"]).is_ok());
+
+        assert!(Cli::try_parse_from(["x2ssh", "forward", "add"]).is_err());
+        assert!(Cli::try_parse_from(["x2ssh", "forward", "add", "-R", "0:localhost:22"]).is_err());
+        assert!(
+            Cli::try_parse_from(["x2ssh", "forward", "add", "-D", "1080", "-L", "1:a:2"]).is_err()
+        );
+    }
+
+    #[test]
+    fn test_daemon_flags() {
+        let cli = Cli::try_parse_from(["x2ssh", "proxy", "-D", "1080", "user@host.com"]).unwrap();
+        assert_eq!(cli.daemon(), None);
+
+        let cli = Cli::try_parse_from([
+            "x2ssh",
+            "vpn",
+            "--daemon",
+            "--pidfile",
+            "/run/x2ssh/work.pid",
+            "--log-file",
+            "/var/log/x2ssh/work.log",
+            "user@host.com",
+        ])
+        .unwrap();
+        assert_eq!(
+            cli.daemon(),
+            Some(Daemon {
+                pidfile: PathBuf::from("/run/x2ssh/work.pid"),
+                log_file: PathBuf::from("/var/log/x2ssh/work.log"),
+            })
+        );
+
+        let cli = Cli::try_parse_from(["x2ssh", "proxy", "--daemon", "-D", "1080", "u@h"]).unwrap();
+        assert_eq!(
+            cli.daemon(),
+            Some(Daemon {
+                pidfile: daemon::default_pidfile(),
+                log_file: daemon::default_log_file(),
+            })
+        );
+
+        assert!(proxy(&["--pidfile", "x.pid", "-D", "1080", "u@h"]).is_err());
+
+        let cli = Cli::try_parse_from(["x2ssh", "proxy", "--log-file", "x.log", "u@h"]).unwrap();
+        assert_eq!(cli.daemon(), None);
+        assert_eq!(cli.log_file(), Some(Path::new("x.log")));
+    }
+
+    #[test]
+    fn test_log_flags() {
+        let cli = Cli::try_parse_from(["x2ssh", "proxy", "-vv", "u@h"]).unwrap();
+        assert_eq!((cli.verbose, cli.quiet, cli.log_format), (2, 0, None));
+
+        let cli =
+            Cli::try_parse_from(["x2ssh", "-q", "vpn", "--log-format", "json", "u@h"]).unwrap();
+        assert_eq!(
+            (cli.verbose, cli.quiet, cli.log_format),
+            (0, 1, Some(LogFormat::Json))
+        );
+
+        assert!(Cli::try_parse_from(["x2ssh", "proxy", "-v", "-q", "u@h"]).is_err());
+        assert!(Cli::try_parse_from(["x2ssh", "proxy", "--log-format", "xml", "u@h"]).is_err());
+    }
+
+    #[test]
+    fn test_vpn_agent_path_flag() {
+        let args = vpn(&["--vpn-agent-path", "/opt/x2ssh/agent", "user@host.com"]).unwrap();
+        let config = args.vpn_config(&AppConfig::default()).unwrap();
+        assert_eq!(config.agent_path.as_deref(), Some("/opt/x2ssh/agent"));
+        assert!(!config.keep_agent);
+        assert!(!config.nat);
+
+        let args = vpn(&["--vpn-keep-agent", "user@host.com"]).unwrap();
+        assert!(args.vpn_config(&AppConfig::default()).unwrap().keep_agent);
+
+        let args = vpn(&["--vpn-no-deploy-agent", "user@host.com"]).unwrap();
+        assert!(!args.vpn_config(&AppConfig::default()).unwrap().deploy_agent);
+
+        let args = vpn(&["--vpn-nat", "--vpn-agent-dns", "user@host.com"]).unwrap();
+        let config = args.vpn_config(&AppConfig::default()).unwrap();
+        assert!(config.nat);
+        assert!(config.agent_dns);
+    }
+
+    #[test]
+    fn test_ready_flags() {
+        let args = proxy(&[
+            "-D",
+            "1080",
+            "--ready-command",
+            "systemctl start app",
+            "--ready-target",
+            "intranet.example:443",
+            "--health-listen",
+            "127.0.0.1:8086",
+            "user@host.com",
+        ])
+        .unwrap();
+        assert_eq!(args.connect.readiness(), Readiness {
+            command: Some("systemctl start app".to_string()),
+            target: Some("intranet.example:443".to_string()),
+            probed: true,
+        });
+
+        let args = proxy(&["-D", "1080", "user@host.com"]).unwrap();
+        assert_eq!(args.connect.readiness(), Readiness::default());
+    }
+
+    #[test]
+    fn test_vpn_elevation_flags() {
+        let args = vpn(&[
+            "--vpn-elevation",
+            "sudo",
+            "--vpn-sudo-password-file",
+            "/etc/x2ssh/sudo-password",
+            "user@host.com",
+        ])
+        .unwrap();
+        let config = args.vpn_config(&AppConfig::default()).unwrap();
+        assert_eq!(config.elevation, Elevation::Sudo);
+        assert_eq!(
+            config.sudo_password_file,
+            Some(PathBuf::from("/etc/x2ssh/sudo-password"))
+        );
+
+        assert!(vpn(&["--vpn-elevation", "su", "user@host.com"]).is_err());
+    }
+
+    #[test]
+    fn test_vpn_cleanup_needs_no_destination() {
+        let args = vpn(&["--vpn-cleanup", "--vpn-client-tun", "tun9"]).unwrap();
+        assert!(args.vpn_cleanup);
+        assert_eq!(args.connect.destination, None);
+        assert_eq!(
+            args.vpn_config(&AppConfig::default()).unwrap().client_tun,
+            "tun9"
+        );
+
+        let args = vpn(&[]).unwrap();
+        assert!(
+            args.connect
+                .transport_config(&ConnectionConfig::default(), &RetryConfig::default())
+                .is_err()
+        );
+        assert!(vpn(&["--vpn-cleanup", "--vpn-dry-run"]).is_err());
+    }
+
+    #[cfg(feature = "vpn")]
+    #[test]
+    fn test_vpn_cleanup_action() {
+        assert!(vpn(&["--vpn-cleanup"]).unwrap().cleanup());
+        let args = vpn(&["--vpn-client-tun", "tun9", "cleanup"]).unwrap();
+        assert!(args.cleanup());
+        assert_eq!(args.connect.destination, None);
+        assert!(!vpn(&["user@cleanup"]).unwrap().cleanup());
+    }
+
+    #[test]
+    fn test_vpn_dry_run_is_vpn_only() {
+        let args = vpn(&["--vpn-dry-run", "user@host.com"]).unwrap();
+        assert!(args.vpn_dry_run);
+
+        assert!(proxy(&["--vpn-dry-run", "user@host.com"]).is_err());
+    }
+
+    #[test]
+    fn test_vpn_post_up_pre_down() {
+        let args = vpn(&[
             "--vpn-post-up",
             "sysctl -w net.ipv4.ip_forward=1",
             "--vpn-post-up",
             "iptables -t nat -I POSTROUTING -o eth0 -j MASQUERADE",
             "--vpn-pre-down",
             "iptables -t nat -D POSTROUTING -o eth0 -j MASQUERADE",
+            "--vpn-local-post-up",
+            "notify-send 'VPN up'",
+            "--vpn-local-pre-down",
+            "notify-send 'VPN down'",
             "user@host.com",
         ])
         .unwrap();
 
-        assert!(cli.vpn);
-        assert_eq!(cli.vpn_post_up, vec![
+        assert_eq!(args.vpn_post_up, vec![
             "sysctl -w net.ipv4.ip_forward=1".to_string(),
             "iptables -t nat -I POSTROUTING -o eth0 -j MASQUERADE".to_string(),
         ]);
-        assert_eq!(cli.vpn_pre_down, vec![
+        assert_eq!(args.vpn_pre_down, vec![
             "iptables -t nat -D POSTROUTING -o eth0 -j MASQUERADE".to_string()
         ]);
+
+        let mut app = AppConfig::default();
+        app.vpn.local_post_up = vec!["from config".to_string()];
+        let config = args.vpn_config(&app).unwrap();
+        assert_eq!(config.local_post_up, vec![
+            "notify-send 'VPN up'".to_string()
+        ]);
+        assert_eq!(config.local_pre_down, vec![
+            "notify-send 'VPN down'".to_string()
+        ]);
     }
 
     #[test]
@@ -376,19 +3096,78 @@ mod tests {
         assert_eq!(host, "server.com");
     }
 
+    #[test]
+    fn test_socks_resolve_flag() {
+        let mut app_config = AppConfig::default();
+        app_config
+            .socks
+            .resolve
+            .insert("onion".to_string(), ResolvePolicy::Remote);
+
+        let args = proxy(&[
+            "-D",
+            "1080",
+            "--socks-resolve",
+            "onion=socks5://127.0.0.1:9050",
+            "--socks-resolve",
+            "internal=remote",
+            "user@host.com",
+        ])
+        .unwrap();
+        let options = args.socks_config(&app_config.socks).options().unwrap();
+        assert!(matches!(
+            options.resolve.policy("abc.onion"),
+            ResolvePolicy::Socks { port: 9050, .. }
+        ));
+        assert_eq!(
+            options.resolve.policy("git.internal"),
+            &ResolvePolicy::Remote
+        );
+
+        assert!(proxy(&["--socks-resolve", "onion", "user@host.com"]).is_err());
+        assert!(proxy(&["--socks-resolve", "onion=tor", "user@host.com"]).is_err());
+    }
+
     #[test]
     fn test_socks_addr_port_only() {
-        let cli = Cli::try_parse_from(["x2ssh", "-D", "1080", "user@host.com"]).unwrap();
+        let args = proxy(&["-D", "1080", "user@host.com"]).unwrap();
 
-        let addr = cli.socks_socket_addr().unwrap();
+        let addr = args.socks_socket_addr(&SocksConfig::default()).unwrap();
         assert_eq!(addr.port(), 1080);
     }
 
     #[test]
     fn test_socks_addr_full() {
-        let cli = Cli::try_parse_from(["x2ssh", "-D", "127.0.0.1:8080", "user@host.com"]).unwrap();
+        let args = proxy(&["-D", "127.0.0.1:8080", "user@host.com"]).unwrap();
 
-        let addr = cli.socks_socket_addr().unwrap();
+        let addr = args.socks_socket_addr(&SocksConfig::default()).unwrap();
         assert_eq!(addr.port(), 8080);
     }
+
+    #[test]
+    fn test_socks_listen_from_config() {
+        let config = SocksConfig {
+            listen: Some("0.0.0.0:1080".to_string()),
+            ..Default::default()
+        };
+        let args = proxy(&["user@host.com"]).unwrap();
+        assert_eq!(
+            args.socks_socket_addr(&config).unwrap(),
+            "0.0.0.0:1080".parse().unwrap()
+        );
+
+        // -D overrides the config...
+        let args = proxy(&["-D", "9050", "user@host.com"]).unwrap();
+        assert_eq!(
+            args.socks_socket_addr(&config).unwrap(),
+            "127.0.0.1:9050".parse().unwrap()
+        );
+
+        assert!(
+            proxy(&["user@host.com"])
+                .unwrap()
+                .socks_socket_addr(&SocksConfig::default())
+                .is_err()
+        );
+    }
 }
@@ -0,0 +1,902 @@
+//! Activity counters, delivered to a pluggable [`MetricsSink`] so embedders
+//! can bridge them to their own telemetry.
+
+use std::fmt::Write;
+use std::sync::Arc;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+
+use tokio::io::AsyncReadExt;
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpListener;
+use tokio::net::TcpStream;
+use tracing::debug;
+use tracing::info;
+
+/// Something worth counting, reported as it happens.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Metric {
+    /// A new SSH session replaced a lost one.
+    Reconnected,
+    /// A reconnect gave up after exhausting the retry policy.
+    ReconnectFailed,
+    /// A health probe answered after `rtt`, or failed if `None`.
+    HealthProbe { rtt: Option<Duration> },
+    /// A client connected to the SOCKS port.
+    SocksAccepted,
+    /// A SOCKS connection finished; `sent` is client to target.
+    SocksClosed { sent: u64, received: u64 },
+    /// A SOCKS connection failed before or while connecting.
+    SocksFailed,
+    /// A packet from the TUN device went into the tunnel.
+    PacketSent { bytes: usize },
+    /// A packet from the tunnel was written to the TUN device.
+    PacketReceived { bytes: usize },
+    /// A packet finished a step of the VPN forwarding path after `elapsed`.
+    StageLatency { stage: Stage, elapsed: Duration },
+    /// A packet was queued for `stage` behind `depth` others.
+    QueueDepth { stage: Stage, depth: usize },
+    /// A VPN packet was lost at `stage`: its queue was full, or the TUN
+    /// device refused it.
+    PacketDropped { stage: Stage },
+    /// A VPN packet was refused for `reason` before reaching the TUN device
+    /// or the tunnel.
+    PacketRejected { reason: Reject },
+}
+
+/// Why a VPN packet was refused.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Reject {
+    /// The agent sent something that is not a whole IP packet.
+    Malformed,
+    /// `blocked_protocols` or `block_inbound` matched it.
+    Filtered,
+}
+
+impl Reject {
+    pub const ALL: [Reject; 2] = [Reject::Malformed, Reject::Filtered];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Reject::Malformed => "malformed",
+            Reject::Filtered => "filtered",
+        }
+    }
+}
+
+/// A step of the VPN forwarding path. Packets are queued between the side
+/// that reads them and the side that writes them, so a slow SSH channel or
+/// TUN device shows up as time spent in its queue.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stage {
+    /// Waiting between the TUN reader and the SSH writer.
+    OutboundQueue,
+    /// Framing a packet and writing it to the SSH channel.
+    SshWrite,
+    /// Waiting between the SSH reader and the TUN writer.
+    InboundQueue,
+    /// Writing a packet to the TUN device.
+    TunWrite,
+}
+
+impl Stage {
+    pub const ALL: [Stage; 4] = [
+        Stage::OutboundQueue,
+        Stage::SshWrite,
+        Stage::InboundQueue,
+        Stage::TunWrite,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Stage::OutboundQueue => "outbound_queue",
+            Stage::SshWrite => "ssh_write",
+            Stage::InboundQueue => "inbound_queue",
+            Stage::TunWrite => "tun_write",
+        }
+    }
+
+    /// Whether packets wait here, so queue depth is reported for it.
+    pub fn is_queue(self) -> bool {
+        matches!(self, Stage::OutboundQueue | Stage::InboundQueue)
+    }
+}
+
+/// Upper bounds of the latency buckets, in microseconds.
+const LATENCY_BOUNDS_US: &[u64] = &[
+    50, 100, 250, 500, 1_000, 2_500, 5_000, 10_000, 25_000, 50_000, 100_000, 250_000, 1_000_000,
+];
+
+/// Upper bounds of the queue depth buckets, in packets.
+const DEPTH_BOUNDS: &[u64] = &[0, 1, 2, 4, 8, 16, 32, 64, 128, 256];
+
+/// Counts of values in fixed buckets, plus an overflow bucket above the
+/// last bound.
+pub struct Histogram {
+    bounds: &'static [u64],
+    buckets: Vec<AtomicU64>,
+    sum: AtomicU64,
+}
+
+impl Histogram {
+    fn new(bounds: &'static [u64]) -> Self {
+        Self {
+            bounds,
+            buckets: (0..=bounds.len()).map(|_| AtomicU64::new(0)).collect(),
+            sum: AtomicU64::new(0),
+        }
+    }
+
+    fn observe(&self, value: u64) {
+        let bucket = self.bounds.partition_point(|bound| *bound < value);
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.sum.fetch_add(value, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> HistogramSnapshot {
+        HistogramSnapshot {
+            bounds: self.bounds,
+            counts: self
+                .buckets
+                .iter()
+                .map(|bucket| bucket.load(Ordering::Relaxed))
+                .collect(),
+            sum: self.sum.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// A point-in-time copy of a [`Histogram`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HistogramSnapshot {
+    pub bounds: &'static [u64],
+    /// Per bucket, not cumulative; the last one is above every bound.
+    pub counts: Vec<u64>,
+    pub sum: u64,
+}
+
+impl HistogramSnapshot {
+    pub fn count(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    /// Upper bound of the bucket holding quantile `q` (0 to 1), or `None`
+    /// if it is the overflow bucket or nothing was observed.
+    pub fn quantile(&self, q: f64) -> Option<u64> {
+        let count = self.count();
+        if count == 0 {
+            return None;
+        }
+        let rank = ((q * count as f64).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (bucket, n) in self.counts.iter().enumerate() {
+            seen += n;
+            if seen >= rank {
+                return self.bounds.get(bucket).copied();
+            }
+        }
+        None
+    }
+}
+
+/// Receives [`Metric`]s. Called inline on hot paths (once per tunnelled
+/// packet), so implementations should only update counters or hand off.
+pub trait MetricsSink: Send + Sync + 'static {
+    fn record(&self, metric: Metric);
+}
+
+/// Discards everything; the default.
+pub struct NoopMetrics;
+
+impl MetricsSink for NoopMetrics {
+    fn record(&self, _metric: Metric) {}
+}
+
+/// Hands every metric to each of several sinks, e.g. a log summary and a
+/// Prometheus endpoint at once.
+pub struct FanoutMetrics(pub Vec<Arc<dyn MetricsSink>>);
+
+impl MetricsSink for FanoutMetrics {
+    fn record(&self, metric: Metric) {
+        for sink in &self.0 {
+            sink.record(metric);
+        }
+    }
+}
+
+/// Running totals of all metrics.
+pub struct Counters {
+    reconnects: AtomicU64,
+    reconnect_failures: AtomicU64,
+    health_probes: AtomicU64,
+    health_probe_failures: AtomicU64,
+    /// Sum of the successful probes' round-trip times.
+    health_rtt_us: AtomicU64,
+    socks_accepted: AtomicU64,
+    socks_closed: AtomicU64,
+    socks_failed: AtomicU64,
+    socks_bytes_sent: AtomicU64,
+    socks_bytes_received: AtomicU64,
+    packets_sent: AtomicU64,
+    packets_received: AtomicU64,
+    tunnel_bytes_sent: AtomicU64,
+    tunnel_bytes_received: AtomicU64,
+    /// Indexed like [`Stage::ALL`].
+    latency: [Histogram; 4],
+    depth: [Histogram; 4],
+    dropped: [AtomicU64; 4],
+    /// Indexed like [`Reject::ALL`].
+    rejected: [AtomicU64; 2],
+}
+
+impl Default for Counters {
+    fn default() -> Self {
+        Self {
+            reconnects: AtomicU64::new(0),
+            reconnect_failures: AtomicU64::new(0),
+            health_probes: AtomicU64::new(0),
+            health_probe_failures: AtomicU64::new(0),
+            health_rtt_us: AtomicU64::new(0),
+            socks_accepted: AtomicU64::new(0),
+            socks_closed: AtomicU64::new(0),
+            socks_failed: AtomicU64::new(0),
+            socks_bytes_sent: AtomicU64::new(0),
+            socks_bytes_received: AtomicU64::new(0),
+            packets_sent: AtomicU64::new(0),
+            packets_received: AtomicU64::new(0),
+            tunnel_bytes_sent: AtomicU64::new(0),
+            tunnel_bytes_received: AtomicU64::new(0),
+            latency: Stage::ALL.map(|_| Histogram::new(LATENCY_BOUNDS_US)),
+            depth: Stage::ALL.map(|_| Histogram::new(DEPTH_BOUNDS)),
+            dropped: Stage::ALL.map(|_| AtomicU64::new(0)),
+            rejected: Reject::ALL.map(|_| AtomicU64::new(0)),
+        }
+    }
+}
+
+/// A point-in-time copy of [`Counters`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Snapshot {
+    pub reconnects: u64,
+    pub reconnect_failures: u64,
+    pub socks_accepted: u64,
+    pub socks_closed: u64,
+    pub socks_failed: u64,
+    pub socks_bytes_sent: u64,
+    pub socks_bytes_received: u64,
+    pub packets_sent: u64,
+    pub packets_received: u64,
+    pub tunnel_bytes_sent: u64,
+    pub tunnel_bytes_received: u64,
+    /// VPN packets dropped at any stage.
+    pub packets_dropped: u64,
+    /// VPN packets refused for any reason.
+    pub packets_rejected: u64,
+}
+
+impl Snapshot {
+    /// SOCKS connections accepted but not yet finished.
+    pub fn socks_active(&self) -> u64 {
+        self.socks_accepted
+            .saturating_sub(self.socks_closed + self.socks_failed)
+    }
+}
+
+impl Counters {
+    pub fn add(&self, metric: Metric) {
+        let inc = |counter: &AtomicU64, n: u64| {
+            counter.fetch_add(n, Ordering::Relaxed);
+        };
+        match metric {
+            Metric::Reconnected => inc(&self.reconnects, 1),
+            Metric::ReconnectFailed => inc(&self.reconnect_failures, 1),
+            Metric::HealthProbe { rtt } => {
+                inc(&self.health_probes, 1);
+                match rtt {
+                    Some(rtt) => inc(&self.health_rtt_us, rtt.as_micros() as u64),
+                    None => inc(&self.health_probe_failures, 1),
+                }
+            }
+            Metric::SocksAccepted => inc(&self.socks_accepted, 1),
+            Metric::SocksClosed { sent, received } => {
+                inc(&self.socks_closed, 1);
+                inc(&self.socks_bytes_sent, sent);
+                inc(&self.socks_bytes_received, received);
+            }
+            Metric::SocksFailed => inc(&self.socks_failed, 1),
+            Metric::PacketSent { bytes } => {
+                inc(&self.packets_sent, 1);
+                inc(&self.tunnel_bytes_sent, bytes as u64);
+            }
+            Metric::PacketReceived { bytes } => {
+                inc(&self.packets_received, 1);
+                inc(&self.tunnel_bytes_received, bytes as u64);
+            }
+            Metric::StageLatency { stage, elapsed } => {
+                self.latency[stage as usize].observe(elapsed.as_micros() as u64)
+            }
+            Metric::QueueDepth { stage, depth } => self.depth[stage as usize].observe(depth as u64),
+            Metric::PacketDropped { stage } => inc(&self.dropped[stage as usize], 1),
+            Metric::PacketRejected { reason } => inc(&self.rejected[reason as usize], 1),
+        }
+    }
+
+    /// Health probes that succeeded and that failed. Not in [`Snapshot`],
+    /// which would then change with every probe of an idle session.
+    pub fn health_probes(&self) -> (u64, u64) {
+        let failed = self.health_probe_failures.load(Ordering::Relaxed);
+        (self.health_probes.load(Ordering::Relaxed) - failed, failed)
+    }
+
+    /// Summed round-trip time of the successful health probes.
+    pub fn health_rtt(&self) -> Duration {
+        Duration::from_micros(self.health_rtt_us.load(Ordering::Relaxed))
+    }
+
+    /// VPN packets refused for `reason`.
+    pub fn rejected(&self, reason: Reject) -> u64 {
+        self.rejected[reason as usize].load(Ordering::Relaxed)
+    }
+
+    /// VPN packets lost at `stage`.
+    pub fn dropped(&self, stage: Stage) -> u64 {
+        self.dropped[stage as usize].load(Ordering::Relaxed)
+    }
+
+    /// Time packets spent in `stage`, in microseconds.
+    pub fn latency(&self, stage: Stage) -> HistogramSnapshot {
+        self.latency[stage as usize].snapshot()
+    }
+
+    /// Packets already queued when one was added to `stage`.
+    pub fn queue_depth(&self, stage: Stage) -> HistogramSnapshot {
+        self.depth[stage as usize].snapshot()
+    }
+
+    pub fn snapshot(&self) -> Snapshot {
+        let get = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        Snapshot {
+            reconnects: get(&self.reconnects),
+            reconnect_failures: get(&self.reconnect_failures),
+            socks_accepted: get(&self.socks_accepted),
+            socks_closed: get(&self.socks_closed),
+            socks_failed: get(&self.socks_failed),
+            socks_bytes_sent: get(&self.socks_bytes_sent),
+            socks_bytes_received: get(&self.socks_bytes_received),
+            packets_sent: get(&self.packets_sent),
+            packets_received: get(&self.packets_received),
+            tunnel_bytes_sent: get(&self.tunnel_bytes_sent),
+            tunnel_bytes_received: get(&self.tunnel_bytes_received),
+            packets_dropped: self.dropped.iter().map(get).sum(),
+            packets_rejected: self.rejected.iter().map(get).sum(),
+        }
+    }
+}
+
+/// Keeps totals and renders them in the Prometheus text exposition format,
+/// for the embedder's own HTTP endpoint or [`serve`](Self::serve).
+#[derive(Default)]
+pub struct PrometheusMetrics {
+    counters: Counters,
+}
+
+impl PrometheusMetrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn snapshot(&self) -> Snapshot {
+        self.counters.snapshot()
+    }
+
+    pub fn counters(&self) -> &Counters {
+        &self.counters
+    }
+
+    pub fn render(&self) -> String {
+        let s = self.snapshot();
+        let (probes_ok, probes_failed) = self.counters.health_probes();
+        let mut out = String::new();
+        let mut family = |name: &str, kind: &str, help: &str, samples: &[(&str, u64)]| {
+            let _ = writeln!(out, "# HELP x2ssh_{name} {help}");
+            let _ = writeln!(out, "# TYPE x2ssh_{name} {kind}");
+            for (labels, value) in samples {
+                let _ = writeln!(out, "x2ssh_{name}{labels} {value}");
+            }
+        };
+
+        family(
+            "reconnects_total",
+            "counter",
+            "SSH sessions re-established after a loss.",
+            &[("", s.reconnects)],
+        );
+        family(
+            "reconnect_failures_total",
+            "counter",
+            "Reconnects that gave up.",
+            &[("", s.reconnect_failures)],
+        );
+        family(
+            "health_probes_total",
+            "counter",
+            "Health probes of the SSH connection by outcome.",
+            &[
+                ("{outcome=\"ok\"}", probes_ok),
+                ("{outcome=\"failed\"}", probes_failed),
+            ],
+        );
+        family(
+            "socks_connections_total",
+            "counter",
+            "SOCKS connections by outcome.",
+            &[
+                ("{outcome=\"closed\"}", s.socks_closed),
+                ("{outcome=\"failed\"}", s.socks_failed),
+            ],
+        );
+        family(
+            "socks_active_connections",
+            "gauge",
+            "SOCKS connections in progress.",
+            &[("", s.socks_active())],
+        );
+        family(
+            "socks_bytes_total",
+            "counter",
+            "Bytes relayed for SOCKS clients.",
+            &[
+                ("{direction=\"sent\"}", s.socks_bytes_sent),
+                ("{direction=\"received\"}", s.socks_bytes_received),
+            ],
+        );
+        family(
+            "tunnel_packets_total",
+            "counter",
+            "VPN packets through the tunnel.",
+            &[
+                ("{direction=\"sent\"}", s.packets_sent),
+                ("{direction=\"received\"}", s.packets_received),
+            ],
+        );
+        family(
+            "tunnel_bytes_total",
+            "counter",
+            "VPN bytes through the tunnel.",
+            &[
+                ("{direction=\"sent\"}", s.tunnel_bytes_sent),
+                ("{direction=\"received\"}", s.tunnel_bytes_received),
+            ],
+        );
+        let dropped: Vec<(String, u64)> = Stage::ALL
+            .into_iter()
+            .map(|stage| {
+                let labels = format!("{{stage=\"{}\"}}", stage.name());
+                (labels, self.counters.dropped(stage))
+            })
+            .collect();
+        let dropped: Vec<(&str, u64)> = dropped
+            .iter()
+            .map(|(labels, n)| (labels.as_str(), *n))
+            .collect();
+        family(
+            "tunnel_packets_dropped_total",
+            "counter",
+            "VPN packets dropped, by the step that dropped them.",
+            &dropped,
+        );
+        let rejected: Vec<(String, u64)> = Reject::ALL
+            .into_iter()
+            .map(|reason| {
+                let labels = format!("{{reason=\"{}\"}}", reason.name());
+                (labels, self.counters.rejected(reason))
+            })
+            .collect();
+        let rejected: Vec<(&str, u64)> = rejected
+            .iter()
+            .map(|(labels, n)| (labels.as_str(), *n))
+            .collect();
+        family(
+            "tunnel_packets_rejected_total",
+            "counter",
+            "VPN packets refused as malformed or by the packet filter.",
+            &rejected,
+        );
+
+        let _ = writeln!(
+            out,
+            "# HELP x2ssh_health_probe_rtt_seconds Round-trip time of successful health probes."
+        );
+        let _ = writeln!(out, "# TYPE x2ssh_health_probe_rtt_seconds summary");
+        let _ = writeln!(
+            out,
+            "x2ssh_health_probe_rtt_seconds_sum {}",
+            self.counters.health_rtt().as_secs_f64()
+        );
+        let _ = writeln!(out, "x2ssh_health_probe_rtt_seconds_count {}", probes_ok);
+        let latency: Vec<_> = Stage::ALL
+            .into_iter()
+            .map(|stage| (stage, self.counters.latency(stage)))
+            .collect();
+        histogram_family(
+            &mut out,
+            "vpn_stage_latency_seconds",
+            "Time VPN packets spent in each step of the forwarding path.",
+            &latency,
+            1e6,
+        );
+        let depth: Vec<_> = Stage::ALL
+            .into_iter()
+            .filter(|stage| stage.is_queue())
+            .map(|stage| (stage, self.counters.queue_depth(stage)))
+            .collect();
+        histogram_family(
+            &mut out,
+            "vpn_queue_depth",
+            "Packets already queued when a VPN packet was added.",
+            &depth,
+            1.0,
+        );
+        out
+    }
+}
+
+impl PrometheusMetrics {
+    /// Answers `GET /metrics` on `listener` with [`render`](Self::render),
+    /// one request per connection, for a Prometheus scraper. Runs until
+    /// dropped.
+    pub async fn serve(self: Arc<Self>, listener: TcpListener) {
+        loop {
+            let (stream, peer) = match listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    debug!("Metrics accept error: {}", e);
+                    continue;
+                }
+            };
+            let metrics = Arc::clone(&self);
+            tokio::spawn(async move {
+                if let Err(e) = metrics.answer(stream).await {
+                    debug!("Metrics request from {} failed: {}", peer, e);
+                }
+            });
+        }
+    }
+
+    async fn answer(&self, mut stream: TcpStream) -> anyhow::Result<()> {
+        let head = read_request_head(&mut stream).await?;
+        let (status, body) = if head.starts_with(b"GET /metrics ") {
+            ("200 OK", self.render())
+        } else {
+            ("404 Not Found", "Not found; try /metrics\n".to_string())
+        };
+        respond(stream, status, "text/plain; version=0.0.4", &body).await
+    }
+}
+
+/// Reads an HTTP request up to the end of its headers, giving up on slow
+/// or oversized ones.
+pub(crate) async fn read_request_head(stream: &mut TcpStream) -> anyhow::Result<Vec<u8>> {
+    let mut head = Vec::new();
+    let mut buf = [0u8; 1024];
+    tokio::time::timeout(SCRAPE_TIMEOUT, async {
+        while !head.windows(4).any(|w| w == b"\r\n\r\n") {
+            let n = stream.read(&mut buf).await?;
+            if n == 0 || head.len() + n > MAX_REQUEST_HEAD {
+                anyhow::bail!("incomplete request");
+            }
+            head.extend_from_slice(&buf[..n]);
+        }
+        Ok(())
+    })
+    .await
+    .map_err(|_| anyhow::anyhow!("request timed out"))??;
+    Ok(head)
+}
+
+/// Writes a whole response and closes the connection.
+pub(crate) async fn respond(
+    mut stream: TcpStream,
+    status: &str,
+    content_type: &str,
+    body: &str,
+) -> anyhow::Result<()> {
+    let response = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\nConnection: \
+         close\r\n\r\n{body}",
+        body.len()
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+/// How long a scraper gets to send its request.
+const SCRAPE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Longest request head read before giving up on a scraper.
+const MAX_REQUEST_HEAD: usize = 8192;
+
+/// Writes one histogram per stage, dividing bucket bounds and sums by
+/// `per_unit` to get the family's unit.
+fn histogram_family(
+    out: &mut String,
+    name: &str,
+    help: &str,
+    stages: &[(Stage, HistogramSnapshot)],
+    per_unit: f64,
+) {
+    let _ = writeln!(out, "# HELP x2ssh_{name} {help}");
+    let _ = writeln!(out, "# TYPE x2ssh_{name} histogram");
+    for (stage, histogram) in stages {
+        let stage = stage.name();
+        let mut cumulative = 0;
+        for (bucket, count) in histogram.counts.iter().enumerate() {
+            cumulative += count;
+            let le = match histogram.bounds.get(bucket) {
+                Some(bound) => (*bound as f64 / per_unit).to_string(),
+                None => "+Inf".to_string(),
+            };
+            let _ = writeln!(
+                out,
+                "x2ssh_{name}_bucket{{stage=\"{stage}\",le=\"{le}\"}} {cumulative}"
+            );
+        }
+        let sum = histogram.sum as f64 / per_unit;
+        let _ = writeln!(out, "x2ssh_{name}_sum{{stage=\"{stage}\"}} {sum}");
+        let _ = writeln!(out, "x2ssh_{name}_count{{stage=\"{stage}\"}} {cumulative}");
+    }
+}
+
+impl MetricsSink for PrometheusMetrics {
+    fn record(&self, metric: Metric) {
+        self.counters.add(metric);
+    }
+}
+
+/// Keeps totals and writes a one-line summary to the log, periodically via
+/// [`run`](Self::run) and on demand via [`log`](Self::log).
+#[derive(Default)]
+pub struct LogSummaryMetrics {
+    counters: Counters,
+}
+
+impl LogSummaryMetrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn snapshot(&self) -> Snapshot {
+        self.counters.snapshot()
+    }
+
+    pub fn summary(&self) -> String {
+        let s = self.snapshot();
+        format!(
+            "reconnects {} (failed {}), socks {} active / {} closed / {} failed, {} sent / {} \
+             received, tunnel {} packets {} bytes sent / {} packets {} bytes received / {} \
+             dropped / {} rejected",
+            s.reconnects,
+            s.reconnect_failures,
+            s.socks_active(),
+            s.socks_closed,
+            s.socks_failed,
+            s.socks_bytes_sent,
+            s.socks_bytes_received,
+            s.packets_sent,
+            s.tunnel_bytes_sent,
+            s.packets_received,
+            s.tunnel_bytes_received,
+            s.packets_dropped,
+            s.packets_rejected,
+        )
+    }
+
+    /// Median and 99th percentile time per forwarding step, for the steps
+    /// packets went through; empty outside VPN mode.
+    pub fn latency_summary(&self) -> String {
+        let stages: Vec<String> = Stage::ALL
+            .into_iter()
+            .filter_map(|stage| {
+                let latency = self.counters.latency(stage);
+                let p50 = latency.quantile(0.5);
+                let p99 = latency.quantile(0.99);
+                (latency.count() > 0)
+                    .then(|| format!("{} {}/{}", stage.name(), micros(p50), micros(p99)))
+            })
+            .collect();
+        stages.join(", ")
+    }
+
+    pub fn log(&self) {
+        info!("Metrics: {}", self.summary());
+        let latency = self.latency_summary();
+        if !latency.is_empty() {
+            info!("VPN latency p50/p99: {}", latency);
+        }
+    }
+
+    /// Logs the summary every `interval`, skipping intervals in which
+    /// nothing changed. Runs until dropped.
+    pub async fn run(&self, interval: Duration) {
+        let mut ticker = tokio::time::interval(interval);
+        ticker.tick().await;
+        let mut last = self.snapshot();
+        loop {
+            ticker.tick().await;
+            let now = self.snapshot();
+            if now != last {
+                self.log();
+                last = now;
+            }
+        }
+    }
+}
+
+/// A bucket bound in microseconds for the log; `None` is the overflow
+/// bucket.
+fn micros(bound: Option<u64>) -> String {
+    match bound {
+        Some(us) if us >= 1000 => format!("<={}ms", us as f64 / 1000.0),
+        Some(us) => format!("<={}us", us),
+        None => format!(
+            ">{}s",
+            LATENCY_BOUNDS_US[LATENCY_BOUNDS_US.len() - 1] / 1_000_000
+        ),
+    }
+}
+
+impl MetricsSink for LogSummaryMetrics {
+    fn record(&self, metric: Metric) {
+        self.counters.add(metric);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_counters() {
+        let counters = Counters::default();
+        counters.add(Metric::SocksAccepted);
+        counters.add(Metric::SocksAccepted);
+        counters.add(Metric::SocksAccepted);
+        counters.add(Metric::SocksClosed {
+            sent: 100,
+            received: 2000,
+        });
+        counters.add(Metric::SocksFailed);
+        counters.add(Metric::PacketSent { bytes: 60 });
+        counters.add(Metric::PacketSent { bytes: 1400 });
+        counters.add(Metric::Reconnected);
+
+        let s = counters.snapshot();
+        assert_eq!(s.socks_active(), 1);
+        assert_eq!(s.socks_bytes_sent, 100);
+        assert_eq!(s.socks_bytes_received, 2000);
+        assert_eq!(s.packets_sent, 2);
+        assert_eq!(s.tunnel_bytes_sent, 1460);
+        assert_eq!(s.packets_received, 0);
+        assert_eq!(s.reconnects, 1);
+    }
+
+    #[test]
+    fn test_prometheus_render() {
+        let metrics = PrometheusMetrics::new();
+        metrics.record(Metric::SocksAccepted);
+        metrics.record(Metric::PacketReceived { bytes: 84 });
+        metrics.record(Metric::HealthProbe {
+            rtt: Some(Duration::from_millis(40)),
+        });
+        metrics.record(Metric::HealthProbe { rtt: None });
+
+        let text = metrics.render();
+        assert!(text.contains("# TYPE x2ssh_reconnects_total counter\nx2ssh_reconnects_total 0\n"));
+        assert!(text.contains("x2ssh_socks_active_connections 1\n"));
+        assert!(text.contains("x2ssh_tunnel_packets_total{direction=\"received\"} 1\n"));
+        assert!(text.contains("x2ssh_tunnel_bytes_total{direction=\"received\"} 84\n"));
+        assert!(text.contains("x2ssh_health_probes_total{outcome=\"failed\"} 1\n"));
+        assert!(text.contains("x2ssh_health_probe_rtt_seconds_sum 0.04\n"));
+        // Every sample line belongs to a declared family.
+        for line in text.lines().filter(|line| !line.starts_with('#')) {
+            let name = line.split(['{', ' ']).next().unwrap();
+            let family = ["_bucket", "_sum", "_count"]
+                .iter()
+                .find_map(|suffix| name.strip_suffix(suffix))
+                .filter(|family| {
+                    text.contains(&format!("# TYPE {family} histogram"))
+                        || text.contains(&format!("# TYPE {family} summary"))
+                })
+                .unwrap_or(name);
+            assert!(text.contains(&format!("# TYPE {family} ")), "{line}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_prometheus_serve() {
+        let metrics = Arc::new(PrometheusMetrics::new());
+        let fanout = FanoutMetrics(vec![metrics.clone(), Arc::new(LogSummaryMetrics::new())]);
+        fanout.record(Metric::PacketSent { bytes: 60 });
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(metrics.serve(listener));
+
+        let get = |path: &'static str| async move {
+            let mut stream = TcpStream::connect(addr).await.unwrap();
+            stream
+                .write_all(format!("GET {path} HTTP/1.1\r\nHost: x\r\n\r\n").as_bytes())
+                .await
+                .unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).await.unwrap();
+            response
+        };
+        let response = get("/metrics").await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{response}");
+        assert!(response.contains("\r\n\r\n# HELP x2ssh_reconnects_total"));
+        assert!(response.contains("x2ssh_tunnel_bytes_total{direction=\"sent\"} 60\n"));
+        assert!(get("/").await.starts_with("HTTP/1.1 404 Not Found\r\n"));
+    }
+
+    #[test]
+    fn test_stage_histograms() {
+        let metrics = PrometheusMetrics::new();
+        for us in [40, 80, 90, 3000] {
+            metrics.record(Metric::StageLatency {
+                stage: Stage::SshWrite,
+                elapsed: Duration::from_micros(us),
+            });
+        }
+        metrics.record(Metric::StageLatency {
+            stage: Stage::TunWrite,
+            elapsed: Duration::from_secs(5),
+        });
+        metrics.record(Metric::QueueDepth {
+            stage: Stage::OutboundQueue,
+            depth: 3,
+        });
+        metrics.record(Metric::PacketDropped {
+            stage: Stage::InboundQueue,
+        });
+        metrics.record(Metric::PacketRejected {
+            reason: Reject::Malformed,
+        });
+
+        let ssh = metrics.counters().latency(Stage::SshWrite);
+        assert_eq!(ssh.count(), 4);
+        assert_eq!(ssh.sum, 3210);
+        assert_eq!(ssh.quantile(0.5), Some(100));
+        assert_eq!(ssh.quantile(0.99), Some(5_000));
+        assert_eq!(
+            metrics.counters().latency(Stage::TunWrite).quantile(0.5),
+            None
+        );
+        assert_eq!(metrics.counters().latency(Stage::InboundQueue).count(), 0);
+
+        let text = metrics.render();
+        assert!(text.contains(
+            "x2ssh_vpn_stage_latency_seconds_bucket{stage=\"ssh_write\",le=\"0.0001\"} 3\n"
+        ));
+        assert!(text.contains("x2ssh_vpn_stage_latency_seconds_count{stage=\"ssh_write\"} 4\n"));
+        assert!(
+            text.contains("x2ssh_vpn_queue_depth_bucket{stage=\"outbound_queue\",le=\"4\"} 1\n")
+        );
+        assert!(!text.contains("x2ssh_vpn_queue_depth_count{stage=\"ssh_write\"}"));
+        assert!(text.contains("x2ssh_tunnel_packets_dropped_total{stage=\"inbound_queue\"} 1\n"));
+        assert!(text.contains("x2ssh_tunnel_packets_dropped_total{stage=\"tun_write\"} 0\n"));
+        assert!(text.contains("x2ssh_tunnel_packets_rejected_total{reason=\"malformed\"} 1\n"));
+        assert!(text.contains("x2ssh_tunnel_packets_rejected_total{reason=\"filtered\"} 0\n"));
+        assert_eq!(metrics.snapshot().packets_dropped, 1);
+        assert_eq!(metrics.snapshot().packets_rejected, 1);
+
+        let summary = LogSummaryMetrics::new();
+        summary.record(Metric::StageLatency {
+            stage: Stage::InboundQueue,
+            elapsed: Duration::from_micros(700),
+        });
+        assert_eq!(summary.latency_summary(), "inbound_queue <=1ms/<=1ms");
+    }
+}
@@ -0,0 +1,499 @@
+//! Spans of SOCKS connections and SSH reconnects, exported to an
+//! OpenTelemetry collector over OTLP/HTTP in its JSON encoding.
+//!
+//! Only what x2ssh itself times is traced: a SOCKS connection's handshake,
+//! channel open and transfer, and each reconnect with its attempts. Spans
+//! are batched and posted every few seconds; when the collector cannot keep
+//! up they are dropped rather than queued without bound.
+
+use std::collections::hash_map::RandomState;
+use std::fmt;
+use std::hash::BuildHasher;
+use std::hash::Hasher;
+use std::sync::Arc;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+use serde_json::Value;
+use serde_json::json;
+use tokio::io::AsyncReadExt;
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+use tracing::info;
+use tracing::warn;
+
+/// Where spans go and which of them are kept.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TraceOptions {
+    pub endpoint: Endpoint,
+    /// Share of traces kept, 0 to 1; the decision is made once per trace,
+    /// so a kept trace has all its spans.
+    pub sample_ratio: f64,
+    /// `service.name` of the exported resource.
+    pub service_name: String,
+}
+
+/// An OTLP/HTTP traces endpoint, `http://HOST:PORT/PATH`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Endpoint {
+    pub host: String,
+    pub port: u16,
+    pub path: String,
+}
+
+impl std::str::FromStr for Endpoint {
+    type Err = anyhow::Error;
+
+    /// Parses a collector URL; the port defaults to 4318 and an empty path
+    /// to `/v1/traces`, as for `OTEL_EXPORTER_OTLP_ENDPOINT`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let rest = match s.split_once("://") {
+            Some(("http", rest)) => rest,
+            Some((scheme, _)) => anyhow::bail!(
+                "unsupported scheme {:?} in {}; only http:// is, so export to a local collector",
+                scheme,
+                s
+            ),
+            None => s,
+        };
+        let (authority, path) = match rest.find('/') {
+            Some(slash) => rest.split_at(slash),
+            None => (rest, ""),
+        };
+        let (host, port) = match authority.rsplit_once(':') {
+            Some((host, port)) if !port.contains(']') => (
+                host,
+                port.parse()
+                    .map_err(|_| anyhow::anyhow!("invalid port {:?} in {}", port, s))?,
+            ),
+            _ => (authority, 4318),
+        };
+        let host = host.trim_matches(['[', ']']);
+        if host.is_empty() {
+            anyhow::bail!("no host in {}", s);
+        }
+        let path = match path.trim_end_matches('/') {
+            "" => "/v1/traces",
+            path => path,
+        };
+        Ok(Self {
+            host: host.to_string(),
+            port,
+            path: path.to_string(),
+        })
+    }
+}
+
+impl fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.host.contains(':') {
+            write!(f, "http://[{}]:{}{}", self.host, self.port, self.path)
+        } else {
+            write!(f, "http://{}:{}{}", self.host, self.port, self.path)
+        }
+    }
+}
+
+/// What a span stands for, as OTLP's `SpanKind`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SpanKind {
+    Internal = 1,
+    /// Serving a client, as for a SOCKS connection.
+    Server = 2,
+}
+
+/// Starts spans and hands finished ones to the exporter. The default one
+/// records nothing.
+#[derive(Clone, Default)]
+pub struct Tracer(Option<Arc<Exporter>>);
+
+struct Exporter {
+    sample_ratio: f64,
+    spans: mpsc::Sender<SpanData>,
+    dropped: AtomicU64,
+}
+
+/// Finished spans waiting to be exported before new ones are dropped.
+const QUEUE_SIZE: usize = 4096;
+
+/// Most spans posted in one request.
+const MAX_BATCH: usize = 512;
+
+/// How long a span waits for others to share its request.
+const EXPORT_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How long a collector gets to take a batch.
+const EXPORT_TIMEOUT: Duration = Duration::from_secs(10);
+
+impl Tracer {
+    /// Exports to `options.endpoint` from a background task. Must be called
+    /// within a Tokio runtime.
+    pub fn start(options: TraceOptions) -> Self {
+        let (tx, rx) = mpsc::channel(QUEUE_SIZE);
+        info!(
+            "Exporting traces to {} (sampling {})",
+            options.endpoint, options.sample_ratio
+        );
+        tokio::spawn(export(rx, options.endpoint, options.service_name));
+        Self(Some(Arc::new(Exporter {
+            sample_ratio: options.sample_ratio,
+            spans: tx,
+            dropped: AtomicU64::new(0),
+        })))
+    }
+
+    /// Starts a trace, or an inert span when tracing is off or the trace is
+    /// not sampled.
+    pub fn root(&self, name: &'static str, kind: SpanKind) -> Span {
+        let Some(exporter) = &self.0 else {
+            return Span(None);
+        };
+        if !sampled(exporter.sample_ratio, random_u64()) {
+            return Span(None);
+        }
+        let trace_id = (u128::from(random_u64()) << 64) | u128::from(random_u64());
+        Span::open(exporter.clone(), trace_id, None, name, kind)
+    }
+}
+
+fn sampled(ratio: f64, roll: u64) -> bool {
+    ratio >= 1.0 || (roll as f64) < ratio * u64::MAX as f64
+}
+
+fn random_u64() -> u64 {
+    RandomState::new().build_hasher().finish()
+}
+
+/// A timed operation, exported when dropped. Inert spans, and their
+/// children, cost next to nothing.
+pub struct Span(Option<Box<SpanData>>);
+
+struct SpanData {
+    exporter: Arc<Exporter>,
+    trace_id: u128,
+    span_id: u64,
+    parent: Option<u64>,
+    name: &'static str,
+    kind: SpanKind,
+    start: SystemTime,
+    end: SystemTime,
+    attributes: Vec<(&'static str, Attribute)>,
+    error: Option<String>,
+}
+
+/// A span attribute value.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Attribute {
+    String(String),
+    Int(i64),
+}
+
+impl From<&str> for Attribute {
+    fn from(value: &str) -> Self {
+        Attribute::String(value.to_string())
+    }
+}
+
+impl From<String> for Attribute {
+    fn from(value: String) -> Self {
+        Attribute::String(value)
+    }
+}
+
+impl From<u64> for Attribute {
+    fn from(value: u64) -> Self {
+        Attribute::Int(value.try_into().unwrap_or(i64::MAX))
+    }
+}
+
+impl From<u32> for Attribute {
+    fn from(value: u32) -> Self {
+        Attribute::Int(value.into())
+    }
+}
+
+impl Span {
+    fn open(
+        exporter: Arc<Exporter>,
+        trace_id: u128,
+        parent: Option<u64>,
+        name: &'static str,
+        kind: SpanKind,
+    ) -> Self {
+        let now = SystemTime::now();
+        Self(Some(Box::new(SpanData {
+            exporter,
+            trace_id,
+            span_id: random_u64(),
+            parent,
+            name,
+            kind,
+            start: now,
+            end: now,
+            attributes: Vec::new(),
+            error: None,
+        })))
+    }
+
+    /// Starts a span within this one.
+    pub fn child(&self, name: &'static str) -> Span {
+        match &self.0 {
+            Some(data) => Span::open(
+                data.exporter.clone(),
+                data.trace_id,
+                Some(data.span_id),
+                name,
+                SpanKind::Internal,
+            ),
+            None => Span(None),
+        }
+    }
+
+    pub fn set(&mut self, key: &'static str, value: impl Into<Attribute>) {
+        if let Some(data) = &mut self.0 {
+            data.attributes.push((key, value.into()));
+        }
+    }
+
+    /// Marks the span as failed with `error`.
+    pub fn fail(&mut self, error: impl fmt::Display) {
+        if let Some(data) = &mut self.0 {
+            data.error = Some(error.to_string());
+        }
+    }
+
+    /// Ends the span now, as dropping it does.
+    pub fn end(self) {}
+}
+
+impl Drop for Span {
+    fn drop(&mut self) {
+        let Some(mut data) = self.0.take() else {
+            return;
+        };
+        data.end = SystemTime::now();
+        let exporter = data.exporter.clone();
+        if exporter.spans.try_send(*data).is_err()
+            && exporter.dropped.fetch_add(1, Ordering::Relaxed) == 0
+        {
+            warn!("Trace export is falling behind; dropping spans");
+        }
+    }
+}
+
+/// Posts finished spans in batches until every [`Tracer`] is gone.
+async fn export(mut spans: mpsc::Receiver<SpanData>, endpoint: Endpoint, service_name: String) {
+    let mut failing = false;
+    let mut batch = Vec::new();
+    while let Some(span) = spans.recv().await {
+        batch.push(span);
+        let deadline = tokio::time::sleep(EXPORT_INTERVAL);
+        tokio::pin!(deadline);
+        while batch.len() < MAX_BATCH {
+            tokio::select! {
+                span = spans.recv() => match span {
+                    Some(span) => batch.push(span),
+                    None => break,
+                },
+                _ = &mut deadline => break,
+            }
+        }
+        let body = request_body(&service_name, &batch).to_string();
+        match post(&endpoint, &body).await {
+            Ok(()) if failing => {
+                info!("Exporting traces to {} again", endpoint);
+                failing = false;
+            }
+            Ok(()) => {}
+            Err(e) if !failing => {
+                warn!("Cannot export traces to {}: {}", endpoint, e);
+                failing = true;
+            }
+            Err(_) => {}
+        }
+        batch.clear();
+    }
+}
+
+/// Sends one OTLP/HTTP request over a fresh connection.
+async fn post(endpoint: &Endpoint, body: &str) -> anyhow::Result<()> {
+    tokio::time::timeout(EXPORT_TIMEOUT, async {
+        let mut stream = TcpStream::connect((endpoint.host.as_str(), endpoint.port)).await?;
+        let authority = if endpoint.host.contains(':') {
+            format!("[{}]:{}", endpoint.host, endpoint.port)
+        } else {
+            format!("{}:{}", endpoint.host, endpoint.port)
+        };
+        let request = format!(
+            "POST {} HTTP/1.1\r\nHost: {authority}\r\nContent-Type: \
+             application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+            endpoint.path,
+            body.len()
+        );
+        stream.write_all(request.as_bytes()).await?;
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).await?;
+        let status = String::from_utf8_lossy(&response)
+            .lines()
+            .next()
+            .unwrap_or_default()
+            .to_string();
+        match status.split(' ').nth(1) {
+            Some(code) if code.starts_with('2') => Ok(()),
+            _ => anyhow::bail!("collector answered {:?}", status),
+        }
+    })
+    .await
+    .map_err(|_| anyhow::anyhow!("timed out"))?
+}
+
+/// An `ExportTraceServiceRequest` holding `spans`.
+fn request_body(service_name: &str, spans: &[SpanData]) -> Value {
+    let spans: Vec<Value> = spans.iter().map(span_json).collect();
+    json!({
+        "resourceSpans": [{
+            "resource": {
+                "attributes": [attribute_json("service.name", &service_name.into())],
+            },
+            "scopeSpans": [{
+                "scope": { "name": "x2ssh", "version": env!("CARGO_PKG_VERSION") },
+                "spans": spans,
+            }],
+        }],
+    })
+}
+
+fn span_json(span: &SpanData) -> Value {
+    let mut value = json!({
+        "traceId": format!("{:032x}", span.trace_id),
+        "spanId": format!("{:016x}", span.span_id),
+        "name": span.name,
+        "kind": span.kind as u8,
+        "startTimeUnixNano": unix_nanos(span.start),
+        "endTimeUnixNano": unix_nanos(span.end),
+        "attributes": span
+            .attributes
+            .iter()
+            .map(|(key, value)| attribute_json(key, value))
+            .collect::<Vec<_>>(),
+        "status": match &span.error {
+            Some(message) => json!({ "code": 2, "message": message }),
+            None => json!({ "code": 1 }),
+        },
+    });
+    if let Some(parent) = span.parent {
+        value["parentSpanId"] = format!("{parent:016x}").into();
+    }
+    value
+}
+
+fn attribute_json(key: &str, value: &Attribute) -> Value {
+    // 64-bit integers are strings in OTLP's JSON encoding.
+    let value = match value {
+        Attribute::String(s) => json!({ "stringValue": s }),
+        Attribute::Int(i) => json!({ "intValue": i.to_string() }),
+    };
+    json!({ "key": key, "value": value })
+}
+
+fn unix_nanos(time: SystemTime) -> String {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos()
+        .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    #[test]
+    fn test_endpoint_parse() {
+        let endpoint: Endpoint = "http://collector:4318".parse().unwrap();
+        assert_eq!(endpoint.host, "collector");
+        assert_eq!(endpoint.port, 4318);
+        assert_eq!(endpoint.path, "/v1/traces");
+
+        let endpoint: Endpoint = "[::1]:9000/otlp/v1/traces".parse().unwrap();
+        assert_eq!(endpoint.host, "::1");
+        assert_eq!(endpoint.port, 9000);
+        assert_eq!(endpoint.path, "/otlp/v1/traces");
+        assert_eq!(endpoint.to_string(), "http://[::1]:9000/otlp/v1/traces");
+
+        assert_eq!("localhost".parse::<Endpoint>().unwrap().port, 4318);
+        assert!("https://collector:4318".parse::<Endpoint>().is_err());
+        assert!("http://collector:x".parse::<Endpoint>().is_err());
+    }
+
+    #[test]
+    fn test_sampling() {
+        assert!(sampled(1.0, u64::MAX));
+        assert!(!sampled(0.0, 0));
+        assert!(sampled(0.5, u64::MAX / 4));
+        assert!(!sampled(0.5, u64::MAX / 4 * 3));
+        assert!(
+            Tracer::default()
+                .root("idle", SpanKind::Internal)
+                .0
+                .is_none()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_export() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let tracer = Tracer::start(TraceOptions {
+            endpoint: format!("http://127.0.0.1:{port}").parse().unwrap(),
+            sample_ratio: 1.0,
+            service_name: "test".to_string(),
+        });
+
+        let mut root = tracer.root("socks.connection", SpanKind::Server);
+        root.set("socks.destination", "example.com:443");
+        let mut child = root.child("ssh.channel_open");
+        child.fail("refused");
+        child.end();
+        root.set("socks.bytes_sent", 12u64);
+        root.end();
+
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut request = Vec::new();
+        let mut buf = [0u8; 4096];
+        let body = loop {
+            let n = stream.read(&mut buf).await.unwrap();
+            request.extend_from_slice(&buf[..n]);
+            let text = String::from_utf8_lossy(&request);
+            if let Some((_, body)) = text.split_once("\r\n\r\n")
+                && serde_json::from_str::<Value>(body).is_ok()
+            {
+                break serde_json::from_str::<Value>(body).unwrap();
+            }
+        };
+        assert!(request.starts_with(b"POST /v1/traces HTTP/1.1\r\n"));
+        stream
+            .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n")
+            .await
+            .unwrap();
+
+        let spans = &body["resourceSpans"][0]["scopeSpans"][0]["spans"];
+        let (child, root) = (&spans[0], &spans[1]);
+        assert_eq!(child["name"], "ssh.channel_open");
+        assert_eq!(child["status"]["code"], 2);
+        assert_eq!(child["parentSpanId"], root["spanId"]);
+        assert_eq!(child["traceId"], root["traceId"]);
+        assert_eq!(root["kind"], 2);
+        assert!(root.get("parentSpanId").is_none());
+        assert_eq!(root["attributes"][1]["value"]["intValue"], "12");
+        assert_eq!(
+            body["resourceSpans"][0]["resource"]["attributes"][0]["value"]["stringValue"],
+            "test"
+        );
+    }
+}
@@ -0,0 +1,222 @@
+//! Telling services that depend on the tunnel that it is up: the
+//! `--ready-command` and systemd's `READY=1`, both sent only once traffic
+//! has been seen to flow end to end.
+
+use std::future::Future;
+use std::net::IpAddr;
+use std::net::Ipv4Addr;
+use std::net::Ipv6Addr;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use tokio::io::AsyncReadExt;
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+use tracing::debug;
+use tracing::info;
+use tracing::warn;
+
+/// How long a single check may take before it counts as failed.
+const CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Pause between failed checks.
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// What to do once the tunnel works.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Readiness {
+    /// Run through the local shell before readiness is reported to systemd.
+    pub command: Option<String>,
+    /// `HOST:PORT` that has to accept a connection through the tunnel
+    /// first. Without it, the check only covers what x2ssh itself runs.
+    pub target: Option<String>,
+    /// Whether `/readyz` reports the check, so it runs even with nothing
+    /// else waiting for it.
+    pub probed: bool,
+}
+
+impl Readiness {
+    /// Whether anyone is waiting to hear about readiness.
+    fn wanted(&self) -> bool {
+        self.command.is_some() || self.probed || std::env::var_os("NOTIFY_SOCKET").is_some()
+    }
+
+    /// Retries `check` until it succeeds, then runs the ready command and
+    /// notifies systemd. Returns at once when neither is asked for.
+    pub async fn signal_when<F, Fut>(&self, mut check: F)
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = anyhow::Result<()>>,
+    {
+        if !self.wanted() {
+            return;
+        }
+
+        let mut failures = 0u32;
+        loop {
+            match tokio::time::timeout(CHECK_TIMEOUT, check()).await {
+                Ok(Ok(())) => break,
+                Ok(Err(e)) if failures == 0 => warn!("Tunnel not ready yet: {:#}", e),
+                Err(_) if failures == 0 => warn!("Tunnel not ready yet: check timed out"),
+                Ok(Err(e)) => debug!("Tunnel not ready yet: {:#}", e),
+                Err(_) => debug!("Tunnel not ready yet: check timed out"),
+            }
+            failures += 1;
+            tokio::time::sleep(CHECK_INTERVAL).await;
+        }
+        info!("Tunnel verified end to end");
+
+        if let Some(command) = &self.command {
+            match run_command(command).await {
+                Ok(()) => info!("Ready command finished"),
+                Err(e) => warn!("Ready command failed: {}", e),
+            }
+        }
+        crate::systemd::ready();
+    }
+
+    /// Checks a SOCKS proxy listening on `proxy`, through the proxy itself.
+    pub async fn check_socks(&self, proxy: SocketAddr) -> anyhow::Result<()> {
+        check_socks(proxy, self.target.as_deref()).await
+    }
+
+    /// Checks a VPN whose agent has already answered its handshake, so only
+    /// the target (routed like any other traffic) is left to try.
+    pub async fn check_vpn(&self) -> anyhow::Result<()> {
+        if let Some(target) = &self.target {
+            TcpStream::connect(target.as_str()).await?;
+        }
+        Ok(())
+    }
+}
+
+/// Greets the proxy on `proxy` and, with a `target`, has it connect there.
+async fn check_socks(proxy: SocketAddr, target: Option<&str>) -> anyhow::Result<()> {
+    let mut stream = TcpStream::connect(reachable(proxy)).await?;
+    stream.write_all(&[5, 1, 0]).await?;
+    let mut choice = [0u8; 2];
+    stream.read_exact(&mut choice).await?;
+    if choice != [5, 0] {
+        anyhow::bail!("proxy refused the greeting: {:?}", choice);
+    }
+    let Some(target) = target else {
+        return Ok(());
+    };
+
+    let (host, port) = target
+        .rsplit_once(':')
+        .ok_or_else(|| anyhow::anyhow!("ready target '{}' is not HOST:PORT", target))?;
+    let port: u16 = port
+        .parse()
+        .map_err(|_| anyhow::anyhow!("invalid port in ready target '{}'", target))?;
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let host_len = u8::try_from(host.len())
+        .map_err(|_| anyhow::anyhow!("ready target host '{}' is too long", host))?;
+
+    let mut request = vec![5, 1, 0, 3, host_len];
+    request.extend_from_slice(host.as_bytes());
+    request.extend_from_slice(&port.to_be_bytes());
+    stream.write_all(&request).await?;
+
+    let mut reply = [0u8; 2];
+    stream.read_exact(&mut reply).await?;
+    if reply[1] != 0 {
+        anyhow::bail!("proxy could not reach {} (reply {})", target, reply[1]);
+    }
+    Ok(())
+}
+
+/// The address to connect to for a listener bound to `addr`; a wildcard
+/// bind is reached through loopback.
+fn reachable(addr: SocketAddr) -> SocketAddr {
+    match addr.ip() {
+        IpAddr::V4(ip) if ip.is_unspecified() => (Ipv4Addr::LOCALHOST, addr.port()).into(),
+        IpAddr::V6(ip) if ip.is_unspecified() => (Ipv6Addr::LOCALHOST, addr.port()).into(),
+        _ => addr,
+    }
+}
+
+async fn run_command(command: &str) -> anyhow::Result<()> {
+    info!("Running ready command: {}", command);
+    #[cfg(unix)]
+    let status = tokio::process::Command::new("sh")
+        .args(["-c", command])
+        .status()
+        .await?;
+    #[cfg(windows)]
+    let status = tokio::process::Command::new("cmd")
+        .args(["/C", command])
+        .status()
+        .await?;
+    if !status.success() {
+        anyhow::bail!("'{}' exited with {}", command, status);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    /// Answers one SOCKS5 greeting and CONNECT, recording the request.
+    async fn fake_proxy(reply: u8) -> (SocketAddr, tokio::task::JoinHandle<Vec<u8>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut greeting = [0u8; 3];
+            stream.read_exact(&mut greeting).await.unwrap();
+            stream.write_all(&[5, 0]).await.unwrap();
+            let mut request = Vec::new();
+            let mut head = [0u8; 5];
+            if stream.read_exact(&mut head).await.is_ok() {
+                request.extend_from_slice(&head);
+                let mut rest = vec![0u8; usize::from(head[4]) + 2];
+                stream.read_exact(&mut rest).await.unwrap();
+                request.extend_from_slice(&rest);
+                stream
+                    .write_all(&[5, reply, 0, 1, 0, 0, 0, 0, 0, 0])
+                    .await
+                    .unwrap();
+            }
+            request
+        });
+        (addr, server)
+    }
+
+    #[tokio::test]
+    async fn test_check_socks() {
+        let (addr, server) = fake_proxy(0).await;
+        check_socks(addr, Some("example.com:443")).await.unwrap();
+        let request = server.await.unwrap();
+        assert_eq!(&request[..5], &[5, 1, 0, 3, 11]);
+        assert_eq!(&request[5..16], b"example.com");
+        assert_eq!(&request[16..], &443u16.to_be_bytes());
+
+        let (addr, _server) = fake_proxy(5).await;
+        let error = check_socks(addr, Some("[::1]:22")).await.unwrap_err();
+        assert!(error.to_string().contains("reply 5"), "{error}");
+
+        let (addr, server) = fake_proxy(0).await;
+        check_socks(addr, None).await.unwrap();
+        drop(server);
+    }
+
+    #[test]
+    fn test_reachable() {
+        assert_eq!(
+            reachable("0.0.0.0:1080".parse().unwrap()),
+            "127.0.0.1:1080".parse().unwrap()
+        );
+        assert_eq!(
+            reachable("[::]:1080".parse().unwrap()),
+            "[::1]:1080".parse().unwrap()
+        );
+        assert_eq!(
+            reachable("10.0.0.1:1080".parse().unwrap()),
+            "10.0.0.1:1080".parse().unwrap()
+        );
+    }
+}
@@ -1,11 +1,18 @@
+use std::collections::VecDeque;
+use std::fmt;
 use std::time::Duration;
 
+use serde::Deserialize;
+use serde::Serialize;
+
 #[derive(Clone, Debug)]
 pub struct RetryPolicy {
     pub max_attempts: Option<u32>,
     pub initial_delay: Duration,
     pub backoff: f64,
     pub max_delay: Duration,
+    /// How long reconnecting may take in all, whatever the attempt count.
+    pub max_total_duration: Option<Duration>,
 }
 
 impl Default for RetryPolicy {
@@ -15,6 +22,7 @@ impl Default for RetryPolicy {
             initial_delay: Duration::from_millis(1000),
             backoff: 2.0,
             max_delay: Duration::from_millis(30000),
+            max_total_duration: None,
         }
     }
 }
@@ -32,6 +40,300 @@ impl RetryPolicy {
             None => true,
         }
     }
+
+    /// Whether, `elapsed` after the first failure, waiting out the delay
+    /// before `attempt` would end past [`Self::max_total_duration`].
+    pub fn past_deadline(&self, elapsed: Duration, attempt: u32) -> bool {
+        self.max_total_duration
+            .is_some_and(|max| elapsed + self.delay_for_attempt(attempt) > max)
+    }
+
+    /// [`Self::should_retry`] for a failure of `class`; never for one that
+    /// another attempt cannot fix.
+    pub fn should_retry_after(&self, attempt: u32, class: ErrorClass) -> bool {
+        class.is_retryable() && self.should_retry(attempt)
+    }
+}
+
+/// Reconnecting stopped for good, and with it the session. Attached as
+/// context to the last attempt's error.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GaveUp {
+    pub attempts: u32,
+    pub elapsed: Duration,
+}
+
+impl GaveUp {
+    /// Exit status of a session that ended this way, `EX_TEMPFAIL`, so
+    /// service managers and scripts can tell it from a configuration error.
+    pub const EXIT_CODE: i32 = 75;
+}
+
+impl fmt::Display for GaveUp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "gave up reconnecting after {} attempt(s) in {}",
+            self.attempts,
+            crate::status::format_elapsed(self.elapsed)
+        )
+    }
+}
+
+/// What kind of failure a connection attempt ran into, which decides
+/// whether trying again can help.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorClass {
+    /// The key, its passphrase, the server's host key or the login itself;
+    /// wrong until someone changes the configuration.
+    Auth,
+    /// The server's host name did not resolve.
+    Dns,
+    /// The server or the network stopped answering.
+    Timeout,
+    /// Anything else: refused or reset connections, unreachable networks,
+    /// broken SSH sessions.
+    Network,
+}
+
+impl ErrorClass {
+    /// Whether another attempt may succeed without a configuration change.
+    pub fn is_retryable(self) -> bool {
+        self != ErrorClass::Auth
+    }
+
+    /// Marks `error` as being of this class, leaving its message as is.
+    pub fn wrap(self, error: impl Into<anyhow::Error>) -> anyhow::Error {
+        anyhow::Error::new(Classified {
+            class: self,
+            error: error.into(),
+        })
+    }
+
+    /// The class of `error`: the one it was [wrapped](Self::wrap) in, else
+    /// guessed from the SSH and I/O errors in its chain.
+    pub fn of(error: &anyhow::Error) -> Self {
+        for cause in error.chain() {
+            if let Some(classified) = cause.downcast_ref::<Classified>() {
+                return classified.class;
+            }
+            if let Some(error) = cause.downcast_ref::<russh::Error>() {
+                match error {
+                    russh::Error::UnknownKey
+                    | russh::Error::KeyChanged { .. }
+                    | russh::Error::WrongServerSig
+                    | russh::Error::NotAuthenticated
+                    | russh::Error::NoAuthMethod
+                    | russh::Error::UnsupportedAuthMethod
+                    | russh::Error::CouldNotReadKey
+                    | russh::Error::Keys(_) => return ErrorClass::Auth,
+                    russh::Error::ConnectionTimeout
+                    | russh::Error::KeepaliveTimeout
+                    | russh::Error::InactivityTimeout
+                    | russh::Error::Elapsed(_) => return ErrorClass::Timeout,
+                    _ => {}
+                }
+            }
+            if cause.is::<russh::keys::Error>() {
+                return ErrorClass::Auth;
+            }
+            if let Some(error) = cause.downcast_ref::<std::io::Error>()
+                && error.kind() == std::io::ErrorKind::TimedOut
+            {
+                return ErrorClass::Timeout;
+            }
+            if cause.is::<tokio::time::error::Elapsed>() {
+                return ErrorClass::Timeout;
+            }
+        }
+        ErrorClass::Network
+    }
+}
+
+impl fmt::Display for ErrorClass {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Auth => "authentication",
+            Self::Dns => "DNS",
+            Self::Timeout => "timeout",
+            Self::Network => "network",
+        })
+    }
+}
+
+/// An error with its [`ErrorClass`], transparent otherwise: it displays as
+/// the wrapped error and has the same sources.
+#[derive(Debug)]
+struct Classified {
+    class: ErrorClass,
+    error: anyhow::Error,
+}
+
+impl fmt::Display for Classified {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&self.error, f)
+    }
+}
+
+impl std::error::Error for Classified {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        self.error.source()
+    }
+}
+
+/// Health check interval that tightens while the connection keeps dropping
+/// and relaxes back to the configured value once it has been stable, so
+/// outages are noticed quickly without probing an idle link constantly.
+#[derive(Clone, Debug)]
+pub struct AdaptiveInterval {
+    base: Duration,
+    min: Duration,
+    current: Duration,
+    stable_for: Duration,
+}
+
+impl AdaptiveInterval {
+    /// How long the connection must stay healthy before the interval is
+    /// doubled back towards the base.
+    pub const STABLE_PERIOD: Duration = Duration::from_secs(60);
+
+    pub fn new(base: Duration) -> Self {
+        Self {
+            base,
+            min: (base / 8).max(Duration::from_millis(500)).min(base),
+            current: base,
+            stable_for: Duration::ZERO,
+        }
+    }
+
+    pub fn current(&self) -> Duration {
+        self.current
+    }
+
+    /// Halves the interval, down to an eighth of the base (at least 500ms).
+    pub fn on_reconnect(&mut self) {
+        self.current = (self.current / 2).max(self.min);
+        self.stable_for = Duration::ZERO;
+    }
+
+    /// Records one healthy check; after [`Self::STABLE_PERIOD`] of them the
+    /// interval doubles, up to the base.
+    pub fn on_healthy(&mut self) {
+        if self.current == self.base {
+            return;
+        }
+        self.stable_for += self.current;
+        if self.stable_for >= Self::STABLE_PERIOD {
+            self.current = (self.current * 2).min(self.base);
+            self.stable_for = Duration::ZERO;
+        }
+    }
+}
+
+/// When the probes in a [`ProbeWindow`] count as degraded.
+#[derive(Clone, Debug)]
+pub struct DegradeThresholds {
+    /// Probes kept.
+    pub window: usize,
+    /// Median round-trip time of the successful probes above which the
+    /// connection is slow.
+    pub rtt: Duration,
+    /// Share of failed probes, 0 to 1, above which it is unreliable.
+    pub failure_rate: f64,
+}
+
+impl Default for DegradeThresholds {
+    fn default() -> Self {
+        Self {
+            window: 20,
+            rtt: Duration::from_secs(1),
+            failure_rate: 0.2,
+        }
+    }
+}
+
+/// The last health probes, to notice a connection getting slow or flaky
+/// before it fails outright.
+#[derive(Debug)]
+pub struct ProbeWindow {
+    thresholds: DegradeThresholds,
+    /// Round-trip times, `None` for failed probes; oldest first.
+    probes: VecDeque<Option<Duration>>,
+    degraded: bool,
+}
+
+/// What the probes in a [`ProbeWindow`] add up to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProbeStats {
+    /// Median round-trip time of the successful probes.
+    pub rtt: Option<Duration>,
+    pub failed: usize,
+    pub probes: usize,
+}
+
+impl fmt::Display for ProbeStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(rtt) = self.rtt {
+            write!(f, "median rtt {:?}, ", rtt)?;
+        }
+        write!(f, "{} of {} probes failed", self.failed, self.probes)
+    }
+}
+
+/// The probes crossed a threshold, one way or the other.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HealthChange {
+    Degraded(ProbeStats),
+    Recovered(ProbeStats),
+}
+
+impl ProbeWindow {
+    /// Probes needed before the window is judged at all.
+    const MIN_PROBES: usize = 5;
+
+    pub fn new(thresholds: DegradeThresholds) -> Self {
+        Self {
+            probes: VecDeque::with_capacity(thresholds.window),
+            thresholds,
+            degraded: false,
+        }
+    }
+
+    /// Adds a probe that took `rtt`, or failed if `None`; returns the
+    /// change if this one crossed a threshold.
+    pub fn record(&mut self, rtt: Option<Duration>) -> Option<HealthChange> {
+        if self.probes.len() == self.thresholds.window.max(1) {
+            self.probes.pop_front();
+        }
+        self.probes.push_back(rtt);
+
+        let stats = self.stats();
+        if stats.probes < Self::MIN_PROBES.min(self.thresholds.window) {
+            return None;
+        }
+        let degraded = stats.rtt.is_some_and(|rtt| rtt > self.thresholds.rtt)
+            || stats.failed as f64 > self.thresholds.failure_rate * stats.probes as f64;
+        if degraded == self.degraded {
+            return None;
+        }
+        self.degraded = degraded;
+        Some(if degraded {
+            HealthChange::Degraded(stats)
+        } else {
+            HealthChange::Recovered(stats)
+        })
+    }
+
+    pub fn stats(&self) -> ProbeStats {
+        let mut rtts: Vec<Duration> = self.probes.iter().flatten().copied().collect();
+        rtts.sort();
+        ProbeStats {
+            rtt: rtts.get(rtts.len() / 2).copied(),
+            failed: self.probes.len() - rtts.len(),
+            probes: self.probes.len(),
+        }
+    }
 }
 
 #[cfg(test)]
@@ -76,4 +378,157 @@ mod tests {
         assert!(policy.should_retry(100));
         assert!(policy.should_retry(1000));
     }
+
+    #[test]
+    fn test_no_retry_after_auth_error() {
+        let policy = RetryPolicy::default();
+
+        assert!(!policy.should_retry_after(0, ErrorClass::Auth));
+        assert!(policy.should_retry_after(0, ErrorClass::Dns));
+        assert!(policy.should_retry_after(100, ErrorClass::Network));
+        let policy = RetryPolicy {
+            max_attempts: Some(1),
+            ..Default::default()
+        };
+        assert!(!policy.should_retry_after(1, ErrorClass::Timeout));
+    }
+
+    #[test]
+    fn test_past_deadline() {
+        let policy = RetryPolicy {
+            max_total_duration: Some(Duration::from_secs(10)),
+            ..Default::default()
+        };
+
+        assert!(!policy.past_deadline(Duration::ZERO, 0));
+        assert!(!policy.past_deadline(Duration::from_secs(9), 0));
+        assert!(policy.past_deadline(Duration::from_secs(9), 1));
+        assert!(policy.past_deadline(Duration::from_secs(11), 0));
+        assert!(!RetryPolicy::default().past_deadline(Duration::from_secs(86400), 100));
+    }
+
+    #[test]
+    fn test_probe_window() {
+        let mut window = ProbeWindow::new(DegradeThresholds {
+            window: 10,
+            rtt: Duration::from_millis(500),
+            failure_rate: 0.2,
+        });
+        let ms = |ms| Some(Duration::from_millis(ms));
+
+        // Judged only from the fifth probe on.
+        for _ in 0..4 {
+            assert_eq!(window.record(ms(900)), None);
+        }
+        let Some(HealthChange::Degraded(stats)) = window.record(ms(900)) else {
+            panic!("expected degraded");
+        };
+        assert_eq!(stats.rtt, ms(900));
+        assert_eq!(window.record(ms(900)), None);
+
+        for _ in 0..5 {
+            window.record(ms(50));
+        }
+        let Some(HealthChange::Recovered(stats)) = window.record(ms(50)) else {
+            panic!("expected recovered");
+        };
+        assert_eq!(stats.rtt, ms(50));
+        assert_eq!(stats.probes, 10);
+
+        // 3 failures of 10 is above 20%.
+        window.record(None);
+        window.record(None);
+        let Some(HealthChange::Degraded(stats)) = window.record(None) else {
+            panic!("expected degraded");
+        };
+        assert_eq!(stats.failed, 3);
+        assert_eq!(stats.to_string(), "median rtt 50ms, 3 of 10 probes failed");
+    }
+
+    #[test]
+    fn test_error_class() {
+        let error = ErrorClass::Auth.wrap(anyhow::anyhow!("No identity file specified"));
+        assert_eq!(ErrorClass::of(&error), ErrorClass::Auth);
+        assert_eq!(error.to_string(), "No identity file specified");
+
+        let error = ErrorClass::Dns
+            .wrap(anyhow::Error::new(std::io::Error::other("no such host")).context("resolving"));
+        assert_eq!(ErrorClass::of(&error), ErrorClass::Dns);
+        assert_eq!(format!("{:#}", error), "resolving: no such host");
+
+        let error = anyhow::Error::new(russh::Error::UnknownKey);
+        assert_eq!(ErrorClass::of(&error), ErrorClass::Auth);
+        let error = anyhow::Error::new(russh::Error::ConnectionTimeout);
+        assert_eq!(ErrorClass::of(&error), ErrorClass::Timeout);
+        let error = anyhow::Error::new(std::io::Error::from(std::io::ErrorKind::TimedOut));
+        assert_eq!(ErrorClass::of(&error), ErrorClass::Timeout);
+        let error = anyhow::Error::new(std::io::Error::from(std::io::ErrorKind::ConnectionRefused));
+        assert_eq!(ErrorClass::of(&error), ErrorClass::Network);
+    }
+
+    #[test]
+    fn test_adaptive_interval_tightens_on_reconnect() {
+        let mut interval = AdaptiveInterval::new(Duration::from_secs(8));
+
+        interval.on_reconnect();
+        assert_eq!(interval.current(), Duration::from_secs(4));
+        interval.on_reconnect();
+        interval.on_reconnect();
+        assert_eq!(interval.current(), Duration::from_secs(1));
+        interval.on_reconnect();
+        assert_eq!(interval.current(), Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_adaptive_interval_relaxes_after_stable_period() {
+        let mut interval = AdaptiveInterval::new(Duration::from_secs(8));
+        interval.on_reconnect();
+        interval.on_reconnect();
+        assert_eq!(interval.current(), Duration::from_secs(2));
+
+        for _ in 0..29 {
+            interval.on_healthy();
+        }
+        assert_eq!(interval.current(), Duration::from_secs(2));
+        interval.on_healthy();
+        assert_eq!(interval.current(), Duration::from_secs(4));
+
+        for _ in 0..15 {
+            interval.on_healthy();
+        }
+        assert_eq!(interval.current(), Duration::from_secs(8));
+
+        for _ in 0..100 {
+            interval.on_healthy();
+        }
+        assert_eq!(interval.current(), Duration::from_secs(8));
+    }
+
+    #[test]
+    fn test_adaptive_interval_reconnect_resets_stability() {
+        let mut interval = AdaptiveInterval::new(Duration::from_secs(8));
+        interval.on_reconnect();
+
+        for _ in 0..14 {
+            interval.on_healthy();
+        }
+        interval.on_reconnect();
+        for _ in 0..29 {
+            interval.on_healthy();
+        }
+        assert_eq!(interval.current(), Duration::from_secs(2));
+    }
+
+    #[test]
+    fn test_adaptive_interval_min_floor() {
+        let mut interval = AdaptiveInterval::new(Duration::from_millis(300));
+        interval.on_reconnect();
+        assert_eq!(interval.current(), Duration::from_millis(300));
+
+        let mut interval = AdaptiveInterval::new(Duration::from_secs(2));
+        interval.on_reconnect();
+        interval.on_reconnect();
+        interval.on_reconnect();
+        assert_eq!(interval.current(), Duration::from_millis(500));
+    }
 }
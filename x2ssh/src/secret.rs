@@ -0,0 +1,202 @@
+//! Secrets kept out of the config file: read from a file, printed by a
+//! command, or looked up in the OS keyring. What is read is wiped from
+//! memory once dropped.
+
+use std::path::PathBuf;
+use std::process::Command;
+use std::process::Stdio;
+
+use zeroize::Zeroizing;
+
+/// The keyring service x2ssh's entries are stored under.
+pub const KEYRING_SERVICE: &str = "x2ssh";
+
+/// Where a secret comes from.
+#[derive(Clone, PartialEq)]
+pub enum SecretSource {
+    /// Written in the config itself.
+    Inline(Zeroizing<String>),
+    /// The content of a file, without its trailing newline.
+    File(PathBuf),
+    /// The first line a `sh -c` command prints, e.g. `pass show vpn`.
+    Command(String),
+    /// The keyring entry with this account under [`KEYRING_SERVICE`]:
+    /// libsecret (`secret-tool`) on Linux, the login keychain on macOS.
+    Keyring(String),
+}
+
+impl SecretSource {
+    /// The one source set among the config keys `sources` names; `None`
+    /// when none is.
+    pub fn one_of<const N: usize>(
+        sources: [(&str, Option<SecretSource>); N],
+    ) -> anyhow::Result<Option<Self>> {
+        let names: Vec<&str> = sources.iter().map(|(name, _)| *name).collect();
+        let mut set = sources
+            .into_iter()
+            .filter_map(|(name, source)| Some((name, source?)));
+        let first = set.next();
+        if let Some((second, _)) = set.next() {
+            anyhow::bail!(
+                "{} and {} are both set; use only one of {}",
+                first.map_or("", |(name, _)| name),
+                second,
+                names.join(", ")
+            );
+        }
+        Ok(first.map(|(_, source)| source))
+    }
+
+    /// Reads the secret. Commands inherit the terminal, so they can ask
+    /// for a passphrase of their own (gpg, for `pass`).
+    pub fn read(&self) -> anyhow::Result<Zeroizing<String>> {
+        let secret = match self {
+            Self::Inline(secret) => secret.clone(),
+            Self::File(path) => {
+                let content = Zeroizing::new(
+                    std::fs::read_to_string(path)
+                        .map_err(|e| anyhow::anyhow!("cannot read {}: {}", self, e))?,
+                );
+                Zeroizing::new(content.trim_end_matches(['\r', '\n']).to_string())
+            }
+            Self::Command(command) => {
+                first_line(run(Command::new("sh").args(["-c", command]), self)?)?
+            }
+            Self::Keyring(account) => first_line(run(&mut keyring_lookup(account), self)?)?,
+        };
+        if secret.is_empty() {
+            anyhow::bail!("{} is empty", self);
+        }
+        Ok(secret)
+    }
+}
+
+impl std::fmt::Display for SecretSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Inline(_) => write!(f, "the config"),
+            Self::File(path) => write!(f, "{}", path.display()),
+            Self::Command(command) => write!(f, "command `{}`", command),
+            Self::Keyring(account) => write!(
+                f,
+                "keyring entry service={} account={}",
+                KEYRING_SERVICE, account
+            ),
+        }
+    }
+}
+
+impl std::fmt::Debug for SecretSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Inline(_) => write!(f, "Inline(..)"),
+            Self::File(path) => f.debug_tuple("File").field(path).finish(),
+            Self::Command(command) => f.debug_tuple("Command").field(command).finish(),
+            Self::Keyring(account) => f.debug_tuple("Keyring").field(account).finish(),
+        }
+    }
+}
+
+#[cfg(target_os = "macos")]
+fn keyring_lookup(account: &str) -> Command {
+    let mut command = Command::new("security");
+    command.args([
+        "find-generic-password",
+        "-s",
+        KEYRING_SERVICE,
+        "-a",
+        account,
+        "-w",
+    ]);
+    command
+}
+
+#[cfg(not(target_os = "macos"))]
+fn keyring_lookup(account: &str) -> Command {
+    let mut command = Command::new("secret-tool");
+    command.args(["lookup", "service", KEYRING_SERVICE, "account", account]);
+    command
+}
+
+/// Runs `command` for `source`, returning its output.
+fn run(command: &mut Command, source: &SecretSource) -> anyhow::Result<Zeroizing<Vec<u8>>> {
+    let output = command
+        .stdin(Stdio::inherit())
+        .stderr(Stdio::inherit())
+        .output()
+        .map_err(|e| anyhow::anyhow!("cannot run {}: {}", source, e))?;
+    let stdout = Zeroizing::new(output.stdout);
+    if !output.status.success() {
+        anyhow::bail!("{} failed ({})", source, output.status);
+    }
+    Ok(stdout)
+}
+
+fn first_line(output: Zeroizing<Vec<u8>>) -> anyhow::Result<Zeroizing<String>> {
+    let text = std::str::from_utf8(&output)
+        .map_err(|_| anyhow::anyhow!("the secret is not valid UTF-8"))?;
+    let line = text.lines().next().unwrap_or_default();
+    Ok(Zeroizing::new(line.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("password");
+        std::fs::write(&file, "from file\n").unwrap();
+        let read = |source: SecretSource| source.read().map(|secret| secret.to_string());
+
+        assert_eq!(read(SecretSource::File(file)).unwrap(), "from file");
+        assert_eq!(
+            read(SecretSource::Command(
+                "printf 'first\\nsecond\\n'".to_string()
+            ))
+            .unwrap(),
+            "first"
+        );
+        assert_eq!(
+            read(SecretSource::Inline(Zeroizing::new("inline".to_string()))).unwrap(),
+            "inline"
+        );
+
+        let err = read(SecretSource::Command("exit 3".to_string())).unwrap_err();
+        assert!(
+            err.to_string().starts_with("command `exit 3` failed"),
+            "{err}"
+        );
+        let err = read(SecretSource::Command("true".to_string())).unwrap_err();
+        assert_eq!(err.to_string(), "command `true` is empty");
+        let err = read(SecretSource::File(dir.path().join("missing"))).unwrap_err();
+        assert!(err.to_string().starts_with("cannot read "), "{err}");
+    }
+
+    #[test]
+    fn test_one_of() {
+        let file = || Some(SecretSource::File(PathBuf::from("/secret")));
+        let command = || Some(SecretSource::Command("pass show vpn".to_string()));
+
+        assert_eq!(
+            SecretSource::one_of([("password_file", None), ("password_cmd", None)]).unwrap(),
+            None
+        );
+        assert_eq!(
+            SecretSource::one_of([("password_file", None), ("password_cmd", command())]).unwrap(),
+            command()
+        );
+        let err = SecretSource::one_of([
+            ("password_file", file()),
+            ("password_cmd", command()),
+            ("password_keyring", None),
+        ])
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "password_file and password_cmd are both set; use only one of password_file, \
+             password_cmd, password_keyring"
+        );
+    }
+}
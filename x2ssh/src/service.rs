@@ -0,0 +1,284 @@
+//! `x2ssh install-service`: a systemd unit (launchd plist on macOS) that
+//! starts a proxy or VPN session at boot and restarts it when it fails.
+
+use std::path::Path;
+use std::path::PathBuf;
+
+use tracing::info;
+
+/// A session to run as a service.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Service {
+    /// The unit name, or the plist's label.
+    pub name: String,
+    pub description: String,
+    /// The full command line, starting with the x2ssh executable.
+    pub command: Vec<String>,
+    /// VPN sessions run as root, with only the capabilities they need.
+    pub vpn: bool,
+    /// Install for the current user rather than system-wide.
+    pub user_service: bool,
+    /// The account a system-wide proxy runs as, to read its keys.
+    pub run_as: Option<String>,
+}
+
+impl Service {
+    /// The systemd unit. Proxies run sandboxed with no capabilities; a VPN
+    /// keeps the ones it needs for the TUN device, routes and firewall.
+    pub fn systemd_unit(&self) -> String {
+        let exec_start: Vec<String> = self.command.iter().map(|arg| systemd_quote(arg)).collect();
+        let mut unit = format!(
+            r#"[Unit]
+Description={}
+Wants=network-online.target
+After=network-online.target
+
+[Service]
+Type=notify
+NotifyAccess=main
+ExecStart={}
+Restart=on-failure
+RestartSec=5s
+"#,
+            self.description,
+            exec_start.join(" ")
+        );
+        if self.vpn {
+            unit.push_str(
+                r#"CapabilityBoundingSet=CAP_NET_ADMIN CAP_NET_RAW CAP_DAC_OVERRIDE
+RuntimeDirectory=x2ssh
+RuntimeDirectoryPreserve=yes
+ProtectHome=read-only
+"#,
+            );
+        } else {
+            if let Some(user) = &self.run_as {
+                unit.push_str(&format!("User={}\n", user));
+            }
+            if !self.user_service {
+                // Status files go to /run/x2ssh, where `x2ssh status` looks.
+                unit.push_str("RuntimeDirectory=x2ssh\nEnvironment=XDG_RUNTIME_DIR=/run\n");
+            }
+            unit.push_str("CapabilityBoundingSet=\nProtectSystem=strict\nProtectHome=read-only\n");
+        }
+        unit.push_str("NoNewPrivileges=yes\nPrivateTmp=yes\n\n[Install]\n");
+        unit.push_str(if self.user_service {
+            "WantedBy=default.target\n"
+        } else {
+            "WantedBy=multi-user.target\n"
+        });
+        unit
+    }
+
+    /// The launchd job, kept alive and started at load.
+    pub fn launchd_plist(&self) -> String {
+        let arguments: String = self
+            .command
+            .iter()
+            .map(|arg| format!("        <string>{}</string>\n", xml_escape(arg)))
+            .collect();
+        format!(
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+<!DOCTYPE plist PUBLIC "-//Apple//DTD PLIST 1.0//EN" "http://www.apple.com/DTDs/PropertyList-1.0.dtd">
+<plist version="1.0">
+<dict>
+    <key>Label</key>
+    <string>{}</string>
+    <key>ProgramArguments</key>
+    <array>
+{}    </array>
+    <key>RunAtLoad</key>
+    <true/>
+    <key>KeepAlive</key>
+    <dict>
+        <key>SuccessfulExit</key>
+        <false/>
+    </dict>
+</dict>
+</plist>
+"#,
+            xml_escape(&self.name),
+            arguments
+        )
+    }
+
+    /// The service definition for this platform.
+    pub fn render(&self) -> anyhow::Result<String> {
+        if cfg!(target_os = "macos") {
+            Ok(self.launchd_plist())
+        } else if cfg!(target_os = "linux") {
+            Ok(self.systemd_unit())
+        } else {
+            anyhow::bail!(
+                "install-service supports systemd and launchd; run x2ssh under a service wrapper \
+                 on this system"
+            )
+        }
+    }
+
+    /// Where the service definition goes.
+    pub fn path(&self) -> anyhow::Result<PathBuf> {
+        let home = || {
+            std::env::var_os("HOME")
+                .map(PathBuf::from)
+                .ok_or_else(|| anyhow::anyhow!("HOME is not set"))
+        };
+        if cfg!(target_os = "macos") {
+            let dir = if self.user_service {
+                home()?.join("Library/LaunchAgents")
+            } else {
+                PathBuf::from("/Library/LaunchDaemons")
+            };
+            return Ok(dir.join(format!("{}.plist", self.name)));
+        }
+        let dir = if self.user_service {
+            std::env::var_os("XDG_CONFIG_HOME")
+                .map(PathBuf::from)
+                .filter(|dir| dir.is_absolute())
+                .map_or_else(|| home().map(|home| home.join(".config")), Ok)?
+                .join("systemd/user")
+        } else {
+            PathBuf::from("/etc/systemd/system")
+        };
+        Ok(dir.join(format!("{}.service", self.name)))
+    }
+
+    /// Writes the service definition, then has the service manager start
+    /// it now and at every boot. An existing one is only replaced with
+    /// `force`.
+    pub fn install(&self, force: bool) -> anyhow::Result<PathBuf> {
+        let content = self.render()?;
+        let path = self.path()?;
+        if path.exists() && !force {
+            anyhow::bail!(
+                "{} already exists; pass --force to replace it",
+                path.display()
+            );
+        }
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        std::fs::write(&path, content)
+            .map_err(|e| anyhow::anyhow!("cannot write {}: {}", path.display(), e))?;
+        info!("Wrote {}", path.display());
+
+        if cfg!(target_os = "macos") {
+            run("launchctl", &["load", "-w", &path.to_string_lossy()])?;
+        } else {
+            let scope: &[&str] = if self.user_service { &["--user"] } else { &[] };
+            run("systemctl", &[scope, &["daemon-reload"]].concat())?;
+            run(
+                "systemctl",
+                &[scope, &["enable", "--now", &self.name]].concat(),
+            )?;
+        }
+        Ok(path)
+    }
+}
+
+fn run(program: &str, args: &[&str]) -> anyhow::Result<()> {
+    let status = std::process::Command::new(program)
+        .args(args)
+        .status()
+        .map_err(|e| anyhow::anyhow!("cannot run {}: {}", program, e))?;
+    if !status.success() {
+        anyhow::bail!("{} {} exited with {}", program, args.join(" "), status);
+    }
+    Ok(())
+}
+
+/// Quotes an `ExecStart=` argument; `%` and `$` would otherwise be
+/// expanded by systemd.
+fn systemd_quote(arg: &str) -> String {
+    let escaped = arg.replace('%', "%%").replace('$', "$$");
+    if !escaped.is_empty()
+        && !escaped
+            .chars()
+            .any(|c| c.is_whitespace() || matches!(c, '"' | '\'' | '\\' | ';'))
+    {
+        return escaped;
+    }
+    format!("\"{}\"", escaped.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+fn xml_escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// `path` made absolute, for a service that does not start where x2ssh
+/// was run.
+pub fn absolute(path: &Path) -> PathBuf {
+    std::path::absolute(path).unwrap_or_else(|_| path.to_path_buf())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn service(vpn: bool) -> Service {
+        Service {
+            name: "x2ssh".to_string(),
+            description: "x2ssh to alice@server".to_string(),
+            command: [
+                "/usr/local/bin/x2ssh",
+                "--config",
+                "/etc/x2ssh/my config.toml",
+                if vpn { "vpn" } else { "proxy" },
+                "alice@server",
+            ]
+            .map(String::from)
+            .to_vec(),
+            vpn,
+            user_service: false,
+            run_as: (!vpn).then(|| "alice".to_string()),
+        }
+    }
+
+    #[test]
+    fn test_systemd_unit() {
+        let unit = service(false).systemd_unit();
+        assert!(unit.contains(
+            "\nExecStart=/usr/local/bin/x2ssh --config \"/etc/x2ssh/my config.toml\" proxy \
+             alice@server\n"
+        ));
+        assert!(unit.contains("\nType=notify\n"));
+        assert!(unit.contains("\nUser=alice\n"));
+        assert!(unit.contains("\nCapabilityBoundingSet=\n"));
+        assert!(unit.ends_with("[Install]\nWantedBy=multi-user.target\n"));
+
+        let unit = service(true).systemd_unit();
+        assert!(unit.contains("\nCapabilityBoundingSet=CAP_NET_ADMIN CAP_NET_RAW"));
+        assert!(!unit.contains("User="));
+        assert!(!unit.contains("ProtectSystem"));
+
+        let mut user = service(false);
+        user.user_service = true;
+        user.run_as = None;
+        let unit = user.systemd_unit();
+        assert!(!unit.contains("RuntimeDirectory"));
+        assert!(unit.ends_with("WantedBy=default.target\n"));
+    }
+
+    #[test]
+    fn test_systemd_quote() {
+        assert_eq!(systemd_quote("-D"), "-D");
+        assert_eq!(systemd_quote("100%"), "100%%");
+        assert_eq!(systemd_quote("$HOME"), "$$HOME");
+        assert_eq!(systemd_quote("a b"), "\"a b\"");
+        assert_eq!(systemd_quote("say \"hi\""), "\"say \\\"hi\\\"\"");
+        assert_eq!(systemd_quote(""), "\"\"");
+    }
+
+    #[test]
+    fn test_launchd_plist() {
+        let mut service = service(false);
+        service.command.push("a&b".to_string());
+        let plist = service.launchd_plist();
+        assert!(plist.contains("    <key>Label</key>\n    <string>x2ssh</string>\n"));
+        assert!(plist.contains("        <string>/etc/x2ssh/my config.toml</string>\n"));
+        assert!(plist.contains("        <string>a&amp;b</string>\n"));
+    }
+}
@@ -0,0 +1,286 @@
+//! Bounded cleanup on exit, so a remote that never answers a channel close
+//! cannot hang it, and the signals that start it: the first begins a
+//! graceful shutdown, a second cuts it short.
+
+use std::time::Duration;
+
+use tokio::io::AsyncRead;
+use tokio::io::AsyncWrite;
+use tokio::io::AsyncWriteExt;
+use tokio::task::JoinSet;
+use tokio_util::sync::CancellationToken;
+use tracing::info;
+use tracing::warn;
+
+/// Closes channels one by one, giving each at most `timeout`, and counts
+/// the ones given up on for the shutdown log.
+#[derive(Debug)]
+pub struct Shutdown {
+    timeout: Duration,
+    closed: usize,
+    abandoned: usize,
+}
+
+impl Shutdown {
+    pub fn new(timeout: Duration) -> Self {
+        Self {
+            timeout,
+            closed: 0,
+            abandoned: 0,
+        }
+    }
+
+    /// Runs `close` for up to the timeout. Returns its result, or `None`
+    /// if it was dropped unfinished and the channel abandoned.
+    pub async fn close<T>(
+        &mut self,
+        what: &str,
+        close: impl Future<Output = anyhow::Result<T>>,
+    ) -> Option<anyhow::Result<T>> {
+        match tokio::time::timeout(self.timeout, close).await {
+            Ok(result) => {
+                self.closed += 1;
+                Some(result)
+            }
+            Err(_) => {
+                warn!(
+                    "{} did not close within {:?}; abandoning it",
+                    what, self.timeout
+                );
+                self.abandoned += 1;
+                None
+            }
+        }
+    }
+
+    /// Waits up to the timeout for the tasks in `tasks` to finish on their
+    /// own, e.g. SOCKS connections whose clients are done.
+    pub async fn drain<T: 'static>(&mut self, what: &str, tasks: &mut JoinSet<T>) {
+        if tasks.is_empty() {
+            return;
+        }
+        info!(
+            "Waiting up to {:?} for {} open {} to finish; press Ctrl+C again to close them now",
+            self.timeout,
+            tasks.len(),
+            what
+        );
+        let _ = tokio::time::timeout(self.timeout, async {
+            while tasks.join_next().await.is_some() {
+                self.closed += 1;
+            }
+        })
+        .await;
+    }
+
+    /// Cancels `token`, on which the tasks in `tasks` close their channels
+    /// with an EOF, and waits up to the timeout for them; the ones left are
+    /// aborted.
+    pub async fn cancel_all<T: 'static>(
+        &mut self,
+        what: &str,
+        token: &CancellationToken,
+        tasks: &mut JoinSet<T>,
+    ) {
+        token.cancel();
+        if tasks.is_empty() {
+            return;
+        }
+        info!("Closing {} open {}", tasks.len(), what);
+        let _ = tokio::time::timeout(self.timeout, async {
+            while tasks.join_next().await.is_some() {
+                self.closed += 1;
+            }
+        })
+        .await;
+        self.abort_all(what, tasks).await;
+    }
+
+    /// Aborts the tasks still running in `tasks`, each holding a channel
+    /// that is dropped without waiting for the remote.
+    pub async fn abort_all<T: 'static>(&mut self, what: &str, tasks: &mut JoinSet<T>) {
+        if tasks.is_empty() {
+            return;
+        }
+        info!("Aborting {} open {}", tasks.len(), what);
+        self.abandoned += tasks.len();
+        tasks.shutdown().await;
+    }
+
+    pub fn abandoned(&self) -> usize {
+        self.abandoned
+    }
+
+    /// Writes the summary line of the shutdown log.
+    pub fn log(&self) {
+        if self.abandoned == 0 {
+            info!("Shutdown complete: {} channel(s) closed", self.closed);
+        } else {
+            warn!(
+                "Shutdown complete: {} channel(s) closed, {} abandoned",
+                self.closed, self.abandoned
+            );
+        }
+    }
+}
+
+/// Copies between `client` and `channel` until both are done, or `None`
+/// once `shutdown` is cancelled. On shutdown both sides get an EOF rather
+/// than a reset, so neither mistakes the end of the transfer for a failure.
+pub async fn copy_until_shutdown<A, B>(
+    client: &mut A,
+    channel: &mut B,
+    buffer_size: usize,
+    shutdown: &CancellationToken,
+) -> Option<std::io::Result<(u64, u64)>>
+where
+    A: AsyncRead + AsyncWrite + Unpin,
+    B: AsyncRead + AsyncWrite + Unpin,
+{
+    tokio::select! {
+        result = tokio::io::copy_bidirectional_with_sizes(
+            client,
+            channel,
+            buffer_size,
+            buffer_size,
+        ) => Some(result),
+        () = shutdown.cancelled() => {
+            let _ = client.shutdown().await;
+            let _ = channel.shutdown().await;
+            None
+        }
+    }
+}
+
+/// Requests to stop from outside: Ctrl+C, and on Unix also SIGTERM
+/// (`systemctl stop`) and SIGHUP (the terminal closing). Listening starts
+/// on creation, so one made before anything needs undoing holds a signal
+/// that arrives meanwhile for [`recv`](Self::recv) instead of letting it
+/// kill the process.
+pub struct Signals {
+    #[cfg(unix)]
+    term: tokio::signal::unix::Signal,
+    #[cfg(unix)]
+    hup: tokio::signal::unix::Signal,
+    #[cfg(unix)]
+    int: tokio::signal::unix::Signal,
+}
+
+impl Signals {
+    #[cfg(unix)]
+    pub fn new() -> anyhow::Result<Self> {
+        use tokio::signal::unix::SignalKind;
+        use tokio::signal::unix::signal;
+
+        Ok(Self {
+            term: signal(SignalKind::terminate())?,
+            hup: signal(SignalKind::hangup())?,
+            int: signal(SignalKind::interrupt())?,
+        })
+    }
+
+    #[cfg(not(unix))]
+    pub fn new() -> anyhow::Result<Self> {
+        Ok(Self {})
+    }
+
+    /// Waits for the next signal and returns its name.
+    #[cfg(unix)]
+    pub async fn recv(&mut self) -> &'static str {
+        tokio::select! {
+            _ = self.term.recv() => "SIGTERM",
+            _ = self.hup.recv() => "SIGHUP",
+            _ = self.int.recv() => "SIGINT",
+        }
+    }
+
+    #[cfg(not(unix))]
+    pub async fn recv(&mut self) -> &'static str {
+        let _ = tokio::signal::ctrl_c().await;
+        "Ctrl+C"
+    }
+
+    /// Runs a phase of a graceful shutdown, unless another signal asks to
+    /// exit at once first; then `None`, and the caller skips what is left.
+    pub async fn unless_repeated<T>(&mut self, phase: impl Future<Output = T>) -> Option<T> {
+        tokio::select! {
+            result = phase => Some(result),
+            signal = self.recv() => {
+                warn!("Received {} again, exiting now", signal);
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_close_abandons_hung_channels() {
+        let mut shutdown = Shutdown::new(Duration::from_millis(50));
+
+        let answered = shutdown.close("channel 1", async { Ok(1) }).await;
+        assert_eq!(answered.unwrap().unwrap(), 1);
+
+        let hung = shutdown
+            .close("channel 2", std::future::pending::<anyhow::Result<()>>())
+            .await;
+        assert!(hung.is_none());
+
+        let mut tasks = JoinSet::new();
+        tasks.spawn(std::future::pending::<()>());
+        tasks.spawn(std::future::pending::<()>());
+        shutdown.abort_all("connections", &mut tasks).await;
+        assert!(tasks.is_empty());
+
+        assert_eq!(shutdown.closed, 1);
+        assert_eq!(shutdown.abandoned(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_drain_waits_for_tasks() {
+        let mut shutdown = Shutdown::new(Duration::from_millis(200));
+        let mut tasks = JoinSet::new();
+        tasks.spawn(tokio::time::sleep(Duration::from_millis(10)));
+        tasks.spawn(std::future::pending::<()>());
+        shutdown.drain("connections", &mut tasks).await;
+        assert_eq!(shutdown.closed, 1);
+        assert_eq!(tasks.len(), 1);
+        shutdown.abort_all("connections", &mut tasks).await;
+        assert_eq!(shutdown.abandoned(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_cancel_all_closes_before_aborting() {
+        let mut shutdown = Shutdown::new(Duration::from_millis(100));
+        let token = CancellationToken::new();
+        let mut tasks = JoinSet::new();
+        let watching = token.clone();
+        tasks.spawn(async move { watching.cancelled().await });
+        tasks.spawn(async { std::future::pending::<()>().await });
+        shutdown.cancel_all("connections", &token, &mut tasks).await;
+        assert!(tasks.is_empty());
+        assert_eq!(shutdown.closed, 1);
+        assert_eq!(shutdown.abandoned(), 1);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_signals_holds_sighup() {
+        let mut signals = Signals::new().unwrap();
+        // The handler is installed, so this only wakes `recv`.
+        unsafe { libc::raise(libc::SIGHUP) };
+        let received = tokio::time::timeout(Duration::from_secs(5), signals.recv()).await;
+        assert_eq!(received.unwrap(), "SIGHUP");
+
+        let phase = signals.unless_repeated(async {
+            unsafe { libc::raise(libc::SIGHUP) };
+            std::future::pending::<()>().await
+        });
+        let interrupted = tokio::time::timeout(Duration::from_secs(5), phase).await;
+        assert_eq!(interrupted.unwrap(), None);
+        assert_eq!(signals.unless_repeated(async { 1 }).await, Some(1));
+    }
+}
@@ -1,69 +1,1056 @@
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::net::IpAddr;
 use std::net::Ipv4Addr;
+use std::net::SocketAddr;
 use std::net::ToSocketAddrs;
+use std::pin::Pin;
 use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::task::Context;
+use std::task::Poll;
+use std::time::Duration;
+use std::time::Instant;
 
+use fast_socks5::ReplyError;
 use fast_socks5::Socks5Command;
 use fast_socks5::server::DnsResolveHelper;
 use fast_socks5::server::ErrorContext;
 use fast_socks5::server::Socks5ServerProtocol;
 use fast_socks5::server::SocksServerError;
 use fast_socks5::server::states;
+use fast_socks5::util::target_addr::TargetAddr;
+use ipnet::IpNet;
+use russh::ChannelOpenFailure;
+use serde::Deserialize;
+use serde::Serialize;
 use tokio::io::AsyncRead;
+use tokio::io::AsyncReadExt;
 use tokio::io::AsyncWrite;
+use tokio::io::AsyncWriteExt;
+use tokio::io::ReadBuf;
 use tokio::net::TcpStream;
 use tracing::debug;
 use tracing::error;
+use tracing::info;
 use tracing::warn;
+use zeroize::Zeroizing;
 
+use crate::journal::JournalEvent;
+use crate::metrics::Metric;
+use crate::otel::Span;
+use crate::otel::SpanKind;
+use crate::shutdown::copy_until_shutdown;
+use crate::transport::ForwardStream;
+use crate::transport::TcpOptions;
 use crate::transport::Transport;
 
-pub async fn serve(session: Arc<Transport>, socket: TcpStream) -> anyhow::Result<()> {
-    let (proto, cmd, target_addr) = Socks5ServerProtocol::accept_no_auth(socket)
-        .await?
-        .read_command()
-        .await?
-        .resolve_dns()
-        .await?;
+/// Per-connection settings for the SOCKS5 server.
+#[derive(Clone, Debug)]
+pub struct SocksOptions {
+    /// Copy buffer size in bytes, per direction.
+    pub buffer_size: usize,
+    pub tcp: TcpOptions,
+    /// When set, connections that have not transferred any data yet survive
+    /// an SSH reconnect: the channel is re-opened on the new session, waiting
+    /// at most this long for it to come up.
+    pub redial: Option<Duration>,
+    /// Per-suffix overrides for how domain names are resolved.
+    pub resolve: Resolvers,
+    /// Username and password clients must authenticate with; without
+    /// them, no authentication is asked for.
+    pub auth: Option<Credentials>,
+    /// Which client addresses may use the proxy.
+    pub acl: Acl,
+    /// The connections open right now, shared by the clones of these
+    /// options.
+    pub open: Arc<OpenConnections>,
+    /// Destinations refused without trying while they keep failing,
+    /// shared by the clones of these options.
+    pub breaker: Arc<CircuitBreaker>,
+}
 
-    let (addr, proto) = try_notify(
-        proto,
-        target_addr
-            .to_socket_addrs()
-            .err_when("converting to socket addr")
-            .and_then(|mut addrs| addrs.next().ok_or(SocksServerError::Bug("no socket addrs"))),
-    )
-    .await?;
+impl Default for SocksOptions {
+    fn default() -> Self {
+        Self {
+            buffer_size: 64 * 1024,
+            tcp: TcpOptions::default(),
+            redial: None,
+            resolve: Resolvers::default(),
+            auth: None,
+            acl: Acl::default(),
+            open: Arc::default(),
+            breaker: Arc::default(),
+        }
+    }
+}
+
+/// SOCKS connections open right now, by the destination the client asked
+/// for.
+#[derive(Debug, Default)]
+pub struct OpenConnections(Mutex<HashMap<String, u64>>);
+
+impl OpenConnections {
+    /// Counts a connection to `destination` until the guard is dropped.
+    pub fn open(self: &Arc<Self>, destination: String) -> OpenConnection {
+        *self
+            .0
+            .lock()
+            .unwrap()
+            .entry(destination.clone())
+            .or_default() += 1;
+        OpenConnection {
+            connections: self.clone(),
+            destination,
+        }
+    }
+
+    pub fn by_destination(&self) -> BTreeMap<String, u64> {
+        self.0
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(destination, count)| (destination.clone(), *count))
+            .collect()
+    }
+}
+
+/// One connection counted in [`OpenConnections`].
+pub struct OpenConnection {
+    connections: Arc<OpenConnections>,
+    destination: String,
+}
+
+impl Drop for OpenConnection {
+    fn drop(&mut self) {
+        let mut open = self.connections.0.lock().unwrap();
+        if let Some(count) = open.get_mut(&self.destination) {
+            *count -= 1;
+            if *count == 0 {
+                open.remove(&self.destination);
+            }
+        }
+    }
+}
+
+/// Stops opening channels to a destination that failed `threshold` times in
+/// a row, answering its clients at once with the last error for
+/// `cooldown`; then lets one attempt through, and closes again unless it
+/// succeeds. Keeps a client that retries in a tight loop from flooding the
+/// SSH session with channel opens.
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    /// 0 disables the breaker.
+    threshold: u32,
+    cooldown: Duration,
+    destinations: Mutex<HashMap<String, Failures>>,
+}
+
+#[derive(Debug)]
+struct Failures {
+    in_a_row: u32,
+    last: ReplyError,
+    /// When the last failure was recorded.
+    at: Instant,
+    open_until: Option<Instant>,
+    /// The one attempt let through after the cooldown has not reported
+    /// back yet.
+    trial: bool,
+}
+
+impl Failures {
+    /// Whether the entry no longer matters: closed or past its cooldown,
+    /// with no failure within the cooldown either.
+    fn expired(&self, now: Instant, cooldown: Duration) -> bool {
+        self.open_until.is_none_or(|until| until <= now) && now >= self.at + cooldown
+    }
+}
+
+impl Default for CircuitBreaker {
+    fn default() -> Self {
+        Self::new(5, Duration::from_secs(30))
+    }
+}
+
+impl CircuitBreaker {
+    pub fn new(threshold: u32, cooldown: Duration) -> Self {
+        Self {
+            threshold,
+            cooldown,
+            destinations: Mutex::default(),
+        }
+    }
+
+    /// `Err` with the reply for the client and how long `destination`
+    /// stays refused, while the breaker is open for it.
+    pub fn check(&self, destination: &str) -> Result<(), (ReplyError, Duration)> {
+        self.check_at(destination, Instant::now())
+    }
+
+    fn check_at(&self, destination: &str, now: Instant) -> Result<(), (ReplyError, Duration)> {
+        let mut destinations = self.destinations.lock().unwrap();
+        let Some(failures) = destinations.get_mut(destination) else {
+            return Ok(());
+        };
+        match failures.open_until {
+            Some(until) if until > now => Err((failures.last, until - now)),
+            Some(_) => {
+                // Half-open: one attempt, which decides. The others are
+                // refused meanwhile, for another cooldown at most should it
+                // never report back.
+                failures.open_until = Some(now + self.cooldown);
+                failures.trial = true;
+                Ok(())
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Records how a channel open to `destination` went; `Err` carries the
+    /// reply sent to the client. Returns whether this opened the breaker.
+    pub fn record(&self, destination: &str, result: Result<(), ReplyError>) -> bool {
+        self.record_at(destination, result, Instant::now())
+    }
+
+    fn record_at(&self, destination: &str, result: Result<(), ReplyError>, now: Instant) -> bool {
+        if self.threshold == 0 {
+            return false;
+        }
+        let mut destinations = self.destinations.lock().unwrap();
+        let reply = match result {
+            Ok(()) => {
+                destinations.remove(destination);
+                return false;
+            }
+            Err(reply) => reply,
+        };
+        if !destinations.contains_key(destination) {
+            // Forget destinations nobody has tried for a while, so the map
+            // does not grow with every host that ever failed.
+            destinations.retain(|_, failures| !failures.expired(now, self.cooldown));
+        }
+        let failures = destinations
+            .entry(destination.to_string())
+            .or_insert(Failures {
+                in_a_row: 0,
+                last: reply,
+                at: now,
+                open_until: None,
+                trial: false,
+            });
+        failures.in_a_row += 1;
+        failures.last = reply;
+        failures.at = now;
+        if failures.trial {
+            failures.trial = false;
+        } else if failures.in_a_row < self.threshold || failures.open_until.is_some() {
+            return false;
+        }
+        failures.open_until = Some(now + self.cooldown);
+        true
+    }
+}
+
+/// Parses a listen address: `HOST:PORT`, or a bare port on 127.0.0.1.
+pub fn parse_listen(addr: &str) -> Result<SocketAddr, String> {
+    if let Ok(port) = addr.parse::<u16>() {
+        return Ok(SocketAddr::from(([127, 0, 0, 1], port)));
+    }
+
+    addr.parse::<SocketAddr>()
+        .map_err(|e| format!("Invalid SOCKS address '{}': {}", addr, e))
+}
+
+/// Username/password authentication (RFC 1929).
+#[derive(Clone, PartialEq)]
+pub struct Credentials {
+    pub username: String,
+    pub password: Zeroizing<String>,
+}
+
+impl Credentials {
+    fn check(&self, username: &str, password: &str) -> bool {
+        self.username == username && *self.password == password
+    }
+}
+
+impl std::fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Credentials")
+            .field("username", &self.username)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Client access rules, `allow CIDR` or `deny CIDR`. The first rule that
+/// matches the client's address decides; a client no rule matches is
+/// refused if any rule allows, and accepted otherwise.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Acl {
+    rules: Vec<(bool, IpNet)>,
+}
+
+impl Acl {
+    pub fn parse(rules: &[String]) -> anyhow::Result<Self> {
+        let rules = rules
+            .iter()
+            .map(|rule| {
+                let invalid = || {
+                    anyhow::anyhow!(
+                        "invalid acl rule '{}': expected \"allow CIDR\" or \"deny CIDR\"",
+                        rule
+                    )
+                };
+                let (action, network) = rule
+                    .trim()
+                    .split_once(char::is_whitespace)
+                    .ok_or_else(invalid)?;
+                let allow = match action {
+                    "allow" => true,
+                    "deny" => false,
+                    _ => return Err(invalid()),
+                };
+                let network = network.trim();
+                let network = match network.parse::<IpNet>() {
+                    Ok(network) => network.trunc(),
+                    Err(_) => IpNet::from(network.parse::<IpAddr>().map_err(|_| invalid())?),
+                };
+                Ok((allow, network))
+            })
+            .collect::<anyhow::Result<_>>()?;
+        Ok(Self { rules })
+    }
+
+    pub fn allows(&self, ip: IpAddr) -> bool {
+        let ip = ip.to_canonical();
+        self.rules
+            .iter()
+            .find(|(_, network)| network.contains(&ip))
+            .map_or_else(
+                || !self.rules.iter().any(|(allow, _)| *allow),
+                |(allow, _)| *allow,
+            )
+    }
+}
+
+/// How a requested domain name is resolved and reached.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum ResolvePolicy {
+    /// Resolve on the client, as for any other name (the default).
+    Local,
+    /// Hand the name to the SSH server, which resolves it.
+    Remote,
+    /// Connect through an upstream SOCKS5 proxy (as reachable from the SSH
+    /// server, e.g. Tor on `127.0.0.1:9050`) and let it resolve the name.
+    Socks { host: String, port: u16 },
+}
+
+impl TryFrom<String> for ResolvePolicy {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<ResolvePolicy> for String {
+    fn from(policy: ResolvePolicy) -> Self {
+        policy.to_string()
+    }
+}
+
+impl std::str::FromStr for ResolvePolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "local" => return Ok(ResolvePolicy::Local),
+            "remote" => return Ok(ResolvePolicy::Remote),
+            _ => {}
+        }
+        let invalid =
+            || format!("invalid resolver '{s}': expected local, remote or socks5://HOST:PORT");
+        let address = s
+            .strip_prefix("socks5://")
+            .or_else(|| s.strip_prefix("socks5h://"))
+            .ok_or_else(invalid)?;
+        let (host, port) = address.rsplit_once(':').ok_or_else(invalid)?;
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        if host.is_empty() {
+            return Err(invalid());
+        }
+        Ok(ResolvePolicy::Socks {
+            host: host.to_string(),
+            port: port.parse().map_err(|_| invalid())?,
+        })
+    }
+}
+
+impl std::fmt::Display for ResolvePolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ResolvePolicy::Local => write!(f, "local"),
+            ResolvePolicy::Remote => write!(f, "remote"),
+            ResolvePolicy::Socks { host, port } if host.contains(':') => {
+                write!(f, "socks5://[{host}]:{port}")
+            }
+            ResolvePolicy::Socks { host, port } => write!(f, "socks5://{host}:{port}"),
+        }
+    }
+}
+
+/// Resolution policies by domain suffix (`onion`, `corp.internal`). The
+/// longest matching suffix wins; unmatched names resolve locally.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Resolvers {
+    rules: Vec<(String, ResolvePolicy)>,
+}
+
+impl Resolvers {
+    pub fn new<'a>(rules: impl IntoIterator<Item = (&'a String, &'a ResolvePolicy)>) -> Self {
+        let rules = rules
+            .into_iter()
+            .map(|(suffix, policy)| (normalize_name(suffix), policy.clone()))
+            .collect();
+        Self { rules }
+    }
+
+    pub fn policy(&self, host: &str) -> &ResolvePolicy {
+        let host = normalize_name(host);
+        self.rules
+            .iter()
+            .filter(|(suffix, _)| {
+                host == *suffix
+                    || host
+                        .strip_suffix(suffix.as_str())
+                        .is_some_and(|prefix| prefix.ends_with('.'))
+            })
+            .max_by_key(|(suffix, _)| suffix.len())
+            .map_or(&ResolvePolicy::Local, |(_, policy)| policy)
+    }
+}
+
+fn normalize_name(name: &str) -> String {
+    name.trim_start_matches('.')
+        .trim_end_matches('.')
+        .to_ascii_lowercase()
+}
+
+/// Where a CONNECT request is sent.
+#[derive(Debug, Clone, PartialEq)]
+enum Target {
+    /// Resolved on the client.
+    Addr(SocketAddr),
+    /// Resolved by the SSH server.
+    Host(String, u16),
+    /// Reached through an upstream SOCKS5 proxy, which resolves the name.
+    Upstream {
+        proxy: (String, u16),
+        host: String,
+        port: u16,
+    },
+}
+
+impl std::fmt::Display for Target {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Target::Addr(addr) => write!(f, "{addr}"),
+            Target::Host(host, port) => write!(f, "{host}:{port}"),
+            Target::Upstream {
+                proxy: (proxy_host, proxy_port),
+                host,
+                port,
+            } => write!(f, "{host}:{port} via socks5://{proxy_host}:{proxy_port}"),
+        }
+    }
+}
+
+/// The upstream SOCKS5 proxy refused the request with this reply code.
+#[derive(Debug)]
+struct UpstreamRefused(u8);
+
+impl std::fmt::Display for UpstreamRefused {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "upstream SOCKS5 proxy refused the request (reply {})",
+            self.0
+        )
+    }
+}
+
+impl std::error::Error for UpstreamRefused {}
+
+/// The SSH session went away and was not re-established in time.
+#[derive(Debug)]
+struct SessionLost;
+
+impl std::fmt::Display for SessionLost {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "SSH session lost")
+    }
+}
+
+impl std::error::Error for SessionLost {}
+
+pub async fn serve(
+    session: Arc<Transport>,
+    socket: TcpStream,
+    options: &SocksOptions,
+) -> anyhow::Result<()> {
+    let metrics = Arc::clone(session.metrics());
+    metrics.record(Metric::SocksAccepted);
+    let mut span = session.tracer().root("socks.connection", SpanKind::Server);
+    match serve_connection(session, socket, options, &mut span).await {
+        Ok((sent, received)) => {
+            metrics.record(Metric::SocksClosed { sent, received });
+            span.set("socks.bytes_sent", sent);
+            span.set("socks.bytes_received", received);
+            Ok(())
+        }
+        Err(e) => {
+            metrics.record(Metric::SocksFailed);
+            span.fail(&e);
+            Err(e)
+        }
+    }
+}
+
+/// Serves one SOCKS client; returns the bytes relayed in each direction.
+/// Its handshake, channel open and transfer are timed as children of
+/// `span`.
+async fn serve_connection(
+    session: Arc<Transport>,
+    mut socket: TcpStream,
+    options: &SocksOptions,
+    span: &mut Span,
+) -> anyhow::Result<(u64, u64)> {
+    options.tcp.apply(&socket)?;
+    let peer = socket.peer_addr().ok();
+    if let Some(peer) = peer {
+        span.set("client.address", peer.to_string());
+    }
+    let handshake = span.child("socks.handshake");
+
+    if let Some(peer) = peer
+        && !options.acl.allows(peer.ip())
+    {
+        anyhow::bail!("client {} is not allowed by the acl", peer.ip());
+    }
+
+    if reject_misdirected(&mut socket).await? {
+        return Ok((0, 0));
+    }
+
+    let protocol = match &options.auth {
+        Some(credentials) => {
+            Socks5ServerProtocol::accept_password_auth(socket, |username, password| {
+                credentials.check(&username, &password)
+            })
+            .await?
+            .0
+        }
+        None => Socks5ServerProtocol::accept_no_auth(socket).await?,
+    };
+    let request = protocol.read_command().await?;
+    let destination = request.2.to_string();
+    let _open = options.open.open(destination.clone());
+    span.set("socks.destination", destination.clone());
+
+    let policy = match &request.2 {
+        TargetAddr::Domain(host, _) => options.resolve.policy(host).clone(),
+        TargetAddr::Ip(_) => ResolvePolicy::Local,
+    };
+    let (proto, cmd, target) = match (policy, request) {
+        (ResolvePolicy::Remote, (proto, cmd, TargetAddr::Domain(host, port))) => {
+            (proto, cmd, Target::Host(host, port))
+        }
+        (
+            ResolvePolicy::Socks {
+                host: proxy_host,
+                port: proxy_port,
+            },
+            (proto, cmd, TargetAddr::Domain(host, port)),
+        ) => (proto, cmd, Target::Upstream {
+            proxy: (proxy_host, proxy_port),
+            host,
+            port,
+        }),
+        (_, request) => {
+            let (proto, cmd, target_addr) = request.resolve_dns().await?;
+            let (addr, proto) = try_notify(
+                proto,
+                target_addr
+                    .to_socket_addrs()
+                    .err_when("converting to socket addr")
+                    .and_then(|mut addrs| {
+                        addrs.next().ok_or(SocksServerError::Bug("no socket addrs"))
+                    }),
+            )
+            .await?;
+            (proto, cmd, Target::Addr(addr))
+        }
+    };
+    handshake.end();
 
     match cmd {
         Socks5Command::TCPConnect => {
-            let (s0, s1) = tokio::io::duplex(4096);
+            if let Err((reply, remaining)) = options.breaker.check(&destination) {
+                if let Err(rep_err) = proto.reply_error(&reply).await {
+                    error!("error while reporting an error to the client: {}", rep_err);
+                }
+                anyhow::bail!(
+                    "{} keeps failing; not trying it for another {:?}",
+                    destination,
+                    remaining
+                );
+            }
+            let mut channel_open = span.child("ssh.channel_open");
+            let stream = match open_channel(&session, &target, options.redial).await {
+                Ok(stream) => {
+                    channel_open.end();
+                    stream
+                }
+                Err(e) => {
+                    channel_open.fail(&e);
+                    let reply = reply_for(&e);
+                    // A lost session says nothing about the destination.
+                    if !e.is::<SessionLost>() && options.breaker.record(&destination, Err(reply)) {
+                        warn!(
+                            "{} failed {} times in a row; refusing it for {:?}",
+                            destination, options.breaker.threshold, options.breaker.cooldown
+                        );
+                    }
+                    if let Err(rep_err) = proto.reply_error(&reply).await {
+                        error!("error while reporting an error to the client: {}", rep_err);
+                    }
+                    return Err(e);
+                }
+            };
+            options.breaker.record(&destination, Ok(()));
 
-            tokio::select! {
-                Err(e) = session.forward(addr, s0) => return Err(e),
-                Err(e) = run_tcp_proxy(proto, s1) => return Err(e),
-                else => {}
+            let mut transfer = span.child("socks.transfer");
+            let relayed = Relayed::default();
+            let result = run_tcp_proxy(&session, proto, stream, &target, options, &relayed).await;
+            if let Err(e) = &result {
+                transfer.fail(e);
             }
+            transfer.end();
+            let (sent, received) = relayed.totals();
+            session.record(JournalEvent::Forward {
+                peer: peer.map(|p| p.to_string()),
+                destination: target.to_string(),
+                sent,
+                received,
+            });
+            result.map(|()| (sent, received))
+        }
+        Socks5Command::UDPAssociate => {
+            warn!("UDP is not supported yet");
+            Ok((0, 0))
         }
-        Socks5Command::UDPAssociate => warn!("UDP is not supported yet"),
         _ => anyhow::bail!("command not supported"),
     }
+}
 
-    Ok(())
+/// What a client opened the connection with, judged from its first bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Greeting {
+    Socks5,
+    Socks4,
+    Http,
+    Tls,
+    Unknown,
 }
 
+impl Greeting {
+    fn detect(prefix: &[u8]) -> Self {
+        const HTTP_METHODS: &[&[u8]] = &[
+            b"GET ",
+            b"POST ",
+            b"HEAD ",
+            b"PUT ",
+            b"DELETE ",
+            b"OPTIONS ",
+            b"PATCH ",
+            b"CONNECT ",
+            b"TRACE ",
+        ];
+        match prefix {
+            [0x05, ..] => Greeting::Socks5,
+            [0x04, 0x01 | 0x02, ..] => Greeting::Socks4,
+            // TLS handshake record, SSL 3.0 through TLS 1.3 record versions.
+            [0x16, 0x03, 0x00..=0x04, ..] => Greeting::Tls,
+            _ if HTTP_METHODS.iter().any(|method| prefix.starts_with(method)) => Greeting::Http,
+            _ => Greeting::Unknown,
+        }
+    }
+
+    /// Hint for the log, and a reply the client can make sense of.
+    fn rejection(self) -> Option<(&'static str, &'static [u8])> {
+        match self {
+            Greeting::Http => Some((
+                "client sent an HTTP request; configure it to use a SOCKS5 proxy, not an HTTP \
+                 proxy",
+                b"HTTP/1.1 400 Bad Request\r\n\
+                  Content-Type: text/plain\r\n\
+                  Content-Length: 69\r\n\
+                  Connection: close\r\n\r\n\
+                  This is a SOCKS5 proxy, not an HTTP proxy. Configure SOCKS5 instead.\n",
+            )),
+            Greeting::Tls => Some((
+                "client started a TLS handshake; configure it to use a SOCKS5 proxy, not an HTTPS \
+                 proxy",
+                // Fatal handshake_failure alert.
+                &[0x15, 0x03, 0x01, 0x00, 0x02, 0x02, 0x28],
+            )),
+            Greeting::Socks4 => Some((
+                "client speaks SOCKS4; only SOCKS5 is supported (e.g. use socks5h:// in curl)",
+                // SOCKS4 "request rejected or failed".
+                &[0x00, 0x5b, 0, 0, 0, 0, 0, 0],
+            )),
+            Greeting::Socks5 | Greeting::Unknown => None,
+        }
+    }
+}
+
+/// Answers clients that speak HTTP, TLS or SOCKS4 to the SOCKS port with an
+/// error they can display, instead of failing the SOCKS5 handshake
+/// silently. Returns whether the connection was rejected.
+async fn reject_misdirected(socket: &mut TcpStream) -> anyhow::Result<bool> {
+    let mut prefix = [0u8; 8];
+    let n = socket.peek(&mut prefix).await?;
+    let greeting = Greeting::detect(&prefix[..n]);
+    let Some((hint, reply)) = greeting.rejection() else {
+        return Ok(false);
+    };
+
+    warn!(
+        "Rejecting connection from {}: {}",
+        socket
+            .peer_addr()
+            .map_or_else(|_| "unknown peer".to_string(), |addr| addr.to_string()),
+        hint
+    );
+    // Best effort: the client may already have given up. Closing with its
+    // request still unread would reset the connection and could discard
+    // the reply, so drain the input for a moment after sending it.
+    if socket.write_all(reply).await.is_ok() && socket.shutdown().await.is_ok() {
+        let mut sink = [0u8; 1024];
+        let _ = tokio::time::timeout(Duration::from_secs(1), async {
+            while matches!(socket.read(&mut sink).await, Ok(n) if n > 0) {}
+        })
+        .await;
+    }
+    Ok(true)
+}
+
+/// Bytes relayed over one SOCKS connection, counted as they are written so
+/// the totals hold however the transfer ends.
+#[derive(Debug, Default)]
+struct Relayed {
+    /// From the client to the destination.
+    sent: AtomicU64,
+    /// From the destination to the client.
+    received: AtomicU64,
+}
+
+impl Relayed {
+    fn totals(&self) -> (u64, u64) {
+        (
+            self.sent.load(Ordering::Relaxed),
+            self.received.load(Ordering::Relaxed),
+        )
+    }
+}
+
+/// A stream that adds the bytes written to it to `written`.
+struct Counted<'a, S> {
+    inner: S,
+    written: &'a AtomicU64,
+}
+
+impl<'a, S> Counted<'a, S> {
+    fn new(inner: S, written: &'a AtomicU64) -> Self {
+        Self { inner, written }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Counted<'_, S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Counted<'_, S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let result = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = result {
+            self.written.fetch_add(n as u64, Ordering::Relaxed);
+        }
+        result
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// Relays between the client and `channel`, counting the bytes into
+/// `relayed` as they go.
 async fn run_tcp_proxy(
+    session: &Transport,
     proto: Socks5ServerProtocol<TcpStream, states::CommandRead>,
-    mut socket: impl AsyncRead + AsyncWrite + Unpin,
-) -> anyhow::Result<TcpStream> {
+    channel: ForwardStream,
+    target: &Target,
+    options: &SocksOptions,
+    relayed: &Relayed,
+) -> anyhow::Result<()> {
     debug!("Connected to remote destination");
 
-    let mut inner = proto
+    let inner = proto
         .reply_success((Ipv4Addr::new(127, 0, 0, 1), 0).into())
         .await?;
+    let mut client = Counted::new(inner, &relayed.received);
+    let mut channel = Counted::new(channel, &relayed.sent);
 
-    fast_socks5::server::transfer(&mut inner, &mut socket).await;
+    if let Some(timeout) = options.redial
+        && !redial_until_first_byte(session, &mut client, &mut channel, target, timeout, options)
+            .await?
+    {
+        return Ok(());
+    }
+
+    let transfer = copy_until_shutdown(
+        &mut client,
+        &mut channel,
+        options.buffer_size,
+        session.shutdown(),
+    )
+    .await;
+    match transfer {
+        Some(Ok(_)) => {
+            let (sent, received) = relayed.totals();
+            debug!("transfer closed ({}, {})", sent, received);
+        }
+        Some(Err(err)) => {
+            if session.is_closed().await {
+                warn!(
+                    "SSH session lost mid-transfer to {}; connection cannot be resumed once data \
+                     has been exchanged",
+                    target
+                );
+            } else {
+                error!("transfer error: {:?}", err);
+            }
+        }
+        None => debug!("Closed transfer to {} for shutdown", target),
+    }
+    Ok(())
+}
 
-    Ok(inner)
+/// Relays the first chunk of data in either direction, re-opening the
+/// channel whenever the SSH session is lost before anything was exchanged.
+///
+/// Returns `false` if the session shut down before anything was sent.
+async fn redial_until_first_byte(
+    session: &Transport,
+    client: &mut Counted<'_, TcpStream>,
+    channel: &mut Counted<'_, ForwardStream>,
+    target: &Target,
+    timeout: Duration,
+    options: &SocksOptions,
+) -> anyhow::Result<bool> {
+    let mut client_buf = vec![0u8; options.buffer_size];
+    let mut channel_buf = vec![0u8; options.buffer_size];
+
+    loop {
+        let generation = *session.reconnects().borrow();
+
+        tokio::select! {
+            n = client.read(&mut client_buf) => {
+                let n = n?;
+                if n == 0 {
+                    // The client half-closed; the regular copy sees the same
+                    // EOF, passes it on and relays what the target answers,
+                    // e.g. a banner.
+                    return Ok(true);
+                }
+                if let Err(e) = channel.write_all(&client_buf[..n]).await {
+                    if !session_lost(session, generation).await {
+                        return Err(e.into());
+                    }
+                    channel.inner = redial(session, target, generation, timeout).await?;
+                    // What reached the lost channel never arrived.
+                    channel.written.store(0, Ordering::Relaxed);
+                    channel.write_all(&client_buf[..n]).await?;
+                }
+                return Ok(true);
+            }
+            n = channel.read(&mut channel_buf) => {
+                match n {
+                    Ok(n) if n > 0 => {
+                        client.write_all(&channel_buf[..n]).await?;
+                        return Ok(true);
+                    }
+                    result => {
+                        if !session_lost(session, generation).await {
+                            // The target closed (or failed) on its own; let
+                            // the regular copy propagate it to the client.
+                            result?;
+                            return Ok(true);
+                        }
+                        channel.inner = redial(session, target, generation, timeout).await?;
+                    }
+                }
+            }
+            () = session.shutdown().cancelled() => {
+                let _ = client.shutdown().await;
+                let _ = channel.shutdown().await;
+                return Ok(false);
+            }
+        }
+    }
+}
+
+/// Opens a channel to `target`, retrying once on the next SSH session if the
+/// current one was lost and redial is enabled.
+async fn open_channel(
+    session: &Transport,
+    target: &Target,
+    redial_timeout: Option<Duration>,
+) -> anyhow::Result<ForwardStream> {
+    let generation = *session.reconnects().borrow();
+    match connect(session, target).await {
+        Ok(stream) => Ok(stream),
+        Err(e) if e.is::<UpstreamRefused>() => Err(e),
+        Err(e) => match redial_timeout {
+            Some(timeout) if session_lost(session, generation).await => {
+                redial(session, target, generation, timeout).await
+            }
+            _ if session.is_closed().await => Err(e.context(SessionLost)),
+            _ => Err(e),
+        },
+    }
+}
+
+async fn redial(
+    session: &Transport,
+    target: &Target,
+    generation: u64,
+    timeout: Duration,
+) -> anyhow::Result<ForwardStream> {
+    info!("SSH session lost, waiting to re-dial {}", target);
+
+    let mut reconnects = session.reconnects();
+    match tokio::time::timeout(timeout, reconnects.wait_for(|g| *g != generation)).await {
+        Ok(Ok(_)) => {}
+        _ => return Err(anyhow::Error::new(SessionLost)),
+    }
+
+    let stream = connect(session, target).await?;
+    info!("Re-dialed {} after SSH reconnect", target);
+    Ok(stream)
+}
+
+async fn connect(session: &Transport, target: &Target) -> anyhow::Result<ForwardStream> {
+    match target {
+        Target::Addr(addr) => session.open_forward_stream(*addr).await,
+        Target::Host(host, port) => session.open_forward_stream_to_host(host, *port).await,
+        Target::Upstream {
+            proxy: (proxy_host, proxy_port),
+            host,
+            port,
+        } => {
+            let mut stream = session
+                .open_forward_stream_to_host(proxy_host, *proxy_port)
+                .await?;
+            socks5_connect(&mut stream, host, *port).await?;
+            Ok(stream)
+        }
+    }
+}
+
+/// Performs a no-auth SOCKS5 CONNECT to `host:port` over `stream`, leaving
+/// name resolution to the proxy.
+async fn socks5_connect<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    host: &str,
+    port: u16,
+) -> anyhow::Result<()> {
+    let host_len = u8::try_from(host.len())
+        .map_err(|_| anyhow::anyhow!("host name too long for SOCKS5: {}", host))?;
+
+    stream.write_all(&[0x05, 0x01, 0x00]).await?;
+    let mut choice = [0u8; 2];
+    stream.read_exact(&mut choice).await?;
+    if choice != [0x05, 0x00] {
+        anyhow::bail!("upstream SOCKS5 proxy requires authentication");
+    }
+
+    let mut request = vec![0x05, 0x01, 0x00, 0x03, host_len];
+    request.extend_from_slice(host.as_bytes());
+    request.extend_from_slice(&port.to_be_bytes());
+    stream.write_all(&request).await?;
+
+    let mut reply = [0u8; 4];
+    stream.read_exact(&mut reply).await?;
+    if reply[1] != 0x00 {
+        return Err(UpstreamRefused(reply[1]).into());
+    }
+    let bound_len = match reply[3] {
+        0x01 => 4,
+        0x04 => 16,
+        0x03 => usize::from(stream.read_u8().await?),
+        other => anyhow::bail!("upstream SOCKS5 proxy sent address type {}", other),
+    };
+    let mut bound = vec![0u8; bound_len + 2];
+    stream.read_exact(&mut bound).await?;
+    Ok(())
+}
+
+async fn session_lost(session: &Transport, generation: u64) -> bool {
+    *session.reconnects().borrow() != generation || session.is_closed().await
+}
+
+/// Maps a channel-open failure to the most specific SOCKS reply.
+fn reply_for(err: &anyhow::Error) -> ReplyError {
+    if err.is::<SessionLost>() {
+        return ReplyError::NetworkUnreachable;
+    }
+    if let Some(UpstreamRefused(code)) = err.downcast_ref() {
+        return match code {
+            0x02 => ReplyError::ConnectionNotAllowed,
+            0x03 => ReplyError::NetworkUnreachable,
+            0x04 => ReplyError::HostUnreachable,
+            0x05 => ReplyError::ConnectionRefused,
+            0x06 => ReplyError::TtlExpired,
+            _ => ReplyError::GeneralFailure,
+        };
+    }
+    match err.downcast_ref::<russh::Error>() {
+        Some(russh::Error::ChannelOpenFailure(reason)) => match reason {
+            ChannelOpenFailure::AdministrativelyProhibited => ReplyError::ConnectionNotAllowed,
+            ChannelOpenFailure::ConnectFailed => ReplyError::HostUnreachable,
+            _ => ReplyError::GeneralFailure,
+        },
+        _ => ReplyError::HostUnreachable,
+    }
 }
 
 async fn try_notify<T, P: AsyncRead + AsyncWrite + Unpin>(
@@ -80,3 +1067,393 @@ async fn try_notify<T, P: AsyncRead + AsyncWrite + Unpin>(
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use tokio_util::sync::CancellationToken;
+
+    use super::*;
+
+    #[test]
+    fn test_circuit_breaker() {
+        let breaker = CircuitBreaker::new(3, Duration::from_secs(30));
+        let now = Instant::now();
+        let refused = Err(ReplyError::ConnectionRefused);
+
+        assert!(!breaker.record_at("a:80", refused, now));
+        assert!(!breaker.record_at("a:80", refused, now));
+        assert!(breaker.check_at("a:80", now).is_ok());
+        assert!(breaker.record_at("a:80", refused, now));
+        let (reply, remaining) = breaker.check_at("a:80", now).unwrap_err();
+        assert!(matches!(reply, ReplyError::ConnectionRefused));
+        assert_eq!(remaining, Duration::from_secs(30));
+        assert!(breaker.check_at("b:80", now).is_ok());
+
+        // After the cooldown one attempt goes through while the others wait
+        // for it; failing, it opens the breaker again at once.
+        let later = now + Duration::from_secs(31);
+        assert!(breaker.check_at("a:80", later).is_ok());
+        assert!(breaker.check_at("a:80", later).is_err());
+        assert!(breaker.record_at("a:80", refused, later));
+        assert!(breaker.check_at("a:80", later).is_err());
+
+        // A success forgets the failures.
+        let later = later + Duration::from_secs(31);
+        assert!(breaker.check_at("a:80", later).is_ok());
+        breaker.record_at("a:80", Ok(()), later);
+        assert!(!breaker.record_at("a:80", refused, later));
+        assert!(breaker.check_at("a:80", later).is_ok());
+
+        // A trial that never reports back holds the others off for one
+        // cooldown only.
+        for _ in 0..3 {
+            breaker.record_at("c:80", refused, now);
+        }
+        let trial = now + Duration::from_secs(31);
+        assert!(breaker.check_at("c:80", trial).is_ok());
+        assert!(breaker.check_at("c:80", trial).is_err());
+        assert!(
+            breaker
+                .check_at("c:80", trial + Duration::from_secs(31))
+                .is_ok()
+        );
+
+        // Failures older than the cooldown are forgotten once another
+        // destination fails.
+        let much_later = later + Duration::from_secs(120);
+        breaker.record_at("d:80", refused, much_later);
+        let destinations = breaker.destinations.lock().unwrap();
+        assert_eq!(destinations.keys().collect::<Vec<_>>(), ["d:80"]);
+
+        let disabled = CircuitBreaker::new(0, Duration::from_secs(30));
+        for _ in 0..10 {
+            assert!(!disabled.record_at("a:80", refused, now));
+        }
+        assert!(disabled.check_at("a:80", now).is_ok());
+    }
+
+    #[test]
+    fn test_open_connections() {
+        let open = Arc::new(OpenConnections::default());
+        let first = open.open("example.com:443".to_string());
+        let second = open.open("example.com:443".to_string());
+        let other = open.open("10.0.0.1:22".to_string());
+        assert_eq!(
+            open.by_destination(),
+            BTreeMap::from([
+                ("10.0.0.1:22".to_string(), 1),
+                ("example.com:443".to_string(), 2)
+            ])
+        );
+        drop((first, other));
+        assert_eq!(
+            open.by_destination(),
+            BTreeMap::from([("example.com:443".to_string(), 1)])
+        );
+        drop(second);
+        assert!(open.by_destination().is_empty());
+    }
+
+    #[test]
+    fn test_reply_for_channel_open_failures() {
+        let prohibited = anyhow::Error::new(russh::Error::ChannelOpenFailure(
+            ChannelOpenFailure::AdministrativelyProhibited,
+        ));
+        assert!(matches!(
+            reply_for(&prohibited),
+            ReplyError::ConnectionNotAllowed
+        ));
+
+        let failed = anyhow::Error::new(russh::Error::ChannelOpenFailure(
+            ChannelOpenFailure::ConnectFailed,
+        ));
+        assert!(matches!(reply_for(&failed), ReplyError::HostUnreachable));
+    }
+
+    fn resolvers(rules: &[(&str, &str)]) -> Resolvers {
+        let rules: Vec<(String, ResolvePolicy)> = rules
+            .iter()
+            .map(|(suffix, policy)| (suffix.to_string(), policy.parse().unwrap()))
+            .collect();
+        Resolvers::new(rules.iter().map(|(suffix, policy)| (suffix, policy)))
+    }
+
+    #[test]
+    fn test_resolve_policy_parse() {
+        assert_eq!("remote".parse(), Ok(ResolvePolicy::Remote));
+        assert_eq!("local".parse(), Ok(ResolvePolicy::Local));
+        assert_eq!(
+            "socks5://127.0.0.1:9050".parse(),
+            Ok(ResolvePolicy::Socks {
+                host: "127.0.0.1".to_string(),
+                port: 9050
+            })
+        );
+        assert_eq!(
+            "socks5h://[::1]:9050".parse(),
+            Ok(ResolvePolicy::Socks {
+                host: "::1".to_string(),
+                port: 9050
+            })
+        );
+        for invalid in [
+            "tor",
+            "socks5://127.0.0.1",
+            "socks5://:9050",
+            "http://proxy:3128",
+        ] {
+            assert!(invalid.parse::<ResolvePolicy>().is_err(), "{invalid}");
+        }
+
+        for policy in [
+            "local",
+            "remote",
+            "socks5://tor:9050",
+            "socks5://[::1]:9050",
+        ] {
+            assert_eq!(policy.parse::<ResolvePolicy>().unwrap().to_string(), policy);
+        }
+    }
+
+    #[test]
+    fn test_resolvers_longest_suffix_wins() {
+        let resolvers = resolvers(&[
+            (".onion", "socks5://127.0.0.1:9050"),
+            ("internal", "remote"),
+            ("public.internal", "local"),
+        ]);
+
+        assert!(matches!(
+            resolvers.policy("duckduckgogg42xjoc72x3sjasowoarfbgcmvfimaftt6twagswzczad.onion"),
+            ResolvePolicy::Socks { port: 9050, .. }
+        ));
+        assert_eq!(resolvers.policy("git.internal"), &ResolvePolicy::Remote);
+        assert_eq!(resolvers.policy("GIT.Internal."), &ResolvePolicy::Remote);
+        assert_eq!(resolvers.policy("internal"), &ResolvePolicy::Remote);
+        assert_eq!(
+            resolvers.policy("www.public.internal"),
+            &ResolvePolicy::Local
+        );
+        assert_eq!(resolvers.policy("notinternal"), &ResolvePolicy::Local);
+        assert_eq!(resolvers.policy("example.com"), &ResolvePolicy::Local);
+    }
+
+    #[tokio::test]
+    async fn test_socks5_connect_sends_domain() {
+        let (mut client, mut proxy) = tokio::io::duplex(256);
+        let upstream = tokio::spawn(async move {
+            let mut greeting = [0u8; 3];
+            proxy.read_exact(&mut greeting).await.unwrap();
+            assert_eq!(greeting, [0x05, 0x01, 0x00]);
+            proxy.write_all(&[0x05, 0x00]).await.unwrap();
+
+            let mut request = [0u8; 5 + 11 + 2];
+            proxy.read_exact(&mut request).await.unwrap();
+            assert_eq!(&request[..5], &[0x05, 0x01, 0x00, 0x03, 11]);
+            assert_eq!(&request[5..16], b"xyz.onion.i");
+            assert_eq!(&request[16..], &80u16.to_be_bytes());
+            proxy
+                .write_all(&[0x05, 0x00, 0x00, 0x01, 0, 0, 0, 0, 0, 0])
+                .await
+                .unwrap();
+            proxy.write_all(b"payload").await.unwrap();
+        });
+
+        socks5_connect(&mut client, "xyz.onion.i", 80)
+            .await
+            .unwrap();
+        let mut payload = [0u8; 7];
+        client.read_exact(&mut payload).await.unwrap();
+        assert_eq!(&payload, b"payload");
+        upstream.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_socks5_connect_refused() {
+        let (mut client, mut proxy) = tokio::io::duplex(256);
+        tokio::spawn(async move {
+            let mut greeting = [0u8; 3];
+            proxy.read_exact(&mut greeting).await.unwrap();
+            proxy.write_all(&[0x05, 0x00]).await.unwrap();
+            let mut request = [0u8; 5 + 10 + 2];
+            proxy.read_exact(&mut request).await.unwrap();
+            proxy
+                .write_all(&[0x05, 0x04, 0x00, 0x01, 0, 0, 0, 0, 0, 0])
+                .await
+                .unwrap();
+        });
+
+        let err = socks5_connect(&mut client, "gone.onion", 443)
+            .await
+            .unwrap_err();
+        assert!(matches!(reply_for(&err), ReplyError::HostUnreachable));
+    }
+
+    #[test]
+    fn test_greeting_detect() {
+        assert_eq!(Greeting::detect(&[0x05, 0x01, 0x00]), Greeting::Socks5);
+        assert_eq!(Greeting::detect(b"\x04\x01\x01\xbb"), Greeting::Socks4);
+        assert_eq!(Greeting::detect(b"GET / HTTP/1.1\r\n"), Greeting::Http);
+        assert_eq!(Greeting::detect(b"CONNECT "), Greeting::Http);
+        assert_eq!(
+            Greeting::detect(&[0x16, 0x03, 0x01, 0x02, 0x00, 0x01]),
+            Greeting::Tls
+        );
+        assert_eq!(Greeting::detect(b"GE"), Greeting::Unknown);
+        assert_eq!(Greeting::detect(b"SSH-2.0-"), Greeting::Unknown);
+        assert_eq!(Greeting::detect(&[]), Greeting::Unknown);
+    }
+
+    #[test]
+    fn test_http_rejection_content_length() {
+        let (_, reply) = Greeting::Http.rejection().unwrap();
+        let reply = std::str::from_utf8(reply).unwrap();
+        let (headers, body) = reply.split_once("\r\n\r\n").unwrap();
+        assert!(headers.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+        assert!(headers.contains(&format!("Content-Length: {}", body.len())));
+    }
+
+    #[tokio::test]
+    async fn test_reject_misdirected_http_client() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (mut server, _) = listener.accept().await.unwrap();
+
+        client
+            .write_all(b"GET http://example.com/ HTTP/1.1\r\nHost: example.com\r\n\r\n")
+            .await
+            .unwrap();
+        client.shutdown().await.unwrap();
+        assert!(reject_misdirected(&mut server).await.unwrap());
+        drop(server);
+
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 400 Bad Request"));
+        assert!(response.ends_with("Configure SOCKS5 instead.\n"));
+    }
+
+    #[tokio::test]
+    async fn test_reject_misdirected_passes_socks5() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (mut server, _) = listener.accept().await.unwrap();
+
+        client.write_all(&[0x05, 0x01, 0x00]).await.unwrap();
+        assert!(!reject_misdirected(&mut server).await.unwrap());
+
+        // Peeking leaves the greeting for the SOCKS5 handshake.
+        let mut greeting = [0u8; 3];
+        server.read_exact(&mut greeting).await.unwrap();
+        assert_eq!(greeting, [0x05, 0x01, 0x00]);
+    }
+
+    #[tokio::test]
+    async fn test_relayed_survives_failed_transfer() {
+        let (mut client, proxy_client) = tokio::io::duplex(64);
+        let (proxy_channel, mut destination) = tokio::io::duplex(64);
+        let relayed = Relayed::default();
+        let mut proxy_client = Counted::new(proxy_client, &relayed.received);
+        let mut proxy_channel = Counted::new(proxy_channel, &relayed.sent);
+
+        let transfer = tokio::io::copy_bidirectional(&mut proxy_client, &mut proxy_channel);
+        let peers = async {
+            client.write_all(b"hello").await.unwrap();
+            let mut buf = [0u8; 5];
+            destination.read_exact(&mut buf).await.unwrap();
+            destination.write_all(b"hi").await.unwrap();
+            client.read_exact(&mut buf[..2]).await.unwrap();
+            // The destination goes away; the next write to it fails.
+            drop(destination);
+            client.write_all(b"more").await.unwrap();
+        };
+        let (result, ()) = tokio::join!(transfer, peers);
+
+        assert!(result.is_err());
+        assert_eq!(relayed.totals(), (5, 2));
+    }
+
+    #[tokio::test]
+    async fn test_relayed_survives_shutdown() {
+        let (mut client, proxy_client) = tokio::io::duplex(64);
+        let (proxy_channel, mut destination) = tokio::io::duplex(64);
+        let relayed = Relayed::default();
+        let mut proxy_client = Counted::new(proxy_client, &relayed.received);
+        let mut proxy_channel = Counted::new(proxy_channel, &relayed.sent);
+        let shutdown = CancellationToken::new();
+
+        let transfer = copy_until_shutdown(&mut proxy_client, &mut proxy_channel, 64, &shutdown);
+        let peers = async {
+            client.write_all(b"hello").await.unwrap();
+            let mut buf = [0u8; 5];
+            destination.read_exact(&mut buf).await.unwrap();
+            destination.write_all(b"hi").await.unwrap();
+            client.read_exact(&mut buf[..2]).await.unwrap();
+            shutdown.cancel();
+        };
+        let (result, ()) = tokio::join!(transfer, peers);
+
+        assert!(result.is_none());
+        assert_eq!(relayed.totals(), (5, 2));
+        // Both sides see the end of the stream.
+        assert_eq!(client.read(&mut [0u8; 1]).await.unwrap(), 0);
+        assert_eq!(destination.read(&mut [0u8; 1]).await.unwrap(), 0);
+    }
+
+    #[test]
+    fn test_reply_for_session_lost() {
+        let lost = anyhow::anyhow!("channel open failed").context(SessionLost);
+        assert!(matches!(reply_for(&lost), ReplyError::NetworkUnreachable));
+
+        let lost = anyhow::Error::new(SessionLost);
+        assert!(matches!(reply_for(&lost), ReplyError::NetworkUnreachable));
+    }
+
+    fn acl(rules: &[&str]) -> Acl {
+        let rules: Vec<String> = rules.iter().map(|rule| rule.to_string()).collect();
+        Acl::parse(&rules).unwrap()
+    }
+
+    #[test]
+    fn test_acl() {
+        let ip = |s: &str| s.parse::<IpAddr>().unwrap();
+        assert!(acl(&[]).allows(ip("203.0.113.7")));
+
+        let lan = acl(&["deny 192.168.1.13", "allow 192.168.1.0/24", "allow ::1"]);
+        assert!(lan.allows(ip("192.168.1.20")));
+        assert!(lan.allows(ip("::ffff:192.168.1.20")));
+        assert!(lan.allows(ip("::1")));
+        assert!(!lan.allows(ip("192.168.1.13")));
+        assert!(!lan.allows(ip("10.0.0.1")));
+
+        let blocklist = acl(&["deny 10.0.0.0/8"]);
+        assert!(!blocklist.allows(ip("10.1.2.3")));
+        assert!(blocklist.allows(ip("127.0.0.1")));
+
+        for rule in [
+            "permit 10.0.0.0/8",
+            "allow",
+            "deny 10.0.0.0/33",
+            "allow example.com",
+        ] {
+            let error = Acl::parse(&[rule.to_string()]).unwrap_err().to_string();
+            assert!(error.contains("invalid acl rule"), "{error}");
+        }
+    }
+
+    #[test]
+    fn test_credentials() {
+        let credentials = Credentials {
+            username: "alice".to_string(),
+            password: Zeroizing::new("secret".to_string()),
+        };
+        assert!(credentials.check("alice", "secret"));
+        assert!(!credentials.check("alice", "Secret"));
+        assert!(!credentials.check("bob", "secret"));
+        assert!(!format!("{credentials:?}").contains("secret"));
+    }
+}
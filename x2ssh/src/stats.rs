@@ -0,0 +1,285 @@
+//! Traffic totals kept across sessions for `x2ssh stats`: each session
+//! appends one JSON line to the stats file when it ends, with its bytes in
+//! each direction and, for SOCKS, per destination.
+
+use std::collections::BTreeMap;
+use std::fs::OpenOptions;
+use std::io::BufRead;
+use std::io::BufReader;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use serde::Deserialize;
+use serde::Serialize;
+
+use crate::journal::JournalEvent;
+use crate::status::format_utc;
+use crate::status::now_ms;
+
+/// Traffic through one destination, or in total.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Usage {
+    pub connections: u64,
+    /// From the client to the destination.
+    pub sent: u64,
+    pub received: u64,
+}
+
+impl Usage {
+    fn add(&mut self, other: Usage) {
+        self.connections += other.connections;
+        self.sent += other.sent;
+        self.received += other.received;
+    }
+
+    fn total(&self) -> u64 {
+        self.sent + self.received
+    }
+}
+
+/// One line of the stats file.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SessionRecord {
+    pub started_ms: u64,
+    pub ended_ms: u64,
+    pub mode: String,
+    /// `user@host:port` of the SSH server.
+    pub server: String,
+    /// SOCKS connections and VPN tunnel traffic together.
+    pub usage: Usage,
+    /// SOCKS traffic by the destination the client asked for.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub destinations: BTreeMap<String, Usage>,
+}
+
+/// Adds up a running session's traffic and appends it to the stats file
+/// when the session ends.
+pub struct StatsRecorder {
+    path: PathBuf,
+    record: Mutex<SessionRecord>,
+}
+
+impl StatsRecorder {
+    /// Starts recording a session to `path`, checking now that the file can
+    /// be written rather than when the session ends.
+    pub fn open(path: &Path, mode: &str, server: &str) -> anyhow::Result<Self> {
+        if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            std::fs::create_dir_all(dir)?;
+        }
+        OpenOptions::new().create(true).append(true).open(path)?;
+        let started_ms = now_ms();
+        Ok(Self {
+            path: path.to_path_buf(),
+            record: Mutex::new(SessionRecord {
+                started_ms,
+                ended_ms: started_ms,
+                mode: mode.to_string(),
+                server: server.to_string(),
+                usage: Usage::default(),
+                destinations: BTreeMap::new(),
+            }),
+        })
+    }
+
+    /// Counts the traffic of a finished forward.
+    pub fn observe(&self, event: &JournalEvent) {
+        let JournalEvent::Forward {
+            destination,
+            sent,
+            received,
+            ..
+        } = event
+        else {
+            return;
+        };
+        let usage = Usage {
+            connections: 1,
+            sent: *sent,
+            received: *received,
+        };
+        let mut record = self.record.lock().unwrap();
+        record.usage.add(usage);
+        record
+            .destinations
+            .entry(destination.clone())
+            .or_default()
+            .add(usage);
+    }
+
+    /// Appends the session, with `tunnel_sent` and `tunnel_received` bytes
+    /// of VPN traffic on top of the forwards observed.
+    pub fn finish(&self, tunnel_sent: u64, tunnel_received: u64) -> anyhow::Result<()> {
+        let mut record = self.record.lock().unwrap().clone();
+        record.ended_ms = now_ms();
+        record.usage.sent += tunnel_sent;
+        record.usage.received += tunnel_received;
+        let mut line = serde_json::to_vec(&record)?;
+        line.push(b'\n');
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?
+            .write_all(&line)?;
+        Ok(())
+    }
+}
+
+/// Reads every session in the stats file at `path`.
+pub fn read(path: &Path) -> anyhow::Result<Vec<SessionRecord>> {
+    let file = std::fs::File::open(path)
+        .map_err(|e| anyhow::anyhow!("cannot open {}: {}", path.display(), e))?;
+    let mut records = Vec::new();
+    for (n, line) in BufReader::new(file).lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        records.push(
+            serde_json::from_str(&line)
+                .map_err(|e| anyhow::anyhow!("{}:{}: {}", path.display(), n + 1, e))?,
+        );
+    }
+    Ok(records)
+}
+
+/// Sessions added up by UTC day and by destination.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct Totals {
+    pub sessions: usize,
+    pub usage: Usage,
+    /// By the UTC day sessions ended on, `YYYY-MM-DD`.
+    pub days: BTreeMap<String, Usage>,
+    pub destinations: BTreeMap<String, Usage>,
+}
+
+impl Totals {
+    /// Adds up the sessions that ended at or after `since_ms`.
+    pub fn of(records: &[SessionRecord], since_ms: u64) -> Self {
+        let mut totals = Self::default();
+        for record in records.iter().filter(|record| record.ended_ms >= since_ms) {
+            totals.sessions += 1;
+            totals.usage.add(record.usage);
+            let day = format_utc(record.ended_ms)[..10].to_string();
+            totals.days.entry(day).or_default().add(record.usage);
+            for (destination, usage) in &record.destinations {
+                totals
+                    .destinations
+                    .entry(destination.clone())
+                    .or_default()
+                    .add(*usage);
+            }
+        }
+        totals
+    }
+
+    /// A total line and one per day, then with `destinations` one per
+    /// destination, busiest first.
+    pub fn render(&self, destinations: bool) -> String {
+        let mut out = format!(
+            "{} session(s): {} sent, {} received\n",
+            self.sessions,
+            format_bytes(self.usage.sent),
+            format_bytes(self.usage.received)
+        );
+        for (day, usage) in &self.days {
+            out.push_str(&format!(
+                "  {}: {} sent, {} received\n",
+                day,
+                format_bytes(usage.sent),
+                format_bytes(usage.received)
+            ));
+        }
+        if destinations && !self.destinations.is_empty() {
+            out.push_str("by destination:\n");
+            let mut busiest: Vec<_> = self.destinations.iter().collect();
+            busiest.sort_by_key(|(_, usage)| std::cmp::Reverse(usage.total()));
+            for (destination, usage) in busiest {
+                out.push_str(&format!(
+                    "  {}: {} connection(s), {} sent, {} received\n",
+                    destination,
+                    usage.connections,
+                    format_bytes(usage.sent),
+                    format_bytes(usage.received)
+                ));
+            }
+        }
+        out
+    }
+}
+
+/// Formats a byte count in binary units, e.g. `1.5 MiB`.
+fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+    let mut value = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", value, UNITS[unit])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stats_roundtrip() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("stats/usage.jsonl");
+        let forward = |destination: &str, sent, received| JournalEvent::Forward {
+            peer: None,
+            destination: destination.to_string(),
+            sent,
+            received,
+        };
+
+        let recorder = StatsRecorder::open(&path, "socks", "u@h:22").unwrap();
+        recorder.observe(&forward("example.com:443", 100, 4000));
+        recorder.observe(&forward("example.com:443", 50, 2000));
+        recorder.observe(&forward("db:5432", 10, 20));
+        recorder.observe(&JournalEvent::SessionEnd {
+            sent: 0,
+            received: 0,
+        });
+        recorder.finish(0, 0).unwrap();
+        StatsRecorder::open(&path, "vpn", "u@h:22")
+            .unwrap()
+            .finish(1 << 20, 3 << 20)
+            .unwrap();
+
+        let records = read(&path).unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].usage, Usage {
+            connections: 3,
+            sent: 160,
+            received: 6020,
+        });
+        assert!(records[1].destinations.is_empty());
+
+        let totals = Totals::of(&records, 0);
+        assert_eq!(totals.sessions, 2);
+        assert_eq!(totals.destinations["example.com:443"].connections, 2);
+        let text = totals.render(true);
+        assert!(text.starts_with("2 session(s): 1.0 MiB sent, 3.0 MiB received\n"));
+        let busiest = text.find("example.com:443").unwrap();
+        assert!(busiest < text.find("db:5432").unwrap(), "{text}");
+        assert_eq!(Totals::of(&records, u64::MAX).sessions, 0);
+
+        assert_eq!(format_bytes(1023), "1023 B");
+        assert_eq!(format_bytes(1536), "1.5 KiB");
+
+        std::fs::write(&path, "{\"mode\":").unwrap();
+        assert!(
+            read(&path)
+                .unwrap_err()
+                .to_string()
+                .contains("usage.jsonl:1:")
+        );
+    }
+}
@@ -0,0 +1,788 @@
+//! Reconnect timeline and tunnel traffic of a running session, published as
+//! a status file so `x2ssh status` can show that packets flow and
+//! `--history` when and why the tunnel dropped.
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::AtomicU32;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+use serde::Deserialize;
+use serde::Serialize;
+use tracing::warn;
+
+use crate::metrics::Snapshot;
+use crate::retry::ErrorClass;
+
+/// Timeline entries kept per session; older ones are dropped first.
+pub const HISTORY_LEN: usize = 100;
+
+/// Why a session had to be re-established.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DisconnectCause {
+    /// A periodic health check could not open a channel.
+    HealthCheck,
+    /// The server or the network closed the SSH connection.
+    RemoteClosed,
+    /// The local address towards the server changed.
+    NetworkChange,
+    /// The VPN agent stopped answering keepalives.
+    KeepaliveTimeout,
+    /// The VPN agent exited while the SSH session stayed up.
+    AgentExited,
+}
+
+impl fmt::Display for DisconnectCause {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::HealthCheck => "health check failed",
+            Self::RemoteClosed => "connection closed by remote",
+            Self::NetworkChange => "local network changed",
+            Self::KeepaliveTimeout => "VPN agent stopped answering keepalives",
+            Self::AgentExited => "VPN agent exited",
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum TimelineEvent {
+    Disconnected {
+        cause: DisconnectCause,
+    },
+    /// A new SSH session is up; `downtime_ms` counts from the disconnect.
+    Reconnected {
+        downtime_ms: u64,
+        attempts: u32,
+    },
+    ReconnectFailed {
+        attempts: u32,
+        /// What the last attempt failed with; absent in status files of
+        /// older versions.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        class: Option<ErrorClass>,
+        /// From the disconnect to giving up; 0 in status files of older
+        /// versions.
+        #[serde(default)]
+        downtime_ms: u64,
+    },
+    /// A fresh VPN agent took over forwarding.
+    AgentRestarted,
+    /// Health probes got slow or started failing, past the thresholds.
+    Degraded {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        rtt_ms: Option<u64>,
+        failed: u32,
+        probes: u32,
+    },
+    /// Health probes are back within the thresholds.
+    Recovered {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        rtt_ms: Option<u64>,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TimelineEntry {
+    pub ts_ms: u64,
+    #[serde(flatten)]
+    pub event: TimelineEvent,
+}
+
+/// What the status file says about the session besides its history.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SessionInfo {
+    pub pid: u32,
+    pub mode: String,
+    pub destination: String,
+    pub started_ms: u64,
+    /// The process's start time as `/proc/<pid>/stat` gives it, which tells
+    /// the session from a later process reusing its pid. Absent off Linux
+    /// and in status files of older versions.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub start_time: Option<u64>,
+}
+
+impl SessionInfo {
+    /// Describes this process, started now.
+    pub fn new(mode: &str, destination: &str) -> Self {
+        let pid = std::process::id();
+        Self {
+            pid,
+            mode: mode.to_string(),
+            destination: destination.to_string(),
+            started_ms: now_ms(),
+            start_time: process_start_time(pid),
+        }
+    }
+
+    /// Whether the session's process still runs. On Linux its pid must
+    /// belong to a process started at `start_time`, or, for status files
+    /// without one, to an x2ssh executable.
+    #[cfg(target_os = "linux")]
+    pub fn is_running(&self) -> bool {
+        match self.start_time {
+            Some(start_time) => process_start_time(self.pid) == Some(start_time),
+            None => std::fs::read_link(format!("/proc/{}/exe", self.pid)).is_ok_and(|exe| {
+                exe.file_name()
+                    .and_then(|name| name.to_str())
+                    // " (deleted)" follows once the binary was upgraded.
+                    .is_some_and(|name| name == "x2ssh" || name.starts_with("x2ssh "))
+            }),
+        }
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn is_running(&self) -> bool {
+        is_running(self.pid)
+    }
+}
+
+/// Field 22 of `/proc/<pid>/stat`: when the process started, in clock ticks
+/// after boot.
+#[cfg(target_os = "linux")]
+fn process_start_time(pid: u32) -> Option<u64> {
+    let stat = std::fs::read_to_string(format!("/proc/{pid}/stat")).ok()?;
+    // The command name before it is in parentheses and may hold spaces.
+    let fields = &stat[stat.rfind(')')? + 1..];
+    fields.split_whitespace().nth(19)?.parse().ok()
+}
+
+#[cfg(not(target_os = "linux"))]
+fn process_start_time(_pid: u32) -> Option<u64> {
+    None
+}
+
+/// Whether the status file at `path` may speak for its session: written by
+/// root or by the user the session's process runs as. Anyone can write to
+/// the temporary directory status files fall back to, but should not get
+/// `x2ssh stop` to signal processes of others.
+#[cfg(target_os = "linux")]
+fn owned_by_session(path: &Path, pid: u32) -> bool {
+    use std::os::unix::fs::MetadataExt;
+
+    let (Ok(file), Ok(process)) = (
+        std::fs::metadata(path),
+        std::fs::metadata(format!("/proc/{pid}")),
+    ) else {
+        return false;
+    };
+    file.uid() == 0 || file.uid() == process.uid()
+}
+
+#[cfg(not(target_os = "linux"))]
+fn owned_by_session(_path: &Path, _pid: u32) -> bool {
+    true
+}
+
+/// Packets and bytes through the VPN tunnel so far.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Traffic {
+    pub packets_sent: u64,
+    pub bytes_sent: u64,
+    pub packets_received: u64,
+    pub bytes_received: u64,
+}
+
+impl From<Snapshot> for Traffic {
+    fn from(s: Snapshot) -> Self {
+        Self {
+            packets_sent: s.packets_sent,
+            bytes_sent: s.tunnel_bytes_sent,
+            packets_received: s.packets_received,
+            bytes_received: s.tunnel_bytes_received,
+        }
+    }
+}
+
+/// Contents of a status file.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Status {
+    #[serde(flatten)]
+    pub session: SessionInfo,
+    pub history: Vec<TimelineEntry>,
+    /// Only VPN sessions report traffic; older x2ssh releases leave it out.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub traffic: Option<Traffic>,
+}
+
+impl Status {
+    pub fn reconnects(&self) -> usize {
+        self.history
+            .iter()
+            .filter(|entry| matches!(entry.event, TimelineEvent::Reconnected { .. }))
+            .count()
+    }
+
+    /// One line per session, plus its timeline with `history`.
+    pub fn render(&self, history: bool, now_ms: u64) -> String {
+        let uptime = Duration::from_millis(now_ms.saturating_sub(self.session.started_ms));
+        let mut out = format!(
+            "{} {} (pid {}): up {}, {} reconnect(s)\n",
+            self.session.mode,
+            self.session.destination,
+            self.session.pid,
+            format_elapsed(uptime),
+            self.reconnects()
+        );
+        if let Some(traffic) = &self.traffic {
+            out.push_str(&format!(
+                "  tunnel: {} packets ({} bytes) sent, {} packets ({} bytes) received\n",
+                traffic.packets_sent,
+                traffic.bytes_sent,
+                traffic.packets_received,
+                traffic.bytes_received
+            ));
+        }
+        if history {
+            out.push_str(&self.render_history());
+        }
+        out
+    }
+
+    /// The timeline, one line per entry.
+    pub fn render_history(&self) -> String {
+        let mut out = String::new();
+        if self.history.is_empty() {
+            out.push_str("  no disconnects\n");
+        }
+        for entry in &self.history {
+            let what = match &entry.event {
+                TimelineEvent::Disconnected { cause } => format!("disconnected: {}", cause),
+                TimelineEvent::Reconnected {
+                    downtime_ms,
+                    attempts,
+                } => format!(
+                    "reconnected after {} ({} attempt(s))",
+                    format_elapsed(Duration::from_millis(*downtime_ms)),
+                    attempts
+                ),
+                TimelineEvent::ReconnectFailed {
+                    attempts,
+                    class,
+                    downtime_ms,
+                } => {
+                    let mut what = format!("reconnect gave up after {} attempt(s)", attempts);
+                    if *downtime_ms > 0 {
+                        what.push_str(&format!(
+                            " in {}",
+                            format_elapsed(Duration::from_millis(*downtime_ms))
+                        ));
+                    }
+                    if let Some(class) = class {
+                        what.push_str(&format!(" ({} error)", class));
+                    }
+                    what
+                }
+                TimelineEvent::AgentRestarted => "VPN agent restarted".to_string(),
+                TimelineEvent::Degraded {
+                    rtt_ms,
+                    failed,
+                    probes,
+                } => {
+                    let mut what = "degraded:".to_string();
+                    if let Some(rtt_ms) = rtt_ms {
+                        what.push_str(&format!(" median rtt {}ms,", rtt_ms));
+                    }
+                    what.push_str(&format!(" {} of {} probes failed", failed, probes));
+                    what
+                }
+                TimelineEvent::Recovered { rtt_ms } => match rtt_ms {
+                    Some(rtt_ms) => format!("recovered: median rtt {}ms", rtt_ms),
+                    None => "recovered".to_string(),
+                },
+            };
+            out.push_str(&format!("  {}  {}\n", format_utc(entry.ts_ms), what));
+        }
+        out
+    }
+}
+
+/// Ring buffer of the last [`HISTORY_LEN`] disconnects and reconnects,
+/// optionally mirrored to a status file that is removed on drop.
+pub struct Timeline {
+    entries: Mutex<VecDeque<TimelineEntry>>,
+    /// Last reported by [`set_traffic`](Self::set_traffic); always locked
+    /// after `entries`.
+    traffic: Mutex<Option<Traffic>>,
+    /// The reconnect attempt under way, 0 while connected; only reported
+    /// live, not written to the status file.
+    attempt: AtomicU32,
+    /// Median round-trip time of the recent health probes; live only.
+    rtt: Mutex<Option<Duration>>,
+    /// Whether traffic has been seen to flow end to end; live only.
+    verified: AtomicBool,
+    file: Option<(PathBuf, SessionInfo)>,
+}
+
+impl Default for Timeline {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Timeline {
+    /// A timeline kept in memory only.
+    pub fn new() -> Self {
+        Self {
+            entries: Mutex::new(VecDeque::with_capacity(HISTORY_LEN)),
+            traffic: Mutex::new(None),
+            attempt: AtomicU32::new(0),
+            rtt: Mutex::new(None),
+            verified: AtomicBool::new(false),
+            file: None,
+        }
+    }
+
+    /// A timeline published to `status_file(dir, session.pid)`.
+    pub fn published(dir: &Path, session: SessionInfo) -> anyhow::Result<Self> {
+        std::fs::create_dir_all(dir)?;
+        let timeline = Self {
+            entries: Mutex::new(VecDeque::with_capacity(HISTORY_LEN)),
+            traffic: Mutex::new(None),
+            attempt: AtomicU32::new(0),
+            rtt: Mutex::new(None),
+            verified: AtomicBool::new(false),
+            file: Some((status_file(dir, session.pid), session)),
+        };
+        timeline.publish(&timeline.entries.lock().unwrap())?;
+        Ok(timeline)
+    }
+
+    pub fn record(&self, event: TimelineEvent) {
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() == HISTORY_LEN {
+            entries.pop_front();
+        }
+        entries.push_back(TimelineEntry {
+            ts_ms: now_ms(),
+            event,
+        });
+        if let Err(e) = self.publish(&entries) {
+            warn!("Failed to write status file: {}", e);
+        }
+    }
+
+    /// Updates the traffic in the status file, rewriting it only when the
+    /// counters moved.
+    pub fn set_traffic(&self, traffic: Traffic) {
+        let entries = self.entries.lock().unwrap();
+        {
+            let mut current = self.traffic.lock().unwrap();
+            if *current == Some(traffic) {
+                return;
+            }
+            *current = Some(traffic);
+        }
+        if let Err(e) = self.publish(&entries) {
+            warn!("Failed to write status file: {}", e);
+        }
+    }
+
+    pub fn set_attempt(&self, attempt: u32) {
+        self.attempt.store(attempt, Ordering::Relaxed);
+    }
+
+    pub fn attempt(&self) -> u32 {
+        self.attempt.load(Ordering::Relaxed)
+    }
+
+    pub fn set_rtt(&self, rtt: Option<Duration>) {
+        *self.rtt.lock().unwrap() = rtt;
+    }
+
+    pub fn rtt(&self) -> Option<Duration> {
+        *self.rtt.lock().unwrap()
+    }
+
+    /// Notes that the readiness check passed.
+    pub fn set_verified(&self) {
+        self.verified.store(true, Ordering::Relaxed);
+    }
+
+    pub fn verified(&self) -> bool {
+        self.verified.load(Ordering::Relaxed)
+    }
+
+    pub fn traffic(&self) -> Option<Traffic> {
+        *self.traffic.lock().unwrap()
+    }
+
+    pub fn history(&self) -> Vec<TimelineEntry> {
+        self.entries.lock().unwrap().iter().cloned().collect()
+    }
+
+    /// What the status file holds; `None` for a timeline kept in memory
+    /// only.
+    pub fn status(&self) -> Option<Status> {
+        let (_, session) = self.file.as_ref()?;
+        Some(Status {
+            session: session.clone(),
+            history: self.history(),
+            traffic: self.traffic(),
+        })
+    }
+
+    /// Rewrites the status file through a rename, so readers never see it
+    /// half-written.
+    fn publish(&self, entries: &VecDeque<TimelineEntry>) -> anyhow::Result<()> {
+        let Some((path, session)) = &self.file else {
+            return Ok(());
+        };
+        let status = Status {
+            session: session.clone(),
+            history: entries.iter().cloned().collect(),
+            traffic: self.traffic(),
+        };
+        let partial = path.with_extension("json.tmp");
+        std::fs::write(&partial, serde_json::to_vec(&status)?)?;
+        std::fs::rename(&partial, path)?;
+        Ok(())
+    }
+}
+
+impl Drop for Timeline {
+    fn drop(&mut self) {
+        if let Some((path, _)) = &self.file {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+/// Where root keeps runtime state: status files and a VPN's routing state.
+/// Under `/run`, so it is cleared on reboot.
+pub const RUN_DIR: &str = "/run/x2ssh";
+
+/// Where this process publishes its status file: [`RUN_DIR`] as root,
+/// like the routing state, otherwise the user's runtime directory.
+pub fn status_dir() -> PathBuf {
+    if crate::elevate::is_root() {
+        PathBuf::from(RUN_DIR)
+    } else {
+        user_status_dir()
+    }
+}
+
+fn user_status_dir() -> PathBuf {
+    std::env::var_os("XDG_RUNTIME_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_else(std::env::temp_dir)
+        .join("x2ssh")
+}
+
+/// Where sessions of any user may have published their status.
+pub fn status_dirs() -> Vec<PathBuf> {
+    let mut dirs = vec![PathBuf::from(RUN_DIR)];
+    let user_dir = user_status_dir();
+    if !dirs.contains(&user_dir) {
+        dirs.push(user_dir);
+    }
+    dirs
+}
+
+pub fn status_file(dir: &Path, pid: u32) -> PathBuf {
+    dir.join(format!("status-{}.json", pid))
+}
+
+/// Reads the status files of all running sessions, oldest first. Files
+/// left behind by sessions that are gone, or written for processes by
+/// another user, are removed.
+pub fn running_sessions() -> anyhow::Result<Vec<Status>> {
+    let mut sessions = Vec::new();
+    for dir in status_dirs() {
+        let Ok(entries) = std::fs::read_dir(&dir) else {
+            continue;
+        };
+        for entry in entries {
+            let path = entry?.path();
+            let is_status = path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .is_some_and(|name| name.starts_with("status-") && name.ends_with(".json"));
+            if !is_status {
+                continue;
+            }
+            let status: Status = match std::fs::read(&path)
+                .map_err(anyhow::Error::from)
+                .and_then(|bytes| Ok(serde_json::from_slice(&bytes)?))
+            {
+                Ok(status) => status,
+                Err(e) => {
+                    warn!("Skipping unreadable status file {}: {}", path.display(), e);
+                    continue;
+                }
+            };
+            if status.session.is_running() && owned_by_session(&path, status.session.pid) {
+                sessions.push(status);
+            } else {
+                let _ = std::fs::remove_file(&path);
+            }
+        }
+    }
+    sessions.sort_by_key(|status| status.session.started_ms);
+    Ok(sessions)
+}
+
+#[cfg(target_os = "linux")]
+pub fn is_running(pid: u32) -> bool {
+    Path::new("/proc").join(pid.to_string()).exists()
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn is_running(pid: u32) -> bool {
+    std::process::Command::new("kill")
+        .args(["-0", &pid.to_string()])
+        .stderr(std::process::Stdio::null())
+        .status()
+        .is_ok_and(|status| status.success())
+}
+
+/// Asks the session `pid` to shut down, the way Ctrl+C would.
+#[cfg(target_os = "linux")]
+pub fn terminate(pid: u32) -> anyhow::Result<()> {
+    if unsafe { libc::kill(pid as libc::pid_t, libc::SIGTERM) } != 0 {
+        anyhow::bail!(
+            "cannot stop session {}: {}",
+            pid,
+            std::io::Error::last_os_error()
+        );
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn terminate(pid: u32) -> anyhow::Result<()> {
+    let status = std::process::Command::new("kill")
+        .args(["-TERM", &pid.to_string()])
+        .status()
+        .map_err(|e| anyhow::anyhow!("cannot run kill: {}", e))?;
+    if !status.success() {
+        anyhow::bail!("cannot stop session {} ({})", pid, status);
+    }
+    Ok(())
+}
+
+pub fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+/// Formats an elapsed time in its two largest units, e.g. `2h 5m` or
+/// `1.3s`.
+pub(crate) fn format_elapsed(elapsed: Duration) -> String {
+    let secs = elapsed.as_secs();
+    match secs {
+        0..60 => format!("{:.1}s", elapsed.as_secs_f64()),
+        60..3600 => format!("{}m {}s", secs / 60, secs % 60),
+        3600..86400 => format!("{}h {}m", secs / 3600, secs % 3600 / 60),
+        _ => format!("{}d {}h", secs / 86400, secs % 86400 / 3600),
+    }
+}
+
+/// Formats milliseconds since the epoch as `YYYY-MM-DD HH:MM:SS` UTC.
+pub(crate) fn format_utc(ms: u64) -> String {
+    let secs = ms / 1000;
+    let (days, rest) = (secs / 86400, secs % 86400);
+
+    // Civil date from days since 1970-01-01 (Howard Hinnant's algorithm).
+    let z = days as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+        year,
+        month,
+        day,
+        rest / 3600,
+        rest % 3600 / 60,
+        rest % 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_timeline_is_a_ring_buffer() {
+        let timeline = Timeline::new();
+        for _ in 0..HISTORY_LEN {
+            timeline.record(TimelineEvent::AgentRestarted);
+        }
+        timeline.record(TimelineEvent::Disconnected {
+            cause: DisconnectCause::HealthCheck,
+        });
+
+        let history = timeline.history();
+        assert_eq!(history.len(), HISTORY_LEN);
+        assert_eq!(history.last().unwrap().event, TimelineEvent::Disconnected {
+            cause: DisconnectCause::HealthCheck
+        });
+    }
+
+    #[test]
+    fn test_published_status_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let session = SessionInfo::new("vpn", "user@host");
+        let path = status_file(dir.path(), session.pid);
+
+        let timeline = Timeline::published(dir.path(), session.clone()).unwrap();
+        timeline.record(TimelineEvent::Disconnected {
+            cause: DisconnectCause::NetworkChange,
+        });
+        timeline.record(TimelineEvent::Reconnected {
+            downtime_ms: 2100,
+            attempts: 2,
+        });
+
+        let status: Status = serde_json::from_slice(&std::fs::read(&path).unwrap()).unwrap();
+        assert_eq!(status.session, session);
+        assert_eq!(status.history, timeline.history());
+        assert_eq!(status.reconnects(), 1);
+        assert_eq!(status.traffic, None);
+
+        let traffic = Traffic {
+            packets_sent: 3,
+            bytes_sent: 180,
+            packets_received: 2,
+            bytes_received: 3000,
+        };
+        timeline.set_traffic(traffic);
+        let status: Status = serde_json::from_slice(&std::fs::read(&path).unwrap()).unwrap();
+        assert_eq!(status.traffic, Some(traffic));
+        assert_eq!(status.history, timeline.history());
+
+        drop(timeline);
+        assert!(!path.exists());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_session_is_running() {
+        let session = SessionInfo::new("socks", "user@host");
+        assert!(session.start_time.is_some());
+        assert!(session.is_running());
+
+        // A process that reused the pid started at another time.
+        let reused = SessionInfo {
+            start_time: session.start_time.map(|t| t + 1),
+            ..session.clone()
+        };
+        assert!(!reused.is_running());
+
+        // Without a start time the pid must run an x2ssh executable, which
+        // the test binary is not.
+        let older = SessionInfo {
+            start_time: None,
+            ..session
+        };
+        assert!(!older.is_running());
+    }
+
+    #[test]
+    fn test_reconnect_failed_class() {
+        let entry: TimelineEntry =
+            serde_json::from_str(r#"{"ts_ms":1,"event":"reconnect_failed","attempts":3}"#).unwrap();
+        assert_eq!(entry.event, TimelineEvent::ReconnectFailed {
+            attempts: 3,
+            class: None,
+            downtime_ms: 0,
+        });
+
+        let entry = TimelineEntry {
+            ts_ms: 1,
+            event: TimelineEvent::ReconnectFailed {
+                attempts: 1,
+                class: Some(ErrorClass::Auth),
+                downtime_ms: 600_000,
+            },
+        };
+        assert_eq!(
+            serde_json::to_string(&entry).unwrap(),
+            r#"{"ts_ms":1,"event":"reconnect_failed","attempts":1,"class":"auth","downtime_ms":600000}"#
+        );
+        let status = Status {
+            session: SessionInfo::new("socks", "user@host"),
+            history: vec![entry],
+            traffic: None,
+        };
+        assert!(
+            status.render_history().ends_with(
+                "reconnect gave up after 1 attempt(s) in 10m 0s (authentication error)\n"
+            )
+        );
+    }
+
+    #[test]
+    fn test_render_history() {
+        let status = Status {
+            session: SessionInfo {
+                pid: 42,
+                mode: "socks".to_string(),
+                destination: "user@host".to_string(),
+                started_ms: 1_700_000_000_000,
+                start_time: None,
+            },
+            history: vec![
+                TimelineEntry {
+                    ts_ms: 1_700_000_060_000,
+                    event: TimelineEvent::Disconnected {
+                        cause: DisconnectCause::RemoteClosed,
+                    },
+                },
+                TimelineEntry {
+                    ts_ms: 1_700_000_062_500,
+                    event: TimelineEvent::Reconnected {
+                        downtime_ms: 2500,
+                        attempts: 3,
+                    },
+                },
+            ],
+            traffic: None,
+        };
+
+        let now = 1_700_003_660_000;
+        assert_eq!(
+            status.render(false, now),
+            "socks user@host (pid 42): up 1h 1m, 1 reconnect(s)\n"
+        );
+        assert_eq!(
+            status.render(true, now),
+            "socks user@host (pid 42): up 1h 1m, 1 reconnect(s)\n\x20 2023-11-14 22:14:20  \
+             disconnected: connection closed by remote\n\x20 2023-11-14 22:14:22  reconnected \
+             after 2.5s (3 attempt(s))\n"
+        );
+
+        let status = Status {
+            traffic: Some(Traffic {
+                packets_sent: 3,
+                bytes_sent: 180,
+                packets_received: 2,
+                bytes_received: 3000,
+            }),
+            ..status
+        };
+        assert_eq!(
+            status.render(false, now),
+            "socks user@host (pid 42): up 1h 1m, 1 reconnect(s)\n\x20 tunnel: 3 packets (180 \
+             bytes) sent, 2 packets (3000 bytes) received\n"
+        );
+    }
+}
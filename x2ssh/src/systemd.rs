@@ -0,0 +1,227 @@
+//! Running under systemd: `sd_notify` messages (`READY=1` once the tunnel
+//! is verified, `RELOADING=1` while the SSH session reconnects, and
+//! `WATCHDOG=1` after each passed health check) and the SOCKS listener
+//! handed over by socket activation.
+
+use std::ffi::OsStr;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+
+use tracing::debug;
+use tracing::warn;
+
+/// Whether `READY=1` has been sent; reconnects only report themselves to
+/// a service that has finished starting.
+static READY: AtomicBool = AtomicBool::new(false);
+
+/// Sends `state` to systemd when started with `Type=notify`. Returns
+/// whether there was anyone to tell.
+pub fn notify(state: &str) -> std::io::Result<bool> {
+    match std::env::var_os("NOTIFY_SOCKET") {
+        Some(socket) => notify_socket(&socket, state).map(|()| true),
+        None => Ok(false),
+    }
+}
+
+/// [`notify`], logging failures.
+fn send(state: &str) {
+    match notify(state) {
+        Ok(true) => debug!("Notified systemd: {}", state.replace('\n', " ")),
+        Ok(false) => {}
+        Err(e) => warn!("Failed to notify systemd: {}", e),
+    }
+}
+
+/// The service has started and the tunnel works.
+pub fn ready() {
+    READY.store(true, Ordering::Relaxed);
+    send("READY=1\nSTATUS=Connected");
+}
+
+/// The SSH session was lost and is being re-established.
+pub fn reconnecting() {
+    if READY.load(Ordering::Relaxed) {
+        send(&format!(
+            "RELOADING=1\nMONOTONIC_USEC={}\nSTATUS=Reconnecting",
+            monotonic_usec()
+        ));
+    }
+}
+
+/// The SSH session is back after [`reconnecting`].
+pub fn reconnected() {
+    if READY.load(Ordering::Relaxed) {
+        send("READY=1\nSTATUS=Connected");
+    }
+}
+
+/// Reconnecting gave up; the session is about to end.
+pub fn gave_up() {
+    send("STOPPING=1\nSTATUS=Gave up reconnecting");
+}
+
+/// A health check passed.
+pub fn watchdog() {
+    if watchdog_timeout().is_some() {
+        send("WATCHDOG=1");
+    }
+}
+
+/// `WatchdogSec=` of the unit, when it is meant for this process.
+pub fn watchdog_timeout() -> Option<Duration> {
+    parse_watchdog(
+        std::env::var("WATCHDOG_USEC").ok().as_deref(),
+        std::env::var("WATCHDOG_PID").ok().as_deref(),
+        std::process::id(),
+    )
+}
+
+/// How long to wait between health checks: `interval`, but at most half
+/// the watchdog timeout, so a healthy session is never taken for a hung
+/// one.
+pub fn health_interval(interval: Duration) -> Duration {
+    match watchdog_timeout() {
+        Some(timeout) => interval.min(timeout / 2),
+        None => interval,
+    }
+}
+
+fn parse_watchdog(usec: Option<&str>, pid: Option<&str>, own_pid: u32) -> Option<Duration> {
+    if let Some(pid) = pid
+        && pid.parse() != Ok(own_pid)
+    {
+        return None;
+    }
+    match usec?.parse() {
+        Ok(0) | Err(_) => None,
+        Ok(usec) => Some(Duration::from_micros(usec)),
+    }
+}
+
+/// The listening socket systemd passed with socket activation
+/// (`LISTEN_FDS`), if any.
+#[cfg(unix)]
+pub fn listener() -> anyhow::Result<Option<tokio::net::TcpListener>> {
+    use std::os::fd::FromRawFd;
+
+    /// The first descriptor systemd passes, after stdin, stdout and stderr.
+    const LISTEN_FDS_START: i32 = 3;
+
+    let count = listen_fd_count(
+        std::env::var("LISTEN_FDS").ok().as_deref(),
+        std::env::var("LISTEN_PID").ok().as_deref(),
+        std::process::id(),
+    )?;
+    match count {
+        0 => return Ok(None),
+        1 => {}
+        n => anyhow::bail!(
+            "systemd passed {} sockets; x2ssh takes one, for the SOCKS proxy",
+            n
+        ),
+    }
+    let fd = LISTEN_FDS_START;
+    // Keep it from the commands x2ssh runs.
+    if unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) } == -1 {
+        anyhow::bail!("socket from systemd: {}", std::io::Error::last_os_error());
+    }
+    let listener = unsafe { std::net::TcpListener::from_raw_fd(fd) };
+    listener.set_nonblocking(true)?;
+    let listener = tokio::net::TcpListener::from_std(listener)
+        .map_err(|e| anyhow::anyhow!("socket from systemd is not a TCP listener: {}", e))?;
+    Ok(Some(listener))
+}
+
+#[cfg(not(unix))]
+pub fn listener() -> anyhow::Result<Option<tokio::net::TcpListener>> {
+    Ok(None)
+}
+
+fn listen_fd_count(fds: Option<&str>, pid: Option<&str>, own_pid: u32) -> anyhow::Result<usize> {
+    let (Some(fds), Some(pid)) = (fds, pid) else {
+        return Ok(0);
+    };
+    if pid.parse() != Ok(own_pid) {
+        return Ok(0);
+    }
+    fds.parse()
+        .map_err(|_| anyhow::anyhow!("invalid LISTEN_FDS '{}'", fds))
+}
+
+#[cfg(unix)]
+fn monotonic_usec() -> u64 {
+    let mut now = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut now) };
+    now.tv_sec as u64 * 1_000_000 + now.tv_nsec as u64 / 1_000
+}
+
+#[cfg(not(unix))]
+fn monotonic_usec() -> u64 {
+    0
+}
+
+#[cfg(target_os = "linux")]
+fn notify_socket(socket: &OsStr, state: &str) -> std::io::Result<()> {
+    use std::os::linux::net::SocketAddrExt;
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::net::SocketAddr;
+    use std::os::unix::net::UnixDatagram;
+
+    let addr = match socket.as_bytes().strip_prefix(b"@") {
+        Some(name) => SocketAddr::from_abstract_name(name)?,
+        None => SocketAddr::from_pathname(socket)?,
+    };
+    UnixDatagram::unbound()?.send_to_addr(state.as_bytes(), &addr)?;
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn notify_socket(_socket: &OsStr, _state: &str) -> std::io::Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_notify_socket() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("notify");
+        let systemd = std::os::unix::net::UnixDatagram::bind(&path).unwrap();
+        notify_socket(path.as_os_str(), "READY=1").unwrap();
+        let mut buf = [0u8; 16];
+        let n = systemd.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"READY=1");
+    }
+
+    #[test]
+    fn test_parse_watchdog() {
+        assert_eq!(
+            parse_watchdog(Some("30000000"), None, 42),
+            Some(Duration::from_secs(30))
+        );
+        assert_eq!(
+            parse_watchdog(Some("30000000"), Some("42"), 42),
+            Some(Duration::from_secs(30))
+        );
+        assert_eq!(parse_watchdog(Some("30000000"), Some("7"), 42), None);
+        assert_eq!(parse_watchdog(Some("0"), None, 42), None);
+        assert_eq!(parse_watchdog(Some("soon"), None, 42), None);
+        assert_eq!(parse_watchdog(None, None, 42), None);
+    }
+
+    #[test]
+    fn test_listen_fd_count() {
+        assert_eq!(listen_fd_count(Some("1"), Some("42"), 42).unwrap(), 1);
+        assert_eq!(listen_fd_count(Some("1"), Some("7"), 42).unwrap(), 0);
+        assert_eq!(listen_fd_count(Some("1"), None, 42).unwrap(), 0);
+        assert_eq!(listen_fd_count(None, None, 42).unwrap(), 0);
+        assert!(listen_fd_count(Some("x"), Some("42"), 42).is_err());
+    }
+}
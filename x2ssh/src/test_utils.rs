@@ -0,0 +1,230 @@
+//! Test harnesses for exercising the agent protocol without SSH, containers
+//! or root.
+
+#[cfg(feature = "embed-agent")]
+mod local_agent;
+
+#[cfg(feature = "embed-agent")]
+pub use local_agent::LocalAgent;
+use proto::Control;
+use proto::Features;
+use tokio::sync::Mutex;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+use crate::vpn::agent::AgentChannel;
+use crate::vpn::tun::PacketDevice;
+
+/// How an [`EchoAgent`] answers the packets it receives.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EchoMode {
+    /// Send every packet back unchanged.
+    Reflect,
+    /// Answer IPv4 ICMP echo requests with echo replies, as a remote host
+    /// would; everything else is dropped.
+    IcmpReply,
+    /// Answer nothing, not even keepalive pings, like an agent behind a
+    /// connection that died silently.
+    Silent,
+}
+
+/// In-process stand-in for the remote agent, speaking the framed agent
+/// protocol over an in-memory pipe.
+pub struct EchoAgent {
+    channel: AgentChannel,
+    task: JoinHandle<u64>,
+}
+
+impl EchoAgent {
+    /// Starts the agent and completes the handshake with it.
+    pub async fn spawn(mode: EchoMode) -> Self {
+        let (client, agent) = tokio::io::duplex(64 * 1024);
+        let (client_read, client_write) = tokio::io::split(client);
+        let (mut agent_read, mut agent_write) = tokio::io::split(agent);
+
+        let task = tokio::spawn(async move {
+            let features = Features::all().without(Features::SHARED);
+            let handshake = proto::handshake::accept(&mut agent_read, &mut agent_write, features);
+            if handshake.await.is_err() {
+                return 0;
+            }
+            let mut answered = 0;
+            'frames: while let Ok(frame) = proto::read_framed(&mut agent_read).await {
+                let packets = match (mode, Control::parse(&frame)) {
+                    (EchoMode::Silent, _) => continue,
+                    // Answered like the real agent does, but not counted.
+                    (_, Some(control)) => {
+                        if let Some(reply) = control.reply()
+                            && proto::write_framed(&mut agent_write, &reply.encode())
+                                .await
+                                .is_err()
+                        {
+                            break;
+                        }
+                        continue;
+                    }
+                    (_, None) => match proto::unbatch(&frame) {
+                        Ok(Some(packets)) => packets,
+                        Ok(None) => vec![frame.as_slice()],
+                        Err(_) => break,
+                    },
+                };
+                for packet in packets {
+                    let reply = match mode {
+                        EchoMode::IcmpReply => icmp_echo_reply(packet),
+                        _ => Some(packet.to_vec()),
+                    };
+                    if let Some(reply) = reply {
+                        if proto::write_framed(&mut agent_write, &reply).await.is_err() {
+                            break 'frames;
+                        }
+                        answered += 1;
+                    }
+                }
+            }
+            answered
+        });
+
+        let mut channel = AgentChannel::from_io(client_read, client_write);
+        channel
+            .handshake()
+            .await
+            .expect("echo agent speaks the protocol");
+        Self { channel, task }
+    }
+
+    pub fn channel(&self) -> &AgentChannel {
+        &self.channel
+    }
+
+    /// Closes the client side and returns how many packets were answered.
+    pub async fn shutdown(self) -> anyhow::Result<u64> {
+        self.channel.close().await?;
+        Ok(self.task.await?)
+    }
+}
+
+/// In-memory [`PacketDevice`]: packets pushed through [`MemoryDeviceHandle`]
+/// come out of `recv`, and packets passed to `send` are delivered back to
+/// the handle. Dropping the handle's sender makes `recv` fail, as a TUN
+/// device that went away would.
+pub struct MemoryDevice {
+    outbound: Mutex<mpsc::Receiver<Vec<u8>>>,
+    inbound: mpsc::Sender<Vec<u8>>,
+}
+
+pub struct MemoryDeviceHandle {
+    pub outbound: mpsc::Sender<Vec<u8>>,
+    pub inbound: mpsc::Receiver<Vec<u8>>,
+}
+
+impl MemoryDevice {
+    /// Creates a device whose queues hold at most `capacity` packets each,
+    /// so a stalled consumer exerts backpressure on the producer.
+    pub fn new(capacity: usize) -> (Self, MemoryDeviceHandle) {
+        let (outbound_tx, outbound_rx) = mpsc::channel(capacity);
+        let (inbound_tx, inbound_rx) = mpsc::channel(capacity);
+        let device = Self {
+            outbound: Mutex::new(outbound_rx),
+            inbound: inbound_tx,
+        };
+        let handle = MemoryDeviceHandle {
+            outbound: outbound_tx,
+            inbound: inbound_rx,
+        };
+        (device, handle)
+    }
+}
+
+impl PacketDevice for MemoryDevice {
+    async fn recv(&self, buf: &mut [u8]) -> anyhow::Result<usize> {
+        let packet = self
+            .outbound
+            .lock()
+            .await
+            .recv()
+            .await
+            .ok_or_else(|| anyhow::anyhow!("Memory device closed"))?;
+        let n = packet.len().min(buf.len());
+        buf[..n].copy_from_slice(&packet[..n]);
+        Ok(n)
+    }
+
+    async fn send(&self, packet: &[u8]) -> anyhow::Result<()> {
+        self.inbound
+            .send(packet.to_vec())
+            .await
+            .map_err(|_| anyhow::anyhow!("Memory device closed"))
+    }
+}
+
+/// Builds a minimal IPv4 ICMP echo request.
+pub fn icmp_echo_request(
+    src: std::net::Ipv4Addr,
+    dst: std::net::Ipv4Addr,
+    id: u16,
+    seq: u16,
+    payload: &[u8],
+) -> Vec<u8> {
+    let total_len = 20 + 8 + payload.len();
+    let mut packet = vec![0u8; total_len];
+
+    packet[0] = 0x45;
+    packet[2..4].copy_from_slice(&(total_len as u16).to_be_bytes());
+    packet[8] = 64;
+    packet[9] = 1;
+    packet[12..16].copy_from_slice(&src.octets());
+    packet[16..20].copy_from_slice(&dst.octets());
+    let checksum = internet_checksum(&packet[..20]);
+    packet[10..12].copy_from_slice(&checksum.to_be_bytes());
+
+    packet[20] = 8;
+    packet[24..26].copy_from_slice(&id.to_be_bytes());
+    packet[26..28].copy_from_slice(&seq.to_be_bytes());
+    packet[28..].copy_from_slice(payload);
+    let checksum = internet_checksum(&packet[20..]);
+    packet[22..24].copy_from_slice(&checksum.to_be_bytes());
+
+    packet
+}
+
+/// Turns an IPv4 ICMP echo request into the matching reply, or returns
+/// `None` for anything else.
+pub fn icmp_echo_reply(request: &[u8]) -> Option<Vec<u8>> {
+    if request.len() < 20 || request[0] >> 4 != 4 || request[9] != 1 {
+        return None;
+    }
+    let ihl = usize::from(request[0] & 0x0f) * 4;
+    if request.len() < ihl + 8 || request[ihl] != 8 {
+        return None;
+    }
+
+    let mut reply = request.to_vec();
+    // Swapping addresses leaves the header checksum valid: the one's
+    // complement sum does not depend on word order.
+    reply[12..16].copy_from_slice(&request[16..20]);
+    reply[16..20].copy_from_slice(&request[12..16]);
+
+    reply[ihl] = 0;
+    reply[ihl + 2..ihl + 4].fill(0);
+    let checksum = internet_checksum(&reply[ihl..]);
+    reply[ihl + 2..ihl + 4].copy_from_slice(&checksum.to_be_bytes());
+
+    Some(reply)
+}
+
+/// RFC 1071 checksum. Data containing a correct checksum sums to zero.
+pub fn internet_checksum(data: &[u8]) -> u16 {
+    let mut sum: u32 = data
+        .chunks(2)
+        .map(|chunk| match chunk {
+            [hi, lo] => u32::from(u16::from_be_bytes([*hi, *lo])),
+            [hi] => u32::from(*hi) << 8,
+            _ => unreachable!(),
+        })
+        .sum();
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
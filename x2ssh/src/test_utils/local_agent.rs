@@ -0,0 +1,115 @@
+//! The embedded agent run as a local subprocess, for tests of the real
+//! agent that need neither SSH nor root.
+
+use std::path::PathBuf;
+use std::process::ExitStatus;
+use std::process::Stdio;
+use std::sync::OnceLock;
+
+use tokio::process::Child;
+use tokio::process::Command;
+
+use crate::vpn::agent::AGENT_BINARY;
+use crate::vpn::agent::AgentChannel;
+
+/// The embedded agent binary, running as a local subprocess with its stdio
+/// piped into an [`AgentChannel`].
+pub struct LocalAgent {
+    child: Child,
+    channel: AgentChannel,
+}
+
+impl LocalAgent {
+    /// Spawns the agent in `--loopback` mode, which reflects every frame and
+    /// needs no TUN device.
+    pub async fn loopback() -> anyhow::Result<Self> {
+        Self::spawn(&["--loopback"]).await
+    }
+
+    /// Spawns the agent with arbitrary arguments and completes the
+    /// handshake.
+    pub async fn spawn(args: &[&str]) -> anyhow::Result<Self> {
+        let path = agent_path()?;
+
+        let mut child = spawn_retrying_busy(|| {
+            let mut cmd = Command::new(path);
+            cmd.args(args)
+                .stdin(Stdio::piped())
+                .stdout(Stdio::piped())
+                .stderr(Stdio::inherit())
+                .kill_on_drop(true);
+            cmd.spawn()
+        })
+        .await?;
+
+        let stdin = child.stdin.take().expect("stdin is piped");
+        let stdout = child.stdout.take().expect("stdout is piped");
+
+        let mut channel = AgentChannel::from_io(stdout, stdin);
+        channel.handshake().await?;
+        Ok(Self { child, channel })
+    }
+
+    pub fn channel(&self) -> &AgentChannel {
+        &self.channel
+    }
+
+    /// Closes the agent's stdin and waits for it to exit.
+    pub async fn shutdown(mut self) -> anyhow::Result<ExitStatus> {
+        self.channel.close().await?;
+        Ok(self.child.wait().await?)
+    }
+}
+
+/// Writes the embedded agent binary to a per-process temp file once.
+fn agent_path() -> anyhow::Result<&'static PathBuf> {
+    static PATH: OnceLock<Result<PathBuf, String>> = OnceLock::new();
+
+    PATH.get_or_init(|| {
+        let path = std::env::temp_dir().join(format!("x2ssh-agent-test-{}", std::process::id()));
+        write_executable(&path)
+            .map(|()| path)
+            .map_err(|e| format!("Failed to write agent binary: {}", e))
+    })
+    .as_ref()
+    .map_err(|e| anyhow::anyhow!("{}", e))
+}
+
+#[cfg(unix)]
+fn write_executable(path: &PathBuf) -> std::io::Result<()> {
+    use std::io::Write;
+    use std::os::unix::fs::OpenOptionsExt;
+
+    // Write to a private name and rename, so no process ever execs a file
+    // that is still open for writing.
+    let tmp = path.with_extension("partial");
+    let mut file = std::fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o755)
+        .open(&tmp)?;
+    file.write_all(AGENT_BINARY)?;
+    drop(file);
+    std::fs::rename(tmp, path)
+}
+
+#[cfg(not(unix))]
+fn write_executable(path: &PathBuf) -> std::io::Result<()> {
+    std::fs::write(path, AGENT_BINARY)
+}
+
+/// Another test thread forking while our write fd was open can make exec
+/// fail with ETXTBSY until that child execs; retry briefly.
+async fn spawn_retrying_busy(spawn: impl Fn() -> std::io::Result<Child>) -> anyhow::Result<Child> {
+    let mut attempts = 0;
+    loop {
+        match spawn() {
+            Err(e) if e.kind() == std::io::ErrorKind::ExecutableFileBusy && attempts < 10 => {
+                attempts += 1;
+                tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+            }
+            result => return Ok(result?),
+        }
+    }
+}
@@ -0,0 +1,483 @@
+//! `x2ssh top`: a live view of the running sessions, refreshed from their
+//! control sockets, with the VPN's throughput drawn as sparklines.
+
+use std::collections::HashMap;
+use std::collections::VecDeque;
+use std::time::Duration;
+
+use crate::control::ConnectionState;
+use crate::control::Report;
+use crate::status::Traffic;
+use crate::status::format_elapsed;
+
+/// Throughput samples kept per session, more than any terminal is wide.
+const SAMPLES: usize = 512;
+
+/// Bars of a sparkline, from no traffic to the most in view.
+const BARS: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+
+/// What a key press asks for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Key {
+    Quit,
+    Next,
+    Previous,
+}
+
+/// The keys in what was read from the terminal: `q`, Esc or Ctrl+C to
+/// quit, Tab, `n` or the right and down arrows for the next session, `p`
+/// or the left and up arrows for the previous one.
+pub fn keys(input: &[u8]) -> Vec<Key> {
+    let mut keys = Vec::new();
+    let mut rest = input;
+    while let Some((&byte, tail)) = rest.split_first() {
+        rest = tail;
+        let key = match byte {
+            b'q' | b'Q' | 0x03 => Key::Quit,
+            b'\t' | b'n' | b'j' => Key::Next,
+            b'p' | b'k' => Key::Previous,
+            0x1b => match rest {
+                [b'[' | b'O', arrow, tail @ ..] => {
+                    rest = tail;
+                    match arrow {
+                        b'C' | b'B' => Key::Next,
+                        b'D' | b'A' | b'Z' => Key::Previous,
+                        _ => continue,
+                    }
+                }
+                [] => Key::Quit,
+                _ => continue,
+            },
+            _ => continue,
+        };
+        keys.push(key);
+    }
+    keys
+}
+
+/// Bytes per second through a VPN tunnel, one sample per refresh.
+#[derive(Debug, Default)]
+struct Throughput {
+    last: Option<(u64, Traffic)>,
+    sent: VecDeque<u64>,
+    received: VecDeque<u64>,
+}
+
+impl Throughput {
+    fn sample(&mut self, now_ms: u64, traffic: Traffic) {
+        if let Some((then_ms, last)) = self.last
+            && now_ms > then_ms
+        {
+            let rate = |now: u64, then: u64| now.saturating_sub(then) * 1000 / (now_ms - then_ms);
+            for (samples, rate) in [
+                (&mut self.sent, rate(traffic.bytes_sent, last.bytes_sent)),
+                (
+                    &mut self.received,
+                    rate(traffic.bytes_received, last.bytes_received),
+                ),
+            ] {
+                if samples.len() == SAMPLES {
+                    samples.pop_front();
+                }
+                samples.push_back(rate);
+            }
+        }
+        self.last = Some((now_ms, traffic));
+    }
+}
+
+/// The sessions on screen, and the throughput seen so far for each.
+#[derive(Debug, Default)]
+pub struct Top {
+    reports: Vec<Report>,
+    /// The pid of the session shown.
+    selected: Option<u32>,
+    throughput: HashMap<u32, Throughput>,
+}
+
+impl Top {
+    /// Takes in the sessions' reports from one refresh.
+    pub fn update(&mut self, reports: Vec<Report>, now_ms: u64) {
+        self.throughput
+            .retain(|pid, _| reports.iter().any(|r| r.status.session.pid == *pid));
+        for report in &reports {
+            if let Some(traffic) = report.status.traffic {
+                self.throughput
+                    .entry(report.status.session.pid)
+                    .or_default()
+                    .sample(now_ms, traffic);
+            }
+        }
+        self.reports = reports;
+        if self.index().is_none() {
+            self.selected = self.reports.first().map(|r| r.status.session.pid);
+        }
+    }
+
+    fn index(&self) -> Option<usize> {
+        self.reports
+            .iter()
+            .position(|r| Some(r.status.session.pid) == self.selected)
+    }
+
+    /// Shows the next session (`forward`) or the previous one.
+    pub fn select(&mut self, forward: bool) {
+        let (Some(index), count) = (self.index(), self.reports.len()) else {
+            return;
+        };
+        let index = if forward {
+            (index + 1) % count
+        } else {
+            (index + count - 1) % count
+        };
+        self.selected = Some(self.reports[index].status.session.pid);
+    }
+
+    /// The screen, as lines of at most `width` characters and no more than
+    /// `height` of them.
+    pub fn render(&self, width: usize, height: usize) -> Vec<String> {
+        let mut lines = vec![format!(
+            "x2ssh top: {} session(s)    Tab: next session  q: quit",
+            self.reports.len()
+        )];
+        let Some(report) = self.index().map(|index| &self.reports[index]) else {
+            lines.push(String::new());
+            lines.push("No running x2ssh sessions".to_string());
+            return fit(lines, width, height);
+        };
+        if self.reports.len() > 1 {
+            let tabs: Vec<String> = self
+                .reports
+                .iter()
+                .map(|r| {
+                    let tab = format!("{} {}", r.status.session.mode, r.status.session.pid);
+                    if Some(r.status.session.pid) == self.selected {
+                        format!("[{}]", tab)
+                    } else {
+                        format!(" {} ", tab)
+                    }
+                })
+                .collect();
+            lines.push(tabs.join(" "));
+        }
+        lines.push(String::new());
+
+        let session = &report.status.session;
+        lines.push(format!(
+            "{} {} (pid {})",
+            session.mode, session.destination, session.pid
+        ));
+        let state = match (report.state, report.attempt) {
+            (ConnectionState::Reconnecting, Some(attempt)) => {
+                format!("reconnecting, attempt {}", attempt)
+            }
+            (state, _) => state.to_string(),
+        };
+        lines.push(format!(
+            "  {}, up {}, {} reconnect(s){}",
+            state,
+            format_elapsed(Duration::from_millis(report.uptime_ms)),
+            report.reconnects,
+            if report.live {
+                ""
+            } else {
+                " (no control socket)"
+            }
+        ));
+
+        if let Some(throughput) = self.throughput.get(&session.pid) {
+            let graph = width.saturating_sub(6);
+            let latest = |samples: &VecDeque<u64>| samples.back().copied().unwrap_or(0);
+            lines.push(String::new());
+            lines.push(format!(
+                "Tunnel  up {}  down {}",
+                format_rate(latest(&throughput.sent)),
+                format_rate(latest(&throughput.received))
+            ));
+            lines.push(format!("  up  {}", sparkline(&throughput.sent, graph)));
+            lines.push(format!("  dn  {}", sparkline(&throughput.received, graph)));
+        }
+
+        if let Some(active) = report.socks_active {
+            lines.push(String::new());
+            lines.push(format!("SOCKS  {} open connection(s)", active));
+            let mut destinations: Vec<_> = report.socks_destinations.iter().collect();
+            destinations.sort_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(b.0)));
+            for (destination, count) in destinations {
+                lines.push(format!("  {:>5}  {}", count, destination));
+            }
+        }
+
+        if !report.forwards.is_empty() {
+            lines.push(String::new());
+            lines.push("Forwards".to_string());
+            for forward in &report.forwards {
+                lines.push(format!("  {}", forward));
+            }
+        }
+
+        // The most recent events, as many as fit below the rest.
+        let history = report.status.render_history();
+        let events: Vec<&str> = history.lines().collect();
+        let room = height.saturating_sub(lines.len() + 2).min(events.len());
+        if room > 0 {
+            lines.push(String::new());
+            lines.push("Events".to_string());
+            lines.extend(events[events.len() - room..].iter().map(|e| e.to_string()));
+        }
+        fit(lines, width, height)
+    }
+}
+
+/// Cuts `lines` down to the screen.
+fn fit(mut lines: Vec<String>, width: usize, height: usize) -> Vec<String> {
+    lines.truncate(height);
+    for line in &mut lines {
+        if let Some((end, _)) = line.char_indices().nth(width) {
+            line.truncate(end);
+        }
+    }
+    lines
+}
+
+/// The last `width` samples as bars, scaled to the largest of them.
+pub fn sparkline(samples: &VecDeque<u64>, width: usize) -> String {
+    let shown = samples.iter().skip(samples.len().saturating_sub(width));
+    let max = shown.clone().max().copied().unwrap_or(0).max(1);
+    shown
+        .map(|sample| BARS[(sample * (BARS.len() as u64 - 1)).div_ceil(max) as usize])
+        .collect()
+}
+
+/// A byte rate in the largest unit that keeps it above 1, e.g. `1.5 MB/s`.
+pub fn format_rate(bytes_per_sec: u64) -> String {
+    const UNITS: [&str; 4] = ["KB/s", "MB/s", "GB/s", "TB/s"];
+    if bytes_per_sec < 1000 {
+        return format!("{} B/s", bytes_per_sec);
+    }
+    let mut value = bytes_per_sec as f64 / 1000.0;
+    let mut unit = 0;
+    while value >= 1000.0 && unit < UNITS.len() - 1 {
+        value /= 1000.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", value, UNITS[unit])
+}
+
+/// The terminal in full-screen mode, reading key presses as they come;
+/// restored when dropped.
+#[cfg(unix)]
+pub struct Terminal {
+    saved: libc::termios,
+}
+
+#[cfg(unix)]
+impl Terminal {
+    pub fn enter() -> anyhow::Result<Self> {
+        use std::io::IsTerminal;
+        use std::io::Write;
+
+        if !std::io::stdin().is_terminal() || !std::io::stdout().is_terminal() {
+            anyhow::bail!("x2ssh top needs a terminal; scripts can use x2ssh status --json");
+        }
+        let mut saved = unsafe { std::mem::zeroed::<libc::termios>() };
+        if unsafe { libc::tcgetattr(libc::STDIN_FILENO, &mut saved) } == -1 {
+            anyhow::bail!(
+                "cannot read terminal settings: {}",
+                std::io::Error::last_os_error()
+            );
+        }
+        let mut raw = saved;
+        // Keys one at a time, unechoed; Ctrl+C arrives as a key too, so the
+        // terminal is always restored.
+        raw.c_lflag &= !(libc::ICANON | libc::ECHO | libc::ISIG);
+        raw.c_cc[libc::VMIN] = 1;
+        raw.c_cc[libc::VTIME] = 0;
+        if unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &raw) } == -1 {
+            anyhow::bail!(
+                "cannot set up the terminal: {}",
+                std::io::Error::last_os_error()
+            );
+        }
+        let terminal = Self { saved };
+        // Alternate screen, cursor hidden.
+        let mut stdout = std::io::stdout();
+        stdout.write_all(b"\x1b[?1049h\x1b[?25l")?;
+        stdout.flush()?;
+        Ok(terminal)
+    }
+
+    /// Columns and rows.
+    pub fn size(&self) -> (usize, usize) {
+        let mut size = unsafe { std::mem::zeroed::<libc::winsize>() };
+        match unsafe { libc::ioctl(libc::STDOUT_FILENO, libc::TIOCGWINSZ, &mut size) } {
+            0 if size.ws_col > 0 && size.ws_row > 0 => (size.ws_col as usize, size.ws_row as usize),
+            _ => (80, 24),
+        }
+    }
+
+    /// Replaces the screen with `lines`.
+    pub fn draw(&self, lines: &[String]) -> std::io::Result<()> {
+        use std::io::Write;
+
+        let mut screen = String::from("\x1b[H");
+        for (i, line) in lines.iter().enumerate() {
+            if i > 0 {
+                screen.push_str("\r\n");
+            }
+            screen.push_str(line);
+            screen.push_str("\x1b[K");
+        }
+        screen.push_str("\x1b[J");
+        let mut stdout = std::io::stdout();
+        stdout.write_all(screen.as_bytes())?;
+        stdout.flush()
+    }
+}
+
+#[cfg(unix)]
+impl Drop for Terminal {
+    fn drop(&mut self) {
+        use std::io::Write;
+
+        let mut stdout = std::io::stdout();
+        let _ = stdout.write_all(b"\x1b[?25h\x1b[?1049l");
+        let _ = stdout.flush();
+        unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &self.saved) };
+    }
+}
+
+#[cfg(not(unix))]
+pub struct Terminal;
+
+#[cfg(not(unix))]
+impl Terminal {
+    pub fn enter() -> anyhow::Result<Self> {
+        anyhow::bail!("x2ssh top is only supported on Unix; use x2ssh status")
+    }
+
+    pub fn size(&self) -> (usize, usize) {
+        (80, 24)
+    }
+
+    pub fn draw(&self, _lines: &[String]) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::status::SessionInfo;
+    use crate::status::Status;
+
+    fn report(pid: u32, mode: &str, traffic: Option<Traffic>) -> Report {
+        let mut session = SessionInfo::new(mode, "user@host:22");
+        session.pid = pid;
+        let mut report = Report::from_status(
+            Status {
+                session,
+                history: Vec::new(),
+                traffic,
+            },
+            0,
+        );
+        report.live = true;
+        report
+    }
+
+    #[test]
+    fn test_keys() {
+        assert_eq!(keys(b"q"), [Key::Quit]);
+        assert_eq!(keys(b"\x03"), [Key::Quit]);
+        assert_eq!(keys(b"\x1b"), [Key::Quit]);
+        assert_eq!(keys(b"\tn\x1b[C"), [Key::Next; 3]);
+        assert_eq!(keys(b"\x1b[D\x1b[Ap"), [Key::Previous; 3]);
+        assert_eq!(keys(b"x\x1b[5~"), []);
+    }
+
+    #[test]
+    fn test_throughput() {
+        let traffic = |bytes_sent, bytes_received| Traffic {
+            bytes_sent,
+            bytes_received,
+            ..Default::default()
+        };
+        let mut top = Top::default();
+        top.update(vec![report(7, "vpn", Some(traffic(0, 0)))], 1_000);
+        top.update(vec![report(7, "vpn", Some(traffic(3_000, 500)))], 3_000);
+        let throughput = &top.throughput[&7];
+        assert_eq!(throughput.sent, [1_500]);
+        assert_eq!(throughput.received, [250]);
+
+        let screen = top.render(80, 24).join("\n");
+        assert!(screen.contains("Tunnel  up 1.5 KB/s  down 250 B/s"));
+
+        top.update(Vec::new(), 4_000);
+        assert!(top.throughput.is_empty());
+        assert!(
+            top.render(80, 24)
+                .join("\n")
+                .contains("No running x2ssh sessions")
+        );
+    }
+
+    #[test]
+    fn test_select() {
+        let mut top = Top::default();
+        top.update(vec![report(1, "socks", None), report(2, "vpn", None)], 0);
+        assert_eq!(top.selected, Some(1));
+        top.select(true);
+        assert_eq!(top.selected, Some(2));
+        top.select(true);
+        assert_eq!(top.selected, Some(1));
+        top.select(false);
+        assert_eq!(top.selected, Some(2));
+
+        // The session shown stays selected while it runs.
+        top.update(vec![report(3, "socks", None), report(2, "vpn", None)], 0);
+        assert_eq!(top.selected, Some(2));
+        top.update(vec![report(3, "socks", None)], 0);
+        assert_eq!(top.selected, Some(3));
+    }
+
+    #[test]
+    fn test_render_socks() {
+        let mut report = report(1, "socks", None);
+        report.socks_active = Some(3);
+        report.socks_destinations = [("a.example:443", 1), ("b.example:443", 2)]
+            .map(|(destination, count)| (destination.to_string(), count))
+            .into();
+        let mut top = Top::default();
+        top.update(vec![report], 0);
+        let screen = top.render(80, 24);
+        let socks = screen
+            .iter()
+            .position(|l| l == "SOCKS  3 open connection(s)");
+        let socks = socks.expect("a SOCKS section");
+        assert_eq!(screen[socks + 1], "      2  b.example:443");
+        assert_eq!(screen[socks + 2], "      1  a.example:443");
+
+        assert!(
+            top.render(10, 3)
+                .iter()
+                .all(|line| line.chars().count() <= 10)
+        );
+        assert_eq!(top.render(10, 3).len(), 3);
+    }
+
+    #[test]
+    fn test_sparkline() {
+        let samples = VecDeque::from([0, 50, 100]);
+        assert_eq!(sparkline(&samples, 10), "▁▅█");
+        assert_eq!(sparkline(&samples, 2), "▅█");
+        assert_eq!(sparkline(&VecDeque::from([0, 0]), 10), "▁▁");
+    }
+
+    #[test]
+    fn test_format_rate() {
+        assert_eq!(format_rate(999), "999 B/s");
+        assert_eq!(format_rate(1_500), "1.5 KB/s");
+        assert_eq!(format_rate(12_300_000), "12.3 MB/s");
+    }
+}
@@ -1,25 +1,66 @@
-use std::path::PathBuf;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::net::SocketAddr;
 use std::sync::Arc;
+use std::sync::atomic::AtomicU32;
+use std::sync::atomic::Ordering;
 use std::time::Duration;
 
+use futures::future::BoxFuture;
 use russh::ChannelMsg;
-use russh::keys::PrivateKeyWithHashAlg;
+use russh::Preferred;
+use russh::cipher;
+use russh::kex;
+use russh::keys::Algorithm;
+use russh::keys::HashAlg;
 use russh::keys::PublicKey;
+use russh::keys::ssh_key::Fingerprint;
+use socket2::SockRef;
+use socket2::TcpKeepalive;
 use tokio::io::AsyncRead;
+use tokio::io::AsyncReadExt;
 use tokio::io::AsyncWrite;
+use tokio::net::TcpStream;
 use tokio::net::ToSocketAddrs;
 use tokio::sync::Mutex;
+use tokio::sync::watch;
+use tokio_util::sync::CancellationToken;
 use tracing::debug;
+use tracing::error;
 use tracing::info;
 use tracing::warn;
 
+use crate::auth;
+use crate::auth::AuthContext;
+use crate::auth::AuthMethod;
+use crate::config::HealthCheck;
+use crate::journal::Journal;
+use crate::journal::JournalEvent;
+use crate::metrics::Metric;
+use crate::metrics::MetricsSink;
+use crate::otel::SpanKind;
+use crate::otel::Tracer;
+use crate::retry::DegradeThresholds;
+use crate::retry::ErrorClass;
+use crate::retry::GaveUp;
+use crate::retry::HealthChange;
+use crate::retry::ProbeWindow;
 use crate::retry::RetryPolicy;
+use crate::shutdown::copy_until_shutdown;
+use crate::stats::StatsRecorder;
+use crate::status::DisconnectCause;
+use crate::status::Timeline;
+use crate::status::TimelineEvent;
+use crate::systemd;
 
 #[cfg(test)]
 mod tests {
     use std::path::PathBuf;
 
+    use russh::client::Handler;
+
     use super::*;
+    use crate::auth::PublicKeyAuth;
 
     #[tokio::test]
     async fn transport_connect_invalid_host() {
@@ -32,9 +73,20 @@ mod tests {
                 initial_delay: Duration::from_millis(10),
                 backoff: 1.0,
                 max_delay: Duration::from_millis(10),
+                max_total_duration: None,
             },
             health_interval: Duration::from_secs(1),
-            key_path: Some(key_path),
+            health: HealthOptions::default(),
+            shutdown_timeout: Duration::from_secs(1),
+            tcp: TcpOptions::default(),
+            legacy_server: false,
+            journal: None,
+            stats: None,
+            metrics: Arc::new(crate::metrics::NoopMetrics),
+            tracer: Tracer::default(),
+            timeline: Arc::new(Timeline::new()),
+            auth: vec![Arc::new(PublicKeyAuth::new(key_path, None))],
+            host_key: None,
             user: "root".to_string(),
             host: "255.255.255.255".to_string(),
             port: 22,
@@ -43,6 +95,110 @@ mod tests {
         let result = Transport::connect(config).await;
         assert!(result.is_err(), "Connection to invalid host should fail");
     }
+
+    #[tokio::test]
+    async fn no_retry_without_auth_method() {
+        let config = TransportConfig {
+            retry_policy: RetryPolicy::default(),
+            health_interval: Duration::from_secs(1),
+            health: HealthOptions::default(),
+            shutdown_timeout: Duration::from_secs(1),
+            tcp: TcpOptions::default(),
+            legacy_server: false,
+            journal: None,
+            stats: None,
+            metrics: Arc::new(crate::metrics::NoopMetrics),
+            tracer: Tracer::default(),
+            timeline: Arc::new(Timeline::new()),
+            auth: Vec::new(),
+            host_key: None,
+            user: "root".to_string(),
+            host: "127.0.0.1".to_string(),
+            port: 22,
+        };
+
+        // Retrying forever, this would not return before the timeout.
+        let result = tokio::time::timeout(
+            Duration::from_secs(5),
+            Transport::connect_with_retry(config),
+        )
+        .await
+        .expect("an authentication error is not retried");
+        let err = result.err().unwrap();
+        assert_eq!(ErrorClass::of(&err), ErrorClass::Auth);
+        assert_eq!(
+            err.to_string(),
+            "No identity file or other authentication method specified"
+        );
+    }
+
+    #[tokio::test]
+    async fn pinned_host_key() {
+        let key = PublicKey::from_openssh(
+            "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIHJrlegPyj/hcu4ioDgk8xfLNu0wq7witsy7/fm3eU78",
+        )
+        .unwrap();
+        let mut client = Client {
+            host_key: Some(
+                parse_host_key("SHA256:RbfGliidVlNx91NGl6K8xWLhOZAspnCgX2R8U2vfsIQ").unwrap(),
+            ),
+            ..Default::default()
+        };
+        assert!(client.check_server_key(&key).await.unwrap());
+
+        client.host_key =
+            Some(parse_host_key("SHA256:uNiVztksCsDhcc0u9e8BujQXVUpKZIDTMczCvj3tD2s").unwrap());
+        assert!(!client.check_server_key(&key).await.unwrap());
+
+        assert!(parse_host_key("RbfGliidVlNx91NGl6K8xWLhOZAspnCgX2R8U2vfsIQ").is_err());
+    }
+
+    #[test]
+    fn additional_host_keys_skips_current_key() {
+        let current = PublicKey::from_openssh(
+            "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIHJrlegPyj/hcu4ioDgk8xfLNu0wq7witsy7/fm3eU78",
+        )
+        .unwrap();
+        let rotated = PublicKey::from_openssh(
+            "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAID8EmsG5fHsVfOzMnD/ngcDntIssaGmvdJCYXqS6wg9Y \
+             new@server",
+        )
+        .unwrap();
+        let announced = vec![current.clone(), rotated.clone()];
+
+        assert_eq!(additional_host_keys(Some(&current), &announced), vec![
+            &rotated
+        ]);
+        assert_eq!(additional_host_keys(None, &announced).len(), 2);
+        assert!(additional_host_keys(Some(&current), &announced[..1]).is_empty());
+    }
+
+    #[test]
+    fn legacy_server_relaxes_algorithms() {
+        let sha1 = Algorithm::Rsa { hash: None };
+
+        let strict = preferred(false);
+        assert!(!strict.key.contains(&sha1));
+        assert!(!strict.kex.contains(&kex::DH_G14_SHA1));
+        assert!(!strict.cipher.contains(&cipher::AES_128_CBC));
+
+        let legacy = preferred(true);
+        assert!(legacy.key.contains(&sha1));
+        assert!(legacy.kex.contains(&kex::DH_G14_SHA1));
+        assert!(legacy.cipher.contains(&cipher::AES_128_CBC));
+        // Modern algorithms still win when the server has them.
+        assert_eq!(legacy.kex[0], strict.kex[0]);
+        assert_eq!(legacy.cipher[0], strict.cipher[0]);
+
+        assert_eq!(
+            rsa_hash(Some(Some(HashAlg::Sha512)), false),
+            Some(HashAlg::Sha512)
+        );
+        assert_eq!(rsa_hash(None, false), Some(HashAlg::Sha256));
+        assert_eq!(rsa_hash(Some(None), false), Some(HashAlg::Sha256));
+        assert_eq!(rsa_hash(None, true), None);
+        assert_eq!(rsa_hash(Some(None), true), None);
+    }
 }
 
 #[derive(Debug)]
@@ -52,29 +208,510 @@ pub struct ExecResult {
     pub stderr: Vec<u8>,
 }
 
-struct Client;
+/// Handles server-initiated messages. russh itself answers keepalives and
+/// rejects unknown global and channel requests; this logs what is worth
+/// knowing, connects the `forwarded-tcpip` channels of remote forwards to
+/// their targets and closes any other channel the server opens (we never
+/// request agent forwarding or X11).
+///
+/// Public only as the handler type of [`SessionHandle`]; it cannot be
+/// constructed or customised outside this module.
+#[derive(Default)]
+pub struct Client {
+    server_key: Option<PublicKey>,
+    /// The only host key accepted, if pinned.
+    host_key: Option<Fingerprint>,
+    remote_forwards: RemoteForwards,
+    /// The transport's shutdown, on which remote forward connections end
+    /// with an EOF.
+    shutdown: CancellationToken,
+}
+
+impl Client {
+    fn reject_channel(&self, channel: russh::Channel<russh::client::Msg>, kind: &str) {
+        warn!(
+            "Closing unexpected {} channel {:?} opened by the server",
+            kind,
+            channel.id()
+        );
+        // Closing goes through the session loop that is running this
+        // handler, so it must not be awaited here.
+        tokio::spawn(async move {
+            let _ = channel.close().await;
+        });
+    }
+}
 
 impl russh::client::Handler for Client {
     type Error = russh::Error;
 
     async fn check_server_key(
         &mut self,
-        _server_public_key: &PublicKey,
+        server_public_key: &PublicKey,
     ) -> Result<bool, Self::Error> {
+        self.server_key = Some(server_public_key.clone());
+        if let Some(expected) = self.host_key
+            && server_public_key.fingerprint(expected.algorithm()) != expected
+        {
+            error!(
+                "Server host key {} does not match the pinned host key {}",
+                server_public_key.fingerprint(HashAlg::Sha256),
+                expected
+            );
+            return Ok(false);
+        }
         Ok(true)
     }
+
+    async fn auth_banner(
+        &mut self,
+        banner: &str,
+        _session: &mut russh::client::Session,
+    ) -> Result<(), Self::Error> {
+        let banner = banner.trim();
+        if !banner.is_empty() {
+            info!("Server banner: {}", banner);
+        }
+        Ok(())
+    }
+
+    async fn openssh_ext_host_keys_announced(
+        &mut self,
+        keys: Vec<PublicKey>,
+        _session: &mut russh::client::Session,
+    ) -> Result<(), Self::Error> {
+        let added = additional_host_keys(self.server_key.as_ref(), &keys);
+        if added.is_empty() {
+            debug!("Server announced {} host key(s), none new", keys.len());
+        } else {
+            for key in added {
+                info!(
+                    "Server announced additional host key {} {} (key rotation)",
+                    key.algorithm(),
+                    key.fingerprint(russh::keys::HashAlg::Sha256)
+                );
+            }
+        }
+        Ok(())
+    }
+
+    async fn exit_signal(
+        &mut self,
+        channel: russh::ChannelId,
+        signal_name: russh::Sig,
+        core_dumped: bool,
+        error_message: &str,
+        _lang_tag: &str,
+        _session: &mut russh::client::Session,
+    ) -> Result<(), Self::Error> {
+        warn!(
+            "Remote process on channel {:?} killed by signal {:?}{}{}",
+            channel,
+            signal_name,
+            if core_dumped { " (core dumped)" } else { "" },
+            if error_message.is_empty() {
+                String::new()
+            } else {
+                format!(": {error_message}")
+            }
+        );
+        Ok(())
+    }
+
+    async fn server_channel_open_forwarded_tcpip(
+        &mut self,
+        channel: russh::Channel<russh::client::Msg>,
+        connected_address: &str,
+        connected_port: u32,
+        originator_address: &str,
+        originator_port: u32,
+        _session: &mut russh::client::Session,
+    ) -> Result<(), Self::Error> {
+        let target = {
+            let forwards = self.remote_forwards.lock().unwrap();
+            // The server may name the address differently than it was
+            // requested, so the port alone has to do as a fallback.
+            forwards
+                .get(&(connected_address.to_string(), connected_port))
+                .or_else(|| {
+                    forwards
+                        .iter()
+                        .find(|((_, port), _)| *port == connected_port)
+                        .map(|(_, target)| target)
+                })
+                .cloned()
+        };
+        let Some((host, port)) = target else {
+            self.reject_channel(channel, "forwarded-tcpip");
+            return Ok(());
+        };
+        debug!(
+            "Remote forward {}:{} from {}:{} to {}:{}",
+            connected_address, connected_port, originator_address, originator_port, host, port
+        );
+        let shutdown = self.shutdown.clone();
+        tokio::spawn(async move {
+            let mut stream = channel.into_stream();
+            let result = match TcpStream::connect((host.as_str(), port)).await {
+                // The same 8 KiB buffers tokio::io::copy_bidirectional uses.
+                Ok(mut target) => {
+                    copy_until_shutdown(&mut stream, &mut target, 8 * 1024, &shutdown)
+                        .await
+                        .transpose()
+                        .map(|_| ())
+                }
+                Err(e) => Err(e),
+            };
+            if let Err(e) = result {
+                debug!("Remote forward to {}:{} failed: {}", host, port, e);
+            }
+        });
+        Ok(())
+    }
+
+    async fn server_channel_open_forwarded_streamlocal(
+        &mut self,
+        channel: russh::Channel<russh::client::Msg>,
+        _socket_path: &str,
+        _session: &mut russh::client::Session,
+    ) -> Result<(), Self::Error> {
+        self.reject_channel(channel, "forwarded-streamlocal");
+        Ok(())
+    }
+
+    async fn server_channel_open_agent_forward(
+        &mut self,
+        channel: russh::Channel<russh::client::Msg>,
+        _session: &mut russh::client::Session,
+    ) -> Result<(), Self::Error> {
+        self.reject_channel(channel, "agent");
+        Ok(())
+    }
+
+    async fn server_channel_open_session(
+        &mut self,
+        channel: russh::Channel<russh::client::Msg>,
+        _session: &mut russh::client::Session,
+    ) -> Result<(), Self::Error> {
+        self.reject_channel(channel, "session");
+        Ok(())
+    }
+
+    async fn server_channel_open_direct_tcpip(
+        &mut self,
+        channel: russh::Channel<russh::client::Msg>,
+        _host_to_connect: &str,
+        _port_to_connect: u32,
+        _originator_address: &str,
+        _originator_port: u32,
+        _session: &mut russh::client::Session,
+    ) -> Result<(), Self::Error> {
+        self.reject_channel(channel, "direct-tcpip");
+        Ok(())
+    }
+
+    async fn server_channel_open_direct_streamlocal(
+        &mut self,
+        channel: russh::Channel<russh::client::Msg>,
+        _socket_path: &str,
+        _session: &mut russh::client::Session,
+    ) -> Result<(), Self::Error> {
+        self.reject_channel(channel, "direct-streamlocal");
+        Ok(())
+    }
+
+    async fn server_channel_open_x11(
+        &mut self,
+        channel: russh::Channel<russh::client::Msg>,
+        _originator_address: &str,
+        _originator_port: u32,
+        _session: &mut russh::client::Session,
+    ) -> Result<(), Self::Error> {
+        self.reject_channel(channel, "x11");
+        Ok(())
+    }
+
+    async fn disconnected(
+        &mut self,
+        reason: russh::client::DisconnectReason<Self::Error>,
+    ) -> Result<(), Self::Error> {
+        match reason {
+            russh::client::DisconnectReason::ReceivedDisconnect(info) => {
+                warn!(
+                    "Server disconnected ({:?}): {}",
+                    info.reason_code,
+                    info.message.trim()
+                );
+                Ok(())
+            }
+            russh::client::DisconnectReason::Error(e) => Err(e),
+        }
+    }
 }
 
+/// The delay before the attempt after failed `attempt`, `elapsed` after the
+/// first failure; `None` to give up, saying why unless it is for running out
+/// of attempts.
+fn next_delay(
+    policy: &RetryPolicy,
+    attempt: u32,
+    class: ErrorClass,
+    elapsed: Duration,
+) -> Option<Duration> {
+    if !policy.should_retry_after(attempt, class) {
+        if !class.is_retryable() {
+            warn!(
+                "Connection attempt {} failed with an {} error; not retrying, as another attempt \
+                 cannot fix it",
+                attempt, class
+            );
+        }
+        return None;
+    }
+    if let Some(max) = policy.max_total_duration
+        && policy.past_deadline(elapsed, attempt)
+    {
+        warn!(
+            "Connection attempt {} failed; not retrying, as the next attempt would start past the \
+             retry limit of {:?}",
+            attempt, max
+        );
+        return None;
+    }
+    Some(policy.delay_for_attempt(attempt))
+}
+
+/// Parses a pinned host key fingerprint as `ssh-keygen -l` prints it.
+pub(crate) fn parse_host_key(fingerprint: &str) -> anyhow::Result<Fingerprint> {
+    fingerprint.trim().parse().map_err(|e| {
+        anyhow::anyhow!(
+            "Invalid host key fingerprint '{}' (expected SHA256:...): {}",
+            fingerprint,
+            e
+        )
+    })
+}
+
+/// Keys from a `hostkeys-00@openssh.com` announcement other than the one the
+/// server authenticated with.
+fn additional_host_keys<'a>(
+    current: Option<&PublicKey>,
+    announced: &'a [PublicKey],
+) -> Vec<&'a PublicKey> {
+    announced
+        .iter()
+        .filter(|key| current.is_none_or(|current| current.key_data() != key.key_data()))
+        .collect()
+}
+
+/// Algorithms offered to the server: the russh defaults without `ssh-rsa`
+/// (SHA-1) host key signatures. `legacy` puts that back and adds, below
+/// everything else, the SHA-1 and NIST key exchanges and CBC ciphers that
+/// old dropbear and OpenSSH builds are limited to.
+fn preferred(legacy: bool) -> Preferred {
+    let defaults = Preferred::DEFAULT;
+    if !legacy {
+        let sha1 = Algorithm::Rsa { hash: None };
+        return Preferred {
+            key: defaults
+                .key
+                .iter()
+                .filter(|key| **key != sha1)
+                .cloned()
+                .collect(),
+            ..defaults
+        };
+    }
+
+    let mut kex = defaults.kex.to_vec();
+    kex.extend([
+        kex::ECDH_SHA2_NISTP256,
+        kex::ECDH_SHA2_NISTP384,
+        kex::ECDH_SHA2_NISTP521,
+        kex::DH_G14_SHA1,
+        kex::DH_GEX_SHA1,
+        kex::DH_G1_SHA1,
+    ]);
+    let mut ciphers = defaults.cipher.to_vec();
+    ciphers.extend([
+        cipher::AES_256_CBC,
+        cipher::AES_192_CBC,
+        cipher::AES_128_CBC,
+    ]);
+    Preferred {
+        kex: kex.into(),
+        cipher: ciphers.into(),
+        ..defaults
+    }
+}
+
+/// Group sizes asked for in a group-exchange key exchange. Old servers'
+/// moduli files often stop at 2048 bits, the smallest russh accepts.
+fn gex_params(legacy: bool) -> russh::client::GexParams {
+    if legacy {
+        russh::client::GexParams::new(2048, 4096, 8192).expect("valid group sizes")
+    } else {
+        russh::client::GexParams::default()
+    }
+}
+
+/// The hash for RSA signatures, given what the server announced in
+/// `server-sig-algs` (see `best_supported_rsa_hash`). SHA-1 (`ssh-rsa`) is
+/// only used in legacy mode; otherwise a server that announces nothing is
+/// tried with SHA-256.
+pub(crate) fn rsa_hash(advertised: Option<Option<HashAlg>>, legacy: bool) -> Option<HashAlg> {
+    match advertised {
+        Some(Some(hash)) => Some(hash),
+        _ if legacy => None,
+        _ => Some(HashAlg::Sha256),
+    }
+}
+
+/// Bidirectional byte stream over a `direct-tcpip` channel.
+pub type ForwardStream = russh::ChannelStream<russh::client::Msg>;
+
+/// The underlying russh session handle; see [`Transport::with_session`].
+pub type SessionHandle = russh::client::Handle<Client>;
+
+/// Where the server's connections to each remote forward go: the address
+/// and port it listens on, to the host and port connected to from here.
+type RemoteForwards = Arc<std::sync::Mutex<HashMap<(String, u32), (String, u16)>>>;
+
+/// Called with the server's addresses before each reconnect attempt; see
+/// [`Transport::set_resolve_hook`].
+pub type ResolveHook =
+    Arc<dyn Fn(Vec<IpAddr>) -> BoxFuture<'static, anyhow::Result<()>> + Send + Sync>;
+
 pub struct Transport {
-    session: Mutex<russh::client::Handle<Client>>,
+    session: Mutex<SessionHandle>,
+    endpoints: std::sync::Mutex<Endpoints>,
+    reconnected: watch::Sender<u64>,
+    resolve_hook: std::sync::Mutex<Option<ResolveHook>>,
+    remote_forwards: RemoteForwards,
+    /// Health probes failed in a row on the current session.
+    failed_probes: AtomicU32,
+    /// Recent health probes, across sessions.
+    probes: std::sync::Mutex<ProbeWindow>,
+    /// Cancelled once the session is shutting down.
+    shutdown: CancellationToken,
     config: TransportConfig,
 }
 
+/// Local and remote addresses of the current SSH TCP connection.
+#[derive(Clone, Copy, Debug)]
+pub struct Endpoints {
+    pub local: SocketAddr,
+    pub peer: SocketAddr,
+}
+
+/// How [`Transport::health_check`] tells a live connection from a dead one.
+#[derive(Clone, Debug)]
+pub struct HealthOptions {
+    pub check: HealthCheck,
+    /// How long one probe may take; for [`HealthCheck::Tcp`], the time
+    /// between TCP keepalive probes.
+    pub timeout: Duration,
+    /// Failed probes in a row before the connection counts as lost.
+    pub failures: u32,
+    /// Where [`HealthCheck::Echo`] probes connect, as seen from the server.
+    pub echo_target: (String, u16),
+    /// When slow or failing probes are reported, before they add up to a
+    /// lost connection.
+    pub degrade: DegradeThresholds,
+}
+
+impl Default for HealthOptions {
+    fn default() -> Self {
+        Self {
+            check: HealthCheck::default(),
+            timeout: Duration::from_secs(10),
+            failures: 1,
+            echo_target: ("127.0.0.1".to_string(), 22),
+            degrade: DegradeThresholds::default(),
+        }
+    }
+}
+
+impl HealthOptions {
+    /// For [`HealthCheck::Tcp`], has the kernel probe the SSH connection
+    /// after `idle` without traffic, giving up after `failures` unanswered
+    /// probes `timeout` apart.
+    fn apply(&self, stream: &TcpStream, idle: Duration) -> std::io::Result<()> {
+        if self.check != HealthCheck::Tcp {
+            return Ok(());
+        }
+        let keepalive = TcpKeepalive::new().with_time(idle);
+        #[cfg(any(target_os = "linux", target_os = "macos"))]
+        let keepalive = keepalive
+            .with_interval(self.timeout)
+            .with_retries(self.failures);
+        SockRef::from(stream).set_tcp_keepalive(&keepalive)
+    }
+}
+
+/// Socket options applied to TCP connections (the SSH connection itself and
+/// accepted SOCKS clients).
+#[derive(Clone, Debug)]
+pub struct TcpOptions {
+    pub nodelay: bool,
+    pub keepalive: Option<Duration>,
+    pub recv_buffer_size: Option<usize>,
+    pub send_buffer_size: Option<usize>,
+}
+
+impl Default for TcpOptions {
+    fn default() -> Self {
+        Self {
+            nodelay: true,
+            keepalive: None,
+            recv_buffer_size: None,
+            send_buffer_size: None,
+        }
+    }
+}
+
+impl TcpOptions {
+    pub fn apply(&self, stream: &TcpStream) -> std::io::Result<()> {
+        stream.set_nodelay(self.nodelay)?;
+
+        let socket = SockRef::from(stream);
+        if let Some(time) = self.keepalive {
+            socket.set_tcp_keepalive(&TcpKeepalive::new().with_time(time))?;
+        }
+        if let Some(size) = self.recv_buffer_size {
+            socket.set_recv_buffer_size(size)?;
+        }
+        if let Some(size) = self.send_buffer_size {
+            socket.set_send_buffer_size(size)?;
+        }
+
+        Ok(())
+    }
+}
+
 #[derive(Clone)]
 pub struct TransportConfig {
     pub retry_policy: RetryPolicy,
     pub health_interval: Duration,
-    pub key_path: Option<PathBuf>,
+    pub health: HealthOptions,
+    /// How long to wait for each channel close on exit.
+    pub shutdown_timeout: Duration,
+    pub tcp: TcpOptions,
+    /// Offer the weak algorithms old servers need; see [`preferred`].
+    pub legacy_server: bool,
+    pub journal: Option<Arc<Journal>>,
+    /// Where the session's traffic totals are kept for `x2ssh stats`.
+    pub stats: Option<Arc<StatsRecorder>>,
+    pub metrics: Arc<dyn MetricsSink>,
+    /// Where spans of reconnects and SOCKS connections go.
+    pub tracer: Tracer,
+    /// Where disconnects and reconnects are recorded.
+    pub timeline: Arc<Timeline>,
+    /// How to log in, tried in order on each connect until the server
+    /// accepts one.
+    pub auth: Vec<Arc<dyn AuthMethod>>,
+    /// The server's expected host key fingerprint; any key if `None`.
+    pub host_key: Option<String>,
     pub user: String,
     pub host: String,
     pub port: u16,
@@ -82,91 +719,470 @@ pub struct TransportConfig {
 
 impl Transport {
     pub async fn connect(config: TransportConfig) -> anyhow::Result<Self> {
-        let session = Self::connect_once(&config).await?;
+        if config.legacy_server {
+            warn!(
+                "Legacy server mode: allowing SHA-1 key exchange, ssh-rsa signatures and CBC \
+                 ciphers, which an active attacker may be able to break"
+            );
+        }
+        let addrs = Self::resolve(&config).await?;
+        let remote_forwards = RemoteForwards::default();
+        let shutdown = CancellationToken::new();
+        let (session, endpoints) =
+            Self::connect_once(&config, &addrs, &remote_forwards, &shutdown).await?;
         Ok(Self {
             session: Mutex::new(session),
+            endpoints: std::sync::Mutex::new(endpoints),
+            reconnected: watch::Sender::new(0),
+            resolve_hook: std::sync::Mutex::new(None),
+            remote_forwards,
+            failed_probes: AtomicU32::new(0),
+            probes: std::sync::Mutex::new(ProbeWindow::new(config.health.degrade.clone())),
+            shutdown,
             config,
         })
     }
 
+    /// [`Transport::connect`], retrying per the retry policy. Errors that
+    /// another attempt cannot fix, such as a rejected key, are returned
+    /// right away.
+    pub async fn connect_with_retry(config: TransportConfig) -> anyhow::Result<Self> {
+        let started = std::time::Instant::now();
+        let mut attempt = 0;
+        loop {
+            let e = match Self::connect(config.clone()).await {
+                Ok(transport) => return Ok(transport),
+                Err(e) => e,
+            };
+            let class = ErrorClass::of(&e);
+            let Some(delay) = next_delay(&config.retry_policy, attempt, class, started.elapsed())
+            else {
+                return Err(e);
+            };
+            warn!(
+                "Connection attempt {} failed ({} error): {}. Retrying in {:?}...",
+                attempt, class, e, delay
+            );
+            tokio::time::sleep(delay).await;
+            attempt += 1;
+        }
+    }
+
+    /// Every address the server's host name resolves to, in the resolver's
+    /// order.
+    async fn resolve(config: &TransportConfig) -> anyhow::Result<Vec<SocketAddr>> {
+        let addrs: Vec<SocketAddr> = tokio::net::lookup_host((config.host.as_str(), config.port))
+            .await
+            .map_err(|e| ErrorClass::Dns.wrap(e))?
+            .collect();
+        if addrs.is_empty() {
+            return Err(
+                ErrorClass::Dns.wrap(anyhow::anyhow!("Failed to resolve host: {}", config.host))
+            );
+        }
+        Ok(addrs)
+    }
+
+    /// The addresses the server's host name resolves to now.
+    pub async fn resolve_server(&self) -> anyhow::Result<Vec<IpAddr>> {
+        let addrs = Self::resolve(&self.config).await?;
+        Ok(addrs.iter().map(SocketAddr::ip).collect())
+    }
+
+    /// Has `hook` run with the server's freshly resolved addresses before
+    /// each reconnect attempt connects to them, e.g. to keep routes to the
+    /// server off a VPN tunnel that is down. A failing hook fails the
+    /// attempt. `None` removes it.
+    pub fn set_resolve_hook(&self, hook: Option<ResolveHook>) {
+        *self.resolve_hook.lock().unwrap() = hook;
+    }
+
+    /// Resolves the host again and runs the resolve hook, if any.
+    async fn prepare_reconnect(&self) -> anyhow::Result<Vec<SocketAddr>> {
+        let addrs = Self::resolve(&self.config).await?;
+        let hook = self.resolve_hook.lock().unwrap().clone();
+        if let Some(hook) = hook {
+            hook(addrs.iter().map(SocketAddr::ip).collect()).await?;
+        }
+        Ok(addrs)
+    }
+
     async fn connect_once(
         config: &TransportConfig,
-    ) -> anyhow::Result<russh::client::Handle<Client>> {
-        let key_path = config
-            .key_path
-            .as_ref()
-            .ok_or_else(|| anyhow::anyhow!("No identity file specified"))?;
-
-        let key_pair = russh::keys::load_secret_key(key_path, None)?;
-
-        let ssh_config = Arc::new(russh::client::Config::default());
-        let sh = Client;
-
-        let addr = format!("{}:{}", config.host, config.port);
-        let mut session = russh::client::connect(ssh_config, &addr, sh).await?;
-
-        let auth_res = session
-            .authenticate_publickey(
-                &config.user,
-                PrivateKeyWithHashAlg::new(
-                    Arc::new(key_pair),
-                    session.best_supported_rsa_hash().await?.flatten(),
-                ),
-            )
-            .await?;
-
-        if !auth_res.success() {
-            anyhow::bail!("Authentication failed");
+        addrs: &[SocketAddr],
+        remote_forwards: &RemoteForwards,
+        shutdown: &CancellationToken,
+    ) -> anyhow::Result<(SessionHandle, Endpoints)> {
+        if config.auth.is_empty() {
+            return Err(ErrorClass::Auth.wrap(anyhow::anyhow!(
+                "No identity file or other authentication method specified"
+            )));
         }
+        let host_key = config
+            .host_key
+            .as_deref()
+            .map(parse_host_key)
+            .transpose()
+            .map_err(|e| ErrorClass::Auth.wrap(e))?;
+
+        let ssh_config = Arc::new(russh::client::Config {
+            nodelay: config.tcp.nodelay,
+            preferred: preferred(config.legacy_server),
+            gex: gex_params(config.legacy_server),
+            ..Default::default()
+        });
+        let sh = Client {
+            host_key,
+            remote_forwards: remote_forwards.clone(),
+            shutdown: shutdown.clone(),
+            ..Default::default()
+        };
+
+        let stream = TcpStream::connect(addrs).await?;
+        config.tcp.apply(&stream)?;
+        config.health.apply(&stream, config.health_interval)?;
+        let endpoints = Endpoints {
+            local: stream.local_addr()?,
+            peer: stream.peer_addr()?,
+        };
+        let mut session = russh::client::connect_stream(ssh_config, stream, sh).await?;
+
+        auth::authenticate(&mut session, &config.auth, AuthContext {
+            user: &config.user,
+            legacy_server: config.legacy_server,
+        })
+        .await?;
 
-        Ok(session)
+        Ok((session, endpoints))
     }
 
-    pub async fn reconnect(&self) -> anyhow::Result<()> {
+    /// Replaces the SSH session with a new one, retrying per the retry
+    /// policy. `cause` goes into the timeline along with the outcome.
+    pub async fn reconnect(&self, cause: DisconnectCause) -> anyhow::Result<()> {
+        let timeline = &self.config.timeline;
+        timeline.record(TimelineEvent::Disconnected { cause });
+        systemd::reconnecting();
+        let mut span = self.config.tracer.root("ssh.reconnect", SpanKind::Internal);
+        span.set("disconnect.cause", cause.to_string());
+        let started = std::time::Instant::now();
         let mut attempt = 0;
-        loop {
-            match Self::connect_once(&self.config).await {
-                Ok(session) => {
+        let result = loop {
+            timeline.set_attempt(attempt + 1);
+            let mut attempt_span = span.child("ssh.connect");
+            attempt_span.set("attempt", attempt + 1);
+            let attempt_result = match self.prepare_reconnect().await {
+                Ok(addrs) => {
+                    Self::connect_once(&self.config, &addrs, &self.remote_forwards, &self.shutdown)
+                        .await
+                }
+                Err(e) => Err(e),
+            };
+            if let Err(e) = &attempt_result {
+                attempt_span.fail(e);
+            }
+            attempt_span.end();
+            match attempt_result {
+                Ok((mut session, endpoints)) => {
+                    timeline.set_attempt(0);
+                    self.restore_remote_forwards(&mut session).await;
                     *self.session.lock().await = session;
+                    *self.endpoints.lock().unwrap() = endpoints;
+                    self.failed_probes.store(0, Ordering::Relaxed);
+                    self.reconnected.send_modify(|generation| *generation += 1);
+                    self.config.metrics.record(Metric::Reconnected);
+                    timeline.record(TimelineEvent::Reconnected {
+                        downtime_ms: started.elapsed().as_millis() as u64,
+                        attempts: attempt + 1,
+                    });
                     info!("SSH session reconnected");
-                    return Ok(());
+                    systemd::reconnected();
+                    break Ok(());
                 }
                 Err(e) => {
-                    if !self.config.retry_policy.should_retry(attempt) {
-                        return Err(e);
-                    }
-
-                    let delay = self.config.retry_policy.delay_for_attempt(attempt);
+                    let class = ErrorClass::of(&e);
+                    let elapsed = started.elapsed();
+                    let Some(delay) =
+                        next_delay(&self.config.retry_policy, attempt, class, elapsed)
+                    else {
+                        let gave_up = GaveUp {
+                            attempts: attempt + 1,
+                            elapsed,
+                        };
+                        error!("SSH session lost for good: {}", gave_up);
+                        systemd::gave_up();
+                        timeline.set_attempt(0);
+                        self.config.metrics.record(Metric::ReconnectFailed);
+                        timeline.record(TimelineEvent::ReconnectFailed {
+                            attempts: gave_up.attempts,
+                            class: Some(class),
+                            downtime_ms: elapsed.as_millis() as u64,
+                        });
+                        span.set("error.class", class.to_string());
+                        span.fail(gave_up);
+                        break Err(e.context(gave_up));
+                    };
                     warn!(
-                        "Connection attempt {} failed: {}. Retrying in {:?}...",
-                        attempt, e, delay
+                        "Connection attempt {} failed ({} error): {}. Retrying in {:?}...",
+                        attempt, class, e, delay
                     );
 
-                    tokio::time::sleep(delay).await;
-                    attempt += 1;
+                    tokio::select! {
+                        () = tokio::time::sleep(delay) => attempt += 1,
+                        () = self.shutdown.cancelled() => {
+                            timeline.set_attempt(0);
+                            span.fail("shutting down");
+                            break Err(e.context("Reconnect stopped by shutdown"));
+                        }
+                    }
                 }
             }
+        };
+        span.set("reconnect.attempts", attempt + 1);
+        result
+    }
+
+    /// Asks a new session's server to listen for the remote forwards the
+    /// previous one had.
+    async fn restore_remote_forwards(&self, session: &mut SessionHandle) {
+        let listens: Vec<(String, u32)> = self
+            .remote_forwards
+            .lock()
+            .unwrap()
+            .keys()
+            .cloned()
+            .collect();
+        for (address, port) in listens {
+            if let Err(e) = session.tcpip_forward(address.as_str(), port).await {
+                warn!(
+                    "Failed to restore remote forward {}:{}: {}",
+                    address, port, e
+                );
+            }
         }
     }
 
-    pub async fn check_alive(&self) -> anyhow::Result<()> {
+    /// Has the server listen on `address:port` and connect what arrives
+    /// there to `host:host_port`, as seen from here (`ssh -R`).
+    pub async fn add_remote_forward(
+        &self,
+        address: &str,
+        port: u16,
+        host: &str,
+        host_port: u16,
+    ) -> anyhow::Result<()> {
+        let mut session = self.session.lock().await;
+        session
+            .tcpip_forward(address, port.into())
+            .await
+            .map_err(|e| {
+                anyhow::anyhow!("server refused to listen on {}:{}: {}", address, port, e)
+            })?;
+        self.remote_forwards.lock().unwrap().insert(
+            (address.to_string(), port.into()),
+            (host.to_string(), host_port),
+        );
+        Ok(())
+    }
+
+    /// Stops the server listening for a remote forward.
+    pub async fn cancel_remote_forward(&self, address: &str, port: u16) -> anyhow::Result<()> {
+        self.remote_forwards
+            .lock()
+            .unwrap()
+            .remove(&(address.to_string(), port.into()));
         let session = self.session.lock().await;
         session
-            .channel_open_session()
+            .cancel_tcpip_forward(address, port.into())
             .await
-            .map(|ch| {
-                tokio::spawn(async move {
-                    let _ = ch.close().await;
-                });
-            })
+            .map_err(|e| anyhow::anyhow!("cannot cancel remote forward: {}", e))
+    }
+
+    /// Appends `event` to the session journal, and counts the traffic of
+    /// forwards in the stats file, where configured.
+    pub fn record(&self, event: JournalEvent) {
+        if let Some(stats) = &self.config.stats {
+            stats.observe(&event);
+        }
+        if let Some(journal) = &self.config.journal
+            && let Err(e) = journal.record(event)
+        {
+            warn!("Failed to write session journal: {}", e);
+        }
+    }
+
+    /// Where activity counters go.
+    pub fn metrics(&self) -> &Arc<dyn MetricsSink> {
+        &self.config.metrics
+    }
+
+    /// Where spans go.
+    pub fn tracer(&self) -> &Tracer {
+        &self.config.tracer
+    }
+
+    /// The disconnect and reconnect history of this session.
+    pub fn timeline(&self) -> &Arc<Timeline> {
+        &self.config.timeline
+    }
+
+    /// Why a failed health check failed: the session being closed means
+    /// the remote end (or the network) dropped it.
+    pub async fn lost_cause(&self) -> DisconnectCause {
+        if self.is_closed().await {
+            DisconnectCause::RemoteClosed
+        } else {
+            DisconnectCause::HealthCheck
+        }
+    }
+
+    /// Subscribes to reconnects; the value is bumped every time a new SSH
+    /// session replaces the previous one.
+    pub fn reconnects(&self) -> watch::Receiver<u64> {
+        self.reconnected.subscribe()
+    }
+
+    /// Whether the current SSH session has been disconnected.
+    pub async fn is_closed(&self) -> bool {
+        self.session.lock().await.is_closed()
+    }
+
+    /// How often the connection should be checked with [`health_check`].
+    ///
+    /// [`health_check`]: Transport::health_check
+    pub fn health_interval(&self) -> Duration {
+        self.config.health_interval
+    }
+
+    /// How long to wait for the server to answer a channel close on exit.
+    pub fn shutdown_timeout(&self) -> Duration {
+        self.config.shutdown_timeout
+    }
+
+    /// Cancelled when the session starts shutting down. Tasks on it stop at
+    /// the next point where stopping leaves nothing half-written: between
+    /// frames, or by closing their channel with an EOF.
+    pub fn shutdown(&self) -> &CancellationToken {
+        &self.shutdown
+    }
+
+    pub fn endpoints(&self) -> Endpoints {
+        *self.endpoints.lock().unwrap()
+    }
+
+    /// Probes the connection once, the configured way.
+    pub async fn check_alive(&self) -> anyhow::Result<()> {
+        let health = &self.config.health;
+        let probe = async {
+            let echo = {
+                let session = self.session.lock().await;
+                match health.check {
+                    HealthCheck::Channel => {
+                        let channel = session.channel_open_session().await?;
+                        tokio::spawn(async move {
+                            let _ = channel.close().await;
+                        });
+                        None
+                    }
+                    // A closed session also ends the wait for the reply.
+                    HealthCheck::Keepalive => {
+                        session.send_ping().await?;
+                        None
+                    }
+                    // The kernel's keepalive probes close a dead connection.
+                    HealthCheck::Tcp => None,
+                    HealthCheck::Echo => {
+                        let (host, port) = &health.echo_target;
+                        let stream = session
+                            .channel_open_direct_tcpip(
+                                host.as_str(),
+                                (*port).into(),
+                                "127.0.0.1",
+                                0,
+                            )
+                            .await?
+                            .into_stream();
+                        Some(stream)
+                    }
+                }
+            };
+            // Waiting for the echo target to speak, which may take until the
+            // timeout, must not hold up everyone else using the session.
+            if let Some(mut stream) = echo
+                && stream.read(&mut [0; 1]).await? == 0
+            {
+                let (host, port) = &health.echo_target;
+                anyhow::bail!("{}:{} closed without sending anything", host, port);
+            }
+            if self.is_closed().await {
+                anyhow::bail!("SSH session closed");
+            }
+            Ok(())
+        };
+        tokio::time::timeout(health.timeout, probe)
+            .await
+            .unwrap_or_else(|_| Err(anyhow::anyhow!("no answer within {:?}", health.timeout)))
             .map_err(|e| anyhow::anyhow!("Health check failed: {}", e))
     }
 
-    pub async fn forward(
+    /// [`check_alive`](Self::check_alive), failing only once as many
+    /// probes in a row as configured have failed, or the session is gone.
+    pub async fn health_check(&self) -> anyhow::Result<()> {
+        let started = std::time::Instant::now();
+        let result = self.check_alive().await;
+        // TCP keepalive probes are the kernel's; there is nothing to time.
+        if self.config.health.check != HealthCheck::Tcp {
+            self.observe_probe(result.is_ok().then(|| started.elapsed()));
+        }
+        let Err(e) = result else {
+            self.failed_probes.store(0, Ordering::Relaxed);
+            return Ok(());
+        };
+        let failed = self.failed_probes.fetch_add(1, Ordering::Relaxed) + 1;
+        if failed >= self.config.health.failures || self.is_closed().await {
+            self.failed_probes.store(0, Ordering::Relaxed);
+            return Err(e);
+        }
+        warn!(
+            "{} ({} of {} before reconnecting)",
+            e, failed, self.config.health.failures
+        );
+        Ok(())
+    }
+
+    /// Adds a probe's round-trip time, `None` if it failed, to the recent
+    /// ones, reporting when they cross the degradation thresholds.
+    fn observe_probe(&self, rtt: Option<Duration>) {
+        self.config.metrics.record(Metric::HealthProbe { rtt });
+        let (change, stats) = {
+            let mut probes = self.probes.lock().unwrap();
+            (probes.record(rtt), probes.stats())
+        };
+        let timeline = &self.config.timeline;
+        timeline.set_rtt(stats.rtt);
+        let rtt_ms = stats.rtt.map(|rtt| rtt.as_millis() as u64);
+        match change {
+            Some(HealthChange::Degraded(stats)) => {
+                warn!("SSH connection degraded: {}", stats);
+                timeline.record(TimelineEvent::Degraded {
+                    rtt_ms,
+                    failed: stats.failed as u32,
+                    probes: stats.probes as u32,
+                });
+            }
+            Some(HealthChange::Recovered(stats)) => {
+                info!("SSH connection recovered: {}", stats);
+                timeline.record(TimelineEvent::Recovered { rtt_ms });
+            }
+            None => {}
+        }
+    }
+
+    /// Opens a `direct-tcpip` channel to `to` and returns it as a byte stream
+    /// that can be bridged directly to a local socket.
+    pub async fn open_forward_stream(
         &self,
         to: impl ToSocketAddrs,
-        client: impl AsyncRead + AsyncWrite + Send + Unpin + 'static,
-    ) -> anyhow::Result<()> {
+    ) -> anyhow::Result<ForwardStream> {
         let to = tokio::net::lookup_host(to)
             .await?
             .next()
@@ -177,50 +1193,45 @@ impl Transport {
             .channel_open_direct_tcpip(to.ip().to_string(), to.port() as _, "127.0.0.1", 0)
             .await?;
 
-        let (ssh_rx, ssh_tx) = channel.split();
-        let (client_rx, client_tx) = tokio::io::split(client);
+        Ok(channel.into_stream())
+    }
 
-        let jh = tokio::spawn(async move {
-            use tokio::io::AsyncReadExt;
+    /// Opens a `direct-tcpip` channel to `host:port`, leaving name resolution
+    /// to the SSH server.
+    pub async fn open_forward_stream_to_host(
+        &self,
+        host: &str,
+        port: u16,
+    ) -> anyhow::Result<ForwardStream> {
+        let session = self.session.lock().await;
+        let channel = session
+            .channel_open_direct_tcpip(host, port.into(), "127.0.0.1", 0)
+            .await?;
 
-            let mut client_rx = client_rx;
-            let mut buf = Vec::with_capacity(4096);
-            loop {
-                match client_rx.read_buf(&mut buf).await {
-                    Ok(0) => {
-                        let _ = ssh_tx.close().await;
-                        return anyhow::Ok(());
-                    }
-                    Ok(_) => {
-                        if ssh_tx.data(&*buf).await.is_err() {
-                            return Ok(());
-                        }
-                        buf.clear();
-                    }
-                    Err(_) => return Ok(()),
-                }
-            }
-        });
+        Ok(channel.into_stream())
+    }
 
-        use tokio::io::AsyncWriteExt;
-        let mut client_tx = client_tx;
-        let mut ssh_rx = ssh_rx;
-        while let Some(msg) = ssh_rx.wait().await {
-            match msg {
-                russh::ChannelMsg::Data { ref data } => {
-                    if client_tx.write_all(data).await.is_err() {
-                        break;
-                    }
-                    if client_tx.flush().await.is_err() {
-                        break;
-                    }
-                }
-                russh::ChannelMsg::Eof => break,
-                _ => debug!("Channel message: {:?}", msg),
+    /// Forwards `client` to `host:port`, which the server resolves,
+    /// copying through buffers of `buffer_size` bytes in each direction.
+    pub async fn forward(
+        &self,
+        host: &str,
+        port: u16,
+        mut client: impl AsyncRead + AsyncWrite + Unpin,
+        buffer_size: usize,
+    ) -> anyhow::Result<()> {
+        let mut stream = self.open_forward_stream_to_host(host, port).await?;
+
+        match copy_until_shutdown(&mut client, &mut stream, buffer_size, &self.shutdown).await {
+            Some(result) => {
+                let (sent, received) = result?;
+                debug!(
+                    "Forward closed: {} bytes sent, {} bytes received",
+                    sent, received
+                );
             }
+            None => debug!("Forward closed for shutdown"),
         }
-
-        jh.abort();
         Ok(())
     }
 
@@ -251,6 +1262,11 @@ impl Transport {
             }
         }
 
+        self.record(JournalEvent::Exec {
+            command: command.to_string(),
+            exit_code: Some(exit_code),
+        });
+
         Ok(ExecResult {
             exit_code,
             stdout,
@@ -281,4 +1297,18 @@ impl Transport {
         let channel = session.channel_open_session().await?;
         Ok(channel)
     }
+
+    /// Runs `f` with the current SSH session, for channel types this crate
+    /// has no wrapper for (X11, subsystems, streamlocal forwarding).
+    ///
+    /// **Unstable:** this exposes russh types directly and may change with
+    /// any russh upgrade.
+    ///
+    /// The session is locked while `f` runs, so keep it to opening channels
+    /// and return them. A reconnect replaces the session and closes its
+    /// channels; watch [`reconnects`](Self::reconnects) to reopen them.
+    pub async fn with_session<R>(&self, f: impl AsyncFnOnce(&SessionHandle) -> R) -> R {
+        let session = self.session.lock().await;
+        f(&session).await
+    }
 }
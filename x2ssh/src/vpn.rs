@@ -1,15 +1,40 @@
 pub mod agent;
+pub mod cgroup;
+pub mod dns64;
+pub mod domains;
+pub mod dry_run;
+pub mod elevation;
+pub mod filter;
 pub mod hooks;
+pub mod killswitch;
+pub mod nat64;
+pub mod pcap;
+pub mod roaming;
+pub mod route_monitor;
 pub mod routing;
+pub mod self_test;
 pub mod session;
 pub mod tun;
 
 use std::net::IpAddr;
+use std::time::Duration;
+use std::time::Instant;
 
+use roaming::RoamingMonitor;
+use route_monitor::RouteMonitor;
+use session::DeadPeer;
 use session::VpnSession;
+use tracing::error;
 use tracing::info;
+use tracing::warn;
 
 use crate::config::VpnConfig;
+use crate::ready::Readiness;
+use crate::retry::GaveUp;
+use crate::shutdown::Signals;
+use crate::status::DisconnectCause;
+use crate::status::TimelineEvent;
+use crate::systemd;
 use crate::transport::Transport;
 
 pub fn check_root() -> anyhow::Result<()> {
@@ -18,7 +43,7 @@ pub fn check_root() -> anyhow::Result<()> {
         let uid = unsafe { libc::getuid() };
         if uid != 0 {
             return Err(anyhow::anyhow!(
-                "VPN mode requires root privileges. Run with sudo."
+                "VPN mode requires root privileges. Run with sudo, or pass --auto-sudo."
             ));
         }
     }
@@ -38,28 +63,221 @@ pub fn check_root() -> anyhow::Result<()> {
     Ok(())
 }
 
+/// Forwarding that ran at least this long counts as having recovered.
+const STABLE_FORWARDING: Duration = Duration::from_secs(30);
+
+/// Consecutive resumes whose forwarding ended sooner than
+/// [`STABLE_FORWARDING`] before the session is given up.
+const MAX_QUICK_RESUMES: u32 = 5;
+
+/// Decides whether to keep resuming a session whose forwarding ends, so an
+/// agent that exits right after every start does not loop forever.
+struct ResumeBudget {
+    quick: u32,
+}
+
+impl ResumeBudget {
+    fn new() -> Self {
+        Self { quick: 0 }
+    }
+
+    /// Records that forwarding ended after `ran_for`; returns whether to
+    /// resume.
+    fn ended(&mut self, ran_for: Duration) -> bool {
+        if ran_for >= STABLE_FORWARDING {
+            self.quick = 0;
+        } else {
+            self.quick += 1;
+        }
+        self.quick < MAX_QUICK_RESUMES
+    }
+}
+
+/// Completes once a health check of the SSH session fails. A dead TCP
+/// connection may otherwise go unnoticed, leaving forwarding stalled.
+async fn connection_lost(transport: &Transport) {
+    let mut ticker = tokio::time::interval(systemd::health_interval(transport.health_interval()));
+    ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    ticker.tick().await;
+    loop {
+        ticker.tick().await;
+        if let Err(e) = transport.health_check().await {
+            warn!("{}", e);
+            return;
+        }
+        systemd::watchdog();
+    }
+}
+
 pub async fn run_vpn(
     transport: &Transport,
     config: &VpnConfig,
     ssh_server_ip: IpAddr,
+    readiness: &Readiness,
 ) -> anyhow::Result<()> {
-    check_root()?;
+    // An attached persistent TUN device needs no privileges; routing is the
+    // system's then, and only the kill switch's and the cgroup's firewall
+    // rules need root.
+    if !config.persistent_tun || config.kill_switch || config.cgroup.is_some() {
+        check_root()?;
+    }
+
+    // Before anything needs undoing, so `systemctl stop` or a closed
+    // terminal runs the cleanup below instead of killing the process.
+    let mut signals = Signals::new()?;
 
     info!("Starting VPN session");
     let mut session = VpnSession::start(transport, config, ssh_server_ip).await?;
+    let config = &session.running_config(config);
 
     info!("VPN tunnel active. Press Ctrl+C to disconnect.");
 
-    tokio::select! {
-        result = session.forward() => {
-            info!("Forwarding ended: {:?}", result);
+    let roaming = RoamingMonitor::new(config.roaming_interval);
+    let roaming_enabled = config.roaming;
+
+    let mut route_monitor = if config.heal_routes {
+        match RouteMonitor::new(Duration::from_millis(500)) {
+            Ok(monitor) => Some(monitor),
+            Err(e) => {
+                warn!("Route monitoring unavailable: {}", e);
+                None
+            }
         }
-        _ = tokio::signal::ctrl_c() => {
-            info!("Received shutdown signal");
+    } else {
+        None
+    };
+
+    let mut resumes = ResumeBudget::new();
+    let mut forwarding_since = Instant::now();
+
+    let ready = readiness.signal_when(|| readiness.check_vpn());
+    tokio::pin!(ready);
+    let mut signaled = false;
+
+    // Needs forwarding running, so it runs alongside it.
+    let self_test = self_test::run_and_report(config);
+    tokio::pin!(self_test);
+    let mut tested = !config.self_test;
+    // Why the loop below ended, when it was not a signal.
+    let mut failure = None;
+
+    loop {
+        tokio::select! {
+            result = session.forward(transport.shutdown()) => {
+                info!("Forwarding ended: {:?}", result);
+                if !resumes.ended(forwarding_since.elapsed()) {
+                    error!("VPN agent keeps stopping right after it starts; giving up");
+                    break;
+                }
+                // A silent agent means a dead connection; checking it first
+                // could block for as long as TCP takes to notice.
+                if let Err(e) = &result
+                    && e.is::<DeadPeer>()
+                {
+                    warn!("{}; reconnecting SSH session", e);
+                    if let Err(e) = transport.reconnect(DisconnectCause::KeepaliveTimeout).await {
+                        warn!("Reconnect failed: {}", e);
+                        failure = Some(e);
+                        break;
+                    }
+                } else if !transport.is_closed().await {
+                    transport.timeline().record(TimelineEvent::Disconnected {
+                        cause: DisconnectCause::AgentExited,
+                    });
+                }
+                warn!("Resuming VPN session; TUN device and routes are kept");
+                if let Err(e) = session.resume(transport, config).await {
+                    warn!("Resuming VPN session failed: {}", e);
+                    failure = Some(e);
+                    break;
+                }
+                forwarding_since = Instant::now();
+            }
+            _ = connection_lost(transport) => {
+                warn!("SSH connection lost, resuming VPN session");
+                if let Err(e) = session.resume(transport, config).await {
+                    warn!("Resuming VPN session failed: {}", e);
+                    failure = Some(e);
+                    break;
+                }
+                forwarding_since = Instant::now();
+            }
+            change = roaming.changed(transport), if roaming_enabled => {
+                warn!(
+                    "Local address changed ({} -> {}), reconnecting SSH session",
+                    change.old, change.new
+                );
+                if let Err(e) = transport.reconnect(DisconnectCause::NetworkChange).await {
+                    warn!("Reconnect after network change failed: {}", e);
+                    failure = Some(e);
+                    break;
+                }
+                if let Err(e) = session.restart_agent(transport, config).await {
+                    warn!("Agent restart after network change failed: {}", e);
+                    break;
+                }
+            }
+            _ = async { route_monitor.as_mut().unwrap().changed().await }, if route_monitor.is_some() => {
+                match session.heal_routes().await {
+                    Ok(true) => info!("Restored VPN routes after an external change"),
+                    Ok(false) => {}
+                    Err(e) => warn!("Restoring VPN routes failed: {}", e),
+                }
+            }
+            // Never finishes; answers DNS64 queries alongside forwarding.
+            () = session.serve_dns64(transport) => {}
+            () = &mut ready, if !signaled => {
+                signaled = true;
+                transport.timeline().set_verified();
+            }
+            () = &mut self_test, if !tested => tested = true,
+            signal = signals.recv() => {
+                info!("Received {}, shutting down", signal);
+                break;
+            }
         }
     }
 
-    session.cleanup(transport, config).await?;
+    transport.shutdown().cancel();
+    info!("Shutting down; press Ctrl+C again to only restore local routes and exit");
+    match signals
+        .unless_repeated(session.cleanup(transport, config))
+        .await
+    {
+        Some(result) => result?,
+        None => session.abandon(transport).await,
+    }
 
-    Ok(())
+    // A reconnect that gave up ends the session with its error, for the
+    // exit status; other failures have been logged.
+    match failure.filter(|e| e.is::<GaveUp>()) {
+        Some(e) => Err(e),
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resume_budget_gives_up_on_quick_failures() {
+        let mut budget = ResumeBudget::new();
+        for _ in 1..MAX_QUICK_RESUMES {
+            assert!(budget.ended(Duration::from_secs(1)));
+        }
+        assert!(!budget.ended(Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn test_resume_budget_resets_after_stable_forwarding() {
+        let mut budget = ResumeBudget::new();
+        for _ in 1..MAX_QUICK_RESUMES {
+            assert!(budget.ended(Duration::ZERO));
+        }
+        assert!(budget.ended(STABLE_FORWARDING));
+        for _ in 1..MAX_QUICK_RESUMES {
+            assert!(budget.ended(Duration::ZERO));
+        }
+    }
 }
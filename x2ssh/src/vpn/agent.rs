@@ -1,57 +1,486 @@
+use std::collections::VecDeque;
+use std::collections::hash_map::RandomState;
+use std::hash::BuildHasher;
+use std::hash::Hasher;
 use std::sync::Arc;
+use std::sync::LazyLock;
+use std::time::Duration;
+use std::time::Instant;
 
+use anyhow::Context;
 use bytes::BytesMut;
+use ipnet::Ipv4Net;
+use proto::Batch;
+use proto::Control;
+use proto::Features;
+use proto::Hello;
+use proto::gso;
 use russh::ChannelMsg;
 use russh::ChannelReadHalf;
 use russh::ChannelWriteHalf;
 use russh::client::Msg;
+#[cfg(feature = "embed-agent")]
+use sha2::Digest;
+#[cfg(feature = "embed-agent")]
+use sha2::Sha256;
+use tokio::io::AsyncRead;
+use tokio::io::AsyncReadExt;
+use tokio::io::AsyncWrite;
+use tokio::io::AsyncWriteExt;
 use tokio::sync::Mutex;
+use tokio_util::sync::CancellationToken;
 use tracing::debug;
 use tracing::info;
+use tracing::warn;
 
+use super::elevation::RootAccess;
+use crate::config::AGENT_START_TIMEOUT;
+use crate::config::VpnConfig;
+use crate::journal::JournalEvent;
 use crate::transport::Transport;
 
+#[cfg(feature = "embed-agent")]
 pub const AGENT_BINARY: &[u8] = include_bytes!(env!("X2SSH_AGENT_PATH"));
-const AGENT_PATH: &str = "/tmp/x2ssh-agent";
+
+/// Whether this build carries the agent binary, so sessions can deploy it.
+pub const EMBEDDED: bool = cfg!(feature = "embed-agent");
+
+/// Whether sessions with `config` upload the agent rather than run the one
+/// installed at `agent_path`.
+pub fn deploys(config: &VpnConfig) -> bool {
+    EMBEDDED && config.deploy_agent
+}
+
+/// Name of a preinstalled agent looked up on the server's `PATH` when
+/// `agent_path` is not set.
+const AGENT_NAME: &str = "x2ssh-agent";
+
+/// Server directory for per-session agent binaries, expanded by the remote
+/// shell: the runtime dir (cleared on logout) when the server has one.
+const AGENT_DIR: &str = "${XDG_RUNTIME_DIR:-$HOME/.cache}/x2ssh";
 
 #[derive(Clone)]
 pub struct AgentChannel {
-    reader: Arc<Mutex<(ChannelReadHalf, BytesMut)>>,
-    writer: Arc<Mutex<ChannelWriteHalf<Msg>>>,
+    reader: Arc<Mutex<(AgentReader, BytesMut)>>,
+    writer: Arc<Mutex<AgentWriter>>,
+    /// When the last frame of any kind arrived from the agent.
+    last_heard: Arc<std::sync::Mutex<Instant>>,
+    /// Packets of a batch frame that [`recv_packet`](Self::recv_packet) has
+    /// not returned yet.
+    unbatched: Arc<std::sync::Mutex<VecDeque<Vec<u8>>>>,
+    /// Agreed in the [`handshake`](Self::handshake); none before it.
+    features: Features,
+    /// Frames at least this long are compressed once the agent agrees to
+    /// LZ4; `None` leaves compression off.
+    compress_threshold: Option<usize>,
+    /// The client address a shared agent leased this client, sent right
+    /// after the handshake.
+    assigned: Option<Ipv4Net>,
+}
+
+/// Where agent output comes from: the SSH exec channel in production, or any
+/// byte stream (a local subprocess, an in-memory pipe) in tests and embedders.
+enum AgentReader {
+    Ssh(ChannelReadHalf, StderrRelay),
+    Io(Box<dyn AsyncRead + Send + Unpin>),
+}
+
+enum AgentWriter {
+    Ssh(ChannelWriteHalf<Msg>),
+    Io(Box<dyn AsyncWrite + Send + Unpin>),
+    Closed,
+}
+
+impl AgentReader {
+    /// Appends the next chunk of agent stdout to `buffer`. Returns `false`
+    /// once the agent side is closed.
+    async fn read_chunk(&mut self, buffer: &mut BytesMut) -> anyhow::Result<bool> {
+        match self {
+            AgentReader::Ssh(reader, stderr) => loop {
+                match reader.wait().await {
+                    Some(ChannelMsg::Data { data }) => {
+                        debug!("AGENT→CLIENT: {} bytes on channel", data.len());
+                        buffer.extend_from_slice(&data);
+                        return Ok(true);
+                    }
+                    Some(ChannelMsg::Eof) => {
+                        info!("AGENT→CLIENT: EOF");
+                        stderr.finish();
+                        return Ok(false);
+                    }
+                    Some(msg) => stderr.handle(msg),
+                    None => {
+                        info!("AGENT→CLIENT: channel closed");
+                        stderr.finish();
+                        return Ok(false);
+                    }
+                }
+            },
+            AgentReader::Io(reader) => Ok(reader.read_buf(buffer).await? > 0),
+        }
+    }
+
+    /// The agent's last stderr lines, for error messages.
+    fn recent_stderr(&self) -> Vec<String> {
+        match self {
+            AgentReader::Ssh(_, stderr) => stderr.recent.iter().cloned().collect(),
+            AgentReader::Io(_) => Vec::new(),
+        }
+    }
+
+    /// Discards agent output until the agent side closes the channel.
+    async fn closed(&mut self) -> anyhow::Result<()> {
+        match self {
+            AgentReader::Ssh(reader, stderr) => {
+                while let Some(msg) = reader.wait().await {
+                    stderr.handle(msg);
+                }
+                stderr.finish();
+            }
+            AgentReader::Io(reader) => {
+                tokio::io::copy(reader, &mut tokio::io::sink()).await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Relays the agent's stderr into the client's log, a line at a time with
+/// an `agent:` prefix. That is where the agent reports why it could not
+/// create its TUN device, or why it stopped.
+#[derive(Default)]
+struct StderrRelay {
+    partial: Vec<u8>,
+    /// The last [`RECENT`](Self::RECENT) lines.
+    recent: VecDeque<String>,
+}
+
+impl StderrRelay {
+    /// A longer line is logged in pieces rather than buffered further.
+    const MAX_LINE: usize = 4096;
+    /// Lines kept for a failed start's error message.
+    const RECENT: usize = 20;
+
+    /// Logs stderr and the exit status; other messages only at debug.
+    fn handle(&mut self, msg: ChannelMsg) {
+        match msg {
+            ChannelMsg::ExtendedData { data, ext: 1 } => {
+                for line in self.push(&data) {
+                    self.record(line);
+                }
+            }
+            ChannelMsg::ExitStatus { exit_status: 0 } => debug!("agent: exited"),
+            ChannelMsg::ExitStatus { exit_status } => {
+                warn!("agent: exited with status {}", exit_status)
+            }
+            msg => debug!("AGENT→CLIENT: other message: {:?}", msg),
+        }
+    }
+
+    /// Adds stderr output, returning the lines it completed.
+    fn push(&mut self, data: &[u8]) -> Vec<String> {
+        self.partial.extend_from_slice(data);
+        let mut lines = Vec::new();
+        while let Some(end) = self.partial.iter().position(|b| *b == b'\n') {
+            let line: Vec<u8> = self.partial.drain(..=end).collect();
+            lines.push(String::from_utf8_lossy(&line).trim_end().to_string());
+        }
+        if self.partial.len() >= Self::MAX_LINE {
+            lines.push(String::from_utf8_lossy(&self.partial).into_owned());
+            self.partial.clear();
+        }
+        lines.retain(|line| !line.is_empty());
+        lines
+    }
+
+    /// Logs what is left once the agent is gone, even without a newline.
+    fn finish(&mut self) {
+        let rest = String::from_utf8_lossy(&self.partial)
+            .trim_end()
+            .to_string();
+        self.partial.clear();
+        if !rest.is_empty() {
+            self.record(rest);
+        }
+    }
+
+    fn record(&mut self, line: String) {
+        log_agent_line(&line);
+        if self.recent.len() == Self::RECENT {
+            self.recent.pop_front();
+        }
+        self.recent.push_back(line);
+    }
+}
+
+/// The agent prints plain lines, so errors are told apart by their words.
+fn log_agent_line(line: &str) {
+    let lower = line.to_ascii_lowercase();
+    if lower.contains("error") || lower.contains("fail") || lower.contains("denied") {
+        warn!("agent: {}", line);
+    } else {
+        info!("agent: {}", line);
+    }
+}
+
+impl AgentWriter {
+    async fn write(&mut self, data: &[u8]) -> anyhow::Result<()> {
+        match self {
+            AgentWriter::Ssh(writer) => writer.data(data).await?,
+            AgentWriter::Io(writer) => {
+                writer.write_all(data).await?;
+                writer.flush().await?;
+            }
+            AgentWriter::Closed => anyhow::bail!("Agent channel is closed"),
+        }
+        Ok(())
+    }
+
+    async fn close(&mut self) -> anyhow::Result<()> {
+        match self {
+            AgentWriter::Ssh(writer) => writer.close().await?,
+            AgentWriter::Io(writer) => {
+                // Shutting down a pipe does not close it; the peer only sees
+                // EOF once the handle is dropped.
+                writer.shutdown().await?;
+                *self = AgentWriter::Closed;
+            }
+            AgentWriter::Closed => {}
+        }
+        Ok(())
+    }
 }
 
 impl AgentChannel {
+    fn new(reader: AgentReader, writer: AgentWriter) -> Self {
+        Self {
+            reader: Arc::new(Mutex::new((reader, BytesMut::with_capacity(2048)))),
+            writer: Arc::new(Mutex::new(writer)),
+            last_heard: Arc::new(std::sync::Mutex::new(Instant::now())),
+            unbatched: Arc::new(std::sync::Mutex::new(VecDeque::new())),
+            features: Features::NONE,
+            compress_threshold: None,
+            assigned: None,
+        }
+    }
+
+    /// Offers LZ4 compression of frames of at least `threshold` bytes in
+    /// the handshake.
+    pub fn with_compression(mut self, threshold: usize) -> Self {
+        self.compress_threshold = Some(threshold);
+        self
+    }
+
+    /// Speaks the agent protocol over an arbitrary byte stream pair instead
+    /// of an SSH exec channel.
+    pub fn from_io(
+        reader: impl AsyncRead + Send + Unpin + 'static,
+        writer: impl AsyncWrite + Send + Unpin + 'static,
+    ) -> Self {
+        Self::new(
+            AgentReader::Io(Box::new(reader)),
+            AgentWriter::Io(Box::new(writer)),
+        )
+    }
+
+    /// Exchanges hellos with the agent; must come before any other frame.
+    /// Fails if the agent does not answer with a hello of the same protocol
+    /// version, e.g. because it was built by a different x2ssh release.
+    pub async fn handshake(&mut self) -> anyhow::Result<()> {
+        self.handshake_within(AGENT_START_TIMEOUT).await
+    }
+
+    /// [`handshake`](Self::handshake), waiting up to `timeout` for the
+    /// agent's hello. The agent only sends it once its TUN device is up, so
+    /// the hello doubles as its ready signal; when it does not come, the
+    /// error carries the agent's last stderr lines, which say why.
+    pub async fn handshake_within(&mut self, timeout: Duration) -> anyhow::Result<()> {
+        let result = self.exchange_hellos(timeout).await;
+        let Err(e) = result else {
+            return result;
+        };
+        let output = self.reader.lock().await.0.recent_stderr();
+        if output.is_empty() {
+            return Err(e);
+        }
+        Err(anyhow::anyhow!(
+            "{:#}; agent output:\n    {}",
+            e,
+            output.join("\n    ")
+        ))
+    }
+
+    async fn exchange_hellos(&mut self, timeout: Duration) -> anyhow::Result<()> {
+        let mut offered = Features::all();
+        if self.compress_threshold.is_none() {
+            offered = offered.without(Features::LZ4);
+        }
+        let ours = Hello::new(offered);
+        self.send_packet(&ours.encode()).await?;
+
+        let frame = match tokio::time::timeout(timeout, self.recv_frame()).await {
+            Ok(frame) => frame?,
+            Err(_) => anyhow::bail!(
+                "agent did not report ready (create its TUN device and say hello) within {:?}",
+                timeout
+            ),
+        };
+        let Some(frame) = frame else {
+            anyhow::bail!(
+                "agent closed the channel before the handshake; it failed to start or is too old \
+                 to speak it"
+            );
+        };
+        let theirs = Hello::parse(&frame)?;
+        self.features = ours.agree(&theirs)?;
+        debug!(
+            "Agent handshake: protocol v{}, {:?}",
+            theirs.version, self.features
+        );
+        if self.features.contains(Features::SHARED) {
+            let frame = self.recv_frame().await?;
+            match frame.as_deref().and_then(Control::parse) {
+                Some(Control::Assign {
+                    address,
+                    prefix_len,
+                }) => self.assigned = Some(Ipv4Net::new(address, prefix_len)?),
+                _ => anyhow::bail!("shared agent did not assign a client address"),
+            }
+        }
+        Ok(())
+    }
+
+    /// Protocol features both sides support.
+    pub fn features(&self) -> Features {
+        self.features
+    }
+
+    /// The client address a shared agent leased, with the subnet's prefix.
+    pub fn assigned(&self) -> Option<Ipv4Net> {
+        self.assigned
+    }
+
     pub async fn send_packet(&self, packet: &[u8]) -> anyhow::Result<()> {
-        let writer = self.writer.lock().await;
+        let mut writer = self.writer.lock().await;
         let mut framed = Vec::with_capacity(4 + packet.len());
-        framed.extend_from_slice(&(packet.len() as u32).to_be_bytes());
-        framed.extend_from_slice(packet);
-        writer.data(&framed[..]).await?;
-        Ok(())
+        self.push_framed(&mut framed, packet);
+        writer.write(&framed).await
     }
 
+    /// Sends `packets` in one channel write, as batch frames when the agent
+    /// understands them and as one frame per packet otherwise. GSO frames
+    /// always go out on their own.
+    pub async fn send_packets<'a>(
+        &self,
+        packets: impl IntoIterator<Item = &'a [u8]>,
+    ) -> anyhow::Result<()> {
+        let mut framed = Vec::new();
+        if self.features.contains(Features::BATCH) {
+            let mut batch = Batch::new();
+            for packet in packets {
+                if gso::is_gso(packet) {
+                    if let Some(frame) = batch.take() {
+                        self.push_framed(&mut framed, &frame);
+                    }
+                    self.push_framed(&mut framed, packet);
+                } else if let Some(frame) = batch.push(packet) {
+                    self.push_framed(&mut framed, &frame);
+                }
+            }
+            if let Some(frame) = batch.take() {
+                self.push_framed(&mut framed, &frame);
+            }
+        } else {
+            for packet in packets {
+                self.push_framed(&mut framed, packet);
+            }
+        }
+        self.writer.lock().await.write(&framed).await
+    }
+
+    /// Appends `frame` to `out` with its length prefix, compressed when
+    /// that was agreed and pays off.
+    fn push_framed(&self, out: &mut Vec<u8>, frame: &[u8]) {
+        let compressed = match self.compress_threshold {
+            Some(threshold) if self.features.contains(Features::LZ4) => {
+                proto::compress(frame, threshold)
+            }
+            _ => None,
+        };
+        let frame = compressed.as_deref().unwrap_or(frame);
+        out.extend_from_slice(&(frame.len() as u32).to_be_bytes());
+        out.extend_from_slice(frame);
+    }
+
+    /// Asks the agent to answer; the answer shows up in [`idle`](Self::idle)
+    /// once [`recv_packet`](Self::recv_packet) reads it.
+    pub async fn ping(&self, nonce: u64) -> anyhow::Result<()> {
+        self.send_packet(&Control::Ping(nonce).encode()).await
+    }
+
+    /// How long ago the agent last sent anything.
+    pub fn idle(&self) -> Duration {
+        self.last_heard.lock().unwrap().elapsed()
+    }
+
+    /// Returns the next IP packet from the agent, answering any control
+    /// frames that arrive before it.
     pub async fn recv_packet(&self) -> anyhow::Result<Option<Vec<u8>>> {
+        self.recv_packet_until(&CancellationToken::new()).await
+    }
+
+    /// [`recv_packet`](Self::recv_packet), returning `None` once `stop` is
+    /// cancelled. Only waiting for a frame is cut short: a frame read in
+    /// part stays buffered for the next call, and a control reply being
+    /// written is finished first.
+    pub async fn recv_packet_until(
+        &self,
+        stop: &CancellationToken,
+    ) -> anyhow::Result<Option<Vec<u8>>> {
+        if let Some(packet) = self.unbatched.lock().unwrap().pop_front() {
+            return Ok(Some(packet));
+        }
+        loop {
+            let frame = tokio::select! {
+                biased;
+                () = stop.cancelled() => return Ok(None),
+                frame = self.recv_frame() => frame?,
+            };
+            let Some(frame) = frame else {
+                return Ok(None);
+            };
+            *self.last_heard.lock().unwrap() = Instant::now();
+            let frame = proto::decompress(&frame)?.unwrap_or(frame);
+            match Control::parse(&frame) {
+                Some(control) => {
+                    debug!("AGENT→CLIENT: {:?}", control);
+                    if let Some(reply) = control.reply() {
+                        self.send_packet(&reply.encode()).await?;
+                    }
+                }
+                None => {
+                    let Some(packets) = proto::unbatch(&frame)? else {
+                        return Ok(Some(frame));
+                    };
+                    let mut packets = packets.into_iter().map(<[u8]>::to_vec);
+                    let Some(first) = packets.next() else {
+                        continue;
+                    };
+                    self.unbatched.lock().unwrap().extend(packets);
+                    return Ok(Some(first));
+                }
+            }
+        }
+    }
+
+    async fn recv_frame(&self) -> anyhow::Result<Option<Vec<u8>>> {
         let mut guard = self.reader.lock().await;
         let (reader, buffer) = &mut *guard;
 
         // Read length prefix (4 bytes)
         while buffer.len() < 4 {
-            match reader.wait().await {
-                Some(ChannelMsg::Data { data }) => {
-                    debug!("AGENT→CLIENT: {} bytes on channel", data.len());
-                    buffer.extend_from_slice(&data);
-                }
-                Some(ChannelMsg::Eof) => {
-                    info!("AGENT→CLIENT: EOF");
-                    return Ok(None);
-                }
-                Some(msg) => {
-                    debug!("AGENT→CLIENT: other message: {:?}", msg);
-                }
-                None => {
-                    info!("AGENT→CLIENT: channel closed");
-                    return Ok(None);
-                }
+            if !reader.read_chunk(buffer).await? {
+                return Ok(None);
             }
         }
 
@@ -60,14 +489,8 @@ impl AgentChannel {
 
         // Read packet data
         while buffer.len() < 4 + len {
-            match reader.wait().await {
-                Some(ChannelMsg::Data { data }) => {
-                    debug!("AGENT→CLIENT: {} more bytes", data.len());
-                    buffer.extend_from_slice(&data);
-                }
-                Some(ChannelMsg::Eof) => return Ok(None),
-                Some(msg) => debug!("AGENT→CLIENT: other message: {:?}", msg),
-                None => return Ok(None),
+            if !reader.read_chunk(buffer).await? {
+                return Ok(None);
             }
         }
 
@@ -79,21 +502,248 @@ impl AgentChannel {
     }
 
     pub async fn close(&self) -> anyhow::Result<()> {
-        let writer = self.writer.lock().await;
-        writer.close().await?;
-        Ok(())
+        let mut writer = self.writer.lock().await;
+        writer.close().await
+    }
+
+    /// Closes the channel and waits for the agent to close its side, which
+    /// it does once it has exited. Never returns if the agent does not
+    /// answer, so callers bound it with a timeout.
+    pub async fn close_and_wait(&self) -> anyhow::Result<()> {
+        self.close().await?;
+        self.reader.lock().await.0.closed().await
+    }
+}
+
+/// Where the agent binary lives on the server.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AgentPath {
+    path: String,
+    /// Named for this session only, so removed again on cleanup.
+    per_session: bool,
+    /// Installed on the server beforehand, so run as it is.
+    preinstalled: bool,
+}
+
+impl AgentPath {
+    /// `agent_path` from the config; else, when sessions do not deploy the
+    /// agent, [`AGENT_NAME`] on the server's `PATH`; else a name unique to
+    /// this session under [`AGENT_DIR`], so clients sharing a server do not
+    /// overwrite each other's agent and a noexec `/tmp` does not matter.
+    pub async fn resolve(transport: &Transport, config: &VpnConfig) -> anyhow::Result<Self> {
+        if let Some(path) = &config.agent_path {
+            return Self::configured_for(config, path);
+        }
+        if !deploys(config) {
+            let result = transport.exec(&format!("command -v {AGENT_NAME}")).await?;
+            let path = String::from_utf8_lossy(&result.stdout).trim().to_string();
+            if result.exit_code != 0 || !path.starts_with('/') {
+                anyhow::bail!(
+                    "{AGENT_NAME} is not on the server's PATH; install it there or set agent_path"
+                );
+            }
+            return Self::configured_for(config, &path);
+        }
+        let result = transport
+            .exec(&format!("printf '%s' \"{AGENT_DIR}\""))
+            .await?;
+        let dir = String::from_utf8_lossy(&result.stdout);
+        if result.exit_code != 0 || !dir.starts_with('/') {
+            anyhow::bail!("could not find a directory for the agent on the server; set agent_path");
+        }
+        Ok(Self::per_session(&dir))
+    }
+
+    /// Where [`resolve`] would put the agent, without asking the server:
+    /// a per-session name is shown under the unexpanded [`AGENT_DIR`].
+    ///
+    /// [`resolve`]: AgentPath::resolve
+    pub fn planned(config: &VpnConfig) -> anyhow::Result<Self> {
+        match &config.agent_path {
+            Some(path) => Self::configured_for(config, path),
+            None if !deploys(config) => Ok(Self {
+                path: AGENT_NAME.to_string(),
+                per_session: false,
+                preinstalled: true,
+            }),
+            None => Ok(Self::per_session(AGENT_DIR)),
+        }
+    }
+
+    /// `path` from `config`, which is run as installed there unless
+    /// sessions deploy the agent.
+    fn configured_for(config: &VpnConfig, path: &str) -> anyhow::Result<Self> {
+        Ok(Self {
+            preinstalled: !deploys(config),
+            ..Self::configured(path)?
+        })
+    }
+
+    fn configured(path: &str) -> anyhow::Result<Self> {
+        if !path.starts_with('/') {
+            anyhow::bail!("agent_path must be absolute, got '{}'", path);
+        }
+        Ok(Self {
+            path: path.to_string(),
+            per_session: false,
+            preinstalled: false,
+        })
+    }
+
+    fn per_session(dir: &str) -> Self {
+        let id = RandomState::new().build_hasher().finish();
+        Self {
+            path: format!("{}/{}-{:016x}", dir, AGENT_NAME, id),
+            per_session: true,
+            preinstalled: false,
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.path
+    }
+
+    /// Whether the agent here is installed beforehand rather than deployed.
+    pub fn preinstalled(&self) -> bool {
+        self.preinstalled
+    }
+
+    fn quoted(&self) -> String {
+        shell_quote(&self.path)
+    }
+
+    /// The file recording the SHA-256 of the binary x2ssh uploaded to a
+    /// configured path.
+    #[cfg(feature = "embed-agent")]
+    fn marker(&self) -> String {
+        shell_quote(&format!("{}.sha256", self.path))
+    }
+}
+
+impl std::fmt::Display for AgentPath {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.path)
+    }
+}
+
+/// Quotes `s` for a POSIX shell.
+pub(super) fn shell_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', r"'\''"))
+}
+
+/// Escapes `s` so `pkill` matches it literally.
+fn regex_escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        if r"\.^$|?*+()[]{}".contains(c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
     }
+    escaped
 }
 
-pub async fn deploy(transport: &Transport) -> anyhow::Result<()> {
+/// Identifies this client to a shared agent, so an agent restarted after a
+/// reconnect gets the address of the one it replaces.
+static PEER_ID: LazyLock<String> =
+    LazyLock::new(|| format!("{:016x}", RandomState::new().build_hasher().finish()));
+
+/// SHA-256 of [`AGENT_BINARY`], hex-encoded like `sha256sum` prints it.
+#[cfg(feature = "embed-agent")]
+static AGENT_SHA256: LazyLock<String> = LazyLock::new(|| hex::encode(Sha256::digest(AGENT_BINARY)));
+
+/// Uploads the agent binary, unless the server already has this exact
+/// build (compared by SHA-256), which saves pushing several MB on every
+/// start. A preinstalled agent is only checked to be there.
+pub async fn deploy(transport: &Transport, path: &AgentPath) -> anyhow::Result<()> {
+    if path.preinstalled {
+        if !is_deployed(transport, path).await? {
+            anyhow::bail!(
+                "no agent installed at {} on the server; install x2ssh-agent there, or let x2ssh \
+                 deploy it (deploy_agent)",
+                path
+            );
+        }
+        info!("Using the agent installed at {}", path);
+        return Ok(());
+    }
+    upload(transport, path).await
+}
+
+#[cfg(feature = "embed-agent")]
+async fn upload(transport: &Transport, path: &AgentPath) -> anyhow::Result<()> {
+    match remote_sha256(transport, path).await {
+        Ok(Some(remote)) if remote == *AGENT_SHA256 => {
+            info!("Agent binary at {} is up to date", path);
+            return Ok(());
+        }
+        Ok(_) => {}
+        Err(e) => debug!("Checking the deployed agent failed: {}", e),
+    }
+    if !path.per_session && transport.exec(&foreign_command(path)).await?.exit_code == 0 {
+        info!(
+            "Using the agent installed at {} as it is; x2ssh did not deploy it there",
+            path
+        );
+        return Ok(());
+    }
+
     info!("Deploying agent binary ({} bytes)", AGENT_BINARY.len());
 
+    let command = upload_command(path);
+    let exit_code = exec_with_input(transport, &command, AGENT_BINARY).await?;
+    if exit_code != 0 {
+        anyhow::bail!("Agent deployment failed with exit code {}", exit_code);
+    }
+
+    info!("Agent binary deployed to {}", path);
+    Ok(())
+}
+
+/// Writes the agent binary from stdin to `path`. A configured path also
+/// gets a `.sha256` file next to it recording what x2ssh put there.
+#[cfg(feature = "embed-agent")]
+fn upload_command(path: &AgentPath) -> String {
+    let quoted = path.quoted();
+    let mut command =
+        format!("mkdir -p \"$(dirname {quoted})\" && cat > {quoted} && chmod 700 {quoted}");
+    if !path.per_session {
+        command.push_str(&format!(" && sha256sum {quoted} > {}", path.marker()));
+    }
+    command
+}
+
+/// Succeeds when a file is at `path` that x2ssh did not upload, or that was
+/// changed since: one its `.sha256` file does not describe. Such a file is
+/// run, never overwritten.
+#[cfg(feature = "embed-agent")]
+fn foreign_command(path: &AgentPath) -> String {
+    let quoted = path.quoted();
+    format!(
+        "test -e {quoted} && [ \"$(sha256sum {quoted})\" != \"$(cat {} 2>/dev/null)\" ]",
+        path.marker()
+    )
+}
+
+#[cfg(not(feature = "embed-agent"))]
+async fn upload(_transport: &Transport, path: &AgentPath) -> anyhow::Result<()> {
+    anyhow::bail!(
+        "this x2ssh has no embedded agent to deploy to {}; set agent_path to an installed one",
+        path
+    )
+}
+
+/// Runs `command` with `input` as its stdin, which keeps large or secret
+/// data off the command line. Returns the exit code.
+pub(super) async fn exec_with_input(
+    transport: &Transport,
+    command: &str,
+    input: &[u8],
+) -> anyhow::Result<u32> {
     let mut channel = transport.open_session_channel().await?;
-    channel
-        .exec(true, b"cat > /tmp/x2ssh-agent && chmod +x /tmp/x2ssh-agent")
-        .await?;
+    channel.exec(true, command.as_bytes()).await?;
 
-    channel.data(AGENT_BINARY).await?;
+    channel.data(input).await?;
     channel.eof().await?;
 
     let mut exit_code = 0u32;
@@ -107,39 +757,633 @@ pub async fn deploy(transport: &Transport) -> anyhow::Result<()> {
         }
     }
 
-    if exit_code != 0 {
-        anyhow::bail!("Agent deployment failed with exit code {}", exit_code);
+    transport.record(JournalEvent::Exec {
+        command: command.to_string(),
+        exit_code: Some(exit_code),
+    });
+    Ok(exit_code)
+}
+
+/// SHA-256 of the agent binary on the server, if it is there and
+/// executable.
+#[cfg(feature = "embed-agent")]
+async fn remote_sha256(transport: &Transport, path: &AgentPath) -> anyhow::Result<Option<String>> {
+    let quoted = path.quoted();
+    let result = transport
+        .exec(&format!("test -x {quoted} && sha256sum {quoted}"))
+        .await?;
+    if result.exit_code != 0 {
+        return Ok(None);
+    }
+    Ok(parse_sha256sum(&result.stdout))
+}
+
+/// The digest from `sha256sum` output (`<hex>  <path>`).
+#[cfg(feature = "embed-agent")]
+fn parse_sha256sum(output: &[u8]) -> Option<String> {
+    let digest = String::from_utf8_lossy(output)
+        .split_whitespace()
+        .next()?
+        .to_ascii_lowercase();
+    (digest.len() == 64 && digest.bytes().all(|b| b.is_ascii_hexdigit())).then_some(digest)
+}
+
+/// Whether the agent binary is still on the server; `/tmp` and the runtime
+/// dir do not survive a reboot.
+pub async fn is_deployed(transport: &Transport, path: &AgentPath) -> anyhow::Result<bool> {
+    let result = transport
+        .exec(&format!("test -x {}", path.quoted()))
+        .await?;
+    Ok(result.exit_code == 0)
+}
+
+/// Deletes a per-session agent binary; a configured `agent_path` is kept
+/// for the next session.
+pub async fn remove(transport: &Transport, path: &AgentPath) -> anyhow::Result<()> {
+    if !path.per_session {
+        return Ok(());
     }
+    transport.exec_success(&remove_command(path)).await
+}
+
+fn remove_command(path: &AgentPath) -> String {
+    format!("rm -f {}", path.quoted())
+}
 
-    info!("Agent binary deployed to {}", AGENT_PATH);
+/// Kills the agent this session started if it is still running, e.g.
+/// because its channel died before the agent saw it close. The process is
+/// matched by its command line, so agents of other sessions keep running.
+pub async fn kill(
+    transport: &Transport,
+    config: &VpnConfig,
+    path: &AgentPath,
+    root: &RootAccess,
+) -> anyhow::Result<()> {
+    let command = kill_command(config, path, root);
+    let exit_code = exec_with_input(transport, &command, &root.stdin()).await?;
+    if exit_code != 0 {
+        anyhow::bail!("Command '{}' failed with exit code {}", command, exit_code);
+    }
     Ok(())
 }
 
-pub async fn start(transport: &Transport, server_address: &str) -> anyhow::Result<AgentChannel> {
-    info!("Starting agent with IP {}", server_address);
+fn kill_command(config: &VpnConfig, path: &AgentPath, root: &RootAccess) -> String {
+    let mut pattern = format!(
+        "^{} --ip {} ",
+        regex_escape(path.as_str()),
+        regex_escape(&config.server_address)
+    );
+    // Only this client's agent; the shared agent exits once its last
+    // client has left.
+    if config.shared_agent.is_some() {
+        pattern.push_str(&format!(".* --peer-id {}$", *PEER_ID));
+    }
+    // pkill exits with 1 when nothing matched, the usual case once the
+    // agent has seen its channel close.
+    format!(
+        "{}pkill -f -- {}; [ $? -le 1 ]",
+        root.prefix(),
+        shell_quote(&pattern)
+    )
+}
+
+pub async fn start(
+    transport: &Transport,
+    config: &VpnConfig,
+    path: &AgentPath,
+    root: &RootAccess,
+) -> anyhow::Result<AgentChannel> {
+    info!("Starting agent with IP {}", config.server_address);
 
     let channel = transport.open_session_channel().await?;
 
-    let cmd = format!("sudo {} --ip {}", AGENT_PATH, server_address);
+    let cmd = start_command(config, path, root)?;
     channel.exec(true, cmd.as_bytes()).await?;
+    transport.record(JournalEvent::Exec {
+        command: cmd,
+        exit_code: None,
+    });
+    // sudo -S takes its password line before the agent reads anything.
+    let password = root.stdin();
+    if !password.is_empty() {
+        channel.data(&password[..]).await?;
+    }
 
     let (reader, writer) = channel.split();
+    let mut agent = AgentChannel::new(
+        AgentReader::Ssh(reader, StderrRelay::default()),
+        AgentWriter::Ssh(writer),
+    );
+    if config.compress {
+        agent = agent.with_compression(config.compress_threshold);
+    }
+    agent
+        .handshake_within(config.agent_start_timeout)
+        .await
+        .with_context(|| format!("agent at {path} failed to start"))?;
+    let wanted = [
+        (config.nat, Features::NAT, "nat"),
+        (config.agent_dns, Features::DNS, "agent_dns"),
+        (config.offload, Features::OFFLOAD, "offload"),
+        (
+            config.shared_agent.is_some(),
+            Features::SHARED,
+            "shared_agent",
+        ),
+    ];
+    for (enabled, feature, name) in wanted {
+        if enabled && !agent.features().contains(feature) {
+            anyhow::bail!(
+                "{name} is enabled, but the agent at {path} does not support it; redeploy it or \
+                 turn {name} off"
+            );
+        }
+    }
+    if config.compress {
+        if agent.features().contains(Features::LZ4) {
+            info!(
+                "Compressing tunnel frames of {} bytes or more with LZ4",
+                config.compress_threshold
+            );
+        } else {
+            warn!("The agent at {path} does not support compression; sending frames as they are");
+        }
+    }
+    if config.agent_dns {
+        info!(
+            "Agent answers DNS on {}:53; point the system resolver there",
+            config.server_ip()?
+        );
+    }
 
     info!("Agent started, channel ready for packet forwarding");
+    Ok(agent)
+}
 
-    Ok(AgentChannel {
-        reader: Arc::new(Mutex::new((reader, BytesMut::with_capacity(2048)))),
-        writer: Arc::new(Mutex::new(writer)),
-    })
+/// The command line the agent is started with, as the SSH user.
+pub fn start_command(
+    config: &VpnConfig,
+    path: &AgentPath,
+    root: &RootAccess,
+) -> anyhow::Result<String> {
+    let mut cmd = format!(
+        "{}{} --ip {} --mtu {}",
+        root.prefix(),
+        path.quoted(),
+        config.server_address,
+        config.mtu
+    );
+    if let Some((_, server6)) = config.ipv6_addresses()? {
+        cmd.push_str(&format!(" --ip6 {}", server6));
+    }
+    if let Some(name) = &config.server_tun {
+        cmd.push_str(&format!(" --name {}", shell_quote(name)));
+    }
+    if config.nat {
+        cmd.push_str(" --nat");
+    }
+    if config.agent_dns {
+        cmd.push_str(" --dns");
+    }
+    if config.offload {
+        cmd.push_str(" --offload");
+    }
+    cmd.push_str(&format!(
+        " --batch-delay-ms {}",
+        config.batch_delay.as_millis()
+    ));
+    if config.compress {
+        cmd.push_str(&format!(
+            " --compress-threshold {}",
+            config.compress_threshold
+        ));
+    }
+    if let Some(socket) = &config.shared_agent {
+        cmd.push_str(&format!(
+            " --connect {} --peer-address {} --peer-id {}",
+            shell_quote(socket),
+            config.client_ip()?,
+            *PEER_ID
+        ));
+    }
+    Ok(cmd)
 }
 
 #[cfg(test)]
 mod tests {
+    use proptest::prelude::*;
+    use proto::test_support::ChunkedReader;
+    use proto::test_support::framed;
+
     use super::*;
+    #[cfg(feature = "embed-agent")]
+    use crate::test_utils::LocalAgent;
 
+    #[cfg(feature = "embed-agent")]
     #[test]
     fn test_agent_binary_embedded() {
         assert!(!AGENT_BINARY.is_empty());
         assert!(AGENT_BINARY.len() > 1000);
     }
+
+    #[cfg(feature = "embed-agent")]
+    #[test]
+    fn test_parse_sha256sum() {
+        let digest = AGENT_SHA256.as_str();
+        let output = format!("{digest}  /tmp/x2ssh-agent\n");
+        assert_eq!(parse_sha256sum(output.as_bytes()).as_deref(), Some(digest));
+        assert_eq!(
+            parse_sha256sum(output.to_uppercase().as_bytes()).as_deref(),
+            Some(digest)
+        );
+        assert_eq!(parse_sha256sum(b""), None);
+        assert_eq!(parse_sha256sum(b"sha256sum: command not found\n"), None);
+    }
+
+    #[cfg(feature = "embed-agent")]
+    #[test]
+    fn test_configured_path_not_overwritten() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = AgentPath::configured(dir.path().join("agent").to_str().unwrap()).unwrap();
+        let sh = |command: &str| {
+            std::process::Command::new("sh")
+                .args(["-c", command])
+                .status()
+                .unwrap()
+                .success()
+        };
+
+        // Nothing there yet: x2ssh deploys, and may replace its own copy.
+        assert!(!sh(&foreign_command(&path)));
+        assert!(sh(&format!("echo agent | {}", upload_command(&path))));
+        assert!(!sh(&foreign_command(&path)));
+
+        // Changed or put there by someone else: left alone.
+        std::fs::write(path.as_str(), "installed").unwrap();
+        assert!(sh(&foreign_command(&path)));
+        std::fs::remove_file(format!("{path}.sha256")).unwrap();
+        assert!(sh(&foreign_command(&path)));
+    }
+
+    #[test]
+    fn test_start_command() {
+        let path = AgentPath::configured("/opt/x2ssh/agent").unwrap();
+        let mut config = VpnConfig::default();
+        assert_eq!(
+            start_command(&config, &path, &RootAccess::Sudo).unwrap(),
+            "sudo -n '/opt/x2ssh/agent' --ip 10.8.0.1/24 --mtu 1400 --batch-delay-ms 1"
+        );
+        assert_eq!(
+            start_command(&config, &path, &RootAccess::Root).unwrap(),
+            "'/opt/x2ssh/agent' --ip 10.8.0.1/24 --mtu 1400 --batch-delay-ms 1"
+        );
+
+        config.client_address6 = Some("fd00:8::2/64".to_string());
+        config.server_address6 = Some("fd00:8::1/64".to_string());
+        config.server_tun = Some("tun-srv".to_string());
+        config.mtu = 1280;
+        config.batch_delay = Duration::ZERO;
+        assert_eq!(
+            start_command(&config, &path, &RootAccess::Doas).unwrap(),
+            "doas -n '/opt/x2ssh/agent' --ip 10.8.0.1/24 --mtu 1280 --ip6 fd00:8::1/64 --name \
+             'tun-srv' --batch-delay-ms 0"
+        );
+
+        config.nat = true;
+        config.agent_dns = true;
+        config.compress = true;
+        config.offload = true;
+        assert!(
+            start_command(&config, &path, &RootAccess::Root)
+                .unwrap()
+                .ends_with(" --nat --dns --offload --batch-delay-ms 0 --compress-threshold 256")
+        );
+
+        let config = VpnConfig {
+            shared_agent: Some("/run/x2ssh/shared.sock".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(
+            start_command(&config, &path, &RootAccess::Root).unwrap(),
+            format!(
+                "'/opt/x2ssh/agent' --ip 10.8.0.1/24 --mtu 1400 --batch-delay-ms 1 --connect \
+                 '/run/x2ssh/shared.sock' --peer-address 10.8.0.2 --peer-id {}",
+                *PEER_ID
+            )
+        );
+    }
+
+    #[test]
+    fn test_stderr_relay_splits_lines() {
+        let mut relay = StderrRelay::default();
+        assert!(relay.push(b"Handshake compl").is_empty());
+        assert_eq!(relay.push(b"ete\nTUN recv error: gone\r\n\npartial"), vec![
+            "Handshake complete",
+            "TUN recv error: gone"
+        ]);
+        assert_eq!(relay.partial, b"partial");
+        relay.finish();
+        assert!(relay.partial.is_empty());
+        // Only what was logged is kept; `handle` logs pushed lines.
+        assert_eq!(relay.recent, ["partial"]);
+        for i in 0..StderrRelay::RECENT {
+            relay.record(i.to_string());
+        }
+        assert_eq!(relay.recent.len(), StderrRelay::RECENT);
+        assert_eq!(relay.recent[0], "0");
+
+        let long = vec![b'x'; StderrRelay::MAX_LINE];
+        assert_eq!(relay.push(&long).len(), 1);
+        assert!(relay.partial.is_empty());
+    }
+
+    #[test]
+    fn test_kill_command() {
+        let path = AgentPath::configured("/home/u/.cache/x2ssh/x2ssh-agent-1").unwrap();
+        assert_eq!(
+            kill_command(&VpnConfig::default(), &path, &RootAccess::Sudo),
+            r"sudo -n pkill -f -- '^/home/u/\.cache/x2ssh/x2ssh-agent-1 --ip 10\.8\.0\.1/24 '; [ $? -le 1 ]"
+        );
+        let config = VpnConfig {
+            shared_agent: Some("/run/x2ssh/shared.sock".to_string()),
+            ..Default::default()
+        };
+        assert!(
+            kill_command(&config, &path, &RootAccess::Root)
+                .ends_with(&format!(" .* --peer-id {}$'; [ $? -le 1 ]", *PEER_ID))
+        );
+    }
+
+    #[test]
+    fn test_remove_command() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path().to_str().unwrap();
+        let this = AgentPath::per_session(dir);
+        let other = AgentPath::per_session(dir);
+        std::fs::write(this.as_str(), "agent").unwrap();
+        std::fs::write(other.as_str(), "agent").unwrap();
+
+        let status = std::process::Command::new("sh")
+            .args(["-c", &remove_command(&this)])
+            .status()
+            .unwrap();
+        assert!(status.success());
+        assert!(!std::path::Path::new(this.as_str()).exists());
+        assert!(std::path::Path::new(other.as_str()).exists());
+    }
+
+    #[test]
+    fn test_agent_path() {
+        assert!(AgentPath::configured("x2ssh-agent").is_err());
+        assert!(
+            !AgentPath::configured("/opt/x2ssh/agent")
+                .unwrap()
+                .per_session
+        );
+
+        // Two sessions on one server never share a binary.
+        let first = AgentPath::per_session("/run/user/1000/x2ssh");
+        let second = AgentPath::per_session("/run/user/1000/x2ssh");
+        assert!(first.per_session);
+        assert!(
+            first
+                .as_str()
+                .starts_with("/run/user/1000/x2ssh/x2ssh-agent-")
+        );
+        assert_ne!(first, second);
+
+        let odd = AgentPath::configured("/home/o'brien/agent").unwrap();
+        assert_eq!(odd.quoted(), r"'/home/o'\''brien/agent'");
+
+        let mut config = VpnConfig {
+            agent_path: Some("/usr/bin/x2ssh-agent".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(
+            AgentPath::planned(&config).unwrap().preinstalled(),
+            !EMBEDDED
+        );
+        config.deploy_agent = false;
+        assert!(AgentPath::planned(&config).unwrap().preinstalled());
+        config.agent_path = None;
+        let planned = AgentPath::planned(&config).unwrap();
+        assert_eq!(
+            (planned.as_str(), planned.preinstalled()),
+            ("x2ssh-agent", true)
+        );
+    }
+
+    #[cfg(feature = "embed-agent")]
+    #[tokio::test]
+    async fn test_local_agent_round_trip() {
+        let agent = LocalAgent::loopback().await.unwrap();
+        let channel = agent.channel();
+
+        let packets: Vec<Vec<u8>> = vec![
+            vec![0x45, 0x00, 0x00, 0x14],
+            Vec::new(),
+            (0..=255).cycle().take(65_535).collect(),
+        ];
+        for packet in &packets {
+            channel.send_packet(packet).await.unwrap();
+        }
+        for packet in &packets {
+            assert_eq!(channel.recv_packet().await.unwrap().as_ref(), Some(packet));
+        }
+
+        let status = agent.shutdown().await.unwrap();
+        assert!(status.success());
+    }
+
+    #[cfg(feature = "embed-agent")]
+    #[tokio::test]
+    async fn test_local_agent_batches() {
+        let agent = LocalAgent::loopback().await.unwrap();
+        let channel = agent.channel();
+        assert!(channel.features().contains(Features::BATCH));
+
+        // Enough to need more than one batch frame; the loopback agent
+        // reflects the frames as they are.
+        let packets: Vec<Vec<u8>> = (0..64u8).map(|i| vec![i; 600]).collect();
+        channel
+            .send_packets(packets.iter().map(Vec::as_slice))
+            .await
+            .unwrap();
+        channel.send_packets([&[0x45; 20][..]]).await.unwrap();
+        for packet in &packets {
+            assert_eq!(channel.recv_packet().await.unwrap().as_ref(), Some(packet));
+        }
+        assert_eq!(channel.recv_packet().await.unwrap(), Some(vec![0x45; 20]));
+    }
+
+    #[cfg(feature = "embed-agent")]
+    #[tokio::test]
+    async fn test_send_packets_keeps_gso_frames_whole() {
+        let agent = LocalAgent::loopback().await.unwrap();
+        let channel = agent.channel();
+
+        let mut segment = gso::PREFIX.to_vec();
+        segment.extend_from_slice(&[1, 4, 0, 0, 0, 0, 0, 0, 0, 0]);
+        segment.extend_from_slice(&[0x45; 60_000]);
+        let packets = [vec![0x45; 20], segment, vec![0x45; 40]];
+        channel
+            .send_packets(packets.iter().map(Vec::as_slice))
+            .await
+            .unwrap();
+        for packet in &packets {
+            assert_eq!(channel.recv_packet().await.unwrap().as_ref(), Some(packet));
+        }
+    }
+
+    #[cfg(feature = "embed-agent")]
+    #[tokio::test]
+    async fn test_local_agent_answers_ping() {
+        let agent = LocalAgent::loopback().await.unwrap();
+        let channel = agent.channel();
+
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        channel.ping(1).await.unwrap();
+        channel.send_packet(&[0x45; 20]).await.unwrap();
+
+        // The pong is consumed, and only the packet comes out.
+        assert_eq!(channel.recv_packet().await.unwrap(), Some(vec![0x45; 20]));
+        assert!(channel.idle() < Duration::from_millis(50));
+    }
+
+    #[tokio::test]
+    async fn test_handshake_rejects_other_protocol_version() {
+        let (client, agent) = tokio::io::duplex(1024);
+        let (client_read, client_write) = tokio::io::split(client);
+        let (_agent_read, mut agent_write) = tokio::io::split(agent);
+        let newer = Hello {
+            version: proto::handshake::PROTOCOL_VERSION + 1,
+            features: Features::all(),
+        };
+        proto::write_framed(&mut agent_write, &newer.encode())
+            .await
+            .unwrap();
+
+        let mut channel = AgentChannel::from_io(client_read, client_write);
+        let error = channel.handshake().await.unwrap_err().to_string();
+        assert!(error.contains("protocol version"), "{error}");
+    }
+
+    #[tokio::test]
+    async fn test_shared_agent_assigns_address() {
+        let (client, agent) = tokio::io::duplex(1 << 16);
+        let (client_read, client_write) = tokio::io::split(client);
+        let (mut agent_read, mut agent_write) = tokio::io::split(agent);
+        tokio::spawn(async move {
+            proto::handshake::accept(&mut agent_read, &mut agent_write, Features::all())
+                .await
+                .unwrap();
+            let assign = Control::Assign {
+                address: "10.8.0.3".parse().unwrap(),
+                prefix_len: 24,
+            };
+            proto::write_framed(&mut agent_write, &assign.encode())
+                .await
+                .unwrap();
+            (agent_read, agent_write)
+        });
+
+        let mut channel = AgentChannel::from_io(client_read, client_write);
+        channel.handshake().await.unwrap();
+        assert_eq!(channel.assigned(), Some("10.8.0.3/24".parse().unwrap()));
+    }
+
+    #[tokio::test]
+    async fn test_compression_when_agreed() {
+        let (client, agent) = tokio::io::duplex(1 << 20);
+        let (client_read, client_write) = tokio::io::split(client);
+        let (mut agent_read, mut agent_write) = tokio::io::split(agent);
+        let fake_agent = tokio::spawn(async move {
+            let features = Features::all().without(Features::SHARED);
+            proto::handshake::accept(&mut agent_read, &mut agent_write, features)
+                .await
+                .unwrap();
+            (agent_read, agent_write)
+        });
+
+        let mut channel = AgentChannel::from_io(client_read, client_write).with_compression(256);
+        channel.handshake().await.unwrap();
+        assert!(channel.features().contains(Features::LZ4));
+        let (mut agent_read, mut agent_write) = fake_agent.await.unwrap();
+
+        // Small packets go out as they are, large compressible ones shrink.
+        let packet = vec![0x45; 1400];
+        channel.send_packet(&[0x45; 20]).await.unwrap();
+        channel.send_packet(&packet).await.unwrap();
+        let small = proto::read_framed(&mut agent_read).await.unwrap();
+        assert_eq!(small, vec![0x45; 20]);
+        let frame = proto::read_framed(&mut agent_read).await.unwrap();
+        assert!(frame.len() < packet.len());
+        assert_eq!(proto::decompress(&frame).unwrap(), Some(packet.clone()));
+
+        let compressed = proto::compress(&packet, 256).unwrap();
+        proto::write_framed(&mut agent_write, &compressed)
+            .await
+            .unwrap();
+        assert_eq!(channel.recv_packet().await.unwrap(), Some(packet));
+    }
+
+    #[cfg(feature = "embed-agent")]
+    #[tokio::test]
+    async fn test_local_agent_eof_after_close() {
+        let agent = LocalAgent::loopback().await.unwrap();
+        agent.channel().close().await.unwrap();
+
+        assert_eq!(agent.channel().recv_packet().await.unwrap(), None);
+    }
+
+    fn packets() -> impl Strategy<Value = Vec<Vec<u8>>> {
+        // A leading zero byte would make a control frame, which no IP packet
+        // starts with.
+        proto::test_support::packets(prop::collection::vec(any::<u8>(), 0..1500).prop_map(
+            |mut packet| {
+                if let Some(first @ 0) = packet.first_mut() {
+                    *first = 0x45;
+                }
+                packet
+            },
+        ))
+    }
+
+    proptest! {
+        #[test]
+        fn prop_recv_packet_reassembles_any_split(
+            packets in packets(),
+            points in prop::collection::vec(any::<prop::sample::Index>(), 0..64),
+        ) {
+            let stream = framed(&packets);
+            let points = points.iter().map(|i| i.index(stream.len() + 1)).collect();
+            let channel = AgentChannel::from_io(ChunkedReader::new(&stream, points), tokio::io::sink());
+
+            let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+            runtime.block_on(async {
+                for expected in &packets {
+                    let received = channel.recv_packet().await.unwrap();
+                    assert_eq!(received.as_ref(), Some(expected));
+                }
+                assert_eq!(channel.recv_packet().await.unwrap(), None);
+            });
+        }
+
+        #[test]
+        fn prop_recv_packet_byte_at_a_time(packets in packets()) {
+            let stream = framed(&packets);
+            let points = (1..stream.len()).collect();
+            let channel = AgentChannel::from_io(ChunkedReader::new(&stream, points), tokio::io::sink());
+
+            let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+            let received: Vec<Vec<u8>> = runtime.block_on(async {
+                let mut received = Vec::new();
+                while let Some(packet) = channel.recv_packet().await.unwrap() {
+                    received.push(packet);
+                }
+                received
+            });
+            prop_assert_eq!(received, packets);
+        }
+    }
 }
@@ -0,0 +1,256 @@
+use std::path::Path;
+use std::path::PathBuf;
+
+use tracing::debug;
+use tracing::error;
+use tracing::info;
+
+use crate::config::VpnConfig;
+
+const TABLE: &str = "x2ssh_cgroup";
+
+/// Where the cgroup v2 hierarchy is mounted.
+const CGROUP_ROOT: &str = "/sys/fs/cgroup";
+
+const SRC_VALID_MARK: &str = "/proc/sys/net/ipv4/conf/all/src_valid_mark";
+
+/// The files of a cgroup its owner needs to manage it, per the kernel's
+/// cgroup v2 delegation rules.
+#[cfg(target_os = "linux")]
+const DELEGATED_FILES: &[&str] = &["cgroup.procs", "cgroup.threads", "cgroup.subtree_control"];
+
+/// Per-application routing: firewall rules that mark traffic from the
+/// processes of one cgroup (`cgroup`), so that only it looks up the policy
+/// routing table and goes through the TUN device. Everything else,
+/// including the SSH connection, keeps using the main table.
+///
+/// Marked packets are masqueraded to the client's tunnel address, since
+/// their source was picked by the main table, and replies get the mark
+/// back from conntrack so reverse path filtering accepts them.
+///
+/// Rules live in a dedicated nftables table, removed on [`disable`] or
+/// drop, which also put `src_valid_mark` back. A cgroup created under sudo
+/// is delegated to the user who ran it.
+///
+/// [`disable`]: CgroupRouting::disable
+pub struct CgroupRouting {
+    enabled: bool,
+    /// Created for this session, so removed with it if still empty.
+    created: Option<PathBuf>,
+    /// The `src_valid_mark` setting before the session changed it.
+    src_valid_mark: Option<String>,
+}
+
+impl CgroupRouting {
+    #[cfg(target_os = "linux")]
+    pub async fn enable(config: &VpnConfig, cgroup: &str) -> anyhow::Result<Self> {
+        let dir = cgroup_dir(cgroup)?;
+        let created = if dir.exists() {
+            None
+        } else {
+            std::fs::create_dir_all(&dir)
+                .map_err(|e| anyhow::anyhow!("failed to create cgroup {}: {}", dir.display(), e))?;
+            if let Some((uid, gid)) = invoking_user() {
+                delegate(&dir, uid, gid)?;
+            }
+            Some(dir)
+        };
+        // Lets reverse path filtering see the restored mark, like wg-quick.
+        let previous = std::fs::read_to_string(SRC_VALID_MARK)?.trim().to_string();
+        let src_valid_mark = if previous == "1" {
+            None
+        } else {
+            std::fs::write(SRC_VALID_MARK, "1")?;
+            Some(previous)
+        };
+
+        let rules = Self::rules(config, cgroup);
+        info!(
+            "Routing cgroup {} through the tunnel (nftables table inet {})",
+            cgroup, TABLE
+        );
+        // Dropped on failure, which puts `src_valid_mark` back.
+        let mut routing = Self {
+            enabled: false,
+            created,
+            src_valid_mark,
+        };
+        crate::vpn::killswitch::run_nft(&rules).await?;
+        routing.enabled = true;
+        Ok(routing)
+    }
+
+    /// The nftables script [`enable`] loads.
+    ///
+    /// [`enable`]: CgroupRouting::enable
+    pub fn rules(config: &VpnConfig, cgroup: &str) -> String {
+        ruleset(&config.client_tun, cgroup, config.policy_table)
+    }
+
+    #[cfg(target_os = "windows")]
+    pub async fn enable(_config: &VpnConfig, _cgroup: &str) -> anyhow::Result<Self> {
+        anyhow::bail!("per-application routing (cgroup) is only supported on Linux")
+    }
+
+    #[cfg(target_os = "linux")]
+    pub async fn disable(&mut self) -> anyhow::Result<()> {
+        if !self.enabled {
+            return Ok(());
+        }
+        info!("Removing cgroup routing");
+        crate::vpn::killswitch::run_nft(&format!("delete table inet {TABLE}\n")).await?;
+        self.enabled = false;
+        self.restore_src_valid_mark();
+        if let Some(dir) = self.created.take()
+            && let Err(e) = std::fs::remove_dir(&dir)
+        {
+            debug!("Leaving cgroup {} in place: {}", dir.display(), e);
+        }
+        Ok(())
+    }
+
+    #[cfg(target_os = "windows")]
+    pub async fn disable(&mut self) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    fn restore_src_valid_mark(&mut self) {
+        if let Some(previous) = self.src_valid_mark.take()
+            && let Err(e) = std::fs::write(SRC_VALID_MARK, &previous)
+        {
+            error!(
+                "Failed to restore {} to {}: {}",
+                SRC_VALID_MARK, previous, e
+            );
+        }
+    }
+}
+
+impl Drop for CgroupRouting {
+    fn drop(&mut self) {
+        #[cfg(target_os = "linux")]
+        if self.enabled {
+            // Blocking, but runs at most once and must not be skipped.
+            let status = std::process::Command::new("nft")
+                .args(["delete", "table", "inet", TABLE])
+                .status();
+            if !matches!(status, Ok(status) if status.success()) {
+                error!("Failed to remove cgroup routing table inet {}", TABLE);
+            }
+        }
+        self.restore_src_valid_mark();
+    }
+}
+
+/// The directory of `cgroup`, a path relative to the cgroup v2 root such
+/// as `x2ssh` or `user.slice/x2ssh`.
+pub fn cgroup_dir(cgroup: &str) -> anyhow::Result<PathBuf> {
+    let path = Path::new(cgroup);
+    let relative = path
+        .components()
+        .all(|component| matches!(component, std::path::Component::Normal(_)));
+    if cgroup.is_empty() || !relative {
+        anyhow::bail!("cgroup must be a path below {CGROUP_ROOT}, e.g. x2ssh; got '{cgroup}'");
+    }
+    Ok(Path::new(CGROUP_ROOT).join(path))
+}
+
+/// The user who ran x2ssh through sudo, as `(uid, gid)`; `None` when it
+/// was not run through sudo.
+pub fn invoking_user() -> Option<(u32, u32)> {
+    let id = |var: &str| std::env::var(var).ok()?.parse().ok();
+    Some((id("SUDO_UID")?, id("SUDO_GID")?))
+}
+
+/// Hands `dir` to the user `uid`, so they can move processes into it
+/// without root.
+#[cfg(target_os = "linux")]
+fn delegate(dir: &Path, uid: u32, gid: u32) -> anyhow::Result<()> {
+    let files = DELEGATED_FILES.iter().map(|file| dir.join(file));
+    for path in std::iter::once(dir.to_path_buf()).chain(files) {
+        std::os::unix::fs::chown(&path, Some(uid), Some(gid)).map_err(|e| {
+            anyhow::anyhow!(
+                "failed to delegate {} to uid {}: {}",
+                path.display(),
+                uid,
+                e
+            )
+        })?;
+    }
+    debug!("Delegated cgroup {} to uid {}", dir.display(), uid);
+    Ok(())
+}
+
+/// Moves this process into `cgroup`, so it and the processes it starts
+/// are routed through the tunnel. Needs write access to the cgroup's
+/// `cgroup.procs` (root, or a cgroup delegated to this user).
+pub fn join(cgroup: &str) -> anyhow::Result<()> {
+    let procs = cgroup_dir(cgroup)?.join("cgroup.procs");
+    std::fs::write(&procs, std::process::id().to_string()).map_err(|e| {
+        anyhow::anyhow!(
+            "failed to join cgroup {} ({}); is the VPN running with cgroup = \"{}\"?",
+            cgroup,
+            e,
+            cgroup
+        )
+    })
+}
+
+/// Builds the nftables script. Recreating the table in one transaction
+/// makes it atomic and replaces leftovers from a previous crashed run.
+fn ruleset(tun: &str, cgroup: &str, mark: u32) -> String {
+    let level = Path::new(cgroup).components().count();
+    [
+        format!("table inet {TABLE}"),
+        format!("delete table inet {TABLE}"),
+        format!("table inet {TABLE} {{"),
+        "    chain output {".to_string(),
+        "        type route hook output priority mangle; policy accept;".to_string(),
+        // Loopback services such as a local resolver stay reachable.
+        "        fib daddr type local return".to_string(),
+        format!(
+            "        socket cgroupv2 level {level} \"{cgroup}\" meta mark set {mark} ct mark set \
+             meta mark"
+        ),
+        "    }".to_string(),
+        "    chain prerouting {".to_string(),
+        "        type filter hook prerouting priority mangle; policy accept;".to_string(),
+        format!("        iifname \"{tun}\" ct mark {mark} meta mark set ct mark"),
+        "    }".to_string(),
+        "    chain postrouting {".to_string(),
+        "        type nat hook postrouting priority srcnat; policy accept;".to_string(),
+        format!("        oifname \"{tun}\" meta mark {mark} masquerade"),
+        "    }".to_string(),
+        "}".to_string(),
+    ]
+    .join("\n")
+        + "\n"
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ruleset() {
+        let rules = ruleset("tun-x2ssh", "user.slice/x2ssh", 30770);
+        assert!(rules.starts_with("table inet x2ssh_cgroup\ndelete table inet x2ssh_cgroup\n"));
+        assert!(rules.contains(
+            "socket cgroupv2 level 2 \"user.slice/x2ssh\" meta mark set 30770 ct mark set meta \
+             mark"
+        ));
+        assert!(rules.contains("iifname \"tun-x2ssh\" ct mark 30770 meta mark set ct mark"));
+        assert!(rules.contains("oifname \"tun-x2ssh\" meta mark 30770 masquerade"));
+    }
+
+    #[test]
+    fn test_cgroup_dir() {
+        assert_eq!(
+            cgroup_dir("user.slice/x2ssh").unwrap(),
+            Path::new("/sys/fs/cgroup/user.slice/x2ssh")
+        );
+        assert!(cgroup_dir("").is_err());
+        assert!(cgroup_dir("/sys/fs/cgroup/x2ssh").is_err());
+        assert!(cgroup_dir("x2ssh/../other").is_err());
+    }
+}
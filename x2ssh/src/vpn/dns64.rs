@@ -0,0 +1,375 @@
+use std::net::IpAddr;
+use std::net::Ipv4Addr;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use futures::StreamExt;
+use futures::stream::FuturesUnordered;
+use ipnet::Ipv6Net;
+use tokio::io::AsyncReadExt;
+use tokio::io::AsyncWriteExt;
+use tokio::net::UdpSocket;
+use tracing::debug;
+use tracing::info;
+
+use super::domains::CLASS_IN;
+use super::domains::TYPE_A;
+use super::domains::TYPE_AAAA;
+use super::domains::be16;
+use super::domains::read_name;
+use super::nat64;
+use crate::config::VpnConfig;
+use crate::transport::Transport;
+
+/// How long one upstream exchange may take.
+const QUERY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Largest UDP answer for clients that did not advertise EDNS.
+const PLAIN_UDP_LIMIT: usize = 512;
+
+/// Local DNS resolver for IPv6-only clients. Queries go to a resolver
+/// reachable from the server, over DNS-over-TCP through an SSH channel.
+/// AAAA queries for names that only have A records are answered with
+/// addresses in the NAT64 prefix, which the forwarder then translates back
+/// to IPv4 (see [`Nat64`](super::nat64::Nat64)).
+pub struct Dns64Resolver {
+    socket: UdpSocket,
+    upstream: SocketAddr,
+    prefix: Ipv6Net,
+}
+
+impl Dns64Resolver {
+    /// Binds `dns64_listen`. Without a configured `dns64_upstream`, uses
+    /// the first nameserver in the server's `/etc/resolv.conf`.
+    pub async fn start(transport: &Transport, config: &VpnConfig) -> anyhow::Result<Self> {
+        let listen: SocketAddr = config.dns64_listen.parse().map_err(|e| {
+            anyhow::anyhow!("invalid dns64_listen '{}': {}", config.dns64_listen, e)
+        })?;
+        let prefix: Ipv6Net = config.nat64_prefix.parse().map_err(|e| {
+            anyhow::anyhow!("invalid nat64_prefix '{}': {}", config.nat64_prefix, e)
+        })?;
+        let upstream = match &config.dns64_upstream {
+            Some(upstream) => parse_upstream(upstream)?,
+            None => server_nameserver(transport).await?,
+        };
+
+        let socket = UdpSocket::bind(listen).await?;
+        info!(
+            "DNS64 resolver listening on {} (upstream {} via the server)",
+            listen, upstream
+        );
+        Ok(Self {
+            socket,
+            upstream,
+            prefix,
+        })
+    }
+
+    /// Answers queries until dropped, several at a time.
+    pub async fn serve(&self, transport: &Transport) {
+        let mut pending = FuturesUnordered::new();
+        let mut buf = vec![0u8; 65535];
+        loop {
+            tokio::select! {
+                received = self.socket.recv_from(&mut buf) => match received {
+                    Ok((n, client)) => {
+                        let query = buf[..n].to_vec();
+                        pending.push(async move {
+                            let result = self.resolve(transport, &query).await;
+                            (client, query, result)
+                        });
+                    }
+                    Err(e) => debug!("DNS64 receive failed: {}", e),
+                },
+                Some((client, query, result)) = pending.next(), if !pending.is_empty() => {
+                    match result {
+                        Ok(response) => {
+                            let response = fit_udp(&query, response);
+                            if let Err(e) = self.socket.send_to(&response, client).await {
+                                debug!("DNS64 reply to {} failed: {}", client, e);
+                            }
+                        }
+                        Err(e) => debug!("DNS64 query from {} failed: {}", client, e),
+                    }
+                }
+            }
+        }
+    }
+
+    async fn resolve(&self, transport: &Transport, query: &[u8]) -> anyhow::Result<Vec<u8>> {
+        let response = exchange(transport, self.upstream, query).await?;
+        let Some((name, TYPE_AAAA, _)) = question(query) else {
+            return Ok(response);
+        };
+        if !lacks_aaaa(&response) {
+            return Ok(response);
+        }
+
+        let a_query = build_query(be16(query, 0).unwrap_or(0), &name, TYPE_A);
+        let a_response = exchange(transport, self.upstream, &a_query).await?;
+        Ok(synthesize_response(&response, &a_response, self.prefix).unwrap_or(response))
+    }
+}
+
+fn parse_upstream(upstream: &str) -> anyhow::Result<SocketAddr> {
+    if let Ok(ip) = upstream.parse::<IpAddr>() {
+        return Ok(SocketAddr::new(ip, 53));
+    }
+    upstream
+        .parse()
+        .map_err(|e| anyhow::anyhow!("invalid dns64_upstream '{}': {}", upstream, e))
+}
+
+async fn server_nameserver(transport: &Transport) -> anyhow::Result<SocketAddr> {
+    let result = transport
+        .exec("sed -n 's/^nameserver[[:space:]]*//p' /etc/resolv.conf")
+        .await?;
+    String::from_utf8_lossy(&result.stdout)
+        .lines()
+        .find_map(|line| line.trim().parse::<IpAddr>().ok())
+        .map(|ip| SocketAddr::new(ip, 53))
+        .ok_or_else(|| {
+            anyhow::anyhow!("the server's /etc/resolv.conf has no nameserver; set dns64_upstream")
+        })
+}
+
+/// Sends `message` to `upstream` over DNS-over-TCP through an SSH channel.
+async fn exchange(
+    transport: &Transport,
+    upstream: SocketAddr,
+    message: &[u8],
+) -> anyhow::Result<Vec<u8>> {
+    let exchange = async {
+        let mut stream = transport.open_forward_stream(upstream).await?;
+        let mut framed = u16::try_from(message.len())?.to_be_bytes().to_vec();
+        framed.extend_from_slice(message);
+        stream.write_all(&framed).await?;
+
+        let mut len = [0u8; 2];
+        stream.read_exact(&mut len).await?;
+        let mut response = vec![0u8; usize::from(u16::from_be_bytes(len))];
+        stream.read_exact(&mut response).await?;
+        anyhow::Ok(response)
+    };
+    tokio::time::timeout(QUERY_TIMEOUT, exchange)
+        .await
+        .map_err(|_| anyhow::anyhow!("no answer from {} within {:?}", upstream, QUERY_TIMEOUT))?
+}
+
+/// The single question of `message`: its name, type, and where the
+/// question section ends.
+fn question(message: &[u8]) -> Option<(String, u16, usize)> {
+    if be16(message, 4)? != 1 {
+        return None;
+    }
+    let (name, pos) = read_name(message, 12)?;
+    Some((name, be16(message, pos)?, pos + 4))
+}
+
+struct Record<'a> {
+    kind: u16,
+    class: u16,
+    ttl: u32,
+    rdata: &'a [u8],
+}
+
+/// The answer section of `message`, if it is a successful response.
+fn answers(message: &[u8]) -> Option<Vec<Record<'_>>> {
+    let flags = be16(message, 2)?;
+    if flags & 0x8000 == 0 || flags & 0x000f != 0 {
+        return None;
+    }
+    let (_, _, mut pos) = question(message)?;
+    let mut records = Vec::new();
+    for _ in 0..be16(message, 6)? {
+        let (_, next) = read_name(message, pos)?;
+        let rdata_len = usize::from(be16(message, next + 8)?);
+        records.push(Record {
+            kind: be16(message, next)?,
+            class: be16(message, next + 2)?,
+            ttl: u32::from(be16(message, next + 4)?) << 16 | u32::from(be16(message, next + 6)?),
+            rdata: message.get(next + 10..next + 10 + rdata_len)?,
+        });
+        pos = next + 10 + rdata_len;
+    }
+    Some(records)
+}
+
+/// Whether `response` is a successful answer without any AAAA record,
+/// i.e. the name may only have IPv4 addresses.
+fn lacks_aaaa(response: &[u8]) -> bool {
+    answers(response).is_some_and(|records| records.iter().all(|r| r.kind != TYPE_AAAA))
+}
+
+fn build_query(id: u16, name: &str, kind: u16) -> Vec<u8> {
+    let mut message = Vec::with_capacity(name.len() + 18);
+    message.extend_from_slice(&id.to_be_bytes());
+    message.extend_from_slice(&[0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0]); // RD, 1 question
+    for label in name.split('.').filter(|label| !label.is_empty()) {
+        message.push(label.len() as u8);
+        message.extend_from_slice(label.as_bytes());
+    }
+    message.push(0);
+    message.extend_from_slice(&kind.to_be_bytes());
+    message.extend_from_slice(&CLASS_IN.to_be_bytes());
+    message
+}
+
+/// Answers the AAAA query behind `aaaa_response` with the A records of
+/// `a_response` mapped into `prefix`. CNAMEs are left out; every record is
+/// owned by the queried name.
+fn synthesize_response(
+    aaaa_response: &[u8],
+    a_response: &[u8],
+    prefix: Ipv6Net,
+) -> Option<Vec<u8>> {
+    let (_, _, question_end) = question(aaaa_response)?;
+    let synthesized: Vec<(u32, Ipv4Addr)> = answers(a_response)?
+        .iter()
+        .filter(|r| r.kind == TYPE_A && r.class == CLASS_IN)
+        .filter_map(|r| Some((r.ttl, Ipv4Addr::from(<[u8; 4]>::try_from(r.rdata).ok()?))))
+        .collect();
+    if synthesized.is_empty() {
+        return None;
+    }
+
+    let mut message = aaaa_response[..question_end].to_vec();
+    message[6..8].copy_from_slice(&(synthesized.len() as u16).to_be_bytes());
+    message[8..12].fill(0);
+    for (ttl, v4) in synthesized {
+        message.extend_from_slice(&[0xc0, 0x0c]); // The question's name
+        message.extend_from_slice(&TYPE_AAAA.to_be_bytes());
+        message.extend_from_slice(&CLASS_IN.to_be_bytes());
+        message.extend_from_slice(&ttl.to_be_bytes());
+        message.extend_from_slice(&16u16.to_be_bytes());
+        message.extend_from_slice(&nat64::synthesize(prefix, v4).octets());
+    }
+    Some(message)
+}
+
+/// Cuts `response` down to its question with the truncated bit set when it
+/// is too large for a client that did not advertise EDNS, so the client
+/// retries over TCP instead of receiving a datagram it cannot take.
+fn fit_udp(query: &[u8], response: Vec<u8>) -> Vec<u8> {
+    let has_edns = be16(query, 10).is_some_and(|additional| additional > 0);
+    if has_edns || response.len() <= PLAIN_UDP_LIMIT {
+        return response;
+    }
+    let Some((_, _, question_end)) = question(&response) else {
+        return response;
+    };
+    let mut truncated = response[..question_end].to_vec();
+    truncated[2] |= 0x02;
+    truncated[6..12].fill(0);
+    truncated
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv6Addr;
+
+    use super::*;
+
+    /// A response to `query` carrying `records` as (type, ttl, rdata).
+    fn response(query: &[u8], records: &[(u16, u32, &[u8])]) -> Vec<u8> {
+        let mut message = query.to_vec();
+        message[2] |= 0x80;
+        message[6..8].copy_from_slice(&(records.len() as u16).to_be_bytes());
+        for (kind, ttl, rdata) in records {
+            message.extend_from_slice(&[0xc0, 0x0c]);
+            message.extend_from_slice(&kind.to_be_bytes());
+            message.extend_from_slice(&CLASS_IN.to_be_bytes());
+            message.extend_from_slice(&ttl.to_be_bytes());
+            message.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+            message.extend_from_slice(rdata);
+        }
+        message
+    }
+
+    #[test]
+    fn test_build_query() {
+        let query = build_query(0x1234, "ipv4.example.", TYPE_AAAA);
+        assert_eq!(
+            question(&query),
+            Some(("ipv4.example".to_string(), TYPE_AAAA, query.len()))
+        );
+        assert_eq!(be16(&query, 0), Some(0x1234));
+    }
+
+    #[test]
+    fn test_synthesizes_aaaa_from_a() {
+        let prefix: Ipv6Net = "64:ff9b::/96".parse().unwrap();
+        let aaaa_query = build_query(7, "ipv4.example", TYPE_AAAA);
+        let aaaa_response = response(&aaaa_query, &[]);
+        assert!(lacks_aaaa(&aaaa_response));
+
+        let a_query = build_query(7, "ipv4.example", TYPE_A);
+        let a_response = response(&a_query, &[
+            (TYPE_A, 300, &[192, 0, 2, 1]),
+            (TYPE_A, 60, &[192, 0, 2, 2]),
+        ]);
+
+        let synthesized = synthesize_response(&aaaa_response, &a_response, prefix).unwrap();
+        let records = answers(&synthesized).unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].kind, TYPE_AAAA);
+        assert_eq!(records[0].ttl, 300);
+        assert_eq!(
+            records[1].rdata,
+            "64:ff9b::c000:202".parse::<Ipv6Addr>().unwrap().octets()
+        );
+        assert_eq!(question(&synthesized).unwrap().0, "ipv4.example");
+    }
+
+    #[test]
+    fn test_keeps_native_aaaa_and_errors() {
+        let query = build_query(7, "dual.example", TYPE_AAAA);
+        let native = response(&query, &[(
+            TYPE_AAAA,
+            60,
+            &"2001:db8::1".parse::<Ipv6Addr>().unwrap().octets(),
+        )]);
+        assert!(!lacks_aaaa(&native));
+
+        let mut nxdomain = response(&query, &[]);
+        nxdomain[3] |= 0x03;
+        assert!(!lacks_aaaa(&nxdomain));
+
+        let prefix = "64:ff9b::/96".parse().unwrap();
+        let no_a = response(&build_query(7, "dual.example", TYPE_A), &[]);
+        assert_eq!(
+            synthesize_response(&response(&query, &[]), &no_a, prefix),
+            None
+        );
+    }
+
+    #[test]
+    fn test_fit_udp_truncates_for_plain_clients() {
+        let query = build_query(7, "big.example", TYPE_A);
+        let records: Vec<(u16, u32, &[u8])> =
+            (0..40).map(|_| (TYPE_A, 60, &[10, 0, 0, 1][..])).collect();
+        let big = response(&query, &records);
+        assert!(big.len() > PLAIN_UDP_LIMIT);
+
+        let truncated = fit_udp(&query, big.clone());
+        assert_eq!(truncated.len(), query.len());
+        assert_ne!(truncated[2] & 0x02, 0);
+
+        let mut edns_query = query.clone();
+        edns_query[11] = 1;
+        assert_eq!(fit_udp(&edns_query, big.clone()), big);
+    }
+
+    #[test]
+    fn test_parse_upstream() {
+        assert_eq!(
+            parse_upstream("10.0.0.2").unwrap(),
+            "10.0.0.2:53".parse().unwrap()
+        );
+        assert_eq!(
+            parse_upstream("[2001:db8::53]:5353").unwrap(),
+            "[2001:db8::53]:5353".parse().unwrap()
+        );
+        assert!(parse_upstream("resolver").is_err());
+    }
+}
@@ -0,0 +1,467 @@
+use std::collections::HashSet;
+use std::net::IpAddr;
+use std::net::Ipv4Addr;
+use std::net::Ipv6Addr;
+use std::sync::Arc;
+use std::sync::Mutex;
+
+use ipnet::IpNet;
+use tracing::debug;
+use tracing::info;
+use tracing::warn;
+
+use super::routing::RoutingManager;
+use crate::transport::Transport;
+
+/// One `domains` entry: an exact name, or `*.suffix` matching every name
+/// below `suffix` (but not `suffix` itself).
+#[derive(Debug, Clone, PartialEq)]
+pub struct DomainRule {
+    domain: String,
+    wildcard: bool,
+}
+
+impl DomainRule {
+    pub fn parse(pattern: &str) -> anyhow::Result<Self> {
+        let normalized = normalize(pattern);
+        let (domain, wildcard) = match normalized.strip_prefix("*.") {
+            Some(suffix) => (suffix.to_string(), true),
+            None => (normalized, false),
+        };
+
+        let valid_label = |label: &str| {
+            !label.is_empty()
+                && label.len() <= 63
+                && label
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        };
+        if domain.is_empty() || !domain.split('.').all(valid_label) {
+            anyhow::bail!("invalid domain pattern '{}'", pattern);
+        }
+
+        Ok(Self { domain, wildcard })
+    }
+
+    pub fn matches(&self, name: &str) -> bool {
+        let name = normalize(name);
+        if self.wildcard {
+            name.strip_suffix(&self.domain)
+                .is_some_and(|prefix| prefix.len() > 1 && prefix.ends_with('.'))
+        } else {
+            name == self.domain
+        }
+    }
+}
+
+fn normalize(name: &str) -> String {
+    name.trim().trim_end_matches('.').to_ascii_lowercase()
+}
+
+/// The configured `domains`, parsed.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DomainRules {
+    rules: Vec<DomainRule>,
+}
+
+impl DomainRules {
+    pub fn parse(patterns: &[String]) -> anyhow::Result<Self> {
+        let rules = patterns
+            .iter()
+            .map(|pattern| DomainRule::parse(pattern))
+            .collect::<anyhow::Result<_>>()?;
+        Ok(Self { rules })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    pub fn matches(&self, name: &str) -> bool {
+        self.rules.iter().any(|rule| rule.matches(name))
+    }
+
+    /// Names that can be resolved up front; wildcards cannot be enumerated.
+    pub fn exact_names(&self) -> impl Iterator<Item = &str> {
+        self.rules
+            .iter()
+            .filter(|rule| !rule.wildcard)
+            .map(|rule| rule.domain.as_str())
+    }
+}
+
+/// Installs host routes through the tunnel for addresses of matching
+/// domains.
+///
+/// Addresses are learned two ways: exact names are resolved by the server
+/// (so split-horizon names resolve as they would on the remote network),
+/// and DNS answers arriving through the tunnel are inspected for any
+/// matching name, which is what makes wildcards work. For the latter the
+/// resolver must be reachable through the tunnel, e.g. listed in
+/// `include`. Routes are installed before the answer is delivered, so the
+/// first connection already takes the tunnel. They stay until the session
+/// is cleaned up.
+pub struct DomainRouter {
+    rules: DomainRules,
+    routing: Arc<tokio::sync::Mutex<RoutingManager>>,
+    routed: Mutex<HashSet<IpAddr>>,
+}
+
+impl DomainRouter {
+    pub fn new(rules: DomainRules, routing: Arc<tokio::sync::Mutex<RoutingManager>>) -> Self {
+        Self {
+            rules,
+            routing,
+            routed: Mutex::new(HashSet::new()),
+        }
+    }
+
+    /// Resolves the exact names on the server and routes their addresses.
+    /// Failures are logged; the names may still be picked up from DNS
+    /// answers later.
+    pub async fn resolve_exact(&self, transport: &Transport) {
+        for name in self.rules.exact_names() {
+            // Rule validation restricts names to shell-safe characters.
+            match transport.exec(&format!("getent ahosts {name}")).await {
+                Ok(result) if result.exit_code == 0 => {
+                    let addresses = parse_getent(&String::from_utf8_lossy(&result.stdout));
+                    self.route(name, &addresses).await;
+                }
+                Ok(result) => warn!(
+                    "Could not resolve {} on the server (exit code {})",
+                    name, result.exit_code
+                ),
+                Err(e) => warn!("Could not resolve {} on the server: {}", name, e),
+            }
+        }
+    }
+
+    /// Routes the addresses in `packet` if it is a DNS answer for a
+    /// matching name. Other packets are ignored.
+    pub async fn inspect(&self, packet: &[u8]) {
+        if let Some(answer) = dns_answer(packet)
+            && self.rules.matches(&answer.name)
+        {
+            self.route(&answer.name, &answer.addresses).await;
+        }
+    }
+
+    async fn route(&self, name: &str, addresses: &[IpAddr]) {
+        let mut routing = self.routing.lock().await;
+        for &address in addresses {
+            if address.is_ipv6() && !routing.tunnels_ipv6() {
+                debug!("Not routing {} ({}): tunnel has no IPv6", address, name);
+                continue;
+            }
+            if !self.routed.lock().unwrap().insert(address) {
+                continue;
+            }
+            info!("Routing {} ({}) through the tunnel", address, name);
+            if let Err(e) = routing.route_through_tunnel(IpNet::from(address)).await {
+                warn!("Failed to route {} through the tunnel: {}", address, e);
+                self.routed.lock().unwrap().remove(&address);
+            }
+        }
+    }
+}
+
+/// Parses `getent ahosts` output: one address per line in the first
+/// column, repeated per socket type.
+fn parse_getent(output: &str) -> Vec<IpAddr> {
+    let mut addresses = Vec::new();
+    for address in output
+        .lines()
+        .filter_map(|line| line.split_whitespace().next()?.parse().ok())
+    {
+        if !addresses.contains(&address) {
+            addresses.push(address);
+        }
+    }
+    addresses
+}
+
+#[derive(Debug, PartialEq)]
+pub struct DnsAnswer {
+    /// The queried name, lowercase and without the trailing dot.
+    pub name: String,
+    /// A and AAAA records in the answer section, including those reached
+    /// through CNAMEs.
+    pub addresses: Vec<IpAddr>,
+}
+
+const DNS_PORT: u16 = 53;
+const UDP: u8 = 17;
+pub(super) const TYPE_A: u16 = 1;
+pub(super) const TYPE_AAAA: u16 = 28;
+pub(super) const CLASS_IN: u16 = 1;
+
+/// Decodes a DNS response carried in an unfragmented IPv4 or IPv6 UDP
+/// packet from port 53. Returns `None` for anything else, including
+/// errors and truncated messages.
+pub fn dns_answer(packet: &[u8]) -> Option<DnsAnswer> {
+    let udp = udp_payload(packet)?;
+    if be16(udp, 0)? != DNS_PORT {
+        return None;
+    }
+    let length = usize::from(be16(udp, 4)?);
+    parse_dns_response(udp.get(8..length.min(udp.len()))?)
+}
+
+fn udp_payload(packet: &[u8]) -> Option<&[u8]> {
+    match packet.first()? >> 4 {
+        4 => {
+            let header_len = usize::from(packet[0] & 0x0f) * 4;
+            let total_len = usize::from(be16(packet, 2)?);
+            let fragment = be16(packet, 6)?;
+            if *packet.get(9)? != UDP || fragment & 0x3fff != 0 {
+                return None;
+            }
+            packet.get(header_len..total_len.min(packet.len()))
+        }
+        6 => {
+            let payload_len = usize::from(be16(packet, 4)?);
+            if *packet.get(6)? != UDP {
+                return None;
+            }
+            packet.get(40..(40 + payload_len).min(packet.len()))
+        }
+        _ => None,
+    }
+}
+
+fn parse_dns_response(message: &[u8]) -> Option<DnsAnswer> {
+    let flags = be16(message, 2)?;
+    let is_response = flags & 0x8000 != 0;
+    let rcode = flags & 0x000f;
+    if !is_response || rcode != 0 || be16(message, 4)? != 1 {
+        return None;
+    }
+    let answers = be16(message, 6)?;
+
+    let (name, mut pos) = read_name(message, 12)?;
+    pos += 4; // QTYPE, QCLASS
+
+    let mut addresses = Vec::new();
+    for _ in 0..answers {
+        let (_, next) = read_name(message, pos)?;
+        let kind = be16(message, next)?;
+        let class = be16(message, next + 2)?;
+        let rdata_len = usize::from(be16(message, next + 8)?);
+        let rdata = message.get(next + 10..next + 10 + rdata_len)?;
+        pos = next + 10 + rdata_len;
+
+        if class != CLASS_IN {
+            continue;
+        }
+        match (kind, rdata.len()) {
+            (TYPE_A, 4) => {
+                let octets: [u8; 4] = rdata.try_into().ok()?;
+                addresses.push(IpAddr::V4(Ipv4Addr::from(octets)));
+            }
+            (TYPE_AAAA, 16) => {
+                let octets: [u8; 16] = rdata.try_into().ok()?;
+                addresses.push(IpAddr::V6(Ipv6Addr::from(octets)));
+            }
+            _ => {}
+        }
+    }
+
+    Some(DnsAnswer { name, addresses })
+}
+
+/// Reads a possibly compressed name at `pos`, returning it and the position
+/// just past it.
+pub(super) fn read_name(message: &[u8], mut pos: usize) -> Option<(String, usize)> {
+    let mut labels: Vec<String> = Vec::new();
+    let mut end = None;
+    // Bounds pointer chasing so a looping message cannot hang us.
+    for _ in 0..128 {
+        let len = *message.get(pos)?;
+        match len & 0xc0 {
+            0x00 if len == 0 => {
+                let name = labels.join(".").to_ascii_lowercase();
+                return Some((name, end.unwrap_or(pos + 1)));
+            }
+            0x00 => {
+                let label = message.get(pos + 1..pos + 1 + usize::from(len))?;
+                labels.push(String::from_utf8_lossy(label).into_owned());
+                pos += 1 + usize::from(len);
+            }
+            0xc0 => {
+                let offset = usize::from(be16(message, pos)? & 0x3fff);
+                end.get_or_insert(pos + 2);
+                pos = offset;
+            }
+            _ => return None,
+        }
+    }
+    None
+}
+
+pub(super) fn be16(bytes: &[u8], at: usize) -> Option<u16> {
+    Some(u16::from_be_bytes([*bytes.get(at)?, *bytes.get(at + 1)?]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rule_matching() {
+        let wildcard = DomainRule::parse("*.corp.example").unwrap();
+        assert!(wildcard.matches("git.corp.example"));
+        assert!(wildcard.matches("a.b.corp.example."));
+        assert!(wildcard.matches("WIKI.Corp.Example"));
+        assert!(!wildcard.matches("corp.example"));
+        assert!(!wildcard.matches("notcorp.example"));
+        assert!(!wildcard.matches("corp.example.com"));
+
+        let exact = DomainRule::parse("Wiki.Example.org.").unwrap();
+        assert!(exact.matches("wiki.example.org"));
+        assert!(!exact.matches("www.wiki.example.org"));
+    }
+
+    #[test]
+    fn test_rule_rejects_invalid_patterns() {
+        for pattern in [
+            "",
+            "*.",
+            "*",
+            "corp..example",
+            "a.*.example",
+            "host;rm -rf /",
+        ] {
+            assert!(DomainRule::parse(pattern).is_err(), "{pattern:?}");
+        }
+    }
+
+    #[test]
+    fn test_exact_names() {
+        let rules =
+            DomainRules::parse(&["*.corp.example".to_string(), "wiki.example.org".to_string()])
+                .unwrap();
+        assert_eq!(rules.exact_names().collect::<Vec<_>>(), vec![
+            "wiki.example.org"
+        ]);
+        assert!(rules.matches("git.corp.example"));
+        assert!(!DomainRules::default().matches("git.corp.example"));
+    }
+
+    #[test]
+    fn test_parse_getent() {
+        let output = "\
+10.20.0.5       STREAM git.corp.example
+10.20.0.5       DGRAM
+10.20.0.5       RAW
+fd00:20::5      STREAM
+fd00:20::5      DGRAM
+";
+        assert_eq!(parse_getent(output), vec![
+            "10.20.0.5".parse::<IpAddr>().unwrap(),
+            "fd00:20::5".parse().unwrap(),
+        ]);
+    }
+
+    /// Builds the DNS response for `git.corp.example`: a CNAME to
+    /// `srv.corp.example` (compressed), then its A and AAAA records.
+    fn dns_response() -> Vec<u8> {
+        let mut message = vec![
+            0x12, 0x34, 0x81, 0x80, // id, flags: response, no error
+            0x00, 0x01, 0x00, 0x03, // 1 question, 3 answers
+            0x00, 0x00, 0x00, 0x00,
+        ];
+        message.extend_from_slice(b"\x03git\x04corp\x07example\x00");
+        message.extend_from_slice(&[0x00, 0x01, 0x00, 0x01]);
+        // git.corp.example CNAME srv.<corp.example at offset 16>
+        message.extend_from_slice(&[0xc0, 0x0c, 0x00, 0x05, 0x00, 0x01, 0, 0, 0, 60, 0x00, 0x06]);
+        let cname = message.len();
+        message.extend_from_slice(b"\x03srv\xc0\x10");
+        let cname_ptr = [0xc0, cname as u8];
+        message.extend_from_slice(&cname_ptr);
+        message.extend_from_slice(&[0x00, 0x01, 0x00, 0x01, 0, 0, 0, 60, 0x00, 0x04]);
+        message.extend_from_slice(&[10, 20, 0, 5]);
+        message.extend_from_slice(&cname_ptr);
+        message.extend_from_slice(&[0x00, 0x1c, 0x00, 0x01, 0, 0, 0, 60, 0x00, 0x10]);
+        message.extend_from_slice(&"fd00:20::5".parse::<Ipv6Addr>().unwrap().octets());
+        message
+    }
+
+    fn udp4(src_port: u16, payload: &[u8]) -> Vec<u8> {
+        let total = 28 + payload.len();
+        let mut packet = vec![
+            0x45,
+            0,
+            (total >> 8) as u8,
+            total as u8,
+            0,
+            0,
+            0x40,
+            0,
+            64,
+            UDP,
+        ];
+        packet.extend_from_slice(&[0, 0, 10, 20, 0, 53, 10, 8, 0, 2]);
+        packet.extend_from_slice(&src_port.to_be_bytes());
+        packet.extend_from_slice(&[0xc3, 0x50]);
+        packet.extend_from_slice(&((8 + payload.len()) as u16).to_be_bytes());
+        packet.extend_from_slice(&[0, 0]);
+        packet.extend_from_slice(payload);
+        packet
+    }
+
+    fn udp6(src_port: u16, payload: &[u8]) -> Vec<u8> {
+        let udp_len = (8 + payload.len()) as u16;
+        let mut packet = vec![0x60, 0, 0, 0];
+        packet.extend_from_slice(&udp_len.to_be_bytes());
+        packet.extend_from_slice(&[UDP, 64]);
+        packet.extend_from_slice(&[0; 32]);
+        packet.extend_from_slice(&src_port.to_be_bytes());
+        packet.extend_from_slice(&[0xc3, 0x50]);
+        packet.extend_from_slice(&udp_len.to_be_bytes());
+        packet.extend_from_slice(&[0, 0]);
+        packet.extend_from_slice(payload);
+        packet
+    }
+
+    #[test]
+    fn test_dns_answer_follows_cname() {
+        let expected = DnsAnswer {
+            name: "git.corp.example".to_string(),
+            addresses: vec!["10.20.0.5".parse().unwrap(), "fd00:20::5".parse().unwrap()],
+        };
+        assert_eq!(dns_answer(&udp4(53, &dns_response())), Some(expected));
+
+        let v6 = dns_answer(&udp6(53, &dns_response())).unwrap();
+        assert_eq!(v6.name, "git.corp.example");
+        assert_eq!(v6.addresses.len(), 2);
+    }
+
+    #[test]
+    fn test_dns_answer_ignores_other_packets() {
+        let response = dns_response();
+        assert_eq!(dns_answer(&udp4(5353, &response)), None);
+
+        let mut query = response.clone();
+        query[2] &= 0x7f;
+        assert_eq!(dns_answer(&udp4(53, &query)), None);
+
+        let mut nxdomain = response.clone();
+        nxdomain[3] = 0x83;
+        assert_eq!(dns_answer(&udp4(53, &nxdomain)), None);
+
+        let mut fragment = udp4(53, &response);
+        fragment[6] = 0x20; // more fragments
+        assert_eq!(dns_answer(&fragment), None);
+
+        let truncated = udp4(53, &response[..response.len() - 8]);
+        assert_eq!(dns_answer(&truncated), None);
+
+        assert_eq!(dns_answer(&[]), None);
+    }
+
+    #[test]
+    fn test_read_name_rejects_pointer_loop() {
+        let message = [0, 0, 0xc0, 0x02];
+        assert_eq!(read_name(&message, 2), None);
+    }
+}
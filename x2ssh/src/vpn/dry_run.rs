@@ -0,0 +1,230 @@
+use std::net::SocketAddr;
+
+use ipnet::IpNet;
+
+use super::agent;
+use super::agent::AgentPath;
+use super::cgroup;
+use super::cgroup::CgroupRouting;
+use super::elevation::RootAccess;
+use super::hooks::HookVars;
+use super::killswitch::KillSwitch;
+use super::nat64::Nat64;
+use super::routing::RoutePlan;
+use super::routing::RoutingManager;
+use super::session;
+use crate::config::VpnConfig;
+
+/// Describes what a VPN session with `config` would change, for
+/// `--vpn-dry-run`: the TUN device, routes, firewall rules and DNS on the
+/// client, and the commands run on either side. Nothing is changed and the
+/// server is not contacted; the current routes are only read.
+pub async fn plan(config: &VpnConfig, ssh_server: SocketAddr) -> anyhow::Result<String> {
+    session::validate(config)?;
+    let routes = if config.persistent_tun {
+        RoutePlan::default()
+    } else {
+        let mut through_tunnel = Vec::new();
+        if config.dns64 {
+            through_tunnel.push(IpNet::V6(Nat64::from_config(config)?.prefix()));
+        }
+        RoutingManager::new()
+            .await?
+            .plan(config, ssh_server.ip(), &through_tunnel)
+            .await?
+    };
+    render(config, ssh_server, &routes)
+}
+
+fn render(
+    config: &VpnConfig,
+    ssh_server: SocketAddr,
+    routes: &RoutePlan,
+) -> anyhow::Result<String> {
+    let mut out = Vec::new();
+
+    out.push("TUN device:".to_string());
+    if config.persistent_tun {
+        out.push(format!("  attach to persistent {}", config.client_tun));
+    } else {
+        let mut tun = format!(
+            "  create {} with {}",
+            config.client_tun, config.client_address
+        );
+        if let Some((client6, _)) = config.ipv6_addresses()? {
+            tun.push_str(&format!(" and {}", client6));
+        }
+        tun.push_str(&format!(
+            ", MTU {}, {} queue(s)",
+            config.mtu, config.tun_queues
+        ));
+        if config.offload {
+            tun.push_str(", with offloads");
+        }
+        out.push(tun);
+    }
+
+    out.push("Routes:".to_string());
+    if config.persistent_tun {
+        out.push("  none; persistent_tun leaves routing to the system".to_string());
+    } else {
+        out.extend(routes.commands.iter().map(|command| format!("  {command}")));
+    }
+
+    out.push("Firewall:".to_string());
+    let mut firewall = Vec::new();
+    if let Some(cgroup) = &config.cgroup {
+        let dir = cgroup::cgroup_dir(cgroup)?;
+        firewall.push(format!("mkdir -p {}", dir.display()));
+        firewall.push(
+            "echo 1 > /proc/sys/net/ipv4/conf/all/src_valid_mark  # restored on exit".to_string(),
+        );
+        firewall.push(nft(&CgroupRouting::rules(config, cgroup)));
+    }
+    if config.kill_switch {
+        firewall.push(nft(&KillSwitch::rules(
+            config,
+            &[ssh_server.ip()],
+            ssh_server.port(),
+            &routes.lan_subnets,
+        )?));
+    }
+    if firewall.is_empty() {
+        firewall.push("no changes".to_string());
+    }
+    out.extend(firewall.iter().map(|entry| indent(entry)));
+
+    out.push("DNS:".to_string());
+    if config.agent_dns {
+        out.push(format!(
+            "  the agent answers on {}:53; the system resolver is left as it is",
+            config.server_ip()?
+        ));
+    }
+    if config.dns64 {
+        out.push(format!(
+            "  a DNS64 resolver listens on {}; the system resolver is left as it is",
+            config.dns64_listen
+        ));
+    }
+    if !config.agent_dns && !config.dns64 {
+        out.push("  no changes".to_string());
+    }
+
+    let path = AgentPath::planned(config)?;
+    out.push("Server commands:".to_string());
+    if path.preinstalled() {
+        out.push(format!("  run the agent installed at {path}"));
+    } else {
+        out.push(format!(
+            "  upload the agent to {path}, unless that build is there already"
+        ));
+    }
+    out.push(
+        "  # as root: directly, or with sudo or doas, whichever the server allows".to_string(),
+    );
+    out.push(format!(
+        "  {}",
+        agent::start_command(config, &path, &RootAccess::Root)?
+    ));
+    let remote = HookVars::remote(config, ssh_server.ip())?;
+    for cmd in &config.post_up {
+        out.push(format!("  {}", remote.expand(cmd)?));
+    }
+
+    let local = HookVars::local(config, ssh_server.ip())?;
+    if !config.local_post_up.is_empty() {
+        out.push("Client commands:".to_string());
+        for cmd in &config.local_post_up {
+            out.push(format!("  {}", local.expand(cmd)?));
+        }
+    }
+
+    if !config.local_pre_down.is_empty() || !config.pre_down.is_empty() {
+        out.push("On disconnect:".to_string());
+        for cmd in &config.local_pre_down {
+            out.push(format!("  client: {}", local.expand(cmd)?));
+        }
+        for cmd in &config.pre_down {
+            out.push(format!("  server: {}", remote.expand(cmd)?));
+        }
+    }
+
+    Ok(out.join("\n"))
+}
+
+/// An nftables script as it is loaded, with `nft -f -`.
+fn nft(rules: &str) -> String {
+    format!("nft -f - <<'EOF'\n{}EOF", rules)
+}
+
+fn indent(text: &str) -> String {
+    text.lines()
+        .map(|line| format!("  {line}"))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let config = VpnConfig {
+            kill_switch: true,
+            agent_dns: true,
+            agent_path: Some("/opt/x2ssh/agent".to_string()),
+            post_up: vec!["iptables -A FORWARD -s %client_ip -j ACCEPT".to_string()],
+            local_pre_down: vec!["logger %i down".to_string()],
+            ..Default::default()
+        };
+        let routes = RoutePlan {
+            commands: vec!["ip -4 route del default".to_string()],
+            lan_subnets: Vec::new(),
+        };
+        let ssh_server = "203.0.113.7:22".parse().unwrap();
+
+        let plan = render(&config, ssh_server, &routes).unwrap();
+        assert!(
+            plan.starts_with(
+                "TUN device:\n  create tun-x2ssh with 10.8.0.2/24, MTU 1400, 1 \
+                 queue(s)\nRoutes:\n  ip -4 route del default\nFirewall:\n  nft -f - <<'EOF'\n  \
+                 table inet x2ssh_killswitch\n"
+            ),
+            "{plan}"
+        );
+        assert!(
+            plan.contains("elements = { 203.0.113.7 }")
+                && plan.contains("ip daddr @ssh_servers4 tcp dport 22 accept"),
+            "{plan}"
+        );
+        assert!(plan.contains("the agent answers on 10.8.0.1:53"), "{plan}");
+        assert!(
+            plan.contains("\n  '/opt/x2ssh/agent' --ip 10.8.0.1/24 --mtu 1400 --dns"),
+            "{plan}"
+        );
+        assert!(
+            plan.ends_with(
+                "  iptables -A FORWARD -s 10.8.0.2 -j ACCEPT\nOn disconnect:\n  client: logger \
+                 tun-x2ssh down"
+            ),
+            "{plan}"
+        );
+    }
+
+    #[test]
+    fn test_render_rejects_invalid_config() {
+        let config = VpnConfig {
+            pre_down: vec!["ip link del %i".to_string()],
+            ..Default::default()
+        };
+        let error = render(
+            &config,
+            "203.0.113.7:22".parse().unwrap(),
+            &RoutePlan::default(),
+        )
+        .unwrap_err();
+        assert!(error.to_string().contains("server_tun"), "{error}");
+    }
+}
@@ -0,0 +1,168 @@
+use tracing::debug;
+use tracing::info;
+use zeroize::Zeroizing;
+
+use super::agent::exec_with_input;
+use crate::config::Elevation;
+use crate::config::VpnConfig;
+use crate::transport::Transport;
+
+/// How commands that need root (the agent, and killing it) are run on the
+/// server. Every variant is non-interactive: a tool that wants a password
+/// it was not given fails at once instead of waiting on a channel nobody
+/// answers.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RootAccess {
+    /// The SSH user is root already.
+    Root,
+    /// sudo without a password.
+    Sudo,
+    /// sudo with the password from `sudo_password_file`, written ahead of
+    /// each command's stdin (see [`RootAccess::stdin`]), so it is never
+    /// stored on the server.
+    SudoPassword(Password),
+    /// doas without a password.
+    Doas,
+}
+
+/// The server user's sudo password, kept out of logs.
+#[derive(Clone, PartialEq, Eq)]
+pub struct Password(Zeroizing<String>);
+
+impl std::fmt::Debug for Password {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Password(..)")
+    }
+}
+
+impl RootAccess {
+    /// Finds a way to get root that works for the SSH user, trying what
+    /// `elevation` allows.
+    pub async fn detect(transport: &Transport, config: &VpnConfig) -> anyhow::Result<Self> {
+        let uid = transport.exec("id -u").await?;
+        if String::from_utf8_lossy(&uid.stdout).trim() == "0" {
+            debug!("Logged in as root; running the agent directly");
+            return Ok(Self::Root);
+        }
+
+        let mut tried = Vec::new();
+        if config.elevation != Elevation::Doas
+            && let Some(access) = Self::sudo(transport, config, &mut tried).await?
+        {
+            return Ok(access);
+        }
+        if config.elevation != Elevation::Sudo {
+            let doas = transport
+                .exec("command -v doas >/dev/null || exit 127; doas -n true")
+                .await?;
+            match doas.exit_code {
+                0 => {
+                    info!("Running the agent with doas");
+                    return Ok(Self::Doas);
+                }
+                127 => tried.push("doas is not installed".to_string()),
+                _ => tried.push(format!("doas -n failed: {}", stderr(&doas.stderr))),
+            }
+        }
+
+        anyhow::bail!(
+            "the VPN agent needs root on the server, but the SSH user is not root and {}. Allow \
+             passwordless sudo (or doas) for the user, or set sudo_password_file (or _cmd, \
+             _keyring)",
+            tried.join(", and ")
+        )
+    }
+
+    async fn sudo(
+        transport: &Transport,
+        config: &VpnConfig,
+        tried: &mut Vec<String>,
+    ) -> anyhow::Result<Option<Self>> {
+        let sudo = transport.exec("sudo -n true").await?;
+        if sudo.exit_code == 0 {
+            info!("Running the agent with sudo");
+            return Ok(Some(Self::Sudo));
+        }
+        let Some(source) = config.sudo_password()? else {
+            tried.push(format!("sudo -n failed: {}", stderr(&sudo.stderr)));
+            return Ok(None);
+        };
+
+        let password = source
+            .read()
+            .map_err(|e| anyhow::anyhow!("sudo password: {}", e))?;
+        let access = Self::SudoPassword(Password(password));
+        let command = format!("{}true", access.prefix());
+        let exit_code = exec_with_input(transport, &command, &access.stdin()).await?;
+        if exit_code != 0 {
+            anyhow::bail!(
+                "sudo rejected the password from {} (exit code {})",
+                source,
+                exit_code
+            );
+        }
+        info!(
+            "Running the agent with sudo, using the password from {}",
+            source
+        );
+        Ok(Some(access))
+    }
+
+    /// What to put in front of a command to run it as root.
+    pub fn prefix(&self) -> String {
+        match self {
+            Self::Root => String::new(),
+            Self::Sudo => "sudo -n ".to_string(),
+            // -k asks for the password even if sudo cached it, so it is
+            // never left for the command to read.
+            Self::SudoPassword(_) => "sudo -k -S -p '' ".to_string(),
+            Self::Doas => "doas -n ".to_string(),
+        }
+    }
+
+    /// What a command run with [`prefix`] must get on its stdin first: the
+    /// password line sudo reads, ahead of the command's own input. The
+    /// password goes over the channel, not the command line, which would
+    /// put it in the server's process list and journal.
+    ///
+    /// [`prefix`]: RootAccess::prefix
+    pub fn stdin(&self) -> Zeroizing<Vec<u8>> {
+        match self {
+            Self::SudoPassword(password) => {
+                Zeroizing::new(format!("{}\n", *password.0).into_bytes())
+            }
+            _ => Zeroizing::new(Vec::new()),
+        }
+    }
+}
+
+fn stderr(output: &[u8]) -> String {
+    match String::from_utf8_lossy(output).trim() {
+        "" => "no error output".to_string(),
+        message => message.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_prefix() {
+        assert_eq!(RootAccess::Root.prefix(), "");
+        assert_eq!(RootAccess::Sudo.prefix(), "sudo -n ");
+        assert_eq!(RootAccess::Doas.prefix(), "doas -n ");
+        assert_eq!(
+            RootAccess::SudoPassword(Password(Zeroizing::new("secret".to_string()))).prefix(),
+            "sudo -k -S -p '' "
+        );
+    }
+
+    #[test]
+    fn test_stdin() {
+        let access = RootAccess::SudoPassword(Password(Zeroizing::new("it's $secret".to_string())));
+        assert_eq!(*access.stdin(), b"it's $secret\n");
+        assert_eq!(format!("{access:?}"), "SudoPassword(Password(..))");
+        assert!(RootAccess::Sudo.stdin().is_empty());
+    }
+}
@@ -0,0 +1,179 @@
+use proto::gso;
+use proto::gso::VNET_HDR_LEN;
+use tracing::debug;
+
+use crate::config::VpnConfig;
+use crate::metrics::Reject;
+
+const TCP: u8 = 6;
+const TCP_SYN: u8 = 0x02;
+const TCP_ACK: u8 = 0x10;
+
+/// Protocol names `blocked_protocols` accepts besides numbers.
+const PROTOCOLS: &[(&str, u8)] = &[
+    ("icmp", 1),
+    ("tcp", TCP),
+    ("udp", 17),
+    ("gre", 47),
+    ("esp", 50),
+    ("ah", 51),
+    ("icmpv6", 58),
+    ("sctp", 132),
+];
+
+/// Checks packets on their way through the tunnel. Packets from the agent
+/// must be whole IP packets before they reach the TUN device; on top of
+/// that, `blocked_protocols` are dropped both ways and, with
+/// `block_inbound`, TCP connection attempts from the tunnel.
+#[derive(Debug, Clone, Default)]
+pub struct PacketFilter {
+    /// GSO frames may arrive (`offload`).
+    offload: bool,
+    block_inbound: bool,
+    protocols: Vec<u8>,
+}
+
+impl PacketFilter {
+    pub fn new(config: &VpnConfig) -> anyhow::Result<Self> {
+        Ok(Self {
+            offload: config.offload,
+            block_inbound: config.block_inbound,
+            protocols: blocked_protocols(config)?,
+        })
+    }
+
+    /// Checks `packet` (or the packet in a GSO frame) from the agent.
+    pub fn inbound(&self, packet: &[u8]) -> Result<(), Reject> {
+        if let Err(e) = proto::packet::validate_frame(packet, self.offload) {
+            debug!("Dropping malformed packet from the agent: {}", e);
+            return Err(Reject::Malformed);
+        }
+        let packet = ip_packet(packet);
+        if self.blocks(packet) || self.block_inbound && is_tcp_syn(packet) {
+            return Err(Reject::Filtered);
+        }
+        Ok(())
+    }
+
+    /// Checks `packet` (or the packet in a GSO frame) from the TUN device.
+    pub fn outbound(&self, packet: &[u8]) -> Result<(), Reject> {
+        if self.blocks(ip_packet(packet)) {
+            return Err(Reject::Filtered);
+        }
+        Ok(())
+    }
+
+    fn blocks(&self, packet: &[u8]) -> bool {
+        !self.protocols.is_empty()
+            && proto::packet::transport(packet)
+                .is_some_and(|(protocol, _)| self.protocols.contains(&protocol))
+    }
+}
+
+/// Parses `blocked_protocols`.
+pub fn blocked_protocols(config: &VpnConfig) -> anyhow::Result<Vec<u8>> {
+    config
+        .blocked_protocols
+        .iter()
+        .map(|name| parse_protocol(name))
+        .collect()
+}
+
+fn parse_protocol(name: &str) -> anyhow::Result<u8> {
+    let lower = name.to_ascii_lowercase();
+    PROTOCOLS
+        .iter()
+        .find(|(known, _)| *known == lower)
+        .map(|(_, number)| *number)
+        .or_else(|| name.parse().ok())
+        .ok_or_else(|| {
+            anyhow::anyhow!(
+                "unknown protocol '{}' in blocked_protocols; expected a number (0-255) or one of \
+                 icmp, tcp, udp, gre, esp, ah, icmpv6, sctp",
+                name
+            )
+        })
+}
+
+fn ip_packet(packet: &[u8]) -> &[u8] {
+    gso::vnet_packet(packet).map_or(packet, |vnet| &vnet[VNET_HDR_LEN..])
+}
+
+/// Whether `packet` opens a TCP connection: SYN without ACK. Only the first
+/// fragment of an IPv4 packet carries the TCP header.
+fn is_tcp_syn(packet: &[u8]) -> bool {
+    let first_fragment =
+        packet[0] >> 4 != 4 || u16::from_be_bytes([packet[6], packet[7]]) & 0x1fff == 0;
+    match proto::packet::transport(packet) {
+        Some((TCP, tcp)) if first_fragment && tcp.len() > 13 => {
+            tcp[13] & (TCP_SYN | TCP_ACK) == TCP_SYN
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+    use crate::test_utils::icmp_echo_request;
+
+    fn tcp(flags: u8) -> Vec<u8> {
+        let mut packet = vec![0u8; 40];
+        packet[0] = 0x45;
+        packet[2..4].copy_from_slice(&40u16.to_be_bytes());
+        packet[9] = TCP;
+        packet[33] = flags;
+        packet
+    }
+
+    fn filter(block_inbound: bool, protocols: &[&str]) -> PacketFilter {
+        let config = VpnConfig {
+            block_inbound,
+            blocked_protocols: protocols.iter().map(|p| p.to_string()).collect(),
+            ..Default::default()
+        };
+        PacketFilter::new(&config).unwrap()
+    }
+
+    #[test]
+    fn test_validates_inbound_only() {
+        let filter = PacketFilter::default();
+        assert_eq!(filter.inbound(&tcp(TCP_SYN)), Ok(()));
+        assert_eq!(filter.inbound(&[0x45; 10]), Err(Reject::Malformed));
+        assert_eq!(filter.outbound(&[0x45; 10]), Ok(()));
+    }
+
+    #[test]
+    fn test_block_inbound() {
+        let filter = filter(true, &[]);
+        assert_eq!(filter.inbound(&tcp(TCP_SYN)), Err(Reject::Filtered));
+        assert_eq!(filter.inbound(&tcp(TCP_SYN | TCP_ACK)), Ok(()));
+        assert_eq!(filter.inbound(&tcp(TCP_ACK)), Ok(()));
+        assert_eq!(filter.outbound(&tcp(TCP_SYN)), Ok(()));
+        // A later fragment has no TCP header to look at.
+        let mut fragment = tcp(TCP_SYN);
+        fragment[7] = 1;
+        assert_eq!(filter.inbound(&fragment), Ok(()));
+    }
+
+    #[test]
+    fn test_blocked_protocols() {
+        let filter = filter(false, &["ICMP", "17"]);
+        let ping = icmp_echo_request(
+            Ipv4Addr::new(10, 8, 0, 2),
+            Ipv4Addr::new(1, 1, 1, 1),
+            1,
+            1,
+            b"",
+        );
+        assert_eq!(filter.inbound(&ping), Err(Reject::Filtered));
+        assert_eq!(filter.outbound(&ping), Err(Reject::Filtered));
+        assert_eq!(filter.inbound(&tcp(TCP_SYN)), Ok(()));
+
+        assert!(parse_protocol("ipip").is_err());
+        assert!(parse_protocol("256").is_err());
+        assert_eq!(parse_protocol("icmpv6").unwrap(), 58);
+    }
+}
@@ -1,19 +1,99 @@
+use std::net::IpAddr;
+use std::process::Output;
+
 use tracing::debug;
 use tracing::error;
 use tracing::info;
 
 use crate::config::VpnConfig;
+use crate::shutdown::Shutdown;
 use crate::transport::Transport;
 
-pub async fn run_post_up(transport: &Transport, config: &VpnConfig) -> anyhow::Result<()> {
+/// Values for the placeholders in hook commands, expanded before they run
+/// as wg-quick expands `%i`: `%i` (the TUN name on the side the command
+/// runs on), `%client_ip`, `%server_ip` and `%ssh_host` (the SSH server's
+/// address). `%%` is a literal `%`; any other `%` is left alone.
+#[derive(Debug, Clone)]
+pub struct HookVars {
+    /// `None` for server hooks when `server_tun` is unset, as the kernel
+    /// picks the name then.
+    interface: Option<String>,
+    client_ip: IpAddr,
+    server_ip: IpAddr,
+    ssh_host: IpAddr,
+}
+
+impl HookVars {
+    /// For `post_up`/`pre_down`, run on the server.
+    pub fn remote(config: &VpnConfig, ssh_host: IpAddr) -> anyhow::Result<Self> {
+        Self::new(config, config.server_tun.clone(), ssh_host)
+    }
+
+    /// For `local_post_up`/`local_pre_down`, run on the client.
+    pub fn local(config: &VpnConfig, ssh_host: IpAddr) -> anyhow::Result<Self> {
+        Self::new(config, Some(config.client_tun.clone()), ssh_host)
+    }
+
+    fn new(
+        config: &VpnConfig,
+        interface: Option<String>,
+        ssh_host: IpAddr,
+    ) -> anyhow::Result<Self> {
+        Ok(Self {
+            interface,
+            client_ip: config.client_ip()?,
+            server_ip: config.server_ip()?,
+            ssh_host,
+        })
+    }
+
+    pub fn expand(&self, cmd: &str) -> anyhow::Result<String> {
+        let placeholders = [
+            ("client_ip", Some(self.client_ip.to_string())),
+            ("server_ip", Some(self.server_ip.to_string())),
+            ("ssh_host", Some(self.ssh_host.to_string())),
+            ("i", self.interface.clone()),
+            ("%", Some("%".to_string())),
+        ];
+        let mut expanded = String::with_capacity(cmd.len());
+        let mut rest = cmd;
+        while let Some(at) = rest.find('%') {
+            expanded.push_str(&rest[..at]);
+            rest = &rest[at + 1..];
+            match placeholders.iter().find(|(name, _)| rest.starts_with(name)) {
+                Some((name, value)) => {
+                    let value = value.as_deref().ok_or_else(|| {
+                        anyhow::anyhow!(
+                            "'{cmd}' uses %i, but the server TUN name is picked by the kernel; \
+                             set server_tun"
+                        )
+                    })?;
+                    expanded.push_str(value);
+                    rest = &rest[name.len()..];
+                }
+                None => expanded.push('%'),
+            }
+        }
+        expanded.push_str(rest);
+        Ok(expanded)
+    }
+}
+
+pub async fn run_post_up(
+    transport: &Transport,
+    config: &VpnConfig,
+    ssh_host: IpAddr,
+) -> anyhow::Result<()> {
     if config.post_up.is_empty() {
         debug!("No PostUp commands to execute");
         return Ok(());
     }
 
     info!("Running {} PostUp command(s)", config.post_up.len());
+    let vars = HookVars::remote(config, ssh_host)?;
 
     for (i, cmd) in config.post_up.iter().enumerate() {
+        let cmd = &vars.expand(cmd)?;
         info!("PostUp [{}/{}]: {}", i + 1, config.post_up.len(), cmd);
 
         if let Err(e) = transport.exec_success(cmd).await {
@@ -26,18 +106,43 @@ pub async fn run_post_up(transport: &Transport, config: &VpnConfig) -> anyhow::R
     Ok(())
 }
 
-pub async fn run_pre_down(transport: &Transport, config: &VpnConfig) {
+/// Runs PreDown commands as part of `shutdown`, abandoning any whose
+/// channel is still open after the shutdown timeout.
+pub async fn run_pre_down(
+    transport: &Transport,
+    config: &VpnConfig,
+    ssh_host: IpAddr,
+    shutdown: &mut Shutdown,
+) {
     if config.pre_down.is_empty() {
         debug!("No PreDown commands to execute");
         return;
     }
 
     info!("Running {} PreDown command(s)", config.pre_down.len());
+    let vars = match HookVars::remote(config, ssh_host) {
+        Ok(vars) => vars,
+        Err(e) => {
+            error!("Skipping PreDown commands: {:#}", e);
+            return;
+        }
+    };
 
     for (i, cmd) in config.pre_down.iter().enumerate() {
+        let cmd = &match vars.expand(cmd) {
+            Ok(cmd) => cmd,
+            Err(e) => {
+                error!("PreDown command skipped: {:#}", e);
+                continue;
+            }
+        };
         info!("PreDown [{}/{}]: {}", i + 1, config.pre_down.len(), cmd);
 
-        match transport.exec(cmd).await {
+        let Some(result) = shutdown.close("PreDown channel", transport.exec(cmd)).await else {
+            error!("PreDown command timed out: {}", cmd);
+            continue;
+        };
+        match result {
             Ok(result) if result.exit_code == 0 => {
                 debug!("PreDown command succeeded: {}", cmd);
             }
@@ -60,3 +165,197 @@ pub async fn run_pre_down(transport: &Transport, config: &VpnConfig) {
 
     info!("PreDown commands completed");
 }
+
+/// Runs the local PostUp commands on the client once the tunnel is up,
+/// stopping at the first that fails.
+pub async fn run_local_post_up(config: &VpnConfig, ssh_host: IpAddr) -> anyhow::Result<()> {
+    if config.local_post_up.is_empty() {
+        debug!("No local PostUp commands to execute");
+        return Ok(());
+    }
+
+    info!(
+        "Running {} local PostUp command(s)",
+        config.local_post_up.len()
+    );
+    let vars = HookVars::local(config, ssh_host)?;
+
+    for (i, cmd) in config.local_post_up.iter().enumerate() {
+        let cmd = &vars.expand(cmd)?;
+        info!(
+            "Local PostUp [{}/{}]: {}",
+            i + 1,
+            config.local_post_up.len(),
+            cmd
+        );
+
+        let output = exec_local(cmd).await?;
+        if !output.status.success() {
+            error!("Local PostUp command failed: {}", cmd);
+            anyhow::bail!(
+                "local command '{}' exited with {}: {}",
+                cmd,
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+        }
+    }
+
+    info!("All local PostUp commands completed successfully");
+    Ok(())
+}
+
+/// Runs the local PreDown commands on the client as part of `shutdown`,
+/// each to completion or until the shutdown timeout kills it.
+pub async fn run_local_pre_down(config: &VpnConfig, ssh_host: IpAddr, shutdown: &mut Shutdown) {
+    if config.local_pre_down.is_empty() {
+        debug!("No local PreDown commands to execute");
+        return;
+    }
+
+    info!(
+        "Running {} local PreDown command(s)",
+        config.local_pre_down.len()
+    );
+    let vars = match HookVars::local(config, ssh_host) {
+        Ok(vars) => vars,
+        Err(e) => {
+            error!("Skipping local PreDown commands: {:#}", e);
+            return;
+        }
+    };
+
+    for (i, cmd) in config.local_pre_down.iter().enumerate() {
+        let cmd = &match vars.expand(cmd) {
+            Ok(cmd) => cmd,
+            Err(e) => {
+                error!("Local PreDown command skipped: {:#}", e);
+                continue;
+            }
+        };
+        info!(
+            "Local PreDown [{}/{}]: {}",
+            i + 1,
+            config.local_pre_down.len(),
+            cmd
+        );
+
+        let Some(result) = shutdown
+            .close("Local PreDown command", exec_local(cmd))
+            .await
+        else {
+            error!("Local PreDown command timed out: {}", cmd);
+            continue;
+        };
+        match result {
+            Ok(output) if output.status.success() => {
+                debug!("Local PreDown command succeeded: {}", cmd);
+            }
+            Ok(output) => {
+                error!(
+                    "Local PreDown command failed ({}): {} - stdout={}, stderr={}",
+                    output.status,
+                    cmd,
+                    String::from_utf8_lossy(&output.stdout).trim(),
+                    String::from_utf8_lossy(&output.stderr).trim()
+                );
+            }
+            Err(e) => {
+                error!("Local PreDown command error: {} - {}", cmd, e);
+            }
+        }
+    }
+
+    info!("Local PreDown commands completed");
+}
+
+/// Runs `cmd` through the client's shell. The process is killed if the
+/// future is dropped, e.g. on a shutdown timeout.
+async fn exec_local(cmd: &str) -> anyhow::Result<Output> {
+    #[cfg(unix)]
+    let mut command = tokio::process::Command::new("sh");
+    #[cfg(unix)]
+    command.args(["-c", cmd]);
+    #[cfg(windows)]
+    let mut command = tokio::process::Command::new("cmd");
+    #[cfg(windows)]
+    command.args(["/C", cmd]);
+    Ok(command.kill_on_drop(true).output().await?)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    const SSH_HOST: IpAddr = IpAddr::V4(std::net::Ipv4Addr::new(203, 0, 113, 7));
+
+    #[test]
+    fn test_expand_placeholders() {
+        let mut config = VpnConfig::default();
+        let local = HookVars::local(&config, SSH_HOST).unwrap();
+        assert_eq!(
+            local
+                .expand("nft add rule inet fw input iifname %i ip saddr %server_ip accept")
+                .unwrap(),
+            "nft add rule inet fw input iifname tun-x2ssh ip saddr 10.8.0.1 accept"
+        );
+        assert_eq!(
+            local
+                .expand("echo %client_ip via %ssh_host, 100%% at %s %")
+                .unwrap(),
+            "echo 10.8.0.2 via 203.0.113.7, 100% at %s %"
+        );
+
+        let remote = HookVars::remote(&config, SSH_HOST).unwrap();
+        assert_eq!(remote.expand("echo %server_ip").unwrap(), "echo 10.8.0.1");
+        let error = remote.expand("iptables -A FORWARD -i %i").unwrap_err();
+        assert!(error.to_string().contains("server_tun"), "{error}");
+
+        config.server_tun = Some("tun-srv".to_string());
+        let remote = HookVars::remote(&config, SSH_HOST).unwrap();
+        assert_eq!(
+            remote.expand("iptables -A FORWARD -i %i").unwrap(),
+            "iptables -A FORWARD -i tun-srv"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_local_post_up_stops_at_failure() {
+        let dir = tempfile::tempdir().unwrap();
+        let marker = dir.path().join("ran");
+        let config = VpnConfig {
+            local_post_up: vec![
+                "echo oops >&2; exit 3".to_string(),
+                format!("touch {}", marker.display()),
+            ],
+            ..Default::default()
+        };
+
+        let error = run_local_post_up(&config, SSH_HOST)
+            .await
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("oops"), "{error}");
+        assert!(!marker.exists());
+    }
+
+    #[tokio::test]
+    async fn test_local_pre_down_runs_every_command() {
+        let dir = tempfile::tempdir().unwrap();
+        let marker = dir.path().join("ran");
+        let config = VpnConfig {
+            local_pre_down: vec![
+                "sleep 5".to_string(),
+                "exit 1".to_string(),
+                format!("touch {}", marker.display()),
+            ],
+            ..Default::default()
+        };
+
+        let mut shutdown = Shutdown::new(Duration::from_millis(200));
+        run_local_pre_down(&config, SSH_HOST, &mut shutdown).await;
+        assert!(marker.exists());
+    }
+}
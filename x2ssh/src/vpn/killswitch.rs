@@ -0,0 +1,324 @@
+use std::net::IpAddr;
+
+use ipnet::IpNet;
+use tracing::error;
+use tracing::info;
+
+use crate::config::VpnConfig;
+
+const TABLE: &str = "x2ssh_killswitch";
+
+/// Firewall rules that drop all outgoing traffic except through the TUN
+/// device, to the SSH server, and to excluded networks, so nothing leaks
+/// while the tunnel is down or reconnecting. DNS (port 53) is dropped
+/// off-tunnel even towards excluded networks.
+///
+/// Rules live in a dedicated nftables table, removed on [`disable`] or
+/// drop. The SSH server's addresses are kept in sets of that table, which
+/// [`allow_ssh_servers`] replaces when a reconnect resolves the host anew.
+///
+/// [`disable`]: KillSwitch::disable
+/// [`allow_ssh_servers`]: KillSwitch::allow_ssh_servers
+pub struct KillSwitch {
+    enabled: bool,
+}
+
+impl KillSwitch {
+    /// `ssh_servers` are the addresses the SSH server resolves to, let
+    /// through on `ssh_port`. `lan` are the subnets found for
+    /// `exclude_lan`, let through like the configured exclusions and
+    /// `via lan` routes.
+    #[cfg(target_os = "linux")]
+    pub async fn enable(
+        config: &VpnConfig,
+        ssh_servers: &[IpAddr],
+        ssh_port: u16,
+        lan: &[IpNet],
+    ) -> anyhow::Result<Self> {
+        let rules = Self::rules(config, ssh_servers, ssh_port, lan)?;
+
+        info!("Enabling kill switch (nftables table inet {})", TABLE);
+        run_nft(&rules).await?;
+        Ok(Self { enabled: true })
+    }
+
+    /// The nftables script [`enable`] loads.
+    ///
+    /// [`enable`]: KillSwitch::enable
+    pub fn rules(
+        config: &VpnConfig,
+        ssh_servers: &[IpAddr],
+        ssh_port: u16,
+        lan: &[IpNet],
+    ) -> anyhow::Result<String> {
+        let mut exclude = config
+            .exclude
+            .iter()
+            .map(|net| net.parse())
+            .collect::<Result<Vec<IpNet>, _>>()?;
+        exclude.extend(
+            config
+                .static_routes()?
+                .into_iter()
+                .filter(|route| route.via == crate::config::RouteVia::Lan)
+                .map(|route| route.destination),
+        );
+        exclude.extend_from_slice(lan);
+        Ok(ruleset(&config.client_tun, ssh_servers, ssh_port, &exclude))
+    }
+
+    #[cfg(target_os = "windows")]
+    pub async fn enable(
+        _config: &VpnConfig,
+        _ssh_servers: &[IpAddr],
+        _ssh_port: u16,
+        _lan: &[IpNet],
+    ) -> anyhow::Result<Self> {
+        anyhow::bail!("the kill switch is not supported on Windows yet")
+    }
+
+    /// Lets through exactly `ips` as the SSH server's addresses, e.g. those
+    /// a reconnect resolved, replacing the previous ones in one transaction.
+    #[cfg(target_os = "linux")]
+    pub async fn allow_ssh_servers(&self, ips: &[IpAddr]) -> anyhow::Result<()> {
+        if !self.enabled {
+            return Ok(());
+        }
+        info!(
+            "Allowing SSH server addresses {:?} through the kill switch",
+            ips
+        );
+        run_nft(&servers_update(ips)).await
+    }
+
+    #[cfg(target_os = "windows")]
+    pub async fn allow_ssh_servers(&self, _ips: &[IpAddr]) -> anyhow::Result<()> {
+        anyhow::bail!("the kill switch is not supported on Windows yet")
+    }
+
+    #[cfg(target_os = "linux")]
+    pub async fn disable(&mut self) -> anyhow::Result<()> {
+        if !self.enabled {
+            return Ok(());
+        }
+        info!("Disabling kill switch");
+        run_nft(&format!("delete table inet {TABLE}\n")).await?;
+        self.enabled = false;
+        Ok(())
+    }
+
+    #[cfg(target_os = "windows")]
+    pub async fn disable(&mut self) -> anyhow::Result<()> {
+        anyhow::bail!("the kill switch is not supported on Windows yet")
+    }
+}
+
+impl Drop for KillSwitch {
+    fn drop(&mut self) {
+        #[cfg(target_os = "linux")]
+        if self.enabled {
+            // Blocking, but runs at most once and must not be skipped.
+            let status = std::process::Command::new("nft")
+                .args(["delete", "table", "inet", TABLE])
+                .status();
+            if !matches!(status, Ok(status) if status.success()) {
+                error!("Failed to remove kill switch table inet {}", TABLE);
+            }
+        }
+    }
+}
+
+/// Loads an nftables script, failing with nft's stderr if it does.
+#[cfg(target_os = "linux")]
+pub(crate) async fn run_nft(rules: &str) -> anyhow::Result<()> {
+    use std::process::Stdio;
+
+    use tokio::io::AsyncWriteExt;
+
+    let mut child = tokio::process::Command::new("nft")
+        .args(["-f", "-"])
+        .stdin(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| anyhow::anyhow!("failed to run nft (is nftables installed?): {}", e))?;
+
+    let mut stdin = child.stdin.take().expect("stdin is piped");
+    stdin.write_all(rules.as_bytes()).await?;
+    drop(stdin);
+
+    let output = child.wait_with_output().await?;
+    if !output.status.success() {
+        anyhow::bail!(
+            "nft failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(())
+}
+
+/// Builds the nftables script. Recreating the table in one transaction makes
+/// it atomic and replaces leftovers from a previous crashed run.
+fn ruleset(tun: &str, ssh_servers: &[IpAddr], ssh_port: u16, exclude: &[IpNet]) -> String {
+    let (v4, v6) = split_families(ssh_servers);
+    let mut rules = vec![
+        format!("table inet {TABLE}"),
+        format!("delete table inet {TABLE}"),
+        format!("table inet {TABLE} {{"),
+        server_set(SERVERS_V4, "ipv4_addr", &v4),
+        server_set(SERVERS_V6, "ipv6_addr", &v6),
+        "    chain output {".to_string(),
+        "        type filter hook output priority 0; policy drop;".to_string(),
+        "        oifname \"lo\" accept".to_string(),
+        format!("        oifname \"{tun}\" accept"),
+        format!("        ip daddr @{SERVERS_V4} tcp dport {ssh_port} accept"),
+        format!("        ip6 daddr @{SERVERS_V6} tcp dport {ssh_port} accept"),
+        // DHCP and neighbor discovery keep the physical link usable.
+        "        udp sport 68 udp dport 67 accept".to_string(),
+        "        icmpv6 type { nd-router-solicit, nd-neighbor-solicit, nd-neighbor-advert } accept"
+            .to_string(),
+        "        udp dport 53 drop".to_string(),
+        "        tcp dport 53 drop".to_string(),
+    ];
+    for net in exclude {
+        rules.push(format!(
+            "        {} daddr {} accept",
+            family(net.addr()),
+            net
+        ));
+    }
+    rules.push("    }".to_string());
+    rules.push("}".to_string());
+
+    let mut script = rules.join("\n");
+    script.push('\n');
+    script
+}
+
+/// Names of the sets holding the SSH server's addresses, one per family as
+/// an nftables set has a single element type.
+const SERVERS_V4: &str = "ssh_servers4";
+const SERVERS_V6: &str = "ssh_servers6";
+
+fn server_set(name: &str, kind: &str, ips: &[String]) -> String {
+    if ips.is_empty() {
+        format!("    set {name} {{ type {kind}; }}")
+    } else {
+        format!(
+            "    set {name} {{ type {kind}; elements = {{ {} }}; }}",
+            ips.join(", ")
+        )
+    }
+}
+
+/// Builds the script replacing the SSH server sets' contents with `ips`,
+/// which nft applies as one transaction.
+fn servers_update(ips: &[IpAddr]) -> String {
+    let (v4, v6) = split_families(ips);
+    let mut script = String::new();
+    for (name, ips) in [(SERVERS_V4, v4), (SERVERS_V6, v6)] {
+        script.push_str(&format!("flush set inet {TABLE} {name}\n"));
+        if !ips.is_empty() {
+            script.push_str(&format!(
+                "add element inet {TABLE} {name} {{ {} }}\n",
+                ips.join(", ")
+            ));
+        }
+    }
+    script
+}
+
+/// `ips` deduplicated and split into IPv4 and IPv6 set elements.
+fn split_families(ips: &[IpAddr]) -> (Vec<String>, Vec<String>) {
+    let mut v4 = Vec::new();
+    let mut v6 = Vec::new();
+    for ip in ips {
+        let list = if ip.is_ipv4() { &mut v4 } else { &mut v6 };
+        let ip = ip.to_string();
+        if !list.contains(&ip) {
+            list.push(ip);
+        }
+    }
+    (v4, v6)
+}
+
+fn family(ip: IpAddr) -> &'static str {
+    match ip {
+        IpAddr::V4(_) => "ip",
+        IpAddr::V6(_) => "ip6",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ruleset() {
+        let servers = ["203.0.113.10".parse().unwrap()];
+        let rules = ruleset("tun-x2ssh", &servers, 2222, &[
+            "192.168.0.0/16".parse().unwrap(),
+            "fd00::/8".parse().unwrap(),
+        ]);
+
+        assert!(
+            rules.starts_with("table inet x2ssh_killswitch\ndelete table inet x2ssh_killswitch\n")
+        );
+        assert!(rules.contains("policy drop;"));
+        assert!(rules.contains("oifname \"tun-x2ssh\" accept"));
+        assert!(
+            rules.contains("set ssh_servers4 { type ipv4_addr; elements = { 203.0.113.10 }; }")
+        );
+        assert!(rules.contains("set ssh_servers6 { type ipv6_addr; }"));
+        assert!(rules.contains("ip daddr @ssh_servers4 tcp dport 2222 accept"));
+        assert!(rules.contains("ip6 daddr @ssh_servers6 tcp dport 2222 accept"));
+        assert!(rules.contains("ip daddr 192.168.0.0/16 accept"));
+        assert!(rules.contains("ip6 daddr fd00::/8 accept"));
+    }
+
+    #[test]
+    fn test_ruleset_allows_every_resolved_address() {
+        let servers: Vec<IpAddr> = [
+            "203.0.113.10",
+            "2001:db8::1",
+            "198.51.100.4",
+            "203.0.113.10",
+        ]
+        .iter()
+        .map(|ip| ip.parse().unwrap())
+        .collect();
+        let rules = ruleset("tun-x2ssh", &servers, 22, &[]);
+
+        assert!(rules.contains(
+            "set ssh_servers4 { type ipv4_addr; elements = { 203.0.113.10, 198.51.100.4 }; }"
+        ));
+        assert!(rules.contains("set ssh_servers6 { type ipv6_addr; elements = { 2001:db8::1 }; }"));
+    }
+
+    #[test]
+    fn test_servers_update() {
+        let servers: Vec<IpAddr> = ["198.51.100.4", "198.51.100.5"]
+            .iter()
+            .map(|ip| ip.parse().unwrap())
+            .collect();
+
+        assert_eq!(
+            servers_update(&servers),
+            "flush set inet x2ssh_killswitch ssh_servers4\nadd element inet x2ssh_killswitch \
+             ssh_servers4 { 198.51.100.4, 198.51.100.5 }\nflush set inet x2ssh_killswitch \
+             ssh_servers6\n"
+        );
+    }
+
+    #[test]
+    fn test_ruleset_blocks_dns_before_exclusions() {
+        let servers = ["2001:db8::1".parse().unwrap()];
+        let rules = ruleset("tun-x2ssh", &servers, 22, &["192.168.0.0/16"
+            .parse()
+            .unwrap()]);
+
+        assert!(rules.contains("elements = { 2001:db8::1 }"));
+        let dns = rules.find("udp dport 53 drop").unwrap();
+        let exclusion = rules.find("192.168.0.0/16").unwrap();
+        assert!(dns < exclusion);
+    }
+}
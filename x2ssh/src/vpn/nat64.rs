@@ -0,0 +1,387 @@
+use std::borrow::Cow;
+use std::net::Ipv4Addr;
+use std::net::Ipv6Addr;
+
+use ipnet::IpNet;
+use ipnet::Ipv6Net;
+
+use crate::config::VpnConfig;
+
+const ICMP: u8 = 1;
+const TCP: u8 = 6;
+const UDP: u8 = 17;
+const ICMPV6: u8 = 58;
+
+/// Embeds `v4` in the last 32 bits of a /96 NAT64 prefix.
+pub fn synthesize(prefix: Ipv6Net, v4: Ipv4Addr) -> Ipv6Addr {
+    let mut octets = prefix.network().octets();
+    octets[12..].copy_from_slice(&v4.octets());
+    Ipv6Addr::from(octets)
+}
+
+/// The IPv4 address embedded in `v6`, if it lies in the NAT64 prefix.
+pub fn extract(prefix: Ipv6Net, v6: Ipv6Addr) -> Option<Ipv4Addr> {
+    if !prefix.contains(&v6) {
+        return None;
+    }
+    let octets: [u8; 4] = v6.octets()[12..].try_into().ok()?;
+    Some(Ipv4Addr::from(octets))
+}
+
+/// Stateless NAT64 for one client, done in the forwarder so the server only
+/// ever sees IPv4: IPv6 packets to the prefix leave with `address` as their
+/// IPv4 source, and IPv4 packets to `address` come back as IPv6 from the
+/// prefix to `client6`. Carries TCP, UDP and ICMP echo; anything else
+/// addressed to the prefix is dropped.
+#[derive(Debug, Clone)]
+pub struct Nat64 {
+    prefix: Ipv6Net,
+    address: Ipv4Addr,
+    client6: Ipv6Addr,
+}
+
+impl Nat64 {
+    pub fn new(prefix: Ipv6Net, address: Ipv4Addr, client6: Ipv6Addr) -> anyhow::Result<Self> {
+        if prefix.prefix_len() != 96 {
+            anyhow::bail!("NAT64 prefix {} must be a /96", prefix);
+        }
+        Ok(Self {
+            prefix,
+            address,
+            client6,
+        })
+    }
+
+    /// Sets up translation for `config`, which needs a dual-stack tunnel.
+    /// Translated traffic uses the tunnel address after the client's, which
+    /// the server routes back through the tunnel like the client's own.
+    pub fn from_config(config: &VpnConfig) -> anyhow::Result<Self> {
+        let prefix: Ipv6Net = config.nat64_prefix.parse().map_err(|e| {
+            anyhow::anyhow!("invalid nat64_prefix '{}': {}", config.nat64_prefix, e)
+        })?;
+        let Some((IpNet::V6(client6), _)) = config.ipv6_addresses()? else {
+            anyhow::bail!("dns64 needs an IPv6 tunnel; set client_address6 and server_address6");
+        };
+        let IpNet::V4(client) = config.network()? else {
+            anyhow::bail!("client_address must be IPv4");
+        };
+
+        let address = u32::from(client.addr())
+            .checked_add(1)
+            .map(Ipv4Addr::from)
+            .filter(|address| {
+                client.contains(address)
+                    && *address != client.broadcast()
+                    && config.server_ip().ok() != Some((*address).into())
+            })
+            .ok_or_else(|| {
+                anyhow::anyhow!(
+                    "no free address after client_address {} for NAT64 in the tunnel subnet",
+                    client
+                )
+            })?;
+        Self::new(prefix, address, client6.addr())
+    }
+
+    pub fn prefix(&self) -> Ipv6Net {
+        self.prefix
+    }
+
+    /// Translates a packet from the TUN device on its way to the agent.
+    /// Returns `None` when it must be dropped.
+    pub fn outbound<'a>(&self, packet: &'a [u8]) -> Option<Cow<'a, [u8]>> {
+        if packet.first()? >> 4 != 6 || packet.len() < 40 {
+            return Some(Cow::Borrowed(packet));
+        }
+        let dst = Ipv6Addr::from(<[u8; 16]>::try_from(&packet[24..40]).ok()?);
+        let Some(dst) = extract(self.prefix, dst) else {
+            return Some(Cow::Borrowed(packet));
+        };
+        self.to_ipv4(packet, dst).map(Cow::Owned)
+    }
+
+    /// Translates a packet from the agent on its way to the TUN device.
+    /// Returns `None` when it must be dropped.
+    pub fn inbound<'a>(&self, packet: &'a [u8]) -> Option<Cow<'a, [u8]>> {
+        if packet.first()? >> 4 != 4 || packet.len() < 20 || packet[16..20] != self.address.octets()
+        {
+            return Some(Cow::Borrowed(packet));
+        }
+        self.to_ipv6(packet).map(Cow::Owned)
+    }
+
+    fn to_ipv4(&self, packet: &[u8], dst: Ipv4Addr) -> Option<Vec<u8>> {
+        let payload_len = usize::from(u16::from_be_bytes([packet[4], packet[5]]));
+        let payload = packet.get(40..40 + payload_len)?;
+        let protocol = match packet[6] {
+            ICMPV6 => ICMP,
+            // Extension headers would need walking; real traffic to NAT64
+            // destinations does not carry them.
+            protocol @ (TCP | UDP) => protocol,
+            _ => return None,
+        };
+
+        let total_len = u16::try_from(20 + payload.len()).ok()?;
+        let mut out = vec![0u8; 20];
+        out[0] = 0x45;
+        out[1] = (packet[0] << 4) | (packet[1] >> 4);
+        out[2..4].copy_from_slice(&total_len.to_be_bytes());
+        out[6] = 0x40; // Don't fragment
+        out[8] = packet[7];
+        out[9] = protocol;
+        out[12..16].copy_from_slice(&self.address.octets());
+        out[16..20].copy_from_slice(&dst.octets());
+        let header_checksum = checksum(&[&out]);
+        out[10..12].copy_from_slice(&header_checksum.to_be_bytes());
+
+        let mut transport = payload.to_vec();
+        if protocol == ICMP {
+            transport[0] = match *transport.first()? {
+                128 => 8, // Echo request
+                129 => 0, // Echo reply
+                _ => return None,
+            };
+            set_checksum(&mut transport, 2, &[])?;
+        } else {
+            let pseudo = pseudo_header_v4(self.address, dst, protocol, transport.len());
+            set_checksum(&mut transport, checksum_offset(protocol), &pseudo)?;
+        }
+        out.extend_from_slice(&transport);
+        Some(out)
+    }
+
+    fn to_ipv6(&self, packet: &[u8]) -> Option<Vec<u8>> {
+        let header_len = usize::from(packet[0] & 0x0f) * 4;
+        let total_len = usize::from(u16::from_be_bytes([packet[2], packet[3]]));
+        let fragment = u16::from_be_bytes([packet[6], packet[7]]);
+        if fragment & 0x3fff != 0 {
+            return None;
+        }
+        let payload = packet.get(header_len..total_len)?;
+        let next_header = match packet[9] {
+            ICMP => ICMPV6,
+            protocol @ (TCP | UDP) => protocol,
+            _ => return None,
+        };
+        let src = synthesize(
+            self.prefix,
+            Ipv4Addr::from(<[u8; 4]>::try_from(&packet[12..16]).ok()?),
+        );
+
+        let mut out = vec![0u8; 40];
+        out[0] = 0x60 | (packet[1] >> 4);
+        out[1] = packet[1] << 4;
+        out[4..6].copy_from_slice(&u16::try_from(payload.len()).ok()?.to_be_bytes());
+        out[6] = next_header;
+        out[7] = packet[8];
+        out[8..24].copy_from_slice(&src.octets());
+        out[24..40].copy_from_slice(&self.client6.octets());
+
+        let mut transport = payload.to_vec();
+        if next_header == ICMPV6 {
+            transport[0] = match *transport.first()? {
+                8 => 128,
+                0 => 129,
+                _ => return None,
+            };
+        }
+        let pseudo = pseudo_header_v6(src, self.client6, next_header, transport.len());
+        let offset = match next_header {
+            ICMPV6 => 2,
+            protocol => checksum_offset(protocol),
+        };
+        set_checksum(&mut transport, offset, &pseudo)?;
+        out.extend_from_slice(&transport);
+        Some(out)
+    }
+}
+
+fn checksum_offset(protocol: u8) -> usize {
+    if protocol == TCP { 16 } else { 6 }
+}
+
+fn pseudo_header_v4(src: Ipv4Addr, dst: Ipv4Addr, protocol: u8, len: usize) -> Vec<u8> {
+    let mut pseudo = Vec::with_capacity(12);
+    pseudo.extend_from_slice(&src.octets());
+    pseudo.extend_from_slice(&dst.octets());
+    pseudo.extend_from_slice(&[0, protocol]);
+    pseudo.extend_from_slice(&(len as u16).to_be_bytes());
+    pseudo
+}
+
+fn pseudo_header_v6(src: Ipv6Addr, dst: Ipv6Addr, next_header: u8, len: usize) -> Vec<u8> {
+    let mut pseudo = Vec::with_capacity(40);
+    pseudo.extend_from_slice(&src.octets());
+    pseudo.extend_from_slice(&dst.octets());
+    pseudo.extend_from_slice(&(len as u32).to_be_bytes());
+    pseudo.extend_from_slice(&[0, 0, 0, next_header]);
+    pseudo
+}
+
+/// Recomputes the checksum stored at `offset` of `segment`.
+fn set_checksum(segment: &mut [u8], offset: usize, pseudo: &[u8]) -> Option<()> {
+    segment.get_mut(offset..offset + 2)?.fill(0);
+    let mut sum = checksum(&[pseudo, segment]);
+    // A computed UDP checksum of zero is sent as all ones; zero means none.
+    if sum == 0 && offset == 6 {
+        sum = 0xffff;
+    }
+    segment[offset..offset + 2].copy_from_slice(&sum.to_be_bytes());
+    Some(())
+}
+
+/// RFC 1071 checksum over the concatenation of `parts`, each of which
+/// except the last must have even length.
+fn checksum(parts: &[&[u8]]) -> u16 {
+    let mut sum: u32 = 0;
+    for part in parts {
+        for chunk in part.chunks(2) {
+            sum += match chunk {
+                [hi, lo] => u32::from(u16::from_be_bytes([*hi, *lo])),
+                [hi] => u32::from(*hi) << 8,
+                _ => unreachable!(),
+            };
+        }
+    }
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::icmp_echo_request;
+    use crate::test_utils::internet_checksum;
+
+    fn nat64() -> Nat64 {
+        Nat64::new(
+            "64:ff9b::/96".parse().unwrap(),
+            Ipv4Addr::new(10, 8, 0, 3),
+            "fd00:8::2".parse().unwrap(),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_from_config() {
+        let config = VpnConfig {
+            client_address6: Some("fd00:8::2/64".to_string()),
+            server_address6: Some("fd00:8::1/64".to_string()),
+            dns64: true,
+            ..Default::default()
+        };
+        let nat64 = Nat64::from_config(&config).unwrap();
+        assert_eq!(nat64.address, Ipv4Addr::new(10, 8, 0, 3));
+        assert_eq!(nat64.client6, "fd00:8::2".parse::<Ipv6Addr>().unwrap());
+
+        let ipv4_only = VpnConfig {
+            dns64: true,
+            ..Default::default()
+        };
+        assert!(Nat64::from_config(&ipv4_only).is_err());
+
+        let last_host = VpnConfig {
+            client_address: "10.8.0.254/24".to_string(),
+            ..config
+        };
+        assert!(Nat64::from_config(&last_host).is_err());
+    }
+
+    #[test]
+    fn test_synthesize_and_extract() {
+        let prefix: Ipv6Net = "64:ff9b::/96".parse().unwrap();
+        let v6 = synthesize(prefix, Ipv4Addr::new(192, 0, 2, 33));
+        assert_eq!(v6, "64:ff9b::c000:221".parse::<Ipv6Addr>().unwrap());
+        assert_eq!(extract(prefix, v6), Some(Ipv4Addr::new(192, 0, 2, 33)));
+        assert_eq!(extract(prefix, "2001:db8::1".parse().unwrap()), None);
+        assert!(Nat64::new("64:ff9b::/64".parse().unwrap(), Ipv4Addr::LOCALHOST, v6).is_err());
+    }
+
+    #[test]
+    fn test_echo_round_trip() {
+        let nat64 = nat64();
+        let remote = Ipv4Addr::new(192, 0, 2, 33);
+
+        // The remote's echo reply, as it arrives from the agent.
+        let mut reply = icmp_echo_request(remote, Ipv4Addr::new(10, 8, 0, 3), 7, 1, b"hi");
+        reply[20] = 0;
+        reply[22..24].fill(0);
+        let icmp_checksum = internet_checksum(&reply[20..]);
+        reply[22..24].copy_from_slice(&icmp_checksum.to_be_bytes());
+
+        let v6 = nat64.inbound(&reply).unwrap().into_owned();
+        assert_eq!(v6[0] >> 4, 6);
+        assert_eq!(v6[6], ICMPV6);
+        assert_eq!(v6[40], 129);
+        assert_eq!(&v6[8..24], &synthesize(nat64.prefix(), remote).octets());
+        assert_eq!(&v6[24..40], &nat64.client6.octets());
+        let pseudo = pseudo_header_v6(
+            synthesize(nat64.prefix(), remote),
+            nat64.client6,
+            ICMPV6,
+            v6.len() - 40,
+        );
+        assert_eq!(checksum(&[&pseudo, &v6[40..]]), 0);
+
+        // Sending the same bytes back out turns them into IPv4 again.
+        let mut request = v6.clone();
+        request[8..24].copy_from_slice(&nat64.client6.octets());
+        request[24..40].copy_from_slice(&synthesize(nat64.prefix(), remote).octets());
+        request[40] = 128;
+        let v4 = nat64.outbound(&request).unwrap().into_owned();
+        assert_eq!(v4[0], 0x45);
+        assert_eq!(v4[9], ICMP);
+        assert_eq!(v4[20], 8);
+        assert_eq!(&v4[12..16], &[10, 8, 0, 3]);
+        assert_eq!(&v4[16..20], &remote.octets());
+        assert_eq!(internet_checksum(&v4[..20]), 0);
+        assert_eq!(internet_checksum(&v4[20..]), 0);
+        assert_eq!(&v4[28..], b"hi");
+    }
+
+    #[test]
+    fn test_udp_checksum_uses_new_pseudo_header() {
+        let nat64 = nat64();
+        let remote = Ipv4Addr::new(198, 51, 100, 7);
+        let mut packet = vec![0u8; 40 + 8 + 4];
+        packet[0] = 0x60;
+        packet[4..6].copy_from_slice(&12u16.to_be_bytes());
+        packet[6] = UDP;
+        packet[7] = 64;
+        packet[8..24].copy_from_slice(&nat64.client6.octets());
+        packet[24..40].copy_from_slice(&synthesize(nat64.prefix(), remote).octets());
+        packet[40..42].copy_from_slice(&5000u16.to_be_bytes());
+        packet[42..44].copy_from_slice(&53u16.to_be_bytes());
+        packet[44..46].copy_from_slice(&12u16.to_be_bytes());
+        packet[48..].copy_from_slice(b"ping");
+
+        let v4 = nat64.outbound(&packet).unwrap().into_owned();
+        let pseudo = pseudo_header_v4(Ipv4Addr::new(10, 8, 0, 3), remote, UDP, 12);
+        assert_eq!(checksum(&[&pseudo, &v4[20..]]), 0);
+        assert_eq!(&v4[28..], b"ping");
+    }
+
+    #[test]
+    fn test_other_traffic_passes_through() {
+        let nat64 = nat64();
+        let native = icmp_echo_request(
+            Ipv4Addr::new(10, 8, 0, 2),
+            Ipv4Addr::new(1, 1, 1, 1),
+            1,
+            1,
+            b"",
+        );
+        assert!(matches!(nat64.outbound(&native), Some(Cow::Borrowed(_))));
+        assert!(matches!(nat64.inbound(&native), Some(Cow::Borrowed(_))));
+
+        // Addressed to the prefix but not translatable.
+        let mut unknown = vec![0u8; 48];
+        unknown[0] = 0x60;
+        unknown[4..6].copy_from_slice(&8u16.to_be_bytes());
+        unknown[6] = 47; // GRE
+        unknown[24..40].copy_from_slice(&synthesize(nat64.prefix(), Ipv4Addr::LOCALHOST).octets());
+        assert!(nat64.outbound(&unknown).is_none());
+    }
+}
@@ -0,0 +1,209 @@
+use std::io::Write;
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+use proto::gso;
+use proto::gso::VNET_HDR_LEN;
+use tracing::warn;
+
+use super::tun::PacketDevice;
+
+/// pcapng block types.
+const SECTION_HEADER: u32 = 0x0A0D_0D0A;
+const INTERFACE_DESCRIPTION: u32 = 1;
+const ENHANCED_PACKET: u32 = 6;
+
+/// Raw IPv4/IPv6 packets, no link-layer header.
+const LINKTYPE_RAW: u16 = 101;
+
+/// Option codes.
+const OPT_END: u16 = 0;
+const IF_NAME: u16 = 2;
+const EPB_FLAGS: u16 = 2;
+
+/// Which way a packet crossed the client TUN device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// Written to the device: arrived through the tunnel.
+    Inbound,
+    /// Read from the device: leaving through the tunnel.
+    Outbound,
+}
+
+impl Direction {
+    /// The direction bits of `epb_flags`.
+    fn flags(self) -> u32 {
+        match self {
+            Direction::Inbound => 1,
+            Direction::Outbound => 2,
+        }
+    }
+}
+
+/// A pcapng file of the packets crossing the client TUN device, one
+/// interface named after it.
+pub struct Pcap {
+    file: Mutex<std::fs::File>,
+}
+
+impl Pcap {
+    /// Creates (or truncates) `path` and writes the section header and the
+    /// interface description for `interface`.
+    pub fn create(path: &Path, interface: &str) -> anyhow::Result<Self> {
+        let mut file = std::fs::File::create(path)
+            .map_err(|e| anyhow::anyhow!("failed to create {}: {}", path.display(), e))?;
+        file.write_all(&section_header())?;
+        file.write_all(&interface_description(interface))?;
+        Ok(Self {
+            file: Mutex::new(file),
+        })
+    }
+
+    /// Appends `packet`, a packet or GSO frame as forwarding handles it,
+    /// timestamped now. Capture is best effort: a failed write is logged,
+    /// never passed on to forwarding.
+    pub fn record(&self, direction: Direction, packet: &[u8]) {
+        let packet = gso::vnet_packet(packet).map_or(packet, |vnet| &vnet[VNET_HDR_LEN..]);
+        let micros = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_micros() as u64);
+        let block = enhanced_packet(direction, micros, packet);
+        if let Err(e) = self.file.lock().unwrap().write_all(&block) {
+            warn!("Failed to write packet capture: {}", e);
+        }
+    }
+}
+
+/// A TUN device whose packets are also written to a [`Pcap`].
+pub struct Capture<D> {
+    device: Arc<D>,
+    pcap: Arc<Pcap>,
+}
+
+impl<D> Capture<D> {
+    pub fn new(device: Arc<D>, pcap: Arc<Pcap>) -> Self {
+        Self { device, pcap }
+    }
+}
+
+impl<D: PacketDevice> PacketDevice for Capture<D> {
+    async fn recv(&self, buf: &mut [u8]) -> anyhow::Result<usize> {
+        let n = self.device.recv(buf).await?;
+        self.pcap.record(Direction::Outbound, &buf[..n]);
+        Ok(n)
+    }
+
+    async fn send(&self, packet: &[u8]) -> anyhow::Result<()> {
+        self.pcap.record(Direction::Inbound, packet);
+        self.device.send(packet).await
+    }
+}
+
+fn section_header() -> Vec<u8> {
+    let mut body = Vec::new();
+    body.extend_from_slice(&0x1A2B_3C4Du32.to_le_bytes());
+    body.extend_from_slice(&1u16.to_le_bytes());
+    body.extend_from_slice(&0u16.to_le_bytes());
+    // Section length not given.
+    body.extend_from_slice(&(-1i64).to_le_bytes());
+    block(SECTION_HEADER, &body)
+}
+
+fn interface_description(name: &str) -> Vec<u8> {
+    let mut body = Vec::new();
+    body.extend_from_slice(&LINKTYPE_RAW.to_le_bytes());
+    body.extend_from_slice(&0u16.to_le_bytes());
+    // No snap length limit.
+    body.extend_from_slice(&0u32.to_le_bytes());
+    option(&mut body, IF_NAME, name.as_bytes());
+    option(&mut body, OPT_END, &[]);
+    block(INTERFACE_DESCRIPTION, &body)
+}
+
+/// A packet of interface 0, at `micros` since the epoch (the default
+/// timestamp resolution).
+fn enhanced_packet(direction: Direction, micros: u64, packet: &[u8]) -> Vec<u8> {
+    let mut body = Vec::with_capacity(packet.len() + 32);
+    body.extend_from_slice(&0u32.to_le_bytes());
+    body.extend_from_slice(&((micros >> 32) as u32).to_le_bytes());
+    body.extend_from_slice(&(micros as u32).to_le_bytes());
+    body.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+    body.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+    body.extend_from_slice(packet);
+    pad(&mut body);
+    option(&mut body, EPB_FLAGS, &direction.flags().to_le_bytes());
+    option(&mut body, OPT_END, &[]);
+    block(ENHANCED_PACKET, &body)
+}
+
+/// Frames `body`, a multiple of 4 bytes, with the block type and the total
+/// length before and after it.
+fn block(kind: u32, body: &[u8]) -> Vec<u8> {
+    let len = (body.len() + 12) as u32;
+    let mut block = Vec::with_capacity(len as usize);
+    block.extend_from_slice(&kind.to_le_bytes());
+    block.extend_from_slice(&len.to_le_bytes());
+    block.extend_from_slice(body);
+    block.extend_from_slice(&len.to_le_bytes());
+    block
+}
+
+fn option(body: &mut Vec<u8>, code: u16, value: &[u8]) {
+    body.extend_from_slice(&code.to_le_bytes());
+    body.extend_from_slice(&(value.len() as u16).to_le_bytes());
+    body.extend_from_slice(value);
+    pad(body);
+}
+
+fn pad(body: &mut Vec<u8>) {
+    body.resize(body.len().next_multiple_of(4), 0);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_enhanced_packet_layout() {
+        let block = enhanced_packet(Direction::Outbound, (7 << 32) | 9, &[0x45; 5]);
+        // Header, 20 bytes of fields, the packet padded to 8, epb_flags and
+        // the end of options, the trailing length.
+        assert_eq!(block.len(), 8 + 20 + 8 + 8 + 4 + 4);
+        assert_eq!(block[..4], ENHANCED_PACKET.to_le_bytes());
+        assert_eq!(block[4..8], (block.len() as u32).to_le_bytes());
+        assert_eq!(block[block.len() - 4..], block[4..8]);
+        assert_eq!(block[12..16], 7u32.to_le_bytes());
+        assert_eq!(block[16..20], 9u32.to_le_bytes());
+        assert_eq!(block[20..24], 5u32.to_le_bytes());
+        assert_eq!(block[28..36], [0x45, 0x45, 0x45, 0x45, 0x45, 0, 0, 0]);
+        assert_eq!(block[36..44], [2, 0, 4, 0, 2, 0, 0, 0]);
+    }
+
+    #[test]
+    fn test_capture_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("tun.pcapng");
+        let pcap = Pcap::create(&path, "tun-x2ssh").unwrap();
+
+        let mut frame = gso::PREFIX.to_vec();
+        frame.extend_from_slice(&[0; VNET_HDR_LEN]);
+        frame.extend_from_slice(&[0x45; 20]);
+        pcap.record(Direction::Inbound, &frame);
+
+        let file = std::fs::read(&path).unwrap();
+        let shb = section_header();
+        let idb = interface_description("tun-x2ssh");
+        assert_eq!(file[..shb.len()], shb);
+        assert_eq!(file[shb.len()..shb.len() + idb.len()], idb);
+        // The GSO prefix and virtio-net header are not captured.
+        let epb = &file[shb.len() + idb.len()..];
+        assert_eq!(
+            epb.len(),
+            enhanced_packet(Direction::Inbound, 0, &[0x45; 20]).len()
+        );
+        assert_eq!(epb[52..56], Direction::Inbound.flags().to_le_bytes());
+    }
+}
@@ -0,0 +1,83 @@
+use std::net::IpAddr;
+use std::net::SocketAddr;
+use std::net::UdpSocket;
+use std::time::Duration;
+
+use tracing::debug;
+
+use crate::transport::Transport;
+
+/// Detects local network changes (Wi-Fi roam, tethering, DHCP renumbering)
+/// that leave the SSH connection bound to a source address the kernel no
+/// longer uses to reach the server.
+pub struct RoamingMonitor {
+    interval: Duration,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct SourceChange {
+    pub old: IpAddr,
+    pub new: IpAddr,
+}
+
+impl RoamingMonitor {
+    pub fn new(interval: Duration) -> Self {
+        Self { interval }
+    }
+
+    /// Waits until the preferred source address towards the SSH server
+    /// differs from the one the current connection is bound to.
+    ///
+    /// While the server is unreachable (no route at all) the check keeps
+    /// waiting, so a brief outage that comes back on the same address does
+    /// not force a reconnect.
+    pub async fn changed(&self, transport: &Transport) -> SourceChange {
+        let mut ticker = tokio::time::interval(self.interval);
+        ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+        loop {
+            ticker.tick().await;
+
+            let endpoints = transport.endpoints();
+            match preferred_source(endpoints.peer) {
+                Ok(new) if new != endpoints.local.ip() => {
+                    return SourceChange {
+                        old: endpoints.local.ip(),
+                        new,
+                    };
+                }
+                Ok(_) => {}
+                Err(e) => debug!("Source address lookup failed: {}", e),
+            }
+        }
+    }
+}
+
+/// Asks the kernel which local address it would use to reach `peer`.
+///
+/// Connecting a UDP socket sends nothing; it only performs the route lookup.
+pub fn preferred_source(peer: SocketAddr) -> std::io::Result<IpAddr> {
+    let bind: SocketAddr = match peer {
+        SocketAddr::V4(_) => ([0, 0, 0, 0], 0).into(),
+        SocketAddr::V6(_) => ([0u16; 8], 0).into(),
+    };
+    let socket = UdpSocket::bind(bind)?;
+    socket.connect(peer)?;
+    Ok(socket.local_addr()?.ip())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    #[test]
+    fn test_preferred_source_loopback() {
+        let peer = SocketAddr::from((Ipv4Addr::LOCALHOST, 22));
+        assert_eq!(
+            preferred_source(peer).unwrap(),
+            IpAddr::V4(Ipv4Addr::LOCALHOST)
+        );
+    }
+}
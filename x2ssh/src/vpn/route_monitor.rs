@@ -0,0 +1,73 @@
+use std::time::Duration;
+
+use tracing::debug;
+
+/// Notifies when the kernel's routing tables change, so routes that
+/// NetworkManager, a DHCP client or a Wi-Fi roam overwrote can be put back
+/// (see [`RoutingManager::heal`]).
+///
+/// [`RoutingManager::heal`]: super::routing::RoutingManager::heal
+pub struct RouteMonitor {
+    #[cfg(target_os = "linux")]
+    messages: futures::channel::mpsc::UnboundedReceiver<(
+        rtnetlink::packet_core::NetlinkMessage<rtnetlink::packet_route::RouteNetlinkMessage>,
+        rtnetlink::sys::SocketAddr,
+    )>,
+    settle: Duration,
+}
+
+impl RouteMonitor {
+    /// Subscribes to IPv4 and IPv6 route notifications. Bursts of changes
+    /// are reported once, after `settle` passes without another one.
+    #[cfg(target_os = "linux")]
+    pub fn new(settle: Duration) -> anyhow::Result<Self> {
+        use rtnetlink::constants::RTMGRP_IPV4_ROUTE;
+        use rtnetlink::constants::RTMGRP_IPV6_ROUTE;
+        use rtnetlink::sys::AsyncSocket;
+        use rtnetlink::sys::SocketAddr;
+
+        let (mut connection, _, messages) = rtnetlink::new_connection()?;
+        connection
+            .socket_mut()
+            .socket_mut()
+            .bind(&SocketAddr::new(0, RTMGRP_IPV4_ROUTE | RTMGRP_IPV6_ROUTE))?;
+        tokio::spawn(connection);
+        Ok(Self { messages, settle })
+    }
+
+    #[cfg(target_os = "windows")]
+    pub fn new(_settle: Duration) -> anyhow::Result<Self> {
+        anyhow::bail!("route monitoring is not supported on Windows yet")
+    }
+
+    /// Waits for a route to be added or removed, then for the changes to
+    /// settle.
+    #[cfg(target_os = "linux")]
+    pub async fn changed(&mut self) {
+        use futures::StreamExt;
+        use rtnetlink::packet_core::NetlinkPayload;
+        use rtnetlink::packet_route::RouteNetlinkMessage;
+
+        loop {
+            let Some((message, _)) = self.messages.next().await else {
+                debug!("Route notifications ended");
+                return std::future::pending().await;
+            };
+            if matches!(
+                message.payload,
+                NetlinkPayload::InnerMessage(
+                    RouteNetlinkMessage::NewRoute(_) | RouteNetlinkMessage::DelRoute(_)
+                )
+            ) {
+                break;
+            }
+        }
+
+        while let Ok(Some(_)) = tokio::time::timeout(self.settle, self.messages.next()).await {}
+    }
+
+    #[cfg(target_os = "windows")]
+    pub async fn changed(&mut self) {
+        std::future::pending().await
+    }
+}
@@ -1,26 +1,199 @@
 use std::net::IpAddr;
+use std::path::Path;
+use std::path::PathBuf;
 
 use ipnet::IpNet;
+use serde::Deserialize;
+use serde::Serialize;
+use tracing::debug;
+use tracing::info;
+use tracing::warn;
 
+use crate::config::RouteVia;
+use crate::config::RoutingMode;
 use crate::config::VpnConfig;
 
+/// Directory of the state files that record what [`RoutingManager::setup`]
+/// changed, so a run killed before cleanup can be undone later. Under
+/// `/run`, so it is cleared on reboot along with the routes themselves.
+pub const STATE_DIR: &str = crate::status::RUN_DIR;
+
+/// State file for the tunnel on `tun_name`.
+pub fn state_file(tun_name: &str) -> PathBuf {
+    Path::new(STATE_DIR).join(format!("routes-{}.json", tun_name))
+}
+
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
 pub struct RoutingState {
     original_default_route: Option<RouteInfo>,
+    original_default_route6: Option<RouteInfo>,
+    /// Whether the IPv4 default route was redirected into the tunnel; false
+    /// in split-tunnel (`include`) mode.
+    full_tunnel: bool,
+    /// Whether the IPv6 default route was redirected into the tunnel.
+    ipv6: bool,
+    /// Policy routing table in use (`routing_mode = "policy"`), with the
+    /// families it was installed for. Replaces `full_tunnel`/`ipv6`.
+    policy: Option<(u32, Vec<Family>)>,
+    /// Whether `policy` only applies to traffic marked for the table
+    /// (`cgroup`), rather than to all traffic without the mark.
+    #[serde(default)]
+    marked_only: bool,
+    /// Metric of the tunnel default routes added next to the system's
+    /// (`routing_mode = "metric"`), with the families they were added for.
+    #[serde(default)]
+    metric: Option<(u32, Vec<Family>)>,
+    /// TUN device and server tunnel addresses, kept for routes added after
+    /// setup (domain routing).
+    tunnel: Option<Tunnel>,
+    /// Host routes keeping the SSH connection itself off the tunnel, one per
+    /// address the server resolves to.
+    #[serde(default)]
+    ssh_routes: Vec<RouteInfo>,
+    include_routes: Vec<RouteInfo>,
     exclusion_routes: Vec<RouteInfo>,
+    /// Directly-connected subnets found for `exclude_lan`.
+    lan_subnets: Vec<IpNet>,
+}
+
+impl RoutingState {
+    /// The state recorded for the tunnel on `tun_name`, if any.
+    pub fn read(tun_name: &str) -> anyhow::Result<Option<Self>> {
+        match std::fs::read_to_string(state_file(tun_name)) {
+            Ok(contents) => Ok(Some(serde_json::from_str(&contents)?)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// The routes the tunnel has installed, as `ip route` shows them.
+    pub fn routes(&self) -> Vec<String> {
+        let mut routes = Vec::new();
+        if let Some(tunnel) = &self.tunnel {
+            let via_tun = |family: Family| {
+                let gateway = match family {
+                    Family::V4 => Some(tunnel.gateway),
+                    Family::V6 => tunnel.gateway6,
+                }?;
+                Some(format!(
+                    "{} via {} dev {}",
+                    family.default_destination(),
+                    gateway,
+                    tunnel.interface
+                ))
+            };
+            if self.full_tunnel {
+                routes.extend(via_tun(Family::V4));
+            }
+            if self.ipv6 {
+                routes.extend(via_tun(Family::V6));
+            }
+            if let Some((table, families)) = &self.policy {
+                for &family in families {
+                    routes
+                        .extend(via_tun(family).map(|route| format!("{} table {}", route, table)));
+                }
+            }
+            if let Some((metric, families)) = &self.metric {
+                for &family in families {
+                    routes.extend(
+                        via_tun(family).map(|route| format!("{} metric {}", route, metric)),
+                    );
+                }
+            }
+        }
+        for route in self
+            .ssh_routes
+            .iter()
+            .chain(&self.include_routes)
+            .chain(&self.exclusion_routes)
+        {
+            routes.push(route.to_string());
+        }
+        routes
+    }
+}
+
+/// What [`RoutingManager::setup`] would do, from [`RoutingManager::plan`].
+#[derive(Debug, Default)]
+pub struct RoutePlan {
+    /// `ip` command lines, in the order they would run.
+    pub commands: Vec<String>,
+    /// Subnets `exclude_lan` would find, which the kill switch lets through.
+    pub lan_subnets: Vec<IpNet>,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct Tunnel {
+    interface: String,
+    gateway: IpAddr,
+    gateway6: Option<IpAddr>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct RouteInfo {
     pub destination: IpNet,
     pub gateway: Option<IpAddr>,
     pub interface: String,
 }
 
+impl std::fmt::Display for RouteInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.destination)?;
+        if let Some(gateway) = self.gateway {
+            write!(f, " via {}", gateway)?;
+        }
+        write!(f, " dev {}", self.interface)
+    }
+}
+
+/// Address family of a route; selects `ip -4` or `ip -6`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Family {
+    V4,
+    V6,
+}
+
+impl Family {
+    pub fn of(ip: IpAddr) -> Self {
+        match ip {
+            IpAddr::V4(_) => Family::V4,
+            IpAddr::V6(_) => Family::V6,
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    fn flag(self) -> &'static str {
+        match self {
+            Family::V4 => "-4",
+            Family::V6 => "-6",
+        }
+    }
+
+    fn default_destination(self) -> IpNet {
+        match self {
+            Family::V4 => "0.0.0.0/0".parse().unwrap(),
+            Family::V6 => "::/0".parse().unwrap(),
+        }
+    }
+}
+
+/// Platform query for the current default route. Linux asks the kernel over
+/// netlink rather than parsing `ip` output, whose format varies between
+/// iproute2 versions.
+pub trait DefaultRouteSource {
+    fn default_route(
+        &self,
+        family: Family,
+    ) -> impl Future<Output = anyhow::Result<Option<RouteInfo>>> + Send;
+}
+
 pub struct RoutingManager {
     #[cfg(target_os = "linux")]
-    #[allow(dead_code)]
     handle: rtnetlink::Handle,
     state: RoutingState,
+    /// Where `state` is persisted; set by `setup`.
+    state_file: Option<PathBuf>,
 }
 
 impl RoutingManager {
@@ -30,13 +203,66 @@ impl RoutingManager {
         tokio::spawn(connection);
         Ok(Self {
             handle,
-            state: RoutingState {
-                original_default_route: None,
-                exclusion_routes: Vec::new(),
-            },
+            state: RoutingState::default(),
+            state_file: None,
         })
     }
 
+    /// Undoes the routing changes of a previous run on `tun_name` that was
+    /// killed before it could clean up, as recorded in its state file.
+    /// Returns whether there was anything to undo.
+    pub async fn recover(tun_name: &str) -> anyhow::Result<bool> {
+        Self::recover_from(&state_file(tun_name)).await
+    }
+
+    /// [`recover`](Self::recover) from the state file at `path`.
+    #[cfg(target_os = "linux")]
+    pub async fn recover_from(path: &Path) -> anyhow::Result<bool> {
+        let contents = match std::fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(false),
+            Err(e) => return Err(e.into()),
+        };
+        let state = match serde_json::from_str(&contents) {
+            Ok(state) => state,
+            Err(e) => {
+                warn!(
+                    "Ignoring unreadable routing state {}: {}",
+                    path.display(),
+                    e
+                );
+                std::fs::remove_file(path)?;
+                return Ok(false);
+            }
+        };
+
+        let mut manager = Self::new().await?;
+        manager.state = state;
+        manager.state_file = Some(path.to_path_buf());
+        manager.cleanup().await?;
+        Ok(true)
+    }
+
+    #[cfg(target_os = "windows")]
+    pub async fn recover_from(_path: &Path) -> anyhow::Result<bool> {
+        anyhow::bail!("routing is not supported on Windows yet")
+    }
+
+    /// Writes the current state to the state file, replacing it atomically.
+    /// Failing to do so only costs crash recovery, so it is not an error.
+    fn persist(&self) {
+        let Some(path) = &self.state_file else {
+            return;
+        };
+        if let Err(e) = write_state(path, &self.state) {
+            warn!(
+                "Failed to record routing state in {}: {}",
+                path.display(),
+                e
+            );
+        }
+    }
+
     #[cfg(target_os = "windows")]
     pub async fn new() -> anyhow::Result<Self> {
         todo!("Windows routing not yet implemented - Phase 4")
@@ -46,19 +272,73 @@ impl RoutingManager {
     pub async fn setup(&mut self, config: &VpnConfig, ssh_server_ip: IpAddr) -> anyhow::Result<()> {
         let tun_name = &config.client_tun;
         let server_ip = config.server_ip()?;
+        let ipv6 = config.ipv6_addresses()?;
 
-        self.save_original_default_route().await?;
-
-        self.route_ssh_server_via_original_gateway(ssh_server_ip)
-            .await?;
+        self.state_file = Some(state_file(tun_name));
+        self.save_original_default_routes(ipv6.is_some()).await?;
+        self.state.tunnel = Some(Tunnel {
+            interface: tun_name.clone(),
+            gateway: server_ip,
+            gateway6: ipv6.map(|(_, server6)| server6.addr()),
+        });
 
-        self.set_default_route_via_tun(tun_name, server_ip).await?;
+        let mut lan = Vec::new();
+        if config.exclude_lan {
+            lan.extend(self.connected_subnets(Family::V4, tun_name).await?);
+            if ipv6.is_some() {
+                lan.extend(self.connected_subnets(Family::V6, tun_name).await?);
+            }
+        }
+        let original = |family| self.original_default_route(family).cloned();
+        let steps = steps(config, ssh_server_ip, original, &lan, &[])?;
 
-        for exclusion in &config.exclude {
-            let net: IpNet = exclusion.parse()?;
-            self.add_exclusion_route(net).await?;
+        // Each change of the default route is recorded before it is made:
+        // undoing one that did not happen is harmless, missing one that did
+        // is not.
+        for step in steps {
+            match step {
+                Step::SshServer(route) => {
+                    add_route_via_gateway(route.destination, route.gateway, &route.interface)
+                        .await?;
+                    self.state.ssh_routes.push(route);
+                    self.persist();
+                }
+                Step::Policy {
+                    gateway,
+                    table,
+                    marked_only,
+                } => {
+                    let (_, families) = self.state.policy.get_or_insert((table, Vec::new()));
+                    families.push(Family::of(gateway));
+                    self.state.marked_only = marked_only;
+                    self.persist();
+                    add_policy_routing(tun_name, gateway, table, marked_only).await?;
+                }
+                Step::Metric { gateway, metric } => {
+                    let family = Family::of(gateway);
+                    self.check_metric(family, metric).await?;
+                    let (_, families) = self.state.metric.get_or_insert((metric, Vec::new()));
+                    families.push(family);
+                    self.persist();
+                    add_metric_route(tun_name, gateway, metric).await?;
+                }
+                Step::Default { gateway } => {
+                    self.state.full_tunnel = true;
+                    self.state.ipv6 = ipv6.is_some();
+                    self.persist();
+                    self.set_default_route_via_tun(tun_name, gateway).await?;
+                }
+                Step::Include(route) => self.route_through_tunnel(route.destination).await?,
+                Step::Exclusion(route) => {
+                    add_route_via_gateway(route.destination, route.gateway, &route.interface)
+                        .await?;
+                    self.state.exclusion_routes.push(route);
+                }
+                Step::Lan(net, interface) => self.add_lan_exclusion(net, &interface).await?,
+            }
         }
 
+        self.persist();
         Ok(())
     }
 
@@ -71,10 +351,19 @@ impl RoutingManager {
         todo!("Windows routing not yet implemented - Phase 4")
     }
 
+    fn original_default_route(&self, family: Family) -> Option<&RouteInfo> {
+        match family {
+            Family::V4 => self.state.original_default_route.as_ref(),
+            Family::V6 => self.state.original_default_route6.as_ref(),
+        }
+    }
+
     #[cfg(target_os = "linux")]
-    async fn save_original_default_route(&mut self) -> anyhow::Result<()> {
-        let route = get_default_route().await?;
-        self.state.original_default_route = route;
+    async fn save_original_default_routes(&mut self, ipv6: bool) -> anyhow::Result<()> {
+        self.state.original_default_route = self.default_route(Family::V4).await?;
+        if ipv6 {
+            self.state.original_default_route6 = self.default_route(Family::V6).await?;
+        }
         Ok(())
     }
 
@@ -83,8 +372,70 @@ impl RoutingManager {
         &mut self,
         ssh_ip: IpAddr,
     ) -> anyhow::Result<()> {
-        if let Some(ref original) = self.state.original_default_route {
+        if let Some(original) = self.original_default_route(Family::of(ssh_ip)).cloned() {
             add_route_via_gateway(ssh_ip, original.gateway, &original.interface).await?;
+            self.state.ssh_routes.push(RouteInfo {
+                destination: ssh_ip.into(),
+                gateway: original.gateway,
+                interface: original.interface,
+            });
+        }
+        Ok(())
+    }
+
+    /// Keeps the SSH server's `ips`, as resolved for a reconnect, off the
+    /// tunnel: pins new addresses via the original gateway and drops the
+    /// routes of addresses the host no longer resolves to.
+    #[cfg(target_os = "linux")]
+    pub async fn pin_ssh_servers(&mut self, ips: &[IpAddr]) -> anyhow::Result<()> {
+        // Routing is not set up, or already cleaned up.
+        if self.state_file.is_none() {
+            return Ok(());
+        }
+        let (kept, stale): (Vec<_>, Vec<_>) = std::mem::take(&mut self.state.ssh_routes)
+            .into_iter()
+            .partition(|route| ips.contains(&route.destination.addr()));
+        self.state.ssh_routes = kept;
+        for &ip in ips {
+            if !self
+                .state
+                .ssh_routes
+                .iter()
+                .any(|route| route.destination.addr() == ip)
+            {
+                info!("Pinning SSH server address {} via the original gateway", ip);
+                self.route_ssh_server_via_original_gateway(ip).await?;
+            }
+        }
+        self.persist();
+        for route in stale {
+            delete_route(route.destination).await?;
+        }
+        Ok(())
+    }
+
+    #[cfg(target_os = "windows")]
+    pub async fn pin_ssh_servers(&mut self, _ips: &[IpAddr]) -> anyhow::Result<()> {
+        anyhow::bail!("routing is not supported on Windows yet")
+    }
+
+    /// Fails unless the tunnel's default route with `metric` would win over
+    /// the system's of `family`.
+    #[cfg(target_os = "linux")]
+    async fn check_metric(&self, family: Family, metric: u32) -> anyhow::Result<()> {
+        use rtnetlink::packet_route::route::RouteHeader;
+
+        let routes = self.dump_routes(family).await?;
+        let defaults = default_routes(&routes, family, RouteHeader::RT_TABLE_MAIN.into());
+        if let Some(best) = defaults.first()
+            && best.metric <= metric
+        {
+            anyhow::bail!(
+                "the system's default route has metric {}, so the tunnel's (route_metric = {}) \
+                 would not be used; lower route_metric or use another routing_mode",
+                best.metric,
+                metric
+            );
         }
         Ok(())
     }
@@ -95,39 +446,447 @@ impl RoutingManager {
         tun_name: &str,
         gateway: IpAddr,
     ) -> anyhow::Result<()> {
-        delete_default_route().await?;
+        let family = Family::of(gateway);
+        delete_default_route(family).await?;
         add_default_route(gateway, tun_name).await?;
         Ok(())
     }
 
+    /// Routes `net` through the TUN device, via the server's tunnel address
+    /// of the same family when there is one. Can be called after [`setup`]
+    /// to add routes on the fly; they are removed by [`cleanup`].
+    ///
+    /// [`setup`]: RoutingManager::setup
+    /// [`cleanup`]: RoutingManager::cleanup
+    #[cfg(target_os = "linux")]
+    pub async fn route_through_tunnel(&mut self, net: IpNet) -> anyhow::Result<()> {
+        let Some(tunnel) = &self.state.tunnel else {
+            anyhow::bail!("routing is not set up");
+        };
+        let gateway = match Family::of(net.addr()) {
+            Family::V4 => Some(tunnel.gateway),
+            Family::V6 => tunnel.gateway6,
+        };
+        let interface = tunnel.interface.clone();
+        add_route_via_gateway(net, gateway, &interface).await?;
+        self.state.include_routes.push(RouteInfo {
+            destination: net,
+            gateway,
+            interface,
+        });
+        self.persist();
+        Ok(())
+    }
+
+    #[cfg(target_os = "windows")]
+    pub async fn route_through_tunnel(&mut self, _net: IpNet) -> anyhow::Result<()> {
+        anyhow::bail!("routing is not supported on Windows yet")
+    }
+
+    /// Whether the tunnel carries IPv6.
+    pub fn tunnels_ipv6(&self) -> bool {
+        self.state
+            .tunnel
+            .as_ref()
+            .is_some_and(|tunnel| tunnel.gateway6.is_some())
+    }
+
+    /// Directly-connected subnets of the client's other interfaces, from the
+    /// kernel's own routes.
     #[cfg(target_os = "linux")]
-    async fn add_exclusion_route(&mut self, net: IpNet) -> anyhow::Result<()> {
-        if let Some(ref original) = self.state.original_default_route {
-            add_route_via_gateway(net, original.gateway, &original.interface).await?;
+    async fn connected_subnets(
+        &self,
+        family: Family,
+        tun_name: &str,
+    ) -> anyhow::Result<Vec<(IpNet, String)>> {
+        let routes = self.dump_routes(family).await?;
+        let mut subnets = Vec::new();
+        for (net, index) in select_connected_subnets(&routes, family) {
+            match self.interface_name(index).await? {
+                Some(name) if name != "lo" && name != tun_name => subnets.push((net, name)),
+                _ => {}
+            }
+        }
+        Ok(subnets)
+    }
+
+    /// Pins a connected subnet to its interface. The kernel's own route
+    /// usually already does this, in which case there is nothing to add (or
+    /// remove on cleanup).
+    #[cfg(target_os = "linux")]
+    async fn add_lan_exclusion(&mut self, net: IpNet, interface: &str) -> anyhow::Result<()> {
+        let output = ip_route(Family::of(net.addr()))
+            .args(["add", &net.to_string(), "dev", interface])
+            .output()
+            .await?;
+        if output.status.success() {
             self.state.exclusion_routes.push(RouteInfo {
                 destination: net,
-                gateway: original.gateway,
-                interface: original.interface.clone(),
+                gateway: None,
+                interface: interface.to_string(),
             });
+        } else {
+            debug!("LAN {} is already routed via {}", net, interface);
         }
+        debug!("Excluding LAN {} ({}) from the tunnel", net, interface);
+        self.state.lan_subnets.push(net);
         Ok(())
     }
 
+    /// Subnets excluded by `exclude_lan`.
+    pub fn lan_subnets(&self) -> &[IpNet] {
+        &self.state.lan_subnets
+    }
+
+    /// The `ip` commands [`setup`] would run for `config`, followed by
+    /// routes for `through_tunnel` as [`route_through_tunnel`] adds them.
+    /// Only reads the current default routes and connected subnets.
+    ///
+    /// [`setup`]: RoutingManager::setup
+    /// [`route_through_tunnel`]: RoutingManager::route_through_tunnel
+    #[cfg(target_os = "linux")]
+    pub async fn plan(
+        &self,
+        config: &VpnConfig,
+        ssh_server_ip: IpAddr,
+        through_tunnel: &[IpNet],
+    ) -> anyhow::Result<RoutePlan> {
+        let ipv6 = config.ipv6_addresses()?.is_some();
+        let original = self.default_route(Family::V4).await?;
+        let original6 = if ipv6 {
+            self.default_route(Family::V6).await?
+        } else {
+            None
+        };
+        let mut lan = Vec::new();
+        if config.exclude_lan {
+            lan.extend(
+                self.connected_subnets(Family::V4, &config.client_tun)
+                    .await?,
+            );
+            if ipv6 {
+                lan.extend(
+                    self.connected_subnets(Family::V6, &config.client_tun)
+                        .await?,
+                );
+            }
+        }
+
+        let originals = |family| match family {
+            Family::V4 => original.clone(),
+            Family::V6 => original6.clone(),
+        };
+        let steps = steps(config, ssh_server_ip, originals, &lan, through_tunnel)?;
+        Ok(RoutePlan {
+            commands: steps
+                .iter()
+                .flat_map(|step| step.commands(&config.client_tun))
+                .collect(),
+            lan_subnets: lan.into_iter().map(|(net, _)| net).collect(),
+        })
+    }
+
+    #[cfg(target_os = "windows")]
+    pub async fn plan(
+        &self,
+        _config: &VpnConfig,
+        _ssh_server_ip: IpAddr,
+        _through_tunnel: &[IpNet],
+    ) -> anyhow::Result<RoutePlan> {
+        anyhow::bail!("planning routes is not supported on Windows yet")
+    }
+
+    /// Re-applies routes that something else (NetworkManager, a DHCP client,
+    /// a Wi-Fi roam) removed or overrode since [`setup`]: the tunnel default
+    /// route or policy table, and the SSH server, include and exclusion
+    /// routes. A new system default route is adopted as the uplink, and the
+    /// routes that bypass the tunnel are moved onto it. Returns whether
+    /// anything was repaired.
+    ///
+    /// Only acts on differences, so the route changes it makes itself settle
+    /// on the next call.
+    ///
+    /// [`setup`]: RoutingManager::setup
+    #[cfg(target_os = "linux")]
+    pub async fn heal(&mut self) -> anyhow::Result<bool> {
+        let Some(tun_name) = self.state.tunnel.as_ref().map(|t| t.interface.clone()) else {
+            return Ok(false);
+        };
+        let Some(tun_index) = self.interface_index(&tun_name).await? else {
+            // Without the TUN device there is nothing to route into; the
+            // forwarding loop fails on its own.
+            debug!("TUN device {} is gone; not healing routes", tun_name);
+            return Ok(false);
+        };
+
+        let mut repaired = false;
+        for family in [Family::V4, Family::V6] {
+            repaired |= self.heal_family(family, &tun_name, tun_index).await?;
+        }
+        if repaired {
+            self.persist();
+        }
+        Ok(repaired)
+    }
+
+    #[cfg(target_os = "windows")]
+    pub async fn heal(&mut self) -> anyhow::Result<bool> {
+        anyhow::bail!("routing is not supported on Windows yet")
+    }
+
+    #[cfg(target_os = "linux")]
+    async fn heal_family(
+        &mut self,
+        family: Family,
+        tun_name: &str,
+        tun_index: u32,
+    ) -> anyhow::Result<bool> {
+        use rtnetlink::packet_route::route::RouteHeader;
+
+        let mut repaired = false;
+        let mut routes = self.dump_routes(family).await?;
+        let defaults = default_routes(&routes, family, RouteHeader::RT_TABLE_MAIN.into());
+
+        if let Some(uplink) = defaults.iter().find(|route| route.oif != tun_index)
+            && let Some(original) = self.original_default_route(family).cloned()
+            && let Some(interface) = self.interface_name(uplink.oif).await?
+        {
+            let current = RouteInfo {
+                destination: family.default_destination(),
+                gateway: uplink.gateway,
+                interface,
+            };
+            if current != original {
+                info!("Uplink changed from {} to {}", original, current);
+                self.adopt_uplink(&original, current).await?;
+                repaired = true;
+            }
+        }
+
+        let gateway = self.state.tunnel.as_ref().and_then(|tunnel| match family {
+            Family::V4 => Some(tunnel.gateway),
+            Family::V6 => tunnel.gateway6,
+        });
+        let tunneled = match family {
+            Family::V4 => self.state.full_tunnel,
+            Family::V6 => self.state.ipv6,
+        };
+        if tunneled
+            && let Some(gateway) = gateway
+            && defaults.first().is_none_or(|route| route.oif != tun_index)
+        {
+            warn!(
+                "Default route no longer points into {}; restoring it",
+                tun_name
+            );
+            self.set_default_route_via_tun(tun_name, gateway).await?;
+            repaired = true;
+        }
+
+        if let Some((table, families)) = &self.state.policy
+            && families.contains(&family)
+            && let Some(gateway) = gateway
+            && !default_routes(&routes, family, *table)
+                .iter()
+                .any(|route| route.oif == tun_index)
+        {
+            warn!(
+                "Policy routing table {} lost its tunnel route; restoring it",
+                table
+            );
+            add_policy_routing(tun_name, gateway, *table, self.state.marked_only).await?;
+            repaired = true;
+        }
+
+        if let Some((metric, families)) = &self.state.metric
+            && families.contains(&family)
+            && let Some(gateway) = gateway
+            && !defaults
+                .iter()
+                .any(|route| route.oif == tun_index && route.metric == *metric)
+        {
+            warn!(
+                "Default route with metric {} into {} disappeared; restoring it",
+                metric, tun_name
+            );
+            add_metric_route(tun_name, gateway, *metric).await?;
+            repaired = true;
+        }
+
+        if repaired {
+            routes = self.dump_routes(family).await?;
+        }
+        let expected = self
+            .state
+            .ssh_routes
+            .iter()
+            .chain(&self.state.include_routes)
+            .chain(&self.state.exclusion_routes);
+        for route in missing_routes(expected, &routes, family) {
+            warn!("Route {} disappeared; restoring it", route);
+            replace_route(&route).await?;
+            repaired = true;
+        }
+
+        Ok(repaired)
+    }
+
+    /// Makes `current` the uplink for `original`'s family, moving the routes
+    /// that went out through `original` (the SSH server and exclusions) onto
+    /// it. Cleanup then restores `current` as the default route.
+    #[cfg(target_os = "linux")]
+    async fn adopt_uplink(
+        &mut self,
+        original: &RouteInfo,
+        current: RouteInfo,
+    ) -> anyhow::Result<()> {
+        let on_original = |route: &RouteInfo| {
+            route.gateway == original.gateway && route.interface == original.interface
+        };
+        for route in self
+            .state
+            .ssh_routes
+            .iter_mut()
+            .chain(&mut self.state.exclusion_routes)
+            .filter(|route| {
+                Family::of(route.destination.addr()) == Family::of(current.destination.addr())
+            })
+            .filter(|route| on_original(route))
+        {
+            route.gateway = current.gateway;
+            route.interface = current.interface.clone();
+            replace_route(route).await?;
+        }
+
+        match Family::of(current.destination.addr()) {
+            Family::V4 => self.state.original_default_route = Some(current),
+            Family::V6 => self.state.original_default_route6 = Some(current),
+        }
+        Ok(())
+    }
+
+    #[cfg(target_os = "linux")]
+    async fn dump_routes(
+        &self,
+        family: Family,
+    ) -> anyhow::Result<Vec<rtnetlink::packet_route::route::RouteMessage>> {
+        use futures::TryStreamExt;
+        use rtnetlink::RouteMessageBuilder;
+
+        let request = match family {
+            Family::V4 => RouteMessageBuilder::<std::net::Ipv4Addr>::new().build(),
+            Family::V6 => RouteMessageBuilder::<std::net::Ipv6Addr>::new().build(),
+        };
+        Ok(self
+            .handle
+            .route()
+            .get(request)
+            .execute()
+            .try_collect()
+            .await?)
+    }
+
+    #[cfg(target_os = "linux")]
+    async fn interface_name(&self, index: u32) -> anyhow::Result<Option<String>> {
+        use futures::TryStreamExt;
+        use rtnetlink::packet_route::link::LinkAttribute;
+
+        let mut links = self.handle.link().get().match_index(index).execute();
+        Ok(links.try_next().await?.and_then(|link| {
+            link.attributes.into_iter().find_map(|attr| match attr {
+                LinkAttribute::IfName(name) => Some(name),
+                _ => None,
+            })
+        }))
+    }
+
+    #[cfg(target_os = "linux")]
+    async fn interface_index(&self, name: &str) -> anyhow::Result<Option<u32>> {
+        use futures::TryStreamExt;
+
+        let mut links = self
+            .handle
+            .link()
+            .get()
+            .match_name(name.to_string())
+            .execute();
+        match links.try_next().await {
+            Ok(link) => Ok(link.map(|link| link.header.index)),
+            Err(rtnetlink::Error::NetlinkError(e)) if e.raw_code() == -libc::ENODEV => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
     #[cfg(target_os = "linux")]
     pub async fn cleanup(&mut self) -> anyhow::Result<()> {
-        delete_default_route().await?;
+        if self.state.full_tunnel {
+            self.restore_default_route(Family::V4).await?;
+            self.state.full_tunnel = false;
+        }
 
-        if let Some(ref original) = self.state.original_default_route
-            && let Some(gw) = original.gateway
+        if self.state.ipv6 {
+            self.restore_default_route(Family::V6).await?;
+            self.state.ipv6 = false;
+        }
+
+        if let Some((table, families)) = self.state.policy.take() {
+            for family in families {
+                remove_policy_routing(family, table, self.state.marked_only).await?;
+            }
+        }
+
+        if let Some((metric, families)) = self.state.metric.take()
+            && let Some(tunnel) = &self.state.tunnel
         {
-            add_default_route(gw, &original.interface).await?;
+            for family in families {
+                delete_metric_route(family, &tunnel.interface, metric).await?;
+            }
         }
 
-        for route in &self.state.exclusion_routes {
+        for route in self
+            .state
+            .ssh_routes
+            .iter()
+            .chain(&self.state.include_routes)
+            .chain(&self.state.exclusion_routes)
+        {
             delete_route(route.destination).await?;
         }
+        self.state.ssh_routes.clear();
+        self.state.include_routes.clear();
         self.state.exclusion_routes.clear();
 
+        if let Some(path) = self.state_file.take()
+            && let Err(e) = std::fs::remove_file(&path)
+            && e.kind() != std::io::ErrorKind::NotFound
+        {
+            warn!("Failed to remove routing state {}: {}", path.display(), e);
+        }
+
+        Ok(())
+    }
+
+    /// Puts the original default route back in place of the tunnel's. A
+    /// default route through another interface is left alone: after a crash
+    /// the TUN device, and with it the tunnel's route, is gone, and whatever
+    /// is there now was put there by the system.
+    #[cfg(target_os = "linux")]
+    async fn restore_default_route(&self, family: Family) -> anyhow::Result<()> {
+        let tun_name = self.state.tunnel.as_ref().map(|t| t.interface.as_str());
+        match self.default_route(family).await? {
+            Some(current) if Some(current.interface.as_str()) != tun_name => {
+                debug!("Leaving default route {} in place", current);
+                return Ok(());
+            }
+            Some(_) => delete_default_route(family).await?,
+            None => {}
+        }
+
+        if let Some(original) = self.original_default_route(family)
+            && let Some(gw) = original.gateway
+        {
+            add_default_route(gw, &original.interface).await?;
+        }
         Ok(())
     }
 
@@ -137,58 +896,522 @@ impl RoutingManager {
     }
 }
 
+fn write_state(path: &Path, state: &RoutingState) -> anyhow::Result<()> {
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    let tmp = path.with_extension("json.tmp");
+    std::fs::write(&tmp, serde_json::to_vec_pretty(state)?)?;
+    std::fs::rename(&tmp, path)?;
+    Ok(())
+}
+
 #[cfg(target_os = "linux")]
-async fn get_default_route() -> anyhow::Result<Option<RouteInfo>> {
-    use std::net::Ipv4Addr;
+fn ip_route(family: Family) -> tokio::process::Command {
+    let mut cmd = tokio::process::Command::new("ip");
+    cmd.args([family.flag(), "route"]);
+    cmd
+}
 
-    let output = tokio::process::Command::new("ip")
-        .args(["route", "show", "default"])
-        .output()
-        .await?;
+#[cfg(target_os = "linux")]
+impl DefaultRouteSource for RoutingManager {
+    async fn default_route(&self, family: Family) -> anyhow::Result<Option<RouteInfo>> {
+        let routes = self.dump_routes(family).await?;
+
+        let Some((gateway, index)) = select_default_route(&routes, family) else {
+            return Ok(None);
+        };
+
+        let Some(interface) = self.interface_name(index).await? else {
+            anyhow::bail!("default route uses unknown interface index {}", index);
+        };
+
+        Ok(Some(RouteInfo {
+            destination: family.default_destination(),
+            gateway,
+            interface,
+        }))
+    }
+}
+
+/// Picks the default route the kernel would use from a route dump: a
+/// unicast `/0` route in the main table with the lowest metric. Returns its
+/// gateway and output interface index.
+#[cfg(target_os = "linux")]
+fn select_default_route(
+    routes: &[rtnetlink::packet_route::route::RouteMessage],
+    family: Family,
+) -> Option<(Option<IpAddr>, u32)> {
+    use rtnetlink::packet_route::route::RouteHeader;
+
+    default_routes(routes, family, RouteHeader::RT_TABLE_MAIN.into())
+        .first()
+        .map(|route| (route.gateway, route.oif))
+}
 
-    let stdout = String::from_utf8_lossy(&output.stdout);
-    let line = stdout.lines().next();
+#[cfg(target_os = "linux")]
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct DefaultRoute {
+    gateway: Option<IpAddr>,
+    oif: u32,
+    metric: u32,
+}
+
+/// Unicast `/0` routes in `table`, best (lowest metric) first.
+#[cfg(target_os = "linux")]
+fn default_routes(
+    routes: &[rtnetlink::packet_route::route::RouteMessage],
+    family: Family,
+    table: u32,
+) -> Vec<DefaultRoute> {
+    use rtnetlink::packet_route::route::RouteAddress;
+    use rtnetlink::packet_route::route::RouteAttribute;
+    use rtnetlink::packet_route::route::RouteType;
+
+    let mut defaults: Vec<_> = routes
+        .iter()
+        .filter(|route| {
+            route.header.destination_prefix_length == 0 && route.header.kind == RouteType::Unicast
+        })
+        .filter_map(|route| {
+            let mut in_table = u32::from(route.header.table);
+            let mut gateway = None;
+            let mut oif = None;
+            let mut metric = 0;
+            for attr in &route.attributes {
+                match attr {
+                    RouteAttribute::Table(id) => in_table = *id,
+                    RouteAttribute::Gateway(RouteAddress::Inet(ip)) => {
+                        gateway = Some(IpAddr::V4(*ip))
+                    }
+                    RouteAttribute::Gateway(RouteAddress::Inet6(ip)) => {
+                        gateway = Some(IpAddr::V6(*ip))
+                    }
+                    RouteAttribute::Oif(index) => oif = Some(*index),
+                    RouteAttribute::Priority(priority) => metric = *priority,
+                    _ => {}
+                }
+            }
+
+            let family_matches = gateway.is_none_or(|gw| Family::of(gw) == family);
+            (in_table == table && family_matches).then_some((metric, gateway, oif?))
+        })
+        .collect();
+    defaults.sort_by_key(|(metric, _, _)| *metric);
+    defaults
+        .into_iter()
+        .map(|(metric, gateway, oif)| DefaultRoute {
+            gateway,
+            oif,
+            metric,
+        })
+        .collect()
+}
 
-    if let Some(line) = line {
-        let parts: Vec<&str> = line.split_whitespace().collect();
-        let mut gateway = None;
-        let mut interface = None;
+/// Routes from `expected` of the given family that have no route to the
+/// same destination in the main table of a route dump.
+#[cfg(target_os = "linux")]
+fn missing_routes<'a>(
+    expected: impl IntoIterator<Item = &'a RouteInfo>,
+    routes: &[rtnetlink::packet_route::route::RouteMessage],
+    family: Family,
+) -> Vec<RouteInfo> {
+    use rtnetlink::packet_route::route::RouteAddress;
+    use rtnetlink::packet_route::route::RouteAttribute;
+    use rtnetlink::packet_route::route::RouteHeader;
 
-        for i in 0..parts.len() {
-            if parts[i] == "via" && i + 1 < parts.len() {
-                gateway = parts[i + 1].parse::<Ipv4Addr>().ok().map(IpAddr::V4);
+    let present: Vec<IpNet> = routes
+        .iter()
+        .filter_map(|route| {
+            let mut table = u32::from(route.header.table);
+            let mut destination = None;
+            for attr in &route.attributes {
+                match attr {
+                    RouteAttribute::Table(id) => table = *id,
+                    RouteAttribute::Destination(RouteAddress::Inet(ip)) => {
+                        destination = Some(IpAddr::V4(*ip))
+                    }
+                    RouteAttribute::Destination(RouteAddress::Inet6(ip)) => {
+                        destination = Some(IpAddr::V6(*ip))
+                    }
+                    _ => {}
+                }
             }
-            if parts[i] == "dev" && i + 1 < parts.len() {
-                interface = Some(parts[i + 1].to_string());
+            if table != u32::from(RouteHeader::RT_TABLE_MAIN) {
+                return None;
             }
-        }
+            IpNet::new(destination?, route.header.destination_prefix_length).ok()
+        })
+        .collect();
+
+    expected
+        .into_iter()
+        .filter(|route| Family::of(route.destination.addr()) == family)
+        .filter(|route| !present.contains(&route.destination))
+        .cloned()
+        .collect()
+}
+
+/// Picks the subnets the kernel routes directly out of an interface: routes
+/// it installed itself for configured addresses, without a gateway. IPv6
+/// link-local is left out; it never leaves the link anyway. Returns each
+/// subnet with its output interface index.
+#[cfg(target_os = "linux")]
+fn select_connected_subnets(
+    routes: &[rtnetlink::packet_route::route::RouteMessage],
+    family: Family,
+) -> Vec<(IpNet, u32)> {
+    use rtnetlink::packet_route::route::RouteAddress;
+    use rtnetlink::packet_route::route::RouteAttribute;
+    use rtnetlink::packet_route::route::RouteHeader;
+    use rtnetlink::packet_route::route::RouteProtocol;
+    use rtnetlink::packet_route::route::RouteType;
+
+    routes
+        .iter()
+        .filter(|route| {
+            route.header.protocol == RouteProtocol::Kernel
+                && route.header.kind == RouteType::Unicast
+                && route.header.destination_prefix_length > 0
+        })
+        .filter_map(|route| {
+            let mut table = u32::from(route.header.table);
+            let mut destination = None;
+            let mut oif = None;
+            for attr in &route.attributes {
+                match attr {
+                    RouteAttribute::Table(id) => table = *id,
+                    RouteAttribute::Destination(RouteAddress::Inet(ip)) => {
+                        destination = Some(IpAddr::V4(*ip))
+                    }
+                    RouteAttribute::Destination(RouteAddress::Inet6(ip)) => {
+                        destination = Some(IpAddr::V6(*ip))
+                    }
+                    RouteAttribute::Gateway(_) => return None,
+                    RouteAttribute::Oif(index) => oif = Some(*index),
+                    _ => {}
+                }
+            }
+
+            let destination = destination.filter(|ip| Family::of(*ip) == family)?;
+            if table != u32::from(RouteHeader::RT_TABLE_MAIN)
+                || matches!(destination, IpAddr::V6(ip) if ip.is_unicast_link_local())
+            {
+                return None;
+            }
+            let net = IpNet::new(destination, route.header.destination_prefix_length).ok()?;
+            Some((net.trunc(), oif?))
+        })
+        .collect()
+}
+
+/// One change [`RoutingManager::setup`] makes; [`RoutingManager::plan`]
+/// shows the same list.
+#[cfg(target_os = "linux")]
+#[derive(Debug, Clone, PartialEq)]
+enum Step {
+    /// Keeps the SSH server off the tunnel, via the original gateway.
+    SshServer(RouteInfo),
+    /// Policy routing through `table` for `gateway`'s family.
+    Policy {
+        gateway: IpAddr,
+        table: u32,
+        marked_only: bool,
+    },
+    /// A default route via the tunnel besides the system's, with `metric`.
+    Metric { gateway: IpAddr, metric: u32 },
+    /// The tunnel's default route in place of the system's.
+    Default { gateway: IpAddr },
+    /// A route through the tunnel.
+    Include(RouteInfo),
+    /// A route around the tunnel, via the original gateway.
+    Exclusion(RouteInfo),
+    /// A connected subnet pinned to its interface, unless the kernel's own
+    /// route already is.
+    Lan(IpNet, String),
+}
 
-        if let Some(iface) = interface {
-            return Ok(Some(RouteInfo {
-                destination: "0.0.0.0/0".parse()?,
+#[cfg(target_os = "linux")]
+impl Step {
+    /// The `ip` command lines of this step.
+    fn commands(&self, tun_name: &str) -> Vec<String> {
+        match self {
+            Self::SshServer(route) | Self::Include(route) | Self::Exclusion(route) => {
+                vec![route_command(route)]
+            }
+            Self::Policy {
                 gateway,
-                interface: iface,
-            }));
+                table,
+                marked_only,
+            } => {
+                let family = Family::of(*gateway);
+                let (gateway, table_arg) = (gateway.to_string(), table.to_string());
+                let mut commands = vec![ip_line(family, "route", [
+                    "replace", "default", "via", &gateway, "dev", tun_name, "table", &table_arg,
+                ])];
+                for rule in policy_rules(*table, *marked_only) {
+                    let args = std::iter::once("add").chain(rule.iter().map(String::as_str));
+                    commands.push(ip_line(family, "rule", args));
+                }
+                commands
+            }
+            Self::Metric { gateway, metric } => vec![ip_line(Family::of(*gateway), "route", [
+                "add",
+                "default",
+                "via",
+                &gateway.to_string(),
+                "dev",
+                tun_name,
+                "metric",
+                &metric.to_string(),
+            ])],
+            Self::Default { gateway } => {
+                let family = Family::of(*gateway);
+                vec![
+                    ip_line(family, "route", ["del", "default"]),
+                    ip_line(family, "route", [
+                        "add",
+                        "default",
+                        "via",
+                        &gateway.to_string(),
+                        "dev",
+                        tun_name,
+                    ]),
+                ]
+            }
+            Self::Lan(net, interface) => {
+                let route = route_command(&RouteInfo {
+                    destination: *net,
+                    gateway: None,
+                    interface: interface.clone(),
+                });
+                vec![format!("{route}  # unless already routed")]
+            }
+        }
+    }
+}
+
+/// What [`RoutingManager::setup`] does for `config`, in order, given the
+/// default route of each family before the tunnel (`original`) and the
+/// connected subnets `exclude_lan` finds (`lan`), followed by routes for
+/// `through_tunnel`. A route via the original gateway of a family without
+/// one is left out.
+#[cfg(target_os = "linux")]
+fn steps(
+    config: &VpnConfig,
+    ssh_server_ip: IpAddr,
+    original: impl Fn(Family) -> Option<RouteInfo>,
+    lan: &[(IpNet, String)],
+    through_tunnel: &[IpNet],
+) -> anyhow::Result<Vec<Step>> {
+    let tun_name = &config.client_tun;
+    let server_ip = config.server_ip()?;
+    let server6 = config.ipv6_addresses()?.map(|(_, server6)| server6.addr());
+    let gateways: Vec<IpAddr> = [Some(server_ip), server6].into_iter().flatten().collect();
+    let via_tunnel = |net: IpNet| {
+        let gateway = match Family::of(net.addr()) {
+            Family::V4 => Some(server_ip),
+            Family::V6 => server6,
+        };
+        Step::Include(RouteInfo {
+            destination: net,
+            gateway,
+            interface: tun_name.clone(),
+        })
+    };
+    let via_original = |net: IpNet| {
+        let original = original(Family::of(net.addr()));
+        if original.is_none() {
+            debug!("No original default route for {}; not excluding it", net);
+        }
+        original.map(|original| RouteInfo {
+            destination: net,
+            gateway: original.gateway,
+            interface: original.interface,
+        })
+    };
+
+    let mut steps = Vec::new();
+    steps.extend(via_original(ssh_server_ip.into()).map(Step::SshServer));
+
+    let marked_only = config.cgroup.is_some();
+    if marked_only || !config.split_tunnel() && config.routing_mode == RoutingMode::Policy {
+        steps.extend(gateways.iter().map(|&gateway| Step::Policy {
+            gateway,
+            table: config.policy_table,
+            marked_only,
+        }));
+    } else if !config.split_tunnel() && config.routing_mode == RoutingMode::Metric {
+        steps.extend(gateways.iter().map(|&gateway| Step::Metric {
+            gateway,
+            metric: config.route_metric,
+        }));
+    } else if !config.split_tunnel() {
+        steps.extend(gateways.iter().map(|&gateway| Step::Default { gateway }));
+    } else {
+        for include in &config.include {
+            steps.push(via_tunnel(include.parse()?));
+        }
+    }
+
+    for exclusion in &config.exclude {
+        steps.extend(via_original(exclusion.parse()?).map(Step::Exclusion));
+    }
+    for route in config.static_routes()? {
+        match route.via {
+            RouteVia::Tun => steps.push(via_tunnel(route.destination)),
+            RouteVia::Lan => steps.extend(via_original(route.destination).map(Step::Exclusion)),
         }
     }
+    for (net, interface) in lan {
+        steps.push(Step::Lan(*net, interface.clone()));
+    }
+    for net in through_tunnel {
+        steps.push(via_tunnel(*net));
+    }
+    Ok(steps)
+}
+
+/// `ip route add` for `route`, as a command line.
+#[cfg(target_os = "linux")]
+fn route_command(route: &RouteInfo) -> String {
+    let destination = route.destination.to_string();
+    let mut args = vec!["add", &destination];
+    let gateway = route.gateway.map(|gateway| gateway.to_string());
+    if let Some(gateway) = &gateway {
+        args.extend(["via", gateway]);
+    }
+    args.extend(["dev", &route.interface]);
+    ip_line(Family::of(route.destination.addr()), "route", args)
+}
+
+/// An `ip` command line, e.g. `ip -4 route del default`.
+#[cfg(target_os = "linux")]
+fn ip_line<'a>(family: Family, object: &str, args: impl IntoIterator<Item = &'a str>) -> String {
+    let mut line = format!("ip {} {}", family.flag(), object);
+    for arg in args {
+        line.push(' ');
+        line.push_str(arg);
+    }
+    line
+}
+
+/// `ip rule` arguments for policy routing through `table`, like wg-quick:
+/// traffic without the table's fwmark looks up `table` (the TUN default
+/// route), except where the main table has something more specific than a
+/// default route (the SSH server, exclusions, the LAN). The second rule is
+/// added last so it takes precedence. With `marked_only`, only traffic
+/// carrying the fwmark looks up `table` instead.
+#[cfg(target_os = "linux")]
+fn policy_rules(table: u32, marked_only: bool) -> Vec<Vec<String>> {
+    let table = table.to_string();
+    if marked_only {
+        return vec![vec!["fwmark".into(), table.clone(), "table".into(), table]];
+    }
+    vec![
+        vec![
+            "not".into(),
+            "fwmark".into(),
+            table.clone(),
+            "table".into(),
+            table,
+        ],
+        vec![
+            "table".into(),
+            "main".into(),
+            "suppress_prefixlength".into(),
+            "0".into(),
+        ],
+    ]
+}
 
-    Ok(None)
+#[cfg(target_os = "linux")]
+fn ip_rule(family: Family) -> tokio::process::Command {
+    let mut cmd = tokio::process::Command::new("ip");
+    cmd.args([family.flag(), "rule"]);
+    cmd
 }
 
+/// Runs an `ip` command, failing with its stderr if it does.
 #[cfg(target_os = "linux")]
-async fn delete_default_route() -> anyhow::Result<()> {
-    tokio::process::Command::new("ip")
-        .args(["route", "del", "default"])
+async fn run_ip(mut cmd: tokio::process::Command) -> anyhow::Result<()> {
+    let output = cmd.output().await?;
+    if !output.status.success() {
+        anyhow::bail!(
+            "{:?} failed: {}",
+            cmd.as_std(),
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+async fn add_policy_routing(
+    tun_name: &str,
+    gateway: IpAddr,
+    table: u32,
+    marked_only: bool,
+) -> anyhow::Result<()> {
+    let family = Family::of(gateway);
+    // Clears rules left behind by a previous run that did not clean up.
+    remove_policy_routing(family, table, marked_only).await?;
+
+    let mut route = ip_route(family);
+    route.args([
+        "replace",
+        "default",
+        "via",
+        &gateway.to_string(),
+        "dev",
+        tun_name,
+        "table",
+        &table.to_string(),
+    ]);
+    run_ip(route).await?;
+
+    for rule in policy_rules(table, marked_only) {
+        let mut cmd = ip_rule(family);
+        cmd.arg("add").args(rule);
+        run_ip(cmd).await?;
+    }
+    Ok(())
+}
+
+/// Removes the rules and flushes `table`. Missing rules are not an error.
+#[cfg(target_os = "linux")]
+async fn remove_policy_routing(
+    family: Family,
+    table: u32,
+    marked_only: bool,
+) -> anyhow::Result<()> {
+    for rule in policy_rules(table, marked_only) {
+        // `ip rule del` removes one match per call; duplicates are possible
+        // after repeated crashes.
+        loop {
+            let output = ip_rule(family).arg("del").args(&rule).output().await?;
+            if !output.status.success() {
+                break;
+            }
+        }
+    }
+    ip_route(family)
+        .args(["flush", "table", &table.to_string()])
         .output()
         .await?;
     Ok(())
 }
 
+#[cfg(target_os = "linux")]
+async fn delete_default_route(family: Family) -> anyhow::Result<()> {
+    ip_route(family).args(["del", "default"]).output().await?;
+    Ok(())
+}
+
 #[cfg(target_os = "linux")]
 async fn add_default_route(gateway: IpAddr, interface: &str) -> anyhow::Result<()> {
-    tokio::process::Command::new("ip")
+    ip_route(Family::of(gateway))
         .args([
-            "route",
             "add",
             "default",
             "via",
@@ -201,6 +1424,42 @@ async fn add_default_route(gateway: IpAddr, interface: &str) -> anyhow::Result<(
     Ok(())
 }
 
+/// Adds a default route via `gateway` on `tun_name` next to the system's,
+/// replacing one of the same metric left behind by a previous run.
+#[cfg(target_os = "linux")]
+async fn add_metric_route(tun_name: &str, gateway: IpAddr, metric: u32) -> anyhow::Result<()> {
+    let mut cmd = ip_route(Family::of(gateway));
+    cmd.args([
+        "replace",
+        "default",
+        "via",
+        &gateway.to_string(),
+        "dev",
+        tun_name,
+        "metric",
+        &metric.to_string(),
+    ]);
+    run_ip(cmd).await
+}
+
+/// Removes the tunnel's default route added by [`add_metric_route`]; the
+/// system's stays. Gone already (with the TUN device) is not an error.
+#[cfg(target_os = "linux")]
+async fn delete_metric_route(family: Family, tun_name: &str, metric: u32) -> anyhow::Result<()> {
+    ip_route(family)
+        .args([
+            "del",
+            "default",
+            "dev",
+            tun_name,
+            "metric",
+            &metric.to_string(),
+        ])
+        .output()
+        .await?;
+    Ok(())
+}
+
 #[cfg(target_os = "linux")]
 async fn add_route_via_gateway(
     dest: impl Into<IpNet>,
@@ -208,11 +1467,11 @@ async fn add_route_via_gateway(
     interface: &str,
 ) -> anyhow::Result<()> {
     let dest = dest.into();
+    let family = Family::of(dest.addr());
 
     if let Some(gw) = gateway {
-        tokio::process::Command::new("ip")
+        ip_route(family)
             .args([
-                "route",
                 "add",
                 &dest.to_string(),
                 "via",
@@ -223,8 +1482,8 @@ async fn add_route_via_gateway(
             .output()
             .await?;
     } else {
-        tokio::process::Command::new("ip")
-            .args(["route", "add", &dest.to_string(), "dev", interface])
+        ip_route(family)
+            .args(["add", &dest.to_string(), "dev", interface])
             .output()
             .await?;
     }
@@ -232,11 +1491,479 @@ async fn add_route_via_gateway(
     Ok(())
 }
 
+/// Installs `route` with `ip route replace`, so it does not matter whether
+/// a route to its destination is still there.
+#[cfg(target_os = "linux")]
+async fn replace_route(route: &RouteInfo) -> anyhow::Result<()> {
+    let mut cmd = ip_route(Family::of(route.destination.addr()));
+    cmd.args(["replace", &route.destination.to_string()]);
+    if let Some(gateway) = route.gateway {
+        cmd.args(["via", &gateway.to_string()]);
+    }
+    cmd.args(["dev", &route.interface]);
+    run_ip(cmd).await
+}
+
 #[cfg(target_os = "linux")]
 async fn delete_route(dest: IpNet) -> anyhow::Result<()> {
-    tokio::process::Command::new("ip")
-        .args(["route", "del", &dest.to_string()])
+    ip_route(Family::of(dest.addr()))
+        .args(["del", &dest.to_string()])
         .output()
         .await?;
     Ok(())
 }
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use std::net::Ipv4Addr;
+    use std::net::Ipv6Addr;
+
+    use rtnetlink::packet_route::AddressFamily;
+    use rtnetlink::packet_route::route::RouteAddress;
+    use rtnetlink::packet_route::route::RouteAttribute;
+    use rtnetlink::packet_route::route::RouteHeader;
+    use rtnetlink::packet_route::route::RouteMessage;
+    use rtnetlink::packet_route::route::RouteProtocol;
+    use rtnetlink::packet_route::route::RouteType;
+
+    use super::*;
+
+    /// Builds a route as the kernel reports it in a `RTM_GETROUTE` dump.
+    fn route(prefix_len: u8, table: u8, attributes: Vec<RouteAttribute>) -> RouteMessage {
+        let mut message = RouteMessage::default();
+        message.header.address_family = AddressFamily::Inet;
+        message.header.destination_prefix_length = prefix_len;
+        message.header.table = table;
+        message.header.kind = RouteType::Unicast;
+        message.attributes = attributes;
+        message
+    }
+
+    fn main_table() -> u8 {
+        RouteHeader::RT_TABLE_MAIN
+    }
+
+    #[test]
+    fn test_select_default_route_dhcp_laptop() {
+        // default via 192.168.1.1 dev wlan0 proto dhcp metric 600
+        // 192.168.1.0/24 dev wlan0 proto kernel scope link
+        let routes = vec![
+            route(0, main_table(), vec![
+                RouteAttribute::Table(254),
+                RouteAttribute::Priority(600),
+                RouteAttribute::Gateway(RouteAddress::Inet(Ipv4Addr::new(192, 168, 1, 1))),
+                RouteAttribute::Oif(3),
+            ]),
+            route(24, main_table(), vec![RouteAttribute::Oif(3)]),
+        ];
+
+        assert_eq!(
+            select_default_route(&routes, Family::V4),
+            Some((Some(IpAddr::V4(Ipv4Addr::new(192, 168, 1, 1))), 3))
+        );
+    }
+
+    #[test]
+    fn test_select_default_route_lowest_metric_wins() {
+        // default via 10.0.0.1 dev eth0 metric 100
+        // default via 192.168.1.1 dev wlan0 metric 600
+        let routes = vec![
+            route(0, main_table(), vec![
+                RouteAttribute::Priority(600),
+                RouteAttribute::Gateway(RouteAddress::Inet(Ipv4Addr::new(192, 168, 1, 1))),
+                RouteAttribute::Oif(3),
+            ]),
+            route(0, main_table(), vec![
+                RouteAttribute::Priority(100),
+                RouteAttribute::Gateway(RouteAddress::Inet(Ipv4Addr::new(10, 0, 0, 1))),
+                RouteAttribute::Oif(2),
+            ]),
+        ];
+
+        assert_eq!(
+            select_default_route(&routes, Family::V4),
+            Some((Some(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1))), 2))
+        );
+    }
+
+    #[test]
+    fn test_select_default_route_ignores_other_tables_and_types() {
+        // default dev wg0 table 51820 (policy-routed VPN)
+        // unreachable default metric 4278198272
+        // default dev ppp0 scope link
+        let mut unreachable = route(0, main_table(), vec![RouteAttribute::Priority(1)]);
+        unreachable.header.kind = RouteType::Unreachable;
+        let routes = vec![
+            route(0, 0, vec![
+                RouteAttribute::Table(51820),
+                RouteAttribute::Oif(7),
+            ]),
+            unreachable,
+            route(0, main_table(), vec![
+                RouteAttribute::Priority(50),
+                RouteAttribute::Oif(5),
+            ]),
+        ];
+
+        assert_eq!(select_default_route(&routes, Family::V4), Some((None, 5)));
+    }
+
+    #[test]
+    fn test_select_default_route_v6_link_local_gateway() {
+        // default via fe80::1 dev eth0 proto ra metric 1024 pref medium
+        let mut message = route(0, main_table(), vec![
+            RouteAttribute::Priority(1024),
+            RouteAttribute::Gateway(RouteAddress::Inet6(Ipv6Addr::new(
+                0xfe80, 0, 0, 0, 0, 0, 0, 1,
+            ))),
+            RouteAttribute::Oif(2),
+        ]);
+        message.header.address_family = AddressFamily::Inet6;
+
+        assert_eq!(
+            select_default_route(&[message], Family::V6),
+            Some((Some("fe80::1".parse().unwrap()), 2))
+        );
+    }
+
+    #[test]
+    fn test_select_connected_subnets() {
+        // default via 192.168.1.1 dev wlan0 proto dhcp metric 600
+        // 192.168.1.0/24 dev wlan0 proto kernel scope link src 192.168.1.5
+        // 172.17.0.0/16 dev docker0 proto kernel scope link src 172.17.0.1
+        // 10.1.0.0/16 via 192.168.1.254 dev wlan0 proto kernel
+        // 192.168.1.1 dev wlan0 proto dhcp scope link
+        let kernel = |prefix_len, attributes| {
+            let mut message = route(prefix_len, main_table(), attributes);
+            message.header.protocol = RouteProtocol::Kernel;
+            message
+        };
+        let destination =
+            |a, b, c, d| RouteAttribute::Destination(RouteAddress::Inet(Ipv4Addr::new(a, b, c, d)));
+        let routes = vec![
+            route(0, main_table(), vec![
+                RouteAttribute::Gateway(RouteAddress::Inet(Ipv4Addr::new(192, 168, 1, 1))),
+                RouteAttribute::Oif(3),
+            ]),
+            kernel(24, vec![
+                destination(192, 168, 1, 0),
+                RouteAttribute::Oif(3),
+            ]),
+            kernel(16, vec![destination(172, 17, 0, 0), RouteAttribute::Oif(4)]),
+            kernel(16, vec![
+                destination(10, 1, 0, 0),
+                RouteAttribute::Gateway(RouteAddress::Inet(Ipv4Addr::new(192, 168, 1, 254))),
+                RouteAttribute::Oif(3),
+            ]),
+            route(32, main_table(), vec![
+                destination(192, 168, 1, 1),
+                RouteAttribute::Oif(3),
+            ]),
+        ];
+
+        assert_eq!(select_connected_subnets(&routes, Family::V4), vec![
+            ("192.168.1.0/24".parse().unwrap(), 3),
+            ("172.17.0.0/16".parse().unwrap(), 4),
+        ]);
+        assert!(select_connected_subnets(&routes, Family::V6).is_empty());
+    }
+
+    #[test]
+    fn test_select_connected_subnets_v6_skips_link_local() {
+        // 2001:db8:1::/64 dev eth0 proto kernel metric 256
+        // fe80::/64 dev eth0 proto kernel metric 256
+        let kernel = |ip: &str| {
+            let mut message = route(64, main_table(), vec![
+                RouteAttribute::Destination(RouteAddress::Inet6(ip.parse().unwrap())),
+                RouteAttribute::Oif(2),
+            ]);
+            message.header.address_family = AddressFamily::Inet6;
+            message.header.protocol = RouteProtocol::Kernel;
+            message
+        };
+        let routes = vec![kernel("2001:db8:1::"), kernel("fe80::")];
+
+        assert_eq!(select_connected_subnets(&routes, Family::V6), vec![(
+            "2001:db8:1::/64".parse().unwrap(),
+            2
+        )]);
+    }
+
+    #[test]
+    fn test_default_routes_by_table_and_metric() {
+        // default dev tun-x2ssh metric 0
+        // default via 192.168.1.1 dev wlan0 proto dhcp metric 600
+        // default via 10.8.0.1 dev tun-x2ssh table 30770
+        let routes = vec![
+            route(0, main_table(), vec![
+                RouteAttribute::Priority(600),
+                RouteAttribute::Gateway(RouteAddress::Inet(Ipv4Addr::new(192, 168, 1, 1))),
+                RouteAttribute::Oif(3),
+            ]),
+            route(0, main_table(), vec![RouteAttribute::Oif(9)]),
+            route(0, 0, vec![
+                RouteAttribute::Table(30770),
+                RouteAttribute::Gateway(RouteAddress::Inet(Ipv4Addr::new(10, 8, 0, 1))),
+                RouteAttribute::Oif(9),
+            ]),
+        ];
+
+        assert_eq!(default_routes(&routes, Family::V4, 254), vec![
+            DefaultRoute {
+                gateway: None,
+                oif: 9,
+                metric: 0
+            },
+            DefaultRoute {
+                gateway: Some(IpAddr::V4(Ipv4Addr::new(192, 168, 1, 1))),
+                oif: 3,
+                metric: 600
+            },
+        ]);
+        assert_eq!(default_routes(&routes, Family::V4, 30770), vec![
+            DefaultRoute {
+                gateway: Some(IpAddr::V4(Ipv4Addr::new(10, 8, 0, 1))),
+                oif: 9,
+                metric: 0
+            }
+        ]);
+        assert!(default_routes(&routes, Family::V4, 100).is_empty());
+    }
+
+    #[test]
+    fn test_missing_routes() {
+        // 203.0.113.10 via 192.168.1.1 dev wlan0
+        // 10.0.0.0/8 via 192.168.1.1 dev wlan0 table 100
+        let routes = vec![
+            route(32, main_table(), vec![
+                RouteAttribute::Destination(RouteAddress::Inet(Ipv4Addr::new(203, 0, 113, 10))),
+                RouteAttribute::Oif(3),
+            ]),
+            route(8, 100, vec![
+                RouteAttribute::Destination(RouteAddress::Inet(Ipv4Addr::new(10, 0, 0, 0))),
+                RouteAttribute::Oif(3),
+            ]),
+        ];
+        let via_wlan = |destination: &str| RouteInfo {
+            destination: destination.parse().unwrap(),
+            gateway: Some(IpAddr::V4(Ipv4Addr::new(192, 168, 1, 1))),
+            interface: "wlan0".into(),
+        };
+        let expected = [
+            via_wlan("203.0.113.10/32"),
+            via_wlan("10.0.0.0/8"),
+            via_wlan("198.51.100.0/24"),
+            RouteInfo {
+                destination: "fd00:1::/48".parse().unwrap(),
+                gateway: None,
+                interface: "wlan0".into(),
+            },
+        ];
+
+        assert_eq!(missing_routes(&expected, &routes, Family::V4), vec![
+            via_wlan("10.0.0.0/8"),
+            via_wlan("198.51.100.0/24"),
+        ]);
+        assert_eq!(missing_routes(&expected, &routes, Family::V6), vec![
+            expected[3].clone()
+        ]);
+    }
+
+    #[test]
+    fn test_route_info_display() {
+        let route = RouteInfo {
+            destination: "0.0.0.0/0".parse().unwrap(),
+            gateway: Some(IpAddr::V4(Ipv4Addr::new(192, 168, 1, 1))),
+            interface: "wlan0".into(),
+        };
+        assert_eq!(route.to_string(), "0.0.0.0/0 via 192.168.1.1 dev wlan0");
+    }
+
+    #[test]
+    fn test_state_file_round_trip() {
+        let state = RoutingState {
+            original_default_route: Some(RouteInfo {
+                destination: Family::V4.default_destination(),
+                gateway: Some(IpAddr::V4(Ipv4Addr::new(192, 168, 1, 1))),
+                interface: "wlan0".into(),
+            }),
+            full_tunnel: true,
+            policy: Some((30770, vec![Family::V4, Family::V6])),
+            tunnel: Some(Tunnel {
+                interface: "tun-x2ssh".into(),
+                gateway: IpAddr::V4(Ipv4Addr::new(10, 8, 0, 1)),
+                gateway6: Some("fd00:8::1".parse().unwrap()),
+            }),
+            exclusion_routes: vec![RouteInfo {
+                destination: "10.0.0.0/8".parse().unwrap(),
+                gateway: Some(IpAddr::V4(Ipv4Addr::new(192, 168, 1, 1))),
+                interface: "wlan0".into(),
+            }],
+            lan_subnets: vec!["192.168.1.0/24".parse().unwrap()],
+            ..Default::default()
+        };
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("run").join("routes-tun-x2ssh.json");
+        write_state(&path, &state).unwrap();
+        write_state(&path, &state).unwrap();
+
+        let read: RoutingState =
+            serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(read, state);
+        assert_eq!(
+            std::fs::read_dir(path.parent().unwrap()).unwrap().count(),
+            1
+        );
+    }
+
+    #[test]
+    fn test_state_routes() {
+        let wlan = |destination: &str| RouteInfo {
+            destination: destination.parse().unwrap(),
+            gateway: Some(IpAddr::V4(Ipv4Addr::new(192, 168, 1, 1))),
+            interface: "wlan0".into(),
+        };
+        let tunnel = Tunnel {
+            interface: "tun-x2ssh".into(),
+            gateway: IpAddr::V4(Ipv4Addr::new(10, 8, 0, 1)),
+            gateway6: Some("fd00:8::1".parse().unwrap()),
+        };
+        let state = RoutingState {
+            full_tunnel: true,
+            ipv6: true,
+            tunnel: Some(tunnel),
+            ssh_routes: vec![wlan("203.0.113.7/32")],
+            exclusion_routes: vec![wlan("10.0.0.0/8")],
+            ..Default::default()
+        };
+        assert_eq!(state.routes(), [
+            "0.0.0.0/0 via 10.8.0.1 dev tun-x2ssh",
+            "::/0 via fd00:8::1 dev tun-x2ssh",
+            "203.0.113.7/32 via 192.168.1.1 dev wlan0",
+            "10.0.0.0/8 via 192.168.1.1 dev wlan0",
+        ]);
+
+        let state = RoutingState {
+            policy: Some((30770, vec![Family::V4])),
+            ..state
+        };
+        assert_eq!(state.routes()[..2], [
+            "0.0.0.0/0 via 10.8.0.1 dev tun-x2ssh",
+            "::/0 via fd00:8::1 dev tun-x2ssh",
+        ]);
+        let state = RoutingState {
+            full_tunnel: false,
+            ipv6: false,
+            ..state
+        };
+        assert_eq!(
+            state.routes()[0],
+            "0.0.0.0/0 via 10.8.0.1 dev tun-x2ssh table 30770"
+        );
+        assert!(RoutingState::default().routes().is_empty());
+    }
+
+    #[test]
+    fn test_state_file_path() {
+        assert_eq!(
+            state_file("tun-x2ssh"),
+            Path::new("/run/x2ssh/routes-tun-x2ssh.json")
+        );
+    }
+
+    #[test]
+    fn test_policy_rules() {
+        let rules = policy_rules(30770, false);
+        let [not_marked, main] = rules.as_slice() else {
+            panic!("expected two rules, got {rules:?}");
+        };
+        assert_eq!(not_marked.join(" "), "not fwmark 30770 table 30770");
+        assert_eq!(main.join(" "), "table main suppress_prefixlength 0");
+
+        let rules = policy_rules(30770, true);
+        assert_eq!(rules.len(), 1);
+        assert_eq!(rules[0].join(" "), "fwmark 30770 table 30770");
+    }
+
+    fn plan_commands(
+        config: &VpnConfig,
+        ssh_server_ip: IpAddr,
+        original: impl Fn(Family) -> Option<RouteInfo>,
+        lan: &[(IpNet, String)],
+        through_tunnel: &[IpNet],
+    ) -> anyhow::Result<Vec<String>> {
+        let steps = steps(config, ssh_server_ip, original, lan, through_tunnel)?;
+        Ok(steps
+            .iter()
+            .flat_map(|step| step.commands(&config.client_tun))
+            .collect())
+    }
+
+    #[test]
+    fn test_plan_commands() {
+        let ssh_server = "203.0.113.7".parse().unwrap();
+        let original = |family: Family| {
+            (family == Family::V4).then(|| RouteInfo {
+                destination: family.default_destination(),
+                gateway: Some("192.168.1.1".parse().unwrap()),
+                interface: "wlan0".to_string(),
+            })
+        };
+        let lan = [("192.168.1.0/24".parse().unwrap(), "wlan0".to_string())];
+        let nat64 = ["64:ff9b::/96".parse().unwrap()];
+        let config = VpnConfig {
+            exclude: vec!["10.0.0.0/8".to_string()],
+            ..Default::default()
+        };
+        assert_eq!(
+            plan_commands(&config, ssh_server, original, &lan, &nat64).unwrap(),
+            [
+                "ip -4 route add 203.0.113.7/32 via 192.168.1.1 dev wlan0",
+                "ip -4 route del default",
+                "ip -4 route add default via 10.8.0.1 dev tun-x2ssh",
+                "ip -4 route add 10.0.0.0/8 via 192.168.1.1 dev wlan0",
+                "ip -4 route add 192.168.1.0/24 dev wlan0  # unless already routed",
+                "ip -6 route add 64:ff9b::/96 dev tun-x2ssh",
+            ]
+        );
+
+        let config = VpnConfig {
+            cgroup: Some("x2ssh".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(
+            plan_commands(&config, ssh_server, |_| None, &[], &[]).unwrap(),
+            [
+                "ip -4 route replace default via 10.8.0.1 dev tun-x2ssh table 30770",
+                "ip -4 rule add fwmark 30770 table 30770",
+            ]
+        );
+
+        let config = VpnConfig {
+            include: vec!["10.1.0.0/16".to_string()],
+            ..Default::default()
+        };
+        assert_eq!(
+            plan_commands(&config, ssh_server, |_| None, &[], &[]).unwrap(),
+            ["ip -4 route add 10.1.0.0/16 via 10.8.0.1 dev tun-x2ssh"]
+        );
+
+        let config = VpnConfig {
+            routing_mode: RoutingMode::Metric,
+            route_metric: 50,
+            ..Default::default()
+        };
+        assert_eq!(
+            plan_commands(&config, ssh_server, |_| None, &[], &[]).unwrap(),
+            ["ip -4 route add default via 10.8.0.1 dev tun-x2ssh metric 50"]
+        );
+    }
+
+    #[test]
+    fn test_select_default_route_none() {
+        let routes = vec![route(24, main_table(), vec![RouteAttribute::Oif(3)])];
+        assert_eq!(select_default_route(&routes, Family::V4), None);
+        assert_eq!(select_default_route(&[], Family::V6), None);
+    }
+}
@@ -0,0 +1,353 @@
+use std::net::IpAddr;
+use std::net::SocketAddr;
+use std::time::Duration;
+use std::time::Instant;
+
+use tokio::io::AsyncReadExt;
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+use tracing::info;
+use tracing::warn;
+
+use crate::config::VpnConfig;
+
+/// How long the HTTP check may take, from resolving the host to the
+/// status line.
+const FETCH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Where traffic stopped, in the order the self-test looks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Stage {
+    /// The client TUN device is gone.
+    Tun,
+    /// The server's tunnel address does not answer: the agent, its channel
+    /// or the server TUN device.
+    Agent,
+    /// The URL's host does not resolve.
+    Dns,
+    /// The URL's address is not routed into the client TUN device.
+    Routing,
+    /// The server is reached through the tunnel, but nothing beyond it:
+    /// forwarding or masquerading on the server.
+    Nat,
+}
+
+impl std::fmt::Display for Stage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Stage::Tun => "TUN",
+            Stage::Agent => "agent",
+            Stage::Dns => "DNS",
+            Stage::Routing => "routing",
+            Stage::Nat => "NAT",
+        })
+    }
+}
+
+/// A self-test that stopped at `stage`.
+#[derive(Debug)]
+struct Failure {
+    stage: Stage,
+    detail: String,
+}
+
+impl Failure {
+    fn new(stage: Stage, detail: impl Into<String>) -> Self {
+        Self {
+            stage,
+            detail: detail.into(),
+        }
+    }
+}
+
+/// A passed self-test.
+#[derive(Debug)]
+struct Report {
+    /// Round trip of a ping to the server's tunnel address.
+    ping: Duration,
+    /// Until the status line of the fetch (or the connection, for HTTPS).
+    fetch: Duration,
+    /// HTTP status of the fetch; `None` for HTTPS.
+    status: Option<u16>,
+}
+
+/// Runs the self-test once forwarding is running and logs the outcome.
+pub async fn run_and_report(config: &VpnConfig) {
+    let url = match Url::parse(&config.self_test_url) {
+        Ok(url) => url,
+        Err(e) => {
+            warn!("Self-test skipped: {:#}", e);
+            return;
+        }
+    };
+    match run(config, &url).await {
+        Ok(report) => {
+            let status = report
+                .status
+                .map(|status| format!("HTTP {status}"))
+                .unwrap_or_else(|| "connected".to_string());
+            info!(
+                "Self-test passed: ping {} in {} ms, {} {} in {} ms",
+                config.server_address,
+                report.ping.as_millis(),
+                config.self_test_url,
+                status,
+                report.fetch.as_millis()
+            );
+        }
+        Err(failure) => warn!("Self-test failed at {}: {}", failure.stage, failure.detail),
+    }
+}
+
+/// Checks that traffic flows, stage by stage: the client TUN device, the
+/// server's tunnel address, then `self_test_url` resolved, routed into the
+/// tunnel and fetched.
+async fn run(config: &VpnConfig, url: &Url) -> Result<Report, Failure> {
+    let tun = &config.client_tun;
+
+    if !std::path::Path::new("/sys/class/net").join(tun).exists() {
+        return Err(Failure::new(
+            Stage::Tun,
+            format!("the client TUN device {tun} is gone"),
+        ));
+    }
+
+    let server_ip = config
+        .server_ip()
+        .map_err(|e| Failure::new(Stage::Agent, e.to_string()))?;
+    let ping = ping(server_ip).await.map_err(|e| {
+        Failure::new(
+            Stage::Agent,
+            format!("the server's tunnel address {server_ip} does not answer ping: {e:#}"),
+        )
+    })?;
+
+    let started = Instant::now();
+    let addr = tokio::net::lookup_host((url.host.as_str(), url.port))
+        .await
+        .ok()
+        .and_then(|mut addrs| addrs.next())
+        .ok_or_else(|| Failure::new(Stage::Dns, format!("{} does not resolve", url.host)))?;
+
+    if !config.persistent_tun {
+        match route_device(addr.ip()).await {
+            Ok(device) if device == *tun => {}
+            Ok(device) => {
+                return Err(Failure::new(
+                    Stage::Routing,
+                    format!("{} is routed via {device}, not {tun}", addr.ip()),
+                ));
+            }
+            Err(e) => return Err(Failure::new(Stage::Routing, format!("{e:#}"))),
+        }
+    }
+
+    let status = tokio::time::timeout(FETCH_TIMEOUT, fetch(addr, url))
+        .await
+        .unwrap_or_else(|_| Err(anyhow::anyhow!("timed out")))
+        .map_err(|e| {
+            Failure::new(
+                Stage::Nat,
+                format!(
+                    "the server answers through the tunnel, but {} does not ({e:#}); check \
+                     forwarding and masquerading (nat) on the server",
+                    config.self_test_url
+                ),
+            )
+        })?;
+
+    Ok(Report {
+        ping,
+        fetch: started.elapsed(),
+        status,
+    })
+}
+
+/// Fails if `self_test_url` is not a URL the self-test can fetch.
+pub fn check_url(url: &str) -> anyhow::Result<()> {
+    Url::parse(url).map(|_| ())
+}
+
+/// Pings `ip` once with the system's `ping`, returning the round trip it
+/// reports.
+async fn ping(ip: IpAddr) -> anyhow::Result<Duration> {
+    let started = Instant::now();
+    let output = tokio::process::Command::new("ping")
+        .args(["-c", "1", "-W", "2", &ip.to_string()])
+        .output()
+        .await
+        .map_err(|e| anyhow::anyhow!("failed to run ping: {}", e))?;
+    if !output.status.success() {
+        anyhow::bail!("no reply");
+    }
+    Ok(parse_ping_time(&String::from_utf8_lossy(&output.stdout))
+        .unwrap_or_else(|| started.elapsed()))
+}
+
+/// The round trip from `ping` output: `... time=23.4 ms`.
+fn parse_ping_time(output: &str) -> Option<Duration> {
+    let (_, rest) = output.split_once("time=")?;
+    let millis: f64 = rest.split_whitespace().next()?.parse().ok()?;
+    Some(Duration::from_secs_f64(millis / 1000.0))
+}
+
+/// The device the kernel routes `ip` out of, from `ip route get`.
+async fn route_device(ip: IpAddr) -> anyhow::Result<String> {
+    let output = tokio::process::Command::new("ip")
+        .args(["route", "get", &ip.to_string()])
+        .output()
+        .await?;
+    if !output.status.success() {
+        anyhow::bail!(
+            "no route to {}: {}",
+            ip,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    parse_route_device(&String::from_utf8_lossy(&output.stdout))
+        .ok_or_else(|| anyhow::anyhow!("no route to {} through a device", ip))
+}
+
+fn parse_route_device(output: &str) -> Option<String> {
+    let mut words = output.split_whitespace();
+    words.find(|word| *word == "dev")?;
+    words.next().map(str::to_string)
+}
+
+/// Connects to `addr` and, for `http://`, sends a GET for the URL's path
+/// and returns the response status.
+async fn fetch(addr: SocketAddr, url: &Url) -> anyhow::Result<Option<u16>> {
+    let mut stream = TcpStream::connect(addr).await?;
+    if url.https {
+        return Ok(None);
+    }
+
+    let request = format!(
+        "GET {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: x2ssh\r\nConnection: close\r\n\r\n",
+        url.path, url.host
+    );
+    stream.write_all(request.as_bytes()).await?;
+    let mut response = Vec::new();
+    let mut buf = [0u8; 512];
+    while !response.contains(&b'\n') {
+        let n = stream.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        response.extend_from_slice(&buf[..n]);
+    }
+    let status_line = String::from_utf8_lossy(&response);
+    let status = status_line
+        .strip_prefix("HTTP/")
+        .and_then(|rest| rest.split_whitespace().nth(1))
+        .and_then(|status| status.parse().ok())
+        .ok_or_else(|| anyhow::anyhow!("not an HTTP response"))?;
+    Ok(Some(status))
+}
+
+/// The parts of `self_test_url` the self-test needs.
+#[derive(Debug, PartialEq)]
+struct Url {
+    https: bool,
+    host: String,
+    port: u16,
+    path: String,
+}
+
+impl Url {
+    fn parse(url: &str) -> anyhow::Result<Self> {
+        let (https, rest) = if let Some(rest) = url.strip_prefix("http://") {
+            (false, rest)
+        } else if let Some(rest) = url.strip_prefix("https://") {
+            (true, rest)
+        } else {
+            anyhow::bail!("self_test_url must start with http:// or https://, got '{url}'");
+        };
+        let (authority, path) = match rest.find('/') {
+            Some(at) => rest.split_at(at),
+            None => (rest, "/"),
+        };
+        let default_port = if https { 443 } else { 80 };
+        let (host, port) = match authority.rsplit_once(':') {
+            Some((host, port)) if !port.contains(']') => (
+                host,
+                port.parse()
+                    .map_err(|_| anyhow::anyhow!("invalid port in self_test_url '{url}'"))?,
+            ),
+            _ => (authority, default_port),
+        };
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        if host.is_empty() {
+            anyhow::bail!("self_test_url '{url}' has no host");
+        }
+        Ok(Self {
+            https,
+            host: host.to_string(),
+            port,
+            path: path.to_string(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_url() {
+        assert_eq!(Url::parse("http://example.com").unwrap(), Url {
+            https: false,
+            host: "example.com".to_string(),
+            port: 80,
+            path: "/".to_string(),
+        });
+        assert_eq!(
+            Url::parse("https://[2001:db8::1]:8443/health?x=1").unwrap(),
+            Url {
+                https: true,
+                host: "2001:db8::1".to_string(),
+                port: 8443,
+                path: "/health?x=1".to_string(),
+            }
+        );
+        assert!(Url::parse("ftp://example.com/").is_err());
+        assert!(Url::parse("http://example.com:http/").is_err());
+        assert!(Url::parse("http:///").is_err());
+    }
+
+    #[test]
+    fn test_parse_command_output() {
+        let ping = "64 bytes from 10.8.0.1: icmp_seq=1 ttl=64 time=23.4 ms\n";
+        assert_eq!(parse_ping_time(ping), Some(Duration::from_secs_f64(0.0234)));
+        assert_eq!(parse_ping_time("1 packets transmitted"), None);
+
+        let route = "93.184.216.34 via 10.8.0.1 dev tun-x2ssh src 10.8.0.2 uid 0 \n    cache\n";
+        assert_eq!(parse_route_device(route).as_deref(), Some("tun-x2ssh"));
+        assert_eq!(parse_route_device("local 127.0.0.1"), None);
+    }
+
+    #[tokio::test]
+    async fn test_fetch_reads_status() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut request = [0u8; 256];
+            let n = socket.read(&mut request).await.unwrap();
+            socket
+                .write_all(b"HTTP/1.1 204 No Content\r\n\r\n")
+                .await
+                .unwrap();
+            String::from_utf8_lossy(&request[..n]).into_owned()
+        });
+
+        let url = Url::parse(&format!("http://localhost:{}/generate_204", addr.port())).unwrap();
+        assert_eq!(fetch(addr, &url).await.unwrap(), Some(204));
+        assert!(
+            server
+                .await
+                .unwrap()
+                .starts_with("GET /generate_204 HTTP/1.1\r\nHost: localhost\r\n")
+        );
+    }
+}
@@ -1,23 +1,76 @@
+use std::borrow::Cow;
+use std::hash::Hash;
+use std::hash::Hasher;
 use std::net::IpAddr;
+use std::path::Path;
 use std::sync::Arc;
+use std::time::Duration;
+use std::time::Instant;
 
+use ipnet::IpNet;
+use proto::Features;
+use proto::gso;
+use proto::gso::MAX_GSO_FRAME;
+use proto::gso::VNET_HDR_LEN;
+use tokio::sync::mpsc;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::task::JoinSet;
+use tokio_util::sync::CancellationToken;
 use tracing::debug;
 use tracing::error;
 use tracing::info;
+use tracing::warn;
 
 use super::agent;
+use super::cgroup::CgroupRouting;
+use super::dns64::Dns64Resolver;
+use super::domains::DomainRouter;
+use super::domains::DomainRules;
+use super::elevation::RootAccess;
+use super::filter;
+use super::filter::PacketFilter;
 use super::hooks;
+use super::killswitch::KillSwitch;
+use super::nat64::Nat64;
+use super::pcap::Capture;
+use super::pcap::Pcap;
+use super::routing;
 use super::routing::RoutingManager;
+use super::tun::PacketDevice;
 use super::tun::TunDevice;
+use crate::config::QueuePolicy;
 use crate::config::VpnConfig;
+use crate::metrics::Metric;
+use crate::metrics::MetricsSink;
+use crate::metrics::Stage;
+use crate::shutdown::Shutdown;
+use crate::status::TimelineEvent;
 use crate::transport::Transport;
 
 pub struct VpnSession {
-    tun: Arc<TunDevice>,
-    routing: RoutingManager,
+    /// One device per TUN queue.
+    tun: Vec<Arc<TunDevice>>,
+    routing: Arc<tokio::sync::Mutex<RoutingManager>>,
+    domains: Option<Arc<DomainRouter>>,
+    nat64: Option<Arc<Nat64>>,
+    dns64: Option<Dns64Resolver>,
+    metrics: Arc<dyn MetricsSink>,
+    keepalive: Keepalive,
+    queueing: Queueing,
+    agent_path: agent::AgentPath,
+    root: RootAccess,
     agent: agent::AgentChannel,
+    kill_switch: Option<Arc<tokio::sync::Mutex<KillSwitch>>>,
+    cgroup: Option<CgroupRouting>,
+    filter: Arc<PacketFilter>,
+    /// Where forwarded packets are also written (`pcap`).
+    pcap: Option<Arc<Pcap>>,
+    /// The client address a shared agent leased.
+    assigned_address: Option<String>,
     #[allow(dead_code)]
     ssh_server_ip: IpAddr,
+    /// Names the routing state file a dropped session recovers from.
+    client_tun: String,
     cleaned_up: bool,
 }
 
@@ -27,49 +80,671 @@ impl VpnSession {
         config: &VpnConfig,
         ssh_server_ip: IpAddr,
     ) -> anyhow::Result<Self> {
-        info!("Creating TUN device: {}", config.client_tun);
+        validate(config)?;
+        let domain_rules = DomainRules::parse(&config.domains)?;
+        // Installed by routing setup; checked here so a typo fails first.
+        config.static_routes()?;
+        let nat64 = if config.dns64 {
+            Some(Nat64::from_config(config)?)
+        } else {
+            None
+        };
+
+        // The agent is started before the TUN device and routes, so a
+        // missing or failing agent leaves the host's routing alone; a shared
+        // agent also leases the client address they need.
+        let mut config = Cow::Borrowed(config);
+        if config.shared_agent.is_some() {
+            info!("Joining the shared VPN agent");
+        } else {
+            info!("Deploying VPN agent");
+        }
+        let agent_path = agent::AgentPath::resolve(transport, &config).await?;
+        agent::deploy(transport, &agent_path).await?;
+        let root = RootAccess::detect(transport, &config).await?;
+
+        info!("Starting VPN agent");
+        let agent = agent::start(transport, &config, &agent_path, &root).await?;
+        if let Some(assigned) = agent.assigned()
+            && assigned.to_string() != config.client_address
+        {
+            info!(
+                "Shared agent assigned {} ({} is taken)",
+                assigned, config.client_address
+            );
+            config.to_mut().client_address = assigned.to_string();
+        }
+        let config = config.as_ref();
+
+        if !config.persistent_tun && RoutingManager::recover(&config.client_tun).await? {
+            warn!("Restored routes left behind by a previous run that did not clean up");
+        }
+
+        if config.persistent_tun {
+            info!("Attaching to persistent TUN device: {}", config.client_tun);
+        } else {
+            info!(
+                "Creating TUN device: {} ({} queue(s))",
+                config.client_tun, config.tun_queues
+            );
+        }
         let tun = TunDevice::create(config).await?;
+        let pcap = match &config.pcap {
+            Some(path) => {
+                info!("Capturing TUN packets to {}", path.display());
+                Some(Arc::new(Pcap::create(path, &config.client_tun)?))
+            }
+            None => None,
+        };
+
+        // The connection is up on `ssh_server_ip`; the host's other
+        // addresses are where a reconnect may go.
+        let mut ssh_servers = vec![ssh_server_ip];
+        if !config.persistent_tun || config.kill_switch {
+            match transport.resolve_server().await {
+                Ok(ips) => ssh_servers.extend(ips.into_iter().filter(|&ip| ip != ssh_server_ip)),
+                Err(e) => warn!("Failed to resolve the SSH server's other addresses: {}", e),
+            }
+        }
 
-        info!("Setting up routing");
         let mut routing = RoutingManager::new().await?;
-        routing.setup(config, ssh_server_ip).await?;
+        let started = async {
+            if config.persistent_tun {
+                info!("Leaving routing to the system (persistent_tun)");
+            } else {
+                info!("Setting up routing");
+                routing.setup(config, ssh_server_ip).await?;
+                routing.pin_ssh_servers(&ssh_servers).await?;
+                if let Some(nat64) = &nat64 {
+                    routing
+                        .route_through_tunnel(IpNet::V6(nat64.prefix()))
+                        .await?;
+                }
+            }
+            let routing = Arc::new(tokio::sync::Mutex::new(routing));
 
-        info!("Deploying VPN agent");
-        agent::deploy(transport).await?;
+            let cgroup = match &config.cgroup {
+                Some(cgroup) => Some(CgroupRouting::enable(config, cgroup).await?),
+                None => None,
+            };
 
-        info!("Starting VPN agent");
-        let agent = agent::start(transport, &config.server_address).await?;
+            let kill_switch = if config.kill_switch {
+                let ssh_port = transport.endpoints().peer.port();
+                let lan = routing.lock().await.lan_subnets().to_vec();
+                let kill_switch = KillSwitch::enable(config, &ssh_servers, ssh_port, &lan).await?;
+                Some(Arc::new(tokio::sync::Mutex::new(kill_switch)))
+            } else {
+                None
+            };
+
+            if !config.persistent_tun || kill_switch.is_some() {
+                // A reconnect may find the host at another address, which the
+                // tunnel would otherwise swallow and the kill switch drop.
+                let pins = Arc::clone(&routing);
+                let allowed = kill_switch.clone();
+                transport.set_resolve_hook(Some(Arc::new(move |ips| {
+                    let pins = Arc::clone(&pins);
+                    let allowed = allowed.clone();
+                    Box::pin(async move {
+                        pins.lock().await.pin_ssh_servers(&ips).await?;
+                        if let Some(allowed) = allowed {
+                            allowed.lock().await.allow_ssh_servers(&ips).await?;
+                        }
+                        Ok(())
+                    })
+                })));
+            }
+
+            info!("Running PostUp hooks");
+            hooks::run_post_up(transport, config, ssh_server_ip).await?;
+            hooks::run_local_post_up(config, ssh_server_ip).await?;
+
+            let domains = if domain_rules.is_empty() {
+                None
+            } else {
+                let router = DomainRouter::new(domain_rules, Arc::clone(&routing));
+                router.resolve_exact(transport).await;
+                Some(Arc::new(router))
+            };
+
+            let dns64 = if config.dns64 {
+                info!("Starting DNS64 resolver on {}", config.dns64_listen);
+                Some(Dns64Resolver::start(transport, config).await?)
+            } else {
+                None
+            };
+
+            let assigned_address = agent.assigned().map(|assigned| assigned.to_string());
+
+            info!("VPN session started");
+
+            anyhow::Ok(Self {
+                tun: tun.into_iter().map(Arc::new).collect(),
+                routing,
+                domains,
+                nat64: nat64.map(Arc::new),
+                dns64,
+                metrics: Arc::clone(transport.metrics()),
+                keepalive: Keepalive::new(config),
+                queueing: Queueing::new(config),
+                agent_path,
+                root,
+                agent,
+                kill_switch,
+                cgroup,
+                filter: Arc::new(PacketFilter::new(config)?),
+                pcap,
+                assigned_address,
+                ssh_server_ip,
+                client_tun: config.client_tun.clone(),
+                cleaned_up: false,
+            })
+        }
+        .await;
+        if started.is_err() {
+            // Drops the routes and kill switch the hook holds.
+            transport.set_resolve_hook(None);
+        }
+        if config.persistent_tun {
+            return started;
+        }
+        undo_routes_on_error(&routing::state_file(&config.client_tun), started).await
+    }
+
+    /// `config` as the session runs it: with the client address a shared
+    /// agent leased, which the hooks, self-test and agent restarts need.
+    pub fn running_config(&self, config: &VpnConfig) -> VpnConfig {
+        let mut config = config.clone();
+        if let Some(address) = &self.assigned_address {
+            config.client_address = address.clone();
+        }
+        config
+    }
+
+    /// Forwards packets between the TUN device and the agent until either
+    /// side stops or `shutdown` is cancelled. Dropping the returned future
+    /// stops both directions too, each after the frame it is writing.
+    pub async fn forward(&self, shutdown: &CancellationToken) -> anyhow::Result<()> {
+        let processing = Processing {
+            domains: self.domains.clone(),
+            nat64: self.nat64.clone(),
+            filter: Arc::clone(&self.filter),
+        };
+        let Some(pcap) = &self.pcap else {
+            return forward_packets(
+                self.tun.clone(),
+                self.agent.clone(),
+                processing,
+                Arc::clone(&self.metrics),
+                self.keepalive,
+                self.queueing,
+                shutdown.clone(),
+            )
+            .await;
+        };
+        let captured = self
+            .tun
+            .iter()
+            .map(|tun| Arc::new(Capture::new(Arc::clone(tun), Arc::clone(pcap))))
+            .collect();
+        forward_packets(
+            captured,
+            self.agent.clone(),
+            processing,
+            Arc::clone(&self.metrics),
+            self.keepalive,
+            self.queueing,
+            shutdown.clone(),
+        )
+        .await
+    }
+
+    /// Answers DNS64 queries until dropped; never finishes if DNS64 is off.
+    pub async fn serve_dns64(&self, transport: &Transport) {
+        match &self.dns64 {
+            Some(dns64) => dns64.serve(transport).await,
+            None => std::future::pending().await,
+        }
+    }
+
+    /// Re-applies routes that were overwritten since setup; see
+    /// [`RoutingManager::heal`].
+    pub async fn heal_routes(&self) -> anyhow::Result<bool> {
+        self.routing.lock().await.heal().await
+    }
+
+    /// Recovers from a lost SSH session or an agent that exited, keeping the
+    /// TUN device and routes: reconnects if the session is gone, redeploys
+    /// the agent if the server lost it (re-running PostUp, since that
+    /// usually means the server rebooted), and starts a fresh agent.
+    pub async fn resume(
+        &mut self,
+        transport: &Transport,
+        config: &VpnConfig,
+    ) -> anyhow::Result<()> {
+        if transport.check_alive().await.is_err() {
+            info!("Reconnecting SSH session");
+            transport.reconnect(transport.lost_cause().await).await?;
+        }
+        if !agent::is_deployed(transport, &self.agent_path).await? {
+            agent::deploy(transport, &self.agent_path).await?;
+            info!("Running PostUp hooks again");
+            hooks::run_post_up(transport, config, self.ssh_server_ip).await?;
+        }
+        self.restart_agent(transport, config).await
+    }
+
+    /// Starts a fresh agent on the (reconnected) transport and swaps it in.
+    /// The TUN device and routes are left untouched, so applications only
+    /// see a stall while the SSH session is re-established. Exact domains
+    /// are resolved again in case their addresses changed meanwhile.
+    pub async fn restart_agent(
+        &mut self,
+        transport: &Transport,
+        config: &VpnConfig,
+    ) -> anyhow::Result<()> {
+        info!("Restarting VPN agent");
+        let agent = agent::start(transport, config, &self.agent_path, &self.root).await?;
+        if let Some(assigned) = agent.assigned()
+            && assigned.to_string() != config.client_address
+        {
+            anyhow::bail!(
+                "the shared agent assigned {} this time, but the TUN device has {}",
+                assigned,
+                config.client_address
+            );
+        }
+        if let Err(e) = self.agent.close().await {
+            debug!("Closing previous agent channel failed: {}", e);
+        }
+        self.agent = agent;
+        transport.timeline().record(TimelineEvent::AgentRestarted);
+        if let Some(domains) = &self.domains {
+            domains.resolve_exact(transport).await;
+        }
+        Ok(())
+    }
+
+    pub async fn cleanup(
+        &mut self,
+        transport: &Transport,
+        config: &VpnConfig,
+    ) -> anyhow::Result<()> {
+        if self.cleaned_up {
+            return Ok(());
+        }
 
-        info!("Running PostUp hooks");
-        hooks::run_post_up(transport, config).await?;
+        info!("Cleaning up VPN session");
+
+        let mut shutdown = Shutdown::new(transport.shutdown_timeout());
+        hooks::run_local_pre_down(config, self.ssh_server_ip, &mut shutdown).await;
+        hooks::run_pre_down(transport, config, self.ssh_server_ip, &mut shutdown).await;
+
+        if let Some(Err(e)) = shutdown
+            .close("Agent channel", self.agent.close_and_wait())
+            .await
+        {
+            error!("Agent close error: {}", e);
+        }
+        if config.keep_agent {
+            info!("Leaving the agent at {} on the server", self.agent_path);
+        } else {
+            if let Some(Err(e)) = shutdown
+                .close(
+                    "Agent process kill",
+                    agent::kill(transport, config, &self.agent_path, &self.root),
+                )
+                .await
+            {
+                warn!("Killing the agent failed: {}", e);
+            }
+            if let Some(Err(e)) = shutdown
+                .close(
+                    "Agent binary removal",
+                    agent::remove(transport, &self.agent_path),
+                )
+                .await
+            {
+                warn!("Removing the agent binary failed: {}", e);
+            }
+        }
+
+        self.restore_local(transport).await;
+        self.cleaned_up = true;
+        shutdown.log();
+        info!("VPN session cleaned up");
+        Ok(())
+    }
+
+    /// What is left of [`cleanup`](Self::cleanup) when it is cut short:
+    /// the local routes, cgroup and firewall are restored, without waiting
+    /// on the server for PreDown or the agent.
+    pub async fn abandon(&mut self, transport: &Transport) {
+        if self.cleaned_up {
+            return;
+        }
+        warn!("Skipping PreDown and the agent's cleanup; restoring local routes");
+        self.restore_local(transport).await;
+        self.cleaned_up = true;
+    }
+
+    async fn restore_local(&mut self, transport: &Transport) {
+        info!("Restoring local routes");
+        transport.set_resolve_hook(None);
+        if let Err(e) = self.routing.lock().await.cleanup().await {
+            error!("Routing cleanup error: {}", e);
+        }
+
+        if let Some(cgroup) = &mut self.cgroup
+            && let Err(e) = cgroup.disable().await
+        {
+            error!("Cgroup routing cleanup error: {}", e);
+        }
+
+        if let Some(kill_switch) = &self.kill_switch
+            && let Err(e) = kill_switch.lock().await.disable().await
+        {
+            error!("Kill switch cleanup error: {}", e);
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    pub fn tun(&self) -> &tun_rs::AsyncDevice {
+        self.tun[0].inner()
+    }
 
-        info!("VPN session started");
+    pub fn agent(&self) -> &agent::AgentChannel {
+        &self.agent
+    }
+}
 
-        Ok(Self {
-            tun: Arc::new(tun),
-            routing,
-            agent,
-            ssh_server_ip,
-            cleaned_up: false,
+/// Pings the agent every `interval` while forwarding, and gives up on it
+/// after `timeout` without hearing anything back.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Keepalive {
+    pub interval: Duration,
+    pub timeout: Duration,
+}
+
+impl Keepalive {
+    pub fn new(config: &VpnConfig) -> Self {
+        Self {
+            interval: config.keepalive_interval,
+            timeout: config.keepalive_timeout,
+        }
+    }
+}
+
+/// How packets wait between each direction's reader and writer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Queueing {
+    /// How long a packet to the agent waits for others to share its
+    /// channel write.
+    pub batch_delay: Duration,
+    /// What a packet that finds its queue full does.
+    pub policy: QueuePolicy,
+}
+
+impl Queueing {
+    pub fn new(config: &VpnConfig) -> Self {
+        Self {
+            batch_delay: config.batch_delay,
+            policy: config.queue_policy,
+        }
+    }
+}
+
+/// What forwarding does to packets besides moving them.
+#[derive(Clone, Default)]
+pub struct Processing {
+    /// Learns the addresses of routed domains from DNS answers.
+    pub domains: Option<Arc<DomainRouter>>,
+    pub nat64: Option<Arc<Nat64>>,
+    pub filter: Arc<PacketFilter>,
+}
+
+/// Forwarding stopped because the agent went silent. The SSH session is
+/// then most likely dead as well, even if the TCP connection looks open.
+#[derive(Debug)]
+pub struct DeadPeer {
+    pub silent_for: Duration,
+}
+
+impl std::fmt::Display for DeadPeer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "VPN agent has not answered for {:.1?}", self.silent_for)
+    }
+}
+
+impl std::error::Error for DeadPeer {}
+
+/// Rejects option combinations a session cannot run with, before anything
+/// is changed.
+pub fn validate(config: &VpnConfig) -> anyhow::Result<()> {
+    filter::blocked_protocols(config)?;
+    if config.kill_switch && config.split_tunnel() {
+        anyhow::bail!(
+            "kill_switch blocks all traffic outside the tunnel and cannot be combined with \
+             include or domains (split tunnel)"
+        );
+    }
+    if config.roaming_interval.is_zero() {
+        anyhow::bail!("roaming_interval must be longer than 0");
+    }
+    if config.keepalive_interval.is_zero() {
+        anyhow::bail!("keepalive_interval must be longer than 0");
+    }
+    if config.keepalive_timeout <= config.keepalive_interval {
+        anyhow::bail!("keepalive_timeout must be longer than keepalive_interval");
+    }
+    if config.cgroup.is_some() && (config.kill_switch || config.split_tunnel()) {
+        anyhow::bail!(
+            "cgroup already limits the tunnel to one cgroup and cannot be combined with \
+             kill_switch, include or domains"
+        );
+    }
+    if config.tun_queues == 0 {
+        anyhow::bail!("tun_queues must be at least 1");
+    }
+    if config.offload && config.dns64 {
+        anyhow::bail!("offload cannot be combined with dns64, which only translates plain packets");
+    }
+    if config.persistent_tun
+        && (config.split_tunnel()
+            || !config.exclude.is_empty()
+            || !config.routes.is_empty()
+            || config.exclude_lan
+            || config.cgroup.is_some())
+    {
+        anyhow::bail!(
+            "persistent_tun leaves routing to the system and cannot be combined with include, \
+             domains, exclude, routes, exclude_lan or cgroup; add the routes to the interface \
+             instead"
+        );
+    }
+    if config.self_test {
+        super::self_test::check_url(&config.self_test_url)?;
+    }
+    if config.shared_agent.is_some() {
+        if config.agent_path.is_none() {
+            anyhow::bail!(
+                "shared_agent needs agent_path, so every client joins with the same agent build"
+            );
+        }
+        if config.client_address6.is_some() || config.offload {
+            anyhow::bail!(
+                "shared_agent routes IPv4 packets without offloads and cannot be combined with \
+                 client_address6 or offload"
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Sends pings to `agent` until a write fails or `stop` is cancelled, or
+/// returns [`DeadPeer`] once nothing has arrived for the keepalive timeout.
+/// Pings are sent apart from the check, so a write stuck on a dead
+/// connection cannot delay it.
+async fn watch_agent(
+    agent: agent::AgentChannel,
+    keepalive: Keepalive,
+    stop: CancellationToken,
+) -> anyhow::Result<()> {
+    let pings = async {
+        let mut ticker = tokio::time::interval(keepalive.interval);
+        let mut nonce = 0;
+        loop {
+            tokio::select! {
+                _ = ticker.tick() => {}
+                () = stop.cancelled() => return Ok(()),
+            }
+            agent.ping(nonce).await?;
+            nonce += 1;
+        }
+    };
+
+    // Frames that queued up before forwarding started are not read yet,
+    // so silence only counts from here.
+    let started = Instant::now();
+    let watchdog = async {
+        let mut ticker = tokio::time::interval(keepalive.interval);
+        loop {
+            ticker.tick().await;
+            let silent_for = agent.idle().min(started.elapsed());
+            if silent_for >= keepalive.timeout {
+                return DeadPeer { silent_for };
+            }
+        }
+    };
+
+    tokio::select! {
+        result = pings => result,
+        dead = watchdog => Err(dead.into()),
+    }
+}
+
+/// Packets each direction holds between its reader and writer; beyond that,
+/// the [`QueuePolicy`] decides between dropping and waiting.
+const QUEUE_LEN: usize = 256;
+
+/// How long forwarding tasks told to stop get to finish the write they are
+/// in; one stuck on a dead connection is left to fail on its own.
+const STOP_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// The tasks of [`forward_packets`]. They are told to stop through `stop`
+/// rather than aborted, whether forwarding ends or its future is dropped,
+/// so none is cut off partway through writing a frame.
+struct Forwarders {
+    tasks: JoinSet<anyhow::Result<()>>,
+    stop: CancellationToken,
+}
+
+impl Forwarders {
+    /// Tells the tasks still running to stop and waits up to
+    /// [`STOP_TIMEOUT`] for them.
+    async fn stop_all(&mut self) {
+        self.stop.cancel();
+        let stopped = tokio::time::timeout(STOP_TIMEOUT, async {
+            while self.tasks.join_next().await.is_some() {}
         })
+        .await;
+        if stopped.is_err() {
+            debug!(
+                "{} forwarding task(s) still writing; leaving them to finish",
+                self.tasks.len()
+            );
+        }
+    }
+}
+
+impl Drop for Forwarders {
+    fn drop(&mut self) {
+        self.stop.cancel();
+        self.tasks.detach_all();
     }
+}
 
-    pub async fn forward(&self) -> anyhow::Result<()> {
-        info!("Starting packet forwarding");
+/// Pumps packets between `devices` and `agent` until either side stops or
+/// the agent stops answering keepalives. Packets from the agent are shown
+/// to `domains` before delivery; with `nat64`, packets to and from the
+/// NAT64 prefix are translated on the way. Packets to the agent wait up to
+/// `queueing.batch_delay` for others to share their channel write. Packets
+/// that find their queue full are dropped or wait as `queueing.policy`
+/// says. Each device, a queue of the same TUN interface, gets its own reader
+/// and writer; packets from the agent go to a queue chosen by their flow,
+/// so a flow stays in order. Cancelling `shutdown` ends forwarding with
+/// `Ok` once every frame being written is complete.
+pub async fn forward_packets<D: PacketDevice>(
+    devices: Vec<Arc<D>>,
+    agent: agent::AgentChannel,
+    processing: Processing,
+    metrics: Arc<dyn MetricsSink>,
+    keepalive: Keepalive,
+    queueing: Queueing,
+    shutdown: CancellationToken,
+) -> anyhow::Result<()> {
+    let Processing {
+        domains,
+        nat64,
+        filter,
+    } = processing;
+    info!("Starting packet forwarding");
 
-        let tun = Arc::clone(&self.tun);
-        let agent = self.agent.clone();
+    let mut forwarders = Forwarders {
+        tasks: JoinSet::new(),
+        stop: shutdown.child_token(),
+    };
+    let tasks = &mut forwarders.tasks;
+    if agent.features().contains(Features::KEEPALIVE) {
+        tasks.spawn(watch_agent(
+            agent.clone(),
+            keepalive,
+            forwarders.stop.clone(),
+        ));
+    } else {
+        warn!("VPN agent does not support keepalives; dead tunnels are detected more slowly");
+    }
 
-        let mut tun_to_agent = tokio::spawn(async move {
-            let mut buf = vec![0u8; 2048];
+    // Each direction is readers and writers joined by bounded queues, so
+    // the time packets wait shows which side is holding traffic up. All
+    // device readers feed the one agent writer; the agent reader feeds one
+    // queue per device writer.
+    let (outbound_tx, mut outbound_rx) = mpsc::channel::<(Vec<u8>, Instant)>(QUEUE_LEN);
+    let mut inbound_txs = Vec::with_capacity(devices.len());
+
+    for device in &devices {
+        let tun = Arc::clone(device);
+        let queued = Arc::clone(&metrics);
+        let outbound = nat64.clone();
+        let outbound_filter = Arc::clone(&filter);
+        let outbound_tx = outbound_tx.clone();
+        let stop = forwarders.stop.clone();
+        tasks.spawn(async move {
+            let mut buf = vec![0u8; MAX_GSO_FRAME];
             loop {
-                match tun.recv(&mut buf).await {
+                let received = tokio::select! {
+                    received = tun.recv(&mut buf) => received,
+                    () = stop.cancelled() => return Ok(()),
+                };
+                match received {
                     Ok(n) => {
                         debug!("TUN→Agent: {} bytes", n);
-                        if let Err(e) = agent.send_packet(&buf[..n]).await {
-                            error!("Failed to send packet to agent: {}", e);
-                            return Err(e);
+                        if let Err(reason) = outbound_filter.outbound(&buf[..n]) {
+                            queued.record(Metric::PacketRejected { reason });
+                            continue;
                         }
+                        let packet = match &outbound {
+                            Some(nat64) => match nat64.outbound(&buf[..n]) {
+                                Some(packet) => packet.into_owned(),
+                                None => {
+                                    debug!("Dropping untranslatable NAT64 packet");
+                                    continue;
+                                }
+                            },
+                            None => buf[..n].to_vec(),
+                        };
+                        let stage = Stage::OutboundQueue;
+                        enqueue(&outbound_tx, packet, stage, queueing.policy, &*queued).await?;
                     }
                     Err(e) => {
                         error!("TUN recv error: {}", e);
@@ -78,96 +753,671 @@ impl VpnSession {
                 }
             }
         });
+    }
+    drop(outbound_tx);
 
-        let tun = Arc::clone(&self.tun);
-        let agent = self.agent.clone();
+    let to_agent = agent.clone();
+    let sent = Arc::clone(&metrics);
+    let stop = forwarders.stop.clone();
 
-        let mut agent_to_tun = tokio::spawn(async move {
-            loop {
-                match agent.recv_packet().await {
-                    Ok(Some(packet)) => {
-                        debug!("Agent→TUN: {} bytes", packet.len());
-                        if let Err(e) = tun.send(&packet).await {
-                            debug!("TUN send failed (continuing): {}", e);
-                        }
+    tasks.spawn(async move {
+        let mut packets = Vec::new();
+        while let Some(first) = tokio::select! {
+            first = outbound_rx.recv() => first,
+            () = stop.cancelled() => None,
+        } {
+            packets.push(first);
+            gather(&mut outbound_rx, &mut packets, queueing.batch_delay).await;
+
+            let started = Instant::now();
+            for (_, queued_at) in &packets {
+                sent.record(Metric::StageLatency {
+                    stage: Stage::OutboundQueue,
+                    elapsed: started - *queued_at,
+                });
+            }
+            let batch = packets.iter().map(|(packet, _)| packet.as_slice());
+            if let Err(e) = to_agent.send_packets(batch).await {
+                error!("Failed to send packet to agent: {}", e);
+                return Err(e);
+            }
+            let elapsed = started.elapsed();
+            for (packet, _) in packets.drain(..) {
+                sent.record(Metric::StageLatency {
+                    stage: Stage::SshWrite,
+                    elapsed,
+                });
+                sent.record(Metric::PacketSent {
+                    bytes: packet.len(),
+                });
+            }
+        }
+        Ok(())
+    });
+
+    let queued = Arc::clone(&metrics);
+    let mut inbound_rxs = Vec::with_capacity(devices.len());
+    for _ in &devices {
+        let (inbound_tx, inbound_rx) = mpsc::channel::<(Vec<u8>, Instant)>(QUEUE_LEN);
+        inbound_txs.push(inbound_tx);
+        inbound_rxs.push(inbound_rx);
+    }
+
+    let stop = forwarders.stop.clone();
+    tasks.spawn(async move {
+        loop {
+            match agent.recv_packet_until(&stop).await {
+                Ok(Some(packet)) => {
+                    debug!("Agent→TUN: {} bytes", packet.len());
+                    if let Err(reason) = filter.inbound(&packet) {
+                        queued.record(Metric::PacketRejected { reason });
+                        continue;
                     }
-                    Ok(None) => {
-                        info!("Agent channel closed");
-                        return Ok(());
+                    if let Some(domains) = &domains {
+                        domains.inspect(&packet).await;
+                    }
+                    let packet = match &nat64 {
+                        Some(nat64) => match nat64.inbound(&packet) {
+                            Some(Cow::Owned(translated)) => translated,
+                            Some(Cow::Borrowed(_)) => packet,
+                            None => {
+                                debug!("Dropping untranslatable NAT64 packet");
+                                continue;
+                            }
+                        },
+                        None => packet,
+                    };
+                    let inbound_tx = &inbound_txs[flow_queue(&packet, inbound_txs.len())];
+                    let stage = Stage::InboundQueue;
+                    enqueue(inbound_tx, packet, stage, queueing.policy, &*queued).await?;
+                }
+                Ok(None) if stop.is_cancelled() => return Ok(()),
+                Ok(None) => {
+                    info!("Agent channel closed");
+                    return Ok(());
+                }
+                Err(e) => {
+                    error!("Agent recv error: {}", e);
+                    return Err(e);
+                }
+            }
+        }
+    });
+
+    for (tun, mut inbound_rx) in devices.into_iter().zip(inbound_rxs) {
+        let metrics = Arc::clone(&metrics);
+        let stop = forwarders.stop.clone();
+        tasks.spawn(async move {
+            while let Some((packet, queued_at)) = tokio::select! {
+                next = inbound_rx.recv() => next,
+                () = stop.cancelled() => None,
+            } {
+                let started = Instant::now();
+                metrics.record(Metric::StageLatency {
+                    stage: Stage::InboundQueue,
+                    elapsed: started - queued_at,
+                });
+                match tun.send(&packet).await {
+                    Ok(()) => {
+                        metrics.record(Metric::StageLatency {
+                            stage: Stage::TunWrite,
+                            elapsed: started.elapsed(),
+                        });
+                        metrics.record(Metric::PacketReceived {
+                            bytes: packet.len(),
+                        });
                     }
                     Err(e) => {
-                        error!("Agent recv error: {}", e);
-                        return Err(e);
+                        metrics.record(Metric::PacketDropped {
+                            stage: Stage::TunWrite,
+                        });
+                        debug!("TUN send failed (continuing): {}", e);
                     }
                 }
             }
+            Ok(())
         });
+    }
+
+    // The first task to finish ends forwarding; the others stop after the
+    // frame they are writing.
+    let result = forwarders
+        .tasks
+        .join_next()
+        .await
+        .expect("forwarding tasks were spawned");
+    forwarders.stop_all().await;
+    info!("Packet forwarding finished");
+
+    result?
+}
 
-        let result = tokio::select! {
-            result = &mut tun_to_agent => {
-                info!("TUN→Agent task finished");
-                agent_to_tun.abort();
-                result
+/// Picks one of `queues` for `packet` (or the packet in a GSO frame) by
+/// its addresses, protocol and, for TCP and UDP, ports, so packets of a
+/// flow always take the same queue.
+fn flow_queue(packet: &[u8], queues: usize) -> usize {
+    if queues == 1 {
+        return 0;
+    }
+    let packet = gso::vnet_packet(packet).map_or(packet, |vnet| &vnet[VNET_HDR_LEN..]);
+    let (addresses, protocol, transport) = match packet.first().map(|b| b >> 4) {
+        Some(4) if packet.len() >= 20 => {
+            let header_len = usize::from(packet[0] & 0x0f) * 4;
+            (&packet[12..20], packet[9], packet.get(header_len..))
+        }
+        Some(6) if packet.len() >= 40 => (&packet[8..40], packet[6], packet.get(40..)),
+        _ => return 0,
+    };
+    let mut hasher = std::hash::DefaultHasher::new();
+    addresses.hash(&mut hasher);
+    protocol.hash(&mut hasher);
+    // TCP and UDP
+    if matches!(protocol, 6 | 17) {
+        transport.and_then(|ports| ports.get(..4)).hash(&mut hasher);
+    }
+    (hasher.finish() % queues as u64) as usize
+}
+
+/// Adds packets queued behind the first one in `packets`, waiting until
+/// `delay` has passed for more, up to a batch frame's worth.
+async fn gather(
+    queue: &mut mpsc::Receiver<(Vec<u8>, Instant)>,
+    packets: &mut Vec<(Vec<u8>, Instant)>,
+    delay: Duration,
+) {
+    let deadline = tokio::time::Instant::now() + delay;
+    let mut bytes: usize = packets.iter().map(|(packet, _)| packet.len()).sum();
+    while bytes < proto::batch::MAX_BATCH_LEN {
+        // Polls the queue before the deadline, so packets already waiting
+        // are taken even without a delay.
+        match tokio::time::timeout_at(deadline, queue.recv()).await {
+            Ok(Some(packet)) => {
+                bytes += packet.0.len();
+                packets.push(packet);
             }
-            result = &mut agent_to_tun => {
-                info!("Agent→TUN task finished");
-                tun_to_agent.abort();
-                result
+            Ok(None) | Err(_) => break,
+        }
+    }
+}
+
+/// Queues `packet` for the writer behind `queue`, recording how many were
+/// already waiting. A full queue drops the packet or waits, per `policy`.
+/// Fails once the writer has stopped.
+async fn enqueue(
+    queue: &mpsc::Sender<(Vec<u8>, Instant)>,
+    packet: Vec<u8>,
+    stage: Stage,
+    policy: QueuePolicy,
+    metrics: &dyn MetricsSink,
+) -> anyhow::Result<()> {
+    metrics.record(Metric::QueueDepth {
+        stage,
+        depth: queue.max_capacity() - queue.capacity(),
+    });
+    let stopped = || anyhow::anyhow!("{} writer stopped", stage.name());
+    let item = (packet, Instant::now());
+    match policy {
+        QueuePolicy::Wait => queue.send(item).await.map_err(|_| stopped()),
+        QueuePolicy::Drop => match queue.try_send(item) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(_)) => {
+                metrics.record(Metric::PacketDropped { stage });
+                debug!("{} full, dropping packet", stage.name());
+                Ok(())
             }
-        };
+            Err(TrySendError::Closed(_)) => Err(stopped()),
+        },
+    }
+}
 
-        result?
+/// Passes `result` through, first undoing the routes recorded in
+/// `state_file` if it is an error: a failed [`VpnSession::start`] leaves no
+/// session whose drop would.
+async fn undo_routes_on_error<T>(
+    state_file: &Path,
+    result: anyhow::Result<T>,
+) -> anyhow::Result<T> {
+    if result.is_err() {
+        match RoutingManager::recover_from(state_file).await {
+            Ok(true) => info!("Restored routes after the failed start"),
+            Ok(false) => {}
+            Err(e) => error!("Routing cleanup error: {}", e),
+        }
     }
+    result
+}
 
-    pub async fn cleanup(
-        &mut self,
-        transport: &Transport,
-        config: &VpnConfig,
-    ) -> anyhow::Result<()> {
+impl Drop for VpnSession {
+    /// Puts the routes back when the session is dropped without
+    /// [`cleanup`](VpnSession::cleanup), e.g. on an error. Drop usually
+    /// runs on a runtime thread, which must not block on the runtime, so
+    /// the routes are recovered from their state file on a thread with a
+    /// runtime of its own.
+    fn drop(&mut self) {
         if self.cleaned_up {
-            return Ok(());
+            return;
+        }
+        #[cfg(target_os = "linux")]
+        {
+            let tun = self.client_tun.clone();
+            let recovered = std::thread::spawn(move || {
+                tokio::runtime::Builder::new_current_thread()
+                    .enable_all()
+                    .build()?
+                    .block_on(RoutingManager::recover(&tun))
+            })
+            .join();
+            match recovered {
+                Ok(Ok(_)) => {}
+                Ok(Err(e)) => error!("VPN cleanup error during drop: {}", e),
+                Err(_) => error!("VPN cleanup panicked during drop"),
+            }
         }
+    }
+}
 
-        info!("Cleaning up VPN session");
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
 
-        hooks::run_pre_down(transport, config).await;
+    use super::*;
+    use crate::metrics::NoopMetrics;
+    use crate::metrics::PrometheusMetrics;
+    use crate::metrics::Reject;
+    use crate::test_utils::EchoAgent;
+    use crate::test_utils::EchoMode;
+    use crate::test_utils::MemoryDevice;
+    use crate::test_utils::icmp_echo_request;
+    use crate::test_utils::internet_checksum;
 
-        if let Err(e) = self.agent.close().await {
-            error!("Agent close error: {}", e);
+    const KEEPALIVE: Keepalive = Keepalive {
+        interval: Duration::from_secs(2),
+        timeout: Duration::from_secs(10),
+    };
+    const QUEUEING: Queueing = Queueing {
+        batch_delay: Duration::from_millis(1),
+        policy: QueuePolicy::Drop,
+    };
+
+    #[tokio::test]
+    async fn test_forward_reflects_packets_in_order() {
+        let agent = EchoAgent::spawn(EchoMode::Reflect).await;
+        let (device, mut handle) = MemoryDevice::new(4);
+        let metrics = Arc::new(PrometheusMetrics::new());
+        let forwarding = tokio::spawn(forward_packets(
+            vec![Arc::new(device)],
+            agent.channel().clone(),
+            Processing::default(),
+            metrics.clone(),
+            KEEPALIVE,
+            QUEUEING,
+            CancellationToken::new(),
+        ));
+
+        let client = Ipv4Addr::new(10, 8, 0, 2);
+        let remote = Ipv4Addr::new(192, 0, 2, 1);
+        let packets: Vec<Vec<u8>> = (0..32u8)
+            .map(|i| icmp_echo_request(client, remote, 1, i.into(), &vec![i; usize::from(i) * 40]))
+            .collect();
+        let sender = handle.outbound.clone();
+        let to_send = packets.clone();
+        tokio::spawn(async move {
+            for packet in to_send {
+                sender.send(packet).await.unwrap();
+            }
+        });
+
+        for packet in &packets {
+            assert_eq!(handle.inbound.recv().await.as_ref(), Some(packet));
         }
 
-        if let Err(e) = self.routing.cleanup().await {
-            error!("Routing cleanup error: {}", e);
+        let total: usize = packets.iter().map(Vec::len).sum();
+        let snapshot = metrics.snapshot();
+        assert_eq!(snapshot.packets_sent, 32);
+        assert_eq!(snapshot.tunnel_bytes_sent, total as u64);
+        assert_eq!(snapshot.packets_received, 32);
+        assert_eq!(snapshot.tunnel_bytes_received, total as u64);
+        assert_eq!(snapshot.packets_dropped, 0);
+        // Every packet went through each step of its direction once.
+        for stage in Stage::ALL {
+            assert_eq!(metrics.counters().latency(stage).count(), 32, "{stage:?}");
+        }
+        for stage in [Stage::OutboundQueue, Stage::InboundQueue] {
+            assert_eq!(
+                metrics.counters().queue_depth(stage).count(),
+                32,
+                "{stage:?}"
+            );
         }
 
-        self.cleaned_up = true;
-        info!("VPN session cleaned up");
-        Ok(())
+        forwarding.abort();
     }
 
-    #[cfg(target_os = "linux")]
-    pub fn tun(&self) -> &tun_rs::AsyncDevice {
-        self.tun.inner()
+    #[tokio::test]
+    async fn test_forward_over_queues_keeps_flows_in_order() {
+        let agent = EchoAgent::spawn(EchoMode::Reflect).await;
+        let (first, mut first_handle) = MemoryDevice::new(64);
+        let (second, mut second_handle) = MemoryDevice::new(64);
+        let forwarding = tokio::spawn(forward_packets(
+            vec![Arc::new(first), Arc::new(second)],
+            agent.channel().clone(),
+            Processing::default(),
+            Arc::new(NoopMetrics),
+            KEEPALIVE,
+            QUEUEING,
+            CancellationToken::new(),
+        ));
+
+        // Four interleaved flows, two read from each queue as the kernel
+        // would hand them out; replies take the queue of their flow.
+        let client = Ipv4Addr::new(10, 8, 0, 2);
+        let remotes: Vec<Ipv4Addr> = (1..=4).map(|i| Ipv4Addr::new(192, 0, 2, i)).collect();
+        for seq in 0..16 {
+            for (i, remote) in remotes.iter().enumerate() {
+                let packet = icmp_echo_request(client, *remote, 1, seq, b"");
+                let handle = if i % 2 == 0 {
+                    &first_handle
+                } else {
+                    &second_handle
+                };
+                handle.outbound.send(packet).await.unwrap();
+            }
+        }
+
+        let mut received = [Vec::new(), Vec::new()];
+        while received[0].len() + received[1].len() < 64 {
+            tokio::select! {
+                Some(packet) = first_handle.inbound.recv() => received[0].push(packet),
+                Some(packet) = second_handle.inbound.recv() => received[1].push(packet),
+            }
+        }
+        for remote in &remotes {
+            let queue = received
+                .iter()
+                .position(|packets| packets.iter().any(|p| p[16..20] == remote.octets()))
+                .unwrap();
+            let flow: Vec<u16> = received[queue]
+                .iter()
+                .filter(|p| p[16..20] == remote.octets())
+                .map(|p| u16::from_be_bytes([p[26], p[27]]))
+                .collect();
+            assert_eq!(flow, (0..16).collect::<Vec<_>>(), "{remote}");
+            let packet = icmp_echo_request(client, *remote, 1, 0, b"");
+            assert_eq!(flow_queue(&packet, 2), queue);
+        }
+
+        forwarding.abort();
     }
 
-    pub fn agent(&self) -> &agent::AgentChannel {
-        &self.agent
+    #[test]
+    fn test_flow_queue() {
+        let udp = |sport: u16| {
+            let mut packet = vec![0u8; 28];
+            packet[0] = 0x45;
+            packet[9] = 17;
+            packet[12..16].copy_from_slice(&[10, 8, 0, 2]);
+            packet[16..20].copy_from_slice(&[192, 0, 2, 1]);
+            packet[20..22].copy_from_slice(&sport.to_be_bytes());
+            packet[22..24].copy_from_slice(&53u16.to_be_bytes());
+            packet
+        };
+        assert_eq!(flow_queue(&udp(5000), 1), 0);
+        assert_eq!(flow_queue(&udp(5000), 8), flow_queue(&udp(5000), 8));
+        // Different ports spread over the queues.
+        let used: std::collections::HashSet<usize> =
+            (5000..5064).map(|port| flow_queue(&udp(port), 8)).collect();
+        assert!(used.len() > 1);
+        assert!((5000..5064).all(|port| flow_queue(&udp(port), 8) < 8));
+        assert_eq!(flow_queue(&[0x45, 0, 0], 8), 0);
+        assert_eq!(flow_queue(&[], 8), 0);
     }
-}
 
-impl Drop for VpnSession {
-    fn drop(&mut self) {
-        if !self.cleaned_up {
-            #[cfg(target_os = "linux")]
-            {
-                if let Ok(rt) = tokio::runtime::Handle::try_current() {
-                    rt.block_on(async {
-                        if let Err(e) = self.routing.cleanup().await {
-                            error!("VPN cleanup error during drop: {}", e);
-                        }
-                    });
-                }
+    #[tokio::test]
+    async fn test_forward_drops_behind_stalled_tun() {
+        let agent = EchoAgent::spawn(EchoMode::Reflect).await;
+        let (device, mut handle) = MemoryDevice::new(4);
+        let metrics = Arc::new(PrometheusMetrics::new());
+        let forwarding = tokio::spawn(forward_packets(
+            vec![Arc::new(device)],
+            agent.channel().clone(),
+            Processing::default(),
+            metrics.clone(),
+            KEEPALIVE,
+            QUEUEING,
+            CancellationToken::new(),
+        ));
+
+        // Nothing reads the device: it takes 4 packets, the TUN writer
+        // holds one more and the inbound queue the next QUEUE_LEN.
+        let sent = QUEUE_LEN + 100;
+        let client = Ipv4Addr::new(10, 8, 0, 2);
+        let remote = Ipv4Addr::new(192, 0, 2, 1);
+        for i in 0..sent {
+            let packet = icmp_echo_request(client, remote, 1, i as u16, &[0; 32]);
+            handle.outbound.send(packet).await.unwrap();
+        }
+        let kept = QUEUE_LEN + 5;
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while metrics.snapshot().packets_dropped < (sent - kept) as u64 {
+            assert!(Instant::now() < deadline, "{:?}", metrics.snapshot());
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(metrics.snapshot().packets_dropped, (sent - kept) as u64);
+
+        // The agent reader kept going; what was queued still arrives.
+        for _ in 0..kept {
+            handle.inbound.recv().await.unwrap();
+        }
+        assert_eq!(metrics.snapshot().packets_received, kept as u64);
+
+        forwarding.abort();
+    }
+
+    #[tokio::test]
+    async fn test_forward_rejects_malformed_and_filtered_packets() {
+        let agent = EchoAgent::spawn(EchoMode::Reflect).await;
+        let (device, mut handle) = MemoryDevice::new(4);
+        let metrics = Arc::new(PrometheusMetrics::new());
+        let config = VpnConfig {
+            blocked_protocols: vec!["udp".to_string()],
+            ..Default::default()
+        };
+        let processing = Processing {
+            filter: Arc::new(PacketFilter::new(&config).unwrap()),
+            ..Default::default()
+        };
+        let forwarding = tokio::spawn(forward_packets(
+            vec![Arc::new(device)],
+            agent.channel().clone(),
+            processing,
+            metrics.clone(),
+            KEEPALIVE,
+            QUEUEING,
+            CancellationToken::new(),
+        ));
+
+        // Reflected, the truncated packet comes back as the agent's and is
+        // refused before the TUN device; UDP never leaves.
+        let mut udp = vec![0u8; 28];
+        udp[0] = 0x45;
+        udp[3] = 28;
+        udp[9] = 17;
+        handle.outbound.send(udp).await.unwrap();
+        handle.outbound.send(vec![0x45; 30]).await.unwrap();
+        let ping = icmp_echo_request(
+            Ipv4Addr::new(10, 8, 0, 2),
+            Ipv4Addr::new(192, 0, 2, 1),
+            1,
+            1,
+            b"",
+        );
+        handle.outbound.send(ping.clone()).await.unwrap();
+
+        assert_eq!(handle.inbound.recv().await, Some(ping));
+        let counters = metrics.counters();
+        assert_eq!(counters.rejected(Reject::Filtered), 1);
+        assert_eq!(counters.rejected(Reject::Malformed), 1);
+        assert_eq!(metrics.snapshot().packets_sent, 2);
+
+        forwarding.abort();
+    }
+
+    #[tokio::test]
+    async fn test_forward_icmp_echo() {
+        let agent = EchoAgent::spawn(EchoMode::IcmpReply).await;
+        let (device, mut handle) = MemoryDevice::new(4);
+        let forwarding = tokio::spawn(forward_packets(
+            vec![Arc::new(device)],
+            agent.channel().clone(),
+            Processing::default(),
+            Arc::new(NoopMetrics),
+            KEEPALIVE,
+            QUEUEING,
+            CancellationToken::new(),
+        ));
+
+        let client = Ipv4Addr::new(10, 8, 0, 2);
+        let remote = Ipv4Addr::new(1, 1, 1, 1);
+        let request = icmp_echo_request(client, remote, 7, 1, b"ping");
+        handle.outbound.send(request).await.unwrap();
+        handle.outbound.send(vec![0x60; 40]).await.unwrap();
+
+        let reply = handle.inbound.recv().await.unwrap();
+        assert_eq!(reply[12..16], remote.octets());
+        assert_eq!(reply[16..20], client.octets());
+        assert_eq!(reply[20], 0);
+        assert_eq!(internet_checksum(&reply[..20]), 0);
+        assert_eq!(internet_checksum(&reply[20..]), 0);
+        assert_eq!(&reply[28..], b"ping");
+
+        forwarding.abort();
+        drop(handle);
+        assert_eq!(agent.shutdown().await.unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_forward_stops_when_agent_closes() {
+        let agent = EchoAgent::spawn(EchoMode::Reflect).await;
+        let (device, _handle) = MemoryDevice::new(4);
+        let channel = agent.channel().clone();
+
+        agent.shutdown().await.unwrap();
+
+        forward_packets(
+            vec![Arc::new(device)],
+            channel,
+            Processing::default(),
+            Arc::new(NoopMetrics),
+            KEEPALIVE,
+            QUEUEING,
+            CancellationToken::new(),
+        )
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_forward_stops_between_frames_on_shutdown() {
+        let agent = EchoAgent::spawn(EchoMode::Reflect).await;
+        let (device, mut handle) = MemoryDevice::new(64);
+        let device = Arc::new(device);
+        let client = Ipv4Addr::new(10, 8, 0, 2);
+        let remote = Ipv4Addr::new(192, 0, 2, 1);
+        let packet = |seq: u16| icmp_echo_request(client, remote, 1, seq, &[0x5a; 1200]);
+
+        // Stopped and started again on the same channel, which only works
+        // if stopping left whole frames on it.
+        let mut seq = 0;
+        for _ in 0..2 {
+            let shutdown = CancellationToken::new();
+            let forwarding = tokio::spawn(forward_packets(
+                vec![Arc::clone(&device)],
+                agent.channel().clone(),
+                Processing::default(),
+                Arc::new(NoopMetrics),
+                KEEPALIVE,
+                QUEUEING,
+                shutdown.clone(),
+            ));
+            for _ in 0..16 {
+                handle.outbound.send(packet(seq)).await.unwrap();
+                assert_eq!(handle.inbound.recv().await, Some(packet(seq)));
+                seq += 1;
             }
+            shutdown.cancel();
+            tokio::time::timeout(Duration::from_secs(1), forwarding)
+                .await
+                .expect("forwarding stops when shut down")
+                .unwrap()
+                .unwrap();
         }
+        assert_eq!(agent.shutdown().await.unwrap(), 32);
+    }
+
+    #[tokio::test]
+    async fn test_forward_keeps_answering_agent_alive() {
+        let agent = EchoAgent::spawn(EchoMode::IcmpReply).await;
+        let (device, _handle) = MemoryDevice::new(4);
+        let keepalive = Keepalive {
+            interval: Duration::from_millis(20),
+            timeout: Duration::from_millis(100),
+        };
+
+        let forwarding = forward_packets(
+            vec![Arc::new(device)],
+            agent.channel().clone(),
+            Processing::default(),
+            Arc::new(NoopMetrics),
+            keepalive,
+            QUEUEING,
+            CancellationToken::new(),
+        );
+        assert!(
+            tokio::time::timeout(Duration::from_millis(400), forwarding)
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_forward_detects_silent_agent() {
+        let agent = EchoAgent::spawn(EchoMode::Silent).await;
+        let (device, _handle) = MemoryDevice::new(4);
+        let keepalive = Keepalive {
+            interval: Duration::from_millis(20),
+            timeout: Duration::from_millis(100),
+        };
+
+        let error = forward_packets(
+            vec![Arc::new(device)],
+            agent.channel().clone(),
+            Processing::default(),
+            Arc::new(NoopMetrics),
+            keepalive,
+            QUEUEING,
+            CancellationToken::new(),
+        )
+        .await
+        .unwrap_err();
+        let dead = error.downcast_ref::<DeadPeer>().unwrap();
+        assert!(dead.silent_for >= keepalive.timeout);
+    }
+
+    #[tokio::test]
+    async fn test_failed_agent_start_restores_routes() {
+        let dir = tempfile::tempdir().unwrap();
+        let state_file = dir.path().join("routes-tun-x2ssh.json");
+        let state = serde_json::to_string(&routing::RoutingState::default()).unwrap();
+        std::fs::write(&state_file, state).unwrap();
+
+        // An agent that exits before its hello.
+        let (client, agent) = tokio::io::duplex(1024);
+        drop(agent);
+        let (client_read, client_write) = tokio::io::split(client);
+        let mut agent = agent::AgentChannel::from_io(client_read, client_write);
+        let started = agent.handshake_within(Duration::from_secs(1)).await;
+
+        assert!(undo_routes_on_error(&state_file, started).await.is_err());
+        assert!(!state_file.exists());
     }
 }
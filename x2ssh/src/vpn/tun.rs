@@ -1,26 +1,40 @@
-use std::net::IpAddr;
-use std::net::Ipv4Addr;
+use ipnet::IpNet;
+use x2ssh_net::TunConfig;
 
 use crate::config::VpnConfig;
 
+/// The local end of the tunnel: produces outbound IP packets and accepts
+/// inbound ones. Implemented by [`TunDevice`]; tests substitute an in-memory
+/// device so forwarding can run without root.
+pub trait PacketDevice: Send + Sync + 'static {
+    fn recv(&self, buf: &mut [u8]) -> impl Future<Output = anyhow::Result<usize>> + Send;
+
+    fn send(&self, packet: &[u8]) -> impl Future<Output = anyhow::Result<()>> + Send;
+}
+
 pub struct TunDevice {
     #[cfg(target_os = "linux")]
     inner: tun_rs::AsyncDevice,
+    /// Created with offloads: packets go through [`x2ssh_net::offload`].
+    #[cfg(target_os = "linux")]
+    offload: bool,
 }
 
 impl TunDevice {
+    /// Creates the client TUN interface, one device per queue
+    /// (`tun_queues`).
     #[cfg(target_os = "linux")]
-    pub async fn create(config: &VpnConfig) -> anyhow::Result<Self> {
-        let client_ip = config.client_ip()?;
-        let mtu = config.mtu;
-        let tun_name = &config.client_tun;
-
-        let device = create_linux_tun(client_ip, mtu, tun_name).await?;
-        Ok(Self { inner: device })
+    pub async fn create(config: &VpnConfig) -> anyhow::Result<Vec<Self>> {
+        let queues = tun_config(config)?.create_queues()?;
+        let offload = config.offload;
+        Ok(queues
+            .into_iter()
+            .map(|inner| Self { inner, offload })
+            .collect())
     }
 
     #[cfg(target_os = "windows")]
-    pub async fn create(_config: &VpnConfig) -> anyhow::Result<Self> {
+    pub async fn create(_config: &VpnConfig) -> anyhow::Result<Vec<Self>> {
         todo!("Windows TUN not yet implemented - Phase 4")
     }
 
@@ -29,13 +43,20 @@ impl TunDevice {
         &self.inner
     }
 
+    /// Reads the next packet, or with offloads a GSO frame, into `buf`.
     #[cfg(target_os = "linux")]
     pub async fn recv(&self, buf: &mut [u8]) -> anyhow::Result<usize> {
+        if self.offload {
+            return Ok(x2ssh_net::offload::recv(&self.inner, buf).await?);
+        }
         self.inner.recv(buf).await.map_err(Into::into)
     }
 
     #[cfg(target_os = "linux")]
     pub async fn send(&self, packet: &[u8]) -> anyhow::Result<()> {
+        if self.offload {
+            return Ok(x2ssh_net::offload::send(&self.inner, packet).await?);
+        }
         self.inner.send(packet).await?;
         Ok(())
     }
@@ -51,30 +72,69 @@ impl TunDevice {
     }
 }
 
-#[cfg(target_os = "linux")]
-async fn create_linux_tun(ip: IpAddr, mtu: u16, name: &str) -> anyhow::Result<tun_rs::AsyncDevice> {
-    let ip = match ip {
-        IpAddr::V4(ip) => ip,
-        IpAddr::V6(_) => anyhow::bail!("IPv6 not yet supported"),
-    };
+impl PacketDevice for TunDevice {
+    async fn recv(&self, buf: &mut [u8]) -> anyhow::Result<usize> {
+        TunDevice::recv(self, buf).await
+    }
 
-    let (addr, prefix) = ip_to_addr_prefix(ip);
+    async fn send(&self, packet: &[u8]) -> anyhow::Result<()> {
+        TunDevice::send(self, packet).await
+    }
+}
 
-    let device = tun_rs::DeviceBuilder::new()
-        .name(name)
-        .ipv4(addr, prefix, None)
-        .mtu(mtu)
-        .build_async()?;
+/// The client end of the tunnel, as the agent sets up the server end.
+fn tun_config(config: &VpnConfig) -> anyhow::Result<TunConfig> {
+    let IpNet::V4(address) = config.network()? else {
+        anyhow::bail!("client_address must be IPv4; use client_address6 for IPv6");
+    };
 
-    Ok(device)
+    let mut tun = TunConfig::new(address);
+    tun.name = Some(config.client_tun.clone());
+    tun.mtu = config.mtu;
+    tun.queues = config.tun_queues;
+    tun.offload = config.offload;
+    tun.persistent = config.persistent_tun;
+    tun.address6 = match config.ipv6_addresses()? {
+        Some((IpNet::V6(client6), _)) => Some(client6),
+        _ => None,
+    };
+    Ok(tun)
 }
 
-#[cfg(target_os = "linux")]
-fn ip_to_addr_prefix(ip: Ipv4Addr) -> (Ipv4Addr, u8) {
-    (ip, 24)
-}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tun_config_uses_client_prefix() {
+        let config = VpnConfig {
+            client_address: "10.9.0.2/16".to_string(),
+            server_address: "10.9.0.1/16".to_string(),
+            client_address6: Some("fd00:8::2/64".to_string()),
+            server_address6: Some("fd00:8::1/64".to_string()),
+            mtu: 1280,
+            tun_queues: 4,
+            offload: true,
+            persistent_tun: true,
+            ..Default::default()
+        };
+
+        let tun = tun_config(&config).unwrap();
+        assert_eq!(tun.address, "10.9.0.2/16".parse().unwrap());
+        assert_eq!(tun.address6, Some("fd00:8::2/64".parse().unwrap()));
+        assert_eq!(tun.name.as_deref(), Some("tun-x2ssh"));
+        assert_eq!(tun.mtu, 1280);
+        assert_eq!(tun.queues, 4);
+        assert!(tun.offload);
+        assert!(tun.persistent);
+    }
 
-#[cfg(target_os = "windows")]
-fn ip_to_addr_prefix(ip: std::net::Ipv4Addr) -> (std::net::Ipv4Addr, u8) {
-    (ip, 24)
+    #[test]
+    fn test_tun_config_rejects_ipv6_client_address() {
+        let config = VpnConfig {
+            client_address: "fd00:8::2/64".to_string(),
+            ..Default::default()
+        };
+        assert!(tun_config(&config).is_err());
+    }
 }